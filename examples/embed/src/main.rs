@@ -36,6 +36,7 @@ async fn main() -> Result<()> {
         match data {
             OutType::Dir => bail!("found unexpected dir"),
             OutType::Symlink(_) => bail!("found unexpected symlink"),
+            OutType::Missing(err) => bail!("missing content: {err}"),
             OutType::Reader(mut reader) => {
                 let mut stdout = tokio::io::stdout();
                 tokio::io::copy(&mut reader, &mut stdout).await?;
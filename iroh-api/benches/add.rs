@@ -68,7 +68,12 @@ fn add_benchmark(c: &mut Criterion) {
                             path,
                             UnixfsConfig {
                                 wrap: false,
+                                keep_name: false,
+                                custom_metadata: Default::default(),
                                 chunker: Some(ChunkerConfig::Fixed(DEFAULT_CHUNKS_SIZE)),
+                                raw_leaf_limit: None,
+                                concurrency: 1,
+                                directory_layout: Default::default(),
                             },
                         )
                         .await
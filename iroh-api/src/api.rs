@@ -1,25 +1,42 @@
 use std::collections::HashMap;
 use std::fmt;
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Instant;
 
 use crate::config::{Config, CONFIG_FILE_NAME, ENV_PREFIX};
+use crate::error::{is_unavailable, map_get_error, GetError};
+use crate::metrics::{record_add, record_add_result, record_get};
+use crate::routing::{read_routing_state, write_routing_state};
 use crate::IpfsPath;
 use crate::P2pApi;
-use anyhow::{ensure, Context, Result};
+use anyhow::{anyhow, ensure, Context, Result};
+use bytes::Bytes;
+use cid::multihash::{Code, MultihashDigest};
 use cid::Cid;
-use futures::stream::BoxStream;
+use futures::stream::{self, BoxStream};
 use futures::{StreamExt, TryStreamExt};
-use iroh_resolver::resolver::Resolver;
-use iroh_rpc_client::{Client, ClientStatus};
+use iroh_resolver::resolver::{Resolver, UnixfsType};
+use iroh_rpc_client::{Client, ClientStatus, ServiceStatus, ServiceType};
+use iroh_rpc_types::p2p::{ImportRoutingStateResponse, RoutingState};
+use iroh_rpc_types::store::{
+    GcSubtreeResponse as GcReport, VerifyIntegrityRequest, VerifyIntegrityResponse,
+};
 use iroh_unixfs::{
     builder::Entry as UnixfsEntry,
-    content_loader::{FullLoader, FullLoaderConfig},
+    codecs::Codec,
+    content_loader::{ContentLoader, FullLoader, FullLoaderConfig},
+    Block,
 };
 use iroh_util::{iroh_config_path, make_config};
 use relative_path::RelativePathBuf;
-use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite};
+use tokio::sync::mpsc;
+
+use crate::store::{add_blocks_to_store, add_blocks_to_store_dedup, AddSummary};
 
-use crate::store::add_blocks_to_store;
+/// The data of the block written by [`Api::check_store`].
+const STORE_CHECK_DATA: &[u8] = b"iroh store check";
 
 /// API to interact with an iroh system.
 ///
@@ -32,12 +49,96 @@ use crate::store::add_blocks_to_store;
 pub struct Api {
     client: Client,
     resolver: Resolver<FullLoader>,
+    staging_dir: Option<PathBuf>,
+}
+
+/// Options for fetching content with [`Api::get_with_options`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct GetOptions {
+    /// Wantlist priority of blocks fetched from the network, higher is more urgent.
+    pub priority: Option<i32>,
+    /// Maximum number of providers queried for each block fetched from the network.
+    pub max_providers: Option<usize>,
+    /// Fetch and verify the whole DAG into the store before yielding any output, so a
+    /// failure to load a block fails the get before anything was written.
+    pub verify_complete: bool,
+    /// Skip entries whose blocks can not be fetched, yielding them as [`OutType::Missing`],
+    /// instead of failing the get. Files are fetched completely before they are yielded.
+    /// The root itself can not be skipped, and `verify_complete` still fails on any
+    /// missing block.
+    pub best_effort: bool,
 }
 
 pub enum OutType {
     Dir,
     Reader(Box<dyn AsyncRead + Unpin + Send>),
     Symlink(PathBuf),
+    /// An entry that could not be fetched, only yielded with [`GetOptions::best_effort`].
+    ///
+    /// Added after v0.2.0, exhaustive matches on `OutType` have to handle it, usually by
+    /// failing like for a get without `best_effort`.
+    Missing(anyhow::Error),
+}
+
+/// Progress of [`Api::add_events`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AddEvent {
+    /// A block was added to the store, `size` is the size of the raw data it contains.
+    Block { cid: Cid, size: u64 },
+    /// An entry below the added root was encoded, its `path` is relative to the root.
+    Entry { path: PathBuf, cid: Cid },
+}
+
+/// An entry of a directory, as listed by [`Api::ls_paginated`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LinkInfo {
+    pub name: String,
+    pub cid: Cid,
+    /// Cumulative size of the entry as recorded in the directory, if any.
+    pub size: Option<u64>,
+}
+
+/// Where to continue listing a directory with [`Api::ls_paginated`].
+///
+/// A cursor points at the next entry by its position in the directory, for sharded
+/// directories the index on each level of the shard tree. As directories are immutable,
+/// a cursor stays valid for the directory it was returned for. It can be passed around as
+/// a string, using its `Display` and `FromStr` implementations.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Cursor {
+    dir: Cid,
+    position: Vec<usize>,
+}
+
+impl fmt::Display for Cursor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:", self.dir)?;
+        for (i, index) in self.position.iter().enumerate() {
+            if i > 0 {
+                write!(f, ".")?;
+            }
+            write!(f, "{index}")?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Cursor {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (dir, position) = s
+            .split_once(':')
+            .ok_or_else(|| anyhow!("invalid cursor {:?}", s))?;
+        let dir = Cid::from_str(dir).with_context(|| format!("invalid cursor {s:?}"))?;
+        let position = position
+            .split('.')
+            .filter(|index| !index.is_empty())
+            .map(|index| index.parse())
+            .collect::<Result<_, _>>()
+            .with_context(|| format!("invalid cursor {s:?}"))?;
+        Ok(Cursor { dir, position })
+    }
 }
 
 impl fmt::Debug for OutType {
@@ -46,6 +147,7 @@ impl fmt::Debug for OutType {
             Self::Dir => write!(f, "Dir"),
             Self::Reader(_) => write!(f, "Reader(impl AsyncRead + Unpin>)"),
             Self::Symlink(arg0) => f.debug_tuple("Symlink").field(arg0).finish(),
+            Self::Missing(arg0) => f.debug_tuple("Missing").field(arg0).finish(),
         }
     }
 }
@@ -94,11 +196,25 @@ impl Api {
         )?;
         let resolver = Resolver::new(content_loader);
 
-        Ok(Self { client, resolver })
+        Ok(Self {
+            client,
+            resolver,
+            staging_dir: config.staging_dir,
+        })
     }
 
     pub fn from_client_and_resolver(client: Client, resolver: Resolver<FullLoader>) -> Self {
-        Self { client, resolver }
+        Self {
+            client,
+            resolver,
+            staging_dir: None,
+        }
+    }
+
+    /// The configured directory to stage the output of `get` in, see
+    /// [`crate::fs::write_get_stream_with_staging`].
+    pub fn staging_dir(&self) -> Option<&Path> {
+        self.staging_dir.as_deref()
     }
 
     /// Announces to the DHT that this node can offer the given [`Cid`].
@@ -109,6 +225,40 @@ impl Api {
         self.client.try_p2p()?.start_providing(&cid).await
     }
 
+    /// Stops announcing that this node can offer the given [`Cid`].
+    ///
+    /// The local provider record is removed and no longer republished, e.g. once the
+    /// content was deleted. Records other peers already hold expire on their own.
+    pub async fn stop_providing(&self, cid: Cid) -> Result<()> {
+        self.client.try_p2p()?.stop_providing(&cid).await
+    }
+
+    /// Writes the DHT routing table and the local provider records of the node into
+    /// `writer`, to restore them with [`Api::import_routing_state`].
+    ///
+    /// This lets a node migrated to new hardware keep its DHT presence, without
+    /// bootstrapping the routing table and announcing all its content again. The format is
+    /// versioned JSON, see [`RoutingState`] for its content.
+    pub async fn export_routing_state<W: AsyncWrite + Unpin>(&self, mut writer: W) -> Result<()> {
+        let state = self.client.try_p2p()?.export_routing_state().await?;
+        write_routing_state(state, &mut writer).await
+    }
+
+    /// Restores the routing state written by [`Api::export_routing_state`] from `reader`.
+    ///
+    /// Peers without addresses and provider records that expired or belong to another
+    /// node are discarded, so the node keeps its identity for the records to be restored.
+    /// Restored records are republished like those of [`Api::provide`].
+    pub async fn import_routing_state<R: AsyncRead + Unpin>(
+        &self,
+        mut reader: R,
+    ) -> Result<ImportRoutingStateResponse> {
+        let state = read_routing_state(&mut reader)
+            .await
+            .context("invalid routing state")?;
+        self.client.try_p2p()?.import_routing_state(state).await
+    }
+
     pub fn p2p(&self) -> Result<P2pApi> {
         let p2p_client = self.client.try_p2p()?;
         Ok(P2pApi::new(p2p_client))
@@ -118,24 +268,67 @@ impl Api {
     ///
     /// Returns a stream of items, where items can be either blobs or UnixFs components.
     /// Each blob will be a full object, a file in case of UnixFs, and not raw chunks.
+    ///
+    /// Errors that can be categorized wrap a [`GetError`].
     pub fn get(
         &self,
         ipfs_path: &IpfsPath,
+    ) -> Result<BoxStream<'static, Result<(RelativePathBuf, OutType)>>> {
+        self.get_with_priority(ipfs_path, None)
+    }
+
+    /// Same as [`Api::get`], but fetches missing blocks from the network with the given
+    /// wantlist `priority`.
+    ///
+    /// Higher priorities are served first, so foreground requests can preempt background
+    /// fetches. If `None`, the default priority is used.
+    pub fn get_with_priority(
+        &self,
+        ipfs_path: &IpfsPath,
+        priority: Option<i32>,
+    ) -> Result<BoxStream<'static, Result<(RelativePathBuf, OutType)>>> {
+        self.get_with_options(
+            ipfs_path,
+            GetOptions {
+                priority,
+                ..Default::default()
+            },
+        )
+    }
+
+    /// Same as [`Api::get`], but fetches missing blocks as configured by [`GetOptions`].
+    pub fn get_with_options(
+        &self,
+        ipfs_path: &IpfsPath,
+        options: GetOptions,
+    ) -> Result<BoxStream<'static, Result<(RelativePathBuf, OutType)>>> {
+        record_get(self.get_stream(ipfs_path, options))
+    }
+
+    fn get_stream(
+        &self,
+        ipfs_path: &IpfsPath,
+        options: GetOptions,
     ) -> Result<BoxStream<'static, Result<(RelativePathBuf, OutType)>>> {
         ensure!(
             ipfs_path.cid().is_some(),
-            "IPFS path does not refer to a CID"
+            GetError::InvalidPath("IPFS path does not refer to a CID".to_string())
         );
 
-        tracing::debug!("get {:?}", ipfs_path);
-        let resolver = self.resolver.clone();
-        let results = resolver.resolve_recursive_with_paths(ipfs_path.clone());
+        tracing::debug!("get {:?} ({:?})", ipfs_path, options);
+        let resolver = self
+            .resolver
+            .with_priority(options.priority)
+            .with_max_providers(options.max_providers);
+        let results = resolver.resolve_recursive_with_paths_partial(ipfs_path.clone());
         let sub_path = ipfs_path.to_relative_string();
+        let client = self.client.clone();
 
         let stream = async_stream::try_stream! {
+            // with `verify_complete`, entries are only yielded once all of them are fetched
+            let mut complete = Vec::new();
             tokio::pin!(results);
-            while let Some(res) = results.next().await {
-                let (relative_ipfs_path, out) = res?;
+            while let Some((relative_ipfs_path, res)) = results.next().await {
                 let relative_path = RelativePathBuf::from_path(&relative_ipfs_path.to_relative_string())?;
                 // TODO(faassen) this focusing in on sub-paths should really be handled in the resolver:
                 // * it can be tested there far more easily than here (where currently it isn't)
@@ -145,24 +338,109 @@ impl Api {
                     continue;
                 }
                 let relative_path = relative_path.strip_prefix(&sub_path).expect("should be a prefix").to_owned();
-                if out.is_dir() {
-                    yield (relative_path, OutType::Dir);
+                let skippable = options.best_effort
+                    && !options.verify_complete
+                    && !relative_path.as_str().is_empty();
+                let out = match res.map_err(map_get_error) {
+                    Err(err) if skippable && is_unavailable(&err) => {
+                        yield (relative_path, OutType::Missing(err));
+                        continue;
+                    }
+                    res => res?,
+                };
+                let entry = if out.is_dir() {
+                    OutType::Dir
                 } else if out.is_symlink() {
                     let mut reader = out.pretty(resolver.clone(), Default::default(), None)?;
                     let mut target = String::new();
                     reader.read_to_string(&mut target).await?;
-                    let target = PathBuf::from(target);
-                    yield (relative_path, OutType::Symlink(target));
+                    OutType::Symlink(PathBuf::from(target))
                 } else {
+                    if skippable || options.verify_complete {
+                        // make sure the whole file is available, before starting to write it
+                        if let Some(cid) = out.metadata().resolved_path.last() {
+                            let root = IpfsPath::from_cid(*cid);
+                            match fetch_complete(&client, &resolver, root).await {
+                                Err(err) if skippable && is_unavailable(&err) => {
+                                    yield (relative_path, OutType::Missing(err));
+                                    continue;
+                                }
+                                res => res?,
+                            }
+                        }
+                    }
                     let reader = out.pretty(resolver.clone(), Default::default(), None)?;
-                    yield (relative_path, OutType::Reader(Box::new(reader)));
+                    OutType::Reader(Box::new(reader))
+                };
+                if options.verify_complete {
+                    complete.push((relative_path, entry));
+                } else {
+                    yield (relative_path, entry);
                 }
             }
+            for entry in complete {
+                yield entry;
+            }
         };
 
         Ok(stream.boxed())
     }
 
+    /// Writes the UnixFS directory `dir` as a tar archive into `writer`.
+    ///
+    /// Nested and sharded directories are included, all entries are placed below a
+    /// directory named after `dir`. The archive is streamed, file contents are copied into
+    /// `writer` as they are resolved. UnixFS does not record modes or modification times,
+    /// so entries get default modes and a modification time of zero.
+    ///
+    /// Errors that can be categorized wrap a [`GetError`].
+    pub async fn get_tar<W: AsyncWrite + Unpin>(&self, dir: Cid, mut writer: W) -> Result<()> {
+        tracing::debug!("get tar {}", dir);
+        crate::tar::write_tar(&self.resolver, dir, &mut writer)
+            .await
+            .map_err(map_get_error)
+    }
+
+    /// Lists up to `limit` entries of the UnixFS directory `dir`, starting at `cursor`, or
+    /// at the first entry if `None`.
+    ///
+    /// Returns the entries and the cursor for the next page, which is `None` once the last
+    /// entry was listed. Entries are listed in the order they are stored in, not by name.
+    /// Sharded directories are descended lazily, only the shards needed for the requested
+    /// page are loaded.
+    ///
+    /// Errors that can be categorized wrap a [`GetError`].
+    pub async fn ls_paginated(
+        &self,
+        dir: Cid,
+        cursor: Option<Cursor>,
+        limit: usize,
+    ) -> Result<(Vec<LinkInfo>, Option<Cursor>)> {
+        tracing::debug!("ls {} from {:?}", dir, cursor);
+        ls_page(&self.resolver, dir, cursor.as_ref(), limit)
+            .await
+            .map_err(map_get_error)
+    }
+
+    /// Reads `len` bytes of the UnixFS file at `ipfs_path`, starting at `offset`, e.g. to
+    /// answer an HTTP `Range` request.
+    ///
+    /// Only the blocks overlapping the range are fetched, the leaves covering it are found
+    /// with the block sizes recorded in the UnixFS nodes above them. Fails if the path does
+    /// not refer to a file, or the range ends beyond the end of the file.
+    ///
+    /// Errors that can be categorized wrap a [`GetError`].
+    pub async fn get_range(&self, ipfs_path: &IpfsPath, offset: u64, len: u64) -> Result<Bytes> {
+        ensure!(
+            ipfs_path.cid().is_some(),
+            GetError::InvalidPath("IPFS path does not refer to a CID".to_string())
+        );
+        tracing::debug!("get {} bytes of {} at {}", len, ipfs_path, offset);
+        read_range(&self.resolver, ipfs_path, offset, len)
+            .await
+            .map_err(map_get_error)
+    }
+
     pub async fn check(&self) -> ClientStatus {
         self.client.check().await
     }
@@ -171,6 +449,101 @@ impl Api {
         self.client.clone().watch().await.boxed()
     }
 
+    /// Like [`Api::watch`], but only for the service `typ`.
+    ///
+    /// Emits the current status of the service first, and afterwards only when it changes.
+    pub async fn watch_service(&self, typ: ServiceType) -> BoxStream<'static, ServiceStatus> {
+        let mut last = None;
+        self.watch()
+            .await
+            .filter_map(move |status| {
+                let service = status.service(&typ);
+                let changed = last.as_ref() != Some(service);
+                if changed {
+                    last = Some(service.clone());
+                }
+                futures::future::ready(changed.then(|| service.clone()))
+            })
+            .boxed()
+    }
+
+    /// Checks that the store is writable, by writing a small block, reading it back and
+    /// removing it again.
+    ///
+    /// The block is always the same, so repeated checks leave nothing behind. No other
+    /// block links to it, so it is removed on its own, without scanning the store.
+    pub async fn check_store(&self) -> Result<()> {
+        let store = self.client.try_store()?;
+        let data = Bytes::from_static(STORE_CHECK_DATA);
+        let cid = Cid::new_v1(Codec::Raw.into(), Code::Sha2_256.digest(&data));
+        // left behind by an interrupted check, removed so the block is written again
+        store.delete_block(cid).await?;
+        store.put(cid, data.clone(), Vec::new()).await?;
+        let read = store.get(cid).await;
+        // removed even if reading it back failed
+        let removed = store.delete_block(cid).await;
+        let read = read?.context("written block is missing from the store")?;
+        ensure!(read == data, "store returned different content for {}", cid);
+        ensure!(removed?, "failed to remove the block {}", cid);
+        Ok(())
+    }
+
+    /// Verifies that the data of stored blocks hashes to their cids, streaming the progress.
+    ///
+    /// Verifies a random `sample_rate` of the blocks, `1.0` scans the whole store. Dropping
+    /// the stream interrupts the verification, which can be continued later by passing the
+    /// last reported [`VerifyIntegrityResponse::last_id`] as `resume_after`. With
+    /// `quarantine` the data of corrupt blocks is removed, so it is fetched again when needed.
+    pub async fn verify_integrity(
+        &self,
+        sample_rate: f64,
+        resume_after: Option<u64>,
+        quarantine: bool,
+    ) -> Result<BoxStream<'static, Result<VerifyIntegrityResponse>>> {
+        ensure!(
+            (0.0..=1.0).contains(&sample_rate),
+            "sample rate must be between 0 and 1, got {}",
+            sample_rate
+        );
+        let req = VerifyIntegrityRequest {
+            sample_rate,
+            resume_after,
+            quarantine,
+        };
+        let progress = self.client.try_store()?.verify_integrity(req).await?;
+        Ok(progress.boxed())
+    }
+
+    /// Removes the blocks of the DAG at `root` from the store, except those still linked to
+    /// from other stored content.
+    ///
+    /// Cheaper than collecting the whole store for a DAG known to be obsolete. All other
+    /// stored blocks are kept, and so is everything they or a pin link to, even if it is
+    /// part of the DAG, see [`Api::pin_remote`]. Blocks that are not in the store are not
+    /// fetched.
+    pub async fn gc_subtree(&self, root: Cid) -> Result<GcReport> {
+        self.client.try_store()?.gc_subtree(root).await
+    }
+
+    /// Reads the raw block `cid` from the store, without resolving or fetching anything.
+    ///
+    /// Returns `None` if the block is not in the store.
+    pub async fn block_get(&self, cid: Cid) -> Result<Option<Bytes>> {
+        self.client.try_store()?.get(cid).await
+    }
+
+    /// Writes the raw block `cid` with the given `data` to the store, as is.
+    ///
+    /// Unlike [`Api::add`], which derives cids by chunking and hashing content, this keeps
+    /// the cid the block has elsewhere, e.g. to import blocks from another system. Fails if
+    /// `data` does not hash to `cid`, or can not be decoded with the codec of `cid`.
+    pub async fn block_put(&self, cid: Cid, data: Bytes) -> Result<()> {
+        let block = block_from_parts(cid, data)?;
+        block.validate()?;
+        let (cid, data, links) = block.into_parts();
+        self.client.try_store()?.put(cid, data, links).await
+    }
+
     /// The `add_stream` method encodes the entry into a DAG and adds
     /// the resulting blocks to the store. It returns a stream of
     /// CIDs and the size of the _raw data_ associated with that block.
@@ -180,21 +553,39 @@ impl Api {
         &self,
         entry: UnixfsEntry,
     ) -> Result<BoxStream<'static, Result<(Cid, u64)>>> {
-        let blocks = match entry {
-            UnixfsEntry::File(f) => f.encode().await?.boxed(),
-            UnixfsEntry::Directory(d) => d.encode(),
-            UnixfsEntry::Symlink(s) => Box::pin(async_stream::try_stream! {
-                yield s.encode()?
-            }),
-        };
+        let blocks = encode_entry(entry).await?;
+        let blocks = add_blocks_to_store(Some(self.client.clone()), blocks).await;
+
+        Ok(Box::pin(record_add(blocks)))
+    }
 
-        Ok(Box::pin(
-            add_blocks_to_store(Some(self.client.clone()), blocks).await,
-        ))
+    /// Like [`Api::add_stream`], but also reports every file and directory below the added
+    /// root as soon as it is encoded.
+    ///
+    /// The last [`AddEvent::Block`] is the root, [`AddEvent::Entry`] events can still follow it.
+    pub async fn add_events(
+        &self,
+        entry: UnixfsEntry,
+    ) -> Result<BoxStream<'static, Result<AddEvent>>> {
+        let (entries, mut receiver) = mpsc::unbounded_channel();
+        let blocks = entry.encode_reporting(entries).await?;
+        let blocks = add_blocks_to_store(Some(self.client.clone()), blocks).await;
+        let blocks = record_add(blocks).map_ok(|(cid, size)| AddEvent::Block { cid, size });
+        let entries = async_stream::stream! {
+            while let Some((path, cid)) = receiver.recv().await {
+                yield Ok(AddEvent::Entry { path, cid });
+            }
+        };
+        Ok(stream::select(blocks, entries).boxed())
     }
 
     /// The `add` method encodes the entry into a DAG and adds the resulting
     /// blocks to the store.
+    ///
+    /// The name of the entry is not part of the resulting root, as UnixFS nodes only
+    /// name their children. To preserve the name of a file or directory, wrap it first,
+    /// e.g. with [`iroh_unixfs::builder::File::wrap`], or store the name of a file in its
+    /// metadata with [`UnixfsConfig::keep_name`](crate::UnixfsConfig::keep_name).
     pub async fn add(&self, entry: UnixfsEntry) -> Result<Cid> {
         let add_events = self.add_stream(entry).await?;
 
@@ -203,4 +594,377 @@ impl Api {
             .await?
             .context("No cid found")
     }
+
+    /// Returns the blocks of the DAG at `root` that are missing from the local store.
+    ///
+    /// This is the gap a transfer has to fetch to complete `root`. Blocks below a missing
+    /// block are not included, as their cids are only known once it is fetched.
+    pub async fn missing_blocks(&self, root: Cid) -> Result<Vec<Cid>> {
+        crate::store::missing_blocks(&self.client, root).await
+    }
+
+    /// Returns whether the complete DAG at `root` is in the local store, not only the root
+    /// block.
+    ///
+    /// This is the check to run before serving content, the counterpart of
+    /// [`Api::missing_blocks`]. Returns `false` as soon as a missing block is found.
+    pub async fn has_full_dag(&self, root: Cid) -> Result<bool> {
+        crate::store::has_full_dag(&self.client, root).await
+    }
+
+    /// Makes the local store contain the complete DAG at `root`, fetching the missing blocks
+    /// over bitswap and yielding the cid of each block once it is stored.
+    ///
+    /// Only blocks that are still missing are fetched, so dropping the stream interrupts the
+    /// transfer and calling this again continues it.
+    pub fn fetch_missing(&self, root: Cid) -> BoxStream<'static, Result<Cid>> {
+        let client = self.client.clone();
+        let ctx = self.resolver.new_context();
+        async_stream::try_stream! {
+            let p2p = client.try_p2p()?;
+            let fetch = |cid| {
+                let (p2p, ctx) = (&p2p, &ctx);
+                async move {
+                    p2p.fetch_bitswap(
+                        ctx.id().into(),
+                        cid,
+                        Default::default(),
+                        ctx.priority(),
+                        ctx.max_providers(),
+                    )
+                    .await
+                }
+            };
+            let blocks = crate::store::fetch_missing(client.clone(), root, fetch);
+            tokio::pin!(blocks);
+            while let Some(cid) = blocks.next().await {
+                yield cid?;
+            }
+        }
+        .boxed()
+    }
+
+    /// Makes sure the complete DAG at `root` is in the local store and kept there, fetching
+    /// the missing blocks from the network, see [`Api::fetch_missing`].
+    ///
+    /// Once the DAG is complete, `root` is pinned, so [`Api::gc_subtree`] keeps it until
+    /// it is unpinned with [`Api::unpin`]. Nothing is pinned on failure: the blocks fetched
+    /// so far stay in the store, and are not fetched again by the next attempt unless they
+    /// are collected in the meantime.
+    ///
+    /// Errors for blocks that can not be fetched wrap a [`GetError`].
+    pub async fn pin_remote(&self, root: Cid) -> Result<()> {
+        self.pin_remote_with_progress(root, |_| {}).await
+    }
+
+    /// Like [`Api::pin_remote`], but calls `progress` with the cid of every fetched block.
+    pub async fn pin_remote_with_progress<F: FnMut(Cid)>(
+        &self,
+        root: Cid,
+        mut progress: F,
+    ) -> Result<()> {
+        let mut blocks = self.fetch_missing(root);
+        while let Some(cid) = blocks.next().await {
+            let cid = cid
+                .map_err(map_get_error)
+                .with_context(|| format!("failed to pin {root}"))?;
+            progress(cid);
+        }
+        self.client.try_store()?.pin(root).await
+    }
+
+    /// Removes the pin of `root`, returning whether it was pinned.
+    ///
+    /// The blocks of the DAG stay in the store, until they are collected.
+    pub async fn unpin(&self, root: Cid) -> Result<bool> {
+        self.client.try_store()?.unpin(root).await
+    }
+
+    /// Moves the pin of `old` to `new`, e.g. after publishing a new version of a directory,
+    /// so the old version can be collected.
+    ///
+    /// Fails if `old` is not pinned, or if the DAG at `new` is not complete in the local
+    /// store, see [`Api::has_full_dag`]. Both pins change at once, so there is no moment in
+    /// which both or neither of them are pinned.
+    pub async fn pin_update(&self, old: Cid, new: Cid) -> Result<()> {
+        ensure!(
+            self.has_full_dag(new).await?,
+            "can not pin {new}, it is not completely stored"
+        );
+        self.client.try_store()?.pin_update(old, new).await
+    }
+
+    /// Lists the pinned roots, see [`Api::pin_remote`].
+    pub async fn pins(&self) -> Result<Vec<Cid>> {
+        self.client.try_store()?.list_pins().await
+    }
+
+    /// Like [`Api::add`], but skips blocks that are already in the store and
+    /// returns an [`AddSummary`] describing how much was deduplicated.
+    pub async fn add_with_summary(&self, entry: UnixfsEntry) -> Result<(Cid, AddSummary)> {
+        let start = Instant::now();
+        let res = async {
+            let blocks = encode_entry(entry).await?;
+            add_blocks_to_store_dedup(self.client.clone(), blocks).await
+        }
+        .await;
+        record_add_result(start, &res);
+        res
+    }
+}
+
+async fn encode_entry(entry: UnixfsEntry) -> Result<BoxStream<'static, Result<Block>>> {
+    let blocks = match entry {
+        UnixfsEntry::File(f) => f.encode().await?.boxed(),
+        UnixfsEntry::Directory(d) => d.encode(),
+        UnixfsEntry::Symlink(s) => Box::pin(async_stream::try_stream! {
+            yield s.encode()?
+        }),
+    };
+    Ok(blocks)
+}
+
+/// Lists the page of `limit` entries of `dir` starting at `cursor`.
+async fn ls_page<T: ContentLoader>(
+    resolver: &Resolver<T>,
+    dir: Cid,
+    cursor: Option<&Cursor>,
+    limit: usize,
+) -> Result<(Vec<LinkInfo>, Option<Cursor>)> {
+    ensure!(limit > 0, "the page limit must be at least 1");
+    let position = match cursor {
+        Some(cursor) => {
+            ensure!(
+                cursor.dir == dir,
+                "the cursor belongs to directory {}",
+                cursor.dir
+            );
+            &cursor.position[..]
+        }
+        None => &[][..],
+    };
+
+    let out = resolver.resolve(IpfsPath::from_cid(dir)).await?;
+    let mut children = out
+        .unixfs_read_dir_from(resolver, position)
+        .with_context(|| format!("{dir} is not a directory"))?;
+    let mut entries = Vec::with_capacity(limit);
+    while let Some(child) = children.next().await {
+        let (position, link) = child?;
+        // the entry after the page is where the next one starts
+        if entries.len() == limit {
+            return Ok((entries, Some(Cursor { dir, position })));
+        }
+        entries.push(LinkInfo {
+            name: link.name.unwrap_or_else(|| link.cid.to_string()),
+            cid: link.cid,
+            size: link.tsize,
+        });
+    }
+    Ok((entries, None))
+}
+
+/// Reads `len` bytes of the file at `ipfs_path`, starting at `offset`, see
+/// [`Api::get_range`].
+async fn read_range<T: ContentLoader + Unpin + 'static>(
+    resolver: &Resolver<T>,
+    ipfs_path: &IpfsPath,
+    offset: u64,
+    len: u64,
+) -> Result<Bytes> {
+    let out = resolver.resolve(ipfs_path.clone()).await?;
+    ensure!(
+        out.metadata().unixfs_type == Some(UnixfsType::File),
+        "{} is not a file",
+        ipfs_path
+    );
+    let size = out
+        .metadata()
+        .size
+        .with_context(|| format!("the size of {ipfs_path} is unknown"))?;
+    let end = offset
+        .checked_add(len)
+        .filter(|end| *end <= size)
+        .ok_or_else(|| {
+            anyhow!(
+                "range of {} bytes at {} is out of bounds of {} with {} bytes",
+                len,
+                offset,
+                ipfs_path,
+                size
+            )
+        })?;
+    if len == 0 {
+        return Ok(Bytes::new());
+    }
+
+    let mut reader = out.pretty(resolver.clone(), Default::default(), Some(end as usize))?;
+    reader.seek(std::io::SeekFrom::Start(offset)).await?;
+    let mut data = Vec::with_capacity(len as usize);
+    reader.read_to_end(&mut data).await?;
+    ensure!(
+        data.len() as u64 == len,
+        "read {} of {} bytes at {} of {}",
+        data.len(),
+        len,
+        offset,
+        ipfs_path
+    );
+    Ok(data.into())
+}
+
+/// Creates the block `cid` from its `data`, with the links decoded from it.
+fn block_from_parts(cid: Cid, data: Bytes) -> Result<Block> {
+    let links = iroh_unixfs::parse_links(&cid, &data)
+        .with_context(|| format!("failed to decode block {cid}"))?;
+    Ok(Block::new(cid, data, links))
+}
+
+/// Fails if `data` is not the content of `cid`.
+///
+/// Blocks hashed with a multihash that is not supported can not be checked, and pass.
+fn ensure_matches_cid(cid: &Cid, data: &[u8]) -> Result<()> {
+    ensure!(
+        iroh_util::verify_hash(cid, data) != Some(false),
+        "block {} does not match its CID",
+        cid
+    );
+    Ok(())
+}
+
+/// Fetches every block of the DAG at `root`, checks it against its CID and writes it to
+/// the store.
+async fn fetch_complete(
+    client: &Client,
+    resolver: &Resolver<FullLoader>,
+    root: IpfsPath,
+) -> Result<()> {
+    let store = client.try_store()?;
+    let blocks = resolver.resolve_recursive_raw(root, None);
+    tokio::pin!(blocks);
+    while let Some(block) = blocks.next().await {
+        let block = block.map_err(map_get_error)?;
+        let cid = *block.cid();
+        ensure_matches_cid(&cid, block.content())?;
+        let links = iroh_unixfs::parse_links(&cid, block.content())?;
+        store.put(cid, block.content().clone(), links).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use iroh_resolver::resolver::stream_to_resolver;
+    use iroh_unixfs::builder::{DirectoryBuilder, FileBuilder};
+
+    #[test]
+    fn test_block_from_parts() {
+        let data = Bytes::from_static(b"hello");
+        let cid = Cid::new_v1(Codec::Raw.into(), Code::Sha2_256.digest(&data));
+        let block = block_from_parts(cid, data.clone()).unwrap();
+        block.validate().unwrap();
+        assert_eq!(block.cid(), &cid);
+        assert!(block.links().is_empty());
+
+        // data that does not match the cid is rejected
+        let block = block_from_parts(cid, Bytes::from_static(b"other")).unwrap();
+        assert!(block.validate().is_err());
+
+        // blocks must decode with the codec of their cid
+        let garbage = Bytes::from_static(b"not cbor");
+        let cid = Cid::new_v1(Codec::DagCbor.into(), Code::Sha2_256.digest(&garbage));
+        assert!(block_from_parts(cid, garbage).is_err());
+    }
+
+    #[test]
+    fn test_ensure_matches_cid() {
+        let data = b"hello";
+        let cid = Cid::new_v1(Codec::Raw.into(), Code::Sha2_256.digest(data));
+        assert!(ensure_matches_cid(&cid, data).is_ok());
+        assert!(ensure_matches_cid(&cid, b"other").is_err());
+
+        // an unsupported multihash can not be checked, which does not make the block corrupt
+        let hash = cid::multihash::Multihash::wrap(0x3f_ffff, &[0; 32]).unwrap();
+        let cid = Cid::new_v1(Codec::Raw.into(), hash);
+        assert_eq!(iroh_util::verify_hash(&cid, data), None);
+        assert!(ensure_matches_cid(&cid, data).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_ls_page() -> Result<()> {
+        for hamt in [false, true] {
+            let count = if hamt { 300 } else { 20 };
+            let mut dir = DirectoryBuilder::new();
+            if hamt {
+                dir = dir.hamt();
+            }
+            for i in 0..count {
+                let file = FileBuilder::new()
+                    .name(format!("file-{i}"))
+                    .content_bytes(format!("content {i}").into_bytes())
+                    .build()
+                    .await?;
+                dir = dir.add_file(file);
+            }
+            let (root, resolver) = stream_to_resolver(dir.build().await?.encode()).await?;
+
+            let mut names = Vec::new();
+            let mut cursor: Option<Cursor> = None;
+            loop {
+                let (page, next) = ls_page(&resolver, root, cursor.as_ref(), 7).await?;
+                assert!(page.len() <= 7);
+                names.extend(page.into_iter().map(|entry| entry.name));
+                match next {
+                    Some(next) => {
+                        // cursors survive being passed around as strings
+                        let parsed: Cursor = next.to_string().parse()?;
+                        assert_eq!(parsed, next);
+                        cursor = Some(parsed);
+                    }
+                    None => break,
+                }
+            }
+            assert_eq!(names.len(), count);
+            names.sort();
+            names.dedup();
+            assert_eq!(names.len(), count);
+
+            // a cursor of another directory is rejected
+            let other = Cursor {
+                dir: Cid::from_str("QmQL8LqkEgYXaDHdNYCG2mmpow7Sp8Z8Kt3QS688vyBeC7")?,
+                position: vec![1],
+            };
+            assert!(ls_page(&resolver, root, Some(&other), 7).await.is_err());
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_read_range() -> Result<()> {
+        let content: Vec<u8> = (0..3000).map(|i| (i % 251) as u8).collect();
+        let file = FileBuilder::new()
+            .name("foo.bin")
+            .fixed_chunker(1024)
+            .content_bytes(content.clone())
+            .build()
+            .await?;
+        let dir = DirectoryBuilder::new().add_file(file).build().await?;
+        let (root, resolver) = stream_to_resolver(dir.encode()).await?;
+        let mut path = IpfsPath::from_cid(root);
+        path.push("foo.bin");
+
+        // within a single chunk, across chunks and up to the end
+        for (offset, len) in [(0, 10), (1000, 100), (500, 2000), (2990, 10), (3000, 0)] {
+            let data = read_range(&resolver, &path, offset, len).await?;
+            let range = offset as usize..(offset + len) as usize;
+            assert_eq!(&data[..], &content[range], "{offset}+{len}");
+        }
+
+        assert!(read_range(&resolver, &path, 2990, 11).await.is_err());
+        assert!(read_range(&resolver, &path, u64::MAX, 1).await.is_err());
+        let dir = IpfsPath::from_cid(root);
+        assert!(read_range(&resolver, &dir, 0, 1).await.is_err());
+        Ok(())
+    }
 }
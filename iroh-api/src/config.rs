@@ -4,6 +4,7 @@ use iroh_rpc_client::Config as RpcClientConfig;
 use iroh_unixfs::indexer::IndexerUrl;
 use iroh_util::insert_into_config_map;
 use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
 
 /// CONFIG_FILE_NAME is the name of the optional config file located in the iroh home directory
 pub const CONFIG_FILE_NAME: &str = "ctl.config.toml";
@@ -18,6 +19,11 @@ pub struct Config {
     pub metrics: MetricsConfig,
     pub http_resolvers: Option<Vec<String>>,
     pub indexer_endpoint: Option<IndexerUrl>,
+    /// Directory to stage the output of `get` in, before it is moved to its final path.
+    ///
+    /// Defaults to the parent directory of the output. Ignored if it is not on the same
+    /// filesystem as the output, as moving the output would no longer be atomic.
+    pub staging_dir: Option<PathBuf>,
 }
 
 impl Default for Config {
@@ -27,6 +33,7 @@ impl Default for Config {
             metrics: Default::default(),
             http_resolvers: None,
             indexer_endpoint: Some(IndexerUrl::default()),
+            staging_dir: None,
         }
     }
 }
@@ -45,6 +52,12 @@ impl Source for Config {
         if let Some(indexer_endpoint) = &self.indexer_endpoint {
             insert_into_config_map(&mut map, "indexer_endpoint", indexer_endpoint.clone());
         }
+        if let Some(staging_dir) = &self.staging_dir {
+            let staging_dir = staging_dir.to_str().ok_or_else(|| {
+                ConfigError::Foreign("`staging_dir` is not a valid UTF-8 path".into())
+            })?;
+            insert_into_config_map(&mut map, "staging_dir", staging_dir);
+        }
 
         Ok(map)
     }
@@ -1,4 +1,6 @@
 use anyhow::{anyhow, Error};
+use iroh_resolver::resolver::LinkNotFound;
+use iroh_unixfs::content_loader::LoadError;
 use std::io;
 use thiserror::Error as ThisError;
 
@@ -15,6 +17,45 @@ pub enum ApiError<'a> {
     },
 }
 
+/// Categorized errors of [`crate::Api::get`], returned wrapped in an [`anyhow::Error`].
+#[derive(ThisError, Debug)]
+pub enum GetError {
+    /// The content could not be found on the network.
+    #[error("content not found: {source}")]
+    NotFound { source: anyhow::Error },
+    /// Loading the content did not finish in time.
+    #[error("timed out: {source}")]
+    Timeout { source: anyhow::Error },
+    /// The requested path is malformed.
+    #[error("invalid path: {0}")]
+    InvalidPath(String),
+    /// Reading from the local store failed.
+    #[error("store error: {source}")]
+    StoreError { source: anyhow::Error },
+}
+
+/// Wraps errors from resolving content in the matching [`GetError`], if any.
+pub fn map_get_error(e: Error) -> Error {
+    match e.downcast_ref::<LoadError>() {
+        Some(LoadError::DeadlineExceeded(_)) => anyhow!(GetError::Timeout { source: e }),
+        Some(LoadError::NotFound(_)) => anyhow!(GetError::NotFound { source: e }),
+        Some(LoadError::Store) => anyhow!(GetError::StoreError { source: e }),
+        None if e.downcast_ref::<LinkNotFound>().is_some() => {
+            anyhow!(GetError::NotFound { source: e })
+        }
+        None => e,
+    }
+}
+
+/// Whether `e` is a [`GetError`] for content that could not be fetched, either because it
+/// was not found or because fetching it timed out.
+pub fn is_unavailable(e: &Error) -> bool {
+    matches!(
+        e.downcast_ref::<GetError>(),
+        Some(GetError::NotFound { .. } | GetError::Timeout { .. })
+    )
+}
+
 pub fn map_service_error(service: &'static str, e: Error) -> Error {
     let io_error = e.root_cause().downcast_ref::<io::Error>();
     if let Some(io_error) = io_error {
@@ -24,3 +65,53 @@ pub fn map_service_error(service: &'static str, e: Error) -> Error {
     }
     e
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_map_get_error() {
+        use cid::Cid;
+        use iroh_unixfs::content_loader::ContextId;
+
+        let err = map_get_error(anyhow!(LoadError::NotFound(Cid::default())).context("resolve"));
+        assert!(matches!(
+            err.downcast_ref::<GetError>(),
+            Some(GetError::NotFound { .. })
+        ));
+
+        let err = map_get_error(anyhow!(LinkNotFound {
+            kind: "UnixfsNode::Directory",
+            name: "foo".to_string(),
+        }));
+        assert!(matches!(
+            err.downcast_ref::<GetError>(),
+            Some(GetError::NotFound { .. })
+        ));
+
+        let err = map_get_error(anyhow!(LoadError::DeadlineExceeded(ContextId(1))));
+        assert!(matches!(
+            err.downcast_ref::<GetError>(),
+            Some(GetError::Timeout { .. })
+        ));
+
+        let err = map_get_error(anyhow!("connection reset").context(LoadError::Store));
+        assert!(matches!(
+            err.downcast_ref::<GetError>(),
+            Some(GetError::StoreError { .. })
+        ));
+
+        // only the type counts, not the message
+        let err = map_get_error(anyhow!("failed to find bafy, not found"));
+        assert!(err.downcast_ref::<GetError>().is_none());
+        assert!(!is_unavailable(&err));
+
+        assert!(is_unavailable(&map_get_error(anyhow!(
+            LoadError::NotFound(Cid::default())
+        ))));
+        assert!(is_unavailable(&map_get_error(anyhow!(
+            LoadError::DeadlineExceeded(ContextId(1))
+        ))));
+    }
+}
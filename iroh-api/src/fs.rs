@@ -1,17 +1,51 @@
 use std::path::{Path, PathBuf};
 
-use anyhow::{anyhow, ensure, Result};
+use anyhow::{anyhow, bail, ensure, Context, Result};
 use futures::{Stream, StreamExt};
 use relative_path::RelativePathBuf;
+use tracing::{debug, warn};
 
 use crate::{IpfsPath, OutType};
 
 /// Takes a stream of blocks as from `get` and writes it to the filesystem.
+///
+/// The output is staged next to the output path, see [`write_get_stream_with_staging`].
 pub async fn write_get_stream(
     ipfs_path: &IpfsPath,
     blocks: impl Stream<Item = Result<(RelativePathBuf, OutType)>>,
     output_path: Option<&Path>,
 ) -> Result<PathBuf> {
+    let (root_path, _skipped) =
+        write_get_stream_with_staging(ipfs_path, blocks, output_path, None, None).await?;
+    Ok(root_path)
+}
+
+/// Takes a stream of blocks as from `get` and writes it to the filesystem, staging the
+/// output in `staging_dir`.
+///
+/// All output is first written to a staging path, and only renamed to the output path once
+/// the stream is complete, so a failed get leaves nothing behind. Staging defaults to the
+/// parent directory of the output path. A rename is only atomic within a single filesystem,
+/// so a `staging_dir` on a different filesystem than the output path is ignored, and the
+/// default is used instead. If the output path was created by someone else while the stream
+/// was written, it is left untouched and the staged output is kept, the error names its path.
+///
+/// Written files get the permissions `file_mode`, e.g. `0o600`, independent of the umask.
+/// If `None`, the permissions are the default of the process. UnixFS nodes carry no mode
+/// metadata yet, once they do it takes precedence over `file_mode`. File modes are only
+/// supported on unix.
+///
+/// Returns the output path, and the paths of all entries that were skipped, because they
+/// were [`OutType::Missing`]. The remaining entries are still written and moved into place.
+pub async fn write_get_stream_with_staging(
+    ipfs_path: &IpfsPath,
+    blocks: impl Stream<Item = Result<(RelativePathBuf, OutType)>>,
+    output_path: Option<&Path>,
+    staging_dir: Option<&Path>,
+    file_mode: Option<u32>,
+) -> Result<(PathBuf, Vec<RelativePathBuf>)> {
+    #[cfg(not(unix))]
+    ensure!(file_mode.is_none(), "file modes are only supported on unix");
     let root_path = get_root_path(ipfs_path, output_path)
         .ok_or_else(|| anyhow!("IPFS path does not refer to a CID"))?;
     ensure!(
@@ -20,14 +54,115 @@ pub async fn write_get_stream(
         root_path.display()
     );
 
-    save_get_stream(&root_path, blocks).await?;
-    Ok(root_path)
+    let parent = output_parent(&root_path);
+    tokio::fs::create_dir_all(parent).await?;
+    let staging_path = get_staging_path(&root_path, staging_dir)?;
+    ensure!(
+        !staging_path.exists(),
+        "staging path {} already exists",
+        staging_path.display()
+    );
+
+    let skipped = match save_get_stream(&staging_path, blocks, file_mode).await {
+        Ok(skipped) => skipped,
+        Err(err) => {
+            remove_staged(&staging_path).await;
+            return Err(err);
+        }
+    };
+    // renaming would replace a file or fail on a non-empty directory
+    if tokio::fs::symlink_metadata(&root_path).await.is_ok() {
+        bail!(
+            "output path {} was created while writing the output, it was kept at {}",
+            root_path.display(),
+            staging_path.display()
+        );
+    }
+    if let Err(err) = tokio::fs::rename(&staging_path, &root_path).await {
+        remove_staged(&staging_path).await;
+        return Err(err)
+            .with_context(|| format!("failed to move output to {}", root_path.display()));
+    }
+    Ok((root_path, skipped))
+}
+
+/// The directory the output at `root_path` is written to.
+fn output_parent(root_path: &Path) -> &Path {
+    match root_path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    }
 }
 
+/// Returns the path to stage the output at `root_path` in, inside `staging_dir` if it is on
+/// the same filesystem as the output, and next to the output otherwise.
+fn get_staging_path(root_path: &Path, staging_dir: Option<&Path>) -> Result<PathBuf> {
+    let parent = output_parent(root_path);
+    let dir = match staging_dir {
+        Some(dir) if same_filesystem(dir, parent) => dir,
+        Some(dir) => {
+            debug!(
+                "staging directory {} is on another filesystem than {}, ignoring it",
+                dir.display(),
+                root_path.display()
+            );
+            parent
+        }
+        None => parent,
+    };
+    let name = root_path
+        .file_name()
+        .ok_or_else(|| anyhow!("invalid output path {}", root_path.display()))?;
+    Ok(dir.join(format!(
+        ".{}.iroh-get-{}",
+        name.to_string_lossy(),
+        std::process::id()
+    )))
+}
+
+#[cfg(unix)]
+fn same_filesystem(a: &Path, b: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    match (std::fs::metadata(a), std::fs::metadata(b)) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev(),
+        _ => false,
+    }
+}
+
+#[cfg(not(unix))]
+fn same_filesystem(a: &Path, b: &Path) -> bool {
+    // No device ids available, compare the path prefixes, i.e. the drives, instead.
+    match (a.canonicalize(), b.canonicalize()) {
+        (Ok(a), Ok(b)) => a.components().next() == b.components().next(),
+        _ => false,
+    }
+}
+
+/// Removes a partially written output from its staging path.
+async fn remove_staged(path: &Path) {
+    let res = match tokio::fs::symlink_metadata(path).await {
+        Ok(metadata) if metadata.is_dir() => tokio::fs::remove_dir_all(path).await,
+        Ok(_) => tokio::fs::remove_file(path).await,
+        Err(_) => return,
+    };
+    if let Err(err) = res {
+        warn!(
+            "failed to remove staged output {}: {:?}",
+            path.display(),
+            err
+        );
+    }
+}
+
+/// Writes the stream below `root_path`, returning the paths of the skipped entries.
+#[cfg_attr(not(unix), allow(unused_variables))]
 async fn save_get_stream(
     root_path: &Path,
     blocks: impl Stream<Item = Result<(RelativePathBuf, OutType)>>,
-) -> Result<()> {
+    file_mode: Option<u32>,
+) -> Result<Vec<RelativePathBuf>> {
+    let mut skipped = Vec::new();
     tokio::pin!(blocks);
     while let Some(block) = blocks.next().await {
         let (path, out) = block?;
@@ -40,7 +175,22 @@ async fn save_get_stream(
                 if let Some(parent) = path.parent() {
                     tokio::fs::create_dir_all(parent.to_path(root_path)).await?;
                 }
-                let mut f = tokio::fs::File::create(full_path).await?;
+                let mut options = tokio::fs::OpenOptions::new();
+                options.write(true).create(true).truncate(true);
+                // the file is created with the mode, so it is never readable with wider
+                // permissions, even while it is written
+                #[cfg(unix)]
+                if let Some(mode) = file_mode {
+                    options.mode(mode);
+                }
+                let mut f = options.open(full_path).await?;
+                #[cfg(unix)]
+                if let Some(mode) = file_mode {
+                    use std::os::unix::fs::PermissionsExt;
+                    // unlike the mode of a newly created file, this is not masked by the umask
+                    f.set_permissions(std::fs::Permissions::from_mode(mode))
+                        .await?;
+                }
                 tokio::io::copy(&mut reader, &mut f).await?;
             }
             OutType::Symlink(target) => {
@@ -56,9 +206,13 @@ async fn save_get_stream(
                 #[cfg(unix)]
                 tokio::fs::symlink(target, full_path).await?;
             }
+            OutType::Missing(err) => {
+                warn!("skipping {}: {:?}", path, err);
+                skipped.push(path);
+            }
         }
     }
-    Ok(())
+    Ok(skipped)
 }
 
 #[cfg(windows)]
@@ -104,7 +258,8 @@ mod tests {
             )),
         ]));
         let tmp_dir = TempDir::new().unwrap().path().join("test_save_get_stream");
-        save_get_stream(&tmp_dir, stream).await.unwrap();
+        let skipped = save_get_stream(&tmp_dir, stream, None).await.unwrap();
+        assert!(skipped.is_empty());
         assert!(tmp_dir.join("a").is_dir());
         assert!(tmp_dir.join("a/c").is_symlink());
         let target = tokio::fs::read_link(tmp_dir.join("a/c"))
@@ -114,6 +269,166 @@ mod tests {
         assert_eq!(std::fs::read_to_string(tmp_dir.join("b")).unwrap(), "hello");
     }
 
+    #[tokio::test]
+    async fn test_write_get_stream_with_staging() {
+        let ipfs_path =
+            IpfsPath::from_str("/ipfs/QmYyQSo1c1Ym7orWxLYvCrM2EmxFTANf8wXmmE7DWjhx5N").unwrap();
+        let tmp_dir = TempDir::new().unwrap();
+        let output = tmp_dir.path().join("out");
+
+        // a failing stream leaves nothing behind
+        let stream = futures::stream::iter(vec![
+            Ok((RelativePathBuf::from_path("a").unwrap(), OutType::Dir)),
+            Err(anyhow!("missing block")),
+        ]);
+        let res =
+            write_get_stream_with_staging(&ipfs_path, stream, Some(&output), None, None).await;
+        assert!(res.is_err());
+        assert_eq!(std::fs::read_dir(tmp_dir.path()).unwrap().count(), 0);
+
+        let stream = futures::stream::iter(vec![
+            Ok((RelativePathBuf::from_path("a").unwrap(), OutType::Dir)),
+            Ok((
+                RelativePathBuf::from_path("a/b").unwrap(),
+                OutType::Reader(Box::new(std::io::Cursor::new("hello"))),
+            )),
+        ]);
+        let staging_dir = tmp_dir.path().join("staging");
+        std::fs::create_dir(&staging_dir).unwrap();
+        let (root_path, skipped) = write_get_stream_with_staging(
+            &ipfs_path,
+            stream,
+            Some(&output),
+            Some(&staging_dir),
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(root_path, output);
+        assert!(skipped.is_empty());
+        assert_eq!(
+            std::fs::read_to_string(output.join("a/b")).unwrap(),
+            "hello"
+        );
+        assert_eq!(std::fs::read_dir(&staging_dir).unwrap().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_write_get_stream_output_created_meanwhile() {
+        let ipfs_path =
+            IpfsPath::from_str("/ipfs/QmYyQSo1c1Ym7orWxLYvCrM2EmxFTANf8wXmmE7DWjhx5N").unwrap();
+        let tmp_dir = TempDir::new().unwrap();
+        let output = tmp_dir.path().join("out");
+
+        // someone else creates a non-empty directory at the output path during the get
+        let created = output.join("other");
+        let stream = futures::stream::iter(vec![Ok((
+            RelativePathBuf::from_path("a").unwrap(),
+            OutType::Dir,
+        ))])
+        .chain(futures::stream::once(async move {
+            std::fs::create_dir_all(&created).unwrap();
+            Ok((
+                RelativePathBuf::from_path("a/b").unwrap(),
+                OutType::Reader(Box::new(std::io::Cursor::new("hello"))),
+            ))
+        }));
+        let err = write_get_stream_with_staging(&ipfs_path, stream, Some(&output), None, None)
+            .await
+            .unwrap_err();
+
+        let staging_path = tmp_dir
+            .path()
+            .join(format!(".out.iroh-get-{}", std::process::id()));
+        assert!(err
+            .to_string()
+            .contains(&staging_path.display().to_string()));
+        assert!(output.join("other").is_dir());
+        assert!(!output.join("a").exists());
+        assert_eq!(
+            std::fs::read_to_string(staging_path.join("a/b")).unwrap(),
+            "hello"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_write_get_stream_skips_missing() {
+        let ipfs_path =
+            IpfsPath::from_str("/ipfs/QmYyQSo1c1Ym7orWxLYvCrM2EmxFTANf8wXmmE7DWjhx5N").unwrap();
+        let tmp_dir = TempDir::new().unwrap();
+        let output = tmp_dir.path().join("out");
+
+        let stream = futures::stream::iter(vec![
+            Ok((RelativePathBuf::from_path("").unwrap(), OutType::Dir)),
+            Ok((
+                RelativePathBuf::from_path("a").unwrap(),
+                OutType::Missing(anyhow!("not found")),
+            )),
+            Ok((
+                RelativePathBuf::from_path("b").unwrap(),
+                OutType::Reader(Box::new(std::io::Cursor::new("hello"))),
+            )),
+        ]);
+        let (root_path, skipped) =
+            write_get_stream_with_staging(&ipfs_path, stream, Some(&output), None, None)
+                .await
+                .unwrap();
+        assert_eq!(root_path, output);
+        assert_eq!(skipped, vec![RelativePathBuf::from("a")]);
+        assert!(!output.join("a").exists());
+        assert_eq!(std::fs::read_to_string(output.join("b")).unwrap(), "hello");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_write_get_stream_file_mode() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let ipfs_path =
+            IpfsPath::from_str("/ipfs/QmYyQSo1c1Ym7orWxLYvCrM2EmxFTANf8wXmmE7DWjhx5N").unwrap();
+        let tmp_dir = TempDir::new().unwrap();
+        let output = tmp_dir.path().join("out");
+
+        let stream = futures::stream::iter(vec![
+            Ok((RelativePathBuf::from_path("").unwrap(), OutType::Dir)),
+            Ok((
+                RelativePathBuf::from_path("a/b").unwrap(),
+                OutType::Reader(Box::new(std::io::Cursor::new("secret"))),
+            )),
+        ]);
+        write_get_stream_with_staging(&ipfs_path, stream, Some(&output), None, Some(0o600))
+            .await
+            .unwrap();
+        let mode = std::fs::metadata(output.join("a/b"))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o7777, 0o600);
+        // directories keep their default permissions
+        assert!(std::fs::read_dir(output.join("a")).is_ok());
+    }
+
+    #[test]
+    fn test_get_staging_path() {
+        let tmp_dir = TempDir::new().unwrap();
+        let output = tmp_dir.path().join("out");
+        let staging = get_staging_path(&output, Some(tmp_dir.path())).unwrap();
+        assert_eq!(staging.parent(), Some(tmp_dir.path()));
+        assert!(staging
+            .file_name()
+            .unwrap()
+            .to_string_lossy()
+            .starts_with(".out.iroh-get-"));
+
+        // staging directories that can not be used fall back to the parent of the output
+        let missing = tmp_dir.path().join("missing");
+        let staging = get_staging_path(&output, Some(&missing)).unwrap();
+        assert_eq!(staging.parent(), Some(tmp_dir.path()));
+
+        let staging = get_staging_path(Path::new("out"), None).unwrap();
+        assert_eq!(staging.parent(), Some(Path::new(".")));
+    }
+
     #[test]
     fn test_get_root_path() {
         let ipfs_path =
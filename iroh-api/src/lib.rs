@@ -1,25 +1,35 @@
 pub use crate::api::Api;
-pub use crate::api::OutType;
+pub use crate::api::{AddEvent, Cursor, GetOptions, LinkInfo, OutType};
 pub use crate::config::Config;
-pub use crate::error::ApiError;
+pub use crate::error::{ApiError, GetError};
 pub use crate::p2p::P2p as P2pApi;
 pub use crate::p2p::PeerIdOrAddr;
+pub use crate::store::AddSummary;
 pub use bytes::Bytes;
 pub use cid::Cid;
 pub use iroh_resolver::resolver::Path as IpfsPath;
 pub use iroh_rpc_client::{ClientStatus, Lookup, ServiceStatus, ServiceType, StatusType};
+pub use iroh_rpc_types::p2p::{
+    BitswapPeerState, ImportRoutingStateResponse, PeerInfo, ProviderRecord, RelayState,
+    RoutingPeer, RoutingState,
+};
+pub use iroh_rpc_types::store::{GcSubtreeResponse as GcReport, VerifyIntegrityResponse};
 pub use iroh_unixfs::builder::{
-    Config as UnixfsConfig, DirectoryBuilder, Entry as UnixfsEntry, FileBuilder, SymlinkBuilder,
+    Config as UnixfsConfig, DirectoryBuilder, DirectoryLayout, Entry as UnixfsEntry, FileBuilder,
+    SymlinkBuilder,
 };
 pub use iroh_unixfs::chunker::{ChunkerConfig, DEFAULT_CHUNKS_SIZE};
 pub use iroh_unixfs::Block;
-pub use libp2p::gossipsub::MessageId;
+pub use libp2p::gossipsub::{GossipsubMessage, MessageId};
 pub use libp2p::{Multiaddr, PeerId};
 
 mod api;
 mod error;
+mod metrics;
 mod p2p;
+mod routing;
 mod store;
+mod tar;
 
 pub mod config;
 pub mod fs;
@@ -0,0 +1,182 @@
+//! Records the end-to-end metrics of adding and getting content.
+//!
+//! Requests are timed until their stream ends, streams dropped before that are counted,
+//! but not timed.
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+use anyhow::{Error, Result};
+use cid::Cid;
+use futures::stream::BoxStream;
+use futures::{Stream, StreamExt};
+use iroh_metrics::{
+    api::{ApiHistograms, ApiMetrics},
+    core::{MObserver, MRecorder},
+    inc, observe, record,
+};
+use relative_path::RelativePathBuf;
+use tokio::io::{AsyncRead, ReadBuf};
+
+use crate::error::GetError;
+use crate::OutType;
+
+/// Records an add request, the raw data of the added `blocks` and its duration.
+pub(crate) fn record_add<S>(blocks: S) -> impl Stream<Item = Result<(Cid, u64)>>
+where
+    S: Stream<Item = Result<(Cid, u64)>> + Send + 'static,
+{
+    inc!(ApiMetrics::AddRequests);
+    let start = Instant::now();
+    async_stream::stream! {
+        tokio::pin!(blocks);
+        let mut failed = false;
+        while let Some(block) = blocks.next().await {
+            match &block {
+                Ok((_, size)) => {
+                    record!(ApiMetrics::AddBytes, *size);
+                }
+                Err(_) if !failed => {
+                    failed = true;
+                    inc!(ApiMetrics::AddFailures);
+                }
+                Err(_) => {}
+            }
+            yield block;
+        }
+        observe!(ApiHistograms::AddRequestTime, start.elapsed().as_secs_f64());
+    }
+}
+
+/// Records an add request that is not streamed, started at `start`.
+pub(crate) fn record_add_result<T>(start: Instant, res: &Result<T>) {
+    inc!(ApiMetrics::AddRequests);
+    if res.is_err() {
+        inc!(ApiMetrics::AddFailures);
+    }
+    observe!(ApiHistograms::AddRequestTime, start.elapsed().as_secs_f64());
+}
+
+/// Records a get request, the file content read from it, its duration and why it failed,
+/// if it did.
+pub(crate) fn record_get(
+    get: Result<BoxStream<'static, Result<(RelativePathBuf, OutType)>>>,
+) -> Result<BoxStream<'static, Result<(RelativePathBuf, OutType)>>> {
+    inc!(ApiMetrics::GetRequests);
+    let start = Instant::now();
+    let mut items = get.map_err(|err| {
+        inc!(get_failure_metric(&err));
+        err
+    })?;
+    let stream = async_stream::stream! {
+        let mut failed = false;
+        while let Some(item) = items.next().await {
+            match item {
+                Ok((path, OutType::Reader(reader))) => {
+                    yield Ok((path, OutType::Reader(Box::new(CountingReader(reader)))));
+                }
+                Err(err) => {
+                    if !failed {
+                        failed = true;
+                        inc!(get_failure_metric(&err));
+                    }
+                    yield Err(err);
+                }
+                item => {
+                    yield item;
+                }
+            }
+        }
+        observe!(ApiHistograms::GetRequestTime, start.elapsed().as_secs_f64());
+    };
+    Ok(stream.boxed())
+}
+
+/// The counter of get failures matching the reason of `err`.
+fn get_failure_metric(err: &Error) -> ApiMetrics {
+    match err.downcast_ref::<GetError>() {
+        Some(GetError::NotFound { .. }) => ApiMetrics::GetFailuresNotFound,
+        Some(GetError::Timeout { .. }) => ApiMetrics::GetFailuresTimeout,
+        Some(GetError::InvalidPath(_)) => ApiMetrics::GetFailuresInvalidPath,
+        Some(GetError::StoreError { .. }) => ApiMetrics::GetFailuresStore,
+        None => ApiMetrics::GetFailuresOther,
+    }
+}
+
+/// Records the bytes read from the file content of a get.
+struct CountingReader<R>(R);
+
+impl<R: AsyncRead + Unpin> AsyncRead for CountingReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        let res = Pin::new(&mut self.0).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = res {
+            record!(ApiMetrics::GetBytes, (buf.filled().len() - filled) as u64);
+        }
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::map_get_error;
+    use anyhow::anyhow;
+    use cid::Cid;
+    use iroh_metrics::core::MetricType;
+    use iroh_unixfs::content_loader::{ContextId, LoadError};
+    use tokio::io::AsyncReadExt;
+
+    #[test]
+    fn test_get_failure_metric() {
+        let reason = |err: Error| get_failure_metric(&map_get_error(err)).name();
+        assert_eq!(
+            reason(anyhow!(LoadError::NotFound(Cid::default()))),
+            ApiMetrics::GetFailuresNotFound.name()
+        );
+        assert_eq!(
+            reason(anyhow!(LoadError::DeadlineExceeded(ContextId(1))).context("resolve")),
+            ApiMetrics::GetFailuresTimeout.name()
+        );
+        assert_eq!(
+            reason(anyhow!(GetError::InvalidPath("foo".to_string()))),
+            ApiMetrics::GetFailuresInvalidPath.name()
+        );
+        assert_eq!(
+            reason(anyhow!("connection reset")),
+            ApiMetrics::GetFailuresOther.name()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_record_get_passes_content_through() -> Result<()> {
+        let items: Vec<Result<(RelativePathBuf, OutType)>> = vec![
+            Ok((RelativePathBuf::from_path("")?, OutType::Dir)),
+            Ok((
+                RelativePathBuf::from_path("a.txt")?,
+                OutType::Reader(Box::new(&b"hello"[..])),
+            )),
+            Err(anyhow!("failed to find bafy")),
+        ];
+        let mut stream = record_get(Ok(futures::stream::iter(items).boxed()))?;
+
+        assert!(matches!(stream.next().await, Some(Ok((_, OutType::Dir)))));
+        match stream.next().await {
+            Some(Ok((path, OutType::Reader(mut reader)))) => {
+                assert_eq!(path.as_str(), "a.txt");
+                let mut content = String::new();
+                reader.read_to_string(&mut content).await?;
+                assert_eq!(content, "hello");
+            }
+            _ => panic!("expected a reader"),
+        }
+        assert!(stream.next().await.unwrap().is_err());
+        assert!(stream.next().await.is_none());
+        Ok(())
+    }
+}
@@ -1,6 +1,10 @@
 use crate::error::map_service_error;
 use anyhow::Result;
+use bytes::Bytes;
+use futures::Stream;
 use iroh_rpc_client::{Lookup, P2pClient};
+use iroh_rpc_types::p2p::{PeerInfo, RelayState};
+use libp2p::gossipsub::{GossipsubMessage, MessageId, TopicHash};
 use libp2p::{multiaddr::Protocol, Multiaddr, PeerId};
 use std::collections::HashMap;
 
@@ -24,6 +28,30 @@ impl P2p {
         self.client.lookup_local().await
     }
 
+    /// The addresses the node is listening on.
+    pub async fn listeners(&self) -> Result<Vec<Multiaddr>> {
+        self.client
+            .listeners()
+            .await
+            .map_err(|e| map_service_error("p2p", e))
+    }
+
+    /// The addresses of the node confirmed to be reachable from the outside.
+    pub async fn external_addresses(&self) -> Result<Vec<Multiaddr>> {
+        self.client
+            .external_addresses()
+            .await
+            .map_err(|e| map_service_error("p2p", e))
+    }
+
+    /// The relays the node listens on, with the state of their reservation.
+    pub async fn relays(&self) -> Result<Vec<(PeerId, RelayState)>> {
+        self.client
+            .relays()
+            .await
+            .map_err(|e| map_service_error("p2p", e))
+    }
+
     pub async fn lookup(&self, addr: &PeerIdOrAddr) -> Result<Lookup> {
         match addr {
             PeerIdOrAddr::PeerId(peer_id) => self.client.lookup(*peer_id, None).await,
@@ -52,6 +80,64 @@ impl P2p {
             .await
             .map_err(|e| map_service_error("p2p", e))
     }
+
+    /// The number of peers in the DHT routing table.
+    pub async fn routing_table_size(&self) -> Result<usize> {
+        self.client
+            .export_routing_state()
+            .await
+            .map(|state| state.peers.len())
+            .map_err(|e| map_service_error("p2p", e))
+    }
+
+    /// The connected peers, with their identify info, latency and bitswap state.
+    pub async fn peers_info(&self) -> Result<Vec<PeerInfo>> {
+        self.client
+            .peers_info()
+            .await
+            .map_err(|e| map_service_error("p2p", e))
+    }
+
+    /// Subscribes the node to the gossipsub `topic`.
+    ///
+    /// Returns `false` if the node was already subscribed.
+    pub async fn subscribe(&self, topic: &str) -> Result<bool> {
+        self.client
+            .gossipsub_subscribe(TopicHash::from_raw(topic))
+            .await
+            .map_err(|e| map_service_error("p2p", e))
+    }
+
+    /// Unsubscribes the node from the gossipsub `topic`.
+    ///
+    /// Returns `false` if the node was not subscribed.
+    pub async fn unsubscribe(&self, topic: &str) -> Result<bool> {
+        self.client
+            .gossipsub_unsubscribe(TopicHash::from_raw(topic))
+            .await
+            .map_err(|e| map_service_error("p2p", e))
+    }
+
+    /// Publishes `data` to the gossipsub `topic`.
+    pub async fn publish(&self, topic: &str, data: Bytes) -> Result<MessageId> {
+        self.client
+            .gossipsub_publish(TopicHash::from_raw(topic), data)
+            .await
+            .map_err(|e| map_service_error("p2p", e))
+    }
+
+    /// Streams the gossipsub messages received on the subscribed topics, or only on `topic`.
+    ///
+    /// Yields the peer each message was received from, the message id and the message.
+    pub async fn messages(
+        &self,
+        topic: Option<&str>,
+    ) -> Result<impl Stream<Item = Result<(PeerId, MessageId, GossipsubMessage)>>> {
+        self.client
+            .gossipsub_messages(topic.map(TopicHash::from_raw))
+            .await
+            .map_err(|e| map_service_error("p2p", e))
+    }
 }
 
 fn peer_id_from_multiaddr(addr: &Multiaddr) -> Result<PeerId> {
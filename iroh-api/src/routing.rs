@@ -0,0 +1,96 @@
+//! Serialization of the routing state exported by [`crate::Api::export_routing_state`].
+//!
+//! The state is written as a JSON object with a format `version` next to the `peers` and
+//! `providers` of [`RoutingState`], so exports stay readable when the format is extended.
+//! Peer ids and addresses are written in their string form, provider record expiries in
+//! seconds since the unix epoch.
+use anyhow::{ensure, Result};
+use iroh_rpc_types::p2p::RoutingState;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// The version of the format written by [`write_routing_state`].
+const ROUTING_STATE_VERSION: u32 = 1;
+/// Maximum size of an export read by [`read_routing_state`], far above the size of the
+/// largest routing tables.
+const MAX_ROUTING_STATE_SIZE: u64 = 64 * 1024 * 1024;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct RoutingStateFile {
+    version: u32,
+    #[serde(flatten)]
+    state: RoutingState,
+}
+
+pub(crate) async fn write_routing_state<W: AsyncWrite + Unpin>(
+    state: RoutingState,
+    writer: &mut W,
+) -> Result<()> {
+    let file = RoutingStateFile {
+        version: ROUTING_STATE_VERSION,
+        state,
+    };
+    writer.write_all(&serde_json::to_vec(&file)?).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+pub(crate) async fn read_routing_state<R: AsyncRead + Unpin>(
+    reader: &mut R,
+) -> Result<RoutingState> {
+    let mut data = Vec::new();
+    reader
+        .take(MAX_ROUTING_STATE_SIZE + 1)
+        .read_to_end(&mut data)
+        .await?;
+    ensure!(
+        data.len() as u64 <= MAX_ROUTING_STATE_SIZE,
+        "routing state larger than {} bytes",
+        MAX_ROUTING_STATE_SIZE
+    );
+    let file: RoutingStateFile = serde_json::from_slice(&data)?;
+    ensure!(
+        file.version == ROUTING_STATE_VERSION,
+        "unsupported routing state version {}",
+        file.version
+    );
+    Ok(file.state)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use iroh_rpc_types::p2p::{ProviderRecord, RoutingPeer};
+    use libp2p::PeerId;
+
+    #[tokio::test]
+    async fn test_routing_state_roundtrip() -> Result<()> {
+        let peer_id = PeerId::random();
+        let state = RoutingState {
+            peers: vec![RoutingPeer {
+                peer_id,
+                addrs: vec!["/ip4/1.2.3.4/tcp/4001".parse()?],
+            }],
+            providers: vec![ProviderRecord {
+                key: vec![1, 2, 3].into(),
+                provider: peer_id,
+                addrs: vec![],
+                expires: Some(1_700_000_000),
+            }],
+        };
+
+        let mut data = Vec::new();
+        write_routing_state(state.clone(), &mut data).await?;
+        let json: serde_json::Value = serde_json::from_slice(&data)?;
+        assert_eq!(json["version"], 1);
+        assert_eq!(json["peers"][0]["peer_id"], peer_id.to_string());
+        assert_eq!(read_routing_state(&mut &data[..]).await?, state);
+
+        let mut json = json;
+        json["version"] = 2.into();
+        let data = serde_json::to_vec(&json)?;
+        assert!(read_routing_state(&mut &data[..]).await.is_err());
+        assert!(read_routing_state(&mut &b"not json"[..]).await.is_err());
+        Ok(())
+    }
+}
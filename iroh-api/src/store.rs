@@ -1,22 +1,57 @@
-use std::{pin::Pin, sync::Arc};
+use std::{collections::HashSet, future::Future, pin::Pin, sync::Arc};
 
-use anyhow::Result;
+use anyhow::{ensure, Context, Result};
 use async_stream::stream;
 use async_trait::async_trait;
 use bytes::Bytes;
 use cid::Cid;
-use futures::{Stream, StreamExt};
+use futures::{stream::BoxStream, Stream, StreamExt, TryFutureExt, TryStreamExt};
 use iroh_rpc_client::Client;
 use iroh_unixfs::Block;
 
 /// How many chunks to buffer up when adding content.
 const _ADD_PAR: usize = 24;
 
+/// Maximum size of blocks sent in a single `put_many` call.
+const MAX_CHUNK_SIZE: u64 = 1024 * 1024;
+
+/// Maximum depth of the DAG walked by [`missing_blocks`] and [`has_full_dag`].
+const MAX_DAG_DEPTH: usize = 4096;
+
+/// How many links are looked up concurrently by [`missing_blocks`] and [`has_full_dag`].
+const GET_LINKS_CONCURRENCY: usize = 16;
+
+/// How many blocks are fetched concurrently by [`fetch_missing`].
+const FETCH_MISSING_CONCURRENCY: usize = 16;
+
+/// Deduplication statistics collected while adding content.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct AddSummary {
+    /// Total number of blocks produced by encoding the content.
+    pub total_blocks: u64,
+    /// Number of blocks that were actually written to the store.
+    pub unique_blocks: u64,
+    /// Number of bytes not written, because the block was already present.
+    pub bytes_saved: u64,
+}
+
 #[async_trait]
 pub trait Store: 'static + Send + Sync + Clone {
     async fn has(&self, &cid: Cid) -> Result<bool>;
+    /// Checks the presence of all `cids` at once, returning the results in the same order.
+    async fn has_many(&self, cids: &[Cid]) -> Result<Vec<bool>>;
     async fn put(&self, cid: Cid, blob: Bytes, links: Vec<Cid>) -> Result<()>;
     async fn put_many(&self, blocks: Vec<Block>) -> Result<()>;
+    /// Returns the links of a stored block, or `None` if it is not in the store.
+    async fn get_links(&self, cid: Cid) -> Result<Option<Vec<Cid>>>;
+    /// Streams the cids of all stored blocks, in unspecified order.
+    fn list_cids(&self) -> BoxStream<'static, Result<Cid>>;
+    /// Forces all blocks put so far to disk.
+    ///
+    /// A put block survives a crash of the store process once `put` returns, but can be
+    /// lost on power loss or an operating system crash until the store is flushed. Long
+    /// running imports can flush at checkpoints, after which their progress is durable.
+    async fn flush(&self) -> Result<()>;
 }
 
 #[async_trait]
@@ -25,6 +60,10 @@ impl Store for Client {
         self.try_store()?.has(cid).await
     }
 
+    async fn has_many(&self, cids: &[Cid]) -> Result<Vec<bool>> {
+        self.try_store()?.has_many(cids.to_vec()).await
+    }
+
     async fn put(&self, cid: Cid, blob: Bytes, links: Vec<Cid>) -> Result<()> {
         self.try_store()?.put(cid, blob, links).await
     }
@@ -34,6 +73,27 @@ impl Store for Client {
             .put_many(blocks.into_iter().map(|x| x.into_parts()).collect())
             .await
     }
+
+    async fn get_links(&self, cid: Cid) -> Result<Option<Vec<Cid>>> {
+        self.try_store()?.get_links(cid).await
+    }
+
+    async fn flush(&self) -> Result<()> {
+        self.try_store()?.flush().await
+    }
+
+    fn list_cids(&self) -> BoxStream<'static, Result<Cid>> {
+        let store = self.try_store();
+        async_stream::try_stream! {
+            let store = store?;
+            let cids = store.list_cids().await?;
+            tokio::pin!(cids);
+            while let Some(cid) = cids.next().await {
+                yield cid?;
+            }
+        }
+        .boxed()
+    }
 }
 
 #[async_trait]
@@ -41,6 +101,10 @@ impl Store for Arc<tokio::sync::Mutex<std::collections::HashMap<Cid, Bytes>>> {
     async fn has(&self, cid: Cid) -> Result<bool> {
         Ok(self.lock().await.contains_key(&cid))
     }
+    async fn has_many(&self, cids: &[Cid]) -> Result<Vec<bool>> {
+        let this = self.lock().await;
+        Ok(cids.iter().map(|cid| this.contains_key(cid)).collect())
+    }
     async fn put(&self, cid: Cid, blob: Bytes, _links: Vec<Cid>) -> Result<()> {
         self.lock().await.insert(cid, blob);
         Ok(())
@@ -53,6 +117,29 @@ impl Store for Arc<tokio::sync::Mutex<std::collections::HashMap<Cid, Bytes>>> {
         }
         Ok(())
     }
+
+    async fn get_links(&self, cid: Cid) -> Result<Option<Vec<Cid>>> {
+        self.lock()
+            .await
+            .get(&cid)
+            .map(|blob| iroh_unixfs::parse_links(&cid, blob))
+            .transpose()
+    }
+
+    async fn flush(&self) -> Result<()> {
+        // nothing is buffered in memory
+        Ok(())
+    }
+
+    fn list_cids(&self) -> BoxStream<'static, Result<Cid>> {
+        let this = self.clone();
+        async move {
+            let cids: Vec<Cid> = this.lock().await.keys().copied().collect();
+            anyhow::Ok(futures::stream::iter(cids.into_iter().map(Ok)))
+        }
+        .try_flatten_stream()
+        .boxed()
+    }
 }
 
 fn add_blocks_to_store_chunked<S: Store>(
@@ -61,7 +148,6 @@ fn add_blocks_to_store_chunked<S: Store>(
 ) -> impl Stream<Item = Result<(Cid, u64)>> {
     let mut chunk = Vec::new();
     let mut chunk_size = 0u64;
-    const MAX_CHUNK_SIZE: u64 = 1024 * 1024;
     stream! {
         while let Some(block) = blocks.next().await {
             let block = block?;
@@ -85,9 +171,410 @@ fn add_blocks_to_store_chunked<S: Store>(
     }
 }
 
+/// Adds the blocks to the store, skipping any block that is already present,
+/// either in the store or earlier in the same stream.
+///
+/// Returns the root, which is the last block, and the collected [`AddSummary`].
+pub(crate) async fn add_blocks_to_store_dedup<S: Store>(
+    store: S,
+    mut blocks: Pin<Box<dyn Stream<Item = Result<Block>> + Send>>,
+) -> Result<(Cid, AddSummary)> {
+    let mut summary = AddSummary::default();
+    let mut seen = HashSet::new();
+    let mut root = None;
+    let mut chunk = Vec::new();
+    let mut chunk_size = 0u64;
+
+    while let Some(block) = blocks.next().await {
+        let block = block?;
+        let cid = *block.cid();
+        summary.total_blocks += 1;
+        root = Some(cid);
+
+        if !seen.insert(cid) || store.has(cid).await? {
+            summary.bytes_saved += block.data().len() as u64;
+            continue;
+        }
+        summary.unique_blocks += 1;
+
+        let block_size = block.data().len() as u64 + block.links().len() as u64 * 128;
+        if chunk_size + block_size > MAX_CHUNK_SIZE {
+            store.put_many(std::mem::take(&mut chunk)).await?;
+            chunk_size = 0;
+        }
+        chunk.push(block);
+        chunk_size += block_size;
+    }
+    store.put_many(chunk).await?;
+
+    let root = root.context("No cid found")?;
+    tracing::debug!("added {}: {:?}", root, summary);
+    Ok((root, summary))
+}
+
+/// Returns the blocks of the DAG at `root` that are missing from the store.
+///
+/// The DAG is walked level by level, checking the presence of each level with a single
+/// `has_many` call. The links of missing blocks are unknown, so blocks below them are not
+/// included. The store does not record whether the DAG below a block is complete, so all
+/// present blocks are descended into. Each block is visited once, which also guards against
+/// cycles in the link index, and walks deeper than `MAX_DAG_DEPTH` levels fail.
+pub(crate) async fn missing_blocks<S: Store>(store: &S, root: Cid) -> Result<Vec<Cid>> {
+    walk_missing(store, root, vec![root], &mut HashSet::from([root]), false).await
+}
+
+/// Returns whether the complete DAG at `root` is in the store.
+///
+/// Walks the DAG like [`missing_blocks`], but stops at the first level with a missing
+/// block. A complete DAG is walked in full, with one `has_many` call per level.
+pub(crate) async fn has_full_dag<S: Store>(store: &S, root: Cid) -> Result<bool> {
+    let missing = walk_missing(store, root, vec![root], &mut HashSet::from([root]), true).await?;
+    Ok(missing.is_empty())
+}
+
+/// Walks the DAG at `root` down from the blocks of `level`, collecting the missing blocks,
+/// see [`missing_blocks`]. Blocks in `visited` are skipped, blocks that are walked are
+/// added to it. With `stop_at_missing` the walk ends once the first missing blocks were
+/// found.
+async fn walk_missing<S: Store>(
+    store: &S,
+    root: Cid,
+    mut level: Vec<Cid>,
+    visited: &mut HashSet<Cid>,
+    stop_at_missing: bool,
+) -> Result<Vec<Cid>> {
+    let mut missing = Vec::new();
+    let mut depth = 0;
+
+    while !level.is_empty() {
+        ensure!(
+            depth < MAX_DAG_DEPTH,
+            "DAG at {} is deeper than {} levels",
+            root,
+            MAX_DAG_DEPTH
+        );
+        let has = store.has_many(&level).await?;
+        let (present, absent): (Vec<_>, Vec<_>) =
+            level.into_iter().zip(has).partition(|(_, has)| *has);
+        missing.extend(absent.into_iter().map(|(cid, _)| cid));
+        if stop_at_missing && !missing.is_empty() {
+            break;
+        }
+
+        let links: Vec<(Cid, Option<Vec<Cid>>)> = futures::stream::iter(present)
+            .map(|(cid, _)| async move { anyhow::Ok((cid, store.get_links(cid).await?)) })
+            .buffered(GET_LINKS_CONCURRENCY)
+            .try_collect()
+            .await?;
+
+        let mut next = Vec::new();
+        for (cid, links) in links {
+            match links {
+                Some(links) => next.extend(links.into_iter().filter(|link| visited.insert(*link))),
+                // removed since checking its presence
+                None => missing.push(cid),
+            }
+        }
+        if stop_at_missing && !missing.is_empty() {
+            break;
+        }
+        level = next;
+        depth += 1;
+    }
+
+    Ok(missing)
+}
+
+/// Fetches the blocks of the DAG at `root` that are missing from `store` with `fetch`,
+/// yielding the cid of each block once it is stored.
+///
+/// The missing blocks are fetched in rounds, as the links of a fetched block are only known
+/// once it is fetched. Each round continues the walk at the links of the blocks fetched in
+/// the previous one, so every block is only checked once. Fetched blocks are checked
+/// against their cid. Blocks already in the store are never fetched, so an interrupted run
+/// is continued by running it again.
+pub(crate) fn fetch_missing<S, F, Fut>(
+    store: S,
+    root: Cid,
+    fetch: F,
+) -> impl Stream<Item = Result<Cid>>
+where
+    S: Store,
+    F: Fn(Cid) -> Fut,
+    Fut: Future<Output = Result<Bytes>>,
+{
+    async_stream::try_stream! {
+        let mut visited = HashSet::from([root]);
+        let mut frontier = vec![root];
+        let mut rounds = 0;
+        while !frontier.is_empty() {
+            ensure!(
+                rounds < MAX_DAG_DEPTH,
+                "DAG at {} is deeper than {} levels",
+                root,
+                MAX_DAG_DEPTH
+            );
+            rounds += 1;
+            let missing = walk_missing(&store, root, frontier, &mut visited, false).await?;
+
+            let blocks = futures::stream::iter(missing)
+                .map(|cid| {
+                    let (store, fetch) = (&store, &fetch);
+                    async move {
+                        let data = fetch(cid)
+                            .await
+                            .with_context(|| format!("failed to fetch {cid}"))?;
+                        // a multihash we can not compute does not make the block corrupt
+                        ensure!(
+                            iroh_util::verify_hash(&cid, &data) != Some(false),
+                            "block {} does not match its CID",
+                            cid
+                        );
+                        let links = iroh_unixfs::parse_links(&cid, &data)?;
+                        store.put(cid, data, links.clone()).await?;
+                        anyhow::Ok((cid, links))
+                    }
+                })
+                .buffer_unordered(FETCH_MISSING_CONCURRENCY);
+            tokio::pin!(blocks);
+            let mut next = Vec::new();
+            while let Some(block) = blocks.next().await {
+                let (cid, links) = block?;
+                next.extend(links.into_iter().filter(|link| visited.insert(*link)));
+                yield cid;
+            }
+            frontier = next;
+        }
+    }
+}
+
 pub async fn add_blocks_to_store<S: Store>(
     store: Option<S>,
     blocks: Pin<Box<dyn Stream<Item = Result<Block>> + Send>>,
 ) -> impl Stream<Item = Result<(Cid, u64)>> {
     add_blocks_to_store_chunked(store.unwrap(), blocks)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use iroh_unixfs::builder::{DirectoryBuilder, FileBuilder};
+
+    #[tokio::test]
+    async fn test_add_blocks_to_store_dedup() -> Result<()> {
+        let store: Arc<tokio::sync::Mutex<std::collections::HashMap<Cid, Bytes>>> =
+            Default::default();
+        let content = Bytes::from_static(b"hello world");
+        let file = |name: &str| {
+            FileBuilder::new()
+                .name(name)
+                .content_bytes(content.clone())
+                .build()
+        };
+        let dir = DirectoryBuilder::new()
+            .name("foo")
+            .add_file(file("a.txt").await?)
+            .add_file(file("b.txt").await?)
+            .build()
+            .await?;
+
+        let (root, summary) = add_blocks_to_store_dedup(store.clone(), dir.encode()).await?;
+        assert_eq!(summary.total_blocks, 3);
+        assert_eq!(summary.unique_blocks, 2);
+        assert_eq!(summary.bytes_saved, content.len() as u64);
+        assert_eq!(store.lock().await.len(), 2);
+        assert!(store.has(root).await?);
+        let cids: HashSet<Cid> = store.list_cids().try_collect().await?;
+        assert_eq!(cids.len(), 2);
+        assert!(cids.contains(&root));
+        assert_eq!(
+            store.has_many(&[root, Cid::default(), root]).await?,
+            vec![true, false, true]
+        );
+
+        // adding the same content again stores nothing new
+        let dir = DirectoryBuilder::new()
+            .name("foo")
+            .add_file(file("a.txt").await?)
+            .add_file(file("b.txt").await?)
+            .build()
+            .await?;
+        let (root2, summary) = add_blocks_to_store_dedup(store.clone(), dir.encode()).await?;
+        assert_eq!(root, root2);
+        assert_eq!(summary.total_blocks, 3);
+        assert_eq!(summary.unique_blocks, 0);
+        assert_eq!(store.lock().await.len(), 2);
+
+        Ok(())
+    }
+    #[tokio::test]
+    async fn test_missing_blocks() -> Result<()> {
+        let store: Arc<tokio::sync::Mutex<std::collections::HashMap<Cid, Bytes>>> =
+            Default::default();
+        let content: Vec<u8> = (0..3000).map(|i| (i % 251) as u8).collect();
+        let file = FileBuilder::new()
+            .name("foo.bin")
+            .fixed_chunker(1024)
+            .content_bytes(content)
+            .build()
+            .await?;
+        let dir = DirectoryBuilder::new()
+            .name("foo")
+            .add_file(file)
+            .build()
+            .await?;
+        let (root, _) = add_blocks_to_store_dedup(store.clone(), dir.encode()).await?;
+        assert!(missing_blocks(&store, root).await?.is_empty());
+
+        let file = store.get_links(root).await?.unwrap()[0];
+        let chunks = store.get_links(file).await?.unwrap();
+        assert_eq!(chunks.len(), 3);
+
+        store.lock().await.remove(&chunks[1]);
+        assert_eq!(missing_blocks(&store, root).await?, vec![chunks[1]]);
+
+        // blocks below a missing block are unknown
+        store.lock().await.remove(&file);
+        assert_eq!(missing_blocks(&store, root).await?, vec![file]);
+
+        store.lock().await.clear();
+        assert_eq!(missing_blocks(&store, root).await?, vec![root]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_has_full_dag() -> Result<()> {
+        let store: Arc<tokio::sync::Mutex<std::collections::HashMap<Cid, Bytes>>> =
+            Default::default();
+        let content: Vec<u8> = (0..3000).map(|i| (i % 251) as u8).collect();
+        let file = FileBuilder::new()
+            .name("foo.bin")
+            .fixed_chunker(1024)
+            .content_bytes(content)
+            .build()
+            .await?;
+        let dir = DirectoryBuilder::new()
+            .name("foo")
+            .add_file(file)
+            .build()
+            .await?;
+        let (root, _) = add_blocks_to_store_dedup(store.clone(), dir.encode()).await?;
+        assert!(has_full_dag(&store, root).await?);
+
+        // a single missing leaf makes the DAG incomplete
+        let file = store.get_links(root).await?.unwrap()[0];
+        let chunks = store.get_links(file).await?.unwrap();
+        store.lock().await.remove(&chunks[2]);
+        assert!(!has_full_dag(&store, root).await?);
+
+        store.lock().await.clear();
+        assert!(!has_full_dag(&store, root).await?);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_fetch_missing() -> Result<()> {
+        let remote: Arc<tokio::sync::Mutex<std::collections::HashMap<Cid, Bytes>>> =
+            Default::default();
+        let content: Vec<u8> = (0..3000).map(|i| (i % 251) as u8).collect();
+        let file = FileBuilder::new()
+            .name("foo.bin")
+            .fixed_chunker(1024)
+            .content_bytes(content)
+            .build()
+            .await?;
+        let dir = DirectoryBuilder::new()
+            .name("foo")
+            .add_file(file)
+            .build()
+            .await?;
+        let (root, _) = add_blocks_to_store_dedup(remote.clone(), dir.encode()).await?;
+        let fetch = |cid: Cid| {
+            let remote = remote.clone();
+            async move {
+                let remote = remote.lock().await;
+                remote.get(&cid).cloned().context("not found")
+            }
+        };
+
+        // start with the file missing, but one of its chunks present
+        let store: Arc<tokio::sync::Mutex<std::collections::HashMap<Cid, Bytes>>> =
+            Default::default();
+        let file = remote.get_links(root).await?.unwrap()[0];
+        let chunks = remote.get_links(file).await?.unwrap();
+        let chunk = remote.lock().await[&chunks[0]].clone();
+        store.put(chunks[0], chunk, Vec::new()).await?;
+
+        let fetched: Vec<Cid> = fetch_missing(store.clone(), root, fetch)
+            .try_collect()
+            .await?;
+        assert_eq!(fetched.len(), 4);
+        assert!(!fetched.contains(&chunks[0]));
+        assert_eq!(*store.lock().await, *remote.lock().await);
+
+        // nothing is left to fetch
+        let fetched: Vec<Cid> = fetch_missing(store.clone(), root, fetch)
+            .try_collect()
+            .await?;
+        assert!(fetched.is_empty());
+
+        // blocks that do not match their cid are rejected
+        store.lock().await.remove(&chunks[1]);
+        let corrupt = |_| async { anyhow::Ok(Bytes::from_static(b"corrupt")) };
+        let res: Result<Vec<Cid>> = fetch_missing(store.clone(), root, corrupt)
+            .try_collect()
+            .await;
+        assert!(res.is_err());
+        assert!(!store.has(chunks[1]).await?);
+
+        // each round continues below the blocks fetched in the previous one, so every block
+        // is only checked once
+        let store = CountingStore::default();
+        let fetched: Vec<Cid> = fetch_missing(store.clone(), root, fetch)
+            .try_collect()
+            .await?;
+        assert_eq!(fetched.len(), 5);
+        assert_eq!(store.checked.load(Ordering::SeqCst), 5);
+        assert_eq!(*store.inner.lock().await, *remote.lock().await);
+
+        Ok(())
+    }
+
+    /// A store that counts the cids whose presence is checked.
+    #[derive(Debug, Clone, Default)]
+    struct CountingStore {
+        inner: Arc<tokio::sync::Mutex<std::collections::HashMap<Cid, Bytes>>>,
+        checked: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl Store for CountingStore {
+        async fn has(&self, cid: Cid) -> Result<bool> {
+            self.checked.fetch_add(1, Ordering::SeqCst);
+            self.inner.has(cid).await
+        }
+        async fn has_many(&self, cids: &[Cid]) -> Result<Vec<bool>> {
+            self.checked.fetch_add(cids.len(), Ordering::SeqCst);
+            self.inner.has_many(cids).await
+        }
+        async fn put(&self, cid: Cid, blob: Bytes, links: Vec<Cid>) -> Result<()> {
+            self.inner.put(cid, blob, links).await
+        }
+        async fn put_many(&self, blocks: Vec<Block>) -> Result<()> {
+            self.inner.put_many(blocks).await
+        }
+        async fn get_links(&self, cid: Cid) -> Result<Option<Vec<Cid>>> {
+            self.inner.get_links(cid).await
+        }
+        fn list_cids(&self) -> BoxStream<'static, Result<Cid>> {
+            self.inner.list_cids()
+        }
+        async fn flush(&self) -> Result<()> {
+            self.inner.flush().await
+        }
+    }
+}
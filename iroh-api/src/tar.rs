@@ -0,0 +1,379 @@
+//! Streams UnixFS content as a tar archive.
+//!
+//! UnixFS nodes do not carry modes or modification times yet, so every entry gets the
+//! default mode for its type and a modification time of zero. Names that do not fit into
+//! the ustar header are written as PAX extended headers. Entry names that could be extracted
+//! outside of the archive's root directory, such as `..`, are rejected.
+use anyhow::{ensure, Context, Result};
+use cid::Cid;
+use futures::StreamExt;
+use iroh_resolver::resolver::{Path as IpfsPath, Resolver};
+use iroh_unixfs::content_loader::ContentLoader;
+use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+const BLOCK_SIZE: usize = 512;
+/// Maximum length of the name and link name fields of a ustar header.
+const NAME_LEN: usize = 100;
+/// Largest size that fits into the octal size field of a ustar header.
+const MAX_USTAR_SIZE: u64 = 0o77777777777;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum EntryType {
+    File,
+    Symlink,
+    Directory,
+    PaxHeader,
+}
+
+impl EntryType {
+    fn flag(self) -> u8 {
+        match self {
+            EntryType::File => b'0',
+            EntryType::Symlink => b'2',
+            EntryType::Directory => b'5',
+            EntryType::PaxHeader => b'x',
+        }
+    }
+
+    fn mode(self) -> u32 {
+        match self {
+            EntryType::File | EntryType::PaxHeader => 0o644,
+            EntryType::Symlink => 0o777,
+            EntryType::Directory => 0o755,
+        }
+    }
+}
+
+/// Writes the DAG at `root` as a tar archive, with all entries below a directory named
+/// after `root`.
+///
+/// Entries are written in the order the resolver traverses them, so parent directories
+/// always come before their content. File contents are copied as they are resolved,
+/// without buffering the archive.
+///
+/// Fails on the first entry whose name is not a single normal path component, see
+/// [`check_entry_name`]. Entries written before are not retracted.
+pub(crate) async fn write_tar<T, W>(resolver: &Resolver<T>, root: Cid, writer: &mut W) -> Result<()>
+where
+    T: ContentLoader + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let entries = resolver.resolve_recursive_with_paths(IpfsPath::from_cid(root));
+    tokio::pin!(entries);
+
+    let root_name = root.to_string();
+    while let Some(entry) = entries.next().await {
+        let (path, out) = entry?;
+        for name in path.tail() {
+            check_entry_name(name)?;
+        }
+        let relative = path.to_relative_string();
+        let name = if relative.is_empty() {
+            root_name.clone()
+        } else {
+            format!("{root_name}/{relative}")
+        };
+
+        if out.is_dir() {
+            let name = format!("{name}/");
+            write_header(writer, &name, EntryType::Directory, 0, None).await?;
+        } else if out.is_symlink() {
+            let mut reader = out.pretty(resolver.clone(), Default::default(), None)?;
+            let mut target = String::new();
+            reader.read_to_string(&mut target).await?;
+            write_header(writer, &name, EntryType::Symlink, 0, Some(&target)).await?;
+        } else {
+            let mut reader = out.pretty(resolver.clone(), Default::default(), None)?;
+            let size = reader
+                .size()
+                .with_context(|| format!("unknown size of {name}"))?;
+            write_header(writer, &name, EntryType::File, size, None).await?;
+            let written = tokio::io::copy(&mut reader, writer).await?;
+            ensure!(
+                written == size,
+                "{} has {} bytes, expected {}",
+                name,
+                written,
+                size
+            );
+            write_padding(writer, size).await?;
+        }
+    }
+
+    // the end of the archive is marked by two empty blocks
+    writer.write_all(&[0; 2 * BLOCK_SIZE]).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+/// Checks that `name`, the name of a link in the DAG, is a single normal path component, so
+/// extracting the archive can not write outside of its root directory.
+fn check_entry_name(name: &str) -> Result<()> {
+    ensure!(
+        !matches!(name, "" | "." | "..") && !name.contains(['/', '\\', '\0']),
+        "invalid entry name {:?}",
+        name
+    );
+    Ok(())
+}
+
+/// Writes the header of an entry, preceded by a PAX extended header if the name, link
+/// name or size do not fit into the ustar header.
+async fn write_header<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    name: &str,
+    typ: EntryType,
+    size: u64,
+    link: Option<&str>,
+) -> Result<()> {
+    let mut records = Vec::new();
+    if name.len() > NAME_LEN {
+        records.extend(pax_record("path", name));
+    }
+    if let Some(link) = link.filter(|link| link.len() > NAME_LEN) {
+        records.extend(pax_record("linkpath", link));
+    }
+    if size > MAX_USTAR_SIZE {
+        records.extend(pax_record("size", &size.to_string()));
+    }
+    if !records.is_empty() {
+        let len = records.len() as u64;
+        let header = encode_header("././@PaxHeader", EntryType::PaxHeader, len, None);
+        writer.write_all(&header).await?;
+        writer.write_all(&records).await?;
+        write_padding(writer, len).await?;
+    }
+
+    let ustar_size = if size > MAX_USTAR_SIZE { 0 } else { size };
+    writer
+        .write_all(&encode_header(name, typ, ustar_size, link))
+        .await?;
+    Ok(())
+}
+
+/// Pads content of `len` bytes to the next block boundary.
+async fn write_padding<W: AsyncWrite + Unpin>(writer: &mut W, len: u64) -> Result<()> {
+    let rest = (len % BLOCK_SIZE as u64) as usize;
+    if rest != 0 {
+        writer.write_all(&[0; BLOCK_SIZE][rest..]).await?;
+    }
+    Ok(())
+}
+
+/// Encodes a ustar header. Values that do not fit are truncated, the caller is expected
+/// to write them into a PAX header before.
+fn encode_header(name: &str, typ: EntryType, size: u64, link: Option<&str>) -> [u8; BLOCK_SIZE] {
+    let mut header = [0u8; BLOCK_SIZE];
+    copy_truncated(&mut header[0..100], name);
+    write_octal(&mut header[100..108], typ.mode() as u64);
+    // uid and gid
+    write_octal(&mut header[108..116], 0);
+    write_octal(&mut header[116..124], 0);
+    write_octal(&mut header[124..136], size);
+    // mtime
+    write_octal(&mut header[136..148], 0);
+    header[156] = typ.flag();
+    if let Some(link) = link {
+        copy_truncated(&mut header[157..257], link);
+    }
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+
+    // the checksum is computed with its own field filled with spaces
+    header[148..156].fill(b' ');
+    let checksum: u32 = header.iter().map(|b| *b as u32).sum();
+    header[148..156].copy_from_slice(format!("{checksum:06o}\0 ").as_bytes());
+    header
+}
+
+fn copy_truncated(field: &mut [u8], value: &str) {
+    let len = value.len().min(field.len());
+    field[..len].copy_from_slice(&value.as_bytes()[..len]);
+}
+
+/// Writes `value` as zero padded octal number, terminated by a NUL byte.
+fn write_octal(field: &mut [u8], value: u64) {
+    let value = format!("{:0width$o}", value, width = field.len() - 1);
+    field[..value.len()].copy_from_slice(value.as_bytes());
+}
+
+/// Encodes a PAX record, `"<len> <key>=<value>\n"`, where `len` includes its own digits.
+fn pax_record(key: &str, value: &str) -> Vec<u8> {
+    let rest = key.len() + value.len() + 3;
+    let mut len = rest + rest.to_string().len();
+    while len != rest + len.to_string().len() {
+        len = rest + len.to_string().len();
+    }
+    format!("{len} {key}={value}\n").into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use iroh_resolver::resolver::stream_to_resolver;
+    use iroh_unixfs::builder::{DirectoryBuilder, FileBuilder, SymlinkBuilder};
+
+    #[derive(Debug, PartialEq)]
+    struct TarEntry {
+        name: String,
+        flag: u8,
+        link: String,
+        content: Vec<u8>,
+    }
+
+    fn parse_str(field: &[u8]) -> String {
+        let end = field.iter().position(|b| *b == 0).unwrap_or(field.len());
+        String::from_utf8(field[..end].to_vec()).unwrap()
+    }
+
+    fn parse_octal(field: &[u8]) -> u64 {
+        u64::from_str_radix(parse_str(field).trim(), 8).unwrap()
+    }
+
+    /// Reads back the archives written by `write_tar`.
+    fn parse_tar(mut data: &[u8]) -> Vec<TarEntry> {
+        let mut entries = Vec::new();
+        let mut pax = Vec::new();
+        loop {
+            let (header, rest) = data.split_at(BLOCK_SIZE);
+            if header.iter().all(|b| *b == 0) {
+                assert!(rest.len() == BLOCK_SIZE && rest.iter().all(|b| *b == 0));
+                return entries;
+            }
+            let mut checked = header.to_vec();
+            checked[148..156].fill(b' ');
+            let checksum: u64 = checked.iter().map(|b| *b as u64).sum();
+            assert_eq!(parse_octal(&header[148..155]), checksum);
+            assert_eq!(&header[257..265], b"ustar\x0000");
+
+            let size = parse_octal(&header[124..136]) as usize;
+            let padded = (size + BLOCK_SIZE - 1) / BLOCK_SIZE * BLOCK_SIZE;
+            let content = rest[..size].to_vec();
+            data = &rest[padded..];
+
+            if header[156] == b'x' {
+                pax = content;
+                continue;
+            }
+            let mut entry = TarEntry {
+                name: parse_str(&header[0..100]),
+                flag: header[156],
+                link: parse_str(&header[157..257]),
+                content,
+            };
+            for record in String::from_utf8(std::mem::take(&mut pax)).unwrap().lines() {
+                let (_, record) = record.split_once(' ').unwrap();
+                match record.split_once('=').unwrap() {
+                    ("path", path) => entry.name = path.to_string(),
+                    ("linkpath", link) => entry.link = link.to_string(),
+                    record => panic!("unexpected pax record {record:?}"),
+                }
+            }
+            entries.push(entry);
+        }
+    }
+
+    #[test]
+    fn test_pax_record() {
+        assert_eq!(pax_record("path", "foo"), b"12 path=foo\n");
+        // the length gains a digit by counting itself
+        assert_eq!(pax_record("a", "xxxxx"), b"11 a=xxxxx\n");
+        assert_eq!(pax_record("a", "xxxx"), b"9 a=xxxx\n");
+    }
+
+    #[test]
+    fn test_encode_header() {
+        let header = encode_header("foo/bar.txt", EntryType::File, 1234, None);
+        assert_eq!(parse_str(&header[0..100]), "foo/bar.txt");
+        assert_eq!(&header[100..108], b"0000644\0");
+        assert_eq!(&header[124..136], b"00000002322\0");
+        assert_eq!(header[156], b'0');
+
+        let mut checked = header;
+        checked[148..156].fill(b' ');
+        let checksum: u32 = checked.iter().map(|b| *b as u32).sum();
+        assert_eq!(&header[148..156], format!("{checksum:06o}\0 ").as_bytes());
+    }
+
+    #[test]
+    fn test_check_entry_name() {
+        for name in ["foo", "foo.txt", ".hidden", "..foo", "foo.."] {
+            assert!(check_entry_name(name).is_ok(), "{name}");
+        }
+        for name in [
+            "", ".", "..", "../foo", "foo/..", "/etc", "foo\\bar", "foo\0",
+        ] {
+            assert!(check_entry_name(name).is_err(), "{name:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_write_tar_rejects_traversal() -> Result<()> {
+        let file = FileBuilder::new()
+            .name("../escaped.txt")
+            .content_bytes(&b"hello world"[..])
+            .build()
+            .await?;
+        let dir = DirectoryBuilder::new().add_file(file).build().await?;
+        let (root, resolver) = stream_to_resolver(dir.encode()).await?;
+
+        let mut out = Vec::new();
+        let err = write_tar(&resolver, root, &mut out).await.unwrap_err();
+        assert!(err.to_string().contains("invalid entry name"), "{err}");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_write_tar() -> Result<()> {
+        let long_name = "x".repeat(120);
+        let hello = FileBuilder::new()
+            .name("hello.txt")
+            .content_bytes(&b"hello world"[..])
+            .build()
+            .await?;
+        let long = FileBuilder::new()
+            .name(long_name.clone())
+            .content_bytes(vec![1u8; 1000])
+            .build()
+            .await?;
+        let mut link = SymlinkBuilder::new("link");
+        link.target("hello.txt");
+        let sharded = DirectoryBuilder::new()
+            .name("sharded")
+            .hamt()
+            .add_file(long)
+            .build()
+            .await?;
+        let dir = DirectoryBuilder::new()
+            .add_file(hello)
+            .add_symlink(link.build().await?)
+            .add_dir(sharded)?
+            .build()
+            .await?;
+        let (root, resolver) = stream_to_resolver(dir.encode()).await?;
+
+        let mut out = Vec::new();
+        write_tar(&resolver, root, &mut out).await?;
+        assert_eq!(out.len() % BLOCK_SIZE, 0);
+
+        let mut entries = parse_tar(&out);
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        let entry = |name: &str, flag: u8, link: &str, content: &[u8]| TarEntry {
+            name: name.replace("ROOT", &root.to_string()),
+            flag,
+            link: link.to_string(),
+            content: content.to_vec(),
+        };
+        assert_eq!(
+            entries,
+            vec![
+                entry("ROOT/", b'5', "", b""),
+                entry("ROOT/hello.txt", b'0', "", b"hello world"),
+                entry("ROOT/link", b'2', "hello.txt", b""),
+                entry("ROOT/sharded/", b'5', "", b""),
+                entry(&format!("ROOT/sharded/{long_name}"), b'0', "", &[1u8; 1000]),
+            ]
+        );
+        Ok(())
+    }
+}
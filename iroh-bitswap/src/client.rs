@@ -1,18 +1,25 @@
-use std::{sync::Arc, time::Duration};
+use std::{collections::VecDeque, sync::Arc, time::Duration};
 
 use iroh_metrics::bitswap::BitswapMetrics;
 use iroh_metrics::{core::MRecorder, inc, record};
 
-use ahash::AHashSet;
-use anyhow::Result;
+use ahash::{AHashMap, AHashSet};
+use anyhow::{bail, ensure, Result};
 use cid::Cid;
 use derivative::Derivative;
 use futures::future::BoxFuture;
 use libp2p::PeerId;
+use tokio::sync::{broadcast, oneshot};
 use tracing::{debug, error, info, warn};
 
-use crate::{block::Block, message::BitswapMessage, network::Network, Store};
+use crate::{
+    block::Block,
+    message::{BitswapMessage, Priority},
+    network::Network,
+    ConnState, Store,
+};
 
+use self::message_queue::Config as MessageQueueConfig;
 use self::session::BlockReceiver;
 use self::{peer_manager::PeerManager, session::Session, session_manager::SessionManager};
 
@@ -20,18 +27,78 @@ mod block_presence_manager;
 mod message_queue;
 mod peer_manager;
 mod peer_want_manager;
+mod preferred_peers;
 mod session;
 mod session_interest_manager;
 mod session_manager;
 pub(crate) mod wantlist;
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+pub use self::session_interest_manager::WantlistDelta;
+
+/// How long to wait for the answer to [`Client::want_have_from`].
+pub(crate) const WANT_HAVE_FROM_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Pending [`Client::want_have_from`] requests, by the asked peer and key.
+type WantHaveWaiters = AHashMap<(PeerId, Cid), Vec<oneshot::Sender<bool>>>;
+
+/// How many distinct providers may send an invalid block for a key, before requests for
+/// it fail. Until then, the want is sent to other providers.
+pub(crate) const MAX_INVALID_PROVIDERS: usize = 3;
+
+/// Pending [`Client::get_block_with_session_id`] requests, notified once the key was
+/// rejected too often, by key.
+type RejectedWaiters = AHashMap<Cid, Vec<oneshot::Sender<()>>>;
+
+/// How many received blocks [`Client::block_origin`] remembers the sender of.
+const MAX_BLOCK_ORIGINS: usize = 4096;
+
+/// The peers the most recently received blocks came from.
+#[derive(Debug, Default)]
+struct BlockOrigins {
+    peers: AHashMap<Cid, PeerId>,
+    /// Keys in the order they were first received, to forget the oldest.
+    order: VecDeque<Cid>,
+}
+
+impl BlockOrigins {
+    fn insert(&mut self, key: Cid, peer: PeerId) {
+        if self.peers.insert(key, peer).is_none() {
+            self.order.push_back(key);
+            if self.order.len() > MAX_BLOCK_ORIGINS {
+                if let Some(oldest) = self.order.pop_front() {
+                    self.peers.remove(&oldest);
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct Config {
     /// Overwrites the global provider search delay
     pub provider_search_delay: Duration,
     /// Overwrites the global rebroadcast delay
     pub rebroadcast_delay: Duration,
+    /// How long to wait for a single block, after sending a want-block for it to a peer,
+    /// before asking another provider. `None`, the default, disables the per block timeout.
+    pub per_block_timeout: Option<Duration>,
     pub simluate_donthaves_on_timeout: bool,
+    /// How the priority of wants decreases over time, off by default.
+    pub priority_decay: PriorityDecay,
+    /// Whether peers are synced with our whole wantlist, on by default.
+    ///
+    /// Newly connected peers are sent all live broadcast wants, and resyncing with a peer,
+    /// see [`Client::reset_peer`], sends a full wantlist that replaces what the peer
+    /// tracked for us. With a huge wantlist and many peers, most of which have none of the
+    /// blocks, this is a lot of redundant traffic. When disabled, peers only learn about
+    /// the wants of sessions they are part of and about new broadcasts, and a resync only
+    /// resends the wants sent to the peer before, without clearing stale ones it kept.
+    pub send_full_wantlist: bool,
+    /// How many unsolicited blocks of a single message are passed on to be stored, in a
+    /// [`BitswapEvent::UnsolicitedBlocks`], 16 by default. `0` drops them all.
+    ///
+    /// [`BitswapEvent::UnsolicitedBlocks`]: crate::BitswapEvent::UnsolicitedBlocks
+    pub max_unsolicited_blocks: usize,
 }
 
 impl Default for Config {
@@ -39,7 +106,46 @@ impl Default for Config {
         Config {
             provider_search_delay: Duration::from_secs(1),
             rebroadcast_delay: Duration::from_secs(60),
+            per_block_timeout: None,
             simluate_donthaves_on_timeout: true,
+            priority_decay: PriorityDecay::Off,
+            send_full_wantlist: true,
+            max_unsolicited_blocks: 16,
+        }
+    }
+}
+
+/// How the priority of a want decreases while it is waiting to be sent to a peer.
+///
+/// The age of a want is the time since it was added to the queue of a peer. Wants are
+/// sent in the order of their decayed priority, which is also the priority the peer
+/// receives, so newer wants outrank older ones with the same initial priority. Wants that
+/// are rebroadcast keep decaying, until they are refreshed with
+/// [`Client::refresh_wants`], which resets their age and thereby their priority.
+#[derive(Debug, Default, Clone, Copy)]
+pub enum PriorityDecay {
+    /// Wants keep their priority.
+    #[default]
+    Off,
+    /// The priority drops by `step` for every full `interval` of age.
+    Linear { interval: Duration, step: Priority },
+    /// The priority is computed from the initial priority and the age of the want.
+    Custom(fn(Priority, Duration) -> Priority),
+}
+
+impl PriorityDecay {
+    /// Returns the priority of a want, that was added `age` ago with `priority`.
+    pub fn apply(&self, priority: Priority, age: Duration) -> Priority {
+        match self {
+            PriorityDecay::Off => priority,
+            PriorityDecay::Linear { interval, step } => {
+                if interval.is_zero() {
+                    return priority;
+                }
+                let intervals = (age.as_nanos() / interval.as_nanos()).min(i32::MAX as u128);
+                priority.saturating_sub((intervals as i32).saturating_mul(*step))
+            }
+            PriorityDecay::Custom(decay) => decay(priority, age),
         }
     }
 }
@@ -63,10 +169,15 @@ pub struct Client<S: Store> {
     session_manager: SessionManager,
     provider_search_delay: Duration,
     rebroadcast_delay: Duration,
+    per_block_timeout: Option<Duration>,
     simulate_dont_haves_on_timeout: bool,
+    max_unsolicited_blocks: usize,
     #[derivative(Debug = "ignore")]
     blocks_received_cb: Option<Arc<Box<BlocksReceivedCb>>>,
     notify: async_broadcast::Sender<Block>,
+    want_have_waiters: Arc<std::sync::Mutex<WantHaveWaiters>>,
+    rejected_waiters: Arc<std::sync::Mutex<RejectedWaiters>>,
+    block_origins: Arc<std::sync::Mutex<BlockOrigins>>,
 }
 
 pub type BlocksReceivedCb =
@@ -107,7 +218,13 @@ impl<S: Store> Client<S> {
             }
         });
 
-        let session_manager = SessionManager::new(self_id, network.clone(), notify.clone()).await;
+        let queue_config = MessageQueueConfig {
+            priority_decay: config.priority_decay,
+            send_full_wantlist: config.send_full_wantlist,
+            ..Default::default()
+        };
+        let session_manager =
+            SessionManager::new(self_id, network.clone(), notify.clone(), queue_config).await;
 
         Client {
             network,
@@ -115,9 +232,14 @@ impl<S: Store> Client<S> {
             session_manager,
             provider_search_delay: config.provider_search_delay,
             rebroadcast_delay: config.rebroadcast_delay,
+            per_block_timeout: config.per_block_timeout,
             simulate_dont_haves_on_timeout: config.simluate_donthaves_on_timeout,
+            max_unsolicited_blocks: config.max_unsolicited_blocks,
             blocks_received_cb: blocks_received_cb.map(Arc::new),
             notify,
+            want_have_waiters: Default::default(),
+            rejected_waiters: Default::default(),
+            block_origins: Default::default(),
         }
     }
 
@@ -135,17 +257,69 @@ impl<S: Store> Client<S> {
         block
     }
 
+    /// Retrieves a block within the session `session_id`.
+    ///
+    /// Wants are sent with the given `priority`, or the default ordering if `None`.
+    /// At most `max_providers` are searched for the block, or the default limit if `None`.
+    /// Both only apply to this block, not to other blocks of the session.
+    ///
+    /// Providers sending an invalid block are not asked for it again, and the block is
+    /// requested from other providers instead. Fails once `MAX_INVALID_PROVIDERS` providers
+    /// sent an invalid block.
+    ///
+    /// Sessions wanting the same block share a single want, and all of them are completed
+    /// by the block once it is received, not just the one that asked first.
     pub async fn get_block_with_session_id(
         &self,
         session_id: u64,
         key: &Cid,
         providers: &[PeerId],
+        priority: Option<Priority>,
+        max_providers: Option<usize>,
     ) -> Result<Block> {
         let session = self.get_or_create_session(session_id).await;
         for provider in providers {
             session.add_provider(key, *provider).await;
         }
-        session.get_block(key).await
+
+        let (s, r) = oneshot::channel();
+        self.rejected_waiters
+            .lock()
+            .unwrap()
+            .entry(*key)
+            .or_default()
+            .push(s);
+
+        // the key may have been rejected before our waiter was registered
+        let presence = self.session_manager.block_presence_manager();
+        let block = if presence.invalid_providers(key).await >= MAX_INVALID_PROVIDERS {
+            None
+        } else {
+            tokio::select! {
+                block = session.get_block_with(key, priority, max_providers) => Some(block),
+                Ok(()) = r => None,
+            }
+        };
+
+        // drop our waiter, if it was not notified
+        {
+            let waiters = &mut *self.rejected_waiters.lock().unwrap();
+            if let Some(senders) = waiters.get_mut(key) {
+                senders.retain(|s| !s.is_closed());
+                if senders.is_empty() {
+                    waiters.remove(key);
+                }
+            }
+        }
+
+        match block {
+            Some(block) => block,
+            None => bail!(
+                "block {} failed verification from {} providers",
+                key,
+                MAX_INVALID_PROVIDERS
+            ),
+        }
     }
 
     pub async fn get_blocks_with_session_id(
@@ -157,6 +331,14 @@ impl<S: Store> Client<S> {
         session.get_blocks(keys).await
     }
 
+    /// Returns the peer the block `key` was last received from, if it was received recently.
+    ///
+    /// Only the senders of the last 4096 wanted blocks are remembered. Blocks that were only
+    /// added locally, with [`Client::notify_new_blocks`], have no origin.
+    pub fn block_origin(&self, key: &Cid) -> Option<PeerId> {
+        self.block_origins.lock().unwrap().peers.get(key).copied()
+    }
+
     /// Returns a channel where the caller may receive blocks that correspond to the
     /// provided `keys`.
     pub async fn get_blocks(&self, keys: &[Cid]) -> Result<BlockReceiver> {
@@ -164,6 +346,109 @@ impl<S: Store> Client<S> {
         session.get_blocks(keys).await
     }
 
+    /// Sends a want-have for `key` only to `peer`, and waits for its answer.
+    ///
+    /// Returns `true` on `HAVE` and `false` on `DONT_HAVE`. Answers already received
+    /// from the peer, e.g. in a session, are returned without asking again.
+    pub async fn want_have_from(
+        &self,
+        key: &Cid,
+        priority: Option<Priority>,
+        peer: &PeerId,
+    ) -> Result<bool> {
+        let presence = self.session_manager.block_presence_manager();
+        if presence.peer_has_block(peer, key).await {
+            return Ok(true);
+        }
+        if presence.peer_does_not_have_block(peer, key).await {
+            return Ok(false);
+        }
+        ensure!(
+            self.peer_manager().connected_peers().await.contains(peer),
+            "peer {} is not connected",
+            peer
+        );
+
+        let (s, r) = oneshot::channel();
+        self.want_have_waiters
+            .lock()
+            .unwrap()
+            .entry((*peer, *key))
+            .or_default()
+            .push(s);
+        self.peer_manager()
+            .send_wants(peer, &[], &[*key], priority)
+            .await;
+
+        let res = tokio::time::timeout(WANT_HAVE_FROM_TIMEOUT, r).await;
+
+        // drop our waiter, if it was not resolved
+        {
+            let waiters = &mut *self.want_have_waiters.lock().unwrap();
+            if let Some(senders) = waiters.get_mut(&(*peer, *key)) {
+                senders.retain(|s| !s.is_closed());
+                if senders.is_empty() {
+                    waiters.remove(&(*peer, *key));
+                }
+            }
+        }
+
+        // only cancel the want, if no session is waiting for it
+        let interested = self
+            .session_manager
+            .session_interest_manager()
+            .interested_sessions(&[], &[*key], &[])
+            .await;
+        if interested.is_empty() {
+            self.peer_manager().send_cancels(&[*key]).await;
+        }
+
+        match res {
+            Ok(Ok(have)) => Ok(have),
+            Ok(Err(_)) => bail!("want-have {} from {}: client shut down", key, peer),
+            Err(_) => bail!("want-have {} from {}: timed out", key, peer),
+        }
+    }
+
+    /// Resolves pending [`Client::want_have_from`] requests answered in this message.
+    async fn resolve_want_haves(
+        &self,
+        peer: &PeerId,
+        incoming: &BitswapMessage,
+        haves: &[Cid],
+        dont_haves: &[Cid],
+    ) {
+        let answers: Vec<(Cid, bool)> = {
+            let waiters = &mut *self.want_have_waiters.lock().unwrap();
+            if waiters.is_empty() {
+                return;
+            }
+            incoming
+                .blocks()
+                .map(|b| *b.cid())
+                .chain(haves.iter().copied())
+                .map(|key| (key, true))
+                .chain(dont_haves.iter().map(|key| (*key, false)))
+                .filter_map(|(key, have)| {
+                    let senders = waiters.remove(&(*peer, key))?;
+                    for s in senders {
+                        s.send(have).ok();
+                    }
+                    Some((key, have))
+                })
+                .collect()
+        };
+
+        for (key, have) in answers {
+            if let Err(err) = self.network.peer_have(*peer, key, have).await {
+                warn!(
+                    "failed to report want-have {} from {}: {:?}",
+                    key, peer, err
+                );
+            }
+        }
+    }
+
     /// Announces the existence of blocks to this bitswap service.
     /// Bitswap itself doesn't store new blocks. It's the caller responsibility to ensure
     /// that those blocks are available in the blockstore before calling this function.
@@ -204,9 +489,20 @@ impl<S: Store> Client<S> {
             .split_wanted_unwanted(&blocks)
             .await;
 
-        for block in not_wanted {
+        // Unsolicited blocks are not announced, so they can not complete any request. Some
+        // of them are passed on to be stored, in case they are wanted later.
+        for block in &not_wanted {
+            inc!(BitswapMetrics::UnsolicitedBlocks);
             debug!("recv block not in wantlist: {} from {}", block.cid(), from);
         }
+        if self.max_unsolicited_blocks > 0 && !not_wanted.is_empty() {
+            let blocks = not_wanted
+                .iter()
+                .take(self.max_unsolicited_blocks)
+                .map(|block| (*block).clone())
+                .collect();
+            self.network.unsolicited_blocks(*from, blocks);
+        }
 
         // Inform the PeerManager so that we can calculate per-peer latency.
         let mut combined = all_keys.clone();
@@ -222,6 +518,13 @@ impl<S: Store> Client<S> {
             .receive_from(Some(*from), &all_keys, haves, dont_haves)
             .await;
 
+        {
+            let origins = &mut *self.block_origins.lock().unwrap();
+            for block in &wanted {
+                origins.insert(*block.cid(), *from);
+            }
+        }
+
         info!("recv_msg broadcast");
         // Publish the blocks
         for block in &wanted {
@@ -237,6 +540,36 @@ impl<S: Store> Client<S> {
         Ok(())
     }
 
+    /// Called by the network interface when `peer` sent blocks that failed verification.
+    ///
+    /// The peer is treated as not having these blocks, so sessions send their wants to
+    /// other providers, and is not asked for them again.
+    pub async fn receive_invalid_blocks(&self, peer: &PeerId, keys: &[Cid]) {
+        let presence = self.session_manager.block_presence_manager();
+        let mut rejected = Vec::new();
+        for key in keys {
+            warn!("invalid block {} from {}", key, peer);
+            if presence.receive_invalid(peer, key).await >= MAX_INVALID_PROVIDERS {
+                rejected.push(*key);
+            }
+        }
+
+        // The peer answered the want, make sure it is not sent to it again.
+        self.peer_manager().send_peer_cancels(peer, keys).await;
+        self.session_manager
+            .receive_from(Some(*peer), &[][..], &[][..], keys)
+            .await;
+
+        if !rejected.is_empty() {
+            let waiters = &mut *self.rejected_waiters.lock().unwrap();
+            for key in rejected {
+                for s in waiters.remove(&key).unwrap_or_default() {
+                    s.send(()).ok();
+                }
+            }
+        }
+    }
+
     /// Called by the network interface when a new message is received.
     pub async fn receive_message(&self, peer: &PeerId, incoming: &BitswapMessage) {
         inc!(BitswapMetrics::MessagesProcessingClient);
@@ -259,6 +592,9 @@ impl<S: Store> Client<S> {
         let haves: Vec<Cid> = incoming.haves().copied().collect();
         let dont_haves: Vec<Cid> = incoming.dont_haves().copied().collect();
 
+        self.resolve_want_haves(peer, incoming, &haves, &dont_haves)
+            .await;
+
         if incoming.blocks_len() > 0 || !haves.is_empty() || !dont_haves.is_empty() {
             // Process blocks
             if let Err(err) = self
@@ -276,8 +612,21 @@ impl<S: Store> Client<S> {
     }
 
     /// Called by the network interface when a peer closes a connection.
-    pub async fn peer_disconnected(&self, peer: &PeerId) {
-        self.peer_manager().disconnected(peer).await;
+    pub async fn peer_disconnected(&self, peer: &PeerId, reason: ConnState) {
+        self.peer_manager().disconnected(peer, reason).await;
+    }
+
+    /// Resends the full wantlist to the given peer with the next message.
+    pub async fn reset_peer(&self, peer: &PeerId) {
+        self.peer_manager().resend_wantlist(peer).await;
+    }
+
+    /// Resets the age of the wants for `keys`, restoring their initial priority, and sends
+    /// them again with it.
+    ///
+    /// Only has an effect if [`Config::priority_decay`] is enabled.
+    pub async fn refresh_wants(&self, keys: &[Cid]) {
+        self.peer_manager().refresh_wants(keys).await;
     }
 
     /// Returns the current local wantlist (both want-blocks and want-haves).
@@ -295,10 +644,25 @@ impl<S: Store> Client<S> {
         self.peer_manager().current_want_haves().await
     }
 
+    /// Subscribes to the changes of the blocks wanted by sessions.
+    pub fn wantlist_changes(&self) -> broadcast::Receiver<WantlistDelta> {
+        self.session_manager.session_interest_manager().subscribe()
+    }
+
     fn peer_manager(&self) -> &PeerManager {
         self.session_manager.peer_manager()
     }
 
+    #[cfg(test)]
+    pub(crate) fn session_manager(&self) -> &SessionManager {
+        &self.session_manager
+    }
+
+    /// Forgets all sessions, so they stop once no longer in use.
+    pub(crate) fn close_sessions(&self) {
+        self.session_manager.close_sessions();
+    }
+
     /// Creates a new Bitswap session. You should use this, rather
     /// that calling `get_blocks`. Any time you intend to do several related
     /// block requests in a row. The session returned will have it's own `get_blocks`
@@ -306,7 +670,11 @@ impl<S: Store> Client<S> {
     /// be more efficient in its requests to peers.
     pub async fn new_session(&self) -> Session {
         self.session_manager
-            .new_session(self.provider_search_delay, self.rebroadcast_delay)
+            .new_session(
+                self.provider_search_delay,
+                self.rebroadcast_delay,
+                self.per_block_timeout,
+            )
             .await
     }
 
@@ -316,6 +684,7 @@ impl<S: Store> Client<S> {
                 session_id,
                 self.provider_search_delay,
                 self.rebroadcast_delay,
+                self.per_block_timeout,
             )
             .await
     }
@@ -328,6 +697,36 @@ impl<S: Store> Client<S> {
         Ok(())
     }
 
+    /// Pauses sending wants for the given session, see [`Session::pause`].
+    pub async fn pause_session(&self, session_id: u64) -> Result<()> {
+        if let Some(session) = self.session_manager.get_session(session_id).await {
+            session.pause().await?;
+        }
+
+        Ok(())
+    }
+
+    /// Asks `peer` first for the blocks wanted next, see [`Bitswap::mark_preferred`].
+    ///
+    /// [`Bitswap::mark_preferred`]: crate::Bitswap::mark_preferred
+    pub fn mark_preferred_peer(&self, peer: PeerId) {
+        self.session_manager.preferred_peers().mark(peer);
+    }
+
+    /// Removes the preference for `peer`, returning whether it had one.
+    pub fn clear_preferred_peer(&self, peer: &PeerId) -> bool {
+        self.session_manager.preferred_peers().clear(peer)
+    }
+
+    /// Resumes a paused session, see [`Session::resume`].
+    pub async fn resume_session(&self, session_id: u64) -> Result<()> {
+        if let Some(session) = self.session_manager.get_session(session_id).await {
+            session.resume().await?;
+        }
+
+        Ok(())
+    }
+
     /// Returns aggregated statistics about bitswap operations.
     pub async fn stat(&self) -> Result<Stat> {
         todo!()
@@ -1,6 +1,6 @@
 use std::sync::Arc;
 
-use ahash::AHashMap;
+use ahash::{AHashMap, AHashSet};
 use cid::Cid;
 use libp2p::PeerId;
 use tokio::sync::RwLock;
@@ -8,6 +8,8 @@ use tokio::sync::RwLock;
 #[derive(Debug, Clone, Default)]
 pub struct BlockPresenceManager {
     presence: Arc<RwLock<AHashMap<Cid, AHashMap<PeerId, bool>>>>,
+    /// Peers that sent an invalid block, by the cid of the block.
+    invalid: Arc<RwLock<AHashMap<Cid, AHashSet<PeerId>>>>,
 }
 
 impl BlockPresenceManager {
@@ -16,10 +18,16 @@ impl BlockPresenceManager {
     }
 
     /// Called when a peer sends us information about which blocks it has and does not have.
+    ///
+    /// `HAVE`s from peers that sent an invalid block for the same cid are ignored.
     pub async fn receive_from(&self, peer: &PeerId, haves: &[Cid], dont_haves: &[Cid]) {
+        let invalid = &*self.invalid.read().await;
         let presence = &mut *self.presence.write().await;
 
         for key in haves {
+            if is_invalid(invalid, peer, key) {
+                continue;
+            }
             update_block_presence(presence, peer, key, true);
         }
         for key in dont_haves {
@@ -27,6 +35,30 @@ impl BlockPresenceManager {
         }
     }
 
+    /// Called when a peer sends us an invalid block for `cid`.
+    ///
+    /// The peer is recorded as not having the block, even if it sent a `HAVE` before, and
+    /// any further `HAVE`s from it for `cid` are ignored.
+    /// Returns the number of distinct peers that sent an invalid block for `cid`.
+    pub async fn receive_invalid(&self, peer: &PeerId, cid: &Cid) -> usize {
+        let invalid = &mut *self.invalid.write().await;
+        let presence = &mut *self.presence.write().await;
+
+        presence.entry(*cid).or_default().insert(*peer, false);
+        let peers = invalid.entry(*cid).or_default();
+        peers.insert(*peer);
+        peers.len()
+    }
+
+    /// Returns the number of distinct peers that sent an invalid block for `cid`.
+    pub async fn invalid_providers(&self, cid: &Cid) -> usize {
+        let invalid = self.invalid.read().await;
+        invalid
+            .get(cid)
+            .map(|peers| peers.len())
+            .unwrap_or_default()
+    }
+
     /// Indicates wether the given peer has sent a `HAVE` for the given `cid`.
     pub async fn peer_has_block(&self, peer: &PeerId, cid: &Cid) -> bool {
         let presence = self.presence.read().await;
@@ -76,8 +108,10 @@ impl BlockPresenceManager {
 
     /// Cleans up the given keys.
     pub async fn remove_keys(&self, keys: &[Cid]) {
+        let invalid = &mut *self.invalid.write().await;
         let presence = &mut *self.presence.write().await;
         for key in keys {
+            invalid.remove(key);
             presence.remove(key);
         }
     }
@@ -89,6 +123,13 @@ impl BlockPresenceManager {
     }
 }
 
+fn is_invalid(invalid: &AHashMap<Cid, AHashSet<PeerId>>, peer: &PeerId, key: &Cid) -> bool {
+    invalid
+        .get(key)
+        .map(|peers| peers.contains(peer))
+        .unwrap_or_default()
+}
+
 fn update_block_presence(
     presence: &mut AHashMap<Cid, AHashMap<PeerId, bool>>,
     peer: &PeerId,
@@ -349,6 +390,42 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn test_receive_invalid() {
+        let bpm = BlockPresenceManager::new();
+
+        let p0 = PeerId::random();
+        let p1 = PeerId::random();
+        let cids = gen_cids(2);
+        let c0 = cids[0];
+        let c1 = cids[1];
+
+        bpm.receive_from(&p0, &[c0, c1][..], &[][..]).await;
+        assert!(bpm.peer_has_block(&p0, &c0).await);
+
+        // An invalid block overrides the earlier HAVE
+        assert_eq!(bpm.receive_invalid(&p0, &c0).await, 1);
+        assert!(!bpm.peer_has_block(&p0, &c0).await);
+        assert!(bpm.peer_does_not_have_block(&p0, &c0).await);
+
+        // Later HAVEs from the peer are ignored, only for the invalid cid
+        bpm.receive_from(&p0, &[c0][..], &[][..]).await;
+        assert!(bpm.peer_does_not_have_block(&p0, &c0).await);
+        assert!(bpm.peer_has_block(&p0, &c1).await);
+
+        // Peers are counted once
+        assert_eq!(bpm.receive_invalid(&p0, &c0).await, 1);
+        assert_eq!(bpm.receive_invalid(&p1, &c0).await, 2);
+        assert_eq!(bpm.invalid_providers(&c0).await, 2);
+        assert_eq!(bpm.invalid_providers(&c1).await, 0);
+
+        // Removing the key clears the invalid peers
+        bpm.remove_keys(&[c0][..]).await;
+        bpm.receive_from(&p0, &[c0][..], &[][..]).await;
+        assert!(bpm.peer_has_block(&p0, &c0).await);
+        assert_eq!(bpm.receive_invalid(&p1, &c0).await, 1);
+    }
+
     fn gen_cids(n: usize) -> Vec<Cid> {
         (0..n).map(|_| *create_random_block_v1().cid()).collect()
     }
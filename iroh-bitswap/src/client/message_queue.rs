@@ -13,13 +13,13 @@ use tokio::{sync::mpsc, task::JoinHandle};
 use tracing::{debug, error, warn};
 
 use crate::{
-    message::{BitswapMessage, Entry, WantType},
+    message::{BitswapMessage, Entry, Priority, WantType},
     network::{MessageSender, MessageSenderConfig, Network},
 };
 
 use self::{dont_have_timeout_manager::DontHaveTimeoutManager, wantlist::Wants};
 
-use super::peer_manager::DontHaveTimeout;
+use super::{peer_manager::DontHaveTimeout, PriorityDecay};
 
 mod dont_have_timeout_manager;
 mod wantlist;
@@ -38,8 +38,11 @@ enum WantsUpdate {
     Wants {
         want_blocks: Vec<Cid>,
         want_haves: Vec<Cid>,
+        priority: Option<Priority>,
     },
     Cancels(AHashSet<Cid>),
+    ResendWantlist,
+    RefreshWants(Vec<Cid>),
     #[cfg(test)]
     #[allow(dead_code)]
     GetWants(tokio::sync::oneshot::Sender<Wants>),
@@ -63,6 +66,11 @@ pub struct Config {
     pub send_message_debounce: Duration,
     pub send_timeout: Duration,
     pub max_retries: usize,
+    /// How the priority of pending wants decreases over time.
+    pub priority_decay: PriorityDecay,
+    /// Whether the whole wantlist is sent when (re)syncing with a peer, see
+    /// [`crate::client::Config::send_full_wantlist`].
+    pub send_full_wantlist: bool,
 }
 
 impl Default for Config {
@@ -78,19 +86,13 @@ impl Default for Config {
             send_message_debounce: Duration::from_millis(1),
             send_timeout: Duration::from_secs(30),
             max_retries: 3,
+            priority_decay: PriorityDecay::Off,
+            send_full_wantlist: true,
         }
     }
 }
 
 impl MessageQueue {
-    pub async fn new(
-        peer: PeerId,
-        network: Network,
-        on_dont_have_timeout: Arc<dyn DontHaveTimeout>,
-    ) -> Self {
-        Self::with_config(peer, network, Config::default(), on_dont_have_timeout).await
-    }
-
     pub async fn with_config(
         peer: PeerId,
         network: Network,
@@ -147,7 +149,14 @@ impl MessageQueue {
     }
 
     /// Add want-haves and want-blocks for the peer for this queue.
-    pub async fn add_wants(&self, want_blocks: &[Cid], want_haves: &[Cid]) {
+    ///
+    /// If no `priority` is given, wants are prioritized in the order they are added.
+    pub async fn add_wants(
+        &self,
+        want_blocks: &[Cid],
+        want_haves: &[Cid],
+        priority: Option<Priority>,
+    ) {
         debug!("add_wants: {} {}", want_blocks.len(), want_haves.len());
         if (want_blocks.is_empty() && want_haves.is_empty()) || !self.is_running() {
             return;
@@ -156,10 +165,32 @@ impl MessageQueue {
         self.send_wants_update(WantsUpdate::Wants {
             want_blocks: want_blocks.to_vec(),
             want_haves: want_haves.to_vec(),
+            priority,
         })
         .await;
     }
 
+    /// Sends all wants that were sent before again, marking the next message as a full
+    /// wantlist, so the peer replaces what it tracked for us.
+    pub async fn resend_wantlist(&self) {
+        if !self.is_running() {
+            return;
+        }
+
+        self.send_wants_update(WantsUpdate::ResendWantlist).await;
+    }
+
+    /// Resets the age of the wants for the given keys, so they are sent again with their
+    /// initial priority. Keys not wanted from this peer are ignored.
+    pub async fn refresh_wants(&self, keys: &[Cid]) {
+        if keys.is_empty() || !self.is_running() {
+            return;
+        }
+
+        self.send_wants_update(WantsUpdate::RefreshWants(keys.to_vec()))
+            .await;
+    }
+
     /// Add cancel messages for the given keys.
     pub async fn add_cancels(&self, cancels: &AHashSet<Cid>) {
         if cancels.is_empty() || !self.is_running() {
@@ -323,6 +354,8 @@ struct MessageQueueActor {
     msg_sender_config: MessageSenderConfig,
     receiver_responses: mpsc::Receiver<Vec<Cid>>,
     receiver_wants: mpsc::Receiver<WantsUpdate>,
+    /// Send the next message as a full wantlist.
+    send_full: bool,
 }
 
 impl MessageQueueActor {
@@ -360,6 +393,7 @@ impl MessageQueueActor {
             peer,
             receiver_responses,
             receiver_wants,
+            send_full: false,
         }
     }
 
@@ -368,6 +402,18 @@ impl MessageQueueActor {
         Ok(())
     }
 
+    /// Returns the explicit `priority` if set, otherwise the next value of the running counter.
+    fn next_priority(&mut self, priority: Option<Priority>) -> Priority {
+        match priority {
+            Some(priority) => priority,
+            None => {
+                let priority = self.wants.priority;
+                self.wants.priority -= 1;
+                priority
+            }
+        }
+    }
+
     async fn handle_wants_update(&mut self, wants_update: WantsUpdate) {
         match wants_update {
             WantsUpdate::BroadcastWantHaves(want_haves) => {
@@ -386,12 +432,12 @@ impl MessageQueueActor {
             WantsUpdate::Wants {
                 want_blocks,
                 want_haves,
+                priority,
             } => {
                 for cid in want_haves {
                     self.wants
                         .peer_wants
-                        .add(cid, self.wants.priority, WantType::Have);
-                    self.wants.priority -= 1;
+                        .add(cid, self.next_priority(priority), WantType::Have);
 
                     // Adding a want-have for the cid, so clear any pending cancels.
                     self.wants.cancels.remove(&cid);
@@ -400,8 +446,7 @@ impl MessageQueueActor {
                 for cid in want_blocks {
                     self.wants
                         .peer_wants
-                        .add(cid, self.wants.priority, WantType::Block);
-                    self.wants.priority -= 1;
+                        .add(cid, self.next_priority(priority), WantType::Block);
 
                     // Adding a want-block for the cid, so clear any pending cancels.
                     self.wants.cancels.remove(&cid);
@@ -435,6 +480,25 @@ impl MessageQueueActor {
                     self.signal_work();
                 }
             }
+            WantsUpdate::ResendWantlist => {
+                self.transfer_rebroadcast_wants().await;
+                self.send_full = self.config.send_full_wantlist;
+                self.signal_work();
+            }
+            WantsUpdate::RefreshWants(keys) => {
+                // without decay the priority never changes, so there is nothing to resend
+                if matches!(self.config.priority_decay, PriorityDecay::Off) {
+                    return;
+                }
+                let mut work_ready = false;
+                for cid in keys {
+                    work_ready |= self.wants.bcst_wants.refresh(&cid);
+                    work_ready |= self.wants.peer_wants.refresh(&cid);
+                }
+                if work_ready {
+                    self.signal_work();
+                }
+            }
             #[cfg(test)]
             WantsUpdate::GetWants(r) => r.send(self.wants.clone()).unwrap(),
         }
@@ -493,6 +557,7 @@ impl MessageQueueActor {
             );
             return true;
         }
+        self.send_full = false;
 
         // Record sent time so as to calculate message latency.
         // Update state after the message has been sent.
@@ -605,7 +670,8 @@ impl MessageQueueActor {
         let supports_have = sender.supports_have();
 
         let (mut peer_entries, mut bcst_entries, mut cancels) = {
-            let mut peer_entries: Vec<_> = self.wants.peer_wants.pending.entries().collect();
+            let decay = self.config.priority_decay;
+            let mut peer_entries = self.wants.peer_wants.pending_entries(decay);
             if !supports_have {
                 // Remove want haves
                 peer_entries.retain(|entry| {
@@ -619,7 +685,7 @@ impl MessageQueueActor {
                     }
                 });
             }
-            let bcst_entries: Vec<_> = self.wants.bcst_wants.pending.entries().collect();
+            let bcst_entries = self.wants.bcst_wants.pending_entries(decay);
             let cancels: Vec<_> = self.wants.cancels.iter().cloned().collect();
             (peer_entries, bcst_entries, cancels)
         };
@@ -638,7 +704,7 @@ impl MessageQueueActor {
         let mut sent_bcst_entries = 0;
         let mut done = false;
 
-        let mut msg = BitswapMessage::default();
+        let mut msg = BitswapMessage::new(self.send_full);
 
         // add cancels
         for c in &cancels {
@@ -731,3 +797,53 @@ impl MessageQueueActor {
         let _ = self.outgoing_work.0.try_send(Instant::now());
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use ahash::AHashMap;
+    use futures::FutureExt;
+
+    use crate::block::tests::create_random_block_v1;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_wants_priority() {
+        let this = PeerId::random();
+        let config = Config {
+            // keep the wants pending
+            send_message_debounce: Duration::from_secs(60),
+            send_message_max_delay: Duration::from_secs(60),
+            ..Default::default()
+        };
+        let queue = MessageQueue::with_config(
+            PeerId::random(),
+            Network::new(this),
+            config,
+            Arc::new(|_, _| async move {}.boxed()),
+        )
+        .await;
+        let cids: Vec<_> = (0..4).map(|_| *create_random_block_v1().cid()).collect();
+
+        // without a priority, wants are prioritized in the order they are added
+        queue.add_wants(&cids[..1], &cids[1..2], None).await;
+        // an explicit priority applies to all wants
+        queue.add_wants(&cids[2..3], &cids[3..], Some(5)).await;
+
+        let mut wants = queue.wants().await.unwrap();
+        let priorities: AHashMap<_, _> = wants
+            .peer_wants
+            .pending
+            .entries()
+            .map(|entry| (entry.cid, entry.priority))
+            .collect();
+        assert_eq!(priorities[&cids[1]], i32::MAX);
+        assert_eq!(priorities[&cids[0]], i32::MAX - 1);
+        assert_eq!(priorities[&cids[2]], 5);
+        assert_eq!(priorities[&cids[3]], 5);
+
+        // the running counter is not used by wants with a priority
+        assert_eq!(wants.priority, i32::MAX - 2);
+        queue.stop().await.unwrap();
+    }
+}
@@ -4,7 +4,10 @@ use ahash::{AHashMap, AHashSet};
 use cid::Cid;
 
 use crate::{
-    client::wantlist::Wantlist,
+    client::{
+        wantlist::{Entry, Wantlist},
+        PriorityDecay,
+    },
     message::{Priority, WantType},
 };
 
@@ -36,12 +39,54 @@ pub struct RecallWantlist {
     pub sent: Wantlist,
     /// The time at which each want was sent.
     pub sent_at: AHashMap<Cid, Instant>,
+    /// The time at which each want was added or last refreshed, its priority decays from then.
+    pub added_at: AHashMap<Cid, Instant>,
 }
 
 impl RecallWantlist {
     /// Adds a want to the pending list.
     pub fn add(&mut self, cid: Cid, priority: Priority, want_type: WantType) {
         self.pending.add(cid, priority, want_type);
+        self.added_at.insert(cid, Instant::now());
+    }
+
+    /// Resets the age of a want, and moves it back to pending if it was already sent.
+    ///
+    /// Returns true if the want is known.
+    pub fn refresh(&mut self, cid: &Cid) -> bool {
+        if !self.added_at.contains_key(cid) {
+            return false;
+        }
+        self.added_at.insert(*cid, Instant::now());
+        if let Some(entry) = self.sent.get(cid) {
+            let (priority, want_type) = (entry.priority, entry.want_type);
+            self.pending.add(*cid, priority, want_type);
+        }
+        true
+    }
+
+    /// Returns the pending entries, sorted descending by their priority after applying
+    /// `decay`.
+    pub fn pending_entries(&mut self, decay: PriorityDecay) -> Vec<Entry> {
+        if matches!(decay, PriorityDecay::Off) {
+            return self.pending.entries().collect();
+        }
+
+        let now = Instant::now();
+        let added_at = &self.added_at;
+        let mut entries: Vec<_> = self
+            .pending
+            .entries()
+            .map(|entry| {
+                let age = added_at
+                    .get(&entry.cid)
+                    .map(|at| now.saturating_duration_since(*at))
+                    .unwrap_or_default();
+                Entry::new(entry.cid, decay.apply(entry.priority, age), entry.want_type)
+            })
+            .collect();
+        entries.sort_by(|a, b| b.priority.cmp(&a.priority));
+        entries
     }
 
     /// Removes wants from both pending and sent list.
@@ -49,6 +94,7 @@ impl RecallWantlist {
         self.pending.remove(cid);
         self.sent.remove(cid);
         self.sent_at.remove(cid);
+        self.added_at.remove(cid);
     }
 
     /// Removes wants from both pending and sent list, by type.
@@ -57,18 +103,24 @@ impl RecallWantlist {
         if self.sent.remove_type(cid, want_type).is_some() {
             self.sent_at.remove(cid);
         }
+        if !self.pending.contains(cid) && !self.sent.contains(cid) {
+            self.added_at.remove(cid);
+        }
     }
 
     /// Moves the want from pending to sent.
     ///
     /// Returns true if the want was marked as sent, false if the want wasn't
     /// pending to begin with.
-    pub fn mark_sent(&mut self, e: &crate::client::wantlist::Entry) -> bool {
-        if self.pending.remove_type(&e.cid, e.want_type).is_none() {
-            return false;
+    pub fn mark_sent(&mut self, e: &Entry) -> bool {
+        match self.pending.remove_type(&e.cid, e.want_type) {
+            // keep the initial priority, `e` might carry a decayed one
+            Some(pending) => {
+                self.sent.add(e.cid, pending.priority, e.want_type);
+                true
+            }
+            None => false,
         }
-        self.sent.add(e.cid, e.priority, e.want_type);
-        true
     }
 
     /// Clears out the recorded sent time.
@@ -82,3 +134,45 @@ impl RecallWantlist {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::block::tests::create_random_block_v1;
+
+    #[test]
+    fn test_pending_entries_decay() {
+        let old = *create_random_block_v1().cid();
+        let new = *create_random_block_v1().cid();
+        let mut wants = RecallWantlist::default();
+        wants.add(old, 10, WantType::Block);
+        wants.add(new, 10, WantType::Block);
+        let minute_ago = Instant::now() - Duration::from_secs(60);
+        wants.added_at.insert(old, minute_ago);
+
+        let decay = PriorityDecay::Linear {
+            interval: Duration::from_secs(10),
+            step: 1,
+        };
+        let entries = wants.pending_entries(decay);
+        assert_eq!(entries[0].cid, new);
+        assert_eq!(entries[0].priority, 10);
+        assert_eq!(entries[1].cid, old);
+        assert_eq!(entries[1].priority, 4);
+
+        // sent wants keep their initial priority
+        assert!(wants.mark_sent(&entries[1]));
+        assert_eq!(wants.sent.get(&old).unwrap().priority, 10);
+
+        // refreshing resets the age and queues the want again
+        assert!(wants.refresh(&old));
+        let entries = wants.pending_entries(decay);
+        assert_eq!(entries.len(), 2);
+        assert!(entries.iter().all(|entry| entry.priority == 10));
+
+        wants.remove(&old);
+        assert!(!wants.refresh(&old));
+    }
+}
@@ -10,9 +10,13 @@ use libp2p::PeerId;
 use tokio::sync::{mpsc, oneshot};
 use tracing::{debug, error, trace, warn};
 
-use crate::network::Network;
+use crate::{message::Priority, network::Network, ConnState};
 
-use super::{message_queue::MessageQueue, peer_want_manager::PeerWantManager, session::Signaler};
+use super::{
+    message_queue::{Config as MessageQueueConfig, MessageQueue},
+    peer_want_manager::PeerWantManager,
+    session::Signaler,
+};
 
 #[derive(Debug, Clone)]
 pub struct PeerManager {
@@ -27,15 +31,19 @@ enum Message {
     GetCurrentWantBlocks(oneshot::Sender<AHashSet<Cid>>),
     GetCurrentWantHaves(oneshot::Sender<AHashSet<Cid>>),
     Connected(PeerId),
-    Disconnected(PeerId),
+    Disconnected(PeerId, ConnState),
     ResponseReceived(PeerId, Vec<Cid>),
     BroadcastWantHaves(AHashSet<Cid>),
     SendWants {
         peer: PeerId,
         want_blocks: Vec<Cid>,
         want_haves: Vec<Cid>,
+        priority: Option<Priority>,
     },
     SendCancels(Vec<Cid>),
+    SendPeerCancels(PeerId, Vec<Cid>),
+    ResendWantlist(PeerId),
+    RefreshWants(Vec<Cid>),
     RegisterSession {
         peer: PeerId,
         signaler: Signaler,
@@ -89,8 +97,17 @@ impl<F: Fn(PeerId, Vec<Cid>) -> BoxFuture<'static, ()> + 'static + Sync + Send>
 
 impl PeerManager {
     pub async fn new(self_id: PeerId, network: Network) -> Self {
+        Self::with_queue_config(self_id, network, MessageQueueConfig::default()).await
+    }
+
+    /// Creates a peer manager, whose message queues use `queue_config`.
+    pub async fn with_queue_config(
+        self_id: PeerId,
+        network: Network,
+        queue_config: MessageQueueConfig,
+    ) -> Self {
         let (sender, receiver) = mpsc::channel(2048);
-        let actor = PeerManagerActor::new(self_id, network, receiver).await;
+        let actor = PeerManagerActor::new(self_id, network, queue_config, receiver).await;
 
         let _worker = tokio::task::spawn(async move {
             run(actor).await;
@@ -129,9 +146,12 @@ impl PeerManager {
         self.send(Message::Connected(*peer)).await;
     }
 
-    /// Called to remove a peer from the pool.
-    pub async fn disconnected(&self, peer: &PeerId) {
-        self.send(Message::Disconnected(*peer)).await;
+    /// Called to remove a peer from the pool, after its connection changed to `reason`.
+    ///
+    /// Wants that were outstanding with this peer only are reported as
+    /// [`BitswapEvent::WantsOrphaned`](crate::BitswapEvent::WantsOrphaned).
+    pub async fn disconnected(&self, peer: &PeerId, reason: ConnState) {
+        self.send(Message::Disconnected(*peer, reason)).await;
     }
 
     /// Called when a message is received from the network.
@@ -152,11 +172,19 @@ impl PeerManager {
 
     /// Sends the given want-blocks and want-haves to the given peer.
     /// It filters out wants that have been previously sent to the peer.
-    pub async fn send_wants(&self, peer: &PeerId, want_blocks: &[Cid], want_haves: &[Cid]) {
+    /// Without an explicit `priority`, wants are prioritized in the order they are sent.
+    pub async fn send_wants(
+        &self,
+        peer: &PeerId,
+        want_blocks: &[Cid],
+        want_haves: &[Cid],
+        priority: Option<Priority>,
+    ) {
         self.send(Message::SendWants {
             peer: *peer,
             want_blocks: want_blocks.to_vec(),
             want_haves: want_haves.to_vec(),
+            priority,
         })
         .await;
     }
@@ -166,6 +194,22 @@ impl PeerManager {
         self.send(Message::SendCancels(cancels.to_vec())).await;
     }
 
+    /// Sends cancels for the given keys only to the given peer, if it received a want for them.
+    pub async fn send_peer_cancels(&self, peer: &PeerId, cancels: &[Cid]) {
+        self.send(Message::SendPeerCancels(*peer, cancels.to_vec()))
+            .await;
+    }
+
+    /// Sends all wants previously sent to the given peer again, as a full wantlist.
+    pub async fn resend_wantlist(&self, peer: &PeerId) {
+        self.send(Message::ResendWantlist(*peer)).await;
+    }
+
+    /// Resets the age of the wants for the given keys, in the queues of all peers.
+    pub async fn refresh_wants(&self, keys: &[Cid]) {
+        self.send(Message::RefreshWants(keys.to_vec())).await;
+    }
+
     /// Returns a list of pending wants (both want-haves and want-blocks).
     pub async fn current_wants(&self) -> AHashSet<Cid> {
         let (s, r) = oneshot::channel();
@@ -323,8 +367,8 @@ async fn run(mut actor: PeerManagerActor) {
                     Some(Message::Connected(peer)) => {
                         actor.connected(peer).await;
                     },
-                    Some(Message::Disconnected(peer)) => {
-                        actor.disconnected(peer).await;
+                    Some(Message::Disconnected(peer, reason)) => {
+                        actor.disconnected(peer, reason).await;
                     },
                     Some(Message::ResponseReceived(peer, responses)) => {
                         actor.response_received(peer, responses).await;
@@ -336,12 +380,22 @@ async fn run(mut actor: PeerManagerActor) {
                         peer,
                         want_blocks,
                         want_haves,
+                        priority,
                     }) => {
-                        actor.send_wants(peer, want_blocks, want_haves).await;
+                        actor.send_wants(peer, want_blocks, want_haves, priority).await;
                     },
                     Some(Message::SendCancels(cancels)) => {
                         actor.send_cancels(cancels).await;
                     },
+                    Some(Message::SendPeerCancels(peer, cancels)) => {
+                        actor.send_peer_cancels(peer, cancels).await;
+                    },
+                    Some(Message::ResendWantlist(peer)) => {
+                        actor.resend_wantlist(peer).await;
+                    },
+                    Some(Message::RefreshWants(keys)) => {
+                        actor.refresh_wants(keys).await;
+                    },
                     Some(Message::RegisterSession { peer, signaler, response }) => {
                         let _ = response.send(actor.register_session(peer, signaler).await);
                     },
@@ -416,9 +470,12 @@ struct PeerManagerActor {
     receiver: mpsc::Receiver<Message>,
     peers: AHashMap<PeerId, PeerState>,
     peer_want_manager: PeerWantManager,
+    /// Peers whose connections are kept alive for their outstanding wants.
+    wants_outstanding: AHashSet<PeerId>,
     sessions: AHashMap<u64, SessionState>,
     self_id: PeerId,
     network: Network,
+    queue_config: MessageQueueConfig,
     #[derivative(Debug = "ignore")]
     on_dont_have_timeout: Arc<dyn DontHaveTimeout>,
 }
@@ -439,13 +496,20 @@ struct SessionState {
 }
 
 impl PeerManagerActor {
-    async fn new(self_id: PeerId, network: Network, receiver: mpsc::Receiver<Message>) -> Self {
+    async fn new(
+        self_id: PeerId,
+        network: Network,
+        queue_config: MessageQueueConfig,
+        receiver: mpsc::Receiver<Message>,
+    ) -> Self {
         Self {
             self_id,
             receiver,
             network,
+            queue_config,
             peers: Default::default(),
             peer_want_manager: Default::default(),
+            wants_outstanding: Default::default(),
             sessions: Default::default(),
             on_dont_have_timeout: Arc::new(|_, _| async move {}.boxed()),
         }
@@ -478,29 +542,40 @@ impl PeerManagerActor {
             trace!("found stopped peer_queue, restarting: {}", peer);
             inc!(BitswapMetrics::MessageQueuesCreated);
             // Restart if the queue was stopped, but not yet cleaned up.
-            peer_state.message_queue = MessageQueue::new(
+            peer_state.message_queue = MessageQueue::with_config(
                 peer,
                 self.network.clone(),
+                self.queue_config.clone(),
                 self.on_dont_have_timeout.clone(),
             )
             .await;
         }
 
         // Inform the peer want manager that there's a new peer.
+        let send_broadcast_wants = self.queue_config.send_full_wantlist;
         self.peer_want_manager
-            .add_peer(&peer_state.message_queue, &peer)
+            .add_peer(&peer_state.message_queue, &peer, send_broadcast_wants)
             .await;
 
         // Inform the session that the peer has connected
         self.signal_availability(peer, true).await;
     }
 
-    async fn disconnected(&mut self, peer: PeerId) {
+    async fn disconnected(&mut self, peer: PeerId, reason: ConnState) {
         if let Some(peer_state) = self.peers.remove(&peer) {
             inc!(BitswapMetrics::MessageQueuesDestroyed);
             // inform the sessions that the peer has disconnected
 
-            self.peer_want_manager.remove_peer(&peer);
+            self.wants_outstanding.remove(&peer);
+            let orphaned = self.peer_want_manager.remove_peer(&peer);
+            if !orphaned.is_empty() {
+                debug!(
+                    "{} wants were only outstanding with {}",
+                    orphaned.len(),
+                    peer
+                );
+                self.network.wants_orphaned(peer, reason, orphaned);
+            }
 
             if let Err(err) = peer_state.message_queue.stop().await {
                 error!("failed to shutdown message queue for {}: {:?}", peer, err);
@@ -520,7 +595,13 @@ impl PeerManagerActor {
             .await;
     }
 
-    async fn send_wants(&mut self, peer: PeerId, want_blocks: Vec<Cid>, want_haves: Vec<Cid>) {
+    async fn send_wants(
+        &mut self,
+        peer: PeerId,
+        want_blocks: Vec<Cid>,
+        want_haves: Vec<Cid>,
+        priority: Option<Priority>,
+    ) {
         debug!(
             "send_wants to {}: {}, {} {:?}, {:?}",
             peer,
@@ -531,8 +612,15 @@ impl PeerManagerActor {
         );
         if let Some(peer_state) = self.peers.get(&peer) {
             self.peer_want_manager
-                .send_wants(&peer, &want_blocks, &want_haves, &peer_state.message_queue)
+                .send_wants(
+                    &peer,
+                    &want_blocks,
+                    &want_haves,
+                    priority,
+                    &peer_state.message_queue,
+                )
                 .await;
+            self.update_keep_alive(&[peer]).await;
         }
     }
 
@@ -540,6 +628,48 @@ impl PeerManagerActor {
         self.peer_want_manager
             .send_cancels(&cancels, &self.peers)
             .await;
+        // only peers with outstanding wants can be affected
+        let peers: Vec<_> = self.wants_outstanding.iter().copied().collect();
+        self.update_keep_alive(&peers).await;
+    }
+
+    async fn send_peer_cancels(&mut self, peer: PeerId, cancels: Vec<Cid>) {
+        if let Some(peer_state) = self.peers.get(&peer) {
+            self.peer_want_manager
+                .send_peer_cancels(&peer, &cancels, &peer_state.message_queue)
+                .await;
+            self.update_keep_alive(&[peer]).await;
+        }
+    }
+
+    /// Keeps the connections to `peers` alive while wants sent to them are outstanding, and
+    /// lets them idle out again once the wants are resolved.
+    async fn update_keep_alive(&mut self, peers: &[PeerId]) {
+        let changed: Vec<_> = peers
+            .iter()
+            .map(|peer| (*peer, self.peer_want_manager.has_outstanding_wants(peer)))
+            .filter(|(peer, outstanding)| *outstanding != self.wants_outstanding.contains(peer))
+            .collect();
+        for (peer, outstanding) in changed {
+            if outstanding {
+                self.wants_outstanding.insert(peer);
+            } else {
+                self.wants_outstanding.remove(&peer);
+            }
+            self.network.keep_alive_for_wants(peer, outstanding).await;
+        }
+    }
+
+    async fn resend_wantlist(&self, peer: PeerId) {
+        if let Some(peer_state) = self.peers.get(&peer) {
+            peer_state.message_queue.resend_wantlist().await;
+        }
+    }
+
+    async fn refresh_wants(&self, keys: Vec<Cid>) {
+        for peer_state in self.peers.values() {
+            peer_state.message_queue.refresh_wants(&keys).await;
+        }
     }
 
     fn current_wants(&self) -> AHashSet<Cid> {
@@ -584,9 +714,10 @@ impl PeerManagerActor {
             }
             std::collections::hash_map::Entry::Vacant(entry) => {
                 inc!(BitswapMetrics::MessageQueuesCreated);
-                let message_queue = MessageQueue::new(
+                let message_queue = MessageQueue::with_config(
                     peer,
                     self.network.clone(),
+                    self.queue_config.clone(),
                     self.on_dont_have_timeout.clone(),
                 )
                 .await;
@@ -753,7 +884,9 @@ mod tests {
         assert!(!connected_peers.contains(&peer5));
 
         // disconnect
-        peer_manager.disconnected(&peer1).await;
+        peer_manager
+            .disconnected(&peer1, ConnState::Disconnected)
+            .await;
         let connected_peers = peer_manager.connected_peers().await;
         assert!(!connected_peers.contains(&peer1));
 
@@ -864,7 +997,7 @@ mod tests {
 
         peer_manager.connected(&peer1).await;
         peer_manager
-            .send_wants(&peer1, &[cids[0]][..], &[cids[2]][..])
+            .send_wants(&peer1, &[cids[0]][..], &[cids[2]][..], None)
             .await;
 
         {
@@ -885,7 +1018,12 @@ mod tests {
         }
 
         peer_manager
-            .send_wants(&peer1, &[cids[0], cids[1]][..], &[cids[2], cids[3]][..])
+            .send_wants(
+                &peer1,
+                &[cids[0], cids[1]][..],
+                &[cids[2], cids[3]][..],
+                None,
+            )
             .await;
 
         {
@@ -929,7 +1067,7 @@ mod tests {
         peer_manager.connected(&peer2).await;
 
         peer_manager
-            .send_wants(&peer1, &[cids[0], cids[1]][..], &[cids[2]][..])
+            .send_wants(&peer1, &[cids[0], cids[1]][..], &[cids[2]][..], None)
             .await;
         std::thread::sleep(Duration::from_millis(100));
 
@@ -3,6 +3,8 @@ use cid::Cid;
 use libp2p::PeerId;
 use tracing::{debug, error};
 
+use crate::message::Priority;
+
 use super::message_queue::MessageQueue;
 use super::peer_manager::PeerState;
 
@@ -27,8 +29,13 @@ struct PeerWant {
 
 impl PeerWantManager {
     /// Adds a peer whose wants we need to keep track of.
-    /// Sends the current list of broadcasts to this peer.
-    pub async fn add_peer(&mut self, peer_queue: &MessageQueue, peer: &PeerId) {
+    /// Sends the current list of broadcasts to this peer, if `send_broadcast_wants` is set.
+    pub async fn add_peer(
+        &mut self,
+        peer_queue: &MessageQueue,
+        peer: &PeerId,
+        send_broadcast_wants: bool,
+    ) {
         if self.peer_wants.contains_key(peer) {
             return;
         }
@@ -42,14 +49,18 @@ impl PeerWantManager {
         );
 
         // Broadcast any live want-haves to the newly connected peer.
-        if !self.broadcast_wants.is_empty() {
+        if send_broadcast_wants && !self.broadcast_wants.is_empty() {
             let wants = &self.broadcast_wants;
             peer_queue.add_broadcast_want_haves(wants).await;
         }
     }
 
     /// Removes a peer and its associated wants from tracking.
-    pub fn remove_peer(&mut self, peer: &PeerId) {
+    ///
+    /// Returns the wants that were sent to this peer only, and are now not outstanding with
+    /// any peer.
+    pub fn remove_peer(&mut self, peer: &PeerId) -> Vec<Cid> {
+        let mut orphaned = Vec::new();
         if let Some(peer_wants) = self.peer_wants.remove(peer) {
             // Clean up want-block
             for cid in peer_wants.want_blocks {
@@ -61,6 +72,7 @@ impl PeerWantManager {
                 if !peer_counts.wanted() {
                     // TODO: wantGauge dec
                 }
+                orphaned.push(cid);
             }
 
             // Clean up want-haves
@@ -70,8 +82,26 @@ impl PeerWantManager {
                 if !peer_counts.wanted() {
                     // TODO: wantGauge dec
                 }
+                orphaned.push(cid);
             }
         }
+        // broadcast wants are also outstanding with every other peer
+        let other_peers = !self.peer_wants.is_empty();
+        orphaned.retain(|cid| {
+            !self.want_peers.contains_key(cid)
+                && !(other_peers && self.broadcast_wants.contains(cid))
+        });
+        orphaned
+    }
+
+    /// Returns true if wants sent to `peer` specifically are still outstanding.
+    ///
+    /// Broadcast wants are not counted, they go to every peer and would keep all of them
+    /// alive.
+    pub fn has_outstanding_wants(&self, peer: &PeerId) -> bool {
+        self.peer_wants.get(peer).map_or(false, |peer_wants| {
+            !peer_wants.want_blocks.is_empty() || !peer_wants.want_haves.is_empty()
+        })
     }
 
     /// Sends want-haves to any peers that have not yet been sent them.
@@ -117,6 +147,7 @@ impl PeerWantManager {
         peer: &PeerId,
         want_blocks: &[Cid],
         want_haves: &[Cid],
+        priority: Option<Priority>,
         message_queue: &MessageQueue,
     ) {
         let mut flt_want_blocks = Vec::with_capacity(want_blocks.len());
@@ -162,7 +193,7 @@ impl PeerWantManager {
             // send out want-blocks and want-haves
             if !flt_want_blocks.is_empty() || !flt_want_haves.is_empty() {
                 message_queue
-                    .add_wants(&flt_want_blocks, &flt_want_haves)
+                    .add_wants(&flt_want_blocks, &flt_want_haves, priority)
                     .await;
             }
         } else {
@@ -265,6 +296,36 @@ impl PeerWantManager {
         }
     }
 
+    /// Sends cancels for the given keys to a single peer, for the wants it had previously received.
+    ///
+    /// Unlike [`PeerWantManager::send_cancels`] the wants sent to other peers are left untouched.
+    pub(super) async fn send_peer_cancels(
+        &mut self,
+        peer: &PeerId,
+        cancels: &[Cid],
+        peer_queue: &MessageQueue,
+    ) {
+        let to_cancel: AHashSet<Cid> = match self.peer_wants.get_mut(peer) {
+            Some(peer_wants) => cancels
+                .iter()
+                .filter(|cid| {
+                    let want_block = peer_wants.want_blocks.remove(cid);
+                    let want_have = peer_wants.want_haves.remove(cid);
+                    want_block || want_have
+                })
+                .copied()
+                .collect(),
+            None => return,
+        };
+
+        for cid in &to_cancel {
+            self.reverse_index_remove(cid, peer);
+        }
+        if !to_cancel.is_empty() {
+            peer_queue.add_cancels(&to_cancel).await;
+        }
+    }
+
     /// Counts how many peers have a pendinng want-block and want-have for the given cid.
     fn want_peer_counts(&self, cid: &Cid) -> WantPeerCounts {
         let mut counts = WantPeerCounts {
@@ -347,3 +408,130 @@ impl WantPeerCounts {
         self.want_block > 0 || self.want_have > 0 || self.is_broadcast
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use futures::FutureExt;
+
+    use crate::block::tests::create_random_block_v1;
+    use crate::client::message_queue::Config as MessageQueueConfig;
+    use crate::network::Network;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_add_peer_broadcast_wants() {
+        let this = PeerId::random();
+        let network = Network::new(this);
+        let cids: AHashSet<_> = (0..2).map(|_| *create_random_block_v1().cid()).collect();
+
+        let mut pwm = PeerWantManager::default();
+        pwm.broadcast_want_haves(&cids, &Default::default()).await;
+
+        for send_broadcast_wants in [true, false] {
+            let peer = PeerId::random();
+            let queue = MessageQueue::with_config(
+                peer,
+                network.clone(),
+                MessageQueueConfig::default(),
+                Arc::new(|_: PeerId, _: Vec<Cid>| async {}.boxed()),
+            )
+            .await;
+            pwm.add_peer(&queue, &peer, send_broadcast_wants).await;
+
+            let wants = queue.wants().await.unwrap();
+            let expected = if send_broadcast_wants { cids.len() } else { 0 };
+            assert_eq!(wants.bcst_wants.pending.len(), expected);
+            queue.stop().await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_remove_peer_orphaned_wants() {
+        let this = PeerId::random();
+        let network = Network::new(this);
+        let cids: Vec<_> = (0..4).map(|_| *create_random_block_v1().cid()).collect();
+
+        let mut pwm = PeerWantManager::default();
+        let peers = [PeerId::random(), PeerId::random()];
+        let mut queues = Vec::new();
+        for peer in &peers {
+            let queue = MessageQueue::with_config(
+                *peer,
+                network.clone(),
+                MessageQueueConfig::default(),
+                Arc::new(|_: PeerId, _: Vec<Cid>| async {}.boxed()),
+            )
+            .await;
+            pwm.add_peer(&queue, peer, false).await;
+            queues.push(queue);
+        }
+
+        // cids[0] is wanted from both peers, cids[1] and cids[2] only from the first one
+        let want_blocks = [cids[0], cids[1], cids[3]];
+        pwm.send_wants(&peers[0], &want_blocks, &cids[2..3], None, &queues[0])
+            .await;
+        pwm.send_wants(&peers[1], &cids[..1], &[], None, &queues[1])
+            .await;
+        // cids[3] is broadcast, so it is outstanding with the second peer as well
+        let broadcast: AHashSet<_> = cids[3..].iter().copied().collect();
+        pwm.broadcast_want_haves(&broadcast, &Default::default())
+            .await;
+
+        let mut orphaned = pwm.remove_peer(&peers[0]);
+        orphaned.sort();
+        let mut expected = cids[1..3].to_vec();
+        expected.sort();
+        assert_eq!(orphaned, expected);
+
+        // nobody is left to ask for any of them
+        let mut orphaned = pwm.remove_peer(&peers[1]);
+        orphaned.sort();
+        assert_eq!(orphaned, vec![cids[0]]);
+        assert!(pwm.remove_peer(&peers[1]).is_empty());
+
+        for queue in queues {
+            queue.stop().await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_has_outstanding_wants() {
+        let this = PeerId::random();
+        let network = Network::new(this);
+        let cids: Vec<_> = (0..2).map(|_| *create_random_block_v1().cid()).collect();
+
+        let mut pwm = PeerWantManager::default();
+        let peer = PeerId::random();
+        let queue = MessageQueue::with_config(
+            peer,
+            network.clone(),
+            MessageQueueConfig::default(),
+            Arc::new(|_: PeerId, _: Vec<Cid>| async {}.boxed()),
+        )
+        .await;
+        // the queues are only needed to send the cancels
+        let peers = AHashMap::new();
+        assert!(!pwm.has_outstanding_wants(&peer));
+        pwm.add_peer(&queue, &peer, false).await;
+        assert!(!pwm.has_outstanding_wants(&peer));
+
+        pwm.send_wants(&peer, &cids[..1], &[], None, &queue).await;
+        assert!(pwm.has_outstanding_wants(&peer));
+        pwm.send_cancels(&cids[..1], &peers).await;
+        assert!(!pwm.has_outstanding_wants(&peer));
+
+        // broadcast wants are not addressed to the peer
+        let broadcast: AHashSet<_> = cids[1..].iter().copied().collect();
+        pwm.broadcast_want_haves(&broadcast, &peers).await;
+        assert!(!pwm.has_outstanding_wants(&peer));
+        pwm.send_wants(&peer, &cids[1..], &[], None, &queue).await;
+        assert!(pwm.has_outstanding_wants(&peer));
+        pwm.send_cancels(&cids[1..], &peers).await;
+        assert!(!pwm.has_outstanding_wants(&peer));
+
+        queue.stop().await.unwrap();
+    }
+}
@@ -0,0 +1,124 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use ahash::AHashMap;
+use libp2p::PeerId;
+
+/// How long it takes for the preference of a peer to halve, unless it keeps sending us
+/// wanted blocks.
+const PREFERENCE_HALF_LIFE: Duration = Duration::from_secs(60);
+
+/// The weight of a freshly marked peer, counted like blocks it was the first to send us.
+const PREFERENCE_WEIGHT: f64 = 64.0;
+
+/// Preferences that decayed below this weight are forgotten.
+const MIN_PREFERENCE_WEIGHT: f64 = 1.0;
+
+/// Peers that are likely to have the blocks wanted next, e.g. because a related fetch
+/// from them succeeded.
+///
+/// Sessions ask connected preferred peers first, and favour them when choosing which peer
+/// to send a want-block to. The preference fades with [`PREFERENCE_HALF_LIFE`], every
+/// wanted block a preferred peer sends renews it.
+#[derive(Debug, Clone, Default)]
+pub struct PreferredPeers {
+    /// When each peer was marked, or last sent a wanted block.
+    peers: Arc<Mutex<AHashMap<PeerId, Instant>>>,
+}
+
+impl PreferredPeers {
+    /// Prefers `peer`, with the full weight.
+    pub fn mark(&self, peer: PeerId) {
+        self.peers.lock().unwrap().insert(peer, Instant::now());
+    }
+
+    /// Removes the preference for `peer`, returning whether it had one.
+    pub fn clear(&self, peer: &PeerId) -> bool {
+        self.peers.lock().unwrap().remove(peer).is_some()
+    }
+
+    /// Restores the full weight of `peer`, if its preference did not fade yet.
+    pub fn renew(&self, peer: &PeerId) {
+        if let Some(marked) = self.peers.lock().unwrap().get_mut(peer) {
+            if decayed_weight(marked.elapsed()) >= MIN_PREFERENCE_WEIGHT {
+                *marked = Instant::now();
+            }
+        }
+    }
+
+    /// Returns how strongly `peer` is preferred, `0` if it is not.
+    pub fn weight(&self, peer: &PeerId) -> f64 {
+        match self.peers.lock().unwrap().get(peer) {
+            Some(marked) => {
+                let weight = decayed_weight(marked.elapsed());
+                if weight >= MIN_PREFERENCE_WEIGHT {
+                    weight
+                } else {
+                    0.
+                }
+            }
+            None => 0.,
+        }
+    }
+
+    /// Returns the preferred peers, the most preferred first, forgetting those whose
+    /// preference faded.
+    pub fn peers(&self) -> Vec<PeerId> {
+        let peers = &mut *self.peers.lock().unwrap();
+        peers.retain(|_, marked| decayed_weight(marked.elapsed()) >= MIN_PREFERENCE_WEIGHT);
+        let mut preferred: Vec<_> = peers
+            .iter()
+            .map(|(peer, marked)| (*marked, *peer))
+            .collect();
+        preferred.sort_by(|a, b| b.0.cmp(&a.0));
+        preferred.into_iter().map(|(_, peer)| peer).collect()
+    }
+}
+
+/// The weight of a preference marked `elapsed` ago.
+fn decayed_weight(elapsed: Duration) -> f64 {
+    PREFERENCE_WEIGHT * 0.5f64.powf(elapsed.as_secs_f64() / PREFERENCE_HALF_LIFE.as_secs_f64())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decayed_weight() {
+        assert_eq!(decayed_weight(Duration::ZERO), PREFERENCE_WEIGHT);
+        assert_eq!(decayed_weight(PREFERENCE_HALF_LIFE), PREFERENCE_WEIGHT / 2.);
+        assert!(decayed_weight(PREFERENCE_HALF_LIFE * 7) < MIN_PREFERENCE_WEIGHT);
+    }
+
+    #[test]
+    fn test_preferred_peers() {
+        let preferred = PreferredPeers::default();
+        let fresh = PeerId::random();
+        let older = PeerId::random();
+        let faded = PeerId::random();
+        assert_eq!(preferred.weight(&fresh), 0.);
+
+        preferred.mark(fresh);
+        {
+            let peers = &mut *preferred.peers.lock().unwrap();
+            let now = Instant::now();
+            peers.insert(older, now - PREFERENCE_HALF_LIFE);
+            peers.insert(faded, now - PREFERENCE_HALF_LIFE * 7);
+        }
+        assert!(preferred.weight(&fresh) > preferred.weight(&older));
+        assert!(preferred.weight(&older) > 0.);
+        assert_eq!(preferred.weight(&faded), 0.);
+        assert_eq!(preferred.peers(), vec![fresh, older]);
+
+        // a faded preference is not renewed
+        preferred.renew(&older);
+        preferred.renew(&faded);
+        assert!(preferred.weight(&older) > PREFERENCE_WEIGHT / 2.);
+        assert_eq!(preferred.weight(&faded), 0.);
+
+        assert!(preferred.clear(&fresh));
+        assert!(!preferred.clear(&fresh));
+        assert_eq!(preferred.peers(), vec![older]);
+    }
+}
@@ -1,6 +1,6 @@
 use std::{ops::Deref, pin::Pin, sync::Arc, time::Duration};
 
-use ahash::AHashSet;
+use ahash::{AHashMap, AHashSet};
 use anyhow::{anyhow, ensure, Result};
 use cid::Cid;
 use futures::{future, stream, StreamExt};
@@ -13,13 +13,14 @@ use tokio::{
 };
 use tracing::{debug, error, info, warn};
 
-use crate::{network::Network, Block};
+use crate::{message::Priority, network::Network, Block};
 
 use self::{session_want_sender::SessionWantSender, session_wants::SessionWants};
 
 use super::{
     block_presence_manager::BlockPresenceManager, peer_manager::PeerManager,
-    session_interest_manager::SessionInterestManager, session_manager::SessionManager,
+    preferred_peers::PreferredPeers, session_interest_manager::SessionInterestManager,
+    session_manager::SessionManager,
 };
 
 mod cid_queue;
@@ -39,10 +40,16 @@ const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
 #[derive(Debug, Clone, PartialEq, Eq)]
 enum Op {
     Receive(Vec<Cid>),
-    Want(Vec<Cid>),
+    Want {
+        keys: Vec<Cid>,
+        priority: Option<Priority>,
+        max_providers: Option<usize>,
+    },
     Cancel(Vec<Cid>),
     Broadcast(AHashSet<Cid>),
     WantsSent(Vec<Cid>),
+    Pause,
+    Resume,
     UpdateWantSender {
         from: PeerId,
         keys: Vec<Cid>,
@@ -81,16 +88,19 @@ impl Session {
         notify: async_broadcast::Sender<Block>,
         initial_search_delay: Duration,
         periodic_search_delay: Duration,
+        per_block_timeout: Option<Duration>,
     ) -> Self {
         info!("creating session {}", id);
         let (incoming_s, incoming_r) = async_channel::bounded(128);
 
+        let preferred_peers = session_manager.preferred_peers().clone();
         let session_want_sender = SessionWantSender::new(
             id,
             peer_manager.clone(),
             session_manager.clone(),
             block_presence_manager,
             incoming_s.clone(),
+            per_block_timeout,
         );
 
         let session_wants = SessionWants::new(BROADCAST_LIVE_WANTS_LIMIT);
@@ -103,6 +113,7 @@ impl Session {
             session_interest_manager.clone(),
             network,
             peer_manager,
+            preferred_peers,
             initial_search_delay,
             incoming_s.clone(),
         );
@@ -127,12 +138,13 @@ impl Session {
                             Ok(Op::Receive(keys)) => {
                                 loop_state.handle_receive(keys).await;
                             },
-                            Ok(Op::Want(keys)) => {
-                                loop_state.want_blocks(keys).await;
+                            Ok(Op::Want { keys, priority, max_providers }) => {
+                                loop_state.want_blocks(keys, priority, max_providers).await;
                             },
                             Ok(Op::Cancel(keys)) => {
                                 record!(BitswapMetrics::CancelBlocks, keys.len() as u64);
                                 loop_state.session_wants.cancel_pending(&keys);
+                                loop_state.forget_max_providers(&keys);
                                 loop_state.session_want_sender.cancel(keys).await;
                             }
                             Ok(Op::WantsSent(keys)) => {
@@ -141,6 +153,12 @@ impl Session {
                             Ok(Op::Broadcast(keys)) => {
                                 loop_state.broadcast(Some(keys)).await;
                             },
+                            Ok(Op::Pause) => {
+                                loop_state.pause().await;
+                            },
+                            Ok(Op::Resume) => {
+                                loop_state.resume().await;
+                            },
                             Ok(Op::UpdateWantSender { from, keys, haves, dont_haves, }) => {
                                 loop_state
                                     .session_want_sender
@@ -274,11 +292,40 @@ impl Session {
 
     /// Fetches a single block.
     pub async fn get_block(&self, key: &Cid) -> Result<Block> {
-        let r = self.get_blocks(&[*key][..]).await?;
+        self.get_block_with(key, None, None).await
+    }
+
+    /// Like [`Session::get_block`], but sends the want with `priority` and searches at most
+    /// `max_providers` for the block, see [`Session::get_blocks_with`].
+    pub async fn get_block_with(
+        &self,
+        key: &Cid,
+        priority: Option<Priority>,
+        max_providers: Option<usize>,
+    ) -> Result<Block> {
+        let r = self
+            .get_blocks_with(&[*key][..], priority, max_providers)
+            .await?;
         let block = r.recv().await?;
         Ok(block)
     }
 
+    /// Pauses sending of wants for this session, without cancelling them.
+    ///
+    /// Blocks requested while paused are tracked, but not sent to any peer until
+    /// [`Session::resume`] is called. Wants that were already sent are cancelled with
+    /// the peers, unless another session wants them too, and sent again on resume.
+    pub async fn pause(&self) -> Result<()> {
+        self.inner.incoming.send(Op::Pause).await?;
+        Ok(())
+    }
+
+    /// Resumes a session previously paused with [`Session::pause`].
+    pub async fn resume(&self) -> Result<()> {
+        self.inner.incoming.send(Op::Resume).await?;
+        Ok(())
+    }
+
     pub async fn add_provider(&self, cid: &Cid, provider: PeerId) {
         let _ = self
             .inner
@@ -296,6 +343,22 @@ impl Session {
     /// returns a channel that found blocks will be returned on. No order is
     /// guaranteed on the returned blocks.
     pub async fn get_blocks(&self, keys: &[Cid]) -> Result<BlockReceiver> {
+        self.get_blocks_with(keys, None, None).await
+    }
+
+    /// Like [`Session::get_blocks`], with options that only apply to these blocks.
+    ///
+    /// Wants are sent to peers with `priority`, higher priorities are served first.
+    /// Without one, wants are prioritized in the order they are requested. At most
+    /// `max_providers` are searched for each block, or the default limit if `None`.
+    ///
+    /// Blocks requested by several calls keep the highest priority and provider limit.
+    pub async fn get_blocks_with(
+        &self,
+        keys: &[Cid],
+        priority: Option<Priority>,
+        max_providers: Option<usize>,
+    ) -> Result<BlockReceiver> {
         ensure!(!keys.is_empty(), "missing keys");
         debug!("get blocks: {:?}", keys);
 
@@ -358,7 +421,14 @@ impl Session {
             }
         });
 
-        self.inner.incoming.send(Op::Want(keys.to_vec())).await?;
+        self.inner
+            .incoming
+            .send(Op::Want {
+                keys: keys.to_vec(),
+                priority,
+                max_providers,
+            })
+            .await?;
 
         Ok(BlockReceiver {
             receiver: r,
@@ -377,13 +447,22 @@ struct LoopState {
     session_interest_manager: SessionInterestManager,
     session_want_sender: SessionWantSender,
     peer_manager: PeerManager,
+    /// Peers asked first, before broadcasting to all connected peers.
+    preferred_peers: PreferredPeers,
     latency_tracker: LatencyTracker,
     idle_tick: Pin<Box<Sleep>>,
     base_tick_delay: Duration,
     initial_search_delay: Duration,
     workers: Vec<JoinHandle<Option<()>>>,
     task_controller: tokio_context::task::TaskController,
-    provider_search_queue: Arc<deadqueue::limited::Queue<Cid>>,
+    /// Blocks to search providers for, with the limit of providers to search.
+    provider_search_queue: Arc<deadqueue::limited::Queue<(Cid, usize)>>,
+    /// The limit of providers searched for wanted blocks requested with one.
+    max_providers: AHashMap<Cid, usize>,
+    paused: bool,
+    /// Wants requested while paused, with their priority, that have not been handed to
+    /// the want sender yet.
+    paused_wants: Vec<(Vec<Cid>, Option<Priority>)>,
 }
 
 impl LoopState {
@@ -395,6 +474,7 @@ impl LoopState {
         session_interest_manager: SessionInterestManager,
         network: Network,
         peer_manager: PeerManager,
+        preferred_peers: PreferredPeers,
         initial_search_delay: Duration,
         incoming: async_channel::Sender<Op>,
     ) -> Self {
@@ -411,8 +491,8 @@ impl LoopState {
 
             workers.push(task_controller.spawn(async move {
                 loop {
-                    let cid = queue.pop().await;
-                    if let Ok(chan) = network.find_providers(cid, MAX_PROVIDERS).await {
+                    let (cid, limit) = queue.pop().await;
+                    if let Ok(chan) = network.find_providers(cid, limit).await {
                         let stream = tokio_stream::wrappers::ReceiverStream::new(chan);
                         stream
                             // Remove intermitten failures.
@@ -461,6 +541,7 @@ impl LoopState {
             session_want_sender,
             session_interest_manager,
             peer_manager,
+            preferred_peers,
             latency_tracker: Default::default(),
             base_tick_delay: Duration::from_millis(500),
             initial_search_delay,
@@ -468,6 +549,9 @@ impl LoopState {
             workers,
             task_controller,
             provider_search_queue: queue,
+            max_providers: Default::default(),
+            paused: false,
+            paused_wants: Vec::new(),
         }
     }
 
@@ -487,10 +571,66 @@ impl LoopState {
         Ok(())
     }
 
+    /// Records the limit of providers searched for `keys`, keeping the higher one of
+    /// blocks requested before.
+    fn record_max_providers(&mut self, keys: &[Cid], max_providers: Option<usize>) {
+        if let Some(max_providers) = max_providers {
+            for key in keys {
+                let limit = self.max_providers.entry(*key).or_default();
+                *limit = (*limit).max(max_providers);
+            }
+        }
+    }
+
+    /// Drops the provider limits of blocks that are no longer wanted.
+    fn forget_max_providers(&mut self, keys: &[Cid]) {
+        for key in keys {
+            self.max_providers.remove(key);
+        }
+    }
+
+    async fn pause(&mut self) {
+        if self.paused {
+            return;
+        }
+        debug!("session:{}: pause", self.id);
+        self.paused = true;
+        self.session_want_sender.set_paused(true).await;
+    }
+
+    async fn resume(&mut self) {
+        if !self.paused {
+            return;
+        }
+        debug!(
+            "session:{}: resume ({} paused wants)",
+            self.id,
+            self.paused_wants.len()
+        );
+        self.paused = false;
+        self.session_want_sender.set_paused(false).await;
+
+        let paused_wants = std::mem::take(&mut self.paused_wants);
+        let held_back = !paused_wants.is_empty();
+        for (keys, priority) in paused_wants {
+            self.session_want_sender.add(keys, priority).await;
+        }
+
+        // The wants sent before the pause were cancelled, send them again.
+        self.broadcast(None).await;
+        if held_back {
+            self.broadcast_next_wants().await;
+        }
+    }
+
     /// Called when the session hasn't received any blocks for some time, or when
     /// all peers in the session have sent DONT_HAVE for a particular set of CIDs.
     /// Send want-haves to all connected peers, and search for new peers with the CID.
     async fn broadcast(&mut self, wants: Option<AHashSet<Cid>>) {
+        if self.paused {
+            self.reset_idle_tick();
+            return;
+        }
         debug!(
             "sesion:{}: broadcast: {:?}",
             self.id,
@@ -524,6 +664,9 @@ impl LoopState {
     /// Called periodically to search for providers of a randomly chosen CID in the sesssion.
     async fn handle_periodic_search(&mut self) {
         debug!("session:{}: periodic search", self.id);
+        if self.paused {
+            return;
+        }
         if let Some(random_want) = self.session_wants.random_live_want() {
             // TODO: come up with a better strategy for determining when to search
             // for new providers for blocks.
@@ -537,7 +680,12 @@ impl LoopState {
     async fn find_more_peers(&mut self, cid: &Cid) {
         debug!("session:{}: find_more_peers {}", self.id, cid);
         inc!(BitswapMetrics::ProviderQueryCreated);
-        self.provider_search_queue.push(*cid).await;
+        let limit = self
+            .max_providers
+            .get(cid)
+            .copied()
+            .unwrap_or(MAX_PROVIDERS);
+        self.provider_search_queue.push((*cid, limit)).await;
     }
 
     /// Called when the session receives blocks from a peer.
@@ -554,6 +702,7 @@ impl LoopState {
             return;
         }
         record!(BitswapMetrics::WantedBlocksReceived, wanted.len() as u64);
+        self.forget_max_providers(&wanted);
 
         // Record latency
         self.latency_tracker
@@ -573,19 +722,39 @@ impl LoopState {
     }
 
     /// Called when blocks are requested by the client.
-    async fn want_blocks(&mut self, new_keys: Vec<Cid>) {
+    async fn want_blocks(
+        &mut self,
+        new_keys: Vec<Cid>,
+        priority: Option<Priority>,
+        max_providers: Option<usize>,
+    ) {
         record!(BitswapMetrics::WantedBlocks, new_keys.len() as u64);
         if !new_keys.is_empty() {
+            self.record_max_providers(&new_keys, max_providers);
             // Inform the SessionInterestManager that this session is interested in the keys.
             self.session_interest_manager
                 .record_session_interest(self.id, &new_keys)
                 .await;
             // Tell the SessionWants tracker that that the wants have been requested.
             self.session_wants.blocks_requested(&new_keys);
+            if self.paused {
+                // Hold on to the wants until the session is resumed, so they do not
+                // take up any of the per peer want limits.
+                self.paused_wants.push((new_keys, priority));
+                return;
+            }
             // Tell the SessionWantSender that the blocks have been requested.
-            self.session_want_sender.add(new_keys).await;
+            self.session_want_sender.add(new_keys, priority).await;
+        } else if self.paused {
+            return;
         }
 
+        self.broadcast_next_wants().await;
+    }
+
+    /// Broadcasts want-haves for the next wants, if the session has not discovered
+    /// any peers yet.
+    async fn broadcast_next_wants(&mut self) {
         // If we have discovered peers already, the sessionWantSender will
         // send wants to them.
         if self
@@ -604,11 +773,32 @@ impl LoopState {
             keys.len()
         );
 
-        if !keys.is_empty() {
+        if !keys.is_empty() && !self.want_haves_from_preferred(&keys).await {
             self.broadcast_want_haves(&keys).await;
         }
     }
 
+    /// Sends want-haves only to the connected preferred peers, returning `false` if none
+    /// is connected.
+    ///
+    /// If none of them sends a block in time, the wants are broadcast to all connected
+    /// peers on the next idle tick, as usual.
+    async fn want_haves_from_preferred(&self, wants: &AHashSet<Cid>) -> bool {
+        let preferred = self.preferred_peers.peers();
+        if preferred.is_empty() {
+            return false;
+        }
+        let connected = self.peer_manager.connected_peers().await;
+        let wants: Vec<Cid> = wants.iter().copied().collect();
+        let mut sent = false;
+        for peer in preferred.iter().filter(|peer| connected.contains(peer)) {
+            debug!("session:{}: asking preferred peer {} first", self.id, peer);
+            self.peer_manager.send_wants(peer, &[], &wants, None).await;
+            sent = true;
+        }
+        sent
+    }
+
     /// Send want-haves to all connected peers
     async fn broadcast_want_haves(&self, wants: &AHashSet<Cid>) {
         debug!(
@@ -5,21 +5,34 @@ use libp2p::PeerId;
 use rand::{thread_rng, Rng};
 use tokio::sync::RwLock;
 
+use crate::client::preferred_peers::PreferredPeers;
+
 /// Keeps track of how many times each peer was the first to send us a block for a
 /// given cid (used to rank peers)
 #[derive(Default, Debug, Clone)]
 pub struct PeerResponseTracker {
     first_responder: Arc<RwLock<AHashMap<PeerId, usize>>>,
+    preferred: PreferredPeers,
 }
 
 impl PeerResponseTracker {
+    /// Creates a tracker that also favours the `preferred` peers.
+    pub fn new(preferred: PreferredPeers) -> Self {
+        PeerResponseTracker {
+            first_responder: Default::default(),
+            preferred,
+        }
+    }
+
     /// Called when a block is received from a peer (only called first time block is received)
     pub async fn received_block_from(&self, from: &PeerId) {
         *self.first_responder.write().await.entry(*from).or_default() += 1;
+        // the peer is still useful
+        self.preferred.renew(from);
     }
 
     /// Picks a peer from the list of candidate peers, favouring those peers
-    /// that were first to send us previous blocks.
+    /// that were first to send us previous blocks, and preferred peers.
     pub async fn choose(&self, peers: &[PeerId]) -> Option<PeerId> {
         if peers.is_empty() {
             return None;
@@ -27,17 +40,18 @@ impl PeerResponseTracker {
 
         let rnd: f64 = thread_rng().gen();
 
-        // Find the total received blocks for all candidate peers
-        let mut total = 0.;
+        // Weigh the candidate peers by their received blocks and preference
+        let mut weights = Vec::with_capacity(peers.len());
         for peer in peers {
-            total += self.get_peer_count(peer).await as f64;
+            let weight = self.get_peer_count(peer).await as f64 + self.preferred.weight(peer);
+            weights.push(weight);
         }
+        let total: f64 = weights.iter().sum();
 
-        // Choose one of the peers with a chance proportional to the number
-        // of blocks received from that peer
+        // Choose one of the peers with a chance proportional to its weight
         let mut counted = 0.0;
-        for peer in peers {
-            counted += self.get_peer_count(peer).await as f64 / total;
+        for (peer, weight) in peers.iter().zip(weights) {
+            counted += weight / total;
             if counted > rnd {
                 return Some(*peer);
             }
@@ -1,4 +1,6 @@
 use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
 
 use ahash::{AHashMap, AHashSet};
 use anyhow::Result;
@@ -13,6 +15,7 @@ use crate::client::{
     block_presence_manager::BlockPresenceManager, peer_manager::PeerManager,
     session_manager::SessionManager,
 };
+use crate::message::Priority;
 
 use super::{
     peer_response_tracker::PeerResponseTracker, sent_want_blocks_tracker::SentWantBlocksTracker,
@@ -25,6 +28,9 @@ const CHANGES_BUFFER_SIZE: usize = 128;
 /// it prunes the peer from the session
 const PEER_DONT_HAVE_LIMIT: usize = 16;
 
+/// Upper bound for how often wants are checked against the per block timeout.
+const BLOCK_TIMEOUT_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
 /// Indicates whether a peer has a block.
 ///
 /// Note that the order is important, we decide which peer to send a want to
@@ -63,14 +69,16 @@ struct PeerAvailability {
 /// connect status of a peer.
 #[derive(Debug)]
 enum Change {
-    /// New wants requested.
-    Add(Vec<Cid>),
+    /// New wants requested, with an explicit priority if any.
+    Add(Vec<Cid>, Option<Priority>),
     /// Wants cancelled.
     Cancel(Vec<Cid>),
     /// New message received by session (blocks / HAVEs / DONT_HAVEs).
     Update(Update),
     /// Peer has connected / disconnected.
     Availability(PeerAvailability),
+    /// Session was paused / resumed.
+    Pause(bool),
 }
 
 /// Convenience structs for passing around want-blocks and want-haves for a peer.
@@ -80,6 +88,9 @@ struct WantSets {
     want_haves: AHashSet<Cid>,
 }
 
+/// The want-blocks and want-haves sent to a peer with the same priority.
+type WantLists = (Vec<Cid>, Vec<Cid>);
+
 #[derive(Default, Debug, PartialEq, Eq)]
 struct AllWants(AHashMap<PeerId, WantSets>);
 
@@ -143,6 +154,7 @@ impl SessionWantSender {
         session_manager: SessionManager,
         block_presence_manager: BlockPresenceManager,
         session_ops: async_channel::Sender<super::Op>,
+        per_block_timeout: Option<Duration>,
     ) -> Self {
         debug!("session:{}: session_want_sender create", session_id);
         let (changes_s, changes_r) = async_channel::bounded(64);
@@ -159,10 +171,18 @@ impl SessionWantSender {
             session_manager,
             block_presence_manager,
             session_ops,
+            per_block_timeout,
         );
         let rt = tokio::runtime::Handle::current();
 
         let worker = rt.spawn(async move {
+            let check_interval = per_block_timeout
+                .unwrap_or(BLOCK_TIMEOUT_CHECK_INTERVAL)
+                .min(BLOCK_TIMEOUT_CHECK_INTERVAL)
+                .max(Duration::from_millis(10));
+            let mut timeout_check = tokio::time::interval(check_interval);
+            timeout_check.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
             // The main loop for processing incoming changes
             loop {
                 inc!(BitswapMetrics::SessionWantSenderLoopTick);
@@ -182,6 +202,9 @@ impl SessionWantSender {
                             }
                         }
                     }
+                    _ = timeout_check.tick(), if per_block_timeout.is_some() => {
+                        loop_state.check_block_timeouts(Instant::now()).await;
+                    }
                 }
             }
 
@@ -210,12 +233,20 @@ impl SessionWantSender {
         Ok(())
     }
 
-    /// Called when new wants are added to the session
-    pub async fn add(&self, keys: Vec<Cid>) {
+    /// Called when new wants are added to the session.
+    ///
+    /// The wants are sent to peers with `priority`, or in the default order if `None`.
+    pub async fn add(&self, keys: Vec<Cid>, priority: Option<Priority>) {
         if keys.is_empty() {
             return;
         }
-        self.add_change(Change::Add(keys)).await;
+        self.add_change(Change::Add(keys, priority)).await;
+    }
+
+    /// Stops sending wants to peers while `paused`, cancelling the ones already sent.
+    /// Resuming sends all wants again.
+    pub async fn set_paused(&self, paused: bool) {
+        self.add_change(Change::Pause(paused)).await;
     }
 
     /// Called when a request is cancelled
@@ -265,22 +296,28 @@ struct WantInfo {
     block_presence: AHashMap<PeerId, BlockPresence>,
     /// The peer that we've sent a want-block to (cleared when we get a response)
     sent_to: Option<PeerId>,
+    /// When the want-block was sent to `sent_to`
+    sent_at: Option<Instant>,
     /// The "best" peer to send the want to next
     best_peer: Option<PeerId>,
     /// Keeps track of how many hits / misses each peer has sent us for wants in the session.
     peer_response_tracker: PeerResponseTracker,
     /// True if all known peers have sent a DONT_HAVE for this want
     exhausted: bool,
+    /// Explicit priority the want is sent with, if any.
+    priority: Option<Priority>,
 }
 
 impl WantInfo {
-    fn new(peer_response_tracker: PeerResponseTracker) -> Self {
+    fn new(peer_response_tracker: PeerResponseTracker, priority: Option<Priority>) -> Self {
         WantInfo {
             block_presence: Default::default(),
             sent_to: None,
+            sent_at: None,
             best_peer: None,
             peer_response_tracker,
             exhausted: false,
+            priority,
         }
     }
 
@@ -324,6 +361,7 @@ impl WantInfo {
         // clear the sent_to field so we no longer wait
         if self.sent_to.is_some() && self.sent_to.as_ref().unwrap() == peer {
             self.sent_to = None;
+            self.sent_at = None;
         }
 
         self.block_presence.remove(peer);
@@ -397,6 +435,10 @@ struct LoopState {
     /// Keeps track of which peer has / doesn't have a block.
     block_presence_manager: BlockPresenceManager,
     session_ops: async_channel::Sender<super::Op>,
+    /// How long to wait for a block from the peer we sent a want-block to.
+    per_block_timeout: Option<Duration>,
+    /// No wants are sent while paused.
+    paused: bool,
 }
 
 impl LoopState {
@@ -407,6 +449,7 @@ impl LoopState {
         session_manager: SessionManager,
         block_presence_manager: BlockPresenceManager,
         session_ops: async_channel::Sender<super::Op>,
+        per_block_timeout: Option<Duration>,
     ) -> Self {
         LoopState {
             changes,
@@ -415,10 +458,14 @@ impl LoopState {
             wants: Default::default(),
             peer_consecutive_dont_haves: Default::default(),
             sent_want_blocks_tracker: SentWantBlocksTracker::default(),
-            peer_response_tracker: PeerResponseTracker::default(),
+            peer_response_tracker: PeerResponseTracker::new(
+                session_manager.preferred_peers().clone(),
+            ),
             session_manager,
             block_presence_manager,
             session_ops,
+            per_block_timeout,
+            paused: false,
         }
     }
 
@@ -457,11 +504,12 @@ impl LoopState {
         let mut availability = AHashMap::with_capacity(changes.len());
         let mut cancels = Vec::new();
         let mut updates = Vec::new();
+        let mut pause = None;
         for change in changes {
             match change {
-                Change::Add(cids) => {
+                Change::Add(cids, priority) => {
                     // Initialize info for new wants
-                    self.track_wants(cids).await;
+                    self.track_wants(cids, priority).await;
                 }
                 Change::Cancel(cids) => {
                     // Remove cancelled wants
@@ -491,11 +539,15 @@ impl LoopState {
                 }) => {
                     availability.insert(target, (is_available, None));
                 }
+                Change::Pause(paused) => {
+                    pause = Some(paused);
+                }
             }
         }
 
         // Update peer availability
-        let (newly_available, newly_unavailable) = self.process_availability(&availability).await;
+        let (mut newly_available, newly_unavailable) =
+            self.process_availability(&availability).await;
 
         // Update wants
         let dont_haves = self.process_updates(updates).await;
@@ -511,12 +563,51 @@ impl LoopState {
                 .await;
         }
 
+        match pause {
+            Some(true) if !self.paused => self.pause().await,
+            Some(false) if self.paused => {
+                // Wants were cancelled on pause, so send them to all peers again.
+                self.paused = false;
+                newly_available = self.peer_manager.peers_for_session(self.id()).await;
+            }
+            _ => {}
+        }
+        if self.paused {
+            return;
+        }
+
         // If there are some connected peers, send any pending wants
         if self.peer_manager.session_has_peers(self.id()).await {
             self.send_next_wants(newly_available).await;
         }
     }
 
+    /// Cancels the wants sent to peers, so they do not count against the per peer
+    /// want limits while paused.
+    ///
+    /// Wants other sessions are interested in are kept.
+    async fn pause(&mut self) {
+        self.paused = true;
+
+        let interest = self.session_manager.session_interest_manager();
+        let mut cancels = Vec::new();
+        for (cid, wi) in &mut self.wants {
+            wi.sent_to = None;
+            wi.sent_at = None;
+            let sessions = interest.interested_sessions(&[*cid], &[], &[]).await;
+            if sessions.iter().all(|id| *id == self.signaler.id) {
+                cancels.push(*cid);
+            }
+        }
+        self.sent_want_blocks_tracker = SentWantBlocksTracker::default();
+        debug!(
+            "session:{}: pause, cancelling {} wants",
+            self.id(),
+            cancels.len()
+        );
+        self.peer_manager.send_cancels(&cancels).await;
+    }
+
     /// Updates the want queue with any changes in peer availability
     /// It returns the peers that have become
     /// - newly available
@@ -566,16 +657,19 @@ impl LoopState {
     }
 
     /// Creates a new entry in the map of cid -> want info.
-    async fn track_wants(&mut self, cids: Vec<Cid>) {
+    ///
+    /// Wants requested again keep the highest of their priorities.
+    async fn track_wants(&mut self, cids: Vec<Cid>, priority: Option<Priority>) {
         debug!("tracking: {} wants", cids.len());
         let peers = self.peer_manager.peers_for_session(self.id()).await;
 
         for cid in cids {
-            if self.wants.contains_key(&cid) {
+            if let Some(wi) = self.wants.get_mut(&cid) {
+                wi.priority = wi.priority.max(priority);
                 continue;
             }
             // Create the want info
-            let mut want_info = WantInfo::new(self.peer_response_tracker.clone());
+            let mut want_info = WantInfo::new(self.peer_response_tracker.clone(), priority);
 
             // For each available peer, register any information we know about
             // whether the peer has the block
@@ -780,6 +874,49 @@ impl LoopState {
         }
     }
 
+    /// Gives up on want-blocks that were not answered within the per block timeout.
+    ///
+    /// The peer is treated as if it sent a DONT_HAVE, so the want is sent to the next best
+    /// peer in the session, or broadcast if no other peer is known to have the block.
+    async fn check_block_timeouts(&mut self, now: Instant) {
+        let timeout = match self.per_block_timeout {
+            Some(timeout) if !self.paused => timeout,
+            _ => return,
+        };
+
+        let timed_out = timed_out_wants(&self.wants, now, timeout);
+        if timed_out.is_empty() {
+            return;
+        }
+
+        let mut exhausted = Vec::new();
+        for (cid, peer) in timed_out {
+            inc!(BitswapMetrics::BlockTimeouts);
+            debug!(
+                "session:{}: block {} timed out from {}",
+                self.id(),
+                cid,
+                peer
+            );
+            if let Some(wi) = self.wants.get_mut(&cid) {
+                wi.sent_to = None;
+                wi.sent_at = None;
+                wi.set_peer_block_presence(peer, BlockPresence::DontHave)
+                    .await;
+                if wi.best_peer.is_none() {
+                    exhausted.push(cid);
+                }
+            }
+        }
+
+        // No other peer in the session is known to have these, search for more providers.
+        self.process_exhausted_wants(exhausted).await;
+
+        if self.peer_manager.session_has_peers(self.id()).await {
+            self.send_next_wants(Vec::new()).await;
+        }
+    }
+
     /// Sends wants to peers according to the latest information about which peers have / dont have blocks.
     async fn send_next_wants(&mut self, newly_available: Vec<PeerId>) {
         debug!(
@@ -804,6 +941,7 @@ impl LoopState {
             if let Some(ref best_peer) = wi.best_peer {
                 // Record that we are sending a want-block for this want to the peer
                 wi.sent_to = Some(*best_peer);
+                wi.sent_at = Some(Instant::now());
 
                 // Send a want-block to the chosen peer.
                 to_send.for_peer(best_peer).want_blocks.insert(*cid);
@@ -840,27 +978,34 @@ impl LoopState {
                 snd.want_haves.insert(cid);
             }
 
-            // Send the wants to the peer.
+            // Send the wants to the peer, grouped by their priority.
             // Note that the PeerManager ensures that we don't sent duplicate
             // want-haves / want-blocks to a peer, and that want-blocks take
             // precedence over want-haves.
-            let mut want_blocks: Vec<_> = snd.want_blocks.into_iter().collect();
-            let want_haves: Vec<_> = snd.want_haves.into_iter().collect();
-            self.peer_manager
-                .send_wants(&peer, &want_blocks, &want_haves)
-                .await;
-            // Record which peers we send want-block to
-            self.sent_want_blocks_tracker
-                .add_sent_want_blocks_to(&peer, &want_blocks);
+            let mut by_priority: BTreeMap<Option<Priority>, WantLists> = BTreeMap::new();
+            for cid in snd.want_blocks {
+                let priority = self.wants.get(&cid).and_then(|wi| wi.priority);
+                by_priority.entry(priority).or_default().0.push(cid);
+            }
+            for cid in snd.want_haves {
+                let priority = self.wants.get(&cid).and_then(|wi| wi.priority);
+                by_priority.entry(priority).or_default().1.push(cid);
+            }
+            let mut sent = Vec::new();
+            for (priority, (want_blocks, want_haves)) in by_priority {
+                self.peer_manager
+                    .send_wants(&peer, &want_blocks, &want_haves, priority)
+                    .await;
+                // Record which peers we send want-block to
+                self.sent_want_blocks_tracker
+                    .add_sent_want_blocks_to(&peer, &want_blocks);
+                sent.extend(want_blocks);
+                sent.extend(want_haves);
+            }
 
             // Inform the session that we've sent the wants.
             // was "on_send"
-            want_blocks.extend(want_haves);
-            if let Err(err) = self
-                .session_ops
-                .send(super::Op::WantsSent(want_blocks))
-                .await
-            {
+            if let Err(err) = self.session_ops.send(super::Op::WantsSent(sent)).await {
                 warn!("unabel to send broadcast op: {:?}", err);
             }
         }
@@ -925,13 +1070,33 @@ impl LoopState {
     fn set_want_sent_to(&mut self, cid: &Cid, peer: Option<PeerId>) {
         if let Some(wi) = self.wants.get_mut(cid) {
             wi.sent_to = peer;
+            wi.sent_at = peer.map(|_| Instant::now());
         }
     }
 }
 
+/// Returns the wants, and the peer they were sent to, that have been waiting on a
+/// want-block for at least `timeout`.
+fn timed_out_wants(
+    wants: &AHashMap<Cid, WantInfo>,
+    now: Instant,
+    timeout: Duration,
+) -> Vec<(Cid, PeerId)> {
+    wants
+        .iter()
+        .filter_map(|(cid, wi)| match (wi.sent_to, wi.sent_at) {
+            (Some(peer), Some(at)) if now.saturating_duration_since(at) >= timeout => {
+                Some((*cid, peer))
+            }
+            _ => None,
+        })
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::block::tests::create_random_block_v1;
 
     #[test]
     fn test_block_presence_order() {
@@ -939,4 +1104,31 @@ mod tests {
         assert!(BlockPresence::Unknown > BlockPresence::DontHave);
         assert!(BlockPresence::Have > BlockPresence::Unknown);
     }
+
+    #[test]
+    fn test_timed_out_wants() {
+        let timeout = Duration::from_secs(5);
+        let now = Instant::now();
+        let peer = PeerId::random();
+
+        let mut wants = AHashMap::new();
+        let (late, recent, unsent) = (
+            *create_random_block_v1().cid(),
+            *create_random_block_v1().cid(),
+            *create_random_block_v1().cid(),
+        );
+        for (cid, sent_at) in [
+            (late, Some(now - Duration::from_secs(6))),
+            (recent, Some(now - Duration::from_secs(1))),
+            (unsent, None),
+        ] {
+            let mut wi = WantInfo::new(PeerResponseTracker::default(), None);
+            wi.sent_to = sent_at.map(|_| peer);
+            wi.sent_at = sent_at;
+            wants.insert(cid, wi);
+        }
+
+        assert_eq!(timed_out_wants(&wants, now, timeout), vec![(late, peer)]);
+        assert!(timed_out_wants(&wants, now - Duration::from_secs(2), timeout).is_empty());
+    }
 }
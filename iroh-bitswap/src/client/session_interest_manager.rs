@@ -2,12 +2,28 @@ use std::sync::Arc;
 
 use ahash::{AHashMap, AHashSet};
 use cid::Cid;
-use tokio::sync::RwLock;
+use tokio::sync::{broadcast, RwLock};
 use tracing::debug;
 
 use crate::Block;
 
-#[derive(Default, Debug, Clone)]
+/// How many [`WantlistDelta`]s are buffered for each receiver of
+/// [`SessionInterestManager::subscribe`].
+const WANTLIST_CHANGES_CAPACITY: usize = 1024;
+
+/// A change of the blocks a session wants.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WantlistDelta {
+    /// The session, the `ctx` blocks are requested with.
+    pub session: u64,
+    /// Blocks the session started to want.
+    pub added: Vec<Cid>,
+    /// Blocks the session no longer wants, as they were received or cancelled, or the
+    /// session ended.
+    pub removed: Vec<Cid>,
+}
+
+#[derive(Debug, Clone)]
 pub struct SessionInterestManager {
     /// Map of cids -> sessions -> bool
     ///
@@ -18,18 +34,53 @@ pub struct SessionInterestManager {
     /// the block, but still wants to receive messages from peers who have
     /// the block as they may have other blocks the session is interested in.
     wants: Arc<RwLock<AHashMap<Cid, AHashMap<u64, bool>>>>,
+    changes: broadcast::Sender<WantlistDelta>,
+}
+
+impl Default for SessionInterestManager {
+    fn default() -> Self {
+        let (changes, _) = broadcast::channel(WANTLIST_CHANGES_CAPACITY);
+        SessionInterestManager {
+            wants: Default::default(),
+            changes,
+        }
+    }
 }
 
 impl SessionInterestManager {
+    /// Subscribes to the changes of the blocks wanted by sessions.
+    ///
+    /// Receivers that fall more than [`WANTLIST_CHANGES_CAPACITY`] deltas behind miss the
+    /// oldest ones, and are told how many with [`broadcast::error::RecvError::Lagged`].
+    pub fn subscribe(&self) -> broadcast::Receiver<WantlistDelta> {
+        self.changes.subscribe()
+    }
+
+    fn notify(&self, session: u64, added: Vec<Cid>, removed: Vec<Cid>) {
+        if added.is_empty() && removed.is_empty() {
+            return;
+        }
+        // fails only if nobody is subscribed
+        let _ = self.changes.send(WantlistDelta {
+            session,
+            added,
+            removed,
+        });
+    }
+
     /// When the client asks the session for blocks, the session calls this methods.
     pub async fn record_session_interest(&self, session: u64, keys: &[Cid]) {
         debug!("session:{} record_session_interest: {:?}", session, keys);
         let wants = &mut *self.wants.write().await;
 
+        let mut added = Vec::new();
         for key in keys {
             // Record that the session wants the block.
-            wants.entry(*key).or_default().insert(session, true);
+            if wants.entry(*key).or_default().insert(session, true) != Some(true) {
+                added.push(*key);
+            }
         }
+        self.notify(session, added, Vec::new());
     }
 
     /// When the session shuts down, this is called.
@@ -39,8 +90,11 @@ impl SessionInterestManager {
         let wants = &mut *self.wants.write().await;
 
         let mut deleted_keys = Vec::new();
+        let mut removed = Vec::new();
         for (key, wants) in wants.iter_mut() {
-            wants.remove(&session);
+            if wants.remove(&session) == Some(true) {
+                removed.push(*key);
+            }
 
             if wants.is_empty() {
                 deleted_keys.push(*key);
@@ -50,6 +104,7 @@ impl SessionInterestManager {
         for key in &deleted_keys {
             wants.remove(key);
         }
+        self.notify(session, Vec::new(), removed);
 
         deleted_keys
     }
@@ -63,16 +118,19 @@ impl SessionInterestManager {
         );
         let wants = &mut *self.wants.write().await;
 
+        let mut removed = Vec::new();
         for key in keys {
             if let Some(wants) = wants.get_mut(key) {
                 if let Some(wanted) = wants.get_mut(&session) {
                     if *wanted {
                         // Mark as unwanted
                         *wanted = false;
+                        removed.push(*key);
                     }
                 }
             }
         }
+        self.notify(session, Vec::new(), removed);
     }
 
     /// Called when a request is cancelled.
@@ -86,10 +144,13 @@ impl SessionInterestManager {
         let wants = &mut *self.wants.write().await;
 
         let mut deleted_keys = Vec::new();
+        let mut removed = Vec::new();
 
         for key in keys {
             if let Some(wants) = wants.get_mut(key) {
-                wants.remove(&session);
+                if wants.remove(&session) == Some(true) {
+                    removed.push(*key);
+                }
 
                 if wants.is_empty() {
                     deleted_keys.push(*key);
@@ -101,6 +162,7 @@ impl SessionInterestManager {
         for key in &deleted_keys {
             wants.remove(key);
         }
+        self.notify(session, Vec::new(), removed);
 
         deleted_keys
     }
@@ -191,3 +253,39 @@ impl SessionInterestManager {
         session_keys
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::tests::create_random_block_v1;
+
+    #[tokio::test]
+    async fn test_wantlist_changes() {
+        let manager = SessionInterestManager::default();
+        let mut changes = manager.subscribe();
+        let a = *create_random_block_v1().cid();
+        let b = *create_random_block_v1().cid();
+        let delta = |session, added: &[Cid], removed: &[Cid]| WantlistDelta {
+            session,
+            added: added.to_vec(),
+            removed: removed.to_vec(),
+        };
+
+        manager.record_session_interest(1, &[a, b]).await;
+        assert_eq!(changes.try_recv().unwrap(), delta(1, &[a, b], &[]));
+        // wanting a block again is no change
+        manager.record_session_interest(1, &[a]).await;
+        manager.record_session_interest(2, &[a]).await;
+        assert_eq!(changes.try_recv().unwrap(), delta(2, &[a], &[]));
+
+        // received, but still interested
+        manager.remove_session_wants(1, &[a]).await;
+        assert_eq!(changes.try_recv().unwrap(), delta(1, &[], &[a]));
+        manager.remove_session_interested(1, &[a, b]).await;
+        assert_eq!(changes.try_recv().unwrap(), delta(1, &[], &[b]));
+        manager.remove_session(2).await;
+        assert_eq!(changes.try_recv().unwrap(), delta(2, &[], &[a]));
+        manager.remove_session(1).await;
+        assert!(changes.try_recv().is_err());
+    }
+}
@@ -1,7 +1,7 @@
 use std::{
     sync::{
         atomic::{AtomicU64, Ordering},
-        Arc,
+        Arc, Weak,
     },
     time::Duration,
 };
@@ -18,7 +18,8 @@ use tracing::debug;
 use crate::{network::Network, Block};
 
 use super::{
-    block_presence_manager::BlockPresenceManager, peer_manager::PeerManager, session::Session,
+    block_presence_manager::BlockPresenceManager, message_queue::Config as MessageQueueConfig,
+    peer_manager::PeerManager, preferred_peers::PreferredPeers, session::Session,
     session_interest_manager::SessionInterestManager,
 };
 
@@ -27,11 +28,24 @@ pub struct SessionManager {
     inner: Arc<Inner>,
 }
 
+/// A reference to a [`SessionManager`] that does not keep it alive.
+#[derive(Debug, Clone)]
+pub struct WeakSessionManager {
+    inner: Weak<Inner>,
+}
+
+impl WeakSessionManager {
+    pub fn upgrade(&self) -> Option<SessionManager> {
+        self.inner.upgrade().map(|inner| SessionManager { inner })
+    }
+}
+
 #[derive(Debug)]
 struct Inner {
     session_interest_manager: SessionInterestManager,
     block_presence_manager: BlockPresenceManager,
     peer_manager: PeerManager,
+    preferred_peers: PreferredPeers,
     network: Network,
     sessions: RwLock<AHashMap<u64, Session>>,
     session_index: AtomicU64,
@@ -43,16 +57,19 @@ impl SessionManager {
         self_id: PeerId,
         network: Network,
         notify: async_broadcast::Sender<Block>,
+        queue_config: MessageQueueConfig,
     ) -> Self {
         let session_interest_manager = SessionInterestManager::default();
         let block_presence_manager = BlockPresenceManager::new();
-        let peer_manager = PeerManager::new(self_id, network.clone()).await;
+        let peer_manager =
+            PeerManager::with_queue_config(self_id, network.clone(), queue_config).await;
 
         let this = SessionManager {
             inner: Arc::new(Inner {
                 session_interest_manager,
                 block_presence_manager,
                 peer_manager,
+                preferred_peers: Default::default(),
                 network,
                 sessions: Default::default(),
                 session_index: Default::default(),
@@ -60,15 +77,19 @@ impl SessionManager {
             }),
         };
 
+        // The peer manager is owned by the session manager, a strong reference from its
+        // callback would keep both alive forever.
         this.inner
             .peer_manager
             .set_cb({
-                let this = this.clone();
+                let this = this.downgrade();
                 move |peer: PeerId, dont_haves: Vec<Cid>| {
                     let this = this.clone();
                     async move {
-                        this.receive_from(Some(peer), &[][..], &[][..], &dont_haves)
-                            .await;
+                        if let Some(this) = this.upgrade() {
+                            this.receive_from(Some(peer), &[][..], &[][..], &dont_haves)
+                                .await;
+                        }
                     }
                     .boxed()
                 }
@@ -81,10 +102,25 @@ impl SessionManager {
         &self.inner.peer_manager
     }
 
+    /// The peers sessions ask first, shared by all sessions.
+    pub fn preferred_peers(&self) -> &PreferredPeers {
+        &self.inner.preferred_peers
+    }
+
+    pub fn downgrade(&self) -> WeakSessionManager {
+        WeakSessionManager {
+            inner: Arc::downgrade(&self.inner),
+        }
+    }
+
     pub fn session_interest_manager(&self) -> &SessionInterestManager {
         &self.inner.session_interest_manager
     }
 
+    pub fn block_presence_manager(&self) -> &BlockPresenceManager {
+        &self.inner.block_presence_manager
+    }
+
     pub async fn stop(self) -> Result<()> {
         let inner = Arc::try_unwrap(self.inner)
             .map_err(|_| anyhow!("session manager refs not shutdown"))?;
@@ -106,15 +142,38 @@ impl SessionManager {
         Ok(())
     }
 
+    /// Forgets all sessions, without waiting for them to stop.
+    ///
+    /// Sessions reference their session manager, so tracked sessions keep it, the peer
+    /// manager and all message queues alive. Once the last reference to a session is gone
+    /// its worker shuts down.
+    pub fn close_sessions(&self) {
+        if let Ok(mut sessions) = self.inner.sessions.try_write() {
+            sessions.clear();
+            return;
+        }
+        // the sessions are in use, close them once they are released
+        if let Ok(rt) = tokio::runtime::Handle::try_current() {
+            let inner = self.inner.clone();
+            rt.spawn(async move { inner.sessions.write().await.clear() });
+        }
+    }
+
     /// Initializes a new session and starts tracking it.
     pub async fn new_session(
         &self,
         provider_search_delay: Duration,
         rebroadcast_delay: Duration,
+        per_block_timeout: Option<Duration>,
     ) -> Session {
         let id = self.get_next_session_id().await;
-        self.new_session_with_id(id, provider_search_delay, rebroadcast_delay)
-            .await
+        self.new_session_with_id(
+            id,
+            provider_search_delay,
+            rebroadcast_delay,
+            per_block_timeout,
+        )
+        .await
     }
 
     async fn new_session_with_id(
@@ -122,6 +181,7 @@ impl SessionManager {
         session_id: u64,
         provider_search_delay: Duration,
         rebroadcast_delay: Duration,
+        per_block_timeout: Option<Duration>,
     ) -> Session {
         inc!(BitswapMetrics::SessionsCreated);
 
@@ -135,6 +195,7 @@ impl SessionManager {
             self.inner.notify.clone(),
             provider_search_delay,
             rebroadcast_delay,
+            per_block_timeout,
         )
         .await;
 
@@ -151,13 +212,19 @@ impl SessionManager {
         session_id: u64,
         provider_search_delay: Duration,
         rebroadcast_delay: Duration,
+        per_block_timeout: Option<Duration>,
     ) -> Session {
         if let Some(session) = self.get_session(session_id).await {
             return session;
         }
 
-        self.new_session_with_id(session_id, provider_search_delay, rebroadcast_delay)
-            .await
+        self.new_session_with_id(
+            session_id,
+            provider_search_delay,
+            rebroadcast_delay,
+            per_block_timeout,
+        )
+        .await
     }
 
     pub async fn get_session(&self, session_id: u64) -> Option<Session> {
@@ -1,10 +1,12 @@
 use std::{
     collections::VecDeque,
     fmt::Debug,
+    sync::{Arc, Mutex},
     task::{Context, Poll},
     time::{Duration, Instant},
 };
 
+use ahash::AHashMap;
 use asynchronous_codec::Framed;
 use futures::StreamExt;
 use futures::{
@@ -15,12 +17,13 @@ use iroh_metrics::{bitswap::BitswapMetrics, core::MRecorder, inc};
 use libp2p::core::{
     muxing::SubstreamBox,
     upgrade::{InboundUpgrade, NegotiationError, OutboundUpgrade, UpgradeError},
-    Negotiated,
+    ConnectedPoint, Negotiated,
 };
 use libp2p::swarm::{
-    ConnectionHandler, ConnectionHandlerEvent, ConnectionHandlerUpgrErr, KeepAlive,
-    NegotiatedSubstream, SubstreamProtocol,
+    ConnectionHandler, ConnectionHandlerEvent, ConnectionHandlerUpgrErr, IntoConnectionHandler,
+    KeepAlive, NegotiatedSubstream, SubstreamProtocol,
 };
+use libp2p::PeerId;
 use smallvec::SmallVec;
 use tokio::sync::oneshot;
 use tracing::{error, trace, warn};
@@ -84,6 +87,8 @@ pub enum BitswapHandlerIn {
     // TODO: do we need a close?
     Protect,
     Unprotect,
+    /// Whether wants sent to the peer are outstanding, which keeps the connection alive.
+    WantsOutstanding(bool),
 }
 
 type BitswapConnectionHandlerEvent = ConnectionHandlerEvent<
@@ -93,6 +98,48 @@ type BitswapConnectionHandlerEvent = ConnectionHandlerEvent<
     BitswapHandlerError,
 >;
 
+/// Creates the [`BitswapHandler`] of a connection once the remote peer is known, restricted
+/// to the protocol preferred for that peer, if any.
+#[derive(Debug, Clone)]
+pub struct BitswapHandlerProto {
+    protocol_config: ProtocolConfig,
+    idle_timeout: Duration,
+    want_keep_alive: Duration,
+    protocol_preferences: Arc<Mutex<AHashMap<PeerId, ProtocolId>>>,
+}
+
+impl BitswapHandlerProto {
+    pub fn new(
+        protocol_config: ProtocolConfig,
+        idle_timeout: Duration,
+        want_keep_alive: Duration,
+        protocol_preferences: Arc<Mutex<AHashMap<PeerId, ProtocolId>>>,
+    ) -> Self {
+        Self {
+            protocol_config,
+            idle_timeout,
+            want_keep_alive,
+            protocol_preferences,
+        }
+    }
+}
+
+impl IntoConnectionHandler for BitswapHandlerProto {
+    type Handler = BitswapHandler;
+
+    fn into_handler(self, peer_id: &PeerId, _: &ConnectedPoint) -> Self::Handler {
+        let mut protocol_config = self.protocol_config;
+        if let Some(protocol) = self.protocol_preferences.lock().unwrap().get(peer_id) {
+            protocol_config.protocol_ids = vec![*protocol];
+        }
+        BitswapHandler::new(protocol_config, self.idle_timeout, self.want_keep_alive)
+    }
+
+    fn inbound_protocol(&self) -> ProtocolConfig {
+        self.protocol_config.clone()
+    }
+}
+
 /// Protocol Handler that manages a single long-lived substream with a peer.
 pub struct BitswapHandler {
     /// Upgrade configuration for the bitswap protocol.
@@ -119,6 +166,13 @@ pub struct BitswapHandler {
 
     /// Flag determining whether to maintain the connection to the peer.
     keep_alive: KeepAlive,
+
+    /// The longest time the connection is kept alive for outstanding wants.
+    want_keep_alive: Duration,
+
+    /// Until when the connection is kept alive for outstanding wants, `None` if there are
+    /// none.
+    wants_deadline: Option<Instant>,
 }
 
 impl Debug for BitswapHandler {
@@ -139,13 +193,19 @@ impl Debug for BitswapHandler {
             .field("idle_timeout", &self.idle_timeout)
             .field("upgrade_errors", &self.upgrade_errors)
             .field("keep_alive", &self.keep_alive)
+            .field("want_keep_alive", &self.want_keep_alive)
+            .field("wants_deadline", &self.wants_deadline)
             .finish()
     }
 }
 
 impl BitswapHandler {
     /// Builds a new [`BitswapHandler`].
-    pub fn new(protocol_config: ProtocolConfig, idle_timeout: Duration) -> Self {
+    pub fn new(
+        protocol_config: ProtocolConfig,
+        idle_timeout: Duration,
+        want_keep_alive: Duration,
+    ) -> Self {
         Self {
             listen_protocol: SubstreamProtocol::new(protocol_config, ()),
             inbound_substreams: Default::default(),
@@ -155,6 +215,8 @@ impl BitswapHandler {
             idle_timeout,
             upgrade_errors: VecDeque::new(),
             keep_alive: KeepAlive::Until(Instant::now() + Duration::from_secs(INITIAL_KEEP_ALIVE)),
+            want_keep_alive,
+            wants_deadline: None,
             events: Default::default(),
         }
     }
@@ -218,6 +280,15 @@ impl ConnectionHandler for BitswapHandler {
                 self.keep_alive =
                     KeepAlive::Until(Instant::now() + Duration::from_secs(INITIAL_KEEP_ALIVE));
             }
+            BitswapHandlerIn::WantsOutstanding(true) => {
+                // the limit counts from when the wants became outstanding
+                let want_keep_alive = self.want_keep_alive;
+                self.wants_deadline
+                    .get_or_insert_with(|| Instant::now() + want_keep_alive);
+            }
+            BitswapHandlerIn::WantsOutstanding(false) => {
+                self.wants_deadline = None;
+            }
         }
     }
 
@@ -233,7 +304,10 @@ impl ConnectionHandler for BitswapHandler {
     }
 
     fn connection_keep_alive(&self) -> KeepAlive {
-        self.keep_alive
+        match (self.keep_alive, self.wants_deadline) {
+            (KeepAlive::Until(idle), Some(wants)) => KeepAlive::Until(idle.max(wants)),
+            (keep_alive, _) => keep_alive,
+        }
     }
 
     fn poll(&mut self, cx: &mut Context<'_>) -> Poll<BitswapConnectionHandlerEvent> {
@@ -2,7 +2,7 @@
 //!
 //! Supports the versions `1.0.0`, `1.1.0` and `1.2.0`.
 
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::fmt::Debug;
 use std::pin::Pin;
 use std::sync::{Arc, Mutex};
@@ -10,10 +10,10 @@ use std::task::{Context, Poll};
 use std::time::{Duration, Instant};
 
 use ahash::AHashMap;
-use anyhow::Result;
+use anyhow::{bail, ensure, Result};
 use async_trait::async_trait;
 use cid::Cid;
-use handler::{BitswapHandler, HandlerEvent};
+use handler::{BitswapHandlerProto, HandlerEvent};
 use iroh_metrics::record;
 use iroh_metrics::{bitswap::BitswapMetrics, core::MRecorder, inc};
 use libp2p::core::connection::ConnectionId;
@@ -24,12 +24,12 @@ use libp2p::swarm::{
     NotifyHandler, PollParameters,
 };
 use libp2p::{Multiaddr, PeerId};
-use tokio::sync::{mpsc, oneshot};
+use tokio::sync::{broadcast, mpsc, oneshot};
 use tokio::task::JoinHandle;
 use tracing::{debug, trace, warn};
 
 use self::client::{Client, Config as ClientConfig};
-use self::message::BitswapMessage;
+use self::message::{BitswapMessage, Priority};
 use self::network::Network;
 use self::network::OutEvent;
 use self::protocol::ProtocolConfig;
@@ -48,9 +48,13 @@ pub mod message;
 pub mod peer_task_queue;
 
 pub use self::block::{tests::*, Block};
+pub use self::client::{PriorityDecay, WantlistDelta};
 pub use self::protocol::ProtocolId;
 
 const DIAL_BACK_OFF: Duration = Duration::from_secs(10 * 60);
+const DEFAULT_DIAL_FAILURE_THRESHOLD: usize = 3;
+/// Default of [`Config::dial_timeout`].
+pub const DEFAULT_DIAL_TIMEOUT: Duration = Duration::from_secs(20);
 
 type DialMap = AHashMap<
     PeerId,
@@ -65,16 +69,40 @@ pub struct Bitswap<S: Store> {
     network: Network,
     protocol_config: ProtocolConfig,
     idle_timeout: Duration,
-    peers: Arc<Mutex<AHashMap<PeerId, PeerState>>>,
+    want_keep_alive: Duration,
+    peers: Arc<Mutex<AHashMap<PeerId, KnownPeer>>>,
     dials: Arc<Mutex<DialMap>>,
+    /// The bitswap protocols each peer announced via identify.
+    peer_protocols: Arc<Mutex<AHashMap<PeerId, Vec<ProtocolId>>>>,
+    /// Protocols to use with specific peers, instead of the best supported one.
+    protocol_preferences: Arc<Mutex<AHashMap<PeerId, ProtocolId>>>,
+    /// Consecutive failed dials per peer, with the time of the last failure.
+    dial_failures: Arc<Mutex<AHashMap<PeerId, (usize, Instant)>>>,
+    dial_failure_threshold: usize,
     /// Set to true when dialing should be disabled because we have reached the conn limit.
     pause_dialing: bool,
+    peer_state_events: bool,
     client: Client<S>,
     server: Option<Server<S>>,
-    incoming_messages: mpsc::Sender<(PeerId, BitswapMessage)>,
+    incoming_messages: mpsc::Sender<(PeerId, BitswapMessage, Vec<Cid>)>,
     peers_connected: mpsc::Sender<PeerId>,
-    peers_disconnected: mpsc::Sender<PeerId>,
+    /// Peers that got disconnected, with the state their connection changed to.
+    peers_disconnected: mpsc::Sender<(PeerId, ConnState)>,
     _workers: Arc<Vec<JoinHandle<()>>>,
+    _sessions: Arc<SessionsGuard<S>>,
+}
+
+/// Closes the sessions of the client once the last clone of the behaviour is dropped.
+///
+/// Sessions and the session manager reference each other, without this they would keep
+/// each other, their workers and timers alive after the behaviour is gone.
+#[derive(Debug)]
+struct SessionsGuard<S: Store>(Client<S>);
+
+impl<S: Store> Drop for SessionsGuard<S> {
+    fn drop(&mut self) {
+        self.0.close_sessions();
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -98,6 +126,63 @@ impl PeerState {
     }
 }
 
+/// The state of the connection to a peer, as seen by bitswap.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnState {
+    /// Connected, but not yet confirmed to speak bitswap.
+    Connected,
+    /// Connected and speaking bitswap with the given protocol.
+    Responsive(ProtocolId),
+    /// Connected, but not speaking bitswap.
+    Unresponsive,
+    /// Not connected. Disconnected peers are forgotten, so this is only reported as part of
+    /// a [`BitswapEvent::PeerState`] transition.
+    Disconnected,
+    /// Dialing was refused, as the connection limit was reached. Until a connection is
+    /// established or closed, no peer is dialed. Like [`ConnState::Disconnected`], this is
+    /// only reported as part of a [`BitswapEvent::PeerState`] transition.
+    ConnectionLimit,
+    /// Dialing failed. Once it failed [`Config::dial_failure_threshold`] times in a row,
+    /// the peer is not dialed again for a while.
+    DialFailure,
+}
+
+/// The state bitswap keeps for a single peer, see [`Bitswap::inspect_ledger`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LedgerSnapshot {
+    /// The state of the connection to the peer, `None` if there is no record of the peer.
+    pub conn_state: Option<ConnState>,
+    /// The blocks the peer wants from us.
+    pub wants: Vec<Cid>,
+    /// Blocks and presences queued to be sent to the peer.
+    pub pending: Vec<Cid>,
+    /// Blocks and presences currently being sent to the peer.
+    pub active: Vec<Cid>,
+    /// Bytes of blocks sent to the peer, only tracked by the server.
+    pub bytes_sent: u64,
+    /// Bytes of blocks received from the peer, only tracked by the server.
+    pub bytes_received: u64,
+}
+
+impl From<PeerState> for ConnState {
+    fn from(state: PeerState) -> Self {
+        match state {
+            PeerState::Connected(_) => ConnState::Connected,
+            PeerState::Responsive(_, protocol) => ConnState::Responsive(protocol),
+            PeerState::Unresponsive => ConnState::Unresponsive,
+            PeerState::Disconnected => ConnState::Disconnected,
+            PeerState::DialFailure(_) => ConnState::DialFailure,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct KnownPeer {
+    state: PeerState,
+    /// The last time the state of this peer was updated, e.g. by receiving a message.
+    last_seen: Instant,
+}
+
 #[derive(Debug)]
 pub struct Config {
     pub client: ClientConfig,
@@ -105,6 +190,22 @@ pub struct Config {
     pub server: Option<ServerConfig>,
     pub protocol: ProtocolConfig,
     pub idle_timeout: Duration,
+    /// Longest time a connection is kept alive beyond the `idle_timeout` while wants sent
+    /// to the peer are outstanding, counted from when they were sent.
+    ///
+    /// Peers can take a while to answer, e.g. while they fetch the blocks themselves.
+    /// `Duration::ZERO` closes idle connections regardless of outstanding wants.
+    pub want_keep_alive: Duration,
+    /// Emit [`BitswapEvent::PeerState`] whenever the connection state of a peer changes.
+    pub peer_state_events: bool,
+    /// Number of consecutive failed dials after which a peer is not dialed again for a while.
+    pub dial_failure_threshold: usize,
+    /// Maximum time a dial to a peer may take, before it is counted as failed.
+    ///
+    /// Dials to unreachable addresses otherwise wait for the transport timeout, so a lower
+    /// value lets sessions move on to other providers sooner. Timeouts count towards
+    /// `dial_failure_threshold`.
+    pub dial_timeout: Duration,
 }
 
 impl Config {
@@ -123,6 +224,10 @@ impl Default for Config {
             server: Some(ServerConfig::default()),
             protocol: ProtocolConfig::default(),
             idle_timeout: Duration::from_secs(30),
+            want_keep_alive: Duration::from_secs(5 * 60),
+            peer_state_events: false,
+            dial_failure_threshold: DEFAULT_DIAL_FAILURE_THRESHOLD,
+            dial_timeout: DEFAULT_DIAL_TIMEOUT,
         }
     }
 }
@@ -136,7 +241,7 @@ pub trait Store: Debug + Clone + Send + Sync + 'static {
 
 impl<S: Store> Bitswap<S> {
     pub async fn new(self_id: PeerId, store: S, config: Config) -> Self {
-        let network = Network::new(self_id);
+        let network = Network::new(self_id).with_dial_timeout(config.dial_timeout);
         let (server, cb) = if let Some(config) = config.server {
             let server = Server::new(network.clone(), store.clone(), config).await;
             let cb = server.received_blocks_cb();
@@ -157,7 +262,10 @@ impl<S: Store> Bitswap<S> {
 
             async move {
                 // process messages serially but without blocking the p2p loop
-                while let Some((peer, message)) = receiver_msg.recv().await {
+                while let Some((peer, message, invalid)) = receiver_msg.recv().await {
+                    if !invalid.is_empty() {
+                        client.receive_invalid_blocks(&peer, &invalid).await;
+                    }
                     if let Some(ref server) = server {
                         futures::future::join(
                             client.receive_message(&peer, &message),
@@ -197,15 +305,15 @@ impl<S: Store> Bitswap<S> {
 
             async move {
                 // process messages serially but without blocking the p2p loop
-                while let Some(peer) = receiver_dis.recv().await {
+                while let Some((peer, reason)) = receiver_dis.recv().await {
                     if let Some(ref server) = server {
                         futures::future::join(
-                            client.peer_disconnected(&peer),
+                            client.peer_disconnected(&peer, reason),
                             server.peer_disconnected(&peer),
                         )
                         .await;
                     } else {
-                        client.peer_disconnected(&peer).await;
+                        client.peer_disconnected(&peer, reason).await;
                     }
                 }
             }
@@ -215,15 +323,22 @@ impl<S: Store> Bitswap<S> {
             network,
             protocol_config: config.protocol,
             idle_timeout: config.idle_timeout,
+            want_keep_alive: config.want_keep_alive,
             peers: Default::default(),
             dials: Default::default(),
+            peer_protocols: Default::default(),
+            protocol_preferences: Default::default(),
+            dial_failures: Default::default(),
+            dial_failure_threshold: config.dial_failure_threshold,
             pause_dialing: false,
+            peer_state_events: config.peer_state_events,
             server,
-            client,
+            client: client.clone(),
             incoming_messages: sender_msg,
             peers_connected: sender_con,
             peers_disconnected: sender_dis,
             _workers: Arc::new(workers),
+            _sessions: Arc::new(SessionsGuard(client)),
         }
     }
 
@@ -255,16 +370,158 @@ impl<S: Store> Bitswap<S> {
         Ok(())
     }
 
+    /// Temporarily stops sending wants for the session `ctx`, without cancelling them.
+    pub async fn pause_session(&self, ctx: u64) -> Result<()> {
+        self.client.pause_session(ctx).await
+    }
+
+    /// Resumes sending wants for the session `ctx`.
+    pub async fn resume_session(&self, ctx: u64) -> Result<()> {
+        self.client.resume_session(ctx).await
+    }
+
+    /// Subscribes to the changes of the wantlist, a [`WantlistDelta`] for every session
+    /// that starts or stops wanting blocks.
+    ///
+    /// A block wanted by several sessions is reported for each of them. Up to 1024 deltas
+    /// are buffered for each receiver, a receiver falling further behind misses the
+    /// oldest deltas, and is told how many with
+    /// [`RecvError::Lagged`](broadcast::error::RecvError::Lagged).
+    pub fn wantlist_changes(&self) -> broadcast::Receiver<WantlistDelta> {
+        self.client.wantlist_changes()
+    }
+
     /// Called on identify events from swarm, informing us about available protocols of this peer.
     pub fn on_identify(&self, peer: &PeerId, protocols: &[String]) {
+        let mut protocols: Vec<ProtocolId> =
+            protocols.iter().filter_map(ProtocolId::try_from).collect();
+        protocols.sort();
         if let Some(PeerState::Connected(conn_id)) = self.get_peer_state(peer) {
-            let mut protocols: Vec<ProtocolId> =
-                protocols.iter().filter_map(ProtocolId::try_from).collect();
-            protocols.sort();
-            if let Some(best_protocol) = protocols.last() {
-                self.set_peer_state(peer, PeerState::Responsive(conn_id, *best_protocol));
+            let preferred = self.protocol_preferences.lock().unwrap().get(peer).copied();
+            let protocol = match preferred {
+                Some(preferred) if protocols.contains(&preferred) => Some(preferred),
+                _ => protocols.last().copied(),
+            };
+            if let Some(protocol) = protocol {
+                self.set_peer_state(peer, PeerState::Responsive(conn_id, protocol));
+            }
+        }
+        self.peer_protocols.lock().unwrap().insert(*peer, protocols);
+    }
+
+    /// Uses `protocol` with `peer`, instead of the best protocol both sides support.
+    ///
+    /// Takes effect on the next connection to or from `peer`, an existing connection keeps
+    /// its protocol. Fails if `protocol` is not enabled locally, or not among the
+    /// protocols `peer` announced, which have to be known from identify.
+    pub fn set_peer_protocol_preference(&self, peer: PeerId, protocol: ProtocolId) -> Result<()> {
+        ensure!(
+            self.protocol_config.protocol_ids.contains(&protocol),
+            "protocol {:?} is not enabled",
+            protocol
+        );
+        match self.peer_protocols.lock().unwrap().get(&peer) {
+            Some(protocols) if protocols.contains(&protocol) => {}
+            Some(protocols) => {
+                bail!(
+                    "peer {} does not support {:?}, only {:?}",
+                    peer,
+                    protocol,
+                    protocols
+                )
             }
+            None => bail!("the protocols supported by peer {} are unknown", peer),
+        }
+        debug!("using protocol {:?} with {}", protocol, peer);
+        self.protocol_preferences
+            .lock()
+            .unwrap()
+            .insert(peer, protocol);
+        Ok(())
+    }
+
+    /// Removes the protocol preference for `peer`, see [`Bitswap::set_peer_protocol_preference`].
+    pub fn clear_peer_protocol_preference(&self, peer: &PeerId) {
+        self.protocol_preferences.lock().unwrap().remove(peer);
+    }
+
+    /// Prefers `peer` as provider of the blocks wanted next, e.g. after a successful fetch
+    /// from it, as it likely has related content.
+    ///
+    /// Sessions looking for blocks without known providers ask the connected preferred
+    /// peers first, and only broadcast to all connected peers if none of them has the
+    /// blocks. Among the peers that have a block, preferred ones are more likely to be
+    /// asked for it. The preference halves every minute, unless the peer keeps sending
+    /// wanted blocks, and is dropped once it faded.
+    pub fn mark_preferred(&self, peer: PeerId) {
+        debug!("preferring {} as provider", peer);
+        self.client.mark_preferred_peer(peer);
+    }
+
+    /// Removes the preference for `peer`, see [`Bitswap::mark_preferred`], returning
+    /// whether it had one.
+    pub fn clear_preferred(&self, peer: &PeerId) -> bool {
+        self.client.clear_preferred_peer(peer)
+    }
+
+    /// Returns the state of the connection to `peer`, or `None` if the peer is not known.
+    pub fn conn_state(&self, peer: &PeerId) -> Option<ConnState> {
+        self.get_peer_state(peer).map(ConnState::from)
+    }
+
+    /// Returns the protocol negotiated with `peer`, if it is connected and speaks bitswap.
+    pub fn peer_protocol(&self, peer: &PeerId) -> Option<ProtocolId> {
+        match self.get_peer_state(peer)? {
+            PeerState::Responsive(_, protocol) => Some(protocol),
+            _ => None,
+        }
+    }
+
+    /// Asks only `peer` whether it has the block `key`.
+    ///
+    /// Returns `true` if the peer answered with `HAVE` (or sent the block) and `false` on
+    /// `DONT_HAVE`. The answer is also emitted as [`BitswapEvent::PeerHave`].
+    pub async fn want_have_from(
+        &self,
+        key: &Cid,
+        priority: Option<Priority>,
+        peer: &PeerId,
+    ) -> Result<bool> {
+        self.client.want_have_from(key, priority, peer).await
+    }
+
+    /// Restores the initial priority of the wants for `keys`, if the client is configured
+    /// with a [`PriorityDecay`].
+    pub async fn refresh_wants(&self, keys: &[Cid]) {
+        self.client.refresh_wants(keys).await
+    }
+
+    /// Returns the state kept for `peer`, or `None` if nothing is known about it.
+    pub async fn inspect_ledger(&self, peer: &PeerId) -> Option<LedgerSnapshot> {
+        let conn_state = self.get_peer_state(peer).map(ConnState::from);
+        let snapshot = match self.server {
+            Some(ref server) => server.inspect_ledger(peer).await,
+            None => None,
+        };
+        if conn_state.is_none() && snapshot.is_none() {
+            return None;
+        }
+        Some(LedgerSnapshot {
+            conn_state,
+            ..snapshot.unwrap_or_default()
+        })
+    }
+
+    /// Clears the state kept for `peer`, to force a clean resync with it.
+    ///
+    /// Drops the wants received from the peer and the blocks queued for it, the peer sends
+    /// its wants again when it rebroadcasts its wantlist. Our own wantlist is sent to the
+    /// peer in full with the next message, replacing whatever it tracked for us.
+    pub async fn reset_ledger(&self, peer: &PeerId) {
+        if let Some(ref server) = self.server {
+            server.reset_ledger(peer).await;
         }
+        self.client.reset_peer(peer).await;
     }
 
     pub async fn wantlist_for_peer(&self, peer: &PeerId) -> Vec<Cid> {
@@ -288,8 +545,8 @@ impl<S: Store> Bitswap<S> {
         }
     }
 
-    fn peer_disconnected(&self, peer: PeerId) {
-        if let Err(err) = self.peers_disconnected.try_send(peer) {
+    fn peer_disconnected(&self, peer: PeerId, reason: PeerState) {
+        if let Err(err) = self.peers_disconnected.try_send((peer, reason.into())) {
             warn!(
                 "failed to process peer disconnection from {}: {:?}, dropping",
                 peer, err
@@ -297,11 +554,12 @@ impl<S: Store> Bitswap<S> {
         }
     }
 
-    fn receive_message(&self, peer: PeerId, message: BitswapMessage) {
+    /// Queues `message` for processing, along with the cids of the invalid blocks removed from it.
+    fn receive_message(&self, peer: PeerId, message: BitswapMessage, invalid: Vec<Cid>) {
         inc!(BitswapMetrics::MessagesReceived);
         record!(BitswapMetrics::MessageBytesIn, message.encoded_len() as u64);
         // TODO: Handle backpressure properly
-        if let Err(err) = self.incoming_messages.try_send((peer, message)) {
+        if let Err(err) = self.incoming_messages.try_send((peer, message, invalid)) {
             warn!(
                 "failed to receive message from {}: {:?}, dropping",
                 peer, err
@@ -309,16 +567,123 @@ impl<S: Store> Bitswap<S> {
         }
     }
 
+    /// Returns all known peers, with the time since they were last seen.
+    pub fn known_peers(&self) -> Vec<(PeerId, Duration)> {
+        self.peers
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(peer, known)| (*peer, known.last_seen.elapsed()))
+            .collect()
+    }
+
+    /// Counts the known peers by the protocol negotiated with them.
+    ///
+    /// Peers that are not connected, or do not speak bitswap, are counted under `None`.
+    /// Only peers speaking [`ProtocolId::Bitswap120`] support provider lookups.
+    pub fn protocol_distribution(&self) -> HashMap<Option<ProtocolId>, usize> {
+        let mut distribution = HashMap::new();
+        for known in self.peers.lock().unwrap().values() {
+            let protocol = match known.state {
+                PeerState::Responsive(_, protocol) => Some(protocol),
+                _ => None,
+            };
+            *distribution.entry(protocol).or_default() += 1;
+        }
+        distribution
+    }
+
+    /// Removes known peers that are not connected and were not seen within `max_age`.
+    ///
+    /// Returns the number of removed peers.
+    pub fn prune_known_peers(&self, max_age: Duration) -> usize {
+        let peers = &mut *self.peers.lock().unwrap();
+        let before = peers.len();
+        peers.retain(|_, known| known.state.is_connected() || known.last_seen.elapsed() < max_age);
+        self.peer_protocols
+            .lock()
+            .unwrap()
+            .retain(|peer, _| peers.contains_key(peer));
+        self.dial_failures
+            .lock()
+            .unwrap()
+            .retain(|peer, (_, last)| peers.contains_key(peer) || last.elapsed() < max_age);
+        let pruned = before - peers.len();
+        if pruned > 0 {
+            debug!("pruned {} stale known peers", pruned);
+            record!(BitswapMetrics::PrunedPeers, pruned as u64);
+        }
+        pruned
+    }
+
+    fn emit_peer_state(&self, peer: PeerId, old: PeerState, new: PeerState) {
+        self.emit_conn_state(peer, old.into(), new.into());
+    }
+
+    fn emit_conn_state(&self, peer: PeerId, old: ConnState, new: ConnState) {
+        // e.g. repeated dial failures
+        if self.peer_state_events && old != new {
+            self.network.peer_state(peer, old, new);
+        }
+    }
+
+    /// Abandons the dial `id` to `peer`, after it exceeded the dial timeout.
+    ///
+    /// The timeout counts as a failed dial, unless the peer got connected in the meantime.
+    fn dial_timed_out(&self, peer: PeerId, id: usize) {
+        {
+            let dials = &mut *self.dials.lock().unwrap();
+            if let Some(pending) = dials.get_mut(&peer) {
+                pending.retain(|(dial_id, _)| *dial_id != id);
+                if pending.is_empty() {
+                    dials.remove(&peer);
+                }
+            }
+        }
+        if matches!(self.get_peer_state(&peer), Some(state) if state.is_connected()) {
+            return;
+        }
+        self.record_dial_failure(peer);
+    }
+
+    /// Counts a failed dial to `peer`, and marks it as [`PeerState::DialFailure`].
+    fn record_dial_failure(&self, peer: PeerId) {
+        let now = Instant::now();
+        {
+            let dial_failures = &mut *self.dial_failures.lock().unwrap();
+            let (failures, last) = dial_failures.entry(peer).or_insert((0, now));
+            *failures += 1;
+            *last = now;
+        }
+        self.set_peer_state(&peer, PeerState::DialFailure(now));
+    }
+
+    /// Returns `true` if dialing `peer` failed `dial_failure_threshold` times in a row.
+    fn dial_failure_threshold_reached(&self, peer: &PeerId) -> bool {
+        self.dial_failures
+            .lock()
+            .unwrap()
+            .get(peer)
+            .map(|(failures, _)| *failures >= self.dial_failure_threshold)
+            .unwrap_or_default()
+    }
+
     fn get_peer_state(&self, peer: &PeerId) -> Option<PeerState> {
-        self.peers.lock().unwrap().get(peer).copied()
+        self.peers
+            .lock()
+            .unwrap()
+            .get(peer)
+            .map(|known| known.state)
     }
 
     fn set_peer_state(&self, peer: &PeerId, new_state: PeerState) {
         let peers = &mut *self.peers.lock().unwrap();
         let peer = *peer;
+        let now = Instant::now();
         match peers.entry(peer) {
             std::collections::hash_map::Entry::Occupied(mut entry) => {
-                let old_state = *entry.get();
+                let old_state = entry.get().state;
+                entry.get_mut().last_seen = now;
                 // skip non state changes
                 if old_state == new_state {
                     return;
@@ -337,15 +702,16 @@ impl<S: Store> Bitswap<S> {
                 if new_state == PeerState::Disconnected {
                     entry.remove();
                 } else {
-                    *entry.get_mut() = new_state;
+                    entry.get_mut().state = new_state;
                 }
+                self.emit_peer_state(peer, old_state, new_state);
                 match new_state {
                     PeerState::DialFailure(_)
                     | PeerState::Disconnected
                     | PeerState::Unresponsive => {
                         if old_state.is_connected() {
                             inc!(BitswapMetrics::DisconnectedPeers);
-                            self.peer_disconnected(peer);
+                            self.peer_disconnected(peer, new_state);
                         }
                     }
                     PeerState::Connected(_) => {
@@ -360,14 +726,18 @@ impl<S: Store> Bitswap<S> {
             }
             std::collections::hash_map::Entry::Vacant(entry) => {
                 if new_state != PeerState::Disconnected {
-                    entry.insert(new_state);
+                    entry.insert(KnownPeer {
+                        state: new_state,
+                        last_seen: now,
+                    });
+                    self.emit_peer_state(peer, PeerState::Disconnected, new_state);
                 }
                 match new_state {
                     PeerState::DialFailure(_)
                     | PeerState::Disconnected
                     | PeerState::Unresponsive => {
                         inc!(BitswapMetrics::DisconnectedPeers);
-                        self.peer_disconnected(peer);
+                        self.peer_disconnected(peer, new_state);
                     }
                     PeerState::Connected(_) => {
                         inc!(BitswapMetrics::ConnectedPeers);
@@ -395,15 +765,46 @@ pub enum BitswapEvent {
         peer: PeerId,
         response: oneshot::Sender<Option<Duration>>,
     },
+    /// The answer of `peer` to a want-have sent only to it, see [`Bitswap::want_have_from`].
+    PeerHave { peer: PeerId, key: Cid, have: bool },
+    /// The connection state of `peer` changed, only emitted if enabled in the [`Config`].
+    PeerState {
+        peer: PeerId,
+        old: ConnState,
+        new: ConnState,
+    },
+    /// `peer` was dropped, as its connection changed to `reason`, while the wants for `keys`
+    /// were outstanding with it and no other peer.
+    ///
+    /// Nobody is asked for these blocks anymore, until their sessions search for providers
+    /// again, or a connected peer announces them. Emitted whenever a peer stops being
+    /// usable, i.e. its connection changes to [`ConnState::Disconnected`],
+    /// [`ConnState::Unresponsive`] or [`ConnState::DialFailure`].
+    WantsOrphaned {
+        peer: PeerId,
+        reason: ConnState,
+        keys: Vec<Cid>,
+    },
+    /// `peer` sent `blocks` that nobody wanted, they did not complete any request.
+    ///
+    /// Bitswap does not store blocks, it is up to the receiver of the event to store them,
+    /// in case they are wanted later. Only up to `max_unsolicited_blocks` of the
+    /// [`Config::client`] are passed on per message, and none while the behaviour is not
+    /// keeping up.
+    UnsolicitedBlocks { peer: PeerId, blocks: Vec<Block> },
 }
 
 impl<S: Store> NetworkBehaviour for Bitswap<S> {
-    type ConnectionHandler = BitswapHandler;
+    type ConnectionHandler = BitswapHandlerProto;
     type OutEvent = BitswapEvent;
 
     fn new_handler(&mut self) -> Self::ConnectionHandler {
-        let protocol_config = self.protocol_config.clone();
-        BitswapHandler::new(protocol_config, self.idle_timeout)
+        BitswapHandlerProto::new(
+            self.protocol_config.clone(),
+            self.idle_timeout,
+            self.want_keep_alive,
+            self.protocol_preferences.clone(),
+        )
     }
 
     fn addresses_of_peer(&mut self, _peer_id: &PeerId) -> Vec<Multiaddr> {
@@ -420,6 +821,7 @@ impl<S: Store> NetworkBehaviour for Bitswap<S> {
     ) {
         trace!("connection established {} ({})", peer_id, other_established);
         self.set_peer_state(peer_id, PeerState::Connected(*connection));
+        self.dial_failures.lock().unwrap().remove(peer_id);
         self.pause_dialing = false;
     }
 
@@ -445,11 +847,13 @@ impl<S: Store> NetworkBehaviour for Bitswap<S> {
         error: &DialError,
     ) {
         if let Some(peer_id) = peer_id {
+            inc!(BitswapMetrics::DialFailures);
             if let DialError::ConnectionLimit(_) = error {
                 self.pause_dialing = true;
                 self.set_peer_state(&peer_id, PeerState::Disconnected);
+                self.emit_conn_state(peer_id, ConnState::Disconnected, ConnState::ConnectionLimit);
             } else {
-                self.set_peer_state(&peer_id, PeerState::DialFailure(Instant::now()));
+                self.record_dial_failure(peer_id);
             }
 
             trace!("inject_dial_failure {}, {:?}", peer_id, error);
@@ -497,8 +901,8 @@ impl<S: Store> NetworkBehaviour for Bitswap<S> {
                 // mark peer as responsive
                 self.set_peer_state(&peer_id, PeerState::Responsive(connection, protocol));
 
-                message.verify_blocks();
-                self.receive_message(peer_id, message);
+                let invalid = message.verify_blocks();
+                self.receive_message(peer_id, message, invalid);
             }
             HandlerEvent::FailedToSendMessage { .. } => {
                 // Handle
@@ -513,6 +917,9 @@ impl<S: Store> NetworkBehaviour for Bitswap<S> {
         _: &mut impl PollParameters,
     ) -> Poll<NetworkBehaviourAction<Self::OutEvent, Self::ConnectionHandler>> {
         inc!(BitswapMetrics::NetworkBehaviourActionPollTick);
+        // Only the events queued by the network are handled here. Ledgers live in the
+        // decision engine of the server and are updated as messages arrive, they are never
+        // polled, so idle peers cost nothing per poll.
         // limit work
         for _ in 0..50 {
             match Pin::new(&mut self.network).poll(cx) {
@@ -543,8 +950,11 @@ impl<S: Store> NetworkBehaviour for Bitswap<S> {
                                 }
                                 continue;
                             }
+                            // transient failures are retried right away, until the threshold is
+                            // reached
                             Some(PeerState::DialFailure(dialed))
-                                if dialed.elapsed() < DIAL_BACK_OFF =>
+                                if dialed.elapsed() < DIAL_BACK_OFF
+                                    && self.dial_failure_threshold_reached(&peer) =>
                             {
                                 // Do not bother trying to dial these for now.
                                 if let Err(err) =
@@ -581,6 +991,7 @@ impl<S: Store> NetworkBehaviour for Bitswap<S> {
                             }
                         }
                     }
+                    OutEvent::DialTimeout { peer, id } => self.dial_timed_out(peer, id),
                     OutEvent::GenerateEvent(ev) => {
                         return Poll::Ready(NetworkBehaviourAction::GenerateEvent(ev))
                     }
@@ -619,6 +1030,16 @@ impl<S: Store> NetworkBehaviour for Bitswap<S> {
                         }
                         let _ = response.send(false);
                     }
+                    OutEvent::WantsOutstanding { peer, outstanding } => {
+                        if let Some(PeerState::Responsive(conn_id, _)) = self.get_peer_state(&peer)
+                        {
+                            return Poll::Ready(NetworkBehaviourAction::NotifyHandler {
+                                peer_id: peer,
+                                handler: NotifyHandler::One(conn_id),
+                                event: handler::BitswapHandlerIn::WantsOutstanding(outstanding),
+                            });
+                        }
+                    }
                 },
             }
         }
@@ -639,7 +1060,7 @@ mod tests {
     use libp2p::core::transport::upgrade::Version;
     use libp2p::core::transport::Boxed;
     use libp2p::identity::Keypair;
-    use libp2p::swarm::SwarmEvent;
+    use libp2p::swarm::{ConnectionHandler, SwarmEvent};
     use libp2p::tcp::{tokio::Transport as TcpTransport, Config as TcpConfig};
     use libp2p::yamux::YamuxConfig;
     use libp2p::{noise, PeerId, Swarm, Transport};
@@ -674,6 +1095,607 @@ mod tests {
         assert_send::<&Bitswap<DummyStore>>();
     }
 
+    #[tokio::test]
+    async fn test_drop_releases_client() {
+        let mut session_managers = Vec::new();
+        for _ in 0..50 {
+            let bs = Bitswap::new(PeerId::random(), DummyStore, Config::default()).await;
+            let clone = bs.clone();
+            // tracked sessions reference the session manager
+            let _session = bs.client().get_or_create_session(1).await;
+            session_managers.push(bs.client().session_manager().downgrade());
+            drop(bs);
+            drop(clone);
+        }
+
+        // the workers notice that their channels closed in the background
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while session_managers.iter().any(|sm| sm.upgrade().is_some()) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("session managers were not released");
+    }
+
+    #[tokio::test]
+    async fn test_shared_want_completes_all_sessions() {
+        let bs = Bitswap::new(PeerId::random(), DummyStore, Config::default_client_mode()).await;
+        let block = create_random_block_v1();
+        let key = *block.cid();
+
+        let gets: Vec<_> = [1, 2]
+            .into_iter()
+            .map(|ctx| {
+                let client = bs.client().clone();
+                tokio::task::spawn(async move {
+                    client
+                        .get_block_with_session_id(ctx, &key, &[], None, None)
+                        .await
+                })
+            })
+            .collect();
+
+        let interest = bs.client().session_manager().session_interest_manager();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while interest.interested_sessions(&[key], &[], &[]).await.len() < 2 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("sessions did not want the block");
+
+        // a single copy of the block completes both sessions
+        let mut message = BitswapMessage::new(false);
+        message.add_block(block.clone());
+        bs.client()
+            .receive_message(&PeerId::random(), &message)
+            .await;
+        for get in gets {
+            let received = tokio::time::timeout(Duration::from_secs(5), get)
+                .await
+                .expect("session was not completed")
+                .unwrap()
+                .unwrap();
+            assert_eq!(received, block);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unsolicited_blocks() {
+        let config = Config {
+            client: ClientConfig {
+                max_unsolicited_blocks: 1,
+                ..Default::default()
+            },
+            ..Config::default_client_mode()
+        };
+        let bs = Bitswap::new(PeerId::random(), DummyStore, config).await;
+        let peer = PeerId::random();
+        let blocks = [create_random_block_v1(), create_random_block_v1()];
+        let mut message = BitswapMessage::new(false);
+        for block in &blocks {
+            message.add_block(block.clone());
+        }
+        bs.client().receive_message(&peer, &message).await;
+
+        // nobody wanted the blocks, only one of them is passed on to be stored
+        let mut network = bs.network.clone();
+        let (from, passed_on) = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let event = future::poll_fn(|cx| Pin::new(&mut network).poll(cx)).await;
+                if let OutEvent::GenerateEvent(BitswapEvent::UnsolicitedBlocks { peer, blocks }) =
+                    event
+                {
+                    return (peer, blocks);
+                }
+            }
+        })
+        .await
+        .expect("unsolicited blocks were not passed on");
+        assert_eq!(from, peer);
+        assert_eq!(passed_on.len(), 1);
+        assert!(blocks.contains(&passed_on[0]));
+    }
+
+    #[test]
+    fn test_verify_blocks() {
+        let valid = create_random_block_v1();
+        let tampered = Block::new(create_random_block_v1().data, *valid.cid());
+        let mut tampered_message = BitswapMessage::new(false);
+        tampered_message.add_block(tampered);
+        assert_eq!(tampered_message.verify_blocks(), vec![*valid.cid()]);
+        assert_eq!(tampered_message.blocks_len(), 0);
+
+        // blocks hashed with an unknown function are dropped without blaming the sender
+        let hash = cid::multihash::Multihash::wrap(0x300001, &[0; 32]).unwrap();
+        let unknown = Block::new(valid.data.clone(), Cid::new_v1(0x55, hash));
+        let mut message = BitswapMessage::new(false);
+        message.add_block(valid.clone());
+        message.add_block(unknown);
+        assert!(message.verify_blocks().is_empty());
+        assert_eq!(message.blocks().collect::<Vec<_>>(), vec![&valid]);
+    }
+
+    #[tokio::test]
+    async fn test_invalid_blocks_fail_get() {
+        let bs = Bitswap::new(PeerId::random(), DummyStore, Config::default_client_mode()).await;
+        let client = bs.client().clone();
+        let key = *create_random_block_v1().cid();
+        let get = tokio::task::spawn(async move {
+            client
+                .get_block_with_session_id(1, &key, &[], None, None)
+                .await
+        });
+
+        let interest = bs.client().session_manager().session_interest_manager();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while interest
+                .interested_sessions(&[key], &[], &[])
+                .await
+                .is_empty()
+            {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("session did not want the block");
+
+        // a pending get fails once enough providers sent an invalid block
+        for _ in 0..client::MAX_INVALID_PROVIDERS {
+            bs.client()
+                .receive_invalid_blocks(&PeerId::random(), &[key])
+                .await;
+        }
+        let res = tokio::time::timeout(Duration::from_secs(5), get)
+            .await
+            .expect("get was not failed")
+            .unwrap();
+        assert!(res.is_err());
+
+        // as does a get started after that
+        let key = *create_random_block_v1().cid();
+        for _ in 0..client::MAX_INVALID_PROVIDERS {
+            bs.client()
+                .receive_invalid_blocks(&PeerId::random(), &[key])
+                .await;
+        }
+        let res = tokio::time::timeout(
+            Duration::from_secs(5),
+            bs.client()
+                .get_block_with_session_id(2, &key, &[], None, None),
+        )
+        .await
+        .expect("get was not failed");
+        assert!(res.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_pause_session() {
+        let bs = Bitswap::new(PeerId::random(), DummyStore, Config::default_client_mode()).await;
+        let peer_manager = bs.client().session_manager().peer_manager().clone();
+        let interest = bs.client().session_manager().session_interest_manager();
+        let wants_sent = |keys: Vec<Cid>, sent: bool| {
+            let peer_manager = peer_manager.clone();
+            async move {
+                tokio::time::timeout(Duration::from_secs(5), async {
+                    loop {
+                        let wants = peer_manager.current_wants().await;
+                        if keys.iter().all(|key| wants.contains(key) == sent) {
+                            break;
+                        }
+                        tokio::time::sleep(Duration::from_millis(10)).await;
+                    }
+                })
+                .await
+                .is_ok()
+            }
+        };
+        let get = |key: Cid| {
+            let client = bs.client().clone();
+            tokio::task::spawn(async move {
+                client
+                    .get_block_with_session_id(1, &key, &[], None, None)
+                    .await
+            })
+        };
+
+        // without any peers, the wanted block is broadcast
+        let sent = *create_random_block_v1().cid();
+        let _get_sent = get(sent);
+        assert!(wants_sent(vec![sent], true).await);
+
+        // pausing cancels it, but the session still wants it
+        bs.pause_session(1).await.unwrap();
+        assert!(wants_sent(vec![sent], false).await);
+        assert!(!interest
+            .interested_sessions(&[sent], &[], &[])
+            .await
+            .is_empty());
+
+        // blocks requested while paused are held back
+        let held_back = *create_random_block_v1().cid();
+        let _get_held_back = get(held_back);
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while interest
+                .interested_sessions(&[held_back], &[], &[])
+                .await
+                .is_empty()
+            {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("session did not want the block");
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!peer_manager.current_wants().await.contains(&held_back));
+
+        // resuming sends both
+        bs.resume_session(1).await.unwrap();
+        assert!(wants_sent(vec![sent, held_back], true).await);
+    }
+
+    #[tokio::test]
+    async fn test_want_priority_per_request() {
+        let bs = Bitswap::new(PeerId::random(), DummyStore, Config::default_client_mode()).await;
+        let peer = PeerId::random();
+        bs.client().peer_connected(&peer).await;
+        let get = |key: Cid, priority: Priority| {
+            let client = bs.client().clone();
+            tokio::task::spawn(async move {
+                client
+                    .get_block_with_session_id(1, &key, &[peer], Some(priority), None)
+                    .await
+            })
+        };
+
+        // two requests in the same session keep their own priority
+        let (low, high) = (
+            *create_random_block_v1().cid(),
+            *create_random_block_v1().cid(),
+        );
+        let _get_low = get(low, 1);
+        let _get_high = get(high, 100);
+
+        let mut network = bs.network.clone();
+        let priorities = tokio::time::timeout(Duration::from_secs(5), async {
+            let mut priorities = AHashMap::new();
+            loop {
+                match future::poll_fn(|cx| Pin::new(&mut network).poll(cx)).await {
+                    OutEvent::Dial { response, .. } => {
+                        let conn = ConnectionId::new(1);
+                        response.send(Ok((conn, Some(ProtocolId::Bitswap120)))).ok();
+                    }
+                    OutEvent::SendMessage {
+                        message, response, ..
+                    } => {
+                        for entry in message.wantlist() {
+                            if entry.want_type == message::WantType::Block {
+                                priorities.insert(entry.cid, entry.priority);
+                            }
+                        }
+                        response.send(Ok(())).ok();
+                        if priorities.contains_key(&low) && priorities.contains_key(&high) {
+                            return priorities;
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        })
+        .await
+        .expect("want-blocks were not sent");
+        assert_eq!(priorities[&low], 1);
+        assert_eq!(priorities[&high], 100);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_want_have_from() {
+        let bs = Bitswap::new(PeerId::random(), DummyStore, Config::default_client_mode()).await;
+        let peer = PeerId::random();
+        let want_have_from = |key: Cid| {
+            let client = bs.client().clone();
+            tokio::task::spawn(async move { client.want_have_from(&key, None, &peer).await })
+        };
+        let want_sent = |key: Cid| {
+            let client = bs.client().clone();
+            async move {
+                tokio::time::timeout(Duration::from_secs(5), async {
+                    while !client.get_want_haves().await.contains(&key) {
+                        tokio::time::sleep(Duration::from_millis(10)).await;
+                    }
+                })
+                .await
+                .expect("want-have was not sent");
+            }
+        };
+
+        // only connected peers are asked
+        let key = *create_random_block_v1().cid();
+        assert!(bs.want_have_from(&key, None, &peer).await.is_err());
+        bs.client().peer_connected(&peer).await;
+
+        // the answer of the peer resolves the request and is emitted
+        let asked = want_have_from(key);
+        want_sent(key).await;
+        let mut message = BitswapMessage::new(false);
+        message.add_have(key);
+        bs.client().receive_message(&peer, &message).await;
+        assert!(asked.await.unwrap().unwrap());
+        let mut network = bs.network.clone();
+        let event = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let event = future::poll_fn(|cx| Pin::new(&mut network).poll(cx)).await;
+                if let OutEvent::GenerateEvent(event @ BitswapEvent::PeerHave { .. }) = event {
+                    return event;
+                }
+            }
+        })
+        .await
+        .expect("answer was not emitted");
+        assert!(matches!(
+            event,
+            BitswapEvent::PeerHave { peer: p, key: k, have: true } if p == peer && k == key
+        ));
+        // and is remembered
+        assert!(bs.want_have_from(&key, None, &peer).await.unwrap());
+
+        // without an answer, the request times out and the want is cancelled
+        let key = *create_random_block_v1().cid();
+        let start = tokio::time::Instant::now();
+        let asked = want_have_from(key);
+        want_sent(key).await;
+        assert!(asked.await.unwrap().is_err());
+        assert!(start.elapsed() >= client::WANT_HAVE_FROM_TIMEOUT);
+        assert!(!bs.client().get_want_haves().await.contains(&key));
+    }
+
+    #[tokio::test]
+    async fn test_prune_known_peers() {
+        let bs = Bitswap::new(PeerId::random(), DummyStore, Config::default_client_mode()).await;
+        let failed = PeerId::random();
+        let connected = PeerId::random();
+        bs.set_peer_state(&failed, PeerState::DialFailure(Instant::now()));
+        bs.set_peer_state(&connected, PeerState::Connected(ConnectionId::new(1)));
+        assert_eq!(bs.known_peers().len(), 2);
+
+        // recently seen peers are kept
+        assert_eq!(bs.prune_known_peers(Duration::from_secs(60)), 0);
+
+        // connected peers are never pruned
+        assert_eq!(bs.prune_known_peers(Duration::ZERO), 1);
+        let known: Vec<_> = bs.known_peers().into_iter().map(|(peer, _)| peer).collect();
+        assert_eq!(known, vec![connected]);
+    }
+
+    #[tokio::test]
+    async fn test_peer_protocol_preference() {
+        let mut bs =
+            Bitswap::new(PeerId::random(), DummyStore, Config::default_client_mode()).await;
+        let peer = PeerId::random();
+        // applies to dialed and accepted connections alike
+        let endpoints = [
+            ConnectedPoint::Dialer {
+                address: Multiaddr::empty(),
+                role_override: libp2p::core::Endpoint::Dialer,
+            },
+            ConnectedPoint::Listener {
+                local_addr: Multiaddr::empty(),
+                send_back_addr: Multiaddr::empty(),
+            },
+        ];
+        let protocol_ids = |bs: &mut Bitswap<DummyStore>, peer: &PeerId| {
+            let ids: Vec<_> = endpoints
+                .iter()
+                .map(|endpoint| {
+                    let handler = bs.new_handler().into_handler(peer, endpoint);
+                    handler.listen_protocol().upgrade().protocol_ids.clone()
+                })
+                .collect();
+            assert_eq!(ids[0], ids[1]);
+            ids[0].clone()
+        };
+
+        // the supported protocols are not known yet
+        assert!(bs
+            .set_peer_protocol_preference(peer, ProtocolId::Bitswap110)
+            .is_err());
+
+        let protocols = [
+            "/ipfs/bitswap/1.2.0".to_string(),
+            "/ipfs/bitswap/1.1.0".to_string(),
+        ];
+        bs.on_identify(&peer, &protocols);
+        assert!(bs
+            .set_peer_protocol_preference(peer, ProtocolId::Bitswap100)
+            .is_err());
+        assert_eq!(
+            protocol_ids(&mut bs, &peer),
+            ProtocolConfig::default().protocol_ids
+        );
+
+        bs.set_peer_protocol_preference(peer, ProtocolId::Bitswap110)
+            .unwrap();
+        assert_eq!(protocol_ids(&mut bs, &peer), vec![ProtocolId::Bitswap110]);
+        assert_eq!(
+            protocol_ids(&mut bs, &PeerId::random()),
+            ProtocolConfig::default().protocol_ids
+        );
+
+        bs.clear_peer_protocol_preference(&peer);
+        assert_eq!(
+            protocol_ids(&mut bs, &peer),
+            ProtocolConfig::default().protocol_ids
+        );
+    }
+
+    #[tokio::test]
+    async fn test_dial_failure_threshold() {
+        let config = Config {
+            dial_failure_threshold: 2,
+            ..Config::default_client_mode()
+        };
+        let mut bs = Bitswap::new(PeerId::random(), DummyStore, config).await;
+        let peer = PeerId::random();
+        let fail_dial = |bs: &mut Bitswap<DummyStore>| {
+            let handler = bs.new_handler();
+            bs.inject_dial_failure(Some(peer), handler, &DialError::NoAddresses);
+        };
+
+        fail_dial(&mut bs);
+        assert_eq!(bs.conn_state(&peer), Some(ConnState::DialFailure));
+        assert!(!bs.dial_failure_threshold_reached(&peer));
+        fail_dial(&mut bs);
+        assert_eq!(bs.conn_state(&peer), Some(ConnState::DialFailure));
+        assert!(bs.dial_failure_threshold_reached(&peer));
+
+        // a successful connection resets the count
+        let endpoint = ConnectedPoint::Dialer {
+            address: Multiaddr::empty(),
+            role_override: libp2p::core::Endpoint::Dialer,
+        };
+        let conn = ConnectionId::new(1);
+        bs.inject_connection_established(&peer, &conn, &endpoint, None, 0);
+        bs.set_peer_state(&peer, PeerState::Disconnected);
+        fail_dial(&mut bs);
+        assert_eq!(bs.conn_state(&peer), Some(ConnState::DialFailure));
+        assert!(!bs.dial_failure_threshold_reached(&peer));
+    }
+
+    #[tokio::test]
+    async fn test_dial_timeout() {
+        let config = Config {
+            dial_failure_threshold: 1,
+            dial_timeout: Duration::from_millis(50),
+            ..Config::default_client_mode()
+        };
+        let bs = Bitswap::new(PeerId::random(), DummyStore, config).await;
+        let peer = PeerId::random();
+
+        // nothing drives the swarm, so the dial never completes
+        let err = bs
+            .network
+            .dial(peer, Duration::from_secs(10))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("timed out"), "{err}");
+
+        bs.dial_timed_out(peer, 0);
+        assert_eq!(bs.conn_state(&peer), Some(ConnState::DialFailure));
+    }
+
+    #[tokio::test]
+    async fn test_peer_state_events() {
+        async fn next_peer_state(
+            bs: &Bitswap<DummyStore>,
+        ) -> Option<(PeerId, ConnState, ConnState)> {
+            let mut network = bs.network.clone();
+            tokio::time::timeout(Duration::from_millis(100), async {
+                loop {
+                    let event = future::poll_fn(|cx| Pin::new(&mut network).poll(cx)).await;
+                    if let OutEvent::GenerateEvent(BitswapEvent::PeerState { peer, old, new }) =
+                        event
+                    {
+                        return (peer, old, new);
+                    }
+                }
+            })
+            .await
+            .ok()
+        }
+
+        let peer = PeerId::random();
+        let conn = ConnectionId::new(1);
+
+        // disabled by default
+        let bs = Bitswap::new(PeerId::random(), DummyStore, Config::default_client_mode()).await;
+        bs.set_peer_state(&peer, PeerState::Connected(conn));
+        assert_eq!(next_peer_state(&bs).await, None);
+
+        let config = Config {
+            peer_state_events: true,
+            ..Config::default_client_mode()
+        };
+        let mut bs = Bitswap::new(PeerId::random(), DummyStore, config).await;
+        bs.set_peer_state(&peer, PeerState::Connected(conn));
+        assert_eq!(
+            next_peer_state(&bs).await,
+            Some((peer, ConnState::Disconnected, ConnState::Connected))
+        );
+        bs.set_peer_state(&peer, PeerState::Responsive(conn, ProtocolId::Bitswap120));
+        assert_eq!(
+            next_peer_state(&bs).await,
+            Some((
+                peer,
+                ConnState::Connected,
+                ConnState::Responsive(ProtocolId::Bitswap120)
+            ))
+        );
+        bs.set_peer_state(&peer, PeerState::Disconnected);
+        assert_eq!(
+            next_peer_state(&bs).await,
+            Some((
+                peer,
+                ConnState::Responsive(ProtocolId::Bitswap120),
+                ConnState::Disconnected
+            ))
+        );
+
+        // a dial refused because of the connection limit
+        let handler = bs.new_handler();
+        let limit = libp2p::swarm::ConnectionLimit {
+            limit: 1,
+            current: 1,
+        };
+        bs.inject_dial_failure(Some(peer), handler, &DialError::ConnectionLimit(limit));
+        assert_eq!(
+            next_peer_state(&bs).await,
+            Some((peer, ConnState::Disconnected, ConnState::ConnectionLimit))
+        );
+        assert_eq!(bs.conn_state(&peer), None);
+    }
+
+    #[tokio::test]
+    async fn test_peer_protocol() {
+        let bs = Bitswap::new(PeerId::random(), DummyStore, Config::default_client_mode()).await;
+        let peer = PeerId::random();
+        assert_eq!(bs.conn_state(&peer), None);
+
+        bs.set_peer_state(&peer, PeerState::Connected(ConnectionId::new(1)));
+        assert_eq!(bs.conn_state(&peer), Some(ConnState::Connected));
+        assert_eq!(bs.peer_protocol(&peer), None);
+
+        bs.on_identify(&peer, &["/ipfs/bitswap/1.1.0".to_string()]);
+        assert_eq!(
+            bs.conn_state(&peer),
+            Some(ConnState::Responsive(ProtocolId::Bitswap110))
+        );
+        assert_eq!(bs.peer_protocol(&peer), Some(ProtocolId::Bitswap110));
+    }
+
+    #[tokio::test]
+    async fn test_protocol_distribution() {
+        let bs = Bitswap::new(PeerId::random(), DummyStore, Config::default_client_mode()).await;
+        assert!(bs.protocol_distribution().is_empty());
+
+        let protocols = [
+            ProtocolId::Bitswap120,
+            ProtocolId::Bitswap120,
+            ProtocolId::Legacy,
+        ];
+        for (i, protocol) in protocols.into_iter().enumerate() {
+            let conn = ConnectionId::new(i);
+            bs.set_peer_state(&PeerId::random(), PeerState::Responsive(conn, protocol));
+        }
+        bs.set_peer_state(&PeerId::random(), PeerState::Unresponsive);
+
+        let distribution = bs.protocol_distribution();
+        assert_eq!(distribution.len(), 3);
+        assert_eq!(distribution[&Some(ProtocolId::Bitswap120)], 2);
+        assert_eq!(distribution[&Some(ProtocolId::Legacy)], 1);
+        assert_eq!(distribution[&None], 1);
+    }
+
     fn mk_transport() -> (PeerId, Boxed<(PeerId, StreamMuxerBox)>) {
         let local_key = Keypair::generate_ed25519();
 
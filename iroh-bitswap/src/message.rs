@@ -215,8 +215,12 @@ impl BitswapMessage {
         self.full
     }
 
-    /// Removes all invalid blocks.
-    pub fn verify_blocks(&mut self) {
+    /// Removes all invalid blocks, returning the cids of those that failed verification.
+    ///
+    /// Blocks hashed with an unknown function are removed too, but not returned, as the
+    /// sender can not be blamed for them.
+    pub fn verify_blocks(&mut self) -> Vec<Cid> {
+        let mut invalid = Vec::new();
         self.blocks.retain(|_, block| {
             let now = Instant::now();
             let is_valid = iroh_util::verify_hash(&block.cid, &block.data);
@@ -227,8 +231,8 @@ impl BitswapMessage {
                     true
                 }
                 Some(false) => {
-                    // TODO: maybe blacklist peer?
-                    warn!("invalid block received");
+                    warn!("invalid block received: {}", block.cid);
+                    invalid.push(block.cid);
                     false
                 }
                 None => {
@@ -237,6 +241,7 @@ impl BitswapMessage {
                 }
             }
         });
+        invalid
     }
 
     pub fn is_empty(&self) -> bool {
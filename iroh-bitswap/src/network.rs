@@ -15,9 +15,11 @@ use libp2p::{core::connection::ConnectionId, PeerId};
 use tokio::sync::{mpsc, oneshot};
 use tracing::{debug, error, info, trace};
 
-use crate::{message::BitswapMessage, protocol::ProtocolId, BitswapEvent};
+use crate::{
+    message::BitswapMessage, protocol::ProtocolId, BitswapEvent, Block, ConnState,
+    DEFAULT_DIAL_TIMEOUT,
+};
 
-const CONNECT_TIMEOUT: Duration = Duration::from_secs(20);
 const MAX_SEND_TIMEOUT: Duration = Duration::from_secs(3 * 60 + 5);
 const MIN_SEND_TIMEOUT: Duration = Duration::from_secs(2);
 const SEND_LATENCY: Duration = Duration::from_secs(2);
@@ -30,6 +32,7 @@ pub struct Network {
     network_out_sender: async_channel::Sender<OutEvent>,
     self_id: PeerId,
     dial_id: Arc<AtomicUsize>,
+    dial_timeout: Duration,
 }
 
 #[derive(Debug)]
@@ -39,6 +42,11 @@ pub enum OutEvent {
         response: oneshot::Sender<std::result::Result<(ConnectionId, Option<ProtocolId>), String>>,
         id: usize,
     },
+    /// The dial `id` to `peer` exceeded its timeout, and was abandoned.
+    DialTimeout {
+        peer: PeerId,
+        id: usize,
+    },
     Disconnect(PeerId, oneshot::Sender<()>),
     SendMessage {
         peer: PeerId,
@@ -54,6 +62,11 @@ pub enum OutEvent {
         peer: PeerId,
         response: oneshot::Sender<bool>,
     },
+    /// Wants sent to `peer` became outstanding, or were all resolved.
+    WantsOutstanding {
+        peer: PeerId,
+        outstanding: bool,
+    },
 }
 
 #[derive(Debug, Clone, thiserror::Error)]
@@ -73,9 +86,16 @@ impl Network {
             network_out_sender,
             self_id,
             dial_id: Arc::new(AtomicUsize::new(0)),
+            dial_timeout: DEFAULT_DIAL_TIMEOUT,
         }
     }
 
+    /// Sets the maximum time a dial may take, see [`crate::Config::dial_timeout`].
+    pub fn with_dial_timeout(mut self, dial_timeout: Duration) -> Self {
+        self.dial_timeout = dial_timeout;
+        self
+    }
+
     pub fn self_id(&self) -> &PeerId {
         &self.self_id
     }
@@ -212,7 +232,8 @@ impl Network {
 
         inc!(BitswapMetrics::AttemptedDials);
         debug!("dial:{}: peer {}", dial_id, peer);
-        let res = tokio::time::timeout(timeout, async move {
+        let timeout = timeout.min(self.dial_timeout);
+        let dial = async move {
             let (s, r) = oneshot::channel();
             self.network_out_sender
                 .send(OutEvent::Dial {
@@ -227,9 +248,19 @@ impl Network {
                 .await?
                 .map_err(|e| anyhow!("dial:{} failed: {}", dial_id, e))?;
             Ok::<_, anyhow::Error>(res)
-        })
-        .await
-        .map_err(|e| anyhow!("dial:{} error: {:?}", dial_id, e))??;
+        };
+        let res = match tokio::time::timeout(timeout, dial).await {
+            Ok(res) => res?,
+            Err(_) => {
+                inc!(BitswapMetrics::DialTimeouts);
+                // libp2p has no timeout for a single dial, so the transport may still be
+                // trying, tell the behaviour to give up on it right away
+                self.network_out_sender
+                    .try_send(OutEvent::DialTimeout { peer, id: dial_id })
+                    .ok();
+                bail!("dial:{}: timed out after {:?}", dial_id, timeout);
+            }
+        };
 
         debug!("dial:{}: success {}", dial_id, peer);
         inc!(BitswapMetrics::Dials);
@@ -242,7 +273,7 @@ impl Network {
         to: PeerId,
         config: MessageSenderConfig,
     ) -> Result<MessageSender> {
-        let (connection_id, protocol_id) = self.dial(to, CONNECT_TIMEOUT).await?;
+        let (connection_id, protocol_id) = self.dial(to, self.dial_timeout).await?;
 
         Ok(MessageSender {
             to,
@@ -254,7 +285,7 @@ impl Network {
     }
 
     pub async fn send_message(&self, peer: PeerId, message: BitswapMessage) -> Result<()> {
-        let (connection_id, _) = self.dial(peer, CONNECT_TIMEOUT).await?;
+        let (connection_id, _) = self.dial(peer, self.dial_timeout).await?;
         let timeout = send_timeout(message.encoded_len());
         self.send_message_with_retry_and_timeout(
             peer,
@@ -287,6 +318,59 @@ impl Network {
         Ok(())
     }
 
+    /// Reports the answer of `peer` to a targeted want-have for `key`.
+    pub async fn peer_have(&self, peer: PeerId, key: Cid, have: bool) -> Result<()> {
+        self.network_out_sender
+            .send(OutEvent::GenerateEvent(BitswapEvent::PeerHave {
+                peer,
+                key,
+                have,
+            }))
+            .await
+            .map_err(|e| anyhow!("channel send: {:?}", e))?;
+
+        Ok(())
+    }
+
+    /// Reports a change of the connection state of `peer`, dropping the event if the
+    /// behaviour is not keeping up.
+    pub fn peer_state(&self, peer: PeerId, old: ConnState, new: ConnState) {
+        if let Err(err) =
+            self.network_out_sender
+                .try_send(OutEvent::GenerateEvent(BitswapEvent::PeerState {
+                    peer,
+                    old,
+                    new,
+                }))
+        {
+            debug!("dropping peer state event for {}: {:?}", peer, err);
+        }
+    }
+
+    /// Reports the wants for `keys` that were only outstanding with `peer`, which was
+    /// dropped as its connection changed to `reason`.
+    pub fn wants_orphaned(&self, peer: PeerId, reason: ConnState, keys: Vec<Cid>) {
+        let event = BitswapEvent::WantsOrphaned { peer, reason, keys };
+        if let Err(err) = self
+            .network_out_sender
+            .try_send(OutEvent::GenerateEvent(event))
+        {
+            debug!("dropping orphaned wants event for {}: {:?}", peer, err);
+        }
+    }
+
+    /// Passes on `blocks` that `peer` sent without us wanting them, dropping them if the
+    /// behaviour is not keeping up.
+    pub fn unsolicited_blocks(&self, peer: PeerId, blocks: Vec<Block>) {
+        let event = BitswapEvent::UnsolicitedBlocks { peer, blocks };
+        if let Err(err) = self
+            .network_out_sender
+            .try_send(OutEvent::GenerateEvent(event))
+        {
+            debug!("dropping unsolicited blocks from {}: {:?}", peer, err);
+        }
+    }
+
     pub fn tag_peer(&self, peer: &PeerId, tag: &str, value: usize) {
         // TODO: is this needed?
         trace!("tag {}: {} - {}", peer, tag, value);
@@ -319,6 +403,18 @@ impl Network {
         r.await.unwrap_or_default()
     }
 
+    /// Keeps the connection to `peer` alive while wants sent to it are `outstanding`, for
+    /// at most [`Config::want_keep_alive`](crate::Config::want_keep_alive).
+    pub async fn keep_alive_for_wants(&self, peer: PeerId, outstanding: bool) {
+        trace!("keep alive {} for wants: {}", peer, outstanding);
+        self.network_out_sender
+            .send(OutEvent::WantsOutstanding { peer, outstanding })
+            .await
+            .context("Failed to keep peer alive")
+            .map_err(|err| error!("{err:#}"))
+            .ok();
+    }
+
     pub fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<OutEvent> {
         inc!(BitswapMetrics::NetworkPollTick);
         match Pin::new(&mut self.network_out_receiver).poll_next(cx) {
@@ -14,7 +14,7 @@ use crate::{handler::BitswapHandlerError, message::BitswapMessage};
 
 const MAX_BUF_SIZE: usize = 1024 * 1024 * 2;
 
-#[derive(Clone, Debug, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Clone, Debug, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum ProtocolId {
     Legacy = 0,
     Bitswap100 = 1,
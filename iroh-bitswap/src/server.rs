@@ -16,7 +16,7 @@ use self::{
     decision::{Config as DecisionConfig, Engine as DecisionEngine, Envelope},
     score_ledger::Receipt,
 };
-use crate::{block::Block, message::BitswapMessage, network::Network, Store};
+use crate::{block::Block, message::BitswapMessage, network::Network, LedgerSnapshot, Store};
 
 mod blockstore_manager;
 mod decision;
@@ -221,6 +221,17 @@ impl<S: Store> Server<S> {
         self.engine.ledger_for_peer(peer).await
     }
 
+    /// Returns the blocks requested by the given peer, and the blocks and presences queued
+    /// for it. The connection state is left for the caller to fill in.
+    pub async fn inspect_ledger(&self, peer: &PeerId) -> Option<LedgerSnapshot> {
+        self.engine.inspect_ledger(peer).await
+    }
+
+    /// Forgets the blocks requested by the given peer and stops sending it queued blocks.
+    pub async fn reset_ledger(&self, peer: &PeerId) {
+        self.engine.reset_ledger(peer).await;
+    }
+
     /// Returns the currently understood list of blocks requested by a given peer.
     pub async fn wantlist_for_peer(&self, peer: &PeerId) -> Vec<Cid> {
         self.engine
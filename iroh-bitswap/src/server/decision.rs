@@ -16,7 +16,7 @@ use crate::{
     client::wantlist,
     message::{BitswapMessage, BlockPresence, BlockPresenceType, Entry, WantType},
     peer_task_queue::{Config as PTQConfig, PeerTaskQueue, Task},
-    Store,
+    LedgerSnapshot, Store,
 };
 
 use super::{
@@ -28,6 +28,9 @@ use super::{
 };
 use iroh_metrics::core::MRecorder;
 
+/// The default of [`Config::response_delay`].
+const DEFAULT_RESPONSE_DELAY: Duration = Duration::from_millis(100);
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct TaskInfo {
     peer: PeerId,
@@ -78,6 +81,12 @@ pub struct Config {
     /// Setting it to 0 will disable any limiting.
     pub max_outstanding_bytes_per_peer: usize,
     pub max_replace_size: usize,
+    /// How long blocks and presences for a peer are batched before they are sent. With a
+    /// zero delay, responses are sent as soon as the requests are processed.
+    ///
+    /// This is independent of how the client batches outgoing wants, see
+    /// [`crate::client::message_queue::Config`].
+    pub response_delay: Duration,
 }
 
 impl Default for Config {
@@ -90,6 +99,7 @@ impl Default for Config {
             target_message_size: 16 * 1024,
             max_outstanding_bytes_per_peer: 1 << 20,
             max_replace_size: 1024,
+            response_delay: DEFAULT_RESPONSE_DELAY,
         }
     }
 }
@@ -149,6 +159,7 @@ impl<S: Store> Engine<S> {
         }))
         .await;
         let target_message_size = config.target_message_size;
+        let response_delay = config.response_delay;
         let task_worker_count = config.engine_task_worker_count;
         let mut workers = Vec::with_capacity(task_worker_count);
 
@@ -158,7 +169,11 @@ impl<S: Store> Engine<S> {
             let (closer_s, mut closer_r) = oneshot::channel();
 
             let peer_task_queue = peer_task_queue.clone();
-            let mut ticker = tokio::time::interval(Duration::from_millis(100));
+            let mut ticker = tokio::time::interval(if response_delay.is_zero() {
+                DEFAULT_RESPONSE_DELAY
+            } else {
+                response_delay
+            });
             let work_signal = work_signal.clone();
             let blockstore_manager = blockstore_manager.clone();
             let peer_task_hook = peer_task_hook.clone();
@@ -176,7 +191,21 @@ impl<S: Store> Engine<S> {
                             // TODO: tag/untag peer
                         }
                         _ = work_signal.notified() => {
-                            // not needed anymore?
+                            // without a delay, respond as soon as there is new work
+                            if response_delay.is_zero()
+                                && send_next_envelope(
+                                    i,
+                                    &peer_task_queue,
+                                    &blockstore_manager,
+                                    target_message_size,
+                                    &work_signal,
+                                    &outbox,
+                                )
+                                .await
+                            {
+                                // there might be more work queued for other peers
+                                work_signal.notify_one();
+                            }
                         }
                         _ = ticker.tick() => {
                             // TODO: remove thaw_round is not used atm
@@ -184,76 +213,15 @@ impl<S: Store> Engine<S> {
                             // for a period of time. We periodically "thaw" the queue
                             // to make sure it doesn't get suck in a frozen state.
                             // peer_task_queue.thaw_round().await;
-                            if let Some((peer, next_tasks, pending_bytes)) = peer_task_queue.pop_tasks(target_message_size).await {
-                                if next_tasks.is_empty() {
-                                    continue;
-                                }
-                                debug!("engine:{} next envelope:tick tasks: {}", i, next_tasks.len());
-
-                                // create a new message
-                                let mut msg = BitswapMessage::new(false);
-                                msg.set_pending_bytes(pending_bytes as _);
-
-                                // split out want-blocks, want-have and DONT_HAVEs
-                                let mut block_cids = Vec::new();
-                                let mut block_tasks = AHashMap::new();
-
-                                for task in &next_tasks {
-                                    if task.data.have_block {
-                                        if task.data.is_want_block {
-                                            block_cids.push(task.topic);
-                                            block_tasks.insert(task.topic, task);
-                                        } else {
-                                            // add HAVEs to the message
-                                            msg.add_have(task.topic);
-                                        }
-                                    } else {
-                                        // add DONT_HAVEs to the message
-                                        msg.add_dont_have(task.topic);
-                                    }
-                                }
-
-                                // Fetch blocks from the store
-                                let mut blocks = match blockstore_manager
-                                    .read()
-                                    .await
-                                    .get_blocks(&block_cids)
-                                    .await {
-                                        Ok(blocks) => blocks,
-                                        Err(err) => {
-                                            warn!("failed to load blocks: {:?}", err);
-                                            continue;
-                                        }
-                                    };
-
-                                for (cid, task) in block_tasks {
-                                    if let Some(block) = blocks.remove(&cid) {
-                                        msg.add_block(block);
-                                    } else {
-                                        // block was not found
-                                        if task.data.send_dont_have {
-                                            msg.add_dont_have(cid);
-                                        }
-                                    }
-                                }
-
-                                // nothing to see here
-                                if msg.is_empty() {
-                                    peer_task_queue.tasks_done(peer, &next_tasks).await;
-                                    continue;
-                                }
-
-                                let envelope = Ok(Envelope {
-                                    peer,
-                                    message: msg,
-                                    sent_tasks: next_tasks,
-                                    queue: peer_task_queue.clone(),
-                                    work_signal: work_signal.clone(),
-                                });
-                                if let Err(err) = outbox.send(envelope).await {
-                                    error!("failed to deliver envelope: {:?}", err);
-                                }
-                            }
+                            send_next_envelope(
+                                i,
+                                &peer_task_queue,
+                                &blockstore_manager,
+                                target_message_size,
+                                &work_signal,
+                                &outbox,
+                            )
+                            .await;
                         }
                     }
                 }
@@ -317,6 +285,51 @@ impl<S: Store> Engine<S> {
         partner.wantlist_mut().entries().collect()
     }
 
+    /// Returns the wantlist of the given peer and the topics of the tasks queued for it,
+    /// pending and active, without creating a ledger if there is none.
+    pub async fn inspect_ledger(&self, peer: &PeerId) -> Option<LedgerSnapshot> {
+        let ledger = self.ledger_map.read().await.get(peer).cloned()?;
+        let mut ledger = ledger.lock().await;
+        let wants = ledger.entries().map(|e| e.cid).collect();
+        let (pending, active) = match self.peer_task_queue.peer_topics(peer).await {
+            Some(topics) => (topics.pending, topics.active),
+            None => Default::default(),
+        };
+        Some(LedgerSnapshot {
+            conn_state: None,
+            wants,
+            pending,
+            active,
+            bytes_sent: ledger.bytes_sent(),
+            bytes_received: ledger.bytes_received(),
+        })
+    }
+
+    /// Forgets the wantlist of the given peer and drops all tasks queued for it.
+    pub async fn reset_ledger(&self, peer: &PeerId) {
+        let ledger = match self.ledger_map.read().await.get(peer).cloned() {
+            Some(ledger) => ledger,
+            None => return,
+        };
+        let mut ledger = ledger.lock().await;
+        let mut topics: AHashSet<Cid> = ledger.entries().map(|entry| entry.cid).collect();
+        if let Some(queued) = self.peer_task_queue.peer_topics(peer).await {
+            topics.extend(queued.pending);
+        }
+
+        let mut peer_ledger = self.peer_ledger.lock().await;
+        for cid in &topics {
+            peer_ledger.cancel_want(peer, cid);
+            self.peer_task_queue.remove(cid, *peer).await;
+        }
+        ledger.clear_wantlist();
+        debug!(
+            "reset ledger of {}, dropped {} wants and tasks",
+            peer,
+            topics.len()
+        );
+    }
+
     /// Returns the aggregated data communication for the given peer.
     pub async fn ledger_for_peer(&self, peer: &PeerId) -> Option<Receipt> {
         self.score_ledger.receipt(peer).await
@@ -462,6 +475,7 @@ impl<S: Store> Engine<S> {
             self.score_ledger
                 .add_to_sent_bytes(ledger.partner(), block.data().len())
                 .await;
+            ledger.add_sent_bytes(block.data().len());
             ledger
                 .wantlist_mut()
                 .remove_type(block.cid(), WantType::Block);
@@ -508,11 +522,12 @@ impl<S: Store> Engine<S> {
         }
 
         let l = self.find_or_create(&from).await;
-        let ledger = l.lock().await;
+        let mut ledger = l.lock().await;
         for block in blocks {
             self.score_ledger
                 .add_to_recv_bytes(ledger.partner(), block.data().len())
                 .await;
+            ledger.add_received_bytes(block.data().len());
         }
     }
 
@@ -650,6 +665,98 @@ impl<S: Store> Engine<S> {
     }
 }
 
+/// Pops the next tasks from the queue and sends the resulting message to the outbox.
+///
+/// Returns `false` if there were no tasks to process.
+async fn send_next_envelope<S: Store>(
+    worker: usize,
+    peer_task_queue: &PeerTaskQueue<Cid, TaskData, TaskMerger>,
+    blockstore_manager: &RwLock<BlockstoreManager<S>>,
+    target_message_size: usize,
+    work_signal: &Arc<Notify>,
+    outbox: &async_channel::Sender<Result<Envelope>>,
+) -> bool {
+    let (peer, next_tasks, pending_bytes) =
+        match peer_task_queue.pop_tasks(target_message_size).await {
+            Some(tasks) => tasks,
+            None => return false,
+        };
+    if next_tasks.is_empty() {
+        return false;
+    }
+    debug!(
+        "engine:{} next envelope: tasks: {}",
+        worker,
+        next_tasks.len()
+    );
+
+    // create a new message
+    let mut msg = BitswapMessage::new(false);
+    msg.set_pending_bytes(pending_bytes as _);
+
+    // split out want-blocks, want-have and DONT_HAVEs
+    let mut block_cids = Vec::new();
+    let mut block_tasks = AHashMap::new();
+
+    for task in &next_tasks {
+        if task.data.have_block {
+            if task.data.is_want_block {
+                block_cids.push(task.topic);
+                block_tasks.insert(task.topic, task);
+            } else {
+                // add HAVEs to the message
+                msg.add_have(task.topic);
+            }
+        } else {
+            // add DONT_HAVEs to the message
+            msg.add_dont_have(task.topic);
+        }
+    }
+
+    // Fetch blocks from the store
+    let mut blocks = match blockstore_manager
+        .read()
+        .await
+        .get_blocks(&block_cids)
+        .await
+    {
+        Ok(blocks) => blocks,
+        Err(err) => {
+            warn!("failed to load blocks: {:?}", err);
+            return true;
+        }
+    };
+
+    for (cid, task) in block_tasks {
+        if let Some(block) = blocks.remove(&cid) {
+            msg.add_block(block);
+        } else {
+            // block was not found
+            if task.data.send_dont_have {
+                msg.add_dont_have(cid);
+            }
+        }
+    }
+
+    // nothing to see here
+    if msg.is_empty() {
+        peer_task_queue.tasks_done(peer, &next_tasks).await;
+        return true;
+    }
+
+    let envelope = Ok(Envelope {
+        peer,
+        message: msg,
+        sent_tasks: next_tasks,
+        queue: peer_task_queue.clone(),
+        work_signal: work_signal.clone(),
+    });
+    if let Err(err) = outbox.send(envelope).await {
+        error!("failed to deliver envelope: {:?}", err);
+    }
+    true
+}
+
 /// Contains a message for a specific peer.
 #[derive(Debug)]
 pub struct Envelope {
@@ -659,3 +766,72 @@ pub struct Envelope {
     pub queue: PeerTaskQueue<Cid, TaskData, TaskMerger>,
     pub work_signal: Arc<Notify>,
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use async_trait::async_trait;
+
+    use super::*;
+    use crate::block::tests::create_random_block_v1;
+
+    #[derive(Debug, Clone)]
+    struct SingleBlockStore(Block);
+
+    #[async_trait]
+    impl Store for SingleBlockStore {
+        async fn get_size(&self, cid: &Cid) -> Result<usize> {
+            self.get(cid).await.map(|block| block.data().len())
+        }
+
+        async fn get(&self, cid: &Cid) -> Result<Block> {
+            if cid == self.0.cid() {
+                Ok(self.0.clone())
+            } else {
+                Err(anyhow!("missing"))
+            }
+        }
+
+        async fn has(&self, cid: &Cid) -> Result<bool> {
+            Ok(cid == self.0.cid())
+        }
+    }
+
+    /// Measures how long it takes until a requested block is ready to be sent, `None` if
+    /// that takes longer than `timeout`.
+    async fn response_latency(response_delay: Duration, timeout: Duration) -> Option<Duration> {
+        let block = create_random_block_v1();
+        let config = Config {
+            response_delay,
+            ..Default::default()
+        };
+        let engine = Engine::new(SingleBlockStore(block.clone()), PeerId::random(), config).await;
+        // let the workers consume the first tick, which completes immediately
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let mut msg = BitswapMessage::new(false);
+        msg.add_entry(*block.cid(), 1, WantType::Block, true);
+        let start = Instant::now();
+        engine.message_received(&PeerId::random(), &msg).await;
+        let envelope = tokio::time::timeout(timeout, engine.outbox().recv())
+            .await
+            .ok()?
+            .unwrap()
+            .unwrap();
+        let latency = start.elapsed();
+        assert_eq!(envelope.message.blocks().collect::<Vec<_>>(), vec![&block]);
+        Some(latency)
+    }
+
+    #[tokio::test]
+    async fn test_response_delay() {
+        let latency = response_latency(Duration::ZERO, Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert!(latency < Duration::from_secs(1), "latency {latency:?}");
+
+        let latency = response_latency(Duration::from_secs(60), Duration::from_millis(500)).await;
+        assert!(latency.is_none(), "latency {latency:?}");
+    }
+}
@@ -12,6 +12,10 @@ pub struct Ledger {
     /// The remote peer.
     partner: PeerId,
     wantlist: Wantlist,
+    /// Bytes of blocks sent to the peer.
+    bytes_sent: u64,
+    /// Bytes of blocks received from the peer.
+    bytes_received: u64,
 }
 
 impl Ledger {
@@ -19,6 +23,8 @@ impl Ledger {
         Ledger {
             partner,
             wantlist: Wantlist::default(),
+            bytes_sent: 0,
+            bytes_received: 0,
         }
     }
 
@@ -30,6 +36,23 @@ impl Ledger {
         &self.partner
     }
 
+    pub fn bytes_sent(&self) -> u64 {
+        self.bytes_sent
+    }
+
+    pub fn bytes_received(&self) -> u64 {
+        self.bytes_received
+    }
+
+    pub fn add_sent_bytes(&mut self, n: usize) {
+        self.bytes_sent += n as u64;
+    }
+
+    pub fn add_received_bytes(&mut self, n: usize) {
+        self.bytes_received += n as u64;
+    }
+
+    /// Clears the wantlist, the byte counters are kept.
     pub fn clear_wantlist(&mut self) {
         self.wantlist.clear();
     }
@@ -50,3 +73,22 @@ impl Ledger {
         self.wantlist.entries()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ledger_bytes() {
+        let mut ledger = Ledger::new(PeerId::random());
+        ledger.add_sent_bytes(10);
+        ledger.add_sent_bytes(5);
+        ledger.add_received_bytes(7);
+        ledger.wants(Cid::default(), 1, WantType::Block);
+        ledger.clear_wantlist();
+
+        assert_eq!(ledger.bytes_sent(), 15);
+        assert_eq!(ledger.bytes_received(), 7);
+        assert!(ledger.wantlist_get(&Cid::default()).is_none());
+    }
+}
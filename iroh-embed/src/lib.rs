@@ -185,6 +185,7 @@ impl IrohBuilder {
             metrics: Default::default(),
             http_resolvers,
             indexer_endpoint: self.indexer,
+            staging_dir: None,
         };
         let api = Api::new(api_config).await?;
 
@@ -5,9 +5,8 @@ use std::task::Poll;
 use anyhow::Result;
 use bytes::Bytes;
 use cid::Cid;
-use futures::{StreamExt, TryStream};
+use futures::{StreamExt, TryStream, TryStreamExt};
 use http::HeaderMap;
-use iroh_car::{CarHeader, CarWriter};
 use iroh_metrics::{
     core::{MObserver, MRecorder},
     gateway::{GatewayHistograms, GatewayMetrics},
@@ -273,23 +272,10 @@ where
     T: ContentLoader,
     W: AsyncWrite + Send + Unpin,
 {
-    let stream = resolver.resolve_recursive_raw(path, Some(RECURSION_LIMIT));
-    tokio::pin!(stream);
-
-    let root = stream
-        .next()
-        .await
-        .ok_or_else(|| anyhow::anyhow!("root cid not found"))??;
-
-    let header = CarHeader::new_v1(vec![*root.cid()]);
-    let mut writer = CarWriter::new(header, writer);
-    writer.write(*root.cid(), root.content()).await?;
-
-    while let Some(block) = stream.next().await {
-        let block = block?;
-        record_ttfb_metrics(start_time, block.source());
-        writer.write(*block.cid(), block.content()).await?;
-    }
+    let blocks = resolver
+        .resolve_recursive_raw(path, Some(RECURSION_LIMIT))
+        .inspect_ok(move |block| record_ttfb_metrics(start_time, block.source()));
+    iroh_resolver::car::write_car(blocks, writer).await?;
     Ok(())
 }
 
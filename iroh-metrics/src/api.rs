@@ -0,0 +1,266 @@
+use std::fmt;
+
+use prometheus_client::{
+    metrics::{
+        counter::Counter,
+        histogram::{exponential_buckets, Histogram},
+    },
+    registry::Registry,
+};
+use tracing::error;
+
+use crate::{
+    core::{HistogramType, MObserver, MRecorder, MetricType, MetricsRecorder},
+    Collector,
+};
+
+/// Metrics of the user facing operations of the iroh API, adding and getting content.
+#[derive(Clone)]
+pub(crate) struct Metrics {
+    add_requests: Counter,
+    add_bytes: Counter,
+    add_failures: Counter,
+    add_request_time: Histogram,
+    get_requests: Counter,
+    get_bytes: Counter,
+    get_failures_not_found: Counter,
+    get_failures_timeout: Counter,
+    get_failures_invalid_path: Counter,
+    get_failures_store: Counter,
+    get_failures_other: Counter,
+    get_request_time: Histogram,
+}
+
+impl fmt::Debug for Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Api Metrics").finish()
+    }
+}
+
+impl Metrics {
+    pub fn new(registry: &mut Registry) -> Self {
+        let sub_registry = registry.sub_registry_with_prefix("api");
+        let add_requests = Counter::default();
+        sub_registry.register(
+            METRICS_CNT_ADD_REQUESTS,
+            "Total number of add requests",
+            Box::new(add_requests.clone()),
+        );
+        let add_bytes = Counter::default();
+        sub_registry.register(
+            METRICS_CNT_ADD_BYTES,
+            "Bytes of raw data added",
+            Box::new(add_bytes.clone()),
+        );
+        let add_failures = Counter::default();
+        sub_registry.register(
+            METRICS_CNT_ADD_FAILURES,
+            "Number of failed add requests",
+            Box::new(add_failures.clone()),
+        );
+        let add_request_time = Histogram::new(exponential_buckets(0.01, 2.0, 16));
+        sub_registry.register(
+            METRICS_HIST_ADD_REQUEST_TIME,
+            "Histogram of add request times in seconds",
+            Box::new(add_request_time.clone()),
+        );
+
+        let get_requests = Counter::default();
+        sub_registry.register(
+            METRICS_CNT_GET_REQUESTS,
+            "Total number of get requests",
+            Box::new(get_requests.clone()),
+        );
+        let get_bytes = Counter::default();
+        sub_registry.register(
+            METRICS_CNT_GET_BYTES,
+            "Bytes of file content read",
+            Box::new(get_bytes.clone()),
+        );
+        let get_failures_not_found = Counter::default();
+        sub_registry.register(
+            METRICS_CNT_GET_FAILURES_NOT_FOUND,
+            "Number of get requests failed because the content was not found",
+            Box::new(get_failures_not_found.clone()),
+        );
+        let get_failures_timeout = Counter::default();
+        sub_registry.register(
+            METRICS_CNT_GET_FAILURES_TIMEOUT,
+            "Number of get requests failed because loading the content timed out",
+            Box::new(get_failures_timeout.clone()),
+        );
+        let get_failures_invalid_path = Counter::default();
+        sub_registry.register(
+            METRICS_CNT_GET_FAILURES_INVALID_PATH,
+            "Number of get requests failed because of a malformed path",
+            Box::new(get_failures_invalid_path.clone()),
+        );
+        let get_failures_store = Counter::default();
+        sub_registry.register(
+            METRICS_CNT_GET_FAILURES_STORE,
+            "Number of get requests failed because reading from the store failed",
+            Box::new(get_failures_store.clone()),
+        );
+        let get_failures_other = Counter::default();
+        sub_registry.register(
+            METRICS_CNT_GET_FAILURES_OTHER,
+            "Number of get requests failed for other reasons",
+            Box::new(get_failures_other.clone()),
+        );
+        let get_request_time = Histogram::new(exponential_buckets(0.01, 2.0, 16));
+        sub_registry.register(
+            METRICS_HIST_GET_REQUEST_TIME,
+            "Histogram of get request times in seconds",
+            Box::new(get_request_time.clone()),
+        );
+
+        Self {
+            add_requests,
+            add_bytes,
+            add_failures,
+            add_request_time,
+            get_requests,
+            get_bytes,
+            get_failures_not_found,
+            get_failures_timeout,
+            get_failures_invalid_path,
+            get_failures_store,
+            get_failures_other,
+            get_request_time,
+        }
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        let mut registry = Registry::default();
+        Metrics::new(&mut registry)
+    }
+}
+
+impl MetricsRecorder for Metrics {
+    fn record<M>(&self, m: M, value: u64)
+    where
+        M: MetricType + std::fmt::Display,
+    {
+        if m.name() == ApiMetrics::AddRequests.name() {
+            self.add_requests.inc_by(value);
+        } else if m.name() == ApiMetrics::AddBytes.name() {
+            self.add_bytes.inc_by(value);
+        } else if m.name() == ApiMetrics::AddFailures.name() {
+            self.add_failures.inc_by(value);
+        } else if m.name() == ApiMetrics::GetRequests.name() {
+            self.get_requests.inc_by(value);
+        } else if m.name() == ApiMetrics::GetBytes.name() {
+            self.get_bytes.inc_by(value);
+        } else if m.name() == ApiMetrics::GetFailuresNotFound.name() {
+            self.get_failures_not_found.inc_by(value);
+        } else if m.name() == ApiMetrics::GetFailuresTimeout.name() {
+            self.get_failures_timeout.inc_by(value);
+        } else if m.name() == ApiMetrics::GetFailuresInvalidPath.name() {
+            self.get_failures_invalid_path.inc_by(value);
+        } else if m.name() == ApiMetrics::GetFailuresStore.name() {
+            self.get_failures_store.inc_by(value);
+        } else if m.name() == ApiMetrics::GetFailuresOther.name() {
+            self.get_failures_other.inc_by(value);
+        } else {
+            error!("record (api): unknown metric {}", m.name());
+        }
+    }
+
+    fn observe<M>(&self, m: M, value: f64)
+    where
+        M: HistogramType + std::fmt::Display,
+    {
+        if m.name() == ApiHistograms::AddRequestTime.name() {
+            self.add_request_time.observe(value);
+        } else if m.name() == ApiHistograms::GetRequestTime.name() {
+            self.get_request_time.observe(value);
+        } else {
+            error!("observe (api): unknown metric {}", m.name());
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum ApiMetrics {
+    AddRequests,
+    AddBytes,
+    AddFailures,
+    GetRequests,
+    GetBytes,
+    GetFailuresNotFound,
+    GetFailuresTimeout,
+    GetFailuresInvalidPath,
+    GetFailuresStore,
+    GetFailuresOther,
+}
+
+impl MetricType for ApiMetrics {
+    fn name(&self) -> &'static str {
+        match self {
+            ApiMetrics::AddRequests => METRICS_CNT_ADD_REQUESTS,
+            ApiMetrics::AddBytes => METRICS_CNT_ADD_BYTES,
+            ApiMetrics::AddFailures => METRICS_CNT_ADD_FAILURES,
+            ApiMetrics::GetRequests => METRICS_CNT_GET_REQUESTS,
+            ApiMetrics::GetBytes => METRICS_CNT_GET_BYTES,
+            ApiMetrics::GetFailuresNotFound => METRICS_CNT_GET_FAILURES_NOT_FOUND,
+            ApiMetrics::GetFailuresTimeout => METRICS_CNT_GET_FAILURES_TIMEOUT,
+            ApiMetrics::GetFailuresInvalidPath => METRICS_CNT_GET_FAILURES_INVALID_PATH,
+            ApiMetrics::GetFailuresStore => METRICS_CNT_GET_FAILURES_STORE,
+            ApiMetrics::GetFailuresOther => METRICS_CNT_GET_FAILURES_OTHER,
+        }
+    }
+}
+
+impl MRecorder for ApiMetrics {
+    fn record(&self, value: u64) {
+        crate::record(Collector::Api, self.clone(), value);
+    }
+}
+
+impl std::fmt::Display for ApiMetrics {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum ApiHistograms {
+    AddRequestTime,
+    GetRequestTime,
+}
+
+impl HistogramType for ApiHistograms {
+    fn name(&self) -> &'static str {
+        match self {
+            ApiHistograms::AddRequestTime => METRICS_HIST_ADD_REQUEST_TIME,
+            ApiHistograms::GetRequestTime => METRICS_HIST_GET_REQUEST_TIME,
+        }
+    }
+}
+
+impl MObserver for ApiHistograms {
+    fn observe(&self, value: f64) {
+        crate::observe(Collector::Api, self.clone(), value);
+    }
+}
+
+impl std::fmt::Display for ApiHistograms {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+const METRICS_CNT_ADD_REQUESTS: &str = "add_requests";
+const METRICS_CNT_ADD_BYTES: &str = "add_bytes";
+const METRICS_CNT_ADD_FAILURES: &str = "add_failures";
+const METRICS_HIST_ADD_REQUEST_TIME: &str = "add_request_time";
+const METRICS_CNT_GET_REQUESTS: &str = "get_requests";
+const METRICS_CNT_GET_BYTES: &str = "get_bytes";
+const METRICS_CNT_GET_FAILURES_NOT_FOUND: &str = "get_failures_not_found";
+const METRICS_CNT_GET_FAILURES_TIMEOUT: &str = "get_failures_timeout";
+const METRICS_CNT_GET_FAILURES_INVALID_PATH: &str = "get_failures_invalid_path";
+const METRICS_CNT_GET_FAILURES_STORE: &str = "get_failures_store";
+const METRICS_CNT_GET_FAILURES_OTHER: &str = "get_failures_other";
+const METRICS_HIST_GET_REQUEST_TIME: &str = "get_request_time";
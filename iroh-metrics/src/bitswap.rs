@@ -24,10 +24,14 @@ make_metrics! {
     ProvidersTotal: Counter: "Number of providers",
     AttemptedDials: Counter: "",
     Dials: Counter: "",
+    DialTimeouts: Counter: "Number of dials abandoned after exceeding the dial timeout",
+    DialFailures: Counter: "Number of dials that failed, other than by timing out",
     KnownPeers: Counter: "",
     ForgottenPeers: Counter: "",
+    PrunedPeers: Counter: "Number of known peers removed, because they were not seen recently",
     WantedBlocks: Counter: "",
     WantedBlocksReceived: Counter: "",
+    UnsolicitedBlocks: Counter: "Number of received blocks that were not in the wantlist",
     WantHaveBlocks: Counter: "",
     CancelBlocks: Counter: "",
     CancelWantBlocks: Counter: "",
@@ -59,6 +63,7 @@ make_metrics! {
     ProviderQueryCreated: Counter: "",
     ProviderQuerySuccess: Counter: "",
     ProviderQueryError: Counter: "",
+    BlockTimeouts: Counter: "Number of blocks re-requested after exceeding the per block timeout",
     EngineActiveTasks: Gauge: "",
     EnginePendingTasks: Gauge: "",
 
@@ -2,6 +2,8 @@ use std::sync::atomic::{AtomicBool, Ordering};
 
 use prometheus_client::{encoding::text::encode, registry::Registry};
 
+#[cfg(feature = "api")]
+use crate::api;
 #[cfg(feature = "bitswap")]
 use crate::bitswap;
 #[cfg(feature = "gateway")]
@@ -20,6 +22,8 @@ lazy_static! {
 pub(crate) struct Core {
     enabled: AtomicBool,
     registry: Registry,
+    #[cfg(feature = "api")]
+    api_metrics: api::Metrics,
     #[cfg(feature = "gateway")]
     gateway_metrics: gateway::Metrics,
     #[cfg(feature = "resolver")]
@@ -39,6 +43,8 @@ impl Default for Core {
         let mut reg = Registry::default();
         Core {
             enabled: AtomicBool::new(false),
+            #[cfg(feature = "api")]
+            api_metrics: api::Metrics::new(&mut reg),
             #[cfg(feature = "gateway")]
             gateway_metrics: gateway::Metrics::new(&mut reg),
             #[cfg(feature = "resolver")]
@@ -61,6 +67,11 @@ impl Core {
         &self.registry
     }
 
+    #[cfg(feature = "api")]
+    pub(crate) fn api_metrics(&self) -> &api::Metrics {
+        &self.api_metrics
+    }
+
     #[cfg(feature = "gateway")]
     pub(crate) fn gateway_metrics(&self) -> &gateway::Metrics {
         &self.gateway_metrics
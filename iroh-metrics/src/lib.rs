@@ -1,5 +1,7 @@
 #[macro_use]
 mod macros;
+#[cfg(feature = "api")]
+pub mod api;
 #[cfg(feature = "bitswap")]
 pub mod bitswap;
 pub mod config;
@@ -20,6 +22,7 @@ use crate::config::Config;
 use crate::core::HistogramType;
 use crate::core::MetricType;
 #[cfg(any(
+    feature = "api",
     feature = "bitswap",
     feature = "gateway",
     feature = "resolver",
@@ -158,6 +161,8 @@ pub fn get_current_trace_id() -> TraceId {
 
 #[derive(Debug, PartialEq, Eq)]
 pub enum Collector {
+    #[cfg(feature = "api")]
+    Api,
     #[cfg(feature = "gateway")]
     Gateway,
     #[cfg(feature = "resolver")]
@@ -177,6 +182,8 @@ where
 {
     if CORE.enabled() {
         match c {
+            #[cfg(feature = "api")]
+            Collector::Api => CORE.api_metrics().record(m, v),
             #[cfg(feature = "gateway")]
             Collector::Gateway => CORE.gateway_metrics().record(m, v),
             #[cfg(feature = "resolver")]
@@ -199,6 +206,8 @@ where
 {
     if CORE.enabled() {
         match c {
+            #[cfg(feature = "api")]
+            Collector::Api => CORE.api_metrics().observe(m, v),
             #[cfg(feature = "gateway")]
             Collector::Gateway => CORE.gateway_metrics().observe(m, v),
             #[cfg(feature = "resolver")]
@@ -17,6 +17,8 @@ pub(crate) struct Metrics {
     skipped_peer_bitswap: Counter,
     skipped_peer_kad: Counter,
     loops: Counter,
+    malformed_messages: Counter,
+    dropped_gossip_events: Counter,
 }
 
 impl fmt::Debug for Metrics {
@@ -54,12 +56,28 @@ impl Metrics {
         let loops = Counter::default();
         sub_registry.register(P2PMetrics::LoopCounter.name(), "", Box::new(loops.clone()));
 
+        let malformed_messages = Counter::default();
+        sub_registry.register(
+            P2PMetrics::MalformedMessage.name(),
+            "",
+            Box::new(malformed_messages.clone()),
+        );
+
+        let dropped_gossip_events = Counter::default();
+        sub_registry.register(
+            P2PMetrics::DroppedGossipEvent.name(),
+            "",
+            Box::new(dropped_gossip_events.clone()),
+        );
+
         Self {
             bad_peers,
             bad_peers_removed,
             skipped_peer_bitswap,
             skipped_peer_kad,
             loops,
+            malformed_messages,
+            dropped_gossip_events,
         }
     }
 }
@@ -79,6 +97,10 @@ impl MetricsRecorder for Metrics {
             self.skipped_peer_kad.inc_by(value);
         } else if m.name() == P2PMetrics::LoopCounter.name() {
             self.loops.inc_by(value);
+        } else if m.name() == P2PMetrics::MalformedMessage.name() {
+            self.malformed_messages.inc_by(value);
+        } else if m.name() == P2PMetrics::DroppedGossipEvent.name() {
+            self.dropped_gossip_events.inc_by(value);
         } else {
             error!("record (bitswap): unknown metric {}", m.name());
         }
@@ -99,6 +121,8 @@ pub enum P2PMetrics {
     SkippedPeerBitswap,
     SkippedPeerKad,
     LoopCounter,
+    MalformedMessage,
+    DroppedGossipEvent,
 }
 
 impl MetricType for P2PMetrics {
@@ -109,6 +133,8 @@ impl MetricType for P2PMetrics {
             P2PMetrics::SkippedPeerBitswap => "skipped_peer_bitswap",
             P2PMetrics::SkippedPeerKad => "skipped_peer_kad",
             P2PMetrics::LoopCounter => "loop_counter",
+            P2PMetrics::MalformedMessage => "malformed_message",
+            P2PMetrics::DroppedGossipEvent => "dropped_gossip_event",
         }
     }
 }
@@ -112,6 +112,9 @@ fn default_store_config(
     Ok(iroh_store::config::Config {
         path,
         rpc_client: ipfsd,
+        cache_size: None,
+        max_open_files: None,
+        verify_on_start: None,
     })
 }
 
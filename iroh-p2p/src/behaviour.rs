@@ -21,10 +21,13 @@ use libp2p::{autonat, dcutr};
 use tracing::{info, warn};
 
 pub(crate) use self::event::Event;
+use self::gossip_fetch::GossipFetch;
+pub use self::peer_manager::PeerAllowList;
 use self::peer_manager::PeerManager;
 use crate::config::Libp2pConfig;
 
 mod event;
+mod gossip_fetch;
 mod peer_manager;
 
 pub const PROTOCOL_VERSION: &str = "ipfs/0.1.0";
@@ -35,7 +38,7 @@ pub const AGENT_VERSION: &str = concat!("iroh/", env!("CARGO_PKG_VERSION"));
 #[behaviour(out_event = "Event")]
 pub(crate) struct NodeBehaviour {
     ping: Ping,
-    identify: identify::Behaviour,
+    pub(crate) identify: identify::Behaviour,
     pub(crate) bitswap: Toggle<Bitswap<BitswapStore>>,
     pub(crate) kad: Toggle<Kademlia<MemoryStore>>,
     mdns: Toggle<Mdns>,
@@ -44,6 +47,8 @@ pub(crate) struct NodeBehaviour {
     relay_client: Toggle<relay::v2::client::Client>,
     dcutr: Toggle<dcutr::behaviour::Behaviour>,
     pub(crate) gossipsub: Toggle<gossipsub::Gossipsub>,
+    /// Answers requests for the last message published or retained on a gossipsub topic.
+    pub(crate) gossip_fetch: Toggle<GossipFetch>,
     pub(crate) peer_manager: PeerManager,
 }
 
@@ -81,6 +86,12 @@ impl Store for BitswapStore {
 }
 
 impl NodeBehaviour {
+    /// Creates the behaviour for the protocols enabled in `config`.
+    ///
+    /// The relay server and relay client roles are independent of each other, any
+    /// combination of `relay_server` and `relay_client` is valid. `relay_client` must be
+    /// the behaviour created together with the relay transport if, and only if, the relay
+    /// client is enabled.
     pub async fn new(
         local_key: &Keypair,
         config: &Libp2pConfig,
@@ -94,11 +105,12 @@ impl NodeBehaviour {
         let bitswap = if config.bitswap_client || config.bitswap_server {
             info!("init bitswap");
             // TODO(dig): server only mode is not implemented yet
-            let bs_config = if config.bitswap_server {
+            let mut bs_config = if config.bitswap_server {
                 BitswapConfig::default()
             } else {
                 BitswapConfig::default_client_mode()
             };
+            bs_config.peer_state_events = config.bitswap_peer_state_events;
             Some(Bitswap::new(peer_id, BitswapStore(rpc_client), bs_config).await)
         } else {
             None
@@ -180,10 +192,13 @@ impl NodeBehaviour {
         }
         .into();
 
+        // Direct connection upgrades happen over relayed connections, which only exist
+        // for relay clients, so dcutr is tied to the client role only.
         let (dcutr, relay_client) = if config.relay_client {
             info!("init relay client");
-            let relay_client =
-                relay_client.expect("missing relay client even though it was enabled");
+            let relay_client = relay_client.ok_or_else(|| {
+                anyhow::anyhow!("missing relay client even though it was enabled")
+            })?;
             let dcutr = dcutr::behaviour::Behaviour::new();
             (Some(dcutr), Some(relay_client))
         } else {
@@ -191,15 +206,26 @@ impl NodeBehaviour {
         };
 
         let identify = {
-            let config = identify::Config::new(PROTOCOL_VERSION.into(), local_key.public())
-                .with_agent_version(String::from(AGENT_VERSION))
-                .with_cache_size(64 * 1024);
+            let config =
+                identify::Config::new(config.identify_protocol_version.clone(), local_key.public())
+                    .with_agent_version(config.identify_agent_version.clone())
+                    .with_cache_size(64 * 1024);
             identify::Behaviour::new(config)
         };
 
+        let gossip_fetch = if config.gossip_fetch {
+            Some(gossip_fetch::new(config.gossipsub_max_transmit_size))
+        } else {
+            None
+        }
+        .into();
+
         let gossipsub = if config.gossipsub {
             info!("init gossipsub");
-            let gossipsub_config = gossipsub::GossipsubConfig::default();
+            let gossipsub_config = gossipsub::GossipsubConfigBuilder::default()
+                .max_transmit_size(config.gossipsub_max_transmit_size)
+                .build()
+                .map_err(|e| anyhow::anyhow!("invalid gossipsub config: {}", e))?;
             let message_authenticity = MessageAuthenticity::Signed(local_key.clone());
             Some(
                 gossipsub::Gossipsub::new(message_authenticity, gossipsub_config)
@@ -221,6 +247,7 @@ impl NodeBehaviour {
             dcutr: dcutr.into(),
             relay_client: relay_client.into(),
             gossipsub,
+            gossip_fetch,
             peer_manager,
         })
     }
@@ -256,4 +283,48 @@ mod tests {
         assert_send::<NodeBehaviour>();
         assert_send::<&Bitswap<BitswapStore>>();
     }
+
+    #[tokio::test]
+    async fn test_relay_roles() -> Result<()> {
+        let keypair = Keypair::generate_ed25519();
+        let peer_id = keypair.public().to_peer_id();
+        let rpc_client = Client::new(Default::default()).await?;
+        let base = Libp2pConfig {
+            bitswap_client: false,
+            bitswap_server: false,
+            kademlia: false,
+            autonat: false,
+            gossipsub: false,
+            ..Default::default()
+        };
+
+        for (relay_server, relay_client) in
+            [(false, false), (true, false), (false, true), (true, true)]
+        {
+            let config = Libp2pConfig {
+                relay_server,
+                relay_client,
+                ..base.clone()
+            };
+            let client = relay_client
+                .then(|| relay::v2::client::Client::new_transport_and_behaviour(peer_id).1);
+            let behaviour =
+                NodeBehaviour::new(&keypair, &config, client, rpc_client.clone()).await?;
+
+            assert_eq!(behaviour.relay.is_enabled(), relay_server);
+            assert_eq!(behaviour.relay_client.is_enabled(), relay_client);
+            assert_eq!(behaviour.dcutr.is_enabled(), relay_client);
+        }
+
+        // The relay client behaviour must be passed in when the client is enabled.
+        let config = Libp2pConfig {
+            relay_client: true,
+            ..base
+        };
+        assert!(NodeBehaviour::new(&keypair, &config, None, rpc_client)
+            .await
+            .is_err());
+
+        Ok(())
+    }
 }
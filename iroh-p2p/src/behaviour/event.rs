@@ -1,7 +1,8 @@
 use iroh_bitswap::BitswapEvent;
 use libp2p::{
-    autonat, dcutr, gossipsub::GossipsubEvent, identify::Event as IdentifyEvent,
-    kad::KademliaEvent, mdns::Event as MdnsEvent, ping::Event as PingEvent, relay,
+    autonat, dcutr, gossipsub::GossipsubEvent, gossipsub::TopicHash,
+    identify::Event as IdentifyEvent, kad::KademliaEvent, mdns::Event as MdnsEvent,
+    ping::Event as PingEvent, relay, request_response::RequestResponseEvent,
 };
 
 use super::peer_manager::PeerManagerEvent;
@@ -21,6 +22,7 @@ pub enum Event {
     RelayClient(relay::v2::client::Event),
     Dcutr(dcutr::behaviour::Event),
     Gossipsub(GossipsubEvent),
+    GossipFetch(RequestResponseEvent<TopicHash, Option<Vec<u8>>>),
     PeerManager(PeerManagerEvent),
 }
 
@@ -60,6 +62,12 @@ impl From<GossipsubEvent> for Event {
     }
 }
 
+impl From<RequestResponseEvent<TopicHash, Option<Vec<u8>>>> for Event {
+    fn from(event: RequestResponseEvent<TopicHash, Option<Vec<u8>>>) -> Self {
+        Event::GossipFetch(event)
+    }
+}
+
 impl From<autonat::Event> for Event {
     fn from(event: autonat::Event) -> Self {
         Event::Autonat(event)
@@ -0,0 +1,171 @@
+//! Direct requests for the last message a peer published on a gossipsub topic.
+//!
+//! Gossipsub only delivers a message to the peers that are known to be subscribed to its
+//! topic when it is published. A peer whose subscription did not reach the publisher in
+//! time misses the message for good. With this protocol it asks the publisher for the
+//! message instead, over the connection it already has to it.
+use std::io;
+use std::iter;
+
+use async_trait::async_trait;
+use futures::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use libp2p::core::upgrade::{read_length_prefixed, write_length_prefixed};
+use libp2p::core::ProtocolName;
+use libp2p::gossipsub::TopicHash;
+use libp2p::request_response::{
+    ProtocolSupport, RequestResponse, RequestResponseCodec, RequestResponseConfig,
+};
+
+/// Maximum size of a request, which only holds a topic hash.
+const MAX_REQUEST_SIZE: usize = 1024;
+
+pub(crate) type GossipFetch = RequestResponse<GossipFetchCodec>;
+
+/// Creates the behaviour, answering with messages of up to `max_message_size` bytes.
+pub(crate) fn new(max_message_size: usize) -> GossipFetch {
+    RequestResponse::new(
+        GossipFetchCodec { max_message_size },
+        iter::once((GossipFetchProtocol, ProtocolSupport::Full)),
+        RequestResponseConfig::default(),
+    )
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct GossipFetchProtocol;
+
+impl ProtocolName for GossipFetchProtocol {
+    fn protocol_name(&self) -> &[u8] {
+        b"/iroh/gossip-fetch/1.0.0"
+    }
+}
+
+/// Requests are the raw topic hash, responses a flag whether the peer published on the
+/// topic, followed by the message.
+#[derive(Debug, Clone)]
+pub(crate) struct GossipFetchCodec {
+    max_message_size: usize,
+}
+
+#[async_trait]
+impl RequestResponseCodec for GossipFetchCodec {
+    type Protocol = GossipFetchProtocol;
+    type Request = TopicHash;
+    type Response = Option<Vec<u8>>;
+
+    async fn read_request<T>(
+        &mut self,
+        _: &GossipFetchProtocol,
+        io: &mut T,
+    ) -> io::Result<TopicHash>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let data = read_length_prefixed(io, MAX_REQUEST_SIZE).await?;
+        let topic = String::from_utf8(data)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        Ok(TopicHash::from_raw(topic))
+    }
+
+    async fn read_response<T>(
+        &mut self,
+        _: &GossipFetchProtocol,
+        io: &mut T,
+    ) -> io::Result<Option<Vec<u8>>>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let mut data = read_length_prefixed(io, self.max_message_size + 1).await?;
+        match data.first() {
+            Some(0) if data.len() == 1 => Ok(None),
+            Some(1) => {
+                data.remove(0);
+                Ok(Some(data))
+            }
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "malformed gossip fetch response",
+            )),
+        }
+    }
+
+    async fn write_request<T>(
+        &mut self,
+        _: &GossipFetchProtocol,
+        io: &mut T,
+        topic: TopicHash,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_length_prefixed(io, topic.as_str()).await?;
+        io.close().await
+    }
+
+    async fn write_response<T>(
+        &mut self,
+        _: &GossipFetchProtocol,
+        io: &mut T,
+        message: Option<Vec<u8>>,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        let data = match message {
+            Some(message) => [&[1], &message[..]].concat(),
+            None => vec![0],
+        };
+        write_length_prefixed(io, data).await?;
+        io.close().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::io::Cursor;
+
+    #[tokio::test]
+    async fn test_gossip_fetch_codec() {
+        let mut codec = GossipFetchCodec {
+            max_message_size: 16,
+        };
+        let topic = TopicHash::from_raw("share/topic");
+        let mut io = Cursor::new(Vec::new());
+        codec
+            .write_request(&GossipFetchProtocol, &mut io, topic.clone())
+            .await
+            .unwrap();
+        io.set_position(0);
+        let got = codec
+            .read_request(&GossipFetchProtocol, &mut io)
+            .await
+            .unwrap();
+        assert_eq!(got, topic);
+
+        for message in [None, Some(vec![]), Some(b"start".to_vec())] {
+            let mut io = Cursor::new(Vec::new());
+            codec
+                .write_response(&GossipFetchProtocol, &mut io, message.clone())
+                .await
+                .unwrap();
+            io.set_position(0);
+            let got = codec
+                .read_response(&GossipFetchProtocol, &mut io)
+                .await
+                .unwrap();
+            assert_eq!(got, message);
+        }
+
+        // messages above the limit are rejected
+        let mut io = Cursor::new(Vec::new());
+        codec
+            .write_response(&GossipFetchProtocol, &mut io, Some(vec![0; 17]))
+            .await
+            .unwrap();
+        io.set_position(0);
+        assert!(codec
+            .read_response(&GossipFetchProtocol, &mut io)
+            .await
+            .is_err());
+    }
+}
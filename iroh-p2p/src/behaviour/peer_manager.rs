@@ -1,27 +1,166 @@
 use std::{
+    collections::VecDeque,
+    convert::Infallible,
+    fmt,
     num::NonZeroUsize,
-    task::{Context, Poll},
+    sync::{Arc, Mutex},
+    task::{Context, Poll, Waker},
     time::Duration,
 };
 
-use ahash::AHashMap;
+use ahash::{AHashMap, AHashSet};
 use iroh_metrics::{core::MRecorder, inc, p2p::P2PMetrics};
 use libp2p::{
-    core::{connection::ConnectionId, transport::ListenerId, ConnectedPoint},
+    core::{
+        connection::ConnectionId, transport::ListenerId, upgrade::DeniedUpgrade, ConnectedPoint,
+    },
     identify::Info as IdentifyInfo,
     ping::Success as PingSuccess,
     swarm::{
-        dummy, ConnectionHandler, DialError, IntoConnectionHandler, NetworkBehaviour,
-        NetworkBehaviourAction, PollParameters,
+        CloseConnection, ConnectionHandler, ConnectionHandlerEvent, DialError,
+        IntoConnectionHandler, KeepAlive, NetworkBehaviour, NetworkBehaviourAction, PollParameters,
+        SubstreamProtocol,
     },
     Multiaddr, PeerId,
 };
 use lru::LruCache;
+use tracing::debug;
 
 pub struct PeerManager {
     info: AHashMap<PeerId, Info>,
     bad_peers: LruCache<PeerId, ()>,
     supported_protocols: Vec<String>,
+    allow_list: PeerAllowList,
+    connected: AHashSet<PeerId>,
+    /// Connected peers that were removed from the allow list, whose connections are closed
+    /// next.
+    disallowed: VecDeque<PeerId>,
+}
+
+/// The peers a node may be connected to, shared between the node and its owner.
+///
+/// All peers are allowed by default. Once restricted, connections to other peers, inbound
+/// as well as outbound, are denied by the connection handler once they are established,
+/// before any protocol runs on them. Only the `NetConnect` rpcs refuse to dial them up
+/// front; dials by bitswap, Kademlia or provider lookups still go out, so other peers can
+/// learn the addresses of the node. Existing connections are closed when the list changes.
+#[derive(Debug, Clone, Default)]
+pub struct PeerAllowList(Arc<Mutex<AllowListState>>);
+
+#[derive(Debug, Default)]
+struct AllowListState {
+    peers: Option<AHashSet<PeerId>>,
+    /// Set on changes, until the peer manager checked the existing connections.
+    changed: bool,
+    waker: Option<Waker>,
+}
+
+impl PeerAllowList {
+    /// Only allows connections to and from `peers`.
+    pub fn allow_only(&self, peers: impl IntoIterator<Item = PeerId>) {
+        self.update(Some(peers.into_iter().collect()));
+    }
+
+    /// Allows connections to and from all peers again.
+    pub fn allow_all(&self) {
+        self.update(None);
+    }
+
+    pub fn is_allowed(&self, peer_id: &PeerId) -> bool {
+        let state = self.0.lock().unwrap();
+        match &state.peers {
+            Some(peers) => peers.contains(peer_id),
+            None => true,
+        }
+    }
+
+    fn update(&self, peers: Option<AHashSet<PeerId>>) {
+        let mut state = self.0.lock().unwrap();
+        state.peers = peers;
+        state.changed = true;
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+    }
+
+    /// Whether the list changed since the last call, registers `cx` to be woken on the next
+    /// change.
+    fn poll_changed(&self, cx: &mut Context<'_>) -> bool {
+        let mut state = self.0.lock().unwrap();
+        state.waker = Some(cx.waker().clone());
+        std::mem::take(&mut state.changed)
+    }
+}
+
+/// Creates the [`AllowListHandler`] of a connection, once the remote peer is known.
+#[derive(Debug, Clone)]
+pub struct AllowListHandlerProto(PeerAllowList);
+
+impl IntoConnectionHandler for AllowListHandlerProto {
+    type Handler = AllowListHandler;
+
+    fn into_handler(self, peer_id: &PeerId, _: &ConnectedPoint) -> Self::Handler {
+        AllowListHandler {
+            denied: !self.0.is_allowed(peer_id),
+        }
+    }
+
+    fn inbound_protocol(&self) -> DeniedUpgrade {
+        DeniedUpgrade
+    }
+}
+
+/// Handles no protocols, but closes connections to peers that are not on the allow list.
+#[derive(Debug)]
+pub struct AllowListHandler {
+    denied: bool,
+}
+
+/// The error connections to peers that are not on the allow list are closed with.
+#[derive(Debug)]
+pub struct NotAllowed;
+
+impl fmt::Display for NotAllowed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "peer is not on the allow list")
+    }
+}
+
+impl std::error::Error for NotAllowed {}
+
+impl ConnectionHandler for AllowListHandler {
+    type InEvent = Infallible;
+    type OutEvent = Infallible;
+    type Error = NotAllowed;
+    type InboundProtocol = DeniedUpgrade;
+    type OutboundProtocol = DeniedUpgrade;
+    type InboundOpenInfo = ();
+    type OutboundOpenInfo = Infallible;
+
+    fn listen_protocol(&self) -> SubstreamProtocol<Self::InboundProtocol, Self::InboundOpenInfo> {
+        SubstreamProtocol::new(DeniedUpgrade, ())
+    }
+
+    fn connection_keep_alive(&self) -> KeepAlive {
+        KeepAlive::No
+    }
+
+    fn poll(
+        &mut self,
+        _: &mut Context<'_>,
+    ) -> Poll<
+        ConnectionHandlerEvent<
+            Self::OutboundProtocol,
+            Self::OutboundOpenInfo,
+            Self::OutEvent,
+            Self::Error,
+        >,
+    > {
+        if self.denied {
+            return Poll::Ready(ConnectionHandlerEvent::Close(NotAllowed));
+        }
+        Poll::Pending
+    }
 }
 
 #[derive(Default, Debug, Clone)]
@@ -45,6 +184,9 @@ impl Default for PeerManager {
             info: Default::default(),
             bad_peers: LruCache::new(DEFAULT_BAD_PEER_CAP.unwrap()),
             supported_protocols: Default::default(),
+            allow_list: Default::default(),
+            connected: Default::default(),
+            disallowed: Default::default(),
         }
     }
 }
@@ -74,14 +216,18 @@ impl PeerManager {
     pub fn supported_protocols(&self) -> Vec<String> {
         self.supported_protocols.clone()
     }
+
+    pub fn allow_list(&self) -> &PeerAllowList {
+        &self.allow_list
+    }
 }
 
 impl NetworkBehaviour for PeerManager {
-    type ConnectionHandler = dummy::ConnectionHandler;
+    type ConnectionHandler = AllowListHandlerProto;
     type OutEvent = PeerManagerEvent;
 
     fn new_handler(&mut self) -> Self::ConnectionHandler {
-        dummy::ConnectionHandler
+        AllowListHandlerProto(self.allow_list.clone())
     }
 
     fn addresses_of_peer(&mut self, peer_id: &PeerId) -> Vec<Multiaddr> {
@@ -105,6 +251,7 @@ impl NetworkBehaviour for PeerManager {
             if p.is_some() {
                 inc!(P2PMetrics::BadPeerRemoved);
             }
+            self.connected.insert(*peer_id);
         }
 
         if let Some(failed_addresses) = failed_addresses {
@@ -122,12 +269,15 @@ impl NetworkBehaviour for PeerManager {
 
     fn inject_connection_closed(
         &mut self,
-        _: &PeerId,
+        peer_id: &PeerId,
         _: &ConnectionId,
         _: &ConnectedPoint,
         _: <Self::ConnectionHandler as IntoConnectionHandler>::Handler,
-        _remaining_established: usize,
+        remaining_established: usize,
     ) {
+        if remaining_established == 0 {
+            self.connected.remove(peer_id);
+        }
     }
 
     fn inject_address_change(
@@ -191,7 +341,7 @@ impl NetworkBehaviour for PeerManager {
 
     fn poll(
         &mut self,
-        _cx: &mut Context<'_>,
+        cx: &mut Context<'_>,
         params: &mut impl PollParameters,
     ) -> Poll<NetworkBehaviourAction<Self::OutEvent, Self::ConnectionHandler>> {
         // TODO(ramfox):
@@ -212,6 +362,68 @@ impl NetworkBehaviour for PeerManager {
                 .collect();
         }
 
+        if self.allow_list.poll_changed(cx) {
+            for peer_id in &self.connected {
+                if !self.allow_list.is_allowed(peer_id) {
+                    debug!("closing connection to {}, not on the allow list", peer_id);
+                    self.disallowed.push_back(*peer_id);
+                }
+            }
+        }
+        if let Some(peer_id) = self.disallowed.pop_front() {
+            return Poll::Ready(NetworkBehaviourAction::CloseConnection {
+                peer_id,
+                connection: CloseConnection::All,
+            });
+        }
+
         Poll::Pending
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allow_list_handler() {
+        let allowed = PeerId::random();
+        let list = PeerAllowList::default();
+        list.allow_only([allowed]);
+        let endpoint = ConnectedPoint::Dialer {
+            address: Multiaddr::empty(),
+            role_override: libp2p::core::Endpoint::Dialer,
+        };
+        let mut cx = Context::from_waker(futures::task::noop_waker_ref());
+
+        let mut handler = AllowListHandlerProto(list.clone()).into_handler(&allowed, &endpoint);
+        assert!(handler.poll(&mut cx).is_pending());
+
+        let mut handler = AllowListHandlerProto(list).into_handler(&PeerId::random(), &endpoint);
+        assert!(matches!(
+            handler.poll(&mut cx),
+            Poll::Ready(ConnectionHandlerEvent::Close(NotAllowed))
+        ));
+    }
+
+    #[test]
+    fn test_peer_allow_list() {
+        let allowed = PeerId::random();
+        let other = PeerId::random();
+        let list = PeerAllowList::default();
+        let mut cx = Context::from_waker(futures::task::noop_waker_ref());
+
+        assert!(list.is_allowed(&other));
+        assert!(!list.poll_changed(&mut cx));
+
+        list.allow_only([allowed]);
+        assert!(list.is_allowed(&allowed));
+        assert!(!list.is_allowed(&other));
+        assert!(list.poll_changed(&mut cx));
+        assert!(!list.poll_changed(&mut cx));
+
+        list.allow_all();
+        assert!(list.is_allowed(&other));
+        assert!(list.poll_changed(&mut cx));
+    }
+}
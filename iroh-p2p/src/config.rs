@@ -9,6 +9,9 @@ use iroh_util::{insert_into_config_map, iroh_data_root};
 use libp2p::Multiaddr;
 use serde::{Deserialize, Serialize};
 
+use crate::behaviour::{AGENT_VERSION, PROTOCOL_VERSION};
+use crate::dial::DialAddressOrder;
+
 /// CONFIG_FILE_NAME is the name of the optional config file located in the iroh home directory
 pub const CONFIG_FILE_NAME: &str = "p2p.config.toml";
 /// ENV_PREFIX should be used along side the config field name to set a config field using
@@ -30,6 +33,9 @@ pub const DEFAULT_BOOTSTRAP: &[&str] = &[
 
 // "/ip4/104.131.131.82/udp/4001/quic/p2p/QmaCpDMGvV2BGHeYERUEnRQAwe3N8SzbUtfsmvsqQLuvuJ", // mars.i.ipfs.io
 
+/// The default of [`Libp2pConfig::gossipsub_max_transmit_size`], the gossipsub default.
+pub const DEFAULT_GOSSIPSUB_MAX_TRANSMIT_SIZE: usize = 65536;
+
 /// The configuration for the p2p server.
 ///
 /// This is the configuration which the p2p server binary needs to run.  It is a superset
@@ -83,16 +89,44 @@ pub struct Libp2pConfig {
     pub bitswap_server: bool,
     /// Bitswap client mode enabled.
     pub bitswap_client: bool,
+    /// Emit an event whenever the bitswap connection state of a peer changes.
+    pub bitswap_peer_state_events: bool,
     /// Kademlia discovery enabled.
     pub kademlia: bool,
     /// Autonat holepunching enabled.
     pub autonat: bool,
-    /// Relay server enabled.
+    /// Relay server enabled, relaying connections between other peers.
+    ///
+    /// Independent of `relay_client`: a node can be a relay server, a relay client, both
+    /// or neither. Resource constrained nodes can disable the server and keep the client.
     pub relay_server: bool,
-    /// Relay client enabled.
+    /// Relay client enabled, making the node reachable through relays it listens on and
+    /// upgrading relayed connections to direct ones (dcutr).
     pub relay_client: bool,
+    /// Seconds to wait before re-establishing a lost relay reservation.
+    pub relay_reconnect_interval_secs: u64,
+    /// Maximum seconds to wait between attempts, when relay reservations keep failing.
+    pub relay_max_backoff_secs: u64,
     /// Gossipsub enabled.
     pub gossipsub: bool,
+    /// Maximum size in bytes of gossipsub messages, sent or received.
+    ///
+    /// Peers drop messages larger than their own limit, so this should only be raised for
+    /// networks where all peers raise it.
+    pub gossipsub_max_transmit_size: usize,
+    /// Lets connected peers fetch the last message this node published or retained on a
+    /// topic directly, also if gossipsub is disabled.
+    pub gossip_fetch: bool,
+    /// Protocol version advertised to other peers via identify.
+    pub identify_protocol_version: String,
+    /// Agent version advertised to other peers via identify.
+    pub identify_agent_version: String,
+    /// Push identify info to all connected peers when the addresses of this node change,
+    /// e.g. after a new relay reservation or a network change, so they can redial it.
+    ///
+    /// Each change sends a message to every connected peer, bandwidth sensitive setups can
+    /// disable this and leave peers to learn the addresses on their next identify.
+    pub identify_push: bool,
     pub max_conns_out: u32,
     pub max_conns_in: u32,
     pub max_conns_pending_out: u32,
@@ -101,6 +135,12 @@ pub struct Libp2pConfig {
     pub notify_handler_buffer_size: usize,
     pub connection_event_buffer_size: usize,
     pub dial_concurrency_factor: u8,
+    /// The order in which the known addresses of a peer are dialed, when the node connects
+    /// to it or checks it for the DHT.
+    ///
+    /// Dials started by behaviours, e.g. bitswap, use the addresses in the order libp2p
+    /// collects them.
+    pub dial_address_order: DialAddressOrder,
 }
 
 /// Configuration for the [`iroh-p2p`] node.
@@ -167,15 +207,48 @@ impl Source for Libp2pConfig {
             "dial_concurrency_factor",
             self.dial_concurrency_factor as i64,
         );
+        insert_into_config_map(
+            &mut map,
+            "relay_reconnect_interval_secs",
+            self.relay_reconnect_interval_secs as i64,
+        );
+        insert_into_config_map(
+            &mut map,
+            "relay_max_backoff_secs",
+            self.relay_max_backoff_secs as i64,
+        );
+        insert_into_config_map(
+            &mut map,
+            "gossipsub_max_transmit_size",
+            self.gossipsub_max_transmit_size as i64,
+        );
 
         insert_into_config_map(&mut map, "kademlia", self.kademlia);
         insert_into_config_map(&mut map, "autonat", self.autonat);
         insert_into_config_map(&mut map, "bitswap_client", self.bitswap_client);
         insert_into_config_map(&mut map, "bitswap_server", self.bitswap_server);
+        insert_into_config_map(
+            &mut map,
+            "bitswap_peer_state_events",
+            self.bitswap_peer_state_events,
+        );
         insert_into_config_map(&mut map, "mdns", self.mdns);
         insert_into_config_map(&mut map, "relay_server", self.relay_server);
         insert_into_config_map(&mut map, "relay_client", self.relay_client);
         insert_into_config_map(&mut map, "gossipsub", self.gossipsub);
+        insert_into_config_map(&mut map, "gossip_fetch", self.gossip_fetch);
+        insert_into_config_map(
+            &mut map,
+            "identify_protocol_version",
+            self.identify_protocol_version.clone(),
+        );
+        insert_into_config_map(
+            &mut map,
+            "identify_agent_version",
+            self.identify_agent_version.clone(),
+        );
+        insert_into_config_map(&mut map, "identify_push", self.identify_push);
+        insert_into_config_map(&mut map, "dial_address_order", self.dial_address_order);
         let peers: Vec<String> = self.bootstrap_peers.iter().map(|b| b.to_string()).collect();
         insert_into_config_map(&mut map, "bootstrap_peers", peers);
         let addrs: Vec<String> = self
@@ -220,9 +293,17 @@ impl Default for Libp2pConfig {
             autonat: true,
             relay_server: true,
             relay_client: true,
+            relay_reconnect_interval_secs: 5,
+            relay_max_backoff_secs: 5 * 60,
             gossipsub: true,
+            gossipsub_max_transmit_size: DEFAULT_GOSSIPSUB_MAX_TRANSMIT_SIZE,
+            gossip_fetch: true,
+            identify_protocol_version: PROTOCOL_VERSION.to_string(),
+            identify_agent_version: AGENT_VERSION.to_string(),
+            identify_push: true,
             bitswap_client: true,
             bitswap_server: true,
+            bitswap_peer_state_events: false,
             max_conns_pending_out: 256,
             max_conns_pending_in: 256,
             max_conns_in: 256,
@@ -231,6 +312,7 @@ impl Default for Libp2pConfig {
             notify_handler_buffer_size: 256,
             connection_event_buffer_size: 256,
             dial_concurrency_factor: 8,
+            dial_address_order: DialAddressOrder::default(),
         }
     }
 }
@@ -342,6 +424,18 @@ mod tests {
             "dial_concurrency_factor".to_string(),
             Value::new(None, default.dial_concurrency_factor as i64),
         );
+        expect.insert(
+            "relay_reconnect_interval_secs".to_string(),
+            Value::new(None, default.relay_reconnect_interval_secs as i64),
+        );
+        expect.insert(
+            "relay_max_backoff_secs".to_string(),
+            Value::new(None, default.relay_max_backoff_secs as i64),
+        );
+        expect.insert(
+            "gossipsub_max_transmit_size".to_string(),
+            Value::new(None, default.gossipsub_max_transmit_size as i64),
+        );
 
         expect.insert("kademlia".to_string(), Value::new(None, default.kademlia));
         expect.insert("autonat".to_string(), Value::new(None, default.autonat));
@@ -354,6 +448,10 @@ mod tests {
             "bitswap_client".to_string(),
             Value::new(None, default.bitswap_client),
         );
+        expect.insert(
+            "bitswap_peer_state_events".to_string(),
+            Value::new(None, default.bitswap_peer_state_events),
+        );
         expect.insert(
             "relay_server".to_string(),
             Value::new(None, default.relay_server),
@@ -363,6 +461,26 @@ mod tests {
             Value::new(None, default.relay_client),
         );
         expect.insert("gossipsub".to_string(), Value::new(None, default.gossipsub));
+        expect.insert(
+            "gossip_fetch".to_string(),
+            Value::new(None, default.gossip_fetch),
+        );
+        expect.insert(
+            "identify_protocol_version".to_string(),
+            Value::new(None, default.identify_protocol_version.clone()),
+        );
+        expect.insert(
+            "identify_agent_version".to_string(),
+            Value::new(None, default.identify_agent_version.clone()),
+        );
+        expect.insert(
+            "identify_push".to_string(),
+            Value::new(None, default.identify_push),
+        );
+        expect.insert(
+            "dial_address_order".to_string(),
+            Value::new(None, default.dial_address_order),
+        );
         expect.insert(
             "bootstrap_peers".to_string(),
             Value::new(None, bootstrap_peers),
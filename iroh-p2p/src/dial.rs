@@ -0,0 +1,166 @@
+use std::fmt::{self, Display};
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+
+use ahash::AHashSet;
+use anyhow::{bail, Result};
+use config::ValueKind;
+use libp2p::multiaddr::Protocol;
+use libp2p::Multiaddr;
+use serde::{Deserialize, Serialize};
+
+/// The order in which the addresses of a peer are dialed.
+///
+/// Addresses are dialed a few at a time, see [`Libp2pConfig::dial_concurrency_factor`],
+/// so those tried first are the most likely to be used for the connection.
+///
+/// [`Libp2pConfig::dial_concurrency_factor`]: crate::Libp2pConfig::dial_concurrency_factor
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DialAddressOrder {
+    /// Addresses are dialed in the order they are known, explicitly given addresses first.
+    Unordered,
+    /// Loopback and local network addresses are dialed first, as they are the fastest to
+    /// connect, then public addresses, and relayed addresses last, as a fallback.
+    #[default]
+    LocalFirst,
+}
+
+impl DialAddressOrder {
+    /// Removes duplicates from `addrs` and sorts them in this order, otherwise keeping the
+    /// order they are in.
+    pub fn order(self, addrs: &mut Vec<Multiaddr>) {
+        let mut seen = AHashSet::new();
+        addrs.retain(|addr| seen.insert(addr.clone()));
+        match self {
+            DialAddressOrder::Unordered => {}
+            DialAddressOrder::LocalFirst => addrs.sort_by_key(AddressClass::of),
+        }
+    }
+}
+
+impl FromStr for DialAddressOrder {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "unordered" => Ok(DialAddressOrder::Unordered),
+            "local_first" => Ok(DialAddressOrder::LocalFirst),
+            _ => bail!("unknown dial address order '{}'", s),
+        }
+    }
+}
+
+impl Display for DialAddressOrder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DialAddressOrder::Unordered => write!(f, "unordered"),
+            DialAddressOrder::LocalFirst => write!(f, "local_first"),
+        }
+    }
+}
+
+impl From<DialAddressOrder> for ValueKind {
+    fn from(source: DialAddressOrder) -> Self {
+        Self::String(source.to_string())
+    }
+}
+
+/// Where an address points to, in the order they are dialed by
+/// [`DialAddressOrder::LocalFirst`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum AddressClass {
+    Local,
+    Public,
+    Relay,
+}
+
+impl AddressClass {
+    fn of(addr: &Multiaddr) -> Self {
+        if addr.iter().any(|p| matches!(p, Protocol::P2pCircuit)) {
+            return AddressClass::Relay;
+        }
+        match addr.iter().next() {
+            Some(Protocol::Ip4(ip)) if is_local_ip4(&ip) => AddressClass::Local,
+            Some(Protocol::Ip6(ip)) if is_local_ip6(&ip) => AddressClass::Local,
+            Some(Protocol::Dns(host) | Protocol::Dns4(host) | Protocol::Dns6(host))
+                if host == "localhost" =>
+            {
+                AddressClass::Local
+            }
+            Some(Protocol::Memory(_)) => AddressClass::Local,
+            _ => AddressClass::Public,
+        }
+    }
+}
+
+fn is_local_ip4(ip: &Ipv4Addr) -> bool {
+    ip.is_loopback() || ip.is_private() || ip.is_link_local()
+}
+
+fn is_local_ip6(ip: &Ipv6Addr) -> bool {
+    let segment = ip.segments()[0];
+    // unique local (fc00::/7) and link local (fe80::/10) addresses
+    ip.is_loopback() || segment & 0xfe00 == 0xfc00 || segment & 0xffc0 == 0xfe80
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libp2p::PeerId;
+
+    #[test]
+    fn test_dial_address_order() {
+        let peer = PeerId::random();
+        let relay: Multiaddr = format!("/ip4/5.6.7.8/tcp/4001/p2p/{peer}/p2p-circuit")
+            .parse()
+            .unwrap();
+        let public: Multiaddr = "/ip4/1.2.3.4/tcp/4444".parse().unwrap();
+        let dns: Multiaddr = "/dns4/example.com/tcp/4444".parse().unwrap();
+        let lan: Multiaddr = "/ip4/192.168.1.10/udp/4445/quic-v1".parse().unwrap();
+        let loopback: Multiaddr = "/ip6/::1/tcp/4444".parse().unwrap();
+        let link_local: Multiaddr = "/ip6/fe80::1/tcp/4444".parse().unwrap();
+
+        let addrs = vec![
+            relay.clone(),
+            public.clone(),
+            lan.clone(),
+            dns.clone(),
+            public.clone(),
+            loopback.clone(),
+            link_local.clone(),
+        ];
+
+        let mut ordered = addrs.clone();
+        DialAddressOrder::LocalFirst.order(&mut ordered);
+        assert_eq!(
+            ordered,
+            vec![
+                lan.clone(),
+                loopback.clone(),
+                link_local.clone(),
+                public.clone(),
+                dns.clone(),
+                relay.clone(),
+            ]
+        );
+
+        let mut unordered = addrs;
+        DialAddressOrder::Unordered.order(&mut unordered);
+        assert_eq!(
+            unordered,
+            vec![relay, public, lan, dns, loopback, link_local]
+        );
+    }
+
+    #[test]
+    fn test_dial_address_order_parse() {
+        for order in [DialAddressOrder::Unordered, DialAddressOrder::LocalFirst] {
+            assert_eq!(
+                order.to_string().parse::<DialAddressOrder>().unwrap(),
+                order
+            );
+        }
+        assert!("fastest".parse::<DialAddressOrder>().is_err());
+    }
+}
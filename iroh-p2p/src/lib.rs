@@ -1,14 +1,18 @@
 mod behaviour;
 pub mod cli;
 pub mod config;
+mod dial;
 mod keys;
 pub mod metrics;
 mod node;
 mod providers;
+mod relay;
 pub mod rpc;
 mod swarm;
 
+pub use self::behaviour::PeerAllowList;
 pub use self::config::*;
+pub use self::dial::DialAddressOrder;
 pub use self::keys::{DiskStorage, Keychain, MemoryStorage};
 pub use self::node::*;
 
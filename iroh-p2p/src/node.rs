@@ -1,45 +1,59 @@
 use std::collections::{HashMap, HashSet};
 use std::fmt;
-use std::time::Duration;
+use std::num::NonZeroUsize;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use ahash::AHashMap;
+use ahash::{AHashMap, AHashSet};
 use anyhow::{anyhow, bail, Context, Result};
+use bytes::Bytes;
 use cid::Cid;
 use futures_util::stream::StreamExt;
 use iroh_metrics::{core::MRecorder, inc, libp2p_metrics, p2p::P2PMetrics};
 use iroh_rpc_client::Client as RpcClient;
-use iroh_rpc_types::p2p::P2pAddr;
-use libp2p::core::Multiaddr;
+use iroh_rpc_types::p2p::{
+    BitswapPeerState, ImportRoutingStateResponse, P2pAddr, PeerInfo, ProviderRecord, RoutingPeer,
+    RoutingState,
+};
+use libp2p::core::{ConnectedPoint, Multiaddr, ProtocolName};
 use libp2p::gossipsub::{GossipsubMessage, MessageId, TopicHash};
 pub use libp2p::gossipsub::{IdentTopic, Topic};
 use libp2p::identify::{Event as IdentifyEvent, Info as IdentifyInfo};
 use libp2p::identity::Keypair;
 use libp2p::kad::kbucket::{Distance, NodeStatus};
+use libp2p::kad::store::RecordStore;
 use libp2p::kad::{
-    self, BootstrapOk, GetClosestPeersError, GetClosestPeersOk, GetProvidersOk, KademliaEvent,
-    QueryId, QueryResult,
+    self, record::Key, BootstrapOk, GetClosestPeersError, GetClosestPeersOk, GetProvidersOk,
+    KademliaEvent, QueryId, QueryResult,
 };
 use libp2p::mdns;
 use libp2p::metrics::Recorder;
 use libp2p::multiaddr::Protocol;
 use libp2p::ping::Result as PingResult;
+use libp2p::relay;
+use libp2p::request_response::{RequestId, RequestResponseEvent, RequestResponseMessage};
 use libp2p::swarm::dial_opts::{DialOpts, PeerCondition};
 use libp2p::swarm::{ConnectionHandler, IntoConnectionHandler, NetworkBehaviour, SwarmEvent};
 use libp2p::{PeerId, Swarm};
-use tokio::sync::mpsc::{channel, Receiver, Sender};
+use lru::LruCache;
+use tokio::sync::mpsc::{
+    channel, error::TrySendError, unbounded_channel, Receiver, Sender, UnboundedSender,
+};
 use tokio::sync::oneshot::{self, Sender as OneShotSender};
 use tokio::task::JoinHandle;
 use tracing::{debug, error, info, trace, warn};
 
-use iroh_bitswap::{BitswapEvent, Block};
+use iroh_bitswap::{message::Priority, BitswapEvent, Block, ConnState};
 use iroh_rpc_client::Lookup;
+use iroh_unixfs::parse_links;
 
+use crate::dial::DialAddressOrder;
 use crate::keys::{Keychain, Storage};
 use crate::providers::Providers;
+use crate::relay::{ReconnectPolicy, Relays};
 use crate::rpc::{P2p, ProviderRequestKey};
 use crate::swarm::build_swarm;
 use crate::{
-    behaviour::{Event, NodeBehaviour},
+    behaviour::{Event, NodeBehaviour, PeerAllowList},
     rpc::{self, RpcMessage},
     Config,
 };
@@ -47,12 +61,55 @@ use crate::{
 #[allow(clippy::large_enum_variant)]
 #[derive(Debug, Clone)]
 pub enum NetworkEvent {
-    PeerConnected(PeerId),
-    PeerDisconnected(PeerId),
+    /// The first connection to a peer was established.
+    PeerConnected {
+        peer_id: PeerId,
+        connection: ConnectionInfo,
+    },
+    /// The last connection to a peer was closed.
+    PeerDisconnected {
+        peer_id: PeerId,
+        connection: ConnectionInfo,
+    },
     Gossipsub(GossipsubEvent),
     CancelLookupQuery(PeerId),
 }
 
+/// Describes a single connection to a peer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionInfo {
+    /// The address of the remote end of the connection.
+    pub remote_addr: Multiaddr,
+    pub direction: ConnectionDirection,
+    /// Whether the connection runs through a relay, rather than directly to the peer.
+    pub relayed: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionDirection {
+    /// The peer dialed us.
+    Inbound,
+    /// We dialed the peer.
+    Outbound,
+}
+
+impl From<&ConnectedPoint> for ConnectionInfo {
+    fn from(endpoint: &ConnectedPoint) -> Self {
+        let remote_addr = endpoint.get_remote_address().clone();
+        let relayed = remote_addr.iter().any(|p| p == Protocol::P2pCircuit);
+        let direction = if endpoint.is_dialer() {
+            ConnectionDirection::Outbound
+        } else {
+            ConnectionDirection::Inbound
+        };
+        ConnectionInfo {
+            remote_addr,
+            direction,
+            relayed,
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum GossipsubEvent {
     Subscribed {
@@ -77,7 +134,7 @@ pub struct Node<KeyStorage: Storage> {
     lookup_queries: AHashMap<PeerId, Vec<oneshot::Sender<Result<IdentifyInfo>>>>,
     // TODO(ramfox): use new providers queue instead
     find_on_dht_queries: AHashMap<Vec<u8>, DHTQuery>,
-    network_events: Vec<Sender<NetworkEvent>>,
+    network_events: Vec<NetworkEventListener>,
     #[allow(dead_code)]
     rpc_client: RpcClient,
     _keychain: Keychain<KeyStorage>,
@@ -88,6 +145,30 @@ pub struct Node<KeyStorage: Storage> {
     bitswap_sessions: BitswapSessions,
     providers: Providers,
     listen_addrs: Vec<Multiaddr>,
+    /// Relayed addresses for each relay we hold an active reservation with.
+    relay_reservations: AHashMap<PeerId, Vec<Multiaddr>>,
+    /// Relays we listen on, whose reservations are re-established once lost.
+    relays: Relays,
+    /// Protocol version advertised via identify.
+    protocol_version: String,
+    /// Agent version advertised via identify.
+    agent_version: String,
+    /// Whether to push identify info to connected peers when our addresses change.
+    identify_push: bool,
+    /// The listen and external addresses connected peers were last told about.
+    advertised_addrs: AHashSet<Multiaddr>,
+    /// The order in which the addresses of a peer are dialed.
+    dial_address_order: DialAddressOrder,
+    /// The last message published, or attempted to be published, on each subscribed topic,
+    /// handed out to peers asking for it directly. Bounded by [`MAX_LAST_PUBLISHED`], the
+    /// least recently used topics are forgotten first.
+    last_published: LruCache<TopicHash, Bytes>,
+    /// The peers that fetched the message in `last_published` on each topic.
+    last_published_fetched_by: AHashMap<TopicHash, AHashSet<PeerId>>,
+    gossip_fetch_queries: AHashMap<RequestId, oneshot::Sender<Result<Option<Bytes>>>>,
+    /// Providers found for wants that were orphaned by a dropped bitswap peer, to be dialed.
+    orphaned_providers: Receiver<ProviderResults>,
+    orphaned_providers_sender: Sender<ProviderResults>,
 }
 
 impl<T: Storage> fmt::Debug for Node<T> {
@@ -106,6 +187,10 @@ impl<T: Storage> fmt::Debug for Node<T> {
             .field("use_dht", &self.use_dht)
             .field("bitswap_sessions", &self.bitswap_sessions)
             .field("providers", &self.providers)
+            .field("relay_reservations", &self.relay_reservations)
+            .field("relays", &self.relays)
+            .field("protocol_version", &self.protocol_version)
+            .field("agent_version", &self.agent_version)
             .finish()
     }
 }
@@ -115,10 +200,17 @@ type DHTQuery = (PeerId, Vec<oneshot::Sender<Result<()>>>);
 
 type BitswapSessions = AHashMap<u64, Vec<(oneshot::Sender<()>, JoinHandle<()>)>>;
 
+type ProviderResults = std::result::Result<HashSet<PeerId>, String>;
+
 pub(crate) const DEFAULT_PROVIDER_LIMIT: usize = 10;
 const NICE_INTERVAL: Duration = Duration::from_secs(6);
 const BOOTSTRAP_INTERVAL: Duration = Duration::from_secs(5 * 60);
 const EXPIRY_INTERVAL: Duration = Duration::from_secs(1);
+const PRUNE_PEERS_INTERVAL: Duration = Duration::from_secs(60);
+/// Bitswap peers not seen for this long are forgotten.
+const KNOWN_PEERS_MAX_AGE: Duration = Duration::from_secs(30 * 60);
+/// How many topics the last published message is retained for.
+const MAX_LAST_PUBLISHED: usize = 1024;
 
 impl<KeyStorage: Storage> Drop for Node<KeyStorage> {
     fn drop(&mut self) {
@@ -133,6 +225,7 @@ impl<KeyStorage: Storage> Node<KeyStorage> {
         mut keychain: Keychain<KeyStorage>,
     ) -> Result<Self> {
         let (network_sender_in, network_receiver_in) = channel(1024); // TODO: configurable
+        let (orphaned_providers_sender, orphaned_providers) = channel(64);
 
         let Config {
             libp2p: libp2p_config,
@@ -154,9 +247,16 @@ impl<KeyStorage: Storage> Node<KeyStorage> {
         let keypair = load_identity(&mut keychain).await?;
         let mut swarm = build_swarm(&libp2p_config, &keypair, rpc_client.clone()).await?;
 
+        let mut relays = Relays::new(ReconnectPolicy {
+            interval: Duration::from_secs(libp2p_config.relay_reconnect_interval_secs),
+            max_backoff: Duration::from_secs(libp2p_config.relay_max_backoff_secs),
+        });
         let mut listen_addrs = vec![];
         for addr in &libp2p_config.listening_multiaddrs {
-            Swarm::listen_on(&mut swarm, addr.clone())?;
+            let listener = Swarm::listen_on(&mut swarm, addr.clone())?;
+            if libp2p_config.relay_client {
+                relays.insert(addr.clone(), listener);
+            }
             listen_addrs.push(addr.clone());
         }
 
@@ -176,6 +276,18 @@ impl<KeyStorage: Storage> Node<KeyStorage> {
             bitswap_sessions: Default::default(),
             providers: Providers::new(4),
             listen_addrs,
+            relay_reservations: Default::default(),
+            relays,
+            protocol_version: libp2p_config.identify_protocol_version.clone(),
+            agent_version: libp2p_config.identify_agent_version.clone(),
+            identify_push: libp2p_config.identify_push,
+            advertised_addrs: Default::default(),
+            dial_address_order: libp2p_config.dial_address_order,
+            last_published: LruCache::new(NonZeroUsize::new(MAX_LAST_PUBLISHED).unwrap()),
+            last_published_fetched_by: Default::default(),
+            gossip_fetch_queries: Default::default(),
+            orphaned_providers,
+            orphaned_providers_sender,
         })
     }
 
@@ -195,6 +307,7 @@ impl<KeyStorage: Storage> Node<KeyStorage> {
         let mut nice_interval = self.use_dht.then(|| tokio::time::interval(NICE_INTERVAL));
         let mut bootstrap_interval = tokio::time::interval(BOOTSTRAP_INTERVAL);
         let mut expiry_interval = tokio::time::interval(EXPIRY_INTERVAL);
+        let mut prune_peers_interval = tokio::time::interval(PRUNE_PEERS_INTERVAL);
 
         loop {
             inc!(P2PMetrics::LoopCounter);
@@ -202,9 +315,21 @@ impl<KeyStorage: Storage> Node<KeyStorage> {
             tokio::select! {
                 swarm_event = self.swarm.next() => {
                     let swarm_event = swarm_event.expect("the swarm will never die");
+                    let addrs_may_change = matches!(
+                        swarm_event,
+                        SwarmEvent::NewListenAddr { .. }
+                            | SwarmEvent::ExpiredListenAddr { .. }
+                            | SwarmEvent::ListenerClosed { .. }
+                            | SwarmEvent::Behaviour(
+                                Event::Identify(_) | Event::Autonat(_) | Event::RelayClient(_)
+                            )
+                    );
                     if let Err(err) = self.handle_swarm_event(swarm_event) {
                         error!("swarm error: {:?}", err);
                     }
+                    if addrs_may_change && self.identify_push {
+                        self.push_address_changes();
+                    }
 
                     if let Some(kad) = self.swarm.behaviour_mut().kad.as_mut() {
                         self.providers.poll(kad);
@@ -251,6 +376,211 @@ impl<KeyStorage: Storage> Node<KeyStorage> {
                     if let Err(err) = self.expiry() {
                         warn!("expiry error {:?}", err);
                     }
+                    self.reconnect_relays();
+                }
+                _ = prune_peers_interval.tick() => {
+                    if let Some(bs) = self.swarm.behaviour().bitswap.as_ref() {
+                        bs.prune_known_peers(KNOWN_PEERS_MAX_AGE);
+                    }
+                }
+                Some(providers) = self.orphaned_providers.recv() => {
+                    self.dial_orphaned_providers(providers);
+                }
+            }
+        }
+    }
+
+    /// Dials the providers found for wants orphaned by a dropped bitswap peer. Once connected
+    /// they are sent the wants with the next broadcast of the sessions.
+    fn dial_orphaned_providers(&mut self, providers: ProviderResults) {
+        let providers = match providers {
+            Ok(providers) => providers,
+            Err(err) => {
+                debug!("failed to find providers for orphaned wants: {}", err);
+                return;
+            }
+        };
+        for peer_id in providers {
+            if self.swarm.is_connected(&peer_id) || peer_id == *self.swarm.local_peer_id() {
+                continue;
+            }
+            let addrs = self.dial_addresses(&peer_id, Vec::new());
+            let dial_opts = DialOpts::peer_id(peer_id)
+                .addresses(addrs)
+                .condition(PeerCondition::Disconnected)
+                .build();
+            if let Err(err) = Swarm::dial(&mut self.swarm, dial_opts) {
+                debug!("failed to dial provider {}: {}", peer_id, err);
+            }
+        }
+    }
+
+    /// The addresses to dial `peer_id` at, `addrs` followed by those known to the behaviours,
+    /// in the configured [`DialAddressOrder`].
+    fn dial_addresses(&mut self, peer_id: &PeerId, mut addrs: Vec<Multiaddr>) -> Vec<Multiaddr> {
+        addrs.extend(self.swarm.behaviour_mut().addresses_of_peer(peer_id));
+        self.dial_address_order.order(&mut addrs);
+        addrs
+    }
+
+    /// Pushes identify info to all connected peers, if the listen or external addresses
+    /// changed since they were last told about them.
+    ///
+    /// External addresses are confirmed by identify and autonat, or added for relay
+    /// reservations, without a swarm event, so this compares the whole set.
+    fn push_address_changes(&mut self) {
+        let addrs: AHashSet<Multiaddr> = self
+            .swarm
+            .listeners()
+            .chain(self.swarm.external_addresses().map(|record| &record.addr))
+            .cloned()
+            .collect();
+        if addrs == self.advertised_addrs {
+            return;
+        }
+        self.advertised_addrs = addrs;
+
+        let peers: Vec<PeerId> = self.swarm.connected_peers().copied().collect();
+        if !peers.is_empty() {
+            debug!(
+                "addresses changed, pushing identify to {} peers",
+                peers.len()
+            );
+            self.swarm.behaviour_mut().identify.push(peers);
+        }
+    }
+
+    /// Announces this node as a provider of `key`.
+    ///
+    /// Kademlia keeps a local provider record, which it publishes again periodically until
+    /// [`Node::stop_providing`] removes it.
+    fn start_providing(&mut self, key: Key) -> Result<QueryId> {
+        let kad = self
+            .swarm
+            .behaviour_mut()
+            .kad
+            .as_mut()
+            .context("kademlia is not available")?;
+        Ok(kad.start_providing(key)?)
+    }
+
+    /// Drops the local provider record of `key`, so it is no longer published.
+    ///
+    /// Records already published to other peers can not be revoked, they expire once they
+    /// are no longer republished.
+    fn stop_providing(&mut self, key: &Key) -> Result<()> {
+        let kad = self
+            .swarm
+            .behaviour_mut()
+            .kad
+            .as_mut()
+            .context("kademlia is not available")?;
+        kad.stop_providing(key);
+        Ok(())
+    }
+
+    /// Returns the peers of the routing table and the local provider records, so they can
+    /// be restored with [`Node::import_routing_state`], e.g. on a new machine.
+    fn export_routing_state(&mut self) -> Result<RoutingState> {
+        let kad = self
+            .swarm
+            .behaviour_mut()
+            .kad
+            .as_mut()
+            .context("kademlia is not available")?;
+        let mut peers = Vec::new();
+        for kbucket in kad.kbuckets() {
+            for entry in kbucket.iter() {
+                peers.push(RoutingPeer {
+                    peer_id: *entry.node.key.preimage(),
+                    addrs: entry.node.value.iter().cloned().collect(),
+                });
+            }
+        }
+
+        // records expire at an `Instant`, which has no meaning outside of this process
+        let now = Instant::now();
+        let unix_now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+        let providers = kad
+            .store_mut()
+            .provided()
+            .map(|record| ProviderRecord {
+                key: record.key.to_vec().into(),
+                provider: record.provider,
+                addrs: record.addresses.clone(),
+                expires: record
+                    .expires
+                    .map(|at| (unix_now + at.saturating_duration_since(now)).as_secs()),
+            })
+            .collect();
+        Ok(RoutingState { peers, providers })
+    }
+
+    /// Adds the peers of `state` to the routing table, and its provider records to the local
+    /// ones, which Kademlia publishes again periodically.
+    ///
+    /// Peers without addresses, records without a key, records of other providers and
+    /// records that already expired are discarded.
+    fn import_routing_state(&mut self, state: RoutingState) -> Result<ImportRoutingStateResponse> {
+        let local_peer_id = *self.swarm.local_peer_id();
+        let kad = self
+            .swarm
+            .behaviour_mut()
+            .kad
+            .as_mut()
+            .context("kademlia is not available")?;
+        let mut res = ImportRoutingStateResponse::default();
+        for peer in state.peers {
+            if peer.peer_id == local_peer_id || peer.addrs.is_empty() {
+                res.discarded += 1;
+                continue;
+            }
+            for addr in peer.addrs {
+                kad.add_address(&peer.peer_id, addr);
+            }
+            res.peers += 1;
+        }
+
+        let now = Instant::now();
+        let unix_now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        for record in state.providers {
+            let expired = record.expires.map_or(false, |expires| expires <= unix_now);
+            if record.key.is_empty() || record.provider != local_peer_id || expired {
+                res.discarded += 1;
+                continue;
+            }
+            let key = Key::from(record.key.to_vec());
+            let mut provider =
+                kad::record::ProviderRecord::new(key.clone(), local_peer_id, record.addrs);
+            provider.expires = record
+                .expires
+                .map(|expires| now + Duration::from_secs(expires - unix_now));
+            if let Err(err) = kad.store_mut().add_provider(provider) {
+                warn!("failed to import provider record {:?}: {:?}", key, err);
+                res.discarded += 1;
+                continue;
+            }
+            res.providers += 1;
+        }
+        debug!(
+            "imported {} peers and {} provider records, discarded {}",
+            res.peers, res.providers, res.discarded
+        );
+        Ok(res)
+    }
+
+    /// Listens on relays again, whose reservation was lost and whose backoff has elapsed.
+    fn reconnect_relays(&mut self) {
+        for (relay, addr, old_listener) in self.relays.due(Instant::now()) {
+            if let Some(old_listener) = old_listener {
+                self.swarm.remove_listener(old_listener);
+            }
+            debug!("re-establishing relay reservation with {}", relay);
+            match Swarm::listen_on(&mut self.swarm, addr) {
+                Ok(listener) => self.relays.listening(&relay, listener),
+                Err(err) => {
+                    warn!("failed to listen on relay {}: {:?}", relay, err);
+                    self.relays.failed(&relay, Instant::now());
                 }
             }
         }
@@ -297,26 +627,22 @@ impl<KeyStorage: Storage> Node<KeyStorage> {
                 // find the first disconnected node
                 for entry in kbucket.iter() {
                     if entry.status == NodeStatus::Disconnected {
-                        let peer_id = entry.node.key.preimage();
-
-                        let dial_opts = DialOpts::peer_id(*peer_id)
-                            .condition(PeerCondition::Disconnected)
-                            .addresses(entry.node.value.clone().into_vec())
-                            .extend_addresses_through_behaviour()
-                            .build();
-                        to_dial = Some((dial_opts, kbucket.range()));
+                        let peer_id = *entry.node.key.preimage();
+                        let addrs = entry.node.value.clone().into_vec();
+                        to_dial = Some((peer_id, addrs, kbucket.range()));
                         break;
                     }
                 }
             }
         }
 
-        if let Some((dial_opts, range)) = to_dial {
-            trace!(
-                "checking node {:?} in bucket range ({:?})",
-                dial_opts.get_peer_id().unwrap(),
-                range
-            );
+        if let Some((peer_id, addrs, range)) = to_dial {
+            trace!("checking node {:?} in bucket range ({:?})", peer_id, range);
+
+            let dial_opts = DialOpts::peer_id(peer_id)
+                .condition(PeerCondition::Disconnected)
+                .addresses(self.dial_addresses(&peer_id, addrs))
+                .build();
 
             if let Err(e) = self.swarm.dial(dial_opts) {
                 warn!("failed to dial: {:?}", e);
@@ -325,11 +651,19 @@ impl<KeyStorage: Storage> Node<KeyStorage> {
         }
     }
 
+    /// The peers this node may be connected to, all by default.
+    ///
+    /// The returned handle stays valid while the node runs, so connections can be
+    /// restricted after the node started.
+    pub fn peer_allow_list(&self) -> PeerAllowList {
+        self.swarm.behaviour().peer_manager.allow_list().clone()
+    }
+
     /// Subscribe to [`NetworkEvent`]s.
     #[tracing::instrument(skip(self))]
     pub fn network_events(&mut self) -> Receiver<NetworkEvent> {
         let (s, r) = channel(512);
-        self.network_events.push(s);
+        self.network_events.push(NetworkEventListener::lossless(s));
         r
     }
 
@@ -368,7 +702,9 @@ impl<KeyStorage: Storage> Node<KeyStorage> {
         ctx: u64,
         cid: Cid,
         providers: HashSet<PeerId>,
-        mut chan: OneShotSender<Result<Block, String>>,
+        priority: Option<Priority>,
+        max_providers: Option<usize>,
+        mut chan: OneShotSender<Result<(Block, Option<PeerId>), String>>,
     ) -> Result<()> {
         if let Some(bs) = self.swarm.behaviour().bitswap.as_ref() {
             let client = bs.client().clone();
@@ -387,9 +723,16 @@ impl<KeyStorage: Storage> Node<KeyStorage> {
                         // RPC dropped
                         debug!("session {}: stopped: request canceled", ctx);
                     }
-                    block = client.get_block_with_session_id(ctx, &cid, &providers) => match block {
+                    block = client.get_block_with_session_id(
+                        ctx,
+                        &cid,
+                        &providers,
+                        priority,
+                        max_providers,
+                    ) => match block {
                         Ok(block) => {
-                            if let Err(e) = chan.send(Ok(block)) {
+                            let from = client.block_origin(block.cid());
+                            if let Err(e) = chan.send(Ok((block, from))) {
                                 warn!("failed to send block response: {:?}", e);
                             }
                         }
@@ -420,6 +763,7 @@ impl<KeyStorage: Storage> Node<KeyStorage> {
             SwarmEvent::Behaviour(event) => self.handle_node_event(event),
             SwarmEvent::ConnectionEstablished {
                 peer_id,
+                endpoint,
                 num_established,
                 ..
             } => {
@@ -430,23 +774,41 @@ impl<KeyStorage: Storage> Node<KeyStorage> {
                 }
 
                 if num_established == 1.try_into().unwrap() {
-                    self.emit_network_event(NetworkEvent::PeerConnected(peer_id));
+                    self.emit_network_event(NetworkEvent::PeerConnected {
+                        peer_id,
+                        connection: ConnectionInfo::from(&endpoint),
+                    });
                 }
                 trace!("ConnectionEstablished: {:}", peer_id);
                 Ok(())
             }
             SwarmEvent::ConnectionClosed {
                 peer_id,
+                endpoint,
                 num_established,
                 ..
             } => {
                 if num_established == 0 {
-                    self.emit_network_event(NetworkEvent::PeerDisconnected(peer_id));
+                    self.relay_reservations.remove(&peer_id);
+                    self.relays.failed(&peer_id, Instant::now());
+                    self.emit_network_event(NetworkEvent::PeerDisconnected {
+                        peer_id,
+                        connection: ConnectionInfo::from(&endpoint),
+                    });
                 }
 
                 trace!("ConnectionClosed: {:}", peer_id);
                 Ok(())
             }
+            SwarmEvent::ListenerClosed {
+                listener_id,
+                reason,
+                ..
+            } => {
+                debug!("listener {:?} closed: {:?}", listener_id, reason);
+                self.relays.listener_closed(listener_id, Instant::now());
+                Ok(())
+            }
             SwarmEvent::OutgoingConnectionError { peer_id, error } => {
                 trace!("failed to dial: {:?}, {:?}", peer_id, error);
 
@@ -465,17 +827,32 @@ impl<KeyStorage: Storage> Node<KeyStorage> {
         }
     }
 
+    /// Collects what identify, ping and bitswap know about `peer_id`.
+    fn peer_info(&mut self, peer_id: PeerId) -> PeerInfo {
+        let addrs = self.swarm.behaviour_mut().addresses_of_peer(&peer_id);
+        let behaviour = self.swarm.behaviour();
+        let info = behaviour.peer_manager.info_for_peer(&peer_id);
+        let identify = info.and_then(|info| info.last_info.as_ref());
+        let bitswap = behaviour.bitswap.as_ref();
+
+        PeerInfo {
+            peer_id,
+            addrs,
+            agent_version: identify.map(|i| i.agent_version.clone()),
+            protocols: identify.map(|i| i.protocols.clone()).unwrap_or_default(),
+            latency: info.and_then(|info| info.latency()),
+            bitswap_state: bitswap
+                .and_then(|bs| bs.conn_state(&peer_id))
+                .map(bitswap_peer_state),
+            bitswap_protocol: bitswap
+                .and_then(|bs| bs.peer_protocol(&peer_id))
+                .map(|p| String::from_utf8_lossy(p.protocol_name()).into_owned()),
+        }
+    }
+
     #[tracing::instrument(skip(self))]
     fn emit_network_event(&mut self, ev: NetworkEvent) {
-        for sender in &mut self.network_events {
-            let ev = ev.clone();
-            let sender = sender.clone();
-            tokio::task::spawn(async move {
-                if let Err(e) = sender.send(ev.clone()).await {
-                    warn!("failed to send network event: {:?}", e);
-                }
-            });
-        }
+        send_network_event(&mut self.network_events, ev);
     }
 
     #[tracing::instrument(skip(self))]
@@ -485,14 +862,10 @@ impl<KeyStorage: Storage> Node<KeyStorage> {
                 match e {
                     BitswapEvent::Provide { key } => {
                         info!("bitswap provide {}", key);
-                        if let Some(kad) = self.swarm.behaviour_mut().kad.as_mut() {
-                            match kad.start_providing(key.hash().to_bytes().into()) {
-                                Ok(_query_id) => {
-                                    // TODO: track query?
-                                }
-                                Err(err) => {
-                                    error!("failed to provide {}: {:?}", key, err);
-                                }
+                        if self.swarm.behaviour().kad.is_enabled() {
+                            // TODO: track query?
+                            if let Err(err) = self.start_providing(key.hash().to_bytes().into()) {
+                                error!("failed to provide {}: {:?}", key, err);
                             }
                         }
                     }
@@ -518,6 +891,40 @@ impl<KeyStorage: Storage> Node<KeyStorage> {
                             }
                         }
                     }
+                    BitswapEvent::PeerHave { peer, key, have } => {
+                        debug!("bitswap peer {} has {}: {}", peer, key, have);
+                    }
+                    BitswapEvent::PeerState { peer, old, new } => {
+                        info!("bitswap peer {}: {:?} -> {:?}", peer, old, new);
+                    }
+                    BitswapEvent::WantsOrphaned { peer, reason, keys } => {
+                        debug!(
+                            "bitswap peer {} dropped ({:?}) with {} orphaned wants",
+                            peer,
+                            reason,
+                            keys.len()
+                        );
+                        // like sessions, only search providers of the first block, which
+                        // likely have the others as well
+                        if let Some(key) = keys.first() {
+                            if self.swarm.behaviour().kad.is_enabled() {
+                                self.providers.push(
+                                    key.hash().to_bytes().into(),
+                                    DEFAULT_PROVIDER_LIMIT,
+                                    self.orphaned_providers_sender.clone(),
+                                );
+                            }
+                        }
+                    }
+                    BitswapEvent::UnsolicitedBlocks { peer, blocks } => {
+                        debug!("storing {} unsolicited blocks from {}", blocks.len(), peer);
+                        let client = self.rpc_client.clone();
+                        tokio::task::spawn(async move {
+                            if let Err(err) = store_blocks(&client, blocks).await {
+                                warn!("failed to store unsolicited blocks: {:?}", err);
+                            }
+                        });
+                    }
                 }
             }
             Event::Kademlia(e) => {
@@ -698,6 +1105,35 @@ impl<KeyStorage: Storage> Node<KeyStorage> {
             Event::Dcutr(e) => {
                 libp2p_metrics().record(&e);
             }
+            Event::RelayClient(e) => match e {
+                relay::v2::client::Event::ReservationReqAccepted { relay_peer_id, .. } => {
+                    let local_peer_id = *self.swarm.local_peer_id();
+                    let addrs: Vec<_> = self
+                        .swarm
+                        .behaviour_mut()
+                        .addresses_of_peer(&relay_peer_id)
+                        .into_iter()
+                        .filter(|addr| !addr.iter().any(|p| p == Protocol::P2pCircuit))
+                        .map(|addr| relayed_addr(addr, relay_peer_id, local_peer_id))
+                        .collect();
+                    debug!("relay reservation with {}: {:?}", relay_peer_id, addrs);
+                    self.relay_reservations.insert(relay_peer_id, addrs);
+                    self.relays.accepted(&relay_peer_id);
+                }
+                relay::v2::client::Event::ReservationReqFailed {
+                    relay_peer_id,
+                    error,
+                    ..
+                } => {
+                    debug!(
+                        "relay reservation with {} failed: {:?}",
+                        relay_peer_id, error
+                    );
+                    self.relay_reservations.remove(&relay_peer_id);
+                    self.relays.failed(&relay_peer_id, Instant::now());
+                }
+                _ => {}
+            },
             Event::Gossipsub(e) => {
                 libp2p_metrics().record(&e);
                 if let libp2p::gossipsub::GossipsubEvent::Message {
@@ -723,6 +1159,55 @@ impl<KeyStorage: Storage> Node<KeyStorage> {
                     ));
                 }
             }
+            Event::GossipFetch(e) => match e {
+                RequestResponseEvent::Message { peer, message } => match message {
+                    RequestResponseMessage::Request {
+                        request, channel, ..
+                    } => {
+                        let message = self.last_published.get(&request).map(|m| m.to_vec());
+                        if message.is_some() {
+                            self.last_published_fetched_by
+                                .entry(request.clone())
+                                .or_default()
+                                .insert(peer);
+                        }
+                        trace!(
+                            "gossip fetch: {} asked for {}, found: {}",
+                            peer,
+                            request,
+                            message.is_some()
+                        );
+                        if let Some(gossip_fetch) = self.swarm.behaviour_mut().gossip_fetch.as_mut()
+                        {
+                            if gossip_fetch.send_response(channel, message).is_err() {
+                                debug!("gossip fetch: {} closed the request", peer);
+                            }
+                        }
+                    }
+                    RequestResponseMessage::Response {
+                        request_id,
+                        response,
+                    } => {
+                        if let Some(chan) = self.gossip_fetch_queries.remove(&request_id) {
+                            chan.send(Ok(response.map(Bytes::from))).ok();
+                        }
+                    }
+                },
+                RequestResponseEvent::OutboundFailure {
+                    peer,
+                    request_id,
+                    error,
+                } => {
+                    if let Some(chan) = self.gossip_fetch_queries.remove(&request_id) {
+                        chan.send(Err(anyhow!("gossip fetch from {} failed: {}", peer, error)))
+                            .ok();
+                    }
+                }
+                RequestResponseEvent::InboundFailure { peer, error, .. } => {
+                    debug!("gossip fetch: request from {} failed: {}", peer, error);
+                }
+                RequestResponseEvent::ResponseSent { .. } => {}
+            },
             Event::Mdns(e) => match e {
                 mdns::Event::Discovered(peers) => {
                     for (peer_id, addr) in peers {
@@ -769,6 +1254,17 @@ impl<KeyStorage: Storage> Node<KeyStorage> {
                     .send(self.swarm.listeners().cloned().collect())
                     .ok();
             }
+            RpcMessage::Relays(response_channel) => {
+                response_channel
+                    .send(self.relays.states(Instant::now()))
+                    .ok();
+            }
+            RpcMessage::ExportRoutingState(response_channel) => {
+                response_channel.send(self.export_routing_state()).ok();
+            }
+            RpcMessage::ImportRoutingState(response_channel, state) => {
+                response_channel.send(self.import_routing_state(state)).ok();
+            }
             RpcMessage::LocalPeerId(response_channel) => {
                 response_channel.send(*self.swarm.local_peer_id()).ok();
             }
@@ -777,11 +1273,20 @@ impl<KeyStorage: Storage> Node<KeyStorage> {
                 cids,
                 response_channels,
                 providers,
+                priority,
+                max_providers,
             } => {
                 trace!("context:{} bitswap_request", ctx);
                 for (cid, response_channel) in cids.into_iter().zip(response_channels.into_iter()) {
-                    self.want_block(ctx, cid, providers.clone(), response_channel)
-                        .map_err(|err| anyhow!("Failed to send a bitswap want_block: {:?}", err))?;
+                    self.want_block(
+                        ctx,
+                        cid,
+                        providers.clone(),
+                        priority,
+                        max_providers,
+                        response_channel,
+                    )
+                    .map_err(|err| anyhow!("Failed to send a bitswap want_block: {:?}", err))?;
                 }
             }
             RpcMessage::BitswapNotifyNewBlocks {
@@ -825,30 +1330,21 @@ impl<KeyStorage: Storage> Node<KeyStorage> {
                 }
             },
             RpcMessage::StartProviding(response_channel, key) => {
-                if let Some(kad) = self.swarm.behaviour_mut().kad.as_mut() {
-                    let res: Result<QueryId> = kad.start_providing(key).map_err(|e| e.into());
-                    // TODO: wait for kad to process the query request before returning
-                    response_channel.send(res).ok();
-                } else {
-                    response_channel
-                        .send(Err(anyhow!("kademlia is not available")))
-                        .ok();
-                }
+                // TODO: wait for kad to process the query request before returning
+                response_channel.send(self.start_providing(key)).ok();
             }
             RpcMessage::StopProviding(response_channel, key) => {
-                if let Some(kad) = self.swarm.behaviour_mut().kad.as_mut() {
-                    kad.stop_providing(&key);
-                    response_channel.send(Ok(())).ok();
-                } else {
-                    response_channel
-                        .send(Err(anyhow!("kademlia is not availalbe")))
-                        .ok();
-                }
+                response_channel.send(self.stop_providing(&key)).ok();
             }
             RpcMessage::NetListeningAddrs(response_channel) => {
                 let mut listeners: Vec<_> = Swarm::listeners(&self.swarm).cloned().collect();
                 let peer_id = *Swarm::local_peer_id(&self.swarm);
                 listeners.extend(Swarm::external_addresses(&self.swarm).map(|r| r.addr.clone()));
+                for addr in self.relay_reservations.values().flatten() {
+                    if !listeners.contains(addr) {
+                        listeners.push(addr.clone());
+                    }
+                }
 
                 response_channel
                     .send((peer_id, listeners))
@@ -866,8 +1362,21 @@ impl<KeyStorage: Storage> Node<KeyStorage> {
                     .send(peer_addresses)
                     .map_err(|_| anyhow!("Failed to get Libp2p peers"))?;
             }
+            RpcMessage::NetPeersInfo(response_channel) => {
+                #[allow(clippy::needless_collect)]
+                let peers = self.swarm.connected_peers().copied().collect::<Vec<_>>();
+                let peers = peers
+                    .into_iter()
+                    .map(|peer_id| self.peer_info(peer_id))
+                    .collect();
+                response_channel.send(peers).ok();
+            }
             RpcMessage::NetConnect(response_channel, peer_id, addrs) => {
-                if self.swarm.is_connected(&peer_id) {
+                if !self.peer_allow_list().is_allowed(&peer_id) {
+                    response_channel
+                        .send(Err(anyhow!("peer {} is not on the allow list", peer_id)))
+                        .ok();
+                } else if self.swarm.is_connected(&peer_id) {
                     response_channel.send(Ok(())).ok();
                 } else {
                     let channels = self.dial_queries.entry(peer_id).or_default();
@@ -876,7 +1385,7 @@ impl<KeyStorage: Storage> Node<KeyStorage> {
                     // when using DialOpts::peer_id, having the `P2p` protocol as part of the
                     // added addresses throws an error
                     // we can filter out that protocol before adding the addresses to the dial opts
-                    let addrs = addrs
+                    let mut addrs = addrs
                         .iter()
                         .map(|a| {
                             a.iter()
@@ -884,6 +1393,7 @@ impl<KeyStorage: Storage> Node<KeyStorage> {
                                 .collect()
                         })
                         .collect();
+                    self.dial_address_order.order(&mut addrs);
                     let dial_opts = DialOpts::peer_id(peer_id)
                         .addresses(addrs)
                         .condition(libp2p::swarm::dial_opts::PeerCondition::Always)
@@ -899,13 +1409,19 @@ impl<KeyStorage: Storage> Node<KeyStorage> {
                 }
             }
             RpcMessage::NetConnectByPeerId(response_channel, peer_id) => {
-                if self.swarm.is_connected(&peer_id) {
+                if !self.peer_allow_list().is_allowed(&peer_id) {
+                    response_channel
+                        .send(Err(anyhow!("peer {} is not on the allow list", peer_id)))
+                        .ok();
+                } else if self.swarm.is_connected(&peer_id) {
                     response_channel.send(Ok(())).ok();
                 } else {
+                    let addrs = self.dial_addresses(&peer_id, Vec::new());
                     let channels = self.dial_queries.entry(peer_id).or_default();
                     channels.push(response_channel);
 
                     let dial_opts = DialOpts::peer_id(peer_id)
+                        .addresses(addrs)
                         .condition(libp2p::swarm::dial_opts::PeerCondition::Always)
                         .build();
                     if let Err(e) = Swarm::dial(&mut self.swarm, dial_opts) {
@@ -928,6 +1444,48 @@ impl<KeyStorage: Storage> Node<KeyStorage> {
                     .send(())
                     .map_err(|_| anyhow!("sender dropped"))?;
             }
+            RpcMessage::GossipFetchLast(response_channel, peer_id, topic_hash) => {
+                match self.swarm.behaviour_mut().gossip_fetch.as_mut() {
+                    Some(gossip_fetch) => {
+                        let id = gossip_fetch.send_request(&peer_id, topic_hash);
+                        self.gossip_fetch_queries.insert(id, response_channel);
+                    }
+                    None => {
+                        response_channel
+                            .send(Err(anyhow!("gossip fetch is not enabled")))
+                            .map_err(|_| anyhow!("sender dropped"))?;
+                    }
+                }
+            }
+            RpcMessage::GossipRetain(response_channel, topic_hash, bytes) => {
+                self.last_published_fetched_by.remove(&topic_hash);
+                retain_published(
+                    &mut self.last_published,
+                    &mut self.last_published_fetched_by,
+                    topic_hash,
+                    bytes,
+                );
+                response_channel
+                    .send(())
+                    .map_err(|_| anyhow!("sender dropped"))?;
+            }
+            RpcMessage::GossipRelease(response_channel, topic_hash) => {
+                self.last_published.pop(&topic_hash);
+                self.last_published_fetched_by.remove(&topic_hash);
+                response_channel
+                    .send(())
+                    .map_err(|_| anyhow!("sender dropped"))?;
+            }
+            RpcMessage::GossipFetchedBy(response_channel, topic_hash) => {
+                let peers = self
+                    .last_published_fetched_by
+                    .get(&topic_hash)
+                    .map(|peers| peers.iter().copied().collect())
+                    .unwrap_or_default();
+                response_channel
+                    .send(peers)
+                    .map_err(|_| anyhow!("sender dropped"))?;
+            }
             RpcMessage::Gossipsub(g) => {
                 let gossipsub = match self.swarm.behaviour_mut().gossipsub.as_mut() {
                     Some(gossipsub) => gossipsub,
@@ -964,7 +1522,21 @@ impl<KeyStorage: Storage> Node<KeyStorage> {
                             .send(peers)
                             .map_err(|_| anyhow!("sender dropped"))?;
                     }
+                    rpc::GossipsubMessage::Messages(sender) => {
+                        self.network_events
+                            .push(NetworkEventListener::Lossy(sender));
+                    }
                     rpc::GossipsubMessage::Publish(response_channel, topic_hash, bytes) => {
+                        // retained even if publishing fails, typically for lack of subscribed
+                        // peers, so that peers can still fetch it directly. Peers that fetched
+                        // an earlier message did not fetch this one.
+                        self.last_published_fetched_by.remove(&topic_hash);
+                        retain_published(
+                            &mut self.last_published,
+                            &mut self.last_published_fetched_by,
+                            topic_hash.clone(),
+                            bytes.clone(),
+                        );
                         let res = gossipsub
                             .publish(IdentTopic::new(topic_hash.into_string()), bytes.to_vec());
                         response_channel
@@ -990,6 +1562,8 @@ impl<KeyStorage: Storage> Node<KeyStorage> {
                             .map_err(|_| anyhow!("sender dropped"))?;
                     }
                     rpc::GossipsubMessage::Unsubscribe(response_channel, topic_hash) => {
+                        self.last_published.pop(&topic_hash);
+                        self.last_published_fetched_by.remove(&topic_hash);
                         let res = gossipsub.unsubscribe(&IdentTopic::new(topic_hash.into_string()));
                         response_channel
                             .send(res)
@@ -1017,8 +1591,8 @@ impl<KeyStorage: Storage> Node<KeyStorage> {
                     .external_addresses()
                     .map(|a| a.addr.clone())
                     .collect();
-                let protocol_version = String::from(crate::behaviour::PROTOCOL_VERSION);
-                let agent_version = String::from(crate::behaviour::AGENT_VERSION);
+                let protocol_version = self.protocol_version.clone();
+                let agent_version = self.agent_version.clone();
                 let protocols = self.swarm.behaviour().peer_manager.supported_protocols();
 
                 response_channel
@@ -1067,6 +1641,112 @@ impl<KeyStorage: Storage> Node<KeyStorage> {
     }
 }
 
+/// Builds the `/p2p-circuit` address, under which `local_peer_id` is reachable via the relay.
+fn relayed_addr(
+    mut relay_addr: Multiaddr,
+    relay_peer_id: PeerId,
+    local_peer_id: PeerId,
+) -> Multiaddr {
+    if !matches!(relay_addr.iter().last(), Some(Protocol::P2p(_))) {
+        relay_addr.push(Protocol::P2p(relay_peer_id.into()));
+    }
+    relay_addr
+        .with(Protocol::P2pCircuit)
+        .with(Protocol::P2p(local_peer_id.into()))
+}
+
+fn bitswap_peer_state(state: ConnState) -> BitswapPeerState {
+    match state {
+        ConnState::Connected => BitswapPeerState::Connected,
+        ConnState::Responsive(_) => BitswapPeerState::Responsive,
+        ConnState::Unresponsive => BitswapPeerState::Unresponsive,
+        ConnState::Disconnected => BitswapPeerState::Disconnected,
+        ConnState::ConnectionLimit => BitswapPeerState::ConnectionLimit,
+        ConnState::DialFailure => BitswapPeerState::DialFailure,
+    }
+}
+
+/// A listener of [`NetworkEvent`]s.
+#[derive(Debug)]
+enum NetworkEventListener {
+    /// Receives every event, see [`NetworkEventListener::lossless`].
+    Lossless(UnboundedSender<NetworkEvent>),
+    /// Misses events while its channel is full, so it can not make events pile up in the
+    /// node. Used for rpc clients streaming gossipsub messages.
+    Lossy(Sender<NetworkEvent>),
+}
+
+impl NetworkEventListener {
+    /// Forwards every event to `sender`, in order. Events are queued until the listener
+    /// catches up, so a slow listener does not block the node.
+    fn lossless(sender: Sender<NetworkEvent>) -> Self {
+        let (s, mut r) = unbounded_channel();
+        tokio::task::spawn(async move {
+            while let Some(ev) = r.recv().await {
+                if sender.send(ev).await.is_err() {
+                    // the listener went away
+                    break;
+                }
+            }
+        });
+        NetworkEventListener::Lossless(s)
+    }
+}
+
+/// Sends `ev` to all `listeners`, in the order events are emitted.
+///
+/// Listeners that went away, e.g. rpc clients no longer streaming messages, are removed.
+fn send_network_event(listeners: &mut Vec<NetworkEventListener>, ev: NetworkEvent) {
+    listeners.retain(|listener| match listener {
+        NetworkEventListener::Lossless(sender) => sender.send(ev.clone()).is_ok(),
+        NetworkEventListener::Lossy(sender) => match sender.try_send(ev.clone()) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                warn!("network event listener is not keeping up, dropping event");
+                true
+            }
+            Err(TrySendError::Closed(_)) => false,
+        },
+    });
+}
+
+/// Retains `bytes` as the last message published on `topic`.
+///
+/// Once messages for [`MAX_LAST_PUBLISHED`] topics are retained, the least recently used
+/// one is forgotten, along with the peers that fetched it.
+fn retain_published(
+    last_published: &mut LruCache<TopicHash, Bytes>,
+    fetched_by: &mut AHashMap<TopicHash, AHashSet<PeerId>>,
+    topic: TopicHash,
+    bytes: Bytes,
+) {
+    if let Some((evicted, _)) = last_published.push(topic.clone(), bytes) {
+        if evicted != topic {
+            fetched_by.remove(&evicted);
+        }
+    }
+}
+
+/// Stores `blocks` that are not in the store yet, with their links.
+async fn store_blocks(client: &RpcClient, blocks: Vec<Block>) -> Result<()> {
+    let store = client.try_store()?;
+    let cids: Vec<Cid> = blocks.iter().map(|block| *block.cid()).collect();
+    let has = store.has_many(cids).await?;
+    let blocks = blocks
+        .into_iter()
+        .zip(has)
+        .filter(|(_, has)| !has)
+        .map(|(block, _)| {
+            let links = parse_links(block.cid(), block.data()).unwrap_or_default();
+            (*block.cid(), block.data().clone(), links)
+        })
+        .collect::<Vec<_>>();
+    if !blocks.is_empty() {
+        store.put_many(blocks).await?;
+    }
+    Ok(())
+}
+
 async fn load_identity<S: Storage>(kc: &mut Keychain<S>) -> Result<Keypair> {
     if kc.is_empty().await? {
         info!("no identity found, creating",);
@@ -1094,7 +1774,7 @@ mod tests {
     use rand_chacha::ChaCha8Rng;
     use ssh_key::private::Ed25519Keypair;
 
-    use libp2p::{identity::Keypair as Libp2pKeypair, kad::record::Key};
+    use libp2p::identity::Keypair as Libp2pKeypair;
 
     use super::*;
     use anyhow::Result;
@@ -1231,18 +1911,15 @@ mod tests {
             };
 
             if let Some(keys) = self.keys {
-                if let Some(kad) = p2p.swarm.behaviour_mut().kad.as_mut() {
-                    for k in keys {
-                        kad.start_providing(k)?;
-                    }
-                } else {
-                    anyhow::bail!("expected kad behaviour to exist");
+                for k in keys {
+                    p2p.start_providing(k)?;
                 }
             }
 
             let client = RpcClient::new(cfg).await?;
 
             let network_events = p2p.network_events();
+            let allow_list = p2p.peer_allow_list();
             let task = tokio::task::spawn(async move { p2p.run().await.unwrap() });
 
             let client = client.try_p2p()?;
@@ -1258,6 +1935,7 @@ mod tests {
                 client,
                 peer_id,
                 network_events,
+                allow_list,
                 addr,
                 dial_addr,
             })
@@ -1283,6 +1961,8 @@ mod tests {
         peer_id: PeerId,
         /// A channel to read the network events received by the node.
         network_events: Receiver<NetworkEvent>,
+        /// The peers the node may be connected to.
+        allow_list: PeerAllowList,
         /// The listening address for this node.
         addr: Multiaddr,
         /// A multiaddr that is a combination of the listening addr and peer_id.
@@ -1340,6 +2020,111 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_relayed_addr() {
+        let relay = PeerId::random();
+        let local = PeerId::random();
+        let expected: Multiaddr =
+            format!("/ip4/1.2.3.4/tcp/4001/p2p/{relay}/p2p-circuit/p2p/{local}")
+                .parse()
+                .unwrap();
+
+        let addr: Multiaddr = "/ip4/1.2.3.4/tcp/4001".parse().unwrap();
+        assert_eq!(relayed_addr(addr, relay, local), expected);
+
+        let addr: Multiaddr = format!("/ip4/1.2.3.4/tcp/4001/p2p/{relay}")
+            .parse()
+            .unwrap();
+        assert_eq!(relayed_addr(addr, relay, local), expected);
+    }
+
+    #[tokio::test]
+    async fn test_stop_providing() -> Result<()> {
+        let rpc_addr = Addr::new_mem();
+        let mut config = Config::default_with_rpc(rpc_addr.clone());
+        config.libp2p.listening_multiaddrs = vec!["/ip4/127.0.0.1/tcp/0".parse()?];
+        config.libp2p.bootstrap_peers = vec![];
+        let mut node = Node::new(config, rpc_addr, Keychain::<MemoryStorage>::new()).await?;
+        let provided = |node: &mut Node<MemoryStorage>| {
+            let kad = node.swarm.behaviour_mut().kad.as_mut().unwrap();
+            kad.store_mut().provided().count()
+        };
+
+        let key: Key = Cid::default().hash().to_bytes().into();
+        node.start_providing(key.clone())?;
+        assert_eq!(provided(&mut node), 1);
+
+        // kademlia only republishes the records left in its store
+        node.stop_providing(&key)?;
+        assert_eq!(provided(&mut node), 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_routing_state() -> Result<()> {
+        let rpc_addr = Addr::new_mem();
+        let mut config = Config::default_with_rpc(rpc_addr.clone());
+        config.libp2p.listening_multiaddrs = vec!["/ip4/127.0.0.1/tcp/0".parse()?];
+        config.libp2p.bootstrap_peers = vec![];
+        let mut node = Node::new(config, rpc_addr, Keychain::<MemoryStorage>::new()).await?;
+        let local = *node.local_peer_id();
+
+        let peer = PeerId::random();
+        let addr: Multiaddr = "/ip4/1.2.3.4/tcp/4001".parse()?;
+        let key: Key = Cid::default().hash().to_bytes().into();
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let record = |provider, expires| ProviderRecord {
+            key: key.to_vec().into(),
+            provider,
+            addrs: vec![],
+            expires,
+        };
+        let state = RoutingState {
+            peers: vec![
+                RoutingPeer {
+                    peer_id: peer,
+                    addrs: vec![addr.clone()],
+                },
+                RoutingPeer {
+                    peer_id: PeerId::random(),
+                    addrs: vec![],
+                },
+            ],
+            providers: vec![
+                record(local, Some(now + 3600)),
+                record(local, Some(now - 1)),
+                record(PeerId::random(), None),
+            ],
+        };
+
+        // peers without addresses, expired records and records of others are discarded
+        let res = node.import_routing_state(state)?;
+        assert_eq!(
+            res,
+            ImportRoutingStateResponse {
+                peers: 1,
+                providers: 1,
+                discarded: 3,
+            }
+        );
+        let kad = node.swarm.behaviour_mut().kad.as_mut().unwrap();
+        assert_eq!(kad.store_mut().provided().count(), 1);
+
+        let exported = node.export_routing_state()?;
+        assert_eq!(
+            exported.peers,
+            vec![RoutingPeer {
+                peer_id: peer,
+                addrs: vec![addr],
+            }]
+        );
+        assert_eq!(exported.providers.len(), 1);
+        assert_eq!(exported.providers[0].provider, local);
+        let expires = exported.providers[0].expires.unwrap();
+        assert!(expires.abs_diff(now + 3600) <= 1);
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_local_peer_id() -> Result<()> {
         let test_runner = TestRunnerBuilder::new().no_bootstrap().build().await?;
@@ -1381,6 +2166,10 @@ mod tests {
         assert!(peers.len() == 1);
         let got_peer = peers.get(&peer_id_b).unwrap();
         assert!(got_peer.contains(&test_runner_b.dial_addr));
+        let peers_info = test_runner_a.client.peers_info().await?;
+        assert_eq!(peers_info.len(), 1);
+        assert_eq!(peers_info[0].peer_id, peer_id_b);
+        assert!(peers_info[0].addrs.contains(&test_runner_b.dial_addr));
 
         // lookup
         let lookup_b = test_runner_a.client.lookup(peer_id_b, None).await?;
@@ -1399,6 +2188,143 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_send_network_event() {
+        let (s, mut r) = channel(2);
+        let (closed, _) = channel(2);
+        let (lossless, mut lossless_r) = channel(2);
+        let mut listeners = vec![
+            NetworkEventListener::Lossy(s),
+            NetworkEventListener::Lossy(closed),
+            NetworkEventListener::lossless(lossless),
+        ];
+
+        let peer_ids = [PeerId::random(), PeerId::random(), PeerId::random()];
+        for peer_id in peer_ids {
+            send_network_event(&mut listeners, NetworkEvent::CancelLookupQuery(peer_id));
+        }
+        // the closed listener is gone, the full lossy one misses the last event
+        assert_eq!(listeners.len(), 2);
+        let mut received = Vec::new();
+        while let Ok(ev) = r.try_recv() {
+            received.push(ev);
+        }
+        assert_eq!(received.len(), 2);
+
+        // the lossless one receives all events, in order
+        for peer_id in peer_ids {
+            match lossless_r.recv().await {
+                Some(NetworkEvent::CancelLookupQuery(got)) => assert_eq!(got, peer_id),
+                ev => panic!("unexpected event {ev:?}"),
+            }
+        }
+
+        // events are received in order, once the listener caught up
+        let peer_id = PeerId::random();
+        send_network_event(&mut listeners, NetworkEvent::CancelLookupQuery(peer_id));
+        match r.recv().await {
+            Some(NetworkEvent::CancelLookupQuery(got)) => assert_eq!(got, peer_id),
+            ev => panic!("unexpected event {ev:?}"),
+        }
+    }
+
+    #[test]
+    fn test_retain_published() {
+        let mut last_published = LruCache::new(NonZeroUsize::new(2).unwrap());
+        let mut fetched_by = AHashMap::<TopicHash, AHashSet<PeerId>>::new();
+        let topics: Vec<_> = (0..3)
+            .map(|i| TopicHash::from_raw(format!("topic-{i}")))
+            .collect();
+
+        for topic in &topics[..2] {
+            retain_published(
+                &mut last_published,
+                &mut fetched_by,
+                topic.clone(),
+                Bytes::from_static(b"first"),
+            );
+            fetched_by
+                .entry(topic.clone())
+                .or_default()
+                .insert(PeerId::random());
+        }
+        // replacing a message keeps the topic
+        retain_published(
+            &mut last_published,
+            &mut fetched_by,
+            topics[0].clone(),
+            Bytes::from_static(b"second"),
+        );
+        assert_eq!(last_published.len(), 2);
+        assert!(fetched_by.contains_key(&topics[0]));
+
+        // the least recently used topic is forgotten, with the peers that fetched it
+        retain_published(
+            &mut last_published,
+            &mut fetched_by,
+            topics[2].clone(),
+            Bytes::from_static(b"first"),
+        );
+        assert_eq!(last_published.len(), 2);
+        assert!(!last_published.contains(&topics[1]));
+        assert!(!fetched_by.contains_key(&topics[1]));
+        assert_eq!(
+            last_published.peek(&topics[0]),
+            Some(&Bytes::from_static(b"second"))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_peer_allow_list() -> Result<()> {
+        let test_runner_a = TestRunnerBuilder::new().no_bootstrap().build().await?;
+        let test_runner_b = TestRunnerBuilder::new()
+            .no_bootstrap()
+            .with_seed(ChaCha8Rng::from_seed([0; 32]))
+            .build()
+            .await?;
+        let peer_id_a = test_runner_a.peer_id;
+        let peer_id_b = test_runner_b.peer_id;
+        let wait_disconnected = |client: P2pClient| async move {
+            tokio::time::timeout(Duration::from_secs(5), async {
+                while !client.get_peers().await?.is_empty() {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+                anyhow::Ok(())
+            })
+            .await
+            .context("peers are still connected")?
+        };
+
+        // a does not dial peers that are not on its list
+        test_runner_a.allow_list.allow_only([PeerId::random()]);
+        let addrs_b = vec![test_runner_b.addr.clone()];
+        let err = test_runner_a
+            .client
+            .connect(peer_id_b, addrs_b.clone())
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("not on the allow list"));
+
+        // and denies their inbound connections
+        let addrs_a = vec![test_runner_a.addr.clone()];
+        test_runner_b.client.connect(peer_id_a, addrs_a).await.ok();
+        wait_disconnected(test_runner_b.client.clone()).await?;
+        assert!(test_runner_a.client.get_peers().await?.is_empty());
+
+        // allowed peers connect, until they are removed from the list
+        test_runner_a.allow_list.allow_only([peer_id_b]);
+        test_runner_a.client.connect(peer_id_b, addrs_b).await?;
+        assert!(test_runner_a
+            .client
+            .get_peers()
+            .await?
+            .contains_key(&peer_id_b));
+        test_runner_a.allow_list.allow_only([]);
+        wait_disconnected(test_runner_a.client.clone()).await?;
+
+        Ok(())
+    }
+
     // assert_lookup ensures each part of the lookup is equal
     fn assert_lookup(
         got: Lookup,
@@ -1456,6 +2382,61 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_identify_push() -> Result<()> {
+        /// Drives `node` until one of its behaviour events matches `done`.
+        async fn run_until(
+            node: &mut Node<MemoryStorage>,
+            done: impl Fn(&Event) -> bool,
+        ) -> Result<()> {
+            tokio::time::timeout(Duration::from_secs(5), async {
+                loop {
+                    let event = node.swarm.next().await.expect("the swarm will never die");
+                    let matched = matches!(&event, SwarmEvent::Behaviour(event) if done(event));
+                    node.handle_swarm_event(event)?;
+                    if matched {
+                        return Ok::<_, anyhow::Error>(());
+                    }
+                }
+            })
+            .await?
+        }
+
+        let test_runner_b = TestRunnerBuilder::new().no_bootstrap().build().await?;
+        let peer_id_b = test_runner_b.peer_id;
+        let rpc_addr = Addr::new_mem();
+        let mut config = Config::default_with_rpc(rpc_addr.clone());
+        config.libp2p.listening_multiaddrs = vec!["/ip4/127.0.0.1/tcp/0".parse()?];
+        config.libp2p.bootstrap_peers = vec![];
+        let mut node = Node::new(config, rpc_addr, Keychain::<MemoryStorage>::new()).await?;
+        assert!(node.identify_push);
+
+        node.swarm.dial(test_runner_b.dial_addr.clone())?;
+        run_until(&mut node, |event| match event {
+            Event::Identify(event) => {
+                matches!(**event, IdentifyEvent::Received { peer_id, .. } if peer_id == peer_id_b)
+            }
+            _ => false,
+        })
+        .await?;
+
+        // a new external address is pushed to the connected peer
+        let external: Multiaddr = "/ip4/1.2.3.4/tcp/4001".parse()?;
+        node.swarm
+            .add_external_address(external.clone(), libp2p::swarm::AddressScore::Infinite);
+        node.push_address_changes();
+        assert!(node.advertised_addrs.contains(&external));
+        run_until(&mut node, |event| match event {
+            Event::Identify(event) => {
+                matches!(**event, IdentifyEvent::Pushed { peer_id } if peer_id == peer_id_b)
+            }
+            _ => false,
+        })
+        .await?;
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_gossipsub() -> Result<()> {
         let mut test_runner_a = TestRunnerBuilder::new().no_bootstrap().build().await?;
@@ -1473,8 +2454,13 @@ mod tests {
             .await?;
 
         match test_runner_a.network_events.recv().await {
-            Some(NetworkEvent::PeerConnected(peer_id)) => {
+            Some(NetworkEvent::PeerConnected {
+                peer_id,
+                connection,
+            }) => {
                 assert_eq!(test_runner_b.peer_id, peer_id);
+                assert_eq!(connection.direction, ConnectionDirection::Outbound);
+                assert!(!connection.relayed);
             }
             Some(n) => {
                 anyhow::bail!("unexpected network event: {:?}", n);
@@ -1578,6 +2564,75 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_gossip_retain() -> Result<()> {
+        let test_runner_a = TestRunnerBuilder::new().no_bootstrap().build().await?;
+        let test_runner_b = TestRunnerBuilder::new().no_bootstrap().build().await?;
+        test_runner_b
+            .client
+            .connect(test_runner_a.peer_id, vec![test_runner_a.addr.clone()])
+            .await?;
+
+        let topic = TopicHash::from_raw("test_topic");
+        let msg = Bytes::from(&b"hello world!"[..]);
+        test_runner_a
+            .client
+            .gossipsub_retain(topic.clone(), msg.clone())
+            .await?;
+        assert!(test_runner_a
+            .client
+            .gossipsub_fetched_by(topic.clone())
+            .await?
+            .is_empty());
+
+        let fetched = test_runner_b
+            .client
+            .gossipsub_fetch_last(test_runner_a.peer_id, topic.clone())
+            .await?;
+        assert_eq!(fetched, Some(msg));
+        let fetched_by = test_runner_a
+            .client
+            .gossipsub_fetched_by(topic.clone())
+            .await?;
+        assert_eq!(fetched_by, vec![test_runner_b.peer_id]);
+
+        // a message published on the topic replaces it, and has not been fetched yet.
+        // Nobody subscribed, so publishing fails, but the message is retained anyway.
+        let republished = Bytes::from(&b"hello again!"[..]);
+        test_runner_a
+            .client
+            .gossipsub_publish(topic.clone(), republished.clone())
+            .await
+            .ok();
+        assert!(test_runner_a
+            .client
+            .gossipsub_fetched_by(topic.clone())
+            .await?
+            .is_empty());
+        let fetched = test_runner_b
+            .client
+            .gossipsub_fetch_last(test_runner_a.peer_id, topic.clone())
+            .await?;
+        assert_eq!(fetched, Some(republished));
+
+        // once released, the message is gone for good
+        test_runner_a
+            .client
+            .gossipsub_release(topic.clone())
+            .await?;
+        let fetched = test_runner_b
+            .client
+            .gossipsub_fetch_last(test_runner_a.peer_id, topic.clone())
+            .await?;
+        assert_eq!(fetched, None);
+        assert!(test_runner_a
+            .client
+            .gossipsub_fetched_by(topic)
+            .await?
+            .is_empty());
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_dht() -> Result<()> {
         // set up three nodes
@@ -1616,8 +2671,12 @@ mod tests {
 
         // expect a network event showing a & b have connected
         match test_runner_b.network_events.recv().await {
-            Some(NetworkEvent::PeerConnected(peer_id)) => {
+            Some(NetworkEvent::PeerConnected {
+                peer_id,
+                connection,
+            }) => {
                 assert_eq!(test_runner_a.peer_id, peer_id);
+                assert_eq!(connection.direction, ConnectionDirection::Inbound);
             }
             Some(n) => {
                 anyhow::bail!("unexpected network event: {:?}", n);
@@ -1634,8 +2693,12 @@ mod tests {
 
         // expect a network event showing b & c have connected
         match test_runner_b.network_events.recv().await {
-            Some(NetworkEvent::PeerConnected(peer_id)) => {
+            Some(NetworkEvent::PeerConnected {
+                peer_id,
+                connection,
+            }) => {
                 assert_eq!(test_runner_c.peer_id, peer_id);
+                assert_eq!(connection.direction, ConnectionDirection::Inbound);
             }
             Some(n) => {
                 anyhow::bail!("unexpected network event: {:?}", n);
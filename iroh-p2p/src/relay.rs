@@ -0,0 +1,194 @@
+//! Keeps relay reservations alive, by listening on relayed addresses again once they are lost.
+use std::time::{Duration, Instant};
+
+use ahash::AHashMap;
+use iroh_rpc_types::p2p::RelayState;
+use libp2p::core::transport::ListenerId;
+use libp2p::multiaddr::Protocol;
+use libp2p::{Multiaddr, PeerId};
+
+/// When to retry lost relay reservations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReconnectPolicy {
+    /// Delay before the first retry.
+    pub interval: Duration,
+    /// Upper bound for the delay between retries, which doubles with each failure.
+    pub max_backoff: Duration,
+}
+
+impl ReconnectPolicy {
+    /// The delay before retrying after `failures` consecutive failures.
+    pub fn backoff(&self, failures: u32) -> Duration {
+        let factor = 2u32.saturating_pow(failures.saturating_sub(1));
+        self.interval.saturating_mul(factor).min(self.max_backoff)
+    }
+}
+
+#[derive(Debug)]
+struct Relay {
+    addr: Multiaddr,
+    listener: Option<ListenerId>,
+    reserved: bool,
+    failures: u32,
+    retry_at: Option<Instant>,
+}
+
+impl Relay {
+    fn fail(&mut self, policy: &ReconnectPolicy, now: Instant) {
+        self.reserved = false;
+        // A single loss can be reported multiple times, e.g. by both the failed
+        // reservation and the closed listener, only schedule one retry for it.
+        if self.retry_at.is_none() {
+            self.failures = self.failures.saturating_add(1);
+            self.retry_at = Some(now + policy.backoff(self.failures));
+        }
+    }
+}
+
+/// The relays we want to hold reservations with, and the state of each reservation.
+#[derive(Debug)]
+pub(crate) struct Relays {
+    policy: ReconnectPolicy,
+    relays: AHashMap<PeerId, Relay>,
+}
+
+impl Relays {
+    pub fn new(policy: ReconnectPolicy) -> Self {
+        Relays {
+            policy,
+            relays: Default::default(),
+        }
+    }
+
+    /// Tracks the relayed `addr`, listened on with `listener`.
+    ///
+    /// Returns `false`, if `addr` is not a relayed address.
+    pub fn insert(&mut self, addr: Multiaddr, listener: ListenerId) -> bool {
+        match relay_peer_id(&addr) {
+            Some(peer) => {
+                self.relays.insert(
+                    peer,
+                    Relay {
+                        addr,
+                        listener: Some(listener),
+                        reserved: false,
+                        failures: 0,
+                        retry_at: None,
+                    },
+                );
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Records the reservation with `peer` as active.
+    pub fn accepted(&mut self, peer: &PeerId) {
+        if let Some(relay) = self.relays.get_mut(peer) {
+            relay.reserved = true;
+            relay.failures = 0;
+            relay.retry_at = None;
+        }
+    }
+
+    /// Records the reservation with `peer` as failed or lost, scheduling a retry.
+    pub fn failed(&mut self, peer: &PeerId, now: Instant) {
+        if let Some(relay) = self.relays.get_mut(peer) {
+            relay.fail(&self.policy, now);
+        }
+    }
+
+    /// Records the loss of the reservation listened on with `listener`, if any.
+    pub fn listener_closed(&mut self, listener: ListenerId, now: Instant) {
+        let policy = self.policy;
+        if let Some(relay) = self
+            .relays
+            .values_mut()
+            .find(|relay| relay.listener == Some(listener))
+        {
+            relay.listener = None;
+            relay.fail(&policy, now);
+        }
+    }
+
+    /// Returns the relays to retry now, with their address and the listener to replace.
+    pub fn due(&mut self, now: Instant) -> Vec<(PeerId, Multiaddr, Option<ListenerId>)> {
+        self.relays
+            .iter_mut()
+            .filter(|(_, relay)| matches!(relay.retry_at, Some(at) if at <= now))
+            .map(|(peer, relay)| {
+                relay.retry_at = None;
+                (*peer, relay.addr.clone(), relay.listener.take())
+            })
+            .collect()
+    }
+
+    /// Records that the relay `peer` is listened on again with `listener`.
+    pub fn listening(&mut self, peer: &PeerId, listener: ListenerId) {
+        if let Some(relay) = self.relays.get_mut(peer) {
+            relay.listener = Some(listener);
+        }
+    }
+
+    pub fn states(&self, now: Instant) -> Vec<(PeerId, RelayState)> {
+        self.relays
+            .iter()
+            .map(|(peer, relay)| {
+                let state = match relay.retry_at {
+                    _ if relay.reserved => RelayState::Reserved,
+                    Some(at) => RelayState::Backoff {
+                        failures: relay.failures,
+                        retry_in: at.saturating_duration_since(now),
+                    },
+                    None => RelayState::Pending,
+                };
+                (*peer, state)
+            })
+            .collect()
+    }
+}
+
+/// Returns the relay of a relayed address, the peer directly before `p2p-circuit`.
+pub(crate) fn relay_peer_id(addr: &Multiaddr) -> Option<PeerId> {
+    let mut relay = None;
+    for protocol in addr.iter() {
+        match protocol {
+            Protocol::P2p(hash) => relay = PeerId::from_multihash(hash).ok(),
+            Protocol::P2pCircuit => return relay,
+            _ => {}
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        let policy = ReconnectPolicy {
+            interval: Duration::from_secs(5),
+            max_backoff: Duration::from_secs(60),
+        };
+        assert_eq!(policy.backoff(1), Duration::from_secs(5));
+        assert_eq!(policy.backoff(2), Duration::from_secs(10));
+        assert_eq!(policy.backoff(4), Duration::from_secs(40));
+        assert_eq!(policy.backoff(5), Duration::from_secs(60));
+        assert_eq!(policy.backoff(u32::MAX), Duration::from_secs(60));
+    }
+
+    #[test]
+    fn test_relay_peer_id() {
+        let relay = PeerId::random();
+        let addr: Multiaddr = format!("/ip4/1.2.3.4/tcp/4001/p2p/{relay}/p2p-circuit")
+            .parse()
+            .unwrap();
+        assert_eq!(relay_peer_id(&addr), Some(relay));
+
+        let addr: Multiaddr = format!("/ip4/1.2.3.4/tcp/4001/p2p/{relay}")
+            .parse()
+            .unwrap();
+        assert_eq!(relay_peer_id(&addr), None);
+    }
+}
@@ -27,7 +27,7 @@ use tokio::sync::mpsc::{channel, Sender};
 use tokio::sync::oneshot;
 use tracing::{debug, info, trace};
 
-use super::node::DEFAULT_PROVIDER_LIMIT;
+use super::node::{GossipsubEvent, NetworkEvent, DEFAULT_PROVIDER_LIMIT};
 use crate::VERSION;
 
 #[derive(Clone)]
@@ -91,6 +91,56 @@ impl P2p {
         Ok(ListenersResponse { addrs })
     }
 
+    #[tracing::instrument(skip(self))]
+    async fn relays(self, _: RelaysRequest) -> Result<RelaysResponse> {
+        trace!("received Relays request");
+
+        let (s, r) = oneshot::channel();
+        let msg = RpcMessage::Relays(s);
+
+        self.sender.send(msg).await?;
+
+        let relays = r.await?;
+
+        Ok(RelaysResponse { relays })
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn export_routing_state(
+        self,
+        _: ExportRoutingStateRequest,
+    ) -> Result<ExportRoutingStateResponse> {
+        trace!("received ExportRoutingState request");
+
+        let (s, r) = oneshot::channel();
+        let msg = RpcMessage::ExportRoutingState(s);
+
+        self.sender.send(msg).await?;
+
+        let state = r.await??;
+
+        Ok(ExportRoutingStateResponse { state })
+    }
+
+    #[tracing::instrument(skip(self, req))]
+    async fn import_routing_state(
+        self,
+        req: ImportRoutingStateRequest,
+    ) -> Result<ImportRoutingStateResponse> {
+        trace!(
+            "received ImportRoutingState request: {} peers, {} providers",
+            req.state.peers.len(),
+            req.state.providers.len()
+        );
+
+        let (s, r) = oneshot::channel();
+        let msg = RpcMessage::ImportRoutingState(s, req.state);
+
+        self.sender.send(msg).await?;
+
+        r.await?
+    }
+
     #[tracing::instrument(skip(self))]
     async fn local_peer_id(self, _: LocalPeerIdRequest) -> Result<LocalPeerIdResponse> {
         trace!("received LocalPeerId request");
@@ -111,6 +161,8 @@ impl P2p {
     async fn fetch_bitswap(self, req: BitswapRequest) -> Result<BitswapResponse> {
         let ctx = req.ctx;
         let cid = req.cid;
+        let priority = req.priority;
+        let max_providers = req.max_providers.map(|n| n as usize);
 
         trace!("context:{}, received fetch_bitswap: {:?}", ctx, cid);
         let providers = req.providers.into_iter().collect();
@@ -120,12 +172,14 @@ impl P2p {
             ctx,
             cids: vec![cid],
             providers,
+            priority,
+            max_providers,
             response_channels: vec![s],
         };
 
         trace!("context:{} making bitswap request for {:?}", ctx, cid);
         self.sender.send(msg).await?;
-        let block = r
+        let (block, from) = r
             .await
             .map_err(|_| anyhow!("bitswap req shut down"))?
             .map_err(|e| anyhow!("bitswap: {}", e))?;
@@ -142,6 +196,7 @@ impl P2p {
         Ok(BitswapResponse {
             data: block.data,
             ctx,
+            from,
         })
     }
 
@@ -286,6 +341,16 @@ impl P2p {
         Ok(GetPeersResponse { peers })
     }
 
+    #[tracing::instrument(skip(self))]
+    async fn peers_info(self, _: PeersInfoRequest) -> Result<PeersInfoResponse> {
+        let (s, r) = oneshot::channel();
+        let msg = RpcMessage::NetPeersInfo(s);
+        self.sender.send(msg).await?;
+
+        let peers = r.await?;
+        Ok(PeersInfoResponse { peers })
+    }
+
     #[tracing::instrument(skip(self, req))]
     /// First attempts to find the peer on the DHT, if found, it will then ensure we have
     /// a connection to the peer.
@@ -453,6 +518,59 @@ impl P2p {
         Ok(GossipsubPeersResponse { peers })
     }
 
+    /// Wrap the inner method gossipsub_messages0 to get the signature expected
+    /// by a server_streaming request.
+    #[tracing::instrument(skip(self, req))]
+    fn gossipsub_messages(
+        self,
+        req: GossipsubMessagesRequest,
+    ) -> BoxStream<'static, RpcResult<GossipsubMessagesResponse>> {
+        async move {
+            let stream = self.gossipsub_messages0(req).await?;
+            Ok(stream.map(Ok))
+        }
+        .try_flatten_stream()
+        .boxed()
+    }
+
+    /// Implementation of gossipsub_messages
+    async fn gossipsub_messages0(
+        self,
+        req: GossipsubMessagesRequest,
+    ) -> anyhow::Result<BoxStream<'static, GossipsubMessagesResponse>> {
+        trace!("received GossipsubMessages request: {:?}", req.topic_hash);
+        // the node drops messages while the channel is full
+        let (s, r) = channel(512);
+        let msg = RpcMessage::Gossipsub(GossipsubMessage::Messages(s));
+        self.sender.send(msg).await?;
+
+        let topic_hash = req.topic_hash;
+        let stream = tokio_stream::wrappers::ReceiverStream::new(r)
+            .filter_map(move |event| {
+                let res = match event {
+                    NetworkEvent::Gossipsub(GossipsubEvent::Message { from, id, message })
+                        if topic_hash
+                            .as_ref()
+                            .map_or(true, |topic| topic == message.topic.as_str()) =>
+                    {
+                        Some(GossipsubMessagesResponse {
+                            from,
+                            source: message.source,
+                            message_id: id.0.into(),
+                            sequence_number: message.sequence_number,
+                            topic_hash: message.topic.into_string(),
+                            data: message.data.into(),
+                        })
+                    }
+                    _ => None,
+                };
+                futures::future::ready(res)
+            })
+            .boxed();
+
+        Ok(stream)
+    }
+
     #[tracing::instrument(skip(self, req))]
     async fn gossipsub_publish(
         self,
@@ -531,6 +649,56 @@ impl P2p {
 
         Ok(GossipsubUnsubscribeResponse { was_subscribed })
     }
+
+    #[tracing::instrument(skip(self, req))]
+    async fn gossipsub_fetch_last(
+        self,
+        req: GossipsubFetchLastRequest,
+    ) -> Result<GossipsubFetchLastResponse> {
+        let (s, r) = oneshot::channel();
+        let msg = RpcMessage::GossipFetchLast(s, req.peer_id, TopicHash::from_raw(req.topic_hash));
+
+        self.sender.send(msg).await?;
+        let data = r.await??;
+
+        Ok(GossipsubFetchLastResponse { data })
+    }
+
+    #[tracing::instrument(skip(self, req))]
+    async fn gossipsub_retain(self, req: GossipsubRetainRequest) -> Result<()> {
+        let (s, r) = oneshot::channel();
+        let msg = RpcMessage::GossipRetain(s, TopicHash::from_raw(req.topic_hash), req.data);
+
+        self.sender.send(msg).await?;
+        r.await?;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self, req))]
+    async fn gossipsub_release(self, req: GossipsubReleaseRequest) -> Result<()> {
+        let (s, r) = oneshot::channel();
+        let msg = RpcMessage::GossipRelease(s, TopicHash::from_raw(req.topic_hash));
+
+        self.sender.send(msg).await?;
+        r.await?;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self, req))]
+    async fn gossipsub_fetched_by(
+        self,
+        req: GossipsubFetchedByRequest,
+    ) -> Result<GossipsubFetchedByResponse> {
+        let (s, r) = oneshot::channel();
+        let msg = RpcMessage::GossipFetchedBy(s, TopicHash::from_raw(req.topic_hash));
+
+        self.sender.send(msg).await?;
+        let peers = r.await?;
+
+        Ok(GossipsubFetchedByResponse { peers })
+    }
 }
 
 /// dispatch a single request from the server 
@@ -551,6 +719,14 @@ async fn dispatch(s: P2pServer, req: P2pRequest, chan: ServerSocket<P2pService>,
         GossipsubSubscribe(req) => s.rpc_map_err(req, chan, target, P2p::gossipsub_subscribe).await,
         GossipsubTopics(req) => s.rpc_map_err(req, chan, target, P2p::gossipsub_topics).await,
         GossipsubUnsubscribe(req) => s.rpc_map_err(req, chan, target, P2p::gossipsub_unsubscribe).await,
+        GossipsubFetchLast(req) => {
+            s.rpc_map_err(req, chan, target, P2p::gossipsub_fetch_last).await
+        }
+        GossipsubRetain(req) => s.rpc_map_err(req, chan, target, P2p::gossipsub_retain).await,
+        GossipsubRelease(req) => s.rpc_map_err(req, chan, target, P2p::gossipsub_release).await,
+        GossipsubFetchedBy(req) => {
+            s.rpc_map_err(req, chan, target, P2p::gossipsub_fetched_by).await
+        }
         StopSessionBitswap(req) => s.rpc_map_err(req, chan, target, P2p::stop_session_bitswap).await,
         StartProviding(req) => s.rpc_map_err(req, chan, target, P2p::start_providing).await,
         StopProviding(req) => s.rpc_map_err(req, chan, target, P2p::stop_providing).await,
@@ -558,6 +734,7 @@ async fn dispatch(s: P2pServer, req: P2pRequest, chan: ServerSocket<P2pService>,
         NotifyNewBlocksBitswap(req) => s.rpc_map_err(req, chan, target, P2p::notify_new_blocks_bitswap).await,
         GetListeningAddrs(req) => s.rpc_map_err(req, chan, target, P2p::get_listening_addrs).await,
         GetPeers(req) => s.rpc_map_err(req, chan, target, P2p::get_peers).await,
+        PeersInfo(req) => s.rpc_map_err(req, chan, target, P2p::peers_info).await,
         PeerConnect(req) => s.rpc_map_err(req, chan, target, P2p::peer_connect).await,
         PeerDisconnect(req) => s.rpc_map_err(req, chan, target, P2p::peer_disconnect).await,
         PeerConnectByPeerId(req) => s.rpc_map_err(req, chan, target, P2p::peer_connect_by_peer_id).await,
@@ -565,7 +742,17 @@ async fn dispatch(s: P2pServer, req: P2pRequest, chan: ServerSocket<P2pService>,
         LookupLocal(req) => s.rpc_map_err(req, chan, target, P2p::lookup_local).await,
         ExternalAddrs(req) => s.rpc_map_err(req, chan, target, P2p::external_addrs).await,
         Listeners(req) => s.rpc_map_err(req, chan, target, P2p::listeners).await,
+        Relays(req) => s.rpc_map_err(req, chan, target, P2p::relays).await,
+        ExportRoutingState(req) => {
+            s.rpc_map_err(req, chan, target, P2p::export_routing_state).await
+        }
+        ImportRoutingState(req) => {
+            s.rpc_map_err(req, chan, target, P2p::import_routing_state).await
+        }
         FetchProviderDht(req) => s.server_streaming(req, chan, target, P2p::fetch_provider_dht).await,
+        GossipsubMessages(req) => {
+            s.server_streaming(req, chan, target, P2p::gossipsub_messages).await
+        }
     }
 }
 
@@ -619,12 +806,21 @@ pub enum ProviderRequestKey {
 pub enum RpcMessage {
     ExternalAddrs(oneshot::Sender<Vec<Multiaddr>>),
     Listeners(oneshot::Sender<Vec<Multiaddr>>),
+    Relays(oneshot::Sender<Vec<(PeerId, RelayState)>>),
+    ExportRoutingState(oneshot::Sender<Result<RoutingState>>),
+    ImportRoutingState(
+        oneshot::Sender<Result<ImportRoutingStateResponse>>,
+        RoutingState,
+    ),
     LocalPeerId(oneshot::Sender<PeerId>),
     BitswapRequest {
         ctx: u64,
         cids: Vec<Cid>,
-        response_channels: Vec<oneshot::Sender<Result<Block, String>>>,
+        /// Each block is sent along with the peer it was received from, if known.
+        response_channels: Vec<oneshot::Sender<Result<(Block, Option<PeerId>), String>>>,
         providers: HashSet<PeerId>,
+        priority: Option<i32>,
+        max_providers: Option<usize>,
     },
     BitswapNotifyNewBlocks {
         blocks: Vec<Block>,
@@ -643,10 +839,19 @@ pub enum RpcMessage {
     StopProviding(oneshot::Sender<Result<()>>, Key),
     NetListeningAddrs(oneshot::Sender<(PeerId, Vec<Multiaddr>)>),
     NetPeers(oneshot::Sender<HashMap<PeerId, Vec<Multiaddr>>>),
+    NetPeersInfo(oneshot::Sender<Vec<PeerInfo>>),
     NetConnectByPeerId(oneshot::Sender<Result<()>>, PeerId),
     NetConnect(oneshot::Sender<Result<()>>, PeerId, Vec<Multiaddr>),
     NetDisconnect(oneshot::Sender<()>, PeerId),
     Gossipsub(GossipsubMessage),
+    /// Requests the last message a peer published or retained on a topic directly from it.
+    GossipFetchLast(oneshot::Sender<Result<Option<Bytes>>>, PeerId, TopicHash),
+    /// Retains a message for peers fetching it directly, without publishing it.
+    GossipRetain(oneshot::Sender<()>, TopicHash, Bytes),
+    /// Stops retaining the message on a topic.
+    GossipRelease(oneshot::Sender<()>, TopicHash),
+    /// Lists the peers that fetched the message retained on a topic.
+    GossipFetchedBy(oneshot::Sender<Vec<PeerId>>, TopicHash),
     FindPeerOnDHT(oneshot::Sender<Result<()>>, PeerId),
     LookupPeerInfo(oneshot::Sender<Option<IdentifyInfo>>, PeerId),
     ListenForIdentify(oneshot::Sender<Result<IdentifyInfo>>, PeerId),
@@ -662,6 +867,8 @@ pub enum GossipsubMessage {
     AllMeshPeers(oneshot::Sender<Vec<PeerId>>),
     AllPeers(oneshot::Sender<Vec<(PeerId, Vec<TopicHash>)>>),
     MeshPeers(oneshot::Sender<Vec<PeerId>>, TopicHash),
+    /// Forwards all network events, including received messages, to the sender.
+    Messages(Sender<NetworkEvent>),
     Publish(
         oneshot::Sender<Result<MessageId, PublishError>>,
         TopicHash,
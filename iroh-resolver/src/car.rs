@@ -0,0 +1,33 @@
+//! Writes resolved DAGs as CAR files.
+use anyhow::{anyhow, Result};
+use futures::{Stream, StreamExt};
+use iroh_car::{CarHeader, CarWriter};
+use tokio::io::AsyncWrite;
+
+use crate::resolver::OutRaw;
+
+/// Writes `blocks` as a CAR file, with the first block as its root, and returns the writer.
+///
+/// `blocks` is usually the output of
+/// [`Resolver::resolve_recursive_raw`](crate::resolver::Resolver::resolve_recursive_raw).
+pub async fn write_car<S, W>(blocks: S, writer: W) -> Result<W>
+where
+    S: Stream<Item = Result<OutRaw>>,
+    W: AsyncWrite + Send + Unpin,
+{
+    tokio::pin!(blocks);
+    let root = blocks
+        .next()
+        .await
+        .ok_or_else(|| anyhow!("root cid not found"))??;
+
+    let header = CarHeader::new_v1(vec![*root.cid()]);
+    let mut writer = CarWriter::new(header, writer);
+    writer.write(*root.cid(), root.content()).await?;
+
+    while let Some(block) = blocks.next().await {
+        let block = block?;
+        writer.write(*block.cid(), block.content()).await?;
+    }
+    Ok(writer.finish().await?)
+}
@@ -0,0 +1,160 @@
+//! Caches the name to CID index of directories, so repeated lookups of names in the same
+//! directory do not need to walk it again.
+//!
+//! Indexing is opt-in per directory. The cache is bounded by the total number of entries of
+//! all indexes, once that is exceeded the least recently used indexes are evicted as a whole.
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+use cid::Cid;
+use lru::LruCache;
+use tracing::debug;
+
+/// Index of a directory, mapping the names of its entries to their CIDs.
+pub type DirIndex = HashMap<String, Cid>;
+
+#[derive(Debug)]
+pub struct DirIndexCache {
+    max_entries: usize,
+    inner: Mutex<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    /// Directories for which an index is built on first access.
+    enabled: HashSet<Cid>,
+    indexes: LruCache<Cid, Arc<DirIndex>>,
+    /// Total number of entries of all cached indexes.
+    entries: usize,
+}
+
+impl DirIndexCache {
+    /// Creates a cache holding indexes of up to `max_entries` entries in total.
+    pub fn new(max_entries: usize) -> Self {
+        DirIndexCache {
+            max_entries,
+            inner: Mutex::new(Inner {
+                enabled: HashSet::new(),
+                indexes: LruCache::unbounded(),
+                entries: 0,
+            }),
+        }
+    }
+
+    pub fn max_entries(&self) -> usize {
+        self.max_entries
+    }
+
+    /// Total number of entries of all cached indexes.
+    pub fn entries(&self) -> usize {
+        self.inner.lock().unwrap().entries
+    }
+
+    /// Enables indexing of the directory `dir`, its index is built the next time a name
+    /// in it is resolved.
+    pub fn enable(&self, dir: Cid) {
+        self.inner.lock().unwrap().enabled.insert(dir);
+    }
+
+    /// Disables indexing of `dir` and drops its index.
+    pub fn disable(&self, dir: &Cid) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.enabled.remove(dir);
+        if let Some(index) = inner.indexes.pop(dir) {
+            inner.entries -= index.len();
+        }
+    }
+
+    pub fn is_enabled(&self, dir: &Cid) -> bool {
+        self.inner.lock().unwrap().enabled.contains(dir)
+    }
+
+    /// Returns the cached index of `dir`, marking it as recently used.
+    pub fn get(&self, dir: &Cid) -> Option<Arc<DirIndex>> {
+        self.inner.lock().unwrap().indexes.get(dir).cloned()
+    }
+
+    /// Caches the `index` of `dir`, evicting the least recently used indexes until all fit.
+    ///
+    /// Indexes larger than the whole cache are not cached, and indexing of their directory
+    /// is disabled, so that it is not walked again on every lookup.
+    pub fn insert(&self, dir: Cid, index: Arc<DirIndex>) {
+        let mut inner = self.inner.lock().unwrap();
+        if !inner.enabled.contains(&dir) {
+            // disabled while the index was built
+            return;
+        }
+        if index.len() > self.max_entries {
+            debug!(
+                "index of {} has {} entries, more than the cache holds, disabling it",
+                dir,
+                index.len()
+            );
+            inner.enabled.remove(&dir);
+            return;
+        }
+        inner.entries += index.len();
+        if let Some(old) = inner.indexes.put(dir, index) {
+            inner.entries -= old.len();
+        }
+        while inner.entries > self.max_entries {
+            match inner.indexes.pop_lru() {
+                Some((evicted, index)) => {
+                    debug!("evicting index of {} with {} entries", evicted, index.len());
+                    inner.entries -= index.len();
+                }
+                None => break,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cid::multihash::{Code, MultihashDigest};
+
+    fn cid(i: u32) -> Cid {
+        Cid::new_v1(0x55, Code::Sha2_256.digest(&i.to_be_bytes()))
+    }
+
+    fn index(len: u32) -> Arc<DirIndex> {
+        Arc::new((0..len).map(|i| (i.to_string(), cid(i))).collect())
+    }
+
+    #[test]
+    fn test_dir_index_cache() {
+        let cache = DirIndexCache::new(10);
+        let (a, b, c, d) = (cid(100), cid(101), cid(102), cid(103));
+
+        // indexes of directories that are not enabled are not cached
+        cache.insert(a, index(4));
+        assert!(cache.get(&a).is_none());
+
+        for dir in [a, b, c, d] {
+            cache.enable(dir);
+        }
+        cache.insert(a, index(4));
+        cache.insert(b, index(4));
+        assert_eq!(cache.entries(), 8);
+        assert_eq!(cache.get(&a).unwrap().get("3"), Some(&cid(3)));
+
+        // b is the least recently used index and evicted as a whole
+        cache.insert(c, index(2));
+        assert_eq!(cache.entries(), 10);
+        cache.insert(d, index(1));
+        assert!(cache.get(&b).is_none());
+        assert_eq!(cache.entries(), 7);
+        assert!(cache.get(&a).is_some());
+        assert!(cache.get(&c).is_some());
+
+        // too large indexes disable indexing of their directory
+        cache.insert(b, index(11));
+        assert!(cache.get(&b).is_none());
+        assert!(!cache.is_enabled(&b));
+
+        cache.disable(&a);
+        assert!(cache.get(&a).is_none());
+        assert_eq!(cache.entries(), 3);
+    }
+}
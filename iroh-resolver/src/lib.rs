@@ -1,4 +1,7 @@
+pub mod car;
+pub mod dir_index;
 pub mod dns_resolver;
+pub mod provenance;
 pub mod resolver;
 
 pub use resolver::{Path, PathType};
@@ -0,0 +1,80 @@
+//! Records where the blocks traversed by a resolution came from, see
+//! [`Resolver::resolve_with_provenance`](crate::resolver::Resolver::resolve_with_provenance).
+use std::sync::{Arc, Mutex};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use cid::Cid;
+use iroh_unixfs::content_loader::{ContentLoader, ContextId, LoaderContext};
+use iroh_unixfs::{LoadedCid, Source};
+use libp2p::PeerId;
+
+/// Where a block traversed by a resolution came from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlockSource {
+    /// Loaded from the local store with the given name.
+    Store(&'static str),
+    /// Fetched via bitswap, from the given peer if it is known.
+    Bitswap(Option<PeerId>),
+    /// Fetched from the HTTP gateway at the given url.
+    Http(String),
+    /// The name was looked up in the cached index of this directory, without walking it,
+    /// see [`Resolver::index_dir`](crate::resolver::Resolver::index_dir).
+    DirIndexCache,
+}
+
+impl From<&Source> for BlockSource {
+    fn from(source: &Source) -> Self {
+        match source {
+            Source::Store(name) => BlockSource::Store(name),
+            Source::Bitswap(peer) => BlockSource::Bitswap(*peer),
+            Source::Http(url) => BlockSource::Http(url.clone()),
+        }
+    }
+}
+
+/// The blocks traversed by a resolution, in the order they were loaded.
+///
+/// Clones share the record, so it keeps growing while the resolution is running.
+#[derive(Debug, Clone, Default)]
+pub struct Provenance {
+    blocks: Arc<Mutex<Vec<(Cid, BlockSource)>>>,
+}
+
+impl Provenance {
+    /// Returns the traversed blocks recorded so far.
+    pub fn blocks(&self) -> Vec<(Cid, BlockSource)> {
+        self.blocks.lock().unwrap().clone()
+    }
+
+    pub(crate) fn record(&self, cid: Cid, source: BlockSource) {
+        self.blocks.lock().unwrap().push((cid, source));
+    }
+}
+
+/// Loads content with `inner`, recording the source of each block to the provenance, if
+/// there is one.
+#[derive(Debug, Clone)]
+pub(crate) struct RecordingLoader<T> {
+    pub(crate) inner: T,
+    pub(crate) provenance: Option<Provenance>,
+}
+
+#[async_trait]
+impl<T: ContentLoader> ContentLoader for RecordingLoader<T> {
+    async fn load_cid(&self, cid: &Cid, ctx: &LoaderContext) -> Result<LoadedCid> {
+        let loaded = self.inner.load_cid(cid, ctx).await?;
+        if let Some(provenance) = &self.provenance {
+            provenance.record(*cid, (&loaded.source).into());
+        }
+        Ok(loaded)
+    }
+
+    async fn stop_session(&self, ctx: ContextId) -> Result<()> {
+        self.inner.stop_session(ctx).await
+    }
+
+    async fn has_cid(&self, cid: &Cid) -> Result<bool> {
+        self.inner.has_cid(cid).await
+    }
+}
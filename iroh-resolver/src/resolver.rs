@@ -1,29 +1,35 @@
-use std::collections::VecDeque;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::fmt::{self, Debug, Display, Formatter};
 use std::pin::Pin;
 use std::str::FromStr;
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 use std::task::{Context, Poll};
+use std::time::Duration;
 
-use anyhow::{anyhow, bail, Context as _, Result};
+use anyhow::{anyhow, bail, ensure, Context as _, Result};
 use async_trait::async_trait;
 use bytes::Bytes;
 use cid::Cid;
-use futures::{Future, Stream, TryStreamExt};
+use futures::{stream::BoxStream, Future, Stream, StreamExt, TryStreamExt};
 use iroh_metrics::inc;
 use iroh_unixfs::{
     codecs::Codec,
     content_loader::{ContentLoader, ContextId, LoaderContext},
     parse_links,
-    unixfs::{read_data_to_buf, DataType, UnixfsChildStream, UnixfsContentReader, UnixfsNode},
+    unixfs::{
+        read_data_to_buf, DataType, DecodeLimitExceeded, DecodeLimits, UnixfsChildStream,
+        UnixfsContentReader, UnixfsNode,
+    },
     Block, Link, LoadedCid, Source,
 };
 use libipld::codec::Encode;
 use libipld::prelude::Codec as _;
 use libipld::{Ipld, IpldCodec};
 use tokio::io::{AsyncRead, AsyncSeek};
+use tokio::sync::Semaphore;
 use tokio::task::JoinHandle;
+use tokio::time::Instant;
 use tracing::{debug, trace, warn};
 
 use iroh_metrics::{
@@ -31,10 +37,15 @@ use iroh_metrics::{
     resolver::{OutMetrics, ResolverMetrics},
 };
 
+use crate::dir_index::{DirIndex, DirIndexCache};
 use crate::dns_resolver::{Config, DnsResolver};
+use crate::provenance::{BlockSource, Provenance, RecordingLoader};
 
 pub const IROH_STORE: &str = "iroh-store";
 
+/// How many cids [`Resolver::prefetch`] loads at once.
+const PREFETCH_CONCURRENCY: usize = 16;
+
 // ToDo: Remove this function
 // Related issue: https://github.com/n0-computer/iroh/issues/593
 fn from_peer_id(id: &str) -> Option<libipld::Multihash> {
@@ -54,6 +65,23 @@ fn from_peer_id(id: &str) -> Option<libipld::Multihash> {
     }
 }
 
+/// The error returned when a segment of a path is not a link of the directory it is
+/// resolved in.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LinkNotFound {
+    /// The kind of directory, e.g. `"UnixfsNode::Directory"`.
+    pub kind: &'static str,
+    pub name: String,
+}
+
+impl Display for LinkNotFound {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{} link '{}' not found", self.kind, self.name)
+    }
+}
+
+impl std::error::Error for LinkNotFound {}
+
 /// Represents an ipfs path.
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Path {
@@ -311,6 +339,41 @@ impl Out {
         self.metadata.unixfs_type == Some(UnixfsType::Symlink)
     }
 
+    /// Application specific metadata attached to the unixfs node, if any.
+    pub fn custom_metadata(&self) -> Option<&BTreeMap<String, String>> {
+        match &self.content {
+            OutContent::Unixfs(node) => node.custom_metadata(),
+            _ => None,
+        }
+    }
+
+    /// The decoded IPLD value of dag-cbor, dag-json, dag-pb and raw content, as detected
+    /// from the codec of its [`Cid`].
+    ///
+    /// Returns `None` for unixfs content, which is read with [`Out::pretty`] instead.
+    pub fn ipld(&self) -> Option<&Ipld> {
+        match &self.content {
+            OutContent::DagPb(ipld, _)
+            | OutContent::DagCbor(ipld, _)
+            | OutContent::DagJson(ipld, _)
+            | OutContent::Raw(ipld, _) => Some(ipld),
+            OutContent::Unixfs(_) => None,
+        }
+    }
+
+    /// Encodes the IPLD value as dag-json, e.g. to display structured data.
+    ///
+    /// Returns `None` for unixfs content, see [`Out::ipld`].
+    pub fn to_dag_json(&self) -> Result<Option<Bytes>> {
+        let ipld = match self.ipld() {
+            Some(ipld) => ipld,
+            None => return Ok(None),
+        };
+        let mut bytes = Vec::new();
+        ipld.encode(IpldCodec::DagJson, &mut bytes)?;
+        Ok(Some(bytes.into()))
+    }
+
     /// What kind of content this is this.
     pub fn typ(&self) -> OutType {
         self.content.typ()
@@ -347,6 +410,22 @@ impl Out {
         }
     }
 
+    /// Returns a stream over the content of this directory starting at `position`, each
+    /// entry with its position, see [`UnixfsNode::children_from`].
+    /// Only if this is of type `unixfs` and a directory.
+    pub fn unixfs_read_dir_from<'a, C: ContentLoader>(
+        &'a self,
+        loader: &Resolver<C>,
+        position: &'a [usize],
+    ) -> Option<BoxStream<'a, Result<(Vec<usize>, Link)>>> {
+        match &self.content {
+            OutContent::Unixfs(node) => {
+                node.children_from(self.context.clone(), loader.loader().clone(), position)
+            }
+            _ => None,
+        }
+    }
+
     pub fn pretty<T: ContentLoader>(
         self,
         loader: Resolver<T>,
@@ -459,6 +538,72 @@ pub enum UnixfsType {
     Symlink,
 }
 
+/// A single difference between two directories, as reported by [`Resolver::diff`].
+///
+/// Paths are relative to the compared directories, using `/` as separator.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DirDiff {
+    /// The entry only exists in the new directory.
+    Added { path: String, cid: Cid },
+    /// The entry only exists in the old directory.
+    Removed { path: String, cid: Cid },
+    /// The entry exists in both directories, with different content.
+    Changed { path: String, old: Cid, new: Cid },
+}
+
+impl DirDiff {
+    pub fn path(&self) -> &str {
+        match self {
+            DirDiff::Added { path, .. }
+            | DirDiff::Removed { path, .. }
+            | DirDiff::Changed { path, .. } => path,
+        }
+    }
+}
+
+/// Proof that a block is part of a DAG, as created by [`Resolver::inclusion_proof`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InclusionProof {
+    /// The blocks on the path from the root to the target, both included.
+    pub blocks: Vec<(Cid, Bytes)>,
+}
+
+impl InclusionProof {
+    /// The CIDs on the path from the root to the target.
+    pub fn cids(&self) -> impl Iterator<Item = &Cid> + '_ {
+        self.blocks.iter().map(|(cid, _)| cid)
+    }
+
+    /// Checks that this proof shows `target` to be part of the DAG at `root`.
+    ///
+    /// Needs nothing but the proof: each block must hash to its CID and link to the next
+    /// one, starting at `root` and ending at `target`.
+    pub fn verify(&self, root: &Cid, target: &Cid) -> Result<()> {
+        let (first, _) = self.blocks.first().context("empty inclusion proof")?;
+        let (last, _) = self.blocks.last().expect("not empty");
+        ensure!(first == root, "proof starts at {}, not at {}", first, root);
+        ensure!(last == target, "proof ends at {}, not at {}", last, target);
+
+        for (cid, bytes) in &self.blocks {
+            ensure!(
+                iroh_util::verify_hash(cid, bytes) == Some(true),
+                "block {} does not match its CID",
+                cid
+            );
+        }
+        for pair in self.blocks.windows(2) {
+            let ((parent, bytes), (child, _)) = (&pair[0], &pair[1]);
+            ensure!(
+                parse_links(parent, bytes)?.contains(child),
+                "block {} does not link to {}",
+                parent,
+                child
+            );
+        }
+        Ok(())
+    }
+}
+
 pub enum OutPrettyReader<C: ContentLoader> {
     DagPb(BytesReader),
     Unixfs(UnixfsContentReader<C>),
@@ -592,6 +737,15 @@ pub struct Resolver<T: ContentLoader> {
     next_id: Arc<AtomicU64>,
     _worker: Arc<JoinHandle<()>>,
     session_closer: async_channel::Sender<ContextId>,
+    priority: Option<i32>,
+    max_providers: Option<usize>,
+    timeout: Option<Duration>,
+    block_budget: Option<usize>,
+    decode_limits: Option<DecodeLimits>,
+    load_limit: Option<Arc<Semaphore>>,
+    dir_index: Option<Arc<DirIndexCache>>,
+    /// Records the blocks traversed by resolutions, see [`Resolver::resolve_with_provenance`].
+    provenance: Option<Provenance>,
 }
 
 impl<T: ContentLoader> Resolver<T> {
@@ -623,69 +777,228 @@ impl<T: ContentLoader> Resolver<T> {
             next_id: Arc::new(AtomicU64::new(0)),
             _worker: Arc::new(worker),
             session_closer: session_closer_s,
+            priority: None,
+            max_providers: None,
+            timeout: None,
+            block_budget: None,
+            decode_limits: None,
+            load_limit: None,
+            dir_index: None,
+            provenance: None,
+        }
+    }
+
+    /// Returns a resolver sharing all state with this one, which loads content with
+    /// the given wantlist `priority`, see [`LoaderContext::priority`].
+    pub fn with_priority(&self, priority: Option<i32>) -> Self {
+        Resolver {
+            priority,
+            ..self.clone()
+        }
+    }
+
+    /// Returns a resolver sharing all state with this one, which queries at most
+    /// `max_providers` when fetching content, see [`LoaderContext::max_providers`].
+    pub fn with_max_providers(&self, max_providers: Option<usize>) -> Self {
+        Resolver {
+            max_providers,
+            ..self.clone()
+        }
+    }
+
+    /// Returns a resolver sharing all state with this one, which gives each resolution a
+    /// deadline `timeout` after it starts, see [`LoaderContext::deadline`].
+    ///
+    /// The deadline bounds HAMT lookups and waiting for a load slot, not every block load.
+    pub fn with_timeout(&self, timeout: Option<Duration>) -> Self {
+        Resolver {
+            timeout,
+            ..self.clone()
+        }
+    }
+
+    /// Returns a resolver sharing all state with this one, where each resolution fails once
+    /// it loaded `block_budget` blocks, see [`LoaderContext::block_budget`].
+    ///
+    /// Unlike depth or size limits this bounds the total fetch work of a request, including
+    /// path resolution and the traversal of sharded directories.
+    pub fn with_block_budget(&self, block_budget: Option<usize>) -> Self {
+        Resolver {
+            block_budget,
+            ..self.clone()
+        }
+    }
+
+    /// Returns a resolver sharing all state with this one, which rejects unixfs nodes
+    /// exceeding `decode_limits`, see [`LoaderContext::decode_limits`].
+    ///
+    /// This protects against peers serving crafted blocks with huge numbers of links or
+    /// huge data fields.
+    pub fn with_decode_limits(&self, decode_limits: Option<DecodeLimits>) -> Self {
+        Resolver {
+            decode_limits,
+            ..self.clone()
         }
     }
 
+    /// Returns a resolver where at most `max_concurrent_loads` blocks are loaded at once,
+    /// by all resolutions of it and its clones together. Further loads wait for a slot, or
+    /// fail once the deadline of their resolution passes, see [`LoaderContext::acquire_load`].
+    ///
+    /// This bounds the fetch work a busy node takes on at once, e.g. a gateway serving many
+    /// requests. If `None`, loads are not limited.
+    pub fn with_max_concurrent_loads(&self, max_concurrent_loads: Option<usize>) -> Self {
+        Resolver {
+            load_limit: max_concurrent_loads.map(|limit| Arc::new(Semaphore::new(limit))),
+            ..self.clone()
+        }
+    }
+
+    /// Returns a resolver sharing all state with this one, which caches the name to CID
+    /// index of directories enabled with [`Resolver::index_dir`], holding up to
+    /// `max_entries` entries of all indexes together. If `None`, no indexes are cached.
+    ///
+    /// Once the cache is full, the least recently used indexes are evicted as a whole.
+    pub fn with_dir_index_cache(&self, max_entries: Option<usize>) -> Self {
+        Resolver {
+            dir_index: max_entries.map(|max| Arc::new(DirIndexCache::new(max))),
+            ..self.clone()
+        }
+    }
+
+    /// Enables indexing of the directory `dir`. The next time a name in it is resolved, all
+    /// its entries are listed into an index, which serves subsequent lookups without loading
+    /// the directory again. For sharded directories this loads the whole hamt once.
+    ///
+    /// Fails if this resolver has no index cache, see [`Resolver::with_dir_index_cache`].
+    pub fn index_dir(&self, dir: Cid) -> Result<()> {
+        let cache = self
+            .dir_index
+            .as_ref()
+            .context("directory index cache is not enabled")?;
+        cache.enable(dir);
+        Ok(())
+    }
+
+    pub fn dir_index_cache(&self) -> Option<&Arc<DirIndexCache>> {
+        self.dir_index.as_ref()
+    }
+
     fn next_id(&self) -> ContextId {
         let id = self.next_id.fetch_add(1, Ordering::SeqCst);
         ContextId(id)
     }
 
+    /// Creates a loader context with the settings of this resolver.
+    ///
+    /// The session of the context is stopped once it and all its clones are dropped.
+    pub fn new_context(&self) -> LoaderContext {
+        let mut ctx = LoaderContext::from_path(self.next_id(), self.session_closer.clone());
+        ctx.set_priority(self.priority);
+        ctx.set_max_providers(self.max_providers);
+        ctx.set_deadline(self.timeout.map(|timeout| Instant::now() + timeout));
+        ctx.set_block_budget(self.block_budget);
+        ctx.set_decode_limits(self.decode_limits);
+        ctx.set_load_limit(self.load_limit.clone());
+        ctx
+    }
+
     pub fn loader(&self) -> &T {
         &self.loader
     }
 
+    /// Returns a clone of the loader, which records the blocks it loads to the provenance
+    /// of this resolver.
+    fn recording_loader(&self) -> RecordingLoader<T> {
+        RecordingLoader {
+            inner: self.loader.clone(),
+            provenance: self.provenance.clone(),
+        }
+    }
+
     #[tracing::instrument(skip(self))]
     pub fn resolve_recursive_with_paths(
         &self,
         root: Path,
     ) -> impl Stream<Item = Result<(Path, Out)>> {
+        let results = self.resolve_recursive_with_paths_partial(root);
+        async_stream::try_stream! {
+            tokio::pin!(results);
+            while let Some((path, out)) = results.next().await {
+                yield (path, out?);
+            }
+        }
+    }
+
+    /// Same as [`Resolver::resolve_recursive_with_paths`], but does not stop at the first
+    /// error.
+    ///
+    /// Errors are yielded together with the path of the entry that failed to resolve, and
+    /// the remaining entries are still resolved. Nothing below a directory that failed to
+    /// resolve is yielded.
+    #[tracing::instrument(skip(self))]
+    pub fn resolve_recursive_with_paths_partial(
+        &self,
+        root: Path,
+    ) -> impl Stream<Item = (Path, Result<Out>)> {
         let mut blocks = VecDeque::new();
         let this = self.clone();
-        async_stream::try_stream! {
+        async_stream::stream! {
             let output_path = root.clone();
             blocks.push_back((output_path, this.resolve(root).await));
-            loop {
-                if let Some((current_output_path, current_out)) = blocks.pop_front() {
-                    let current = current_out?;
-                    if !current.is_dir() {
-                        yield (current_output_path, current);
-                        continue
+            while let Some((current_output_path, current_out)) = blocks.pop_front() {
+                let current = match current_out {
+                    Ok(current) => current,
+                    Err(err) => {
+                        yield (current_output_path, Err(err));
+                        continue;
                     }
+                };
+                if !current.is_dir() {
+                    yield (current_output_path, Ok(current));
+                    continue;
+                }
 
-                    // TODO(ramfox): we may want to just keep the stream and iterate over the links
-                    // that way, rather than gathering and then chunking again
-                    let links: Result<Vec<Link>> = current
-                        .unixfs_read_dir(&this, OutMetrics::default())?
-                        .expect("already know this is a directory")
-                        .try_collect()
-                        .await;
-                    let links = links?;
-                    // TODO: configurable limit
-                    for link_chunk in links.chunks(8) {
-                        let next = futures::future::join_all(
-                            link_chunk.iter().map(|link| {
-                                let this = this.clone();
-                                let mut this_path = current_output_path.clone();
-                                let name = link.name.clone();
-                                match name {
-                                    None => this_path.push(link.cid.to_string()),
-                                    Some(p) =>  this_path.push(p),
-                                };
-                                async move {
-                                    (this_path, this.resolve(Path::from_cid(link.cid)).await)
-                                }
-                            })
-                        ).await;
-                        for res in next.into_iter() {
-                            blocks.push_back(res);
+                // TODO(ramfox): we may want to just keep the stream and iterate over the links
+                // that way, rather than gathering and then chunking again
+                let links: Result<Vec<Link>> =
+                    match current.unixfs_read_dir(&this, OutMetrics::default()) {
+                        Ok(links) => {
+                            links
+                                .expect("already know this is a directory")
+                                .try_collect()
+                                .await
                         }
+                        Err(err) => Err(err),
+                    };
+                let links = match links {
+                    Ok(links) => links,
+                    Err(err) => {
+                        yield (current_output_path, Err(err));
+                        continue;
+                    }
+                };
+                // TODO: configurable limit
+                for link_chunk in links.chunks(8) {
+                    let next = futures::future::join_all(
+                        link_chunk.iter().map(|link| {
+                            let this = this.clone();
+                            let mut this_path = current_output_path.clone();
+                            let name = link.name.clone();
+                            match name {
+                                None => this_path.push(link.cid.to_string()),
+                                Some(p) =>  this_path.push(p),
+                            };
+                            async move {
+                                (this_path, this.resolve(Path::from_cid(link.cid)).await)
+                            }
+                        })
+                    ).await;
+                    for res in next.into_iter() {
+                        blocks.push_back(res);
                     }
-                    yield (current_output_path, current);
-                } else {
-                    // no links left to resolve
-                    break;
                 }
+                yield (current_output_path, Ok(current));
             }
         }
     }
@@ -730,7 +1043,7 @@ impl<T: ContentLoader> Resolver<T> {
         M: Fn(Cid, LoaderContext) -> F + Clone,
         F: Future<Output = Result<O>> + Send + 'static,
     {
-        let mut ctx = LoaderContext::from_path(self.next_id(), self.session_closer.clone());
+        let mut ctx = self.new_context();
 
         let mut cids = VecDeque::new();
         let this = self.clone();
@@ -779,20 +1092,250 @@ impl<T: ContentLoader> Resolver<T> {
     /// Resolves through a given path, returning the [`Cid`] and raw bytes of the final leaf.
     #[tracing::instrument(skip(self))]
     pub async fn resolve(&self, path: Path) -> Result<Out> {
-        let ctx = LoaderContext::from_path(self.next_id(), self.session_closer.clone());
+        let ctx = self.new_context();
 
         self.resolve_with_ctx(ctx, path, false).await
     }
 
+    /// Resolves through a given path like [`Resolver::resolve`], also returning where each
+    /// block traversed on the way came from.
+    ///
+    /// The provenance lists the CID of every node loaded to resolve the path, in order, from
+    /// the root to the target, along with the store, bitswap peer or gateway it was loaded
+    /// from. Lookups served by the directory index cache are listed under the CID of the
+    /// directory, as [`BlockSource::DirIndexCache`]. Content read from the returned [`Out`]
+    /// afterwards is not recorded.
+    #[tracing::instrument(skip(self))]
+    pub async fn resolve_with_provenance(&self, path: Path) -> Result<(Out, Provenance)> {
+        let provenance = Provenance::default();
+        let resolver = Resolver {
+            provenance: Some(provenance.clone()),
+            ..self.clone()
+        };
+        let out = resolver.resolve(path).await?;
+
+        Ok((out, provenance))
+    }
+
+    /// Returns the size in bytes of the content at `path`, without fetching the content.
+    ///
+    /// Only the block at `path` is loaded. Files report the size recorded in their root
+    /// node, the `filesize` or the sum of the `blocksizes`, raw blocks and other IPLD data
+    /// their length. Directories report the sum of the `tsize` of their entries, which
+    /// includes the encoded nodes below them. Fails if no size is recorded.
+    #[tracing::instrument(skip(self))]
+    pub async fn size(&self, path: Path) -> Result<u64> {
+        let out = self.resolve(path).await?;
+        if let Some(size) = out.metadata.size {
+            return Ok(size);
+        }
+        if let OutContent::Unixfs(node) = &out.content {
+            if !node.blocksizes().is_empty() {
+                return Ok(node.blocksizes().iter().sum());
+            }
+            // single blocks, like symlinks and empty directories
+            if let Some(size) = node.size() {
+                return Ok(size as u64);
+            }
+            if node.is_dir() {
+                let mut size = 0;
+                for link in node.links() {
+                    let link = link?;
+                    let tsize = link.tsize.with_context(|| {
+                        format!("no size recorded for {} in {}", link.cid, out.metadata.path)
+                    })?;
+                    size += tsize;
+                }
+                return Ok(size);
+            }
+        }
+        bail!("no size recorded for {}", out.metadata.path)
+    }
+
     /// Resolves through a given path, returning the [`Cid`] and raw bytes of the final leaf.
     /// Forces the RAW codec.
     #[tracing::instrument(skip(self))]
     pub async fn resolve_raw(&self, path: Path) -> Result<Out> {
-        let ctx = LoaderContext::from_path(self.next_id(), self.session_closer.clone());
+        let ctx = self.new_context();
 
         self.resolve_with_ctx(ctx, path, true).await
     }
 
+    /// Resolves through a given path, bypassing any locally cached blocks.
+    ///
+    /// The freshly loaded blocks are cached again, see [`LoaderContext::force_refresh`].
+    #[tracing::instrument(skip(self))]
+    pub async fn resolve_force_refresh(&self, path: Path) -> Result<Out> {
+        let mut ctx = self.new_context();
+        ctx.set_force_refresh(true);
+
+        self.resolve_with_ctx(ctx, path, false).await
+    }
+
+    /// Loads the given `cids` ahead of an expected request, without returning their content.
+    ///
+    /// Loaded blocks are stored by the loader, so resolving them later is served locally. Up
+    /// to 16 cids are loaded at once, in a single session. Returns the number of loaded cids,
+    /// once every cid was either loaded or failed to load, e.g. because it timed out, see
+    /// [`Resolver::with_timeout`].
+    #[tracing::instrument(skip(self))]
+    pub async fn prefetch(&self, cids: &[Cid]) -> usize {
+        let ctx = self.new_context();
+        futures::stream::iter(cids.iter().copied())
+            .map(|cid| {
+                let mut ctx = ctx.clone();
+                async move {
+                    match self.load_cid(&cid, &mut ctx).await {
+                        Ok(_) => true,
+                        Err(err) => {
+                            debug!("failed to prefetch {}: {:?}", cid, err);
+                            false
+                        }
+                    }
+                }
+            })
+            .buffer_unordered(PREFETCH_CONCURRENCY)
+            .filter(|loaded| futures::future::ready(*loaded))
+            .count()
+            .await
+    }
+
+    /// Compares the directories `old` and `new` by entry name, descending into
+    /// subdirectories present in both.
+    ///
+    /// An entry that changes between a file and a directory is reported as
+    /// [`DirDiff::Changed`]. Subtrees with identical [`Cid`]s are skipped.
+    #[tracing::instrument(skip(self))]
+    pub async fn diff(&self, old: Cid, new: Cid) -> Result<Vec<DirDiff>> {
+        let mut diffs = Vec::new();
+        // Guards against visiting the same pair of directories twice.
+        let mut visited = HashSet::new();
+        let mut queue = VecDeque::new();
+        queue.push_back((String::new(), old, new));
+
+        while let Some((prefix, old, new)) = queue.pop_front() {
+            if old == new || !visited.insert((old, new)) {
+                continue;
+            }
+
+            let old_links = self.read_dir_links(old).await?;
+            let new_links = self.read_dir_links(new).await?;
+
+            for (name, old_cid) in &old_links {
+                let path = format!("{prefix}{name}");
+                match new_links.get(name) {
+                    None => diffs.push(DirDiff::Removed {
+                        path,
+                        cid: *old_cid,
+                    }),
+                    Some(new_cid) if new_cid == old_cid => {}
+                    Some(new_cid) => {
+                        if self.is_dir(*old_cid).await? && self.is_dir(*new_cid).await? {
+                            queue.push_back((format!("{path}/"), *old_cid, *new_cid));
+                        } else {
+                            diffs.push(DirDiff::Changed {
+                                path,
+                                old: *old_cid,
+                                new: *new_cid,
+                            });
+                        }
+                    }
+                }
+            }
+            for (name, new_cid) in new_links {
+                if !old_links.contains_key(&name) {
+                    diffs.push(DirDiff::Added {
+                        path: format!("{prefix}{name}"),
+                        cid: new_cid,
+                    });
+                }
+            }
+        }
+
+        Ok(diffs)
+    }
+
+    /// Creates a proof that `target` is part of the DAG at `root`, see [`InclusionProof`].
+    ///
+    /// Searches the DAG breadth first, in the order [`Resolver::resolve_recursive`]
+    /// traverses it, until a block links to `target`, so the proof follows the shortest
+    /// path. Only the blocks before the target in that order are loaded. Fails if `target`
+    /// is not part of the DAG.
+    #[tracing::instrument(skip(self))]
+    pub async fn inclusion_proof(&self, root: Cid, target: Cid) -> Result<InclusionProof> {
+        let mut ctx = self.new_context();
+        // the parent each block was first reached from, and the blocks with links
+        let mut parents = HashMap::new();
+        let mut nodes = HashMap::new();
+        let mut queue = VecDeque::new();
+        queue.push_back(root);
+
+        let mut found = root == target;
+        while !found {
+            let cid = match queue.pop_front() {
+                Some(cid) => cid,
+                None => bail!("{} is not part of the DAG at {}", target, root),
+            };
+            let loaded = self.load_cid(&cid, &mut ctx).await?;
+            let links = parse_links(&cid, &loaded.data)?;
+            for link in &links {
+                if *link == root || parents.contains_key(link) {
+                    continue;
+                }
+                parents.insert(*link, cid);
+                if *link == target {
+                    found = true;
+                    break;
+                }
+                queue.push_back(*link);
+            }
+            if !links.is_empty() {
+                nodes.insert(cid, loaded.data);
+            }
+        }
+
+        let mut path = vec![target];
+        while let Some(parent) = parents.get(path.last().expect("not empty")) {
+            path.push(*parent);
+        }
+        path.reverse();
+
+        let mut blocks = Vec::with_capacity(path.len());
+        for cid in path {
+            let bytes = match nodes.remove(&cid) {
+                Some(bytes) => bytes,
+                None => self.load_cid(&cid, &mut ctx).await?.data,
+            };
+            blocks.push((cid, bytes));
+        }
+        Ok(InclusionProof { blocks })
+    }
+
+    async fn is_dir(&self, cid: Cid) -> Result<bool> {
+        Ok(self.resolve(Path::from_cid(cid)).await?.is_dir())
+    }
+
+    /// Reads the entries of the directory `cid`, keyed by name.
+    async fn read_dir_links(&self, cid: Cid) -> Result<BTreeMap<String, Cid>> {
+        let out = self.resolve(Path::from_cid(cid)).await?;
+        if !out.is_dir() {
+            bail!("{} is not a directory", cid);
+        }
+        let links: Vec<Link> = out
+            .unixfs_read_dir(self, OutMetrics::default())?
+            .expect("already know this is a directory")
+            .try_collect()
+            .await?;
+
+        Ok(links
+            .into_iter()
+            .map(|link| {
+                let name = link.name.unwrap_or_else(|| link.cid.to_string());
+                (name, link.cid)
+            })
+            .collect())
+    }
+
     pub async fn resolve_with_ctx(
         &self,
         mut ctx: LoaderContext,
@@ -833,23 +1376,52 @@ impl<T: ContentLoader> Resolver<T> {
         part: &str,
         ctx: &mut LoaderContext,
     ) -> Result<()> {
+        let dir = *resolved_path.last().expect("the root is always resolved");
+        if let Some(index) = self.cached_dir_index(dir, current, ctx).await? {
+            if let Some(provenance) = &self.provenance {
+                provenance.record(dir, BlockSource::DirIndexCache);
+            }
+            let next_cid = *index.get(part).ok_or_else(|| LinkNotFound {
+                kind: "directory index",
+                name: part.to_string(),
+            })?;
+            let loaded_cid = self.load_cid(&next_cid, ctx).await?;
+            let next_node =
+                UnixfsNode::decode_with_limits(&next_cid, loaded_cid.data, ctx.decode_limits())?;
+            resolved_path.push(next_cid);
+
+            *current = next_node;
+            return Ok(());
+        }
+
         match current {
             UnixfsNode::Directory(_) => {
-                let next_link = current
-                    .get_link_by_name(&part)
-                    .await?
-                    .ok_or_else(|| anyhow!("UnixfsNode::Directory link '{}' not found", part))?;
+                let next_link =
+                    current
+                        .get_link_by_name(&part)
+                        .await?
+                        .ok_or_else(|| LinkNotFound {
+                            kind: "UnixfsNode::Directory",
+                            name: part.to_string(),
+                        })?;
                 let loaded_cid = self.load_cid(&next_link.cid, ctx).await?;
-                let next_node = UnixfsNode::decode(&next_link.cid, loaded_cid.data)?;
+                let next_node = UnixfsNode::decode_with_limits(
+                    &next_link.cid,
+                    loaded_cid.data,
+                    ctx.decode_limits(),
+                )?;
                 resolved_path.push(next_link.cid);
 
                 *current = next_node;
             }
             UnixfsNode::HamtShard(_, hamt) => {
                 let (next_link, next_node) = hamt
-                    .get(ctx.clone(), self.loader().clone(), part.as_bytes())
+                    .get(ctx.clone(), self.recording_loader(), part.as_bytes())
                     .await?
-                    .ok_or_else(|| anyhow!("UnixfsNode::HamtShard link '{}' not found", part))?;
+                    .ok_or_else(|| LinkNotFound {
+                        kind: "UnixfsNode::HamtShard",
+                        name: part.to_string(),
+                    })?;
                 // TODO: is this the right way to to resolved path here?
                 resolved_path.push(next_link.cid);
 
@@ -863,6 +1435,37 @@ impl<T: ContentLoader> Resolver<T> {
         Ok(())
     }
 
+    /// Returns the index of the directory `dir`, building it if indexing is enabled for it,
+    /// but it is not cached yet.
+    async fn cached_dir_index(
+        &self,
+        dir: Cid,
+        current: &UnixfsNode,
+        ctx: &LoaderContext,
+    ) -> Result<Option<Arc<DirIndex>>> {
+        let cache = match &self.dir_index {
+            Some(cache) if current.is_dir() && cache.is_enabled(&dir) => cache,
+            _ => return Ok(None),
+        };
+        if let Some(index) = cache.get(&dir) {
+            return Ok(Some(index));
+        }
+
+        let mut index = DirIndex::new();
+        let mut links = current
+            .as_child_reader(ctx.clone(), self.recording_loader(), OutMetrics::default())?
+            .expect("already know this is a directory");
+        while let Some(link) = links.next().await {
+            let link = link?;
+            let name = link.name.unwrap_or_else(|| link.cid.to_string());
+            index.insert(name, link.cid);
+        }
+        debug!("indexed {} entries of {}", index.len(), dir);
+        let index = Arc::new(index);
+        cache.insert(dir, index.clone());
+        Ok(Some(index))
+    }
+
     /// Resolves through both DagPb and nested UnixFs DAGs.
     #[tracing::instrument(skip(self, loaded_cid))]
     async fn resolve_dag_pb_or_unixfs(
@@ -873,7 +1476,14 @@ impl<T: ContentLoader> Resolver<T> {
         mut ctx: LoaderContext,
     ) -> Result<Out> {
         trace!("{:?} resolving {} for {}", ctx.id(), cid, root_path);
-        if let Ok(node) = UnixfsNode::decode(&cid, loaded_cid.data.clone()) {
+        let node =
+            UnixfsNode::decode_with_limits(&cid, loaded_cid.data.clone(), ctx.decode_limits());
+        let node = match node {
+            // oversized nodes must not be decoded again as plain dag-pb
+            Err(err) if err.is::<DecodeLimitExceeded>() => return Err(err),
+            node => node,
+        };
+        if let Ok(node) = node {
             let tail = &root_path.tail();
             let mut current = node;
             let mut resolved_path = vec![cid];
@@ -1092,7 +1702,13 @@ impl<T: ContentLoader> Resolver<T> {
 
     #[tracing::instrument(skip(self))]
     async fn load_cid(&self, cid: &Cid, ctx: &mut LoaderContext) -> Result<LoadedCid> {
-        self.loader.load_cid(cid, ctx).await
+        ctx.consume_block_budget()?;
+        let _permit = ctx.acquire_load().await?;
+        let loaded = self.loader.load_cid(cid, ctx).await?;
+        if let Some(provenance) = &self.provenance {
+            provenance.record(*cid, (&loaded.source).into());
+        }
+        Ok(loaded)
     }
 
     #[tracing::instrument(skip(self))]
@@ -1301,10 +1917,19 @@ mod tests {
             let loader: Arc<HashMap<_, _>> = Arc::new([(c, bytes)].into_iter().collect());
             let resolver = Resolver::new(loader.clone());
 
+            {
+                let out = resolver.resolve(Path::from_cid(c)).await.unwrap();
+                assert_eq!(out.ipld(), Some(&ipld));
+                let json = out.to_dag_json().unwrap().unwrap();
+                let json_ipld: Ipld = IpldCodec::DagJson.decode(&json).unwrap();
+                assert_eq!(json_ipld, ipld);
+            }
             {
                 let path = format!("/ipfs/{c}/name");
                 let new_ipld = resolver.resolve(path.parse().unwrap()).await.unwrap();
                 let m = new_ipld.metadata().clone();
+                assert_eq!(new_ipld.ipld(), Some(&Ipld::String("Foo".to_string())));
+                assert_eq!(new_ipld.to_dag_json().unwrap().unwrap(), &b"\"Foo\""[..]);
 
                 let out_bytes = read_to_vec(
                     new_ipld
@@ -2408,6 +3033,21 @@ mod tests {
                 panic!("invalid result: {ipld_txt:?}");
             }
         }
+        // block budget, shared by the path resolution and the shard traversal
+        {
+            let path: Path = format!("/ipfs/{root_cid_str}/9999.txt").parse().unwrap();
+            let err = resolver
+                .with_block_budget(Some(2))
+                .resolve(path.clone())
+                .await
+                .unwrap_err();
+            assert!(format!("{err:#}").contains("block budget exhausted"));
+            resolver
+                .with_block_budget(Some(100))
+                .resolve(path)
+                .await
+                .unwrap();
+        }
         // read the directory listing
         {
             let path = format!("/ipfs/{root_cid_str}");
@@ -2549,4 +3189,528 @@ mod tests {
             format!("/ipfs/{root_cid_str}/bar/bar.txt")
         );
     }
+
+    #[tokio::test]
+    async fn test_resolver_size() {
+        use iroh_unixfs::builder::{DirectoryBuilder, FileBuilder};
+
+        let file = FileBuilder::new()
+            .name("big")
+            .fixed_chunker(100)
+            .content_bytes(vec![1u8; 1000])
+            .build()
+            .await
+            .unwrap();
+        let dir = DirectoryBuilder::new()
+            .name("root")
+            .add_file(file)
+            .build()
+            .await
+            .unwrap();
+        let blocks: Vec<_> = dir.encode().try_collect().await.unwrap();
+        let root = *blocks.last().unwrap().cid();
+        // only the directory and the root of the file, none of the leaves
+        let mut loader: HashMap<Cid, Bytes> = blocks
+            .into_iter()
+            .filter(|block| !block.links().is_empty())
+            .map(|block| {
+                let (cid, bytes, _) = block.into_parts();
+                (cid, bytes)
+            })
+            .collect();
+        assert_eq!(loader.len(), 2);
+        let raw = Bytes::from_static(b"hello");
+        let raw_cid = Cid::new_v1(IpldCodec::Raw.into(), Code::Sha2_256.digest(&raw));
+        loader.insert(raw_cid, raw);
+        let resolver = Resolver::new(Arc::new(loader));
+
+        let size = resolver.size(format!("/ipfs/{root}/big").parse().unwrap());
+        assert_eq!(size.await.unwrap(), 1000);
+        assert_eq!(resolver.size(Path::from_cid(raw_cid)).await.unwrap(), 5);
+
+        let out = resolver.resolve(Path::from_cid(root)).await.unwrap();
+        let tsize = match &out.content {
+            OutContent::Unixfs(node) => node.links().next().unwrap().unwrap().tsize.unwrap(),
+            _ => panic!("not unixfs"),
+        };
+        assert!(tsize > 1000);
+        assert_eq!(resolver.size(Path::from_cid(root)).await.unwrap(), tsize);
+    }
+
+    #[tokio::test]
+    async fn test_inclusion_proof() {
+        use iroh_unixfs::builder::{DirectoryBuilder, FileBuilder};
+
+        let file = FileBuilder::new()
+            .name("big")
+            .fixed_chunker(100)
+            .content_bytes(vec![1u8; 1000])
+            .build()
+            .await
+            .unwrap();
+        let sub = DirectoryBuilder::new()
+            .name("sub")
+            .add_file(file)
+            .build()
+            .await
+            .unwrap();
+        let dir = DirectoryBuilder::new()
+            .add_dir(sub)
+            .unwrap()
+            .build()
+            .await
+            .unwrap();
+        let blocks: Vec<_> = dir.encode().try_collect().await.unwrap();
+        let root = *blocks.last().unwrap().cid();
+        // the first block is a leaf of the file
+        let leaf = *blocks[0].cid();
+        let loader: HashMap<Cid, Bytes> = blocks
+            .into_iter()
+            .map(|block| {
+                let (cid, bytes, _) = block.into_parts();
+                (cid, bytes)
+            })
+            .collect();
+        let resolver = Resolver::new(Arc::new(loader));
+
+        let proof = resolver.inclusion_proof(root, leaf).await.unwrap();
+        // root, sub, file root, leaf
+        assert_eq!(proof.blocks.len(), 4);
+        assert_eq!(proof.cids().next(), Some(&root));
+        proof.verify(&root, &leaf).unwrap();
+        assert!(proof.verify(&leaf, &root).is_err());
+
+        // tampered blocks and gaps in the path are detected
+        let mut tampered = proof.clone();
+        tampered.blocks[2].1 = Bytes::from_static(b"garbage");
+        assert!(tampered.verify(&root, &leaf).is_err());
+        let mut gap = proof.clone();
+        gap.blocks.remove(1);
+        assert!(gap.verify(&root, &leaf).is_err());
+
+        let root_only = resolver.inclusion_proof(root, root).await.unwrap();
+        assert_eq!(root_only.blocks.len(), 1);
+        root_only.verify(&root, &root).unwrap();
+
+        let raw = Bytes::from_static(b"hello");
+        let raw_cid = Cid::new_v1(IpldCodec::Raw.into(), Code::Sha2_256.digest(&raw));
+        assert!(resolver.inclusion_proof(root, raw_cid).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_max_concurrent_loads() {
+        use iroh_unixfs::builder::{DirectoryBuilder, FileBuilder};
+        use std::sync::atomic::AtomicUsize;
+
+        /// Counts the loads in flight at once.
+        #[derive(Debug, Clone)]
+        struct CountingLoader {
+            blocks: Arc<HashMap<Cid, Bytes>>,
+            in_flight: Arc<AtomicUsize>,
+            max_in_flight: Arc<AtomicUsize>,
+        }
+
+        #[async_trait]
+        impl ContentLoader for CountingLoader {
+            async fn load_cid(&self, cid: &Cid, ctx: &LoaderContext) -> Result<LoadedCid> {
+                let in_flight = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                self.max_in_flight.fetch_max(in_flight, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(5)).await;
+                self.in_flight.fetch_sub(1, Ordering::SeqCst);
+                self.blocks.load_cid(cid, ctx).await
+            }
+
+            async fn stop_session(&self, _: ContextId) -> Result<()> {
+                Ok(())
+            }
+
+            async fn has_cid(&self, cid: &Cid) -> Result<bool> {
+                Ok(self.blocks.contains_key(cid))
+            }
+        }
+
+        let mut dir = DirectoryBuilder::new();
+        for i in 0..10 {
+            let file = FileBuilder::new()
+                .name(format!("{i}.txt"))
+                .content_bytes(format!("{i}").into_bytes())
+                .build()
+                .await
+                .unwrap();
+            dir = dir.add_file(file);
+        }
+        let dir = dir.build().await.unwrap();
+        let blocks: Vec<_> = dir.encode().try_collect().await.unwrap();
+        let root = *blocks.last().unwrap().cid();
+        let loader = CountingLoader {
+            blocks: Arc::new(
+                blocks
+                    .into_iter()
+                    .map(|block| {
+                        let (cid, bytes, _) = block.into_parts();
+                        (cid, bytes)
+                    })
+                    .collect(),
+            ),
+            in_flight: Default::default(),
+            max_in_flight: Default::default(),
+        };
+        let resolver = Resolver::new(loader.clone()).with_max_concurrent_loads(Some(2));
+
+        let resolves = (0..10).map(|i| {
+            let path: Path = format!("/ipfs/{root}/{i}.txt").parse().unwrap();
+            resolver.resolve(path)
+        });
+        futures::future::try_join_all(resolves).await.unwrap();
+        let max_in_flight = loader.max_in_flight.load(Ordering::SeqCst);
+        assert!(
+            (1..=2).contains(&max_in_flight),
+            "{max_in_flight} loads in flight"
+        );
+
+        // queued loads respect the deadline of their resolution
+        let path: Path = format!("/ipfs/{root}/0.txt").parse().unwrap();
+        let permits = resolver.load_limit.clone().unwrap();
+        let held = permits.acquire_many(2).await.unwrap();
+        let err = resolver
+            .with_timeout(Some(Duration::from_millis(20)))
+            .resolve(path.clone())
+            .await
+            .unwrap_err();
+        assert!(format!("{err:#}").contains("deadline exceeded"));
+        drop(held);
+        resolver.resolve(path).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_resolver_diff() {
+        use iroh_unixfs::builder::{Directory, DirectoryBuilder, FileBuilder};
+
+        async fn file(name: &str, content: &'static [u8]) -> iroh_unixfs::builder::File {
+            FileBuilder::new()
+                .name(name)
+                .content_bytes(content)
+                .build()
+                .await
+                .unwrap()
+        }
+
+        async fn add(loader: &mut HashMap<Cid, Bytes>, dir: Directory) -> Cid {
+            let blocks: Vec<_> = dir.encode().try_collect().await.unwrap();
+            let root = *blocks.last().unwrap().cid();
+            for block in blocks {
+                let (cid, bytes, _) = block.into_parts();
+                loader.insert(cid, bytes);
+            }
+            root
+        }
+
+        let old = DirectoryBuilder::new()
+            .name("root")
+            .add_file(file("a.txt", b"a").await)
+            .add_file(file("b.txt", b"b").await)
+            .add_file(file("gone.txt", b"gone").await)
+            .add_file(file("x", b"x").await)
+            .add_dir(
+                DirectoryBuilder::new()
+                    .name("sub")
+                    .add_file(file("c.txt", b"c").await)
+                    .build()
+                    .await
+                    .unwrap(),
+            )
+            .unwrap()
+            .build()
+            .await
+            .unwrap();
+        let new = DirectoryBuilder::new()
+            .name("root")
+            .add_file(file("a.txt", b"a").await)
+            .add_file(file("b.txt", b"b2").await)
+            .add_file(file("e.txt", b"e").await)
+            .add_dir(
+                DirectoryBuilder::new()
+                    .name("x")
+                    .add_file(file("y.txt", b"y").await)
+                    .build()
+                    .await
+                    .unwrap(),
+            )
+            .unwrap()
+            .add_dir(
+                DirectoryBuilder::new()
+                    .name("sub")
+                    .add_file(file("c.txt", b"c2").await)
+                    .add_file(file("d.txt", b"d").await)
+                    .build()
+                    .await
+                    .unwrap(),
+            )
+            .unwrap()
+            .build()
+            .await
+            .unwrap();
+
+        let mut loader = HashMap::new();
+        let old = add(&mut loader, old).await;
+        let new = add(&mut loader, new).await;
+        let resolver = Resolver::new(Arc::new(loader));
+
+        assert!(resolver.diff(old, old).await.unwrap().is_empty());
+
+        let diffs = resolver.diff(old, new).await.unwrap();
+        let paths: Vec<_> = diffs.iter().map(|d| d.path()).collect();
+        assert_eq!(
+            paths,
+            ["b.txt", "gone.txt", "x", "e.txt", "sub/c.txt", "sub/d.txt"]
+        );
+        assert!(matches!(diffs[0], DirDiff::Changed { .. }));
+        assert!(matches!(diffs[1], DirDiff::Removed { .. }));
+        assert!(matches!(diffs[2], DirDiff::Changed { .. }));
+        assert!(matches!(diffs[3], DirDiff::Added { .. }));
+        assert!(matches!(diffs[4], DirDiff::Changed { .. }));
+        assert!(matches!(diffs[5], DirDiff::Added { .. }));
+
+        let diffs = resolver.diff(new, old).await.unwrap();
+        assert!(diffs
+            .iter()
+            .any(|d| matches!(d, DirDiff::Removed { path, .. } if path == "e.txt")));
+    }
+
+    #[tokio::test]
+    async fn test_prefetch() {
+        let blocks: Vec<Bytes> = (0..40u8).map(|i| Bytes::from(vec![i; 8])).collect();
+        let cids: Vec<Cid> = blocks
+            .iter()
+            .map(|data| Cid::new_v1(IpldCodec::Raw.into(), Code::Sha2_256.digest(data)))
+            .collect();
+        let loader: HashMap<Cid, Bytes> = cids.iter().copied().zip(blocks).collect();
+        let resolver = Resolver::new(Arc::new(loader));
+
+        assert_eq!(resolver.prefetch(&[]).await, 0);
+        assert_eq!(resolver.prefetch(&cids).await, cids.len());
+
+        let missing = Cid::new_v1(IpldCodec::Raw.into(), Code::Sha2_256.digest(b"missing"));
+        assert_eq!(resolver.prefetch(&[cids[0], missing]).await, 1);
+    }
+
+    #[tokio::test]
+    async fn test_resolve_dir_index() {
+        use iroh_unixfs::builder::{DirectoryBuilder, FileBuilder};
+
+        let mut dir = DirectoryBuilder::new().name("root").hamt();
+        for i in 0..20u8 {
+            let file = FileBuilder::new()
+                .name(format!("{i}.txt"))
+                .content_bytes(vec![i; 16])
+                .build()
+                .await
+                .unwrap();
+            dir = dir.add_file(file);
+        }
+        let dir = dir.build().await.unwrap();
+        let (root, resolver) = stream_to_resolver(dir.encode()).await.unwrap();
+        assert!(resolver.index_dir(root).is_err());
+
+        let resolver = resolver.with_dir_index_cache(Some(100));
+        resolver.index_dir(root).unwrap();
+        let cache = resolver.dir_index_cache().unwrap().clone();
+        assert!(cache.get(&root).is_none());
+
+        for i in [3u8, 17] {
+            let path: Path = format!("/ipfs/{root}/{i}.txt").parse().unwrap();
+            let out = resolver.resolve(path).await.unwrap();
+            let reader = out
+                .pretty(resolver.clone(), OutMetrics::default(), None)
+                .unwrap();
+            let content = read_to_vec(reader).await.unwrap();
+            assert_eq!(content, vec![i; 16]);
+        }
+        let index = cache.get(&root).unwrap();
+        assert_eq!(index.len(), 20);
+        assert_eq!(cache.entries(), 20);
+
+        let path: Path = format!("/ipfs/{root}/missing.txt").parse().unwrap();
+        let err = resolver.resolve(path).await.unwrap_err();
+        assert!(err.downcast_ref::<LinkNotFound>().is_some());
+
+        // too small caches fall back to resolving through the directory
+        let resolver = resolver.with_dir_index_cache(Some(10));
+        resolver.index_dir(root).unwrap();
+        let path: Path = format!("/ipfs/{root}/5.txt").parse().unwrap();
+        resolver.resolve(path.clone()).await.unwrap();
+        let cache = resolver.dir_index_cache().unwrap();
+        assert!(cache.get(&root).is_none());
+        assert!(!cache.is_enabled(&root));
+        resolver.resolve(path).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_resolve_with_provenance() {
+        use iroh_unixfs::builder::{DirectoryBuilder, FileBuilder};
+
+        let file = FileBuilder::new()
+            .name("b.txt")
+            .content_bytes(b"hello".to_vec())
+            .build()
+            .await
+            .unwrap();
+        let sub = DirectoryBuilder::new().name("a").add_file(file);
+        let dir = DirectoryBuilder::new()
+            .name("root")
+            .add_dir(sub.build().await.unwrap())
+            .unwrap()
+            .build()
+            .await
+            .unwrap();
+        let (root, resolver) = stream_to_resolver(dir.encode()).await.unwrap();
+
+        let path: Path = format!("/ipfs/{root}/a/b.txt").parse().unwrap();
+        let (out, provenance) = resolver.resolve_with_provenance(path).await.unwrap();
+        let traversed: Vec<_> = provenance
+            .blocks()
+            .into_iter()
+            .map(|(cid, source)| {
+                assert_eq!(source, BlockSource::Bitswap(None));
+                cid
+            })
+            .collect();
+        assert_eq!(traversed, out.metadata().resolved_path);
+        assert_eq!(traversed.len(), 3);
+        assert_eq!(traversed[0], root);
+
+        // lookups in indexed directories are recorded as such
+        let resolver = resolver.with_dir_index_cache(Some(100));
+        resolver.index_dir(root).unwrap();
+        let path: Path = format!("/ipfs/{root}/a").parse().unwrap();
+        resolver.resolve(path.clone()).await.unwrap();
+        let (_, provenance) = resolver.resolve_with_provenance(path).await.unwrap();
+        assert_eq!(
+            provenance.blocks(),
+            vec![
+                (root, BlockSource::Bitswap(None)),
+                (root, BlockSource::DirIndexCache),
+                (traversed[1], BlockSource::Bitswap(None)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_decode_limits_before_decoding() {
+        // five empty links and no data, which is not even a valid unixfs node
+        let buf = Bytes::from_static(&[0x12, 0, 0x12, 0, 0x12, 0, 0x12, 0, 0x12, 0]);
+        let cid = Cid::new_v1(IpldCodec::DagPb.into(), Code::Sha2_256.digest(&buf));
+        let limits = DecodeLimits {
+            max_links: 4,
+            max_data_bytes: 1024,
+        };
+        let err = UnixfsNode::decode_with_limits(&cid, buf.clone(), Some(&limits)).unwrap_err();
+        let exceeded = err.downcast_ref::<DecodeLimitExceeded>().unwrap();
+        assert_eq!((exceeded.len, exceeded.limit), (5, 4));
+
+        // within the limits, the node is decoded, and rejected for its missing data
+        let limits = DecodeLimits {
+            max_links: 5,
+            ..limits
+        };
+        let err = UnixfsNode::decode_with_limits(&cid, buf, Some(&limits)).unwrap_err();
+        assert!(err.downcast_ref::<DecodeLimitExceeded>().is_none());
+
+        // truncated nodes are rejected before they are decoded
+        let buf = Bytes::from_static(&[0x0a, 0x10, 0]);
+        let cid = Cid::new_v1(IpldCodec::DagPb.into(), Code::Sha2_256.digest(&buf));
+        assert!(UnixfsNode::decode_with_limits(&cid, buf, Some(&limits)).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_resolve_decode_limits() {
+        use iroh_unixfs::builder::{DirectoryBuilder, FileBuilder};
+
+        let mut dir = DirectoryBuilder::new().name("root");
+        for i in 0..5u8 {
+            let file = FileBuilder::new()
+                .name(format!("{i}.txt"))
+                .content_bytes(vec![i; 2048])
+                .build()
+                .await
+                .unwrap();
+            dir = dir.add_file(file);
+        }
+        let dir = dir.build().await.unwrap();
+        let (root, resolver) = stream_to_resolver(dir.encode()).await.unwrap();
+        let path = Path::from_cid(root);
+        let file_path: Path = format!("/ipfs/{root}/0.txt").parse().unwrap();
+
+        resolver.resolve(path.clone()).await.unwrap();
+        resolver.resolve(file_path.clone()).await.unwrap();
+
+        let limited = resolver.with_decode_limits(Some(DecodeLimits {
+            max_links: 4,
+            max_data_bytes: 4096,
+        }));
+        let err = limited.resolve(path.clone()).await.unwrap_err();
+        let exceeded = err.downcast_ref::<DecodeLimitExceeded>().unwrap();
+        assert_eq!(exceeded.cid, root);
+        assert_eq!(exceeded.what, "links");
+        assert_eq!((exceeded.len, exceeded.limit), (5, 4));
+
+        let limited = resolver.with_decode_limits(Some(DecodeLimits {
+            max_links: 5,
+            max_data_bytes: 1024,
+        }));
+        limited.resolve(path).await.unwrap();
+        let err = limited.resolve(file_path).await.unwrap_err();
+        let exceeded = err.downcast_ref::<DecodeLimitExceeded>().unwrap();
+        assert_eq!(exceeded.what, "data bytes");
+        assert_eq!((exceeded.len, exceeded.limit), (2048, 1024));
+    }
+
+    #[tokio::test]
+    async fn test_resolve_custom_metadata() {
+        use iroh_unixfs::builder::FileBuilder;
+
+        let metadata: BTreeMap<String, String> = [
+            ("author".to_string(), "iroh".to_string()),
+            ("license".to_string(), "MIT".to_string()),
+        ]
+        .into_iter()
+        .collect();
+
+        // a single chunk, with a raw root, and a file spanning multiple chunks
+        for content in [b"hello world".to_vec(), vec![7u8; 1024 * 10]] {
+            let file = FileBuilder::new()
+                .name("foo")
+                .fixed_chunker(1024)
+                .content_bytes(content.clone())
+                .custom_metadata(metadata.clone())
+                .build()
+                .await
+                .unwrap();
+            let (root, resolver) = stream_to_resolver(file.encode().await.unwrap())
+                .await
+                .unwrap();
+
+            let out = resolver.resolve(Path::from_cid(root)).await.unwrap();
+            assert_eq!(out.custom_metadata(), Some(&metadata));
+            let out_bytes = read_to_vec(
+                out.pretty(resolver.clone(), OutMetrics::default(), None)
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+            assert_eq!(out_bytes, content);
+        }
+
+        let file = FileBuilder::new()
+            .name("foo")
+            .content_bytes(&b"hello world"[..])
+            .build()
+            .await
+            .unwrap();
+        let (root, resolver) = stream_to_resolver(file.encode().await.unwrap())
+            .await
+            .unwrap();
+        let out = resolver.resolve(Path::from_cid(root)).await.unwrap();
+        assert_eq!(out.custom_metadata(), None);
+    }
 }
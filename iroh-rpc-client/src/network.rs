@@ -4,7 +4,7 @@ use bytes::Bytes;
 use cid::Cid;
 use futures::{Stream, StreamExt};
 use iroh_rpc_types::{p2p::*, VersionRequest, WatchRequest};
-use libp2p::gossipsub::{MessageId, TopicHash};
+use libp2p::gossipsub::{GossipsubMessage, MessageId, TopicHash};
 use libp2p::{Multiaddr, PeerId};
 use std::collections::{HashMap, HashSet};
 use tracing::{debug, warn};
@@ -46,14 +46,63 @@ impl P2pClient {
         Ok(res.addrs)
     }
 
+    /// Returns the relays the node listens on, with the state of their reservation.
+    #[tracing::instrument(skip(self))]
+    pub async fn relays(&self) -> Result<Vec<(PeerId, RelayState)>> {
+        let res = self.client.rpc(RelaysRequest).await??;
+        Ok(res.relays)
+    }
+
+    /// Returns the kademlia routing table and the local provider records of the node.
+    #[tracing::instrument(skip(self))]
+    pub async fn export_routing_state(&self) -> Result<RoutingState> {
+        let res = self.client.rpc(ExportRoutingStateRequest).await??;
+        Ok(res.state)
+    }
+
+    /// Adds the peers and provider records of `state` to the node, discarding expired and
+    /// invalid ones.
+    #[tracing::instrument(skip(self, state))]
+    pub async fn import_routing_state(
+        &self,
+        state: RoutingState,
+    ) -> Result<ImportRoutingStateResponse> {
+        let res = self
+            .client
+            .rpc(ImportRoutingStateRequest { state })
+            .await??;
+        Ok(res)
+    }
+
     // Fetches a block directly from the network.
+    // If no `priority` is given, the default wantlist priority is used.
+    // At most `max_providers` are searched, or the bitswap default if not given.
     #[tracing::instrument(skip(self))]
     pub async fn fetch_bitswap(
         &self,
         ctx: u64,
         cid: Cid,
         providers: HashSet<PeerId>,
+        priority: Option<i32>,
+        max_providers: Option<usize>,
     ) -> Result<Bytes> {
+        let (data, _) = self
+            .fetch_bitswap_with_origin(ctx, cid, providers, priority, max_providers)
+            .await?;
+        Ok(data)
+    }
+
+    /// Like [`P2pClient::fetch_bitswap`], but also returns the peer the block was received
+    /// from, if known.
+    #[tracing::instrument(skip(self))]
+    pub async fn fetch_bitswap_with_origin(
+        &self,
+        ctx: u64,
+        cid: Cid,
+        providers: HashSet<PeerId>,
+        priority: Option<i32>,
+        max_providers: Option<usize>,
+    ) -> Result<(Bytes, Option<PeerId>)> {
         debug!("rpc p2p client fetch_bitswap: {:?}", cid);
         let providers = providers.into_iter().collect();
         let res = self
@@ -62,9 +111,11 @@ impl P2pClient {
                 ctx,
                 cid,
                 providers,
+                priority,
+                max_providers: max_providers.map(|n| n as u64),
             })
             .await??;
-        Ok(res.data)
+        Ok((res.data, res.from))
     }
 
     #[tracing::instrument(skip(self))]
@@ -128,6 +179,13 @@ impl P2pClient {
         Ok(peers_map)
     }
 
+    /// Returns the connected peers, with what identify, ping and bitswap know about them.
+    #[tracing::instrument(skip(self))]
+    pub async fn peers_info(&self) -> Result<Vec<PeerInfo>> {
+        let res = self.client.rpc(PeersInfoRequest).await??;
+        Ok(res.peers)
+    }
+
     #[tracing::instrument(skip(self))]
     /// Attempts to connect to the given node. If only the `PeerId` is present, it will
     /// attempt to find the given peer on the DHT before connecting. If the `PeerId` and any
@@ -224,6 +282,30 @@ impl P2pClient {
         Ok(res.peers)
     }
 
+    /// Streams the gossipsub messages received by the node, from all subscribed topics, or
+    /// only from `topic`. Yields the peer each message was received from, and its id.
+    #[tracing::instrument(skip(self))]
+    pub async fn gossipsub_messages(
+        &self,
+        topic: Option<TopicHash>,
+    ) -> Result<impl Stream<Item = Result<(PeerId, MessageId, GossipsubMessage)>>> {
+        let req = GossipsubMessagesRequest {
+            topic_hash: topic.map(|topic| topic.to_string()),
+        };
+        let res = self.client.server_streaming(req).await?;
+        let messages = res.map(|res| -> Result<_> {
+            let res = res??;
+            let message = GossipsubMessage {
+                source: res.source,
+                data: res.data.to_vec(),
+                sequence_number: res.sequence_number,
+                topic: TopicHash::from_raw(res.topic_hash),
+            };
+            Ok((res.from, MessageId::new(&res.message_id), message))
+        });
+        Ok(messages)
+    }
+
     #[tracing::instrument(skip(self, data))]
     pub async fn gossipsub_publish(&self, topic_hash: TopicHash, data: Bytes) -> Result<MessageId> {
         let req = GossipsubPublishRequest {
@@ -267,6 +349,58 @@ impl P2pClient {
         Ok(res.was_subscribed)
     }
 
+    /// Requests the last message `peer_id` published or retained on `topic` directly from it.
+    ///
+    /// Unlike gossip this also works if our subscription had not reached the peer yet when
+    /// it published, or without gossipsub. Returns `None` if the peer has not published on
+    /// the topic.
+    #[tracing::instrument(skip(self))]
+    pub async fn gossipsub_fetch_last(
+        &self,
+        peer_id: PeerId,
+        topic: TopicHash,
+    ) -> Result<Option<Bytes>> {
+        let req = GossipsubFetchLastRequest {
+            peer_id,
+            topic_hash: topic.to_string(),
+        };
+        let res = self.client.rpc(req).await??;
+        Ok(res.data)
+    }
+
+    /// Retains `data` as the last message on `topic`, for peers fetching it with
+    /// [`P2pClient::gossipsub_fetch_last`], without publishing it.
+    #[tracing::instrument(skip(self, data))]
+    pub async fn gossipsub_retain(&self, topic: TopicHash, data: Bytes) -> Result<()> {
+        let req = GossipsubRetainRequest {
+            topic_hash: topic.to_string(),
+            data,
+        };
+        self.client.rpc(req).await??;
+        Ok(())
+    }
+
+    /// Stops retaining the message on `topic`, see [`P2pClient::gossipsub_retain`].
+    #[tracing::instrument(skip(self))]
+    pub async fn gossipsub_release(&self, topic: TopicHash) -> Result<()> {
+        let req = GossipsubReleaseRequest {
+            topic_hash: topic.to_string(),
+        };
+        self.client.rpc(req).await??;
+        Ok(())
+    }
+
+    /// Lists the peers that fetched the message retained on `topic` since it was retained,
+    /// see [`P2pClient::gossipsub_retain`].
+    #[tracing::instrument(skip(self))]
+    pub async fn gossipsub_fetched_by(&self, topic: TopicHash) -> Result<Vec<PeerId>> {
+        let req = GossipsubFetchedByRequest {
+            topic_hash: topic.to_string(),
+        };
+        let res = self.client.rpc(req).await??;
+        Ok(res.peers)
+    }
+
     #[tracing::instrument(skip(self))]
     pub async fn check(&self) -> (StatusType, String) {
         match self.version().await {
@@ -97,6 +97,15 @@ impl ClientStatus {
         }
     }
 
+    /// Returns the status of the service `typ`.
+    pub fn service(&self, typ: &ServiceType) -> &ServiceStatus {
+        match typ {
+            ServiceType::Gateway => &self.gateway,
+            ServiceType::P2p => &self.p2p,
+            ServiceType::Store => &self.store,
+        }
+    }
+
     pub fn update(&mut self, s: ServiceStatus) {
         match s.typ {
             ServiceType::Gateway => self.gateway = s,
@@ -174,6 +183,15 @@ mod tests {
         assert_eq!(expect, ClientStatus::default());
     }
 
+    #[test]
+    fn client_status_service() {
+        let p2p = ServiceStatus::new(ServiceType::P2p, StatusType::Serving, "0.1.0");
+        let table = ClientStatus::new(None, Some(p2p.clone()), None);
+        assert_eq!(table.service(&ServiceType::P2p), &p2p);
+        assert_eq!(table.service(&ServiceType::Store), &table.store);
+        assert_eq!(table.service(&ServiceType::Gateway), &table.gateway);
+    }
+
     #[test]
     fn status_table_new() {
         let expect = ClientStatus {
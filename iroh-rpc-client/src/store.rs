@@ -53,6 +53,12 @@ impl StoreClient {
         Ok(res.has)
     }
 
+    #[tracing::instrument(skip(self, cids))]
+    pub async fn has_many(&self, cids: Vec<Cid>) -> Result<Vec<bool>> {
+        let res = self.client.rpc(HasManyRequest { cids }).await??;
+        Ok(res.has)
+    }
+
     #[tracing::instrument(skip(self))]
     pub async fn get_links(&self, cid: Cid) -> Result<Option<Vec<Cid>>> {
         let res = self.client.rpc(GetLinksRequest { cid }).await??;
@@ -65,6 +71,83 @@ impl StoreClient {
         Ok(res.size)
     }
 
+    /// Forces all writes acknowledged so far to disk, see [`FlushRequest`].
+    #[tracing::instrument(skip(self))]
+    pub async fn flush(&self) -> Result<()> {
+        self.client.rpc(FlushRequest).await??;
+        Ok(())
+    }
+
+    /// Removes the blocks of the DAG at `root` that no other stored block links to, see
+    /// [`GcSubtreeRequest`].
+    #[tracing::instrument(skip(self))]
+    pub async fn gc_subtree(&self, root: Cid) -> Result<GcSubtreeResponse> {
+        let res = self.client.rpc(GcSubtreeRequest { root }).await??;
+        Ok(res)
+    }
+
+    /// Removes the block `cid` alone, returning whether it was stored, see
+    /// [`DeleteBlockRequest`].
+    #[tracing::instrument(skip(self))]
+    pub async fn delete_block(&self, cid: Cid) -> Result<bool> {
+        let res = self.client.rpc(DeleteBlockRequest { cid }).await??;
+        Ok(res.removed)
+    }
+
+    /// Pins `cid`, which must be in the store, see [`PinRequest`].
+    #[tracing::instrument(skip(self))]
+    pub async fn pin(&self, cid: Cid) -> Result<()> {
+        self.client.rpc(PinRequest { cid }).await??;
+        Ok(())
+    }
+
+    /// Removes the pin of `cid`, returning whether it was pinned.
+    #[tracing::instrument(skip(self))]
+    pub async fn unpin(&self, cid: Cid) -> Result<bool> {
+        let res = self.client.rpc(UnpinRequest { cid }).await??;
+        Ok(res.was_pinned)
+    }
+
+    /// Moves the pin of `old` to `new` at once, see [`PinUpdateRequest`].
+    #[tracing::instrument(skip(self))]
+    pub async fn pin_update(&self, old: Cid, new: Cid) -> Result<()> {
+        self.client.rpc(PinUpdateRequest { old, new }).await??;
+        Ok(())
+    }
+
+    /// Lists all pinned cids.
+    #[tracing::instrument(skip(self))]
+    pub async fn list_pins(&self) -> Result<Vec<Cid>> {
+        let res = self.client.rpc(ListPinsRequest).await??;
+        Ok(res.pins)
+    }
+
+    /// Streams the cids of all blocks in the store, in unspecified order.
+    #[tracing::instrument(skip(self))]
+    pub async fn list_cids(&self) -> Result<impl Stream<Item = Result<Cid>>> {
+        let res = self.client.server_streaming(ListCidsRequest).await?;
+        let cids = res.flat_map(|res| {
+            let cids: Vec<Result<Cid>> = match res {
+                Ok(Ok(res)) => res.cids.into_iter().map(Ok).collect(),
+                Ok(Err(err)) => vec![Err(err.into())],
+                Err(err) => vec![Err(err.into())],
+            };
+            futures::stream::iter(cids)
+        });
+        Ok(cids)
+    }
+
+    /// Verifies that the data of stored blocks hashes to their cids, streaming the
+    /// progress, see [`VerifyIntegrityRequest`].
+    #[tracing::instrument(skip(self))]
+    pub async fn verify_integrity(
+        &self,
+        req: VerifyIntegrityRequest,
+    ) -> Result<impl Stream<Item = Result<VerifyIntegrityResponse>>> {
+        let res = self.client.server_streaming(req).await?;
+        Ok(res.map(|res| Ok(res??)))
+    }
+
     #[tracing::instrument(skip(self))]
     pub async fn check(&self) -> (StatusType, String) {
         match self.version().await {
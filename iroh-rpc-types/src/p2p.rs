@@ -8,6 +8,7 @@ use quic_rpc::{
 };
 use serde::{Deserialize, Serialize};
 use std::collections::BTreeMap;
+use std::time::Duration;
 
 use crate::{RpcResult, VersionRequest, VersionResponse, WatchRequest, WatchResponse};
 
@@ -40,17 +41,93 @@ pub struct ListenersResponse {
     pub addrs: Vec<Multiaddr>,
 }
 
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RelaysRequest;
+
+/// The state of the reservation with a relay.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum RelayState {
+    /// Waiting for the relay to accept the reservation.
+    Pending,
+    /// The reservation is active.
+    Reserved,
+    /// The reservation failed or was lost, and is retried in `retry_in`.
+    Backoff { failures: u32, retry_in: Duration },
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RelaysResponse {
+    pub relays: Vec<(PeerId, RelayState)>,
+}
+
+/// A peer of the kademlia routing table, with its known addresses.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct RoutingPeer {
+    pub peer_id: PeerId,
+    pub addrs: Vec<Multiaddr>,
+}
+
+/// A provider record this node publishes to the DHT.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ProviderRecord {
+    pub key: Bytes,
+    pub provider: PeerId,
+    pub addrs: Vec<Multiaddr>,
+    /// Expiry of the record in seconds since the unix epoch, `None` if it does not expire.
+    pub expires: Option<u64>,
+}
+
+/// The kademlia routing table and the local provider records of a node.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct RoutingState {
+    pub peers: Vec<RoutingPeer>,
+    pub providers: Vec<ProviderRecord>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ExportRoutingStateRequest;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ExportRoutingStateResponse {
+    pub state: RoutingState,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ImportRoutingStateRequest {
+    pub state: RoutingState,
+}
+
+/// The outcome of importing a [`RoutingState`].
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct ImportRoutingStateResponse {
+    /// Number of peers added to the routing table.
+    pub peers: usize,
+    /// Number of provider records restored.
+    pub providers: usize,
+    /// Number of invalid or expired peers and records that were discarded.
+    pub discarded: usize,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct BitswapRequest {
     pub cid: Cid,
     pub providers: Vec<PeerId>,
     pub ctx: u64,
+    /// Wantlist priority of the request, higher is more urgent.
+    #[serde(default)]
+    pub priority: Option<i32>,
+    /// Maximum number of providers to search for the block.
+    #[serde(default)]
+    pub max_providers: Option<u64>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct BitswapResponse {
     pub data: Bytes,
     pub ctx: u64,
+    /// The peer the block was received from, if known.
+    #[serde(default)]
+    pub from: Option<PeerId>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -106,6 +183,47 @@ pub struct GetPeersResponse {
     pub peers: BTreeMap<PeerId, Vec<Multiaddr>>,
 }
 
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PeersInfoRequest;
+
+/// The state of the bitswap connection to a peer.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum BitswapPeerState {
+    /// Connected, but not yet confirmed to speak bitswap.
+    Connected,
+    /// Connected and speaking bitswap.
+    Responsive,
+    /// Connected, but not speaking bitswap.
+    Unresponsive,
+    Disconnected,
+    /// Dialing failed, the peer is not dialed again for a while.
+    DialFailure,
+    /// Dialing was refused, as the connection limit was reached.
+    ConnectionLimit,
+}
+
+/// What the node knows about a connected peer.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct PeerInfo {
+    pub peer_id: PeerId,
+    pub addrs: Vec<Multiaddr>,
+    /// The agent version announced via identify, `None` until identify completed.
+    pub agent_version: Option<String>,
+    /// The protocols announced via identify, empty until identify completed.
+    pub protocols: Vec<String>,
+    /// Approximated from the last ping.
+    pub latency: Option<Duration>,
+    /// `None` if bitswap is disabled or has no record of the peer.
+    pub bitswap_state: Option<BitswapPeerState>,
+    /// The bitswap protocol negotiated with the peer, if it speaks bitswap.
+    pub bitswap_protocol: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PeersInfoResponse {
+    pub peers: Vec<PeerInfo>,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ConnectRequest {
     pub peer_id: PeerId,
@@ -165,11 +283,69 @@ pub struct GossipsubAllPeersResponse {
     pub all: Vec<(PeerId, Vec<String>)>,
 }
 
+/// Requests the last message `peer_id` published or retained on `topic_hash` directly from
+/// it, over an existing connection.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GossipsubFetchLastRequest {
+    pub peer_id: PeerId,
+    pub topic_hash: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GossipsubFetchLastResponse {
+    /// `None` if the peer has not published on the topic.
+    pub data: Option<Bytes>,
+}
+
+/// Retains `data` as the last message on `topic_hash` for peers fetching it directly, see
+/// [`GossipsubFetchLastRequest`], without publishing it. Works without gossipsub.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GossipsubRetainRequest {
+    pub topic_hash: String,
+    pub data: Bytes,
+}
+
+/// Stops retaining the message on `topic_hash`, see [`GossipsubRetainRequest`].
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GossipsubReleaseRequest {
+    pub topic_hash: String,
+}
+
+/// Lists the peers that fetched the message retained on `topic_hash` since it was retained.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GossipsubFetchedByRequest {
+    pub topic_hash: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GossipsubFetchedByResponse {
+    pub peers: Vec<PeerId>,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct GossipsubMeshPeersRequest {
     pub topic_hash: String,
 }
 
+/// Streams the gossipsub messages received by the node, from all subscribed topics or only
+/// from `topic_hash`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GossipsubMessagesRequest {
+    pub topic_hash: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GossipsubMessagesResponse {
+    /// The peer the message was received from.
+    pub from: PeerId,
+    /// The peer that published the message, if known.
+    pub source: Option<PeerId>,
+    pub message_id: Bytes,
+    pub sequence_number: Option<u64>,
+    pub topic_hash: String,
+    pub data: Bytes,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct GossipsubPublishRequest {
     pub topic_hash: String,
@@ -225,6 +401,7 @@ pub enum P2pRequest {
     NotifyNewBlocksBitswap(NotifyNewBlocksBitswapRequest),
     GetListeningAddrs(GetListeningAddrsRequest),
     GetPeers(GetPeersRequest),
+    PeersInfo(PeersInfoRequest),
     PeerConnect(ConnectRequest),
     PeerConnectByPeerId(ConnectByPeerIdRequest),
     PeerDisconnect(DisconnectRequest),
@@ -234,16 +411,24 @@ pub enum P2pRequest {
     GossipsubAllMeshPeers(GossipsubAllMeshPeersRequest),
     GossipsubAllPeers(GossipsubAllPeersRequest),
     GossipsubMeshPeers(GossipsubMeshPeersRequest),
+    GossipsubMessages(GossipsubMessagesRequest),
     GossipsubPublish(GossipsubPublishRequest),
     GossipsubRemoveExplicitPeer(GossipsubRemoveExplicitPeerRequest),
     GossipsubSubscribe(GossipsubSubscribeRequest),
     GossipsubTopics(GossipsubTopicsRequest),
     GossipsubUnsubscribe(GossipsubUnsubscribeRequest),
+    GossipsubFetchLast(GossipsubFetchLastRequest),
+    GossipsubRetain(GossipsubRetainRequest),
+    GossipsubRelease(GossipsubReleaseRequest),
+    GossipsubFetchedBy(GossipsubFetchedByRequest),
     StartProviding(StartProvidingRequest),
     StopProviding(StopProvidingRequest),
     LocalPeerId(LocalPeerIdRequest),
     ExternalAddrs(ExternalAddrsRequest),
     Listeners(ListenersRequest),
+    Relays(RelaysRequest),
+    ExportRoutingState(ExportRoutingStateRequest),
+    ImportRoutingState(ImportRoutingStateRequest),
 }
 
 #[derive(Serialize, Deserialize, Debug, From, TryInto)]
@@ -254,16 +439,23 @@ pub enum P2pResponse {
     FetchProviderDht(RpcResult<FetchProvidersDhtResponse>),
     GetListeningAddrs(RpcResult<GetListeningAddrsResponse>),
     GetPeers(RpcResult<GetPeersResponse>),
+    PeersInfo(RpcResult<PeersInfoResponse>),
     Lookup(RpcResult<LookupResponse>),
     GossipsubPeers(RpcResult<GossipsubPeersResponse>),
     GossipsubAllPeers(RpcResult<GossipsubAllPeersResponse>),
+    GossipsubMessages(RpcResult<GossipsubMessagesResponse>),
     GossipsubPublish(RpcResult<GossipsubPublishResponse>),
     GossipsubSubscribe(RpcResult<GossipsubSubscribeResponse>),
     GossipsubTopics(RpcResult<GossipsubTopicsResponse>),
     GossipsubUnsubscribe(RpcResult<GossipsubUnsubscribeResponse>),
+    GossipsubFetchLast(RpcResult<GossipsubFetchLastResponse>),
+    GossipsubFetchedBy(RpcResult<GossipsubFetchedByResponse>),
     LocalPeerId(RpcResult<LocalPeerIdResponse>),
     ExternalAddrs(RpcResult<ExternalAddrsResponse>),
     Listeners(RpcResult<ListenersResponse>),
+    Relays(RpcResult<RelaysResponse>),
+    ExportRoutingState(RpcResult<ExportRoutingStateResponse>),
+    ImportRoutingState(RpcResult<ImportRoutingStateResponse>),
     UnitResult(RpcResult<()>),
 }
 
@@ -319,6 +511,10 @@ impl RpcMsg<P2pService> for GetPeersRequest {
     type Response = RpcResult<GetPeersResponse>;
 }
 
+impl RpcMsg<P2pService> for PeersInfoRequest {
+    type Response = RpcResult<PeersInfoResponse>;
+}
+
 impl RpcMsg<P2pService> for ConnectRequest {
     type Response = RpcResult<()>;
 }
@@ -355,6 +551,14 @@ impl RpcMsg<P2pService> for GossipsubAllPeersRequest {
     type Response = RpcResult<GossipsubAllPeersResponse>;
 }
 
+impl Msg<P2pService> for GossipsubMessagesRequest {
+    type Response = RpcResult<GossipsubMessagesResponse>;
+
+    type Update = Self;
+
+    type Pattern = ServerStreaming;
+}
+
 impl RpcMsg<P2pService> for GossipsubPublishRequest {
     type Response = RpcResult<GossipsubPublishResponse>;
 }
@@ -371,6 +575,22 @@ impl RpcMsg<P2pService> for GossipsubUnsubscribeRequest {
     type Response = RpcResult<GossipsubUnsubscribeResponse>;
 }
 
+impl RpcMsg<P2pService> for GossipsubFetchLastRequest {
+    type Response = RpcResult<GossipsubFetchLastResponse>;
+}
+
+impl RpcMsg<P2pService> for GossipsubRetainRequest {
+    type Response = RpcResult<()>;
+}
+
+impl RpcMsg<P2pService> for GossipsubReleaseRequest {
+    type Response = RpcResult<()>;
+}
+
+impl RpcMsg<P2pService> for GossipsubFetchedByRequest {
+    type Response = RpcResult<GossipsubFetchedByResponse>;
+}
+
 impl RpcMsg<P2pService> for GossipsubRemoveExplicitPeerRequest {
     type Response = RpcResult<()>;
 }
@@ -394,3 +614,15 @@ impl RpcMsg<P2pService> for ExternalAddrsRequest {
 impl RpcMsg<P2pService> for ListenersRequest {
     type Response = RpcResult<ListenersResponse>;
 }
+
+impl RpcMsg<P2pService> for RelaysRequest {
+    type Response = RpcResult<RelaysResponse>;
+}
+
+impl RpcMsg<P2pService> for ExportRoutingStateRequest {
+    type Response = RpcResult<ExportRoutingStateResponse>;
+}
+
+impl RpcMsg<P2pService> for ImportRoutingStateRequest {
+    type Response = RpcResult<ImportRoutingStateResponse>;
+}
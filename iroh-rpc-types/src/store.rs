@@ -55,6 +55,16 @@ pub struct HasResponse {
     pub has: bool,
 }
 
+#[derive(Serialize, Deserialize, Debug)]
+pub struct HasManyRequest {
+    pub cids: Vec<Cid>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct HasManyResponse {
+    pub has: Vec<bool>,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct GetLinksRequest {
     pub cid: Cid,
@@ -75,6 +85,116 @@ pub struct GetSizeResponse {
     pub size: Option<u64>,
 }
 
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ListCidsRequest;
+
+/// Forces all writes the store acknowledged so far to disk.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FlushRequest;
+
+/// Checks that the data of stored blocks hashes to their cids.
+///
+/// Progress is streamed as [`VerifyIntegrityResponse`]s, dropping the stream interrupts
+/// the verification.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct VerifyIntegrityRequest {
+    /// Fraction of the blocks to verify, from `0.0` to `1.0`, which verifies all of them.
+    pub sample_rate: f64,
+    /// Continues an interrupted verification, see [`VerifyIntegrityResponse::last_id`].
+    pub resume_after: Option<u64>,
+    /// Removes the data of corrupt blocks, so they are fetched again when needed.
+    pub quarantine: bool,
+}
+
+/// Progress of a [`VerifyIntegrityRequest`].
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct VerifyIntegrityResponse {
+    /// Number of stored blocks scanned so far, including those that were not sampled.
+    pub scanned: u64,
+    /// Number of blocks whose data was hashed and compared to their cid so far.
+    pub verified: u64,
+    /// Number of sampled blocks that could not be verified so far.
+    pub unverifiable: u64,
+    /// Corrupt blocks found since the previous response.
+    pub corrupt: Vec<Cid>,
+    /// The id of the last scanned block, to resume from.
+    pub last_id: Option<u64>,
+    /// Whether all blocks were scanned, only set on the last response.
+    pub complete: bool,
+}
+
+/// Removes the blocks of the DAG at `root` that no other stored block links to.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GcSubtreeRequest {
+    pub root: Cid,
+}
+
+/// Result of a [`GcSubtreeRequest`].
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct GcSubtreeResponse {
+    /// Number of stored blocks in the DAG.
+    pub reachable: u64,
+    /// Number of blocks that were removed, the others are still linked to from outside of
+    /// the DAG.
+    pub removed: u64,
+    /// Size of the data of the removed blocks.
+    pub removed_bytes: u64,
+}
+
+/// Removes the block `cid` alone, without looking at the blocks it links to or that link
+/// to it. Fails if the block is pinned.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DeleteBlockRequest {
+    pub cid: Cid,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DeleteBlockResponse {
+    /// Whether the block was stored.
+    pub removed: bool,
+}
+
+/// Pins `cid`, so garbage collection keeps it and everything it links to.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PinRequest {
+    pub cid: Cid,
+}
+
+/// Removes the pin of `cid`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct UnpinRequest {
+    pub cid: Cid,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct UnpinResponse {
+    /// Whether `cid` was pinned.
+    pub was_pinned: bool,
+}
+
+/// Moves the pin of `old` to `new` at once.
+///
+/// Fails, without changing any pin, if `old` is not pinned or `new` is not in the store.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PinUpdateRequest {
+    pub old: Cid,
+    pub new: Cid,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ListPinsRequest;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ListPinsResponse {
+    pub pins: Vec<Cid>,
+}
+
+/// A batch of the cids listed by [`ListCidsRequest`].
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ListCidsResponse {
+    pub cids: Vec<Cid>,
+}
+
 #[derive(Serialize, Deserialize, Debug, From, TryInto)]
 pub enum StoreRequest {
     Watch(WatchRequest),
@@ -83,8 +203,18 @@ pub enum StoreRequest {
     PutMany(PutManyRequest),
     Get(GetRequest),
     Has(HasRequest),
+    HasMany(HasManyRequest),
     GetLinks(GetLinksRequest),
     GetSize(GetSizeRequest),
+    ListCids(ListCidsRequest),
+    Flush(FlushRequest),
+    VerifyIntegrity(VerifyIntegrityRequest),
+    GcSubtree(GcSubtreeRequest),
+    Pin(PinRequest),
+    Unpin(UnpinRequest),
+    PinUpdate(PinUpdateRequest),
+    ListPins(ListPinsRequest),
+    DeleteBlock(DeleteBlockRequest),
 }
 
 #[derive(Serialize, Deserialize, Debug, From, TryInto)]
@@ -93,10 +223,17 @@ pub enum StoreResponse {
     Version(VersionResponse),
     Get(RpcResult<GetResponse>),
     Has(RpcResult<HasResponse>),
+    HasMany(RpcResult<HasManyResponse>),
     GetLinks(RpcResult<GetLinksResponse>),
     GetSize(RpcResult<GetSizeResponse>),
+    ListCids(RpcResult<ListCidsResponse>),
+    VerifyIntegrity(RpcResult<VerifyIntegrityResponse>),
+    GcSubtree(RpcResult<GcSubtreeResponse>),
+    Unpin(RpcResult<UnpinResponse>),
+    ListPins(RpcResult<ListPinsResponse>),
     Unit(()),
     UnitResult(RpcResult<()>),
+    DeleteBlock(RpcResult<DeleteBlockResponse>),
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -132,6 +269,10 @@ impl RpcMsg<StoreService> for HasRequest {
     type Response = RpcResult<HasResponse>;
 }
 
+impl RpcMsg<StoreService> for HasManyRequest {
+    type Response = RpcResult<HasManyResponse>;
+}
+
 impl RpcMsg<StoreService> for PutManyRequest {
     type Response = RpcResult<()>;
 }
@@ -143,3 +284,47 @@ impl RpcMsg<StoreService> for GetLinksRequest {
 impl RpcMsg<StoreService> for GetSizeRequest {
     type Response = RpcResult<GetSizeResponse>;
 }
+
+impl RpcMsg<StoreService> for FlushRequest {
+    type Response = RpcResult<()>;
+}
+
+impl RpcMsg<StoreService> for GcSubtreeRequest {
+    type Response = RpcResult<GcSubtreeResponse>;
+}
+
+impl RpcMsg<StoreService> for DeleteBlockRequest {
+    type Response = RpcResult<DeleteBlockResponse>;
+}
+
+impl RpcMsg<StoreService> for PinRequest {
+    type Response = RpcResult<()>;
+}
+
+impl RpcMsg<StoreService> for UnpinRequest {
+    type Response = RpcResult<UnpinResponse>;
+}
+
+impl RpcMsg<StoreService> for PinUpdateRequest {
+    type Response = RpcResult<()>;
+}
+
+impl RpcMsg<StoreService> for ListPinsRequest {
+    type Response = RpcResult<ListPinsResponse>;
+}
+
+impl Msg<StoreService> for ListCidsRequest {
+    type Response = RpcResult<ListCidsResponse>;
+
+    type Update = Self;
+
+    type Pattern = ServerStreaming;
+}
+
+impl Msg<StoreService> for VerifyIntegrityRequest {
+    type Response = RpcResult<VerifyIntegrityResponse>;
+
+    type Update = Self;
+
+    type Pattern = ServerStreaming;
+}
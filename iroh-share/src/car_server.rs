@@ -0,0 +1,152 @@
+use std::net::{IpAddr, SocketAddr};
+
+use anyhow::{Context, Result};
+use axum::{
+    body::StreamBody,
+    extract::{Path, State},
+    http::{header, StatusCode},
+    response::IntoResponse,
+    routing::get,
+    Router,
+};
+use cid::Cid;
+use iroh_resolver::resolver::{Path as IpfsPath, Resolver};
+use libp2p::multiaddr::Protocol;
+use libp2p::Multiaddr;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tokio_util::io::ReaderStream;
+use tracing::{debug, warn};
+
+use crate::p2p_node::Loader;
+
+/// Serves the content of a transfer as a CAR file over HTTP, for receivers that do not
+/// speak libp2p, such as browsers.
+///
+/// The content is only available under `/ipfs/{root}`, other cids are not served.
+#[derive(Debug)]
+pub(crate) struct CarServer {
+    root: Cid,
+    local_addr: SocketAddr,
+    shutdown: oneshot::Sender<()>,
+    task: JoinHandle<Result<()>>,
+}
+
+#[derive(Debug, Clone)]
+struct CarState {
+    root: Cid,
+    resolver: Resolver<Loader>,
+}
+
+impl CarServer {
+    pub(crate) fn new(addr: SocketAddr, root: Cid, resolver: Resolver<Loader>) -> Result<Self> {
+        let app = Router::new()
+            .route("/ipfs/:cid", get(get_car))
+            .with_state(CarState { root, resolver });
+        let server = axum::Server::try_bind(&addr)?.serve(app.into_make_service());
+        let local_addr = server.local_addr();
+        debug!("serving {} as car on {}", root, local_addr);
+
+        let (shutdown, shutdown_receiver) = oneshot::channel();
+        let server = server.with_graceful_shutdown(async move {
+            shutdown_receiver.await.ok();
+        });
+        let task = tokio::task::spawn(async move { Ok(server.await?) });
+
+        Ok(CarServer {
+            root,
+            local_addr,
+            shutdown,
+            task,
+        })
+    }
+
+    /// The url the CAR file can be downloaded from.
+    ///
+    /// If the server is bound to an unspecified ip, such as `0.0.0.0`, the url uses the
+    /// first ip of the same family in `addrs` instead, which should be ranked by how likely
+    /// receivers can reach them.
+    pub(crate) fn url(&self, addrs: &[Multiaddr]) -> Result<String> {
+        let addr = reachable_addr(self.local_addr, addrs)
+            .with_context(|| format!("no reachable ip to serve http on {}", self.local_addr))?;
+        Ok(format!("http://{}/ipfs/{}", addr, self.root))
+    }
+
+    /// Stops accepting requests and waits for running downloads to finish.
+    pub(crate) async fn close(self) -> Result<()> {
+        self.shutdown.send(()).ok();
+        self.task.await??;
+        Ok(())
+    }
+}
+
+async fn get_car(
+    State(state): State<CarState>,
+    Path(cid): Path<String>,
+) -> Result<impl IntoResponse, StatusCode> {
+    match cid.parse::<Cid>() {
+        Ok(cid) if cid == state.root => {}
+        _ => return Err(StatusCode::NOT_FOUND),
+    }
+
+    let (writer, reader) = tokio::io::duplex(64 * 1024);
+    tokio::task::spawn(async move {
+        let blocks = state
+            .resolver
+            .resolve_recursive_raw(IpfsPath::from_cid(state.root), None);
+        if let Err(err) = iroh_resolver::car::write_car(blocks, writer).await {
+            warn!("failed to write car for {}: {:?}", state.root, err);
+        }
+    });
+
+    Ok((
+        [(header::CONTENT_TYPE, "application/vnd.ipld.car")],
+        StreamBody::new(ReaderStream::new(reader)),
+    ))
+}
+
+/// Replaces an unspecified ip of `local` with the first ip of the same family in `addrs`.
+fn reachable_addr(mut local: SocketAddr, addrs: &[Multiaddr]) -> Option<SocketAddr> {
+    if local.ip().is_unspecified() {
+        let ip = addrs
+            .iter()
+            .filter_map(|addr| match addr.iter().next() {
+                Some(Protocol::Ip4(ip)) => Some(IpAddr::from(ip)),
+                Some(Protocol::Ip6(ip)) => Some(IpAddr::from(ip)),
+                _ => None,
+            })
+            .find(|ip| ip.is_ipv4() == local.is_ipv4() && !ip.is_unspecified())?;
+        local.set_ip(ip);
+    }
+    Some(local)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reachable_addr() {
+        let addrs: Vec<Multiaddr> = vec![
+            "/dns4/example.com/tcp/4444".parse().unwrap(),
+            "/ip6/2001:db8::1/tcp/4444".parse().unwrap(),
+            "/ip4/203.0.113.1/tcp/4444".parse().unwrap(),
+            "/ip4/127.0.0.1/tcp/4444".parse().unwrap(),
+        ];
+
+        let local: SocketAddr = "127.0.0.1:8080".parse().unwrap();
+        assert_eq!(reachable_addr(local, &addrs), Some(local));
+
+        let any: SocketAddr = "0.0.0.0:8080".parse().unwrap();
+        assert_eq!(
+            reachable_addr(any, &addrs),
+            Some("203.0.113.1:8080".parse().unwrap())
+        );
+        let any: SocketAddr = "[::]:8080".parse().unwrap();
+        assert_eq!(
+            reachable_addr(any, &addrs),
+            Some("[2001:db8::1]:8080".parse().unwrap())
+        );
+        assert_eq!(reachable_addr(any, &addrs[2..]), None);
+    }
+}
@@ -1,13 +1,104 @@
+mod car_server;
 mod p2p_node;
 mod receiver;
 mod sender;
 
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use anyhow::{ensure, Result};
+use bincode::Options;
 use cid::Cid;
-use serde::{Deserialize, Serialize};
+use iroh_metrics::{core::MRecorder, inc, p2p::P2PMetrics};
+use libp2p::PeerId;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tracing::warn;
+
+pub use crate::p2p_node::{
+    AddressFamily, NodeOptions, StoreBackend, StoreOptions, StoreRecovery, Ticket, TransferMode,
+    DEFAULT_STORE_LOCK_TIMEOUT, DEFAULT_TOPIC_PREFIX,
+};
+pub use crate::receiver::{
+    Backpressure, ProgressEvent, Receiver, ReconnectPolicy, Transfer as ReceiverTransfer,
+};
+pub use crate::sender::{
+    add_and_share, announce, select_ticket_addrs, AnnounceLimit, Announcement, Sender,
+    Transfer as SenderTransfer, TransferGroups, TransferState, ANNOUNCE_TIMEOUT,
+    DEFAULT_GOSSIP_CAPACITY, DEFAULT_MAX_TICKET_ADDRS, DEFAULT_WRITE_QUEUE,
+    MAX_ACTIVE_ANNOUNCEMENTS,
+};
+
+/// The error a transfer fails with once it exceeded its timeout, see
+/// [`Sender::with_timeout`] and [`Receiver::with_timeout`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timeout(pub Duration);
+
+impl fmt::Display for Timeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "transfer timed out after {:?}", self.0)
+    }
+}
+
+impl std::error::Error for Timeout {}
+
+/// The error a transfer fails with once its group was cancelled, see
+/// [`TransferGroups::cancel_group`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "transfer was cancelled")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+/// The default of how often gossip events dropped by a transfer are logged, see
+/// [`Sender::with_drop_log_interval`] and [`Receiver::with_drop_log_interval`].
+pub const DEFAULT_DROP_LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Reports gossip events dropped because they were not processed in time.
+///
+/// Each drop is counted in the `dropped_gossip_event` metric, but logged at most once per
+/// `interval`, together with the number of drops since the previous log line, so a burst
+/// of drops does not flood the log.
+#[derive(Debug)]
+struct DropLog {
+    interval: Duration,
+    last_logged: Option<Instant>,
+    suppressed: usize,
+}
 
-pub use crate::p2p_node::Ticket;
-pub use crate::receiver::{ProgressEvent, Receiver, Transfer as ReceiverTransfer};
-pub use crate::sender::{Sender, Transfer as SenderTransfer};
+impl DropLog {
+    fn new(interval: Duration) -> Self {
+        DropLog {
+            interval,
+            last_logged: None,
+            suppressed: 0,
+        }
+    }
+
+    /// Records that a gossip event of `kind` from `peer` was dropped, returning whether it
+    /// was logged.
+    fn dropped(&mut self, kind: &str, peer: PeerId) -> bool {
+        inc!(P2PMetrics::DroppedGossipEvent);
+        let now = Instant::now();
+        if let Some(last_logged) = self.last_logged {
+            if now.duration_since(last_logged) < self.interval {
+                self.suppressed += 1;
+                return false;
+            }
+        }
+        warn!(
+            "gossip event buffer is full, dropped {} from {} ({} more since last report)",
+            kind, peer, self.suppressed
+        );
+        self.last_logged = Some(now);
+        self.suppressed = 0;
+        true
+    }
+}
 
 /// Messages sent from the sender.
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -29,11 +120,61 @@ enum ReceiverMessage {
     FinishError(String),
 }
 
+/// Upper bound of the encoded size of [`SenderMessage`]s and [`ReceiverMessage`]s, well
+/// below the default gossipsub max transmit size, so they are never dropped for their size.
+const MAX_CONTROL_MESSAGE_SIZE: usize = 8 * 1024;
+
+/// Errors sent with [`ReceiverMessage::FinishError`] are truncated to this many bytes.
+const MAX_ERROR_MESSAGE_LEN: usize = 4 * 1024;
+
+impl ReceiverMessage {
+    /// A [`ReceiverMessage::FinishError`], with `error` truncated to
+    /// [`MAX_ERROR_MESSAGE_LEN`].
+    fn finish_error(mut error: String) -> Self {
+        if error.len() > MAX_ERROR_MESSAGE_LEN {
+            let mut len = MAX_ERROR_MESSAGE_LEN;
+            while !error.is_char_boundary(len) {
+                len -= 1;
+            }
+            error.truncate(len);
+        }
+        ReceiverMessage::FinishError(error)
+    }
+}
+
+/// The bincode options of control messages.
+///
+/// Integers keep the fixed size encoding of `bincode::serialize`, so peers of earlier
+/// versions still understand the messages. The limit keeps a malformed length prefix from
+/// making the receiver allocate more than [`MAX_CONTROL_MESSAGE_SIZE`].
+fn control_message_options() -> impl Options {
+    bincode::options()
+        .with_fixint_encoding()
+        .with_limit(MAX_CONTROL_MESSAGE_SIZE as u64)
+}
+
+fn encode_control_message<T: Serialize>(message: &T) -> Result<Vec<u8>> {
+    Ok(control_message_options().serialize(message)?)
+}
+
+/// Decodes a control message received from a peer, rejecting oversized and malformed
+/// messages, including those with trailing bytes.
+fn decode_control_message<T: DeserializeOwned>(data: &[u8]) -> Result<T> {
+    ensure!(
+        data.len() <= MAX_CONTROL_MESSAGE_SIZE,
+        "control message of {} bytes exceeds the limit of {} bytes",
+        data.len(),
+        MAX_CONTROL_MESSAGE_SIZE
+    );
+    Ok(control_message_options().deserialize(data)?)
+}
+
 #[cfg(test)]
 mod tests {
     use std::time::Duration;
 
     use super::*;
+    use crate::p2p_node::P2pNode;
     use anyhow::{Context, Result};
     use bytes::Bytes;
     use futures::TryStreamExt;
@@ -55,6 +196,214 @@ mod tests {
         transfer_file().await.context("file")?;
         tokio::time::sleep(Duration::from_secs(1)).await;
         transfer_dir().await.context("dir")?;
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        transfer_timeout().await.context("timeout")?;
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        transfer_min_subscribers()
+            .await
+            .context("min subscribers")?;
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        transfer_direct().await.context("direct")?;
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        transfer_handoff().await.context("handoff")?;
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        transfer_handoff_attached()
+            .await
+            .context("handoff attached")?;
+        Ok(())
+    }
+
+    async fn transfer_handoff_attached() -> Result<()> {
+        println!("---- HANDOFF ATTACHED ----");
+        // a node serving senders of other processes over rpc
+        let node_dir = tempfile::tempdir().unwrap();
+        let (node, _events) =
+            P2pNode::new(9990, &node_dir.path().join("db"), NodeOptions::default())
+                .await
+                .context("node")?;
+
+        let sender = s::Sender::attach(node.rpc_config().clone(), TransferMode::Gossip)
+            .await
+            .context("s: attach")?;
+        let sender_transfer = sender
+            .transfer_from_data("foo.txt", Bytes::from_static(b"hello"))
+            .await
+            .context("s: transfer")?;
+        let state = sender_transfer.handoff().await.context("s: handoff")?;
+
+        // another sender serves the transfer through the same node, which kept running
+        let sender = s::Sender::attach(node.rpc_config().clone(), TransferMode::Gossip)
+            .await
+            .context("s: attach")?;
+        let sender_transfer = sender.resume_transfer(state).await.context("s: resume")?;
+
+        let receiver_dir = tempfile::tempdir().unwrap();
+        let receiver = r::Receiver::new(9991, &receiver_dir.path().join("db"))
+            .await
+            .context("r: new")?;
+        let mut receiver_transfer = receiver
+            .transfer_from_ticket(sender_transfer.ticket())
+            .await
+            .context("r: transfer")?;
+        let mut buf = Vec::new();
+        receiver_transfer.recv_to_writer(&mut buf).await?;
+        assert_eq!(&buf, b"hello");
+
+        sender_transfer.done().await?;
+        receiver_transfer.finish().await?;
+        node.close().await?;
+        Ok(())
+    }
+
+    async fn transfer_handoff() -> Result<()> {
+        println!("---- HANDOFF ----");
+        let sender_dir = tempfile::tempdir().unwrap();
+        let sender_db = sender_dir.path().join("db");
+        let sender = s::Sender::new(9990, &sender_db).await.context("s:new")?;
+        let sender_transfer = sender
+            .transfer_from_data("foo.txt", Bytes::from_static(b"hello"))
+            .await
+            .context("s: transfer")?;
+        let state = sender_transfer.handoff().await.context("s: handoff")?;
+        assert_eq!(state.name.as_deref(), Some("foo.txt"));
+
+        // the state is serialized, and resumed by another sender on the same store
+        let state: TransferState = bincode::deserialize(&bincode::serialize(&state)?)?;
+        let sender = s::Sender::new(9990, &sender_db).await.context("s:new")?;
+        let sender_transfer = sender
+            .resume_transfer(state.clone())
+            .await
+            .context("s: resume")?;
+        assert_eq!(sender_transfer.state(), &state);
+        assert_eq!(sender_transfer.ticket().topic, state.topic);
+
+        let receiver_dir = tempfile::tempdir().unwrap();
+        let receiver = r::Receiver::new(9991, &receiver_dir.path().join("db"))
+            .await
+            .context("r: new")?;
+        let mut receiver_transfer = receiver
+            .transfer_from_ticket(sender_transfer.ticket())
+            .await
+            .context("r: transfer")?;
+        let mut buf = Vec::new();
+        receiver_transfer.recv_to_writer(&mut buf).await?;
+        assert_eq!(&buf, b"hello");
+
+        sender_transfer.done().await?;
+        receiver_transfer.finish().await?;
+
+        // content that is not in the store can not be resumed
+        let other_dir = tempfile::tempdir().unwrap();
+        let sender = s::Sender::new(9990, &other_dir.path().join("db"))
+            .await
+            .context("s:new")?;
+        assert!(sender.resume_transfer(state).await.is_err());
+        Ok(())
+    }
+
+    async fn transfer_direct() -> Result<()> {
+        println!("---- DIRECT ----");
+        let sender_dir = tempfile::tempdir().unwrap();
+        let direct = NodeOptions {
+            transfer_mode: TransferMode::Direct,
+            ..Default::default()
+        };
+        let sender = s::Sender::with_options(9990, &sender_dir.path().join("db"), direct)
+            .await
+            .context("s:new")?;
+        let sender_transfer = sender
+            .transfer_from_data("foo.txt", Bytes::from_static(b"hello"))
+            .await
+            .context("s: transfer")?;
+
+        let receiver_dir = tempfile::tempdir().unwrap();
+        let receiver = r::Receiver::with_options(9991, &receiver_dir.path().join("db"), direct)
+            .await
+            .context("r: new")?;
+        let mut receiver_transfer = receiver
+            .transfer_from_ticket(sender_transfer.ticket())
+            .await
+            .context("r: transfer")?;
+
+        let mut buf = Vec::new();
+        let written = receiver_transfer.recv_to_writer(&mut buf).await?;
+        assert_eq!(written, 5);
+        assert_eq!(&buf, b"hello");
+        // only the receiver, which fetched the start message, is counted
+        assert_eq!(sender_transfer.subscriber_count().await?, 1);
+
+        // the sender learns about the outcome without any gossip
+        let (done, finished) = tokio::join!(sender_transfer.done(), receiver_transfer.finish());
+        done.context("s: done")?;
+        finished.context("r: finish")?;
+        Ok(())
+    }
+
+    async fn transfer_min_subscribers() -> Result<()> {
+        println!("---- MIN SUBSCRIBERS ----");
+        let wait = Duration::from_secs(3);
+        let sender_dir = tempfile::tempdir().unwrap();
+        let sender = s::Sender::new(9990, &sender_dir.path().join("db"))
+            .await
+            .context("s:new")?
+            .with_min_subscribers(2, wait);
+        let started = std::time::Instant::now();
+        let sender_transfer = sender
+            .transfer_from_data("foo.txt", Bytes::from_static(b"hello"))
+            .await
+            .context("s: transfer")?;
+
+        let receiver_dir = tempfile::tempdir().unwrap();
+        let receiver = r::Receiver::new(9991, &receiver_dir.path().join("db"))
+            .await
+            .context("r: new")?;
+        let mut receiver_transfer = receiver
+            .transfer_from_ticket(sender_transfer.ticket())
+            .await
+            .context("r: transfer")?;
+
+        // a single receiver only gets the root once the sender stopped waiting for another
+        assert!(receiver_transfer.try_recv()?.is_none());
+        receiver_transfer.recv().await.context("r: recv")?;
+        assert!(started.elapsed() >= wait);
+
+        sender_transfer.done().await?;
+        receiver_transfer.finish().await?;
+        Ok(())
+    }
+
+    async fn transfer_timeout() -> Result<()> {
+        println!("---- TIMEOUT ----");
+        let sender_dir = tempfile::tempdir().unwrap();
+        let sender = s::Sender::new(9990, &sender_dir.path().join("db"))
+            .await
+            .context("s:new")?
+            .with_timeout(Duration::from_millis(500));
+        let sender_transfer = sender
+            .transfer_from_data("foo.txt", Bytes::from_static(b"hello"))
+            .await
+            .context("s: transfer")?;
+        // nobody announces on this topic, so the receiver never starts
+        let mut ticket = sender_transfer.ticket().clone();
+        ticket.topic = s::transfer_topic(DEFAULT_TOPIC_PREFIX, rand::random())
+            .hash()
+            .to_string();
+
+        let receiver_dir = tempfile::tempdir().unwrap();
+        let receiver = r::Receiver::new(9991, &receiver_dir.path().join("db"))
+            .await
+            .context("r: new")?
+            .with_timeout(Duration::from_millis(500));
+        let mut receiver_transfer = receiver
+            .transfer_from_ticket(&ticket)
+            .await
+            .context("r: transfer")?;
+        let err = receiver_transfer.recv().await.unwrap_err();
+        assert!(err.is::<Timeout>(), "{err:?}");
+        receiver_transfer.finish().await?;
+
+        let err = sender_transfer.done().await.unwrap_err();
+        assert!(err.is::<Timeout>(), "{err:?}");
         Ok(())
     }
 
@@ -63,7 +412,10 @@ mod tests {
         let sender_dir = tempfile::tempdir().unwrap();
         let sender_db = sender_dir.path().join("db");
 
-        let sender = s::Sender::new(9990, &sender_db).await.context("s:new")?;
+        let sender = s::Sender::new(9990, &sender_db)
+            .await
+            .context("s:new")?
+            .with_write_queue(4);
         let mut bytes = vec![0u8; 5 * 1024 * 1024 - 8];
         rand::thread_rng().fill_bytes(&mut bytes);
         let bytes = Bytes::from(bytes);
@@ -134,7 +486,11 @@ mod tests {
         let sender_dir = tempfile::tempdir().unwrap();
         let sender_db = sender_dir.path().join("db");
 
-        let sender = s::Sender::new(9990, &sender_db).await.context("s:new")?;
+        let sender = s::Sender::new(9990, &sender_db)
+            .await
+            .context("s:new")?
+            .with_topic_prefix("iroh-share-test")
+            .with_http_addr("127.0.0.1:0".parse().unwrap());
 
         let file_1 = FileBuilder::new()
             .name("bar.txt")
@@ -160,26 +516,56 @@ mod tests {
             .transfer_from_dir_builder(dir_builder)
             .await
             .context("s: transfer")?;
-        let ticket = sender_transfer.ticket();
+        let mut ticket = sender_transfer.ticket().clone();
+        assert_eq!(ticket.topic_prefix, "iroh-share-test");
+        // an unreachable replica must not keep the receiver from the sender
+        let replica = libp2p::PeerId::random();
+        ticket
+            .providers
+            .push((replica, vec!["/ip4/127.0.0.1/tcp/1".parse()?]));
+
+        {
+            println!("downloading car");
+            let url = ticket.http_url.as_ref().context("missing http url")?;
+            let car = reqwest::get(url).await?.error_for_status()?.bytes().await?;
+            let car = iroh_car::CarReader::new(&car[..]).await?;
+            let root = car.header().roots()[0];
+            assert!(url.ends_with(&format!("/ipfs/{root}")));
+            let blocks: Vec<_> = car.stream().try_collect().await?;
+            assert_eq!(blocks[0].0, root);
+            assert!(blocks
+                .iter()
+                .all(|(cid, data)| iroh_util::verify_hash(cid, data) == Some(true)));
+        }
 
         // the ticket is serialized, shared with the receiver and deserialized there
         let receiver_dir = tempfile::tempdir().unwrap();
         let receiver_db = receiver_dir.path().join("db");
         let receiver = r::Receiver::new(9991, &receiver_db)
             .await
-            .context("r: new")?;
+            .context("r: new")?
+            .with_topic_prefix("iroh-share-test");
 
         // tries to discover the sender, and receive the root
         let mut receiver_transfer = receiver
-            .transfer_from_ticket(ticket)
+            .transfer_from_ticket(&ticket)
             .await
             .context("r: transfer")?;
 
         let data = receiver_transfer.recv().await.context("r: recv")?;
         assert!(data.is_dir());
+        assert_eq!(sender_transfer.subscriber_count().await?, 1);
 
         let files: Vec<_> = data.read_dir()?.unwrap().try_collect().await?;
         assert_eq!(files.len(), 2);
+        {
+            println!("resolving bar.txt");
+            let file = receiver_transfer.resolve_path("bar.txt").await?;
+            assert!(file.is_file());
+            let mut file_content = Vec::new();
+            file.pretty()?.read_to_end(&mut file_content).await?;
+            assert_eq!(&file_content, b"bar");
+        }
         {
             println!("reading file bar.txt");
             assert_eq!(files[0].name.as_ref().unwrap(), "bar.txt");
@@ -201,6 +587,20 @@ mod tests {
             assert_eq!(&file_content, &bytes);
         }
 
+        {
+            println!("writing the dir");
+            let dest = receiver_dir.path().join("foo");
+            receiver_transfer.write_to(&dest).await?;
+            assert_eq!(tokio::fs::read(dest.join("bar.txt")).await?, b"bar");
+            assert_eq!(tokio::fs::read(dest.join("baz.txt")).await?, bytes);
+            assert!(receiver_transfer.write_to(&dest).await.is_err());
+            // the staged content was moved, nothing is left next to it
+            for entry in std::fs::read_dir(receiver_dir.path())? {
+                let name = entry?.file_name();
+                assert!(!name.to_string_lossy().starts_with(".iroh-share-"));
+            }
+        }
+
         // Check progress
         {
             let progress = receiver_transfer.progress()?;
@@ -263,4 +663,59 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_drop_log() {
+        let peer = PeerId::random();
+        let mut log = DropLog::new(Duration::from_secs(60 * 60));
+        assert!(log.dropped("message", peer));
+        assert!(!log.dropped("message", peer));
+        assert!(!log.dropped("subscription", peer));
+        assert_eq!(log.suppressed, 2);
+
+        // without an interval every drop is logged
+        let mut log = DropLog::new(Duration::ZERO);
+        assert!(log.dropped("message", peer));
+        assert!(log.dropped("message", peer));
+        assert_eq!(log.suppressed, 0);
+    }
+
+    #[test]
+    fn test_control_messages() -> Result<()> {
+        let root = Cid::default();
+        let start = encode_control_message(&SenderMessage::Start { root, num_parts: 3 })?;
+        // the encoding of earlier versions
+        assert_eq!(
+            start,
+            bincode::serialize(&SenderMessage::Start { root, num_parts: 3 })?
+        );
+        match decode_control_message(&start)? {
+            SenderMessage::Start { root: r, num_parts } => {
+                assert_eq!((r, num_parts), (root, 3));
+            }
+        }
+
+        // malformed, trailing bytes, oversized and lying about its length
+        assert!(decode_control_message::<SenderMessage>(b"garbage").is_err());
+        let mut trailing = start.clone();
+        trailing.push(0);
+        assert!(decode_control_message::<SenderMessage>(&trailing).is_err());
+        let oversized = vec![0u8; MAX_CONTROL_MESSAGE_SIZE + 1];
+        assert!(decode_control_message::<ReceiverMessage>(&oversized).is_err());
+        let mut huge_error = 1u32.to_le_bytes().to_vec();
+        huge_error.extend(u64::MAX.to_le_bytes());
+        assert!(decode_control_message::<ReceiverMessage>(&huge_error).is_err());
+
+        // long errors are truncated to fit
+        let msg = ReceiverMessage::finish_error("ü".repeat(MAX_ERROR_MESSAGE_LEN));
+        let decoded = decode_control_message(&encode_control_message(&msg)?)?;
+        match decoded {
+            ReceiverMessage::FinishError(error) => {
+                assert!(error.len() <= MAX_ERROR_MESSAGE_LEN);
+                assert!(error.starts_with('ü'));
+            }
+            ReceiverMessage::FinishOk => panic!("expected an error"),
+        }
+        Ok(())
+    }
 }
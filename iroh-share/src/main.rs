@@ -1,15 +1,31 @@
+use std::net::SocketAddr;
 use std::path::PathBuf;
 
 use anyhow::{ensure, Context, Result};
 use clap::{Parser, Subcommand};
 use futures::stream::StreamExt;
-use iroh_share::{ProgressEvent, Receiver, Sender, Ticket};
+use iroh_share::{
+    AddressFamily, NodeOptions, ProgressEvent, Receiver, Sender, StoreBackend, StoreOptions,
+    StoreRecovery, Ticket, TransferMode, DEFAULT_TOPIC_PREFIX,
+};
 use tokio::io::AsyncWriteExt;
 use tracing_subscriber::{fmt, prelude::*, EnvFilter};
 
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
 struct Args {
+    /// The address family to listen on: ipv4, ipv6 or dual
+    #[clap(long, default_value_t = AddressFamily::DualStack)]
+    address_family: AddressFamily,
+    /// Path of the block store. Uses a temporary directory, removed on exit, if not set
+    #[clap(long)]
+    db: Option<PathBuf>,
+    /// Prefix of the gossipsub topic, sender and receiver must use the same one
+    #[clap(long, default_value = DEFAULT_TOPIC_PREFIX)]
+    topic_prefix: String,
+    /// How the transfer is negotiated: gossip, or direct between peers on the same network
+    #[clap(long, default_value_t = TransferMode::Gossip)]
+    mode: TransferMode,
     #[clap(subcommand)]
     command: Commands,
 }
@@ -21,6 +37,21 @@ enum Commands {
     Send {
         /// The data to send
         path: PathBuf,
+        /// Size of the store's block cache in bytes
+        #[clap(long)]
+        cache_size: Option<usize>,
+        /// Maximum number of files the store keeps open
+        #[clap(long)]
+        max_open_files: Option<i32>,
+        /// What to do if the store can not be opened: fail, repair or recreate
+        #[clap(long, default_value_t = StoreRecovery::Fail)]
+        recover_store: StoreRecovery,
+        /// Where the store keeps the data: persistent, at the store path, or memory
+        #[clap(long, default_value_t = StoreBackend::Persistent)]
+        store_backend: StoreBackend,
+        /// Also serve the data as a CAR file over HTTP on this address
+        #[clap(long)]
+        http_addr: Option<SocketAddr>,
     },
     /// Receives data
     #[clap(arg_required_else_help = true)]
@@ -30,6 +61,9 @@ enum Commands {
         /// Where to write the received data to
         #[clap(long)]
         out: Option<PathBuf>,
+        /// Only connect to the sender and providers listed in the ticket
+        #[clap(long)]
+        ticket_peers_only: bool,
     },
 }
 
@@ -43,17 +77,39 @@ async fn main() -> Result<()> {
     let args = Args::parse();
 
     match args.command {
-        Commands::Send { path } => {
+        Commands::Send {
+            path,
+            cache_size,
+            max_open_files,
+            recover_store,
+            store_backend,
+            http_addr,
+        } => {
             println!("Sending: {}", path.display());
 
-            // TODO: allow db specification
-            let sender_dir = tempfile::tempdir().unwrap();
-            let sender_db = sender_dir.path().join("db");
+            let (_sender_dir, sender_db) = db_path(args.db)?;
 
             let port = 9990;
-            let sender = Sender::new(port, &sender_db)
+            let store = StoreOptions {
+                cache_size,
+                max_open_files,
+                recovery: recover_store,
+                backend: store_backend,
+                lock_timeout: None,
+            };
+            let options = NodeOptions {
+                family: args.address_family,
+                store,
+                transfer_mode: args.mode,
+                ..Default::default()
+            };
+            let mut sender = Sender::with_options(port, &sender_db, options)
                 .await
-                .context("failed to create sender")?;
+                .context("failed to create sender")?
+                .with_topic_prefix(args.topic_prefix);
+            if let Some(addr) = http_addr {
+                sender = sender.with_http_addr(addr);
+            }
 
             ensure!(path.exists(), "provided file does not exist");
             ensure!(path.is_file(), "currently only supports files");
@@ -76,21 +132,34 @@ async fn main() -> Result<()> {
             let ticket_bytes = ticket.as_bytes();
             let ticket_str = multibase::encode(multibase::Base::Base64, &ticket_bytes);
             println!("Ticket:\n{ticket_str}\n");
+            if let Some(ref url) = ticket.http_url {
+                println!("CAR download:\n{url}\n");
+            }
             sender_transfer.done().await?;
         }
-        Commands::Receive { ticket, out } => {
+        Commands::Receive {
+            ticket,
+            out,
+            ticket_peers_only,
+        } => {
             println!("Receiving");
 
             let (_, ticket_bytes) = multibase::decode(ticket)?;
             let ticket = Ticket::from_bytes(&ticket_bytes)?;
 
-            let sender_dir = tempfile::tempdir().unwrap();
-            let sender_db = sender_dir.path().join("db");
+            let (_receiver_dir, receiver_db) = db_path(args.db)?;
 
             let port = 9991;
-            let receiver = Receiver::new(port, &sender_db)
+            let options = NodeOptions {
+                family: args.address_family,
+                transfer_mode: args.mode,
+                ..Default::default()
+            };
+            let receiver = Receiver::with_options(port, &receiver_db, options)
                 .await
-                .context("failed to create sender")?;
+                .context("failed to create receiver")?
+                .with_topic_prefix(args.topic_prefix)
+                .with_ticket_peers_only(ticket_peers_only);
             let mut receiver_transfer = receiver
                 .transfer_from_ticket(&ticket)
                 .await
@@ -144,3 +213,17 @@ async fn main() -> Result<()> {
 
     Ok(())
 }
+
+/// Returns the store path to use, creating a temporary directory if none is given.
+///
+/// The temporary directory is removed once the returned guard is dropped.
+fn db_path(db: Option<PathBuf>) -> Result<(Option<tempfile::TempDir>, PathBuf)> {
+    match db {
+        Some(db) => Ok((None, db)),
+        None => {
+            let dir = tempfile::tempdir()?;
+            let db = dir.path().join("db");
+            Ok((Some(dir), db))
+        }
+    }
+}
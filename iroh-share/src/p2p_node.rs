@@ -1,9 +1,23 @@
-use std::{collections::HashSet, path::Path, sync::Arc};
+use std::{
+    collections::HashSet,
+    fmt,
+    net::Ipv6Addr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
 
-use anyhow::{ensure, Result};
+use anyhow::{anyhow, bail, ensure, Context, Result};
 use async_trait::async_trait;
+use axum::http::Uri;
+use bincode::Options;
 use cid::Cid;
-use iroh_p2p::{config, Config, Keychain, MemoryStorage, NetworkEvent, Node};
+use fs2::FileExt;
+use futures::StreamExt;
+use iroh_p2p::{
+    config, Config, GossipsubEvent, Keychain, MemoryStorage, NetworkEvent, Node, PeerAllowList,
+};
 use iroh_resolver::resolver::Resolver;
 use iroh_rpc_client::Client;
 use iroh_rpc_types::Addr;
@@ -11,17 +25,149 @@ use iroh_unixfs::{
     content_loader::{ContentLoader, ContextId, LoaderContext, IROH_STORE},
     parse_links, LoadedCid, Source,
 };
-use libp2p::{Multiaddr, PeerId};
-use serde::{Deserialize, Serialize};
-use tokio::sync::mpsc::Receiver;
-use tokio::{sync::Mutex, task::JoinHandle};
-use tracing::{error, warn};
+use libp2p::{multiaddr::Protocol, Multiaddr, PeerId};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::sync::mpsc::{channel, Receiver};
+use tokio::{
+    sync::Mutex,
+    task::{JoinError, JoinHandle},
+    time::Instant,
+};
+use tracing::{debug, error, info, warn};
+
+use crate::sender::DEFAULT_GOSSIP_CAPACITY;
+
+/// The default prefix of the gossipsub topics transfers are negotiated on.
+pub const DEFAULT_TOPIC_PREFIX: &str = "iroh-share";
+
+/// How long [`P2pNode::new`] waits for the p2p node and the store to serve rpc.
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often [`P2pNode::new`] checks whether the p2p node and the store serve rpc.
+const STARTUP_POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Capacity of the channel of events of a node attached over rpc, see [`P2pNode::attach`].
+const ATTACHED_EVENTS_CAPACITY: usize = 512;
+
+/// How long to wait for a store in use by another node, see [`StoreOptions::lock_timeout`].
+pub const DEFAULT_STORE_LOCK_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How often to check whether a store in use by another node was released.
+const STORE_LOCK_RETRY_INTERVAL: Duration = Duration::from_millis(100);
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub struct Ticket {
     pub peer_id: PeerId,
     pub addrs: Vec<Multiaddr>,
     pub topic: String,
+    /// The prefix `topic` was created with, receivers must be configured with the same one.
+    pub topic_prefix: String,
+    /// Where the content can be downloaded as a CAR file, without libp2p, if the sender
+    /// serves it over HTTP.
+    pub http_url: Option<String>,
+    /// Further providers of the content, e.g. replicas, tried in order after the sender.
+    pub providers: Vec<(PeerId, Vec<Multiaddr>)>,
+    /// The target of the transfer.
+    ///
+    /// If `None`, the sender announces a fixed root on `topic`. Otherwise this is an IPNS
+    /// name, e.g. a DNSLink domain, which receivers resolve themselves, and can resolve
+    /// again later to pick up a newer version, see [`crate::ReceiverTransfer::refresh`].
+    pub ipns_name: Option<String>,
+}
+
+/// A [`Ticket`] as serialized before it could name an IPNS target.
+#[derive(Debug, Deserialize)]
+struct AnnouncedTicket {
+    peer_id: PeerId,
+    addrs: Vec<Multiaddr>,
+    topic: String,
+    topic_prefix: String,
+    http_url: Option<String>,
+    providers: Vec<(PeerId, Vec<Multiaddr>)>,
+}
+
+impl From<AnnouncedTicket> for Ticket {
+    fn from(ticket: AnnouncedTicket) -> Self {
+        Ticket {
+            peer_id: ticket.peer_id,
+            addrs: ticket.addrs,
+            topic: ticket.topic,
+            topic_prefix: ticket.topic_prefix,
+            http_url: ticket.http_url,
+            providers: ticket.providers,
+            ipns_name: None,
+        }
+    }
+}
+
+/// A [`Ticket`] as serialized before it carried further providers.
+#[derive(Debug, Deserialize)]
+struct SingleProviderTicket {
+    peer_id: PeerId,
+    addrs: Vec<Multiaddr>,
+    topic: String,
+    topic_prefix: String,
+    http_url: Option<String>,
+}
+
+impl From<SingleProviderTicket> for Ticket {
+    fn from(ticket: SingleProviderTicket) -> Self {
+        Ticket {
+            peer_id: ticket.peer_id,
+            addrs: ticket.addrs,
+            topic: ticket.topic,
+            topic_prefix: ticket.topic_prefix,
+            http_url: ticket.http_url,
+            providers: Vec::new(),
+            ipns_name: None,
+        }
+    }
+}
+
+/// A [`Ticket`] as serialized before it carried an http url.
+#[derive(Debug, Deserialize)]
+struct PrefixedTicket {
+    peer_id: PeerId,
+    addrs: Vec<Multiaddr>,
+    topic: String,
+    topic_prefix: String,
+}
+
+impl From<PrefixedTicket> for Ticket {
+    fn from(ticket: PrefixedTicket) -> Self {
+        Ticket {
+            peer_id: ticket.peer_id,
+            addrs: ticket.addrs,
+            topic: ticket.topic,
+            topic_prefix: ticket.topic_prefix,
+            http_url: None,
+            providers: Vec::new(),
+            ipns_name: None,
+        }
+    }
+}
+
+/// A [`Ticket`] as serialized before its topic prefix was configurable, always using
+/// [`DEFAULT_TOPIC_PREFIX`].
+#[derive(Debug, Deserialize)]
+struct UnprefixedTicket {
+    peer_id: PeerId,
+    addrs: Vec<Multiaddr>,
+    topic: String,
+}
+
+impl From<UnprefixedTicket> for Ticket {
+    fn from(ticket: UnprefixedTicket) -> Self {
+        Ticket {
+            peer_id: ticket.peer_id,
+            addrs: ticket.addrs,
+            topic: ticket.topic,
+            topic_prefix: DEFAULT_TOPIC_PREFIX.to_string(),
+            http_url: None,
+            providers: Vec::new(),
+            ipns_name: None,
+        }
+    }
 }
 
 impl Ticket {
@@ -29,20 +175,222 @@ impl Ticket {
         bincode::serialize(self).expect("failed to serialize")
     }
 
+    /// Deserializes a ticket, also accepting tickets of earlier versions, without further
+    /// providers, an IPNS target, an http url or a topic prefix.
+    ///
+    /// Each layout has to span all of `bytes`, so that a truncated or corrupted ticket is
+    /// rejected rather than read as an earlier version with fields missing.
     pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
-        let ticket = bincode::deserialize(bytes)?;
-        Ok(ticket)
+        let err = match decode_exact(bytes) {
+            Ok(ticket) => return Ok(ticket),
+            Err(err) => err,
+        };
+        if let Ok(ticket) = decode_exact::<AnnouncedTicket>(bytes) {
+            return Ok(ticket.into());
+        }
+        if let Ok(ticket) = decode_exact::<SingleProviderTicket>(bytes) {
+            return Ok(ticket.into());
+        }
+        if let Ok(ticket) = decode_exact::<PrefixedTicket>(bytes) {
+            return Ok(ticket.into());
+        }
+        if let Ok(ticket) = decode_exact::<UnprefixedTicket>(bytes) {
+            return Ok(ticket.into());
+        }
+        Err(err.into())
     }
+
+    /// All providers of the content, in the order receivers try them, starting with the
+    /// sender.
+    pub fn all_providers(&self) -> impl Iterator<Item = (PeerId, &[Multiaddr])> {
+        std::iter::once((self.peer_id, &self.addrs[..])).chain(
+            self.providers
+                .iter()
+                .map(|(peer_id, addrs)| (*peer_id, &addrs[..])),
+        )
+    }
+
+    /// Checks that the ticket is well-formed, before anything is dialed or subscribed.
+    ///
+    /// The peer ids and addresses are already parsed when the ticket is deserialized, so
+    /// this checks that they are consistent: addresses naming a peer must name the one
+    /// they are listed for, and there must be at least one address to dial. The topic
+    /// must be the hash of a transfer topic. Tickets carry no root CID, the root is
+    /// announced on the topic and verified when it is received.
+    pub fn validate(&self) -> Result<()> {
+        ensure!(!self.topic_prefix.is_empty(), "empty topic prefix");
+        ensure!(!self.topic.is_empty(), "empty topic");
+        let hash = multibase::Base::Base64Pad
+            .decode(&self.topic)
+            .ok()
+            .filter(|hash| hash.len() == 32);
+        ensure!(
+            hash.is_some(),
+            "topic {:?} is not a transfer topic",
+            self.topic
+        );
+
+        for (peer_id, addrs) in self.all_providers() {
+            for addr in addrs {
+                ensure!(!addr.is_empty(), "empty address for peer {}", peer_id);
+                for protocol in addr.iter() {
+                    if let Protocol::P2p(hash) = protocol {
+                        let addr_peer = PeerId::from_multihash(hash)
+                            .map_err(|_| anyhow!("invalid peer id in address {}", addr))?;
+                        ensure!(
+                            addr_peer == peer_id,
+                            "address {} of peer {} names peer {}",
+                            addr,
+                            peer_id,
+                            addr_peer
+                        );
+                    }
+                }
+            }
+        }
+        ensure!(
+            self.all_providers().any(|(_, addrs)| !addrs.is_empty()),
+            "no addresses to connect to"
+        );
+
+        if let Some(url) = &self.http_url {
+            let uri: Uri = url
+                .parse()
+                .with_context(|| format!("invalid http url {url:?}"))?;
+            ensure!(
+                matches!(uri.scheme_str(), Some("http" | "https")) && uri.host().is_some(),
+                "invalid http url {:?}",
+                url
+            );
+        }
+        if let Some(name) = &self.ipns_name {
+            ensure!(!name.is_empty(), "empty ipns name");
+        }
+        Ok(())
+    }
+}
+
+/// Deserializes `bytes` in the format of [`bincode::serialize`], failing if any are left over.
+fn decode_exact<T: DeserializeOwned>(bytes: &[u8]) -> bincode::Result<T> {
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .reject_trailing_bytes()
+        .deserialize(bytes)
+}
+
+/// The IP address families the node listens on.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum AddressFamily {
+    Ipv4,
+    Ipv6,
+    /// Listen on both IPv4 and IPv6, if IPv6 is available on this host.
+    #[default]
+    DualStack,
+}
+
+impl AddressFamily {
+    /// The multiaddrs to listen on for the given port.
+    pub fn listening_multiaddrs(&self, port: u16) -> Vec<Multiaddr> {
+        let ip4 = format!("/ip4/0.0.0.0/tcp/{port}").parse().unwrap();
+        let ip6 = format!("/ip6/::/tcp/{port}").parse().unwrap();
+        match self {
+            AddressFamily::Ipv4 => vec![ip4],
+            AddressFamily::Ipv6 => vec![ip6],
+            AddressFamily::DualStack => {
+                if ipv6_available() {
+                    vec![ip4, ip6]
+                } else {
+                    vec![ip4]
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Display for AddressFamily {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AddressFamily::Ipv4 => write!(f, "ipv4"),
+            AddressFamily::Ipv6 => write!(f, "ipv6"),
+            AddressFamily::DualStack => write!(f, "dual"),
+        }
+    }
+}
+
+impl FromStr for AddressFamily {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "ipv4" | "ip4" => Ok(AddressFamily::Ipv4),
+            "ipv6" | "ip6" => Ok(AddressFamily::Ipv6),
+            "dual" | "dualstack" => Ok(AddressFamily::DualStack),
+            _ => bail!("invalid address family: {}", s),
+        }
+    }
+}
+
+/// How the root of a transfer is announced to receivers, and their completion reported back.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum TransferMode {
+    /// Over a gossipsub topic, with the DHT, relays and NAT detection enabled so that peers
+    /// can also reach each other across networks.
+    #[default]
+    Gossip,
+    /// Directly between the sender and the receivers, which dial the addresses of the
+    /// ticket. Gossipsub, the DHT, relays, NAT detection and mDNS are disabled, which keeps
+    /// point-to-point transfers, e.g. on a LAN, free of any other moving parts.
+    Direct,
+}
+
+impl fmt::Display for TransferMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransferMode::Gossip => write!(f, "gossip"),
+            TransferMode::Direct => write!(f, "direct"),
+        }
+    }
+}
+
+impl FromStr for TransferMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "gossip" => Ok(TransferMode::Gossip),
+            "direct" => Ok(TransferMode::Direct),
+            _ => bail!("invalid transfer mode: {}", s),
+        }
+    }
+}
+
+/// Checks if this host can bind to an IPv6 socket.
+fn ipv6_available() -> bool {
+    std::net::TcpListener::bind((Ipv6Addr::UNSPECIFIED, 0)).is_ok()
 }
 
 #[derive(Debug)]
 pub struct P2pNode {
-    p2p_task: JoinHandle<()>,
-    store_task: JoinHandle<()>,
+    services: Services,
     rpc: Client,
+    rpc_config: iroh_rpc_client::Config,
     resolver: Resolver<Loader>,
 }
 
+/// Where the p2p node and the store of a [`P2pNode`] run.
+#[derive(Debug)]
+enum Services {
+    /// In this process, started by [`P2pNode::new`].
+    Local {
+        p2p_task: JoinHandle<Result<()>>,
+        store_task: JoinHandle<Result<()>>,
+        allow_list: PeerAllowList,
+    },
+    /// In another process, see [`P2pNode::attach`]. Its gossipsub messages are forwarded
+    /// as events by `events_task`.
+    Remote { events_task: JoinHandle<()> },
+}
+
 /// Wrapper struct to implement custom content loading
 #[derive(Debug, Clone)]
 pub struct Loader {
@@ -65,20 +413,24 @@ impl Loader {
 
 #[async_trait]
 impl ContentLoader for Loader {
-    async fn load_cid(&self, cid: &Cid, _ctx: &LoaderContext) -> Result<LoadedCid> {
+    async fn load_cid(&self, cid: &Cid, ctx: &LoaderContext) -> Result<LoadedCid> {
         let cid = *cid;
         let providers = self.providers.lock().await.clone();
 
-        match self.client.try_store()?.get(cid).await {
-            Ok(Some(data)) => {
-                return Ok(LoadedCid {
-                    data,
-                    source: Source::Store(IROH_STORE),
-                });
-            }
-            Ok(None) => {}
-            Err(err) => {
-                warn!("failed to fetch data from store {}: {:?}", cid, err);
+        if ctx.force_refresh() {
+            debug!("{:?} force refresh, skipping store for {}", ctx.id(), cid);
+        } else {
+            match self.client.try_store()?.get(cid).await {
+                Ok(Some(data)) => {
+                    return Ok(LoadedCid {
+                        data,
+                        source: Source::Store(IROH_STORE),
+                    });
+                }
+                Ok(None) => {}
+                Err(err) => {
+                    warn!("failed to fetch data from store {}: {:?}", cid, err);
+                }
             }
         }
 
@@ -88,10 +440,10 @@ impl ContentLoader for Loader {
         let res = self
             .client
             .try_p2p()?
-            .fetch_bitswap(0, cid, providers.clone())
+            .fetch_bitswap_with_origin(0, cid, providers.clone(), None, None)
             .await;
-        let bytes = match res {
-            Ok(bytes) => bytes,
+        let (bytes, from) = match res {
+            Ok(res) => res,
             Err(err) => {
                 error!("Bitswap error: {:#?}", err);
                 return Err(err);
@@ -112,7 +464,7 @@ impl ContentLoader for Loader {
 
         Ok(LoadedCid {
             data: bytes,
-            source: Source::Bitswap,
+            source: Source::Bitswap(from),
         })
     }
 
@@ -129,28 +481,312 @@ impl ContentLoader for Loader {
     }
 }
 
+/// How the p2p node and the store of a [`Sender`](crate::Sender) or
+/// [`Receiver`](crate::Receiver) are set up.
+///
+/// Start from the defaults and set what differs, e.g.
+/// `NodeOptions { transfer_mode: TransferMode::Direct, ..Default::default() }`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NodeOptions {
+    /// The IP address families to listen on.
+    pub family: AddressFamily,
+    /// Tuning of the store.
+    pub store: StoreOptions,
+    /// How transfers are announced and their completion reported back.
+    pub transfer_mode: TransferMode,
+    /// How many gossipsub events of each kind a sender buffers, defaults to
+    /// [`DEFAULT_GOSSIP_CAPACITY`]. Unused by receivers.
+    ///
+    /// Events that are not processed in time are dropped once the buffer is full, see
+    /// [`Sender::with_drop_log_interval`](crate::Sender::with_drop_log_interval).
+    /// Subscription changes, which detect receivers, are buffered apart from messages and
+    /// are processed first, so a burst of messages never drops them.
+    pub gossip_capacity: usize,
+}
+
+impl Default for NodeOptions {
+    fn default() -> Self {
+        Self {
+            family: AddressFamily::default(),
+            store: StoreOptions::default(),
+            transfer_mode: TransferMode::default(),
+            gossip_capacity: DEFAULT_GOSSIP_CAPACITY,
+        }
+    }
+}
+
+/// Tuning of the database backing the local store.
+///
+/// Unset values keep the defaults of [`iroh_store::Config`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct StoreOptions {
+    /// Size of the block cache in bytes, defaults to [`iroh_store::config::DEFAULT_CACHE_SIZE`].
+    ///
+    /// Lower this on memory constrained devices, raise it when sharing large content
+    /// from machines with plenty of memory.
+    pub cache_size: Option<usize>,
+    /// Maximum number of files the database keeps open, unlimited by default.
+    pub max_open_files: Option<i32>,
+    /// How to recover if the existing database can not be opened.
+    pub recovery: StoreRecovery,
+    /// Where the database is kept, on disk at the database path by default.
+    pub backend: StoreBackend,
+    /// How long to wait for the database to be released by other nodes, defaults to
+    /// [`DEFAULT_STORE_LOCK_TIMEOUT`].
+    ///
+    /// A database is in use by one node at a time, until that node is closed or its process
+    /// exits. Waiting for it lets a node start while a previous one is still shutting down.
+    pub lock_timeout: Option<Duration>,
+}
+
+/// Where the local store keeps its blocks.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum StoreBackend {
+    /// A database at the given path, which is reused if it exists.
+    #[default]
+    Persistent,
+    /// A database that is only kept in memory, avoiding disk I/O for one-shot transfers.
+    /// Its content is lost when the node is closed.
+    Memory,
+}
+
+impl fmt::Display for StoreBackend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StoreBackend::Persistent => write!(f, "persistent"),
+            StoreBackend::Memory => write!(f, "memory"),
+        }
+    }
+}
+
+impl FromStr for StoreBackend {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "persistent" => Ok(StoreBackend::Persistent),
+            "memory" => Ok(StoreBackend::Memory),
+            _ => bail!("invalid store backend: {}", s),
+        }
+    }
+}
+
+/// How to recover from a database that can not be opened, e.g. because it was corrupted
+/// by an unclean shutdown.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum StoreRecovery {
+    /// Fail to start.
+    #[default]
+    Fail,
+    /// Repair the database, recreating it if that fails.
+    Repair,
+    /// Move the database to a backup directory and create a new, empty one.
+    Recreate,
+}
+
+impl fmt::Display for StoreRecovery {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StoreRecovery::Fail => write!(f, "fail"),
+            StoreRecovery::Repair => write!(f, "repair"),
+            StoreRecovery::Recreate => write!(f, "recreate"),
+        }
+    }
+}
+
+impl FromStr for StoreRecovery {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "fail" => Ok(StoreRecovery::Fail),
+            "repair" => Ok(StoreRecovery::Repair),
+            "recreate" => Ok(StoreRecovery::Recreate),
+            _ => bail!("invalid store recovery: {}", s),
+        }
+    }
+}
+
+/// Creates the store with the backend of `options`, opening the existing one at
+/// `config.path` if it is persistent.
+///
+/// A persistent store is returned with its [`StoreLock`], which must be held as long as
+/// the store is in use.
+async fn create_store(
+    config: iroh_store::Config,
+    options: StoreOptions,
+) -> Result<(iroh_store::Store, Option<StoreLock>)> {
+    if options.backend == StoreBackend::Memory {
+        let store = iroh_store::Store::create_in_memory(config).await?;
+        return Ok((store, None));
+    }
+
+    let timeout = options.lock_timeout.unwrap_or(DEFAULT_STORE_LOCK_TIMEOUT);
+    let lock = StoreLock::acquire(&config.path, Instant::now() + timeout).await?;
+    // an interrupted creation leaves a directory without a database behind
+    let store = if config.path.join("CURRENT").exists() {
+        open_store(config, options.recovery).await?
+    } else {
+        iroh_store::Store::create(config).await?
+    };
+    Ok((store, Some(lock)))
+}
+
+/// Opens the existing store at `config.path`, recovering from failures as configured.
+///
+/// Only a corrupt database is recovered, never one that fails to open otherwise, e.g.
+/// because a process that does not take the [`StoreLock`] holds it.
+async fn open_store(
+    config: iroh_store::Config,
+    recovery: StoreRecovery,
+) -> Result<iroh_store::Store> {
+    let path = config.path.clone();
+    let err = match iroh_store::Store::open(config.clone()).await {
+        Ok(store) => return Ok(store),
+        Err(err) => err,
+    };
+    if !iroh_store::Store::is_corruption(&err) {
+        return Err(err).with_context(|| format!("failed to open store {}", path.display()));
+    }
+
+    match recovery {
+        StoreRecovery::Fail => {
+            return Err(err).with_context(|| format!("failed to open store {}", path.display()));
+        }
+        StoreRecovery::Repair => {
+            warn!(
+                "failed to open store {}: {:?}, repairing it",
+                path.display(),
+                err
+            );
+            let repaired = match iroh_store::Store::repair(config.clone()).await {
+                Ok(()) => iroh_store::Store::open(config.clone()).await,
+                Err(err) => Err(err),
+            };
+            match repaired {
+                Ok(store) => {
+                    warn!("repaired store {}", path.display());
+                    return Ok(store);
+                }
+                Err(err) => {
+                    warn!("failed to repair store {}: {:?}", path.display(), err);
+                }
+            }
+        }
+        StoreRecovery::Recreate => {
+            warn!("failed to open store {}: {:?}", path.display(), err);
+        }
+    }
+
+    let backup = backup_path(&path);
+    tokio::fs::rename(&path, &backup)
+        .await
+        .with_context(|| format!("failed to back up store {}", path.display()))?;
+    warn!(
+        "moved store {} to {}, recreating it empty",
+        path.display(),
+        backup.display()
+    );
+    let store = iroh_store::Store::create(config).await?;
+    info!("recreated store {}", path.display());
+    Ok(store)
+}
+
+/// Keeps nodes in this and other processes from using the store at a path at the same time.
+///
+/// An advisory lock on a file next to the database, taken before the database is opened
+/// or created and held until dropped. The operating system releases it when its holder
+/// exits, also if it crashes, so the file itself is left in place.
+#[derive(Debug)]
+struct StoreLock {
+    _file: std::fs::File,
+}
+
+impl StoreLock {
+    /// Acquires the lock of the store at `db_path`, waiting for other holders until
+    /// `deadline`.
+    async fn acquire(db_path: &Path, deadline: Instant) -> Result<Self> {
+        let mut name = db_path.file_name().unwrap_or_default().to_os_string();
+        name.push(".lock");
+        let path = db_path.with_file_name(name);
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let file = tokio::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(&path)
+            .await
+            .with_context(|| format!("failed to open lock {}", path.display()))?
+            .into_std()
+            .await;
+
+        loop {
+            match file.try_lock_exclusive() {
+                Ok(()) => return Ok(StoreLock { _file: file }),
+                Err(err) if err.kind() == fs2::lock_contended_error().kind() => {}
+                Err(err) => {
+                    return Err(err).with_context(|| format!("failed to lock {}", path.display()))
+                }
+            }
+            ensure!(
+                Instant::now() < deadline,
+                "store {} is in use by another node",
+                db_path.display()
+            );
+            debug!("store {} is in use, waiting for it", db_path.display());
+            tokio::time::sleep(STORE_LOCK_RETRY_INTERVAL).await;
+        }
+    }
+}
+
+/// The first unused path to back up the store at `path` to.
+fn backup_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".corrupt");
+    let mut backup = path.with_file_name(&name);
+    let mut i = 1;
+    while backup.exists() {
+        let mut numbered = name.clone();
+        numbered.push(format!(".{i}"));
+        backup = path.with_file_name(numbered);
+        i += 1;
+    }
+    backup
+}
+
 impl P2pNode {
-    pub async fn new(port: u16, db_path: &Path) -> Result<(Self, Receiver<NetworkEvent>)> {
+    /// Starts a p2p node and a store, and waits until both serve rpc.
+    ///
+    /// Fails if either does not start, e.g. because the store can not be opened, instead
+    /// of returning a node whose services are dead. Errors binding the listening
+    /// addresses are returned as well.
+    ///
+    /// In [`TransferMode::Direct`] only the protocols needed to exchange messages and
+    /// blocks with directly dialed peers are enabled.
+    pub async fn new(
+        port: u16,
+        db_path: &Path,
+        options: NodeOptions,
+    ) -> Result<(Self, Receiver<NetworkEvent>)> {
         let rpc_p2p_addr_server = Addr::new_mem();
         let rpc_p2p_addr_client = rpc_p2p_addr_server.clone();
         let rpc_store_addr_server = Addr::new_mem();
         let rpc_store_addr_client = rpc_store_addr_server.clone();
 
-        let rpc_store_client_config = iroh_rpc_client::Config {
-            p2p_addr: Some(rpc_p2p_addr_client.clone()),
-            store_addr: Some(rpc_store_addr_client.clone()),
-            gateway_addr: None,
-            channels: Some(1),
-        };
-        let rpc_p2p_client_config = iroh_rpc_client::Config {
-            p2p_addr: Some(rpc_p2p_addr_client.clone()),
-            store_addr: Some(rpc_store_addr_client.clone()),
+        // The p2p node and the store each serve rpc on their own in-memory address. Every
+        // client, the one of this node as well as the ones the p2p node and the store use to
+        // reach each other, e.g. for the p2p node to serve blocks from the store, dials the p2p
+        // service at `rpc_p2p_addr` and the store at `rpc_store_addr`. There is no gateway.
+        let rpc_client_config = iroh_rpc_client::Config {
+            p2p_addr: Some(rpc_p2p_addr_client),
+            store_addr: Some(rpc_store_addr_client),
             gateway_addr: None,
             channels: Some(1),
         };
         let mut libp2p_config = config::Libp2pConfig::default();
-        libp2p_config.listening_multiaddrs =
-            vec![format!("/ip4/0.0.0.0/tcp/{port}").parse().unwrap()];
+        libp2p_config.listening_multiaddrs = options.family.listening_multiaddrs(port);
         libp2p_config.mdns = false;
         libp2p_config.kademlia = true;
         libp2p_config.autonat = true;
@@ -159,48 +795,120 @@ impl P2pNode {
         libp2p_config.relay_server = false;
         libp2p_config.max_conns_in = 8;
         libp2p_config.max_conns_out = 8;
+        if options.transfer_mode == TransferMode::Direct {
+            libp2p_config.gossipsub = false;
+            libp2p_config.kademlia = false;
+            libp2p_config.autonat = false;
+            libp2p_config.relay_client = false;
+        }
         let config = Config {
             libp2p: libp2p_config,
-            rpc_client: rpc_p2p_client_config.clone(),
+            rpc_client: rpc_client_config.clone(),
             key_store_path: db_path.parent().unwrap().to_path_buf(),
         };
 
-        let rpc = Client::new(rpc_p2p_client_config).await?;
+        let rpc = Client::new(rpc_client_config.clone()).await?;
         let loader = Loader::new(rpc.clone());
         let resolver = iroh_resolver::resolver::Resolver::new(loader);
 
         let store_config = iroh_store::Config {
             path: db_path.to_path_buf(),
-            rpc_client: rpc_store_client_config,
+            rpc_client: rpc_client_config.clone(),
+            cache_size: options.store.cache_size,
+            max_open_files: options.store.max_open_files,
+            verify_on_start: None,
         };
 
-        let store = if store_config.path.exists() {
-            iroh_store::Store::open(store_config).await?
-        } else {
-            iroh_store::Store::create(store_config).await?
-        };
+        let (store, store_lock) = create_store(store_config, options.store).await?;
 
         let kc = Keychain::<MemoryStorage>::new();
         let mut p2p = Node::new(config, rpc_p2p_addr_server, kc).await?;
         let events = p2p.network_events();
+        let allow_list = p2p.peer_allow_list();
 
-        let p2p_task = tokio::task::spawn(async move {
-            if let Err(err) = p2p.run().await {
+        let mut p2p_task = tokio::task::spawn(async move {
+            let res = p2p.run().await;
+            if let Err(err) = &res {
                 error!("{:?}", err);
             }
+            res
         });
 
-        let store_task = tokio::spawn(async move {
-            iroh_store::rpc::new(rpc_store_addr_server, store)
-                .await
-                .unwrap()
+        let mut store_task = tokio::spawn(async move {
+            // the store stays in use as long as it is served
+            let _store_lock = store_lock;
+            let res = iroh_store::rpc::new(rpc_store_addr_server, store).await;
+            if let Err(err) = &res {
+                error!("store rpc: {:?}", err);
+            }
+            res
         });
 
+        if let Err(err) = wait_until_ready(&rpc, &mut p2p_task, &mut store_task).await {
+            p2p_task.abort();
+            store_task.abort();
+            return Err(err);
+        }
+
         Ok((
             Self {
-                p2p_task,
-                store_task,
+                services: Services::Local {
+                    p2p_task,
+                    store_task,
+                    allow_list,
+                },
+                rpc,
+                rpc_config: rpc_client_config,
+                resolver,
+            },
+            events,
+        ))
+    }
+
+    /// Uses the p2p node and the store of another process, e.g. a daemon or another
+    /// [`P2pNode`], reached over rpc at the addresses in `config`, and waits until both
+    /// serve rpc.
+    ///
+    /// Only gossipsub messages are received as events, the p2p node does not report
+    /// subscriptions or connections over rpc. Closing the returned node leaves the
+    /// services running.
+    pub async fn attach(config: iroh_rpc_client::Config) -> Result<(Self, Receiver<NetworkEvent>)> {
+        let rpc = Client::new(config.clone()).await?;
+        tokio::time::timeout(STARTUP_TIMEOUT, serving(&rpc))
+            .await
+            .map_err(|_| {
+                anyhow!(
+                    "p2p node and store did not serve within {:?}",
+                    STARTUP_TIMEOUT
+                )
+            })??;
+
+        let messages = rpc.try_p2p()?.gossipsub_messages(None).await?;
+        let (sender, events) = channel(ATTACHED_EVENTS_CAPACITY);
+        let events_task = tokio::task::spawn(async move {
+            tokio::pin!(messages);
+            while let Some(message) = messages.next().await {
+                let (from, id, message) = match message {
+                    Ok(message) => message,
+                    Err(err) => {
+                        warn!("stopped receiving gossipsub messages: {:?}", err);
+                        break;
+                    }
+                };
+                let event = NetworkEvent::Gossipsub(GossipsubEvent::Message { from, id, message });
+                if sender.send(event).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        let loader = Loader::new(rpc.clone());
+        let resolver = iroh_resolver::resolver::Resolver::new(loader);
+        Ok((
+            Self {
+                services: Services::Remote { events_task },
                 rpc,
+                rpc_config: config,
                 resolver,
             },
             events,
@@ -211,15 +919,320 @@ impl P2pNode {
         &self.rpc
     }
 
+    /// The addresses the p2p node and the store serve rpc on, to [`P2pNode::attach`] to
+    /// them from elsewhere.
+    pub fn rpc_config(&self) -> &iroh_rpc_client::Config {
+        &self.rpc_config
+    }
+
     pub fn resolver(&self) -> &Resolver<Loader> {
         &self.resolver
     }
 
+    /// The peers the p2p node may be connected to, `None` if it runs in another process.
+    pub fn allow_list(&self) -> Option<&PeerAllowList> {
+        match &self.services {
+            Services::Local { allow_list, .. } => Some(allow_list),
+            Services::Remote { .. } => None,
+        }
+    }
+
+    /// Stops the p2p node and the store, if they run in this process.
     pub async fn close(self) -> Result<()> {
-        self.rpc.try_p2p().unwrap().shutdown().await?;
-        self.store_task.abort();
-        self.p2p_task.await?;
-        self.store_task.await.ok();
+        match self.services {
+            Services::Local {
+                p2p_task,
+                store_task,
+                ..
+            } => {
+                self.rpc.try_p2p().unwrap().shutdown().await?;
+                store_task.abort();
+                p2p_task.await??;
+                store_task.await.ok();
+            }
+            Services::Remote { events_task } => events_task.abort(),
+        }
+        Ok(())
+    }
+}
+
+/// Waits until the p2p node and the store serve rpc, failing early if either stopped.
+async fn wait_until_ready(
+    rpc: &Client,
+    p2p_task: &mut JoinHandle<Result<()>>,
+    store_task: &mut JoinHandle<Result<()>>,
+) -> Result<()> {
+    tokio::select! {
+        res = tokio::time::timeout(STARTUP_TIMEOUT, serving(rpc)) => res.map_err(|_| {
+            anyhow!("p2p node and store did not start within {:?}", STARTUP_TIMEOUT)
+        })?,
+        res = p2p_task => Err(stopped("p2p node", res)),
+        res = store_task => Err(stopped("store", res)),
+    }
+}
+
+/// Waits until the p2p node and the store serve rpc.
+async fn serving(rpc: &Client) -> Result<()> {
+    loop {
+        let p2p = rpc.try_p2p()?.version().await;
+        let store = rpc.try_store()?.version().await;
+        if p2p.is_ok() && store.is_ok() {
+            return Ok(());
+        }
+        tokio::time::sleep(STARTUP_POLL_INTERVAL).await;
+    }
+}
+
+/// The error for a service that stopped while starting up.
+fn stopped(service: &str, res: std::result::Result<Result<()>, JoinError>) -> anyhow::Error {
+    match res {
+        Ok(Ok(())) => anyhow!("{} stopped while starting", service),
+        Ok(Err(err)) => err.context(format!("{service} failed to start")),
+        Err(err) => anyhow!("{} failed to start: {}", service, err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libp2p::gossipsub::Sha256Topic;
+
+    #[tokio::test]
+    async fn test_open_store_recreate() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("db");
+        // not a database
+        tokio::fs::create_dir(&path).await?;
+        tokio::fs::write(path.join("CURRENT"), b"garbage").await?;
+
+        let config = iroh_store::Config::new(path.clone());
+        let res = open_store(config.clone(), StoreRecovery::Fail).await;
+        assert!(res.is_err());
+
+        let store = open_store(config, StoreRecovery::Recreate).await?;
+        assert!(!store.has(&Cid::default())?);
+        let backup = dir.path().join("db.corrupt");
+        assert_eq!(tokio::fs::read(backup.join("CURRENT")).await?, b"garbage");
+        assert_eq!(backup_path(&path), dir.path().join("db.corrupt.1"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_loader_force_refresh() -> Result<()> {
+        use cid::multihash::{Code, MultihashDigest};
+
+        let dir = tempfile::tempdir()?;
+        let (node, _events) =
+            P2pNode::new(0, &dir.path().join("db"), NodeOptions::default()).await?;
+        let data = bytes::Bytes::from_static(b"hello");
+        let cid = Cid::new_v1(0x55, Code::Sha2_256.digest(&data));
+        node.rpc()
+            .try_store()?
+            .put(cid, data.clone(), vec![])
+            .await?;
+
+        let loader = node.resolver().loader();
+        let (closer, _closer_r) = async_channel::bounded(1);
+        let mut ctx = LoaderContext::from_path(ContextId(1), closer);
+        let loaded = loader.load_cid(&cid, &ctx).await?;
+        assert_eq!(loaded.data, data);
+        assert!(matches!(loaded.source, Source::Store(_)));
+
+        // the store is skipped, and there is no provider to fetch the block from
+        ctx.set_force_refresh(true);
+        let err = loader.load_cid(&cid, &ctx).await.unwrap_err();
+        assert!(err.to_string().contains("no providers supplied"), "{err:?}");
+
+        node.close().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_create_store_memory() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("db");
+        let options = StoreOptions {
+            backend: StoreBackend::Memory,
+            ..Default::default()
+        };
+
+        let (store, lock) = create_store(iroh_store::Config::new(path.clone()), options).await?;
+        assert!(lock.is_none());
+        store.put(Cid::default(), b"hello", [])?;
+        assert!(store.has(&Cid::default())?);
+        assert!(!path.exists());
+
+        assert_eq!("Memory".parse::<StoreBackend>()?, StoreBackend::Memory);
+        assert_eq!(StoreBackend::Persistent.to_string(), "persistent");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_create_store_concurrently() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let config = iroh_store::Config::new(dir.path().join("db"));
+        let options = StoreOptions {
+            lock_timeout: Some(Duration::from_secs(10)),
+            ..Default::default()
+        };
+
+        let mut first = tokio::spawn(create_store(config.clone(), options));
+        let mut second = tokio::spawn(create_store(config.clone(), options));
+        // one creates the store, the other waits until it is released and opens it
+        let ((store, lock), waiting) = tokio::select! {
+            res = &mut first => (res??, second),
+            res = &mut second => (res??, first),
+        };
+        store.put(Cid::default(), b"hello", [])?;
+        let no_wait = StoreOptions {
+            lock_timeout: Some(Duration::ZERO),
+            ..Default::default()
+        };
+        let err = create_store(config.clone(), no_wait).await.unwrap_err();
+        assert!(err.to_string().contains("in use"), "{err:#}");
+        drop(store);
+        drop(lock);
+
+        let (store, lock) = waiting.await??;
+        assert!(store.has(&Cid::default())?);
+        drop(store);
+        drop(lock);
+
+        // the lock file is left behind, but no longer held
+        assert!(dir.path().join("db.lock").exists());
+        let (store, _lock) = create_store(config, no_wait).await?;
+        assert!(store.has(&Cid::default())?);
+
+        // a directory left behind by an interrupted creation
+        let partial = dir.path().join("partial");
+        tokio::fs::create_dir(&partial).await?;
+        let (store, _lock) = create_store(iroh_store::Config::new(partial), options).await?;
+        assert!(!store.has(&Cid::default())?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_ticket_providers() -> Result<()> {
+        let peer_id = PeerId::random();
+        let replica = PeerId::random();
+        let addr: Multiaddr = "/ip4/127.0.0.1/tcp/4444".parse()?;
+        let mut ticket = Ticket {
+            peer_id,
+            addrs: vec![addr.clone()],
+            topic: "topic".to_string(),
+            topic_prefix: DEFAULT_TOPIC_PREFIX.to_string(),
+            http_url: None,
+            providers: vec![(replica, Vec::new())],
+            ipns_name: Some("example.com".to_string()),
+        };
+        assert_eq!(Ticket::from_bytes(&ticket.as_bytes())?, ticket);
+        let providers: Vec<_> = ticket.all_providers().map(|(peer, _)| peer).collect();
+        assert_eq!(providers, [peer_id, replica]);
+
+        // tickets serialized without an ipns name
+        let announced = bincode::serialize(&(
+            peer_id,
+            vec![addr.clone()],
+            &ticket.topic,
+            &ticket.topic_prefix,
+            None::<String>,
+            &ticket.providers,
+        ))?;
+        ticket.ipns_name = None;
+        assert_eq!(Ticket::from_bytes(&announced)?, ticket);
+
+        // single provider tickets, serialized without the providers field
+        let legacy = bincode::serialize(&(
+            peer_id,
+            vec![addr.clone()],
+            &ticket.topic,
+            &ticket.topic_prefix,
+            None::<String>,
+        ))?;
+        ticket.providers.clear();
+        assert_eq!(Ticket::from_bytes(&legacy)?, ticket);
+        assert_eq!(ticket.all_providers().count(), 1);
+
+        // tickets serialized before they carried an http url
+        let prefixed = bincode::serialize(&(
+            peer_id,
+            vec![addr.clone()],
+            &ticket.topic,
+            &ticket.topic_prefix,
+        ))?;
+        assert_eq!(Ticket::from_bytes(&prefixed)?, ticket);
+
+        // tickets serialized before the topic prefix was configurable
+        let unprefixed = bincode::serialize(&(peer_id, vec![addr], &ticket.topic))?;
+        assert_eq!(Ticket::from_bytes(&unprefixed)?, ticket);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_ticket_corrupted() -> Result<()> {
+        let ticket = Ticket {
+            peer_id: PeerId::random(),
+            addrs: vec!["/ip4/127.0.0.1/tcp/4444".parse()?],
+            topic: "topic".to_string(),
+            topic_prefix: DEFAULT_TOPIC_PREFIX.to_string(),
+            http_url: None,
+            providers: vec![(PeerId::random(), Vec::new())],
+            ipns_name: Some("example.com".to_string()),
+        };
+        let bytes = ticket.as_bytes();
+
+        // the leading fields still form an earlier layout, which must not be accepted
+        let truncated = &bytes[..bytes.len() - 1];
+        assert!(Ticket::from_bytes(truncated).is_err());
+
+        let mut extended = bytes.clone();
+        extended.push(0);
+        assert!(Ticket::from_bytes(&extended).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_ticket_validate() -> Result<()> {
+        let peer_id = PeerId::random();
+        let addr: Multiaddr = "/ip4/127.0.0.1/tcp/4444".parse()?;
+        let ticket = Ticket {
+            peer_id,
+            addrs: vec![addr.clone()],
+            topic: Sha256Topic::new("iroh-share-1").hash().to_string(),
+            topic_prefix: DEFAULT_TOPIC_PREFIX.to_string(),
+            http_url: Some("http://127.0.0.1:8080/ipfs/root".to_string()),
+            providers: vec![(PeerId::random(), Vec::new())],
+            ipns_name: None,
+        };
+        ticket.validate()?;
+
+        let invalid = |f: fn(&mut Ticket)| {
+            let mut ticket = ticket.clone();
+            f(&mut ticket);
+            ticket.validate().is_err()
+        };
+        assert!(invalid(|t| t.topic.clear()));
+        assert!(invalid(|t| t.topic = "topic".to_string()));
+        assert!(invalid(|t| t.topic_prefix.clear()));
+        assert!(invalid(|t| t.addrs.clear()));
+        assert!(invalid(|t| t.addrs.push(Multiaddr::empty())));
+        assert!(invalid(|t| t.http_url = Some("not a url".to_string())));
+        assert!(invalid(|t| t.ipns_name = Some(String::new())));
+        // an address of another peer
+        assert!(invalid(
+            |t| t.addrs[0].push(Protocol::P2p(PeerId::random().into()))
+        ));
+
+        // the address names its own peer, and a replica is left to connect to
+        let mut ticket = ticket;
+        ticket.addrs[0].push(Protocol::P2p(peer_id.into()));
+        ticket.validate()?;
+        ticket.addrs.clear();
+        ticket.providers[0].1.push(addr);
+        ticket.validate()?;
         Ok(())
     }
 }
@@ -1,45 +1,224 @@
-use anyhow::{anyhow, ensure, Context, Result};
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use cid::Cid;
 use futures::{
     channel::{oneshot::channel as oneshot, oneshot::Receiver as OneShotReceiver},
-    Stream, StreamExt,
+    stream::FuturesUnordered,
+    Stream, StreamExt, TryStreamExt,
 };
+use iroh_metrics::{core::MRecorder, inc, p2p::P2PMetrics};
 use iroh_p2p::NetworkEvent;
 use iroh_resolver::resolver::{Out, OutPrettyReader, OutType, Path, Resolver, UnixfsType};
+use iroh_rpc_client::P2pClient;
 use iroh_unixfs::Link;
 use libp2p::gossipsub::{GossipsubMessage, MessageId, TopicHash};
-use libp2p::PeerId;
-use tokio::sync::mpsc::{channel, Receiver as ChannelReceiver};
+use libp2p::{Multiaddr, PeerId};
+use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc::{
+    channel, error::TrySendError, Receiver as ChannelReceiver, Sender as ChannelSender,
+};
+use tokio::sync::watch;
 use tokio::task::JoinHandle;
 use tokio_stream::wrappers::ReceiverStream;
 use tracing::{debug, info, warn};
 
-use crate::SenderMessage;
 use crate::{
-    p2p_node::{Loader, P2pNode, Ticket},
-    ReceiverMessage,
+    decode_control_message, encode_control_message,
+    p2p_node::{
+        AddressFamily, Loader, NodeOptions, P2pNode, Ticket, TransferMode, DEFAULT_TOPIC_PREFIX,
+    },
+    sender::{ActiveTransfer, AnnounceLimit},
+    DropLog, ReceiverMessage, SenderMessage, Timeout, DEFAULT_DROP_LOG_INTERVAL,
 };
 
+/// Number of malformed messages accepted from the sender, before it is considered bad.
+const MAX_MALFORMED_MESSAGES: usize = 16;
+
+/// Capacity of the channels between the tasks of a receiver and its consumer.
+const CHANNEL_CAPACITY: usize = 1024;
+
+/// How long to wait for the start message via gossip, before asking the providers for it
+/// directly.
+const START_FETCH_DELAY: Duration = Duration::from_secs(2);
+
+/// How often the providers are asked for the start message, until one of them has it.
+const START_FETCH_INTERVAL: Duration = Duration::from_secs(2);
+
+/// How long a receiver in [`TransferMode::Direct`] waits for the sender to fetch the finish
+/// message, when the transfer completed, before it stops retaining the message.
+const DIRECT_FINISH_LINGER: Duration = Duration::from_secs(3);
+
+/// How often a receiver in [`TransferMode::Direct`] checks whether the sender fetched the
+/// finish message.
+const DIRECT_FINISH_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How a [`Receiver`] reconnects to the providers of a transfer, when the connection to
+/// one of them is lost before the transfer completed.
+///
+/// The provider is dialed again after [`ReconnectPolicy::backoff`], up to `max_attempts`
+/// times. Once reconnected it is subscribed to the topic again, and the blocks that are
+/// still missing are fetched from it. If reconnecting fails while no other provider is
+/// connected, the transfer fails.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReconnectPolicy {
+    /// How often to dial a lost provider, `0` disables reconnecting.
+    pub max_attempts: u32,
+    /// Delay before the first attempt.
+    pub interval: Duration,
+    /// Upper bound for the delay between attempts, which doubles with each attempt.
+    pub max_backoff: Duration,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        ReconnectPolicy {
+            max_attempts: 5,
+            interval: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(30),
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    /// The delay before the given attempt, counting from 1.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+        self.interval.saturating_mul(factor).min(self.max_backoff)
+    }
+}
+
+/// What happens to an item sent on a full channel of a [`Receiver`], because its consumer
+/// does not keep up.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Backpressure {
+    /// Wait until the consumer made room, nothing is lost.
+    #[default]
+    Block,
+    /// Drop the item and carry on.
+    Drop,
+    /// Fail the transfer.
+    Error,
+}
+
+impl Backpressure {
+    /// Sends `item` with this strategy, returning whether it was dropped because the
+    /// channel is full.
+    ///
+    /// Fails only if the channel is full and the strategy is [`Backpressure::Error`]. Items
+    /// for a closed channel are dropped as well, its consumer is not interested anymore.
+    async fn send<T>(self, sender: &ChannelSender<T>, item: T) -> Result<bool> {
+        let res = match self {
+            Backpressure::Block => {
+                sender.send(item).await.ok();
+                return Ok(false);
+            }
+            Backpressure::Drop | Backpressure::Error => sender.try_send(item),
+        };
+        match res {
+            Err(TrySendError::Full(_)) if self == Backpressure::Error => {
+                bail!("the consumer of the channel does not keep up")
+            }
+            Err(TrySendError::Full(_)) => {
+                debug!("channel full, dropping item");
+                Ok(true)
+            }
+            Ok(()) | Err(TrySendError::Closed(_)) => Ok(false),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Receiver {
     p2p: P2pNode,
     gossip_messages: ChannelReceiver<(MessageId, PeerId, GossipsubMessage)>,
+    /// Peers the node lost its last connection to.
+    disconnects: ChannelReceiver<PeerId>,
     gossip_task: JoinHandle<()>,
+    /// Shared with the gossip task, which is started before it can be configured.
+    gossip_backpressure: Arc<Mutex<Backpressure>>,
+    /// Shared with the gossip task as well.
+    drop_log: Arc<Mutex<DropLog>>,
+    progress_backpressure: Backpressure,
+    topic_prefix: String,
+    timeout: Option<Duration>,
+    ticket_peers_only: bool,
+    reconnect_policy: ReconnectPolicy,
+    transfer_mode: TransferMode,
+    announce_limit: AnnounceLimit,
 }
 
 impl Receiver {
+    /// Creates a new receiver, listening on `port`.
+    ///
+    /// All received blocks are persisted in the store at `db_path`, which is created if
+    /// it does not exist yet. Reusing the path keeps previously received content available.
     pub async fn new(port: u16, db_path: &std::path::Path) -> Result<Self> {
-        let (p2p, mut events) = P2pNode::new(port, db_path).await?;
-        let (s, r) = channel(1024);
+        Self::with_options(port, db_path, NodeOptions::default()).await
+    }
+
+    pub async fn with_address_family(
+        port: u16,
+        family: AddressFamily,
+        db_path: &std::path::Path,
+    ) -> Result<Self> {
+        let options = NodeOptions {
+            family,
+            ..Default::default()
+        };
+        Self::with_options(port, db_path, options).await
+    }
+
+    /// Creates a receiver with the given [`NodeOptions`].
+    ///
+    /// In [`TransferMode::Direct`] the root is fetched from the providers of the ticket
+    /// right away, without subscribing to the topic, and the outcome of the transfer is
+    /// left for the sender to fetch, see [`Transfer::finish`]. This needs a sender in
+    /// direct mode as well.
+    pub async fn with_options(
+        port: u16,
+        db_path: &std::path::Path,
+        options: NodeOptions,
+    ) -> Result<Self> {
+        let transfer_mode = options.transfer_mode;
+        let (p2p, mut events) = P2pNode::new(port, db_path, options).await?;
+        let (s, r) = channel(CHANNEL_CAPACITY);
+        let (disconnects_s, disconnects_r) = channel(CHANNEL_CAPACITY);
+        let gossip_backpressure = Arc::new(Mutex::new(Backpressure::Drop));
 
+        let drop_log = Arc::new(Mutex::new(DropLog::new(DEFAULT_DROP_LOG_INTERVAL)));
+        let backpressure = gossip_backpressure.clone();
+        let log = drop_log.clone();
+        // the providers of the transfer, once it is joined
+        let providers = p2p.resolver().loader().providers().clone();
         let gossip_task = tokio::task::spawn(async move {
             while let Some(event) = events.recv().await {
-                if let NetworkEvent::Gossipsub(iroh_p2p::GossipsubEvent::Message {
+                if let NetworkEvent::PeerDisconnected { peer_id, .. } = event {
+                    // only the providers matter, so other peers can not crowd them out
+                    if providers.lock().await.contains(&peer_id) {
+                        disconnects_s.try_send(peer_id).ok();
+                    }
+                } else if let NetworkEvent::Gossipsub(iroh_p2p::GossipsubEvent::Message {
                     from,
                     id,
                     message,
                 }) = event
                 {
-                    s.try_send((id, from, message)).ok();
+                    let backpressure = *backpressure.lock().unwrap();
+                    match backpressure.send(&s, (id, from, message)).await {
+                        Ok(true) => {
+                            log.lock().unwrap().dropped("message", from);
+                        }
+                        Ok(false) => {}
+                        Err(err) => {
+                            // closing the channel ends the transfer
+                            inc!(P2PMetrics::DroppedGossipEvent);
+                            warn!("stopped receiving gossip messages from {}: {:?}", from, err);
+                            break;
+                        }
+                    }
                 }
             }
         });
@@ -47,54 +226,252 @@ impl Receiver {
         Ok(Receiver {
             p2p,
             gossip_messages: r,
+            disconnects: disconnects_r,
             gossip_task,
+            gossip_backpressure,
+            drop_log,
+            progress_backpressure: Backpressure::Block,
+            topic_prefix: DEFAULT_TOPIC_PREFIX.to_string(),
+            timeout: None,
+            ticket_peers_only: false,
+            reconnect_policy: ReconnectPolicy::default(),
+            transfer_mode,
+            announce_limit: AnnounceLimit::default(),
         })
     }
 
+    /// Sets the prefix of the gossipsub topics transfers are negotiated on.
+    ///
+    /// Defaults to [`DEFAULT_TOPIC_PREFIX`], only tickets created with the same prefix
+    /// are accepted.
+    pub fn with_topic_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.topic_prefix = prefix.into();
+        self
+    }
+
+    /// Bounds how long a transfer runs, from the moment it is joined.
+    ///
+    /// Once `timeout` elapsed before all content was received, fetching stops, the topic
+    /// is unsubscribed and [`Transfer::recv`] and [`Transfer::progress`] report a
+    /// [`Timeout`] error. No timeout is set by default.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Only allows connections to and from the sender and providers listed in the ticket.
+    ///
+    /// Enforced by the p2p node, connections to any other peer, including ones established
+    /// before the transfer was joined, are closed as soon as they are established, instead
+    /// of only ignoring their messages. Dials to other peers are not prevented, so they may
+    /// still learn the addresses of the node. This also cuts the node off from the DHT and
+    /// relays. Disabled by default.
+    pub fn with_ticket_peers_only(mut self, ticket_peers_only: bool) -> Self {
+        self.ticket_peers_only = ticket_peers_only;
+        self
+    }
+
+    /// Counts the transfers of this receiver against `limit`, failing to join a transfer
+    /// once it is reached.
+    ///
+    /// A transfer holds its slot until it is dropped. Transfers consume their receiver, so
+    /// pass clones of the same limit to all receivers, and senders, that should be limited
+    /// together, see [`Sender::with_announce_limit`](crate::Sender::with_announce_limit).
+    /// Each receiver starts with its own limit of
+    /// [`MAX_ACTIVE_ANNOUNCEMENTS`](crate::MAX_ACTIVE_ANNOUNCEMENTS) by default.
+    pub fn with_announce_limit(mut self, limit: AnnounceLimit) -> Self {
+        self.announce_limit = limit;
+        self
+    }
+
+    /// Sets what happens to gossip messages, once 1024 of them wait to be processed.
+    ///
+    /// Defaults to [`Backpressure::Drop`], so a slow transfer does not hold up the events
+    /// of the p2p node. Dropping the announcement of the sender leaves the transfer waiting
+    /// for it, [`Backpressure::Error`] ends the transfer instead.
+    pub fn with_gossip_backpressure(self, backpressure: Backpressure) -> Self {
+        *self.gossip_backpressure.lock().unwrap() = backpressure;
+        self
+    }
+
+    /// Sets how the providers of a transfer are reconnected to, when the connection to one
+    /// of them is lost before the transfer completed.
+    ///
+    /// Defaults to [`ReconnectPolicy::default`], 5 attempts starting after a second.
+    pub fn with_reconnect_policy(mut self, policy: ReconnectPolicy) -> Self {
+        self.reconnect_policy = policy;
+        self
+    }
+
+    /// Sets how often gossip messages dropped with [`Backpressure::Drop`] are logged, with
+    /// the peer the last dropped message came from.
+    ///
+    /// Every drop is counted in the `dropped_gossip_event` metric, but is only logged if
+    /// `interval` elapsed since the last one was. Defaults to
+    /// [`DEFAULT_DROP_LOG_INTERVAL`], use [`Duration::ZERO`] to log every drop.
+    pub fn with_drop_log_interval(self, interval: Duration) -> Self {
+        self.drop_log.lock().unwrap().interval = interval;
+        self
+    }
+
+    /// Sets what happens to progress events, once 1024 of them were not consumed from
+    /// [`Transfer::progress`].
+    ///
+    /// Each resolved piece of the content is reported there, as is the error a transfer
+    /// fails with. Defaults to [`Backpressure::Block`], which loses no events, but pauses
+    /// resolving until they are consumed. [`Backpressure::Error`] fails the transfer
+    /// instead, with [`Backpressure::Drop`] events are skipped.
+    pub fn with_progress_backpressure(mut self, backpressure: Backpressure) -> Self {
+        self.progress_backpressure = backpressure;
+        self
+    }
+
+    /// Joins the transfer described by `ticket`.
+    ///
+    /// This consumes the receiver, so each receiver subscribes to a single transfer topic
+    /// once. Accepting the same ticket twice, e.g. on a retry, needs a new receiver, which
+    /// can share the store of the previous one once that is closed with
+    /// [`Transfer::finish`].
+    ///
+    /// The sender announces the root once it sees the subscription of the receiver, but
+    /// that announcement is lost if the subscription had not reached it yet. So if it does
+    /// not arrive via gossip within a few seconds, the providers are also asked for it
+    /// directly, repeatedly, until one of them has it. Whichever of the two arrives first
+    /// starts the transfer and the other is ignored, gossip is checked first if both are
+    /// ready at the same time.
     pub async fn transfer_from_ticket(self, ticket: &Ticket) -> Result<Transfer> {
+        ticket.validate().context("invalid ticket")?;
+        ensure!(
+            ticket.topic_prefix == self.topic_prefix,
+            "ticket uses topic prefix {:?}, expected {:?}",
+            ticket.topic_prefix,
+            self.topic_prefix
+        );
+        let active = self.announce_limit.acquire()?;
+
         // Connect to the sender
         info!("connecting");
         let Receiver {
             p2p,
             mut gossip_messages,
+            disconnects,
             gossip_task,
+            progress_backpressure,
+            timeout,
+            ticket_peers_only,
+            reconnect_policy,
+            transfer_mode,
+            ..
         } = self;
+        if ticket_peers_only {
+            p2p.allow_list()
+                .expect("receivers run their own p2p node")
+                .allow_only(ticket.all_providers().map(|(peer_id, _)| peer_id));
+        }
         let p2p_rpc = p2p.rpc().try_p2p()?;
-        p2p_rpc
-            .connect(ticket.peer_id, ticket.addrs.clone())
-            .await?;
-        p2p_rpc.gossipsub_add_explicit_peer(ticket.peer_id).await?;
+        let resolver = p2p.resolver().clone();
+        // any provider can announce the transfer, so it does not depend on the sender alone
+        let mut expected_senders = HashSet::new();
+        let mut connected_providers = Vec::new();
+        let mut last_err = None;
+        for (peer_id, addrs) in ticket.all_providers() {
+            if let Err(err) = p2p_rpc.connect(peer_id, addrs.to_vec()).await {
+                warn!("failed to connect to provider {}: {:?}", peer_id, err);
+                last_err = Some(err);
+                continue;
+            }
+            if transfer_mode == TransferMode::Gossip {
+                p2p_rpc.gossipsub_add_explicit_peer(peer_id).await?;
+            }
+            resolver.loader().providers().lock().await.insert(peer_id);
+            expected_senders.insert(peer_id);
+            connected_providers.push((peer_id, addrs.to_vec()));
+        }
+        if expected_senders.is_empty() {
+            let err = last_err.expect("tickets list at least the sender");
+            return Err(err.context("failed to connect to any provider"));
+        }
+
+        if let Some(name) = &ticket.ipns_name {
+            // nothing is announced, the name is resolved on `recv` and `refresh`
+            let (root_sender, root_receiver) = watch::channel(None);
+            return Ok(Transfer {
+                gossip_task,
+                gossip_task_source: None,
+                p2p,
+                root: root_receiver,
+                data_receiver: None,
+                progress_receiver: None,
+                ipns: Some(IpnsTarget {
+                    name: name.clone(),
+                    root: root_sender,
+                }),
+                _active: active,
+            });
+        }
+
         let topic = TopicHash::from_raw(&ticket.topic);
-        p2p_rpc.gossipsub_subscribe(topic.clone()).await?;
+        // in direct mode nothing is gossiped, the start message is only fetched
+        let start_fetch_delay = match transfer_mode {
+            TransferMode::Gossip => {
+                p2p_rpc.gossipsub_subscribe(topic.clone()).await?;
+                START_FETCH_DELAY
+            }
+            TransferMode::Direct => Duration::ZERO,
+        };
 
-        let expected_sender = ticket.peer_id;
-        let resolver = p2p.resolver().clone();
-        let (progress_sender, progress_receiver) = channel(1024);
+        let (progress_sender, progress_receiver) = channel(CHANNEL_CAPACITY);
         let (data_sender, data_receiver) = oneshot();
-
-        // add provider
-        resolver
-            .loader()
-            .providers()
-            .lock()
-            .await
-            .insert(expected_sender);
+        let (root_sender, root_receiver) = watch::channel(None);
 
         let rpc = p2p.rpc().clone();
+        // on timeout the error is reported to the caller, unless the transfer already did
+        let data_sender = Arc::new(Mutex::new(Some(data_sender)));
+        let timeout_data_sender = data_sender.clone();
+        let timeout_progress_sender = progress_sender.clone();
+        let timeout_rpc = rpc.clone();
+        let timeout_topic = topic.clone();
 
-        let gossip_task_source = tokio::task::spawn(async move {
-            let mut data_sender = Some(data_sender);
+        let reconnect = reconnect(
+            p2p_rpc.clone(),
+            disconnects,
+            connected_providers,
+            topic.clone(),
+            transfer_mode,
+            reconnect_policy,
+        );
+        // fetching runs on its own, so gossip is not held up by unresponsive providers, and
+        // stops once `receive` is done and drops the receiving end
+        let (fetched_sender, mut fetched) = channel(1);
+        tokio::task::spawn(fetch_starts(
+            p2p_rpc.clone(),
+            expected_senders.clone(),
+            topic.clone(),
+            start_fetch_delay,
+            fetched_sender,
+        ));
+        let receive = async move {
+            let mut malformed_messages = 0;
 
-            while let Some((_id, from, message)) = gossip_messages.recv().await {
-                if from == expected_sender {
-                    match bincode::deserialize(&message.data) {
+            loop {
+                let (from, data) = tokio::select! {
+                    biased;
+                    message = gossip_messages.recv() => match message {
+                        Some((_id, from, message)) => (from, message.data),
+                        None => break,
+                    },
+                    Some(start) = fetched.recv() => start,
+                };
+                if expected_senders.contains(&from) {
+                    match decode_control_message(&data) {
                         Ok(SenderMessage::Start { root, num_parts }) => {
+                            root_sender.send(Some(root)).ok();
                             let results = resolver.resolve_recursive(Path::from_cid(root));
                             tokio::pin!(results);
                             // root is the first
                             let mut index = 1;
                             let mut has_err = None;
-                            while let Some(res) = results.next().await {
+                            while let Some(mut res) = results.next().await {
                                 let msg = match &res {
                                     Ok(_out) => Ok(ProgressEvent::Piece {
                                         index,
@@ -106,9 +483,18 @@ impl Receiver {
                                     }
                                 };
                                 debug!("progress {}/{}", index, num_parts);
-                                progress_sender.send(msg).await.unwrap();
+                                if let Err(err) =
+                                    progress_backpressure.send(&progress_sender, msg).await
+                                {
+                                    let err = err.context("progress events are not consumed");
+                                    warn!("{:?}", err);
+                                    has_err = Some(format!("{err:#}"));
+                                    if res.is_ok() {
+                                        res = Err(err);
+                                    }
+                                }
 
-                                if let Some(data_sender) = data_sender.take() {
+                                if let Some(data_sender) = data_sender.lock().unwrap().take() {
                                     data_sender.send(res).ok();
                                 }
 
@@ -123,23 +509,48 @@ impl Receiver {
 
                             // TODO: send finish message or error
                             let msg = if let Some(error) = has_err.take() {
-                                ReceiverMessage::FinishError(error)
+                                ReceiverMessage::finish_error(error)
                             } else {
                                 ReceiverMessage::FinishOk
                             };
-                            rpc.try_p2p()
-                                .expect("missing p2p rpc")
-                                .gossipsub_publish(
-                                    topic,
-                                    bincode::serialize(&msg)
-                                        .expect("failed to serialize")
-                                        .into(),
-                                )
-                                .await
-                                .ok();
+                            let msg = encode_control_message(&msg)
+                                .expect("failed to serialize")
+                                .into();
+                            let p2p = rpc.try_p2p().expect("missing p2p rpc");
+                            match transfer_mode {
+                                TransferMode::Gossip => {
+                                    p2p.gossipsub_publish(topic, msg).await.ok();
+                                }
+                                TransferMode::Direct => {
+                                    p2p.gossipsub_retain(topic.clone(), msg).await.ok();
+                                    wait_for_fetch(&p2p, &topic, from).await;
+                                    p2p.gossipsub_release(topic).await.ok();
+                                }
+                            }
                         }
                         Err(err) => {
-                            warn!("got unexpected message from {}: {:?}", from, err);
+                            inc!(P2PMetrics::MalformedMessage);
+                            malformed_messages += 1;
+                            warn!(
+                                "got malformed message from {} ({}/{}): {:?}",
+                                from, malformed_messages, MAX_MALFORMED_MESSAGES, err
+                            );
+                            if malformed_messages < MAX_MALFORMED_MESSAGES {
+                                continue;
+                            }
+
+                            inc!(P2PMetrics::BadPeer);
+                            let err = format!(
+                                "sender {from} sent {malformed_messages} malformed messages"
+                            );
+                            warn!("{}", err);
+                            progress_backpressure
+                                .send(&progress_sender, Err(err.clone()))
+                                .await
+                                .ok();
+                            if let Some(data_sender) = data_sender.lock().unwrap().take() {
+                                data_sender.send(Err(anyhow!(err))).ok();
+                            }
                         }
                     }
                     // we only receive a single iteration
@@ -148,18 +559,206 @@ impl Receiver {
                     warn!("got message from unexpected sender: {:?}", from);
                 }
             }
+        };
+
+        let gossip_task_source = tokio::task::spawn(async move {
+            let receive = async move {
+                tokio::select! {
+                    _ = receive => Ok(()),
+                    err = reconnect => Err(err),
+                }
+            };
+            let err = match timeout {
+                Some(timeout) => match tokio::time::timeout(timeout, receive).await {
+                    Ok(Ok(())) => return,
+                    Ok(Err(err)) => err,
+                    Err(_) => Timeout(timeout).into(),
+                },
+                None => match receive.await {
+                    Ok(()) => return,
+                    Err(err) => err,
+                },
+            };
+
+            warn!("{:#}", err);
+            let message = format!("{err:#}");
+            if let Some(data_sender) = timeout_data_sender.lock().unwrap().take() {
+                data_sender.send(Err(err)).ok();
+            }
+            if let Ok(p2p) = timeout_rpc.try_p2p() {
+                if transfer_mode == TransferMode::Gossip {
+                    p2p.gossipsub_unsubscribe(timeout_topic).await.ok();
+                }
+            }
+            progress_backpressure
+                .send(&timeout_progress_sender, Err(message))
+                .await
+                .ok();
         });
 
         Ok(Transfer {
             gossip_task,
-            gossip_task_source,
+            gossip_task_source: Some(gossip_task_source),
             p2p,
+            root: root_receiver,
             data_receiver: Some(data_receiver),
             progress_receiver: Some(progress_receiver),
+            ipns: None,
+            _active: active,
+        })
+    }
+}
+
+/// Asks the `providers` for the start message they published on `topic` after `delay`, and
+/// then every [`START_FETCH_INTERVAL`], handing each one found to `fetched`.
+///
+/// Returns once `fetched` is closed.
+async fn fetch_starts(
+    p2p: P2pClient,
+    providers: HashSet<PeerId>,
+    topic: TopicHash,
+    delay: Duration,
+    fetched: ChannelSender<(PeerId, Vec<u8>)>,
+) {
+    let mut interval =
+        tokio::time::interval_at(tokio::time::Instant::now() + delay, START_FETCH_INTERVAL);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        let start = tokio::select! {
+            _ = fetched.closed() => return,
+            start = async {
+                interval.tick().await;
+                fetch_start(&p2p, &providers, &topic).await
+            } => start,
+        };
+        if let Some(start) = start {
+            if fetched.send(start).await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// Asks the `providers` for the start message they published on `topic`, all at once,
+/// returning the first one found along with the provider it came from.
+async fn fetch_start(
+    p2p: &P2pClient,
+    providers: &HashSet<PeerId>,
+    topic: &TopicHash,
+) -> Option<(PeerId, Vec<u8>)> {
+    let mut fetches: FuturesUnordered<_> = providers
+        .iter()
+        .map(|peer_id| async move {
+            let res = p2p.gossipsub_fetch_last(*peer_id, topic.clone()).await;
+            (*peer_id, res)
         })
+        .collect();
+    while let Some((peer_id, res)) = fetches.next().await {
+        match res {
+            Ok(Some(data)) => {
+                info!("fetched start message from {}", peer_id);
+                return Some((peer_id, data.to_vec()));
+            }
+            Ok(None) => debug!("{} has not published the start message yet", peer_id),
+            Err(err) => debug!("failed to fetch start message from {}: {:?}", peer_id, err),
+        }
+    }
+    None
+}
+
+/// Dials the `providers` of a transfer again when they disconnect, following `policy`, and
+/// subscribes them to `topic` again.
+///
+/// Only returns once reconnecting to a provider failed while none of the others is
+/// connected, with the error to fail the transfer with.
+async fn reconnect(
+    p2p: P2pClient,
+    mut disconnects: ChannelReceiver<PeerId>,
+    providers: Vec<(PeerId, Vec<Multiaddr>)>,
+    topic: TopicHash,
+    transfer_mode: TransferMode,
+    policy: ReconnectPolicy,
+) -> anyhow::Error {
+    let mut connected: HashSet<PeerId> = providers.iter().map(|(peer_id, _)| *peer_id).collect();
+    while let Some(peer_id) = disconnects.recv().await {
+        if policy.max_attempts == 0 || !connected.remove(&peer_id) {
+            continue;
+        }
+        let addrs = match providers.iter().find(|(provider, _)| *provider == peer_id) {
+            Some((_, addrs)) => addrs,
+            None => continue,
+        };
+        warn!("lost connection to provider {}, reconnecting", peer_id);
+        match redial(&p2p, peer_id, addrs, &policy).await {
+            Ok(()) => {
+                if transfer_mode == TransferMode::Gossip {
+                    let res = async {
+                        p2p.gossipsub_add_explicit_peer(peer_id).await?;
+                        p2p.gossipsub_subscribe(topic.clone()).await
+                    };
+                    if let Err(err) = res.await {
+                        warn!("failed to subscribe again: {:?}", err);
+                    }
+                }
+                info!("reconnected to provider {}", peer_id);
+                connected.insert(peer_id);
+            }
+            Err(err) if connected.is_empty() => {
+                return err.context("lost connection to all providers of the transfer");
+            }
+            Err(err) => warn!("{:#}", err),
+        }
+    }
+    futures::future::pending().await
+}
+
+/// Dials `peer_id` at `addrs` until connected, up to the attempts of `policy`.
+async fn redial(
+    p2p: &P2pClient,
+    peer_id: PeerId,
+    addrs: &[Multiaddr],
+    policy: &ReconnectPolicy,
+) -> Result<()> {
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        tokio::time::sleep(policy.backoff(attempt)).await;
+        match p2p.connect(peer_id, addrs.to_vec()).await {
+            Ok(()) => return Ok(()),
+            Err(err) if attempt >= policy.max_attempts => {
+                return Err(err.context(format!(
+                    "failed to reconnect to {peer_id} after {attempt} attempts"
+                )));
+            }
+            Err(err) => debug!(
+                "attempt {} to reconnect to {} failed: {:?}",
+                attempt, peer_id, err
+            ),
+        }
     }
 }
 
+/// Waits until `sender` fetched the finish message retained on `topic` for a transfer in
+/// [`TransferMode::Direct`], for at most [`DIRECT_FINISH_LINGER`].
+async fn wait_for_fetch(p2p: &P2pClient, topic: &TopicHash, sender: PeerId) {
+    let deadline = tokio::time::Instant::now() + DIRECT_FINISH_LINGER;
+    while tokio::time::Instant::now() < deadline {
+        match p2p.gossipsub_fetched_by(topic.clone()).await {
+            Ok(peers) if peers.contains(&sender) => return,
+            Ok(_) => {}
+            Err(err) => {
+                debug!(
+                    "failed to list peers that fetched the finish message: {:?}",
+                    err
+                );
+                return;
+            }
+        }
+        tokio::time::sleep(DIRECT_FINISH_POLL_INTERVAL).await;
+    }
+    debug!("{} did not fetch the finish message in time", sender);
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum ProgressEvent {
     Piece { index: usize, total: usize },
@@ -169,19 +768,76 @@ pub enum ProgressEvent {
 pub struct Transfer {
     p2p: P2pNode,
     gossip_task: JoinHandle<()>,
-    gossip_task_source: JoinHandle<()>,
+    /// Receives the announced content, `None` for transfers of an IPNS name.
+    gossip_task_source: Option<JoinHandle<()>>,
+    /// The root announced by the sender, or last resolved from the IPNS name, `None` until
+    /// the transfer started.
+    root: watch::Receiver<Option<Cid>>,
     data_receiver: Option<OneShotReceiver<Result<Out>>>,
     progress_receiver: Option<ChannelReceiver<std::result::Result<ProgressEvent, String>>>,
+    ipns: Option<IpnsTarget>,
+    /// Counted by the [`AnnounceLimit`] of the receiver until dropped.
+    _active: ActiveTransfer,
+}
+
+/// The IPNS name a transfer follows, see [`Ticket::ipns_name`].
+#[derive(Debug)]
+struct IpnsTarget {
+    name: String,
+    root: watch::Sender<Option<Cid>>,
+}
+
+impl IpnsTarget {
+    /// Records `cid` as the current root, returning whether it changed.
+    fn update(&self, cid: Cid) -> bool {
+        self.root
+            .send_if_modified(|root| std::mem::replace(root, Some(cid)) != Some(cid))
+    }
 }
 
 impl Transfer {
+    /// Receives the root of the transfer.
+    ///
+    /// For tickets with an [`Ticket::ipns_name`] this resolves the name to its current
+    /// target, otherwise it waits for the root announced by the sender.
     pub async fn recv(&mut self) -> Result<Data> {
+        if self.ipns.is_some() {
+            return self.resolve_ipns().await.map(|(data, _)| data);
+        }
+
         let data_receiver = self
             .data_receiver
             .take()
             .ok_or_else(|| anyhow!("recv must only be called once"))?;
         let root = data_receiver.await??;
+        self.root_data(root)
+    }
 
+    /// Receives the root of the transfer if the sender announced it already, without
+    /// waiting.
+    ///
+    /// Returns `None` while the root is not available yet. Once it returned the root, or
+    /// an error, it fails like [`Transfer::recv`] when called again. Transfers of tickets
+    /// with an [`Ticket::ipns_name`] can only be received with [`Transfer::recv`].
+    pub fn try_recv(&mut self) -> Result<Option<Data>> {
+        ensure!(
+            self.ipns.is_none(),
+            "the root of an IPNS name can only be received with recv"
+        );
+        let data_receiver = self
+            .data_receiver
+            .as_mut()
+            .ok_or_else(|| anyhow!("recv must only be called once"))?;
+        let root = match data_receiver.try_recv() {
+            Ok(None) => return Ok(None),
+            Ok(Some(root)) => root,
+            Err(err) => Err(err.into()),
+        };
+        self.data_receiver = None;
+        self.root_data(root?).map(Some)
+    }
+
+    fn root_data(&self, root: Out) -> Result<Data> {
         ensure!(
             root.metadata().typ == OutType::Unixfs,
             "expected unixfs data"
@@ -193,9 +849,216 @@ impl Transfer {
         })
     }
 
+    /// Resolves `rel_path`, e.g. `photos/2023/img.jpg`, within the root of the transfer.
+    ///
+    /// Only the blocks on the way to the target are fetched from the sender, so a single
+    /// entry is available without waiting for the whole transfer, which still continues in
+    /// the background. Waits for the sender to announce the root first.
+    pub async fn resolve_path(&self, rel_path: &str) -> Result<Data> {
+        let mut path = Path::from_cid(self.root().await?);
+        for segment in rel_path.split('/').filter(|s| !s.is_empty()) {
+            path.push(segment);
+        }
+        let root = self
+            .p2p
+            .resolver()
+            .resolve(path)
+            .await
+            .with_context(|| format!("resolve {rel_path}"))?;
+
+        Ok(Data {
+            resolver: self.p2p.resolver().clone(),
+            root,
+        })
+    }
+
+    /// Resolves the IPNS name of the transfer again, to pick up a newer version of the
+    /// content.
+    ///
+    /// Returns the new content, or `None` if the name still points to the same root.
+    /// Only transfers of tickets with an [`Ticket::ipns_name`] can be refreshed.
+    pub async fn refresh(&mut self) -> Result<Option<Data>> {
+        ensure!(
+            self.ipns.is_some(),
+            "only transfers of an ipns name can be refreshed"
+        );
+        let (data, changed) = self.resolve_ipns().await?;
+        Ok(changed.then_some(data))
+    }
+
+    /// Resolves the IPNS name of the transfer and records the current root, returning
+    /// whether it changed.
+    async fn resolve_ipns(&self) -> Result<(Data, bool)> {
+        let ipns = self.ipns.as_ref().expect("not an ipns transfer");
+        let path = Path::from_parts("ipns", &ipns.name, "")?;
+        // only DNSLink names can be resolved, there is no lookup of ipns records yet
+        ensure!(
+            path.cid().is_none(),
+            "cannot resolve {}, only DNSLink names are supported",
+            ipns.name
+        );
+        let root = self
+            .p2p
+            .resolver()
+            .resolve(path)
+            .await
+            .with_context(|| format!("resolve /ipns/{}", ipns.name))?;
+        ensure!(
+            root.metadata().typ == OutType::Unixfs,
+            "expected unixfs data"
+        );
+        let cid = *root
+            .metadata()
+            .resolved_path
+            .first()
+            .context("missing resolved root")?;
+        let changed = ipns.update(cid);
+
+        let data = Data {
+            resolver: self.p2p.resolver().clone(),
+            root,
+        };
+        Ok((data, changed))
+    }
+
+    /// Waits for the sender to announce the root of the transfer, or resolves the IPNS name
+    /// if that did not happen yet.
+    async fn root(&self) -> Result<Cid> {
+        if self.ipns.is_some() && self.root.borrow().is_none() {
+            self.resolve_ipns().await?;
+        }
+        let mut root = self.root.clone();
+        loop {
+            if let Some(cid) = *root.borrow() {
+                return Ok(cid);
+            }
+            root.changed()
+                .await
+                .map_err(|_| anyhow!("transfer ended before the root was announced"))?;
+        }
+    }
+
+    /// Receives the transferred file and streams its content into `writer`,
+    /// without buffering it in memory.
+    ///
+    /// The transfer must contain a single file. Returns the number of bytes written.
+    /// On error the writer may contain partial content, which must be discarded.
+    pub async fn recv_to_writer<W: AsyncWrite + Unpin>(&mut self, mut writer: W) -> Result<u64> {
+        let mut data = self.recv().await?;
+        if data.is_dir() {
+            let links: Vec<Link> = data
+                .read_dir()?
+                .expect("already know this is a directory")
+                .try_collect()
+                .await?;
+            ensure!(
+                links.len() == 1,
+                "expected a single file, got {} entries",
+                links.len()
+            );
+            data = data.read_file(&links[0]).await?;
+        }
+        ensure!(data.is_file(), "expected a file, got {:?}", data.typ());
+
+        let mut reader = data.pretty()?;
+        let expected = reader.size();
+        let written = tokio::io::copy(&mut reader, &mut writer)
+            .await
+            .context("failed to write received content")?;
+        if let Some(expected) = expected {
+            if written != expected {
+                bail!("incomplete transfer: wrote {written} of {expected} bytes");
+            }
+        }
+        writer.flush().await?;
+
+        Ok(written)
+    }
+
+    /// Writes the content of the transfer to `dest`, which must not exist yet.
+    ///
+    /// The content is written to a temporary directory next to `dest` first, and only
+    /// renamed to `dest` once every file was received completely and synced to disk. So a
+    /// failed or interrupted transfer never leaves partial content at `dest`. Nested
+    /// directories and symlinks are recreated.
+    pub async fn write_to(&self, dest: &std::path::Path) -> Result<()> {
+        ensure!(!dest.exists(), "{} already exists", dest.display());
+        let root = self.root().await?;
+        let parent = match dest.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => std::path::Path::new("."),
+        };
+        tokio::fs::create_dir_all(parent).await?;
+        // removed when dropped, together with everything that was not moved to `dest`
+        let staging = tempfile::Builder::new()
+            .prefix(".iroh-share-")
+            .tempdir_in(parent)?;
+        let staged = staging.path().join("content");
+        self.write_tree(root, &staged).await?;
+        tokio::fs::rename(&staged, dest)
+            .await
+            .with_context(|| format!("failed to move received content to {}", dest.display()))?;
+        Ok(())
+    }
+
+    /// Writes the DAG at `root` to `path`.
+    async fn write_tree(&self, root: Cid, path: &std::path::Path) -> Result<()> {
+        let resolver = self.p2p.resolver();
+        let entries = resolver.resolve_recursive_with_paths(Path::from_cid(root));
+        tokio::pin!(entries);
+        // directories come before their entries
+        while let Some(entry) = entries.next().await {
+            let (entry_path, out) = entry?;
+            let mut target = path.to_path_buf();
+            for name in entry_path.tail() {
+                // names are chosen by the sender, they must not escape `path`
+                ensure!(
+                    !matches!(name.as_str(), "" | "." | "..") && !name.contains(['/', '\\']),
+                    "invalid name {:?} in {}",
+                    name,
+                    entry_path
+                );
+                target.push(name);
+            }
+
+            if out.is_dir() {
+                tokio::fs::create_dir_all(&target).await?;
+            } else if out.is_symlink() {
+                let mut reader = out.pretty(resolver.clone(), Default::default(), None)?;
+                let mut link = String::new();
+                reader.read_to_string(&mut link).await?;
+                #[cfg(unix)]
+                tokio::fs::symlink(link, &target).await?;
+                #[cfg(not(unix))]
+                bail!("cannot create symlink {} to {}", target.display(), link);
+            } else {
+                let mut reader = out.pretty(resolver.clone(), Default::default(), None)?;
+                let expected = reader.size();
+                let mut file = tokio::fs::File::create(&target).await?;
+                let written = tokio::io::copy(&mut reader, &mut file)
+                    .await
+                    .with_context(|| format!("failed to write {}", target.display()))?;
+                if let Some(expected) = expected {
+                    if written != expected {
+                        bail!(
+                            "incomplete transfer of {}: wrote {written} of {expected} bytes",
+                            entry_path
+                        );
+                    }
+                }
+                file.sync_all().await?;
+            }
+        }
+        Ok(())
+    }
+
     pub fn progress(
         &mut self,
     ) -> Result<ReceiverStream<std::result::Result<ProgressEvent, String>>> {
+        ensure!(
+            self.ipns.is_none(),
+            "transfers of an ipns name report no progress"
+        );
         let progress = self
             .progress_receiver
             .take()
@@ -204,9 +1067,14 @@ impl Transfer {
     }
 
     /// Finish and finalize the transfer.
+    ///
+    /// In [`TransferMode::Direct`] this waits for the sender to fetch the outcome of the
+    /// transfer, for a few seconds at most.
     pub async fn finish(self) -> Result<()> {
         self.gossip_task.abort();
-        self.gossip_task_source.await?;
+        if let Some(gossip_task_source) = self.gossip_task_source {
+            gossip_task_source.await?;
+        }
         self.p2p.close().await?;
 
         Ok(())
@@ -254,3 +1122,175 @@ impl Data {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+
+    #[test]
+    fn test_reconnect_policy_backoff() {
+        let policy = ReconnectPolicy {
+            max_attempts: 8,
+            interval: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(10),
+        };
+        assert_eq!(policy.backoff(1), Duration::from_secs(1));
+        assert_eq!(policy.backoff(2), Duration::from_secs(2));
+        assert_eq!(policy.backoff(4), Duration::from_secs(8));
+        assert_eq!(policy.backoff(5), Duration::from_secs(10));
+        assert_eq!(policy.backoff(u32::MAX), Duration::from_secs(10));
+    }
+
+    #[test]
+    fn test_ipns_target_update() {
+        let (root, receiver) = watch::channel(None);
+        let target = IpnsTarget {
+            name: "example.com".to_string(),
+            root,
+        };
+        let first = Cid::default();
+        let second = Cid::new_v1(0x55, *first.hash());
+
+        // the first resolution always counts as a change
+        assert!(target.update(first));
+        assert!(!target.update(first));
+        assert_eq!(*receiver.borrow(), Some(first));
+
+        // only a different root is reported by `refresh`
+        assert!(target.update(second));
+        assert_eq!(*receiver.borrow(), Some(second));
+        assert!(!target.update(second));
+    }
+
+    #[tokio::test]
+    async fn test_backpressure_send() -> Result<()> {
+        let (s, mut r) = channel(1);
+        assert!(!Backpressure::Drop.send(&s, 1).await?);
+        // full
+        assert!(Backpressure::Drop.send(&s, 2).await?);
+        assert!(Backpressure::Error.send(&s, 3).await.is_err());
+
+        let blocked = tokio::spawn(async move { Backpressure::Block.send(&s, 4).await });
+        assert_eq!(r.recv().await, Some(1));
+        blocked.await??;
+        assert_eq!(r.recv().await, Some(4));
+
+        // nobody is listening anymore
+        let (s, r) = channel(1);
+        drop(r);
+        Backpressure::Error.send(&s, 1).await?;
+        Backpressure::Block.send(&s, 1).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_fetch_starts() -> Result<()> {
+        let provider_dir = tempfile::tempdir()?;
+        let (provider, _events) =
+            P2pNode::new(0, &provider_dir.path().join("db"), NodeOptions::default()).await?;
+        let receiver_dir = tempfile::tempdir()?;
+        let (receiver, _events) =
+            P2pNode::new(0, &receiver_dir.path().join("db"), NodeOptions::default()).await?;
+        let provider_rpc = provider.rpc().try_p2p()?;
+        let receiver_rpc = receiver.rpc().try_p2p()?;
+        let provider_id = provider_rpc.local_peer_id().await?;
+        receiver_rpc
+            .connect(provider_id, provider_rpc.listeners().await?)
+            .await?;
+
+        let topic = TopicHash::from_raw("test-fetch-starts");
+        provider_rpc
+            .gossipsub_retain(topic.clone(), Bytes::from_static(b"start"))
+            .await?;
+
+        // an unreachable provider does not hold up the one that has the message
+        let providers = HashSet::from([provider_id, PeerId::random()]);
+        let (fetched_sender, mut fetched) = channel(1);
+        let task = tokio::task::spawn(fetch_starts(
+            receiver_rpc,
+            providers,
+            topic,
+            Duration::ZERO,
+            fetched_sender,
+        ));
+        let start = tokio::time::timeout(Duration::from_secs(10), fetched.recv()).await?;
+        assert_eq!(start, Some((provider_id, b"start".to_vec())));
+
+        // fetching stops once nobody is interested anymore
+        drop(fetched);
+        tokio::time::timeout(Duration::from_secs(10), task).await??;
+
+        receiver.close().await?;
+        provider.close().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_reconnect() -> Result<()> {
+        let provider_dir = tempfile::tempdir()?;
+        let (provider, _events) =
+            P2pNode::new(0, &provider_dir.path().join("db"), NodeOptions::default()).await?;
+        let receiver_dir = tempfile::tempdir()?;
+        let (receiver, _events) =
+            P2pNode::new(0, &receiver_dir.path().join("db"), NodeOptions::default()).await?;
+        let provider_rpc = provider.rpc().try_p2p()?;
+        let receiver_rpc = receiver.rpc().try_p2p()?;
+        let provider_id = provider_rpc.local_peer_id().await?;
+        let provider_addrs = provider_rpc.listeners().await?;
+        let unreachable: Vec<Multiaddr> = vec!["/ip4/127.0.0.1/tcp/1".parse()?];
+        let policy = ReconnectPolicy {
+            max_attempts: 2,
+            interval: Duration::from_millis(10),
+            max_backoff: Duration::from_millis(10),
+        };
+        let topic = TopicHash::from_raw("test-reconnect");
+
+        // a provider that is reachable again is subscribed to the topic again, and losing
+        // another one does not fail the transfer while it is connected
+        let lost = PeerId::random();
+        let (disconnects_s, disconnects_r) = channel(4);
+        let reconnect_task = tokio::task::spawn(reconnect(
+            receiver_rpc.clone(),
+            disconnects_r,
+            vec![(provider_id, provider_addrs), (lost, unreachable.clone())],
+            topic.clone(),
+            TransferMode::Gossip,
+            policy,
+        ));
+        disconnects_s.send(provider_id).await?;
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while !receiver_rpc.gossipsub_topics().await?.contains(&topic) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            anyhow::Ok(())
+        })
+        .await??;
+        assert!(receiver_rpc.get_peers().await?.contains_key(&provider_id));
+        disconnects_s.send(lost).await?;
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert!(!reconnect_task.is_finished());
+        reconnect_task.abort();
+
+        // losing the last provider fails once the attempts are used up
+        let (disconnects_s, disconnects_r) = channel(4);
+        let reconnect = reconnect(
+            receiver_rpc,
+            disconnects_r,
+            vec![(lost, unreachable)],
+            topic,
+            TransferMode::Gossip,
+            policy,
+        );
+        disconnects_s.send(lost).await?;
+        let err = tokio::time::timeout(Duration::from_secs(10), reconnect).await?;
+        assert!(
+            format!("{err:#}").contains("after 2 attempts"),
+            "unexpected error: {err:#}"
+        );
+
+        receiver.close().await?;
+        provider.close().await?;
+        Ok(())
+    }
+}
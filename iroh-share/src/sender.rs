@@ -1,132 +1,494 @@
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, bail, ensure, Context, Result};
 use bytes::Bytes;
+use cid::Cid;
 use futures::channel::oneshot::{channel as oneshot, Receiver as OneShotReceiver};
-use futures::StreamExt;
+use futures::{StreamExt, TryStreamExt};
 use iroh_p2p::{GossipsubEvent, NetworkEvent};
-use iroh_unixfs::builder::{DirectoryBuilder, FileBuilder};
-use libp2p::gossipsub::Sha256Topic;
+use iroh_rpc_client::{Client, P2pClient, StoreClient};
+use iroh_unixfs::builder::{Config as UnixfsConfig, DirectoryBuilder, Entry, FileBuilder};
+use iroh_unixfs::chunker::{ChunkerConfig, DEFAULT_CHUNKS_SIZE};
+use libp2p::gossipsub::{Sha256Topic, TopicHash};
+use libp2p::multiaddr::Protocol;
+use libp2p::{Multiaddr, PeerId};
 use rand::Rng;
-use tokio::sync::mpsc::{channel, Receiver};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::{channel, error::TrySendError, Receiver};
+use tokio::sync::watch;
 use tokio::task::JoinHandle;
+use tokio::time::Instant;
 use tracing::{debug, info, warn};
 
 use crate::{
-    p2p_node::{P2pNode, Ticket},
-    ReceiverMessage, SenderMessage,
+    car_server::CarServer,
+    decode_control_message, encode_control_message,
+    p2p_node::{AddressFamily, NodeOptions, P2pNode, Ticket, TransferMode, DEFAULT_TOPIC_PREFIX},
+    Cancelled, DropLog, ReceiverMessage, SenderMessage, Timeout, DEFAULT_DROP_LOG_INTERVAL,
 };
 
+/// How long [`announce`] keeps offering content to receivers joining the transfer.
+pub const ANNOUNCE_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// How often [`announce`] checks for receivers joining the transfer.
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The default limit of transfers running at the same time, see [`AnnounceLimit`].
+pub const MAX_ACTIVE_ANNOUNCEMENTS: usize = 64;
+
+/// How often the subscribers of a [`Transfer`] are recounted. Peers that disconnect do
+/// not unsubscribe, so the count can not rely on gossip events alone.
+const SUBSCRIBERS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The default number of blocks written to the store at the same time while encoding.
+pub const DEFAULT_WRITE_QUEUE: usize = 16;
+
+/// How often the receivers of a transfer in [`TransferMode::Direct`] are asked whether they
+/// finished.
+const DIRECT_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The default limit of addresses listed in a [`Ticket`], see
+/// [`Sender::with_max_ticket_addrs`].
+pub const DEFAULT_MAX_TICKET_ADDRS: usize = 8;
+
+/// The default number of gossipsub events buffered by a [`Sender`], see
+/// [`NodeOptions::gossip_capacity`].
+pub const DEFAULT_GOSSIP_CAPACITY: usize = 1024;
+
+/// Maximum depth of the DAG walked by [`has_full_dag`].
+const MAX_DAG_DEPTH: usize = 4096;
+
+/// How many links are looked up concurrently by [`has_full_dag`].
+const GET_LINKS_CONCURRENCY: usize = 16;
+
 /// The sending part of the data transfer.
 #[derive(Debug)]
 pub struct Sender {
     p2p: P2pNode,
+    /// Subscription changes, kept apart from messages so a flood of those can not crowd
+    /// them out.
+    subscription_events: Receiver<GossipsubEvent>,
     gossip_events: Receiver<GossipsubEvent>,
     gossip_task: JoinHandle<()>,
+    /// Shared with the gossip task, which is started before it can be configured.
+    drop_log: Arc<Mutex<DropLog>>,
+    topic_prefix: String,
+    write_queue: usize,
+    http_addr: Option<SocketAddr>,
+    timeout: Option<Duration>,
+    max_ticket_addrs: usize,
+    min_subscribers: usize,
+    min_subscribers_timeout: Option<Duration>,
+    transfer_mode: TransferMode,
+    groups: TransferGroups,
+    announce_limit: AnnounceLimit,
 }
 
 impl Sender {
     pub async fn new(port: u16, db_path: &Path) -> Result<Self> {
-        let (p2p, mut events) = P2pNode::new(port, db_path).await?;
-        let (s, r) = channel(1024);
+        Self::with_options(port, db_path, NodeOptions::default()).await
+    }
+
+    pub async fn with_address_family(
+        port: u16,
+        family: AddressFamily,
+        db_path: &Path,
+    ) -> Result<Self> {
+        let options = NodeOptions {
+            family,
+            ..Default::default()
+        };
+        Self::with_options(port, db_path, options).await
+    }
+
+    /// Creates a sender with the given [`NodeOptions`].
+    ///
+    /// In [`TransferMode::Direct`] nothing is published: receivers, which dial the
+    /// addresses of the ticket, fetch the root from the sender directly, and the sender asks
+    /// the peers that fetched it whether they finished, about once a second. Receivers in
+    /// either mode can join such a transfer, while receivers in direct mode need a sender in
+    /// direct mode as well. [`Sender::with_min_subscribers`] does not apply.
+    pub async fn with_options(port: u16, db_path: &Path, options: NodeOptions) -> Result<Self> {
+        let (p2p, events) = P2pNode::new(port, db_path, options).await?;
+        Ok(Self::with_node(
+            p2p,
+            events,
+            options.gossip_capacity,
+            options.transfer_mode,
+        ))
+    }
+
+    /// Creates a sender using the p2p node and the store of another process, e.g. a daemon,
+    /// reached over rpc at the addresses in `config`.
+    ///
+    /// Nothing is opened locally, so several processes can create and serve transfers
+    /// through the same node, and hand them off to each other, see
+    /// [`Sender::resume_transfer`]. The p2p node has to be configured for `mode`. Receivers
+    /// are detected by polling the subscribers of a transfer, as subscriptions are not
+    /// reported over rpc. Closing the sender leaves the node running.
+    pub async fn attach(config: iroh_rpc_client::Config, mode: TransferMode) -> Result<Self> {
+        let (p2p, events) = P2pNode::attach(config).await?;
+        Ok(Self::with_node(p2p, events, DEFAULT_GOSSIP_CAPACITY, mode))
+    }
 
+    fn with_node(
+        p2p: P2pNode,
+        mut events: Receiver<NetworkEvent>,
+        gossip_capacity: usize,
+        transfer_mode: TransferMode,
+    ) -> Self {
+        let (subscriptions_s, subscriptions_r) = channel(gossip_capacity.max(1));
+        let (s, r) = channel(gossip_capacity.max(1));
+        let drop_log = Arc::new(Mutex::new(DropLog::new(DEFAULT_DROP_LOG_INTERVAL)));
+
+        let log = drop_log.clone();
         let gossip_task = tokio::task::spawn(async move {
             while let Some(event) = events.recv().await {
-                if let NetworkEvent::Gossipsub(e) = event {
+                let event = match event {
+                    NetworkEvent::Gossipsub(event) => event,
+                    _ => continue,
+                };
+                let sender = match event {
+                    GossipsubEvent::Subscribed { .. } | GossipsubEvent::Unsubscribed { .. } => {
+                        &subscriptions_s
+                    }
+                    GossipsubEvent::Message { .. } => &s,
+                };
+                match sender.try_send(event) {
+                    Ok(()) => {}
                     // drop events if they are not processed
-                    s.try_send(e).ok();
+                    Err(TrySendError::Full(event)) => {
+                        let (kind, peer) = match event {
+                            GossipsubEvent::Subscribed { peer_id, .. } => ("subscription", peer_id),
+                            GossipsubEvent::Unsubscribed { peer_id, .. } => {
+                                ("unsubscription", peer_id)
+                            }
+                            GossipsubEvent::Message { from, .. } => ("message", from),
+                        };
+                        log.lock().unwrap().dropped(kind, peer);
+                    }
+                    // the transfer is done
+                    Err(TrySendError::Closed(_)) => {}
                 }
             }
         });
 
-        Ok(Sender {
+        Sender {
             p2p,
+            subscription_events: subscriptions_r,
             gossip_events: r,
             gossip_task,
-        })
+            drop_log,
+            topic_prefix: DEFAULT_TOPIC_PREFIX.to_string(),
+            write_queue: DEFAULT_WRITE_QUEUE,
+            http_addr: None,
+            timeout: None,
+            max_ticket_addrs: DEFAULT_MAX_TICKET_ADDRS,
+            min_subscribers: 1,
+            min_subscribers_timeout: None,
+            transfer_mode,
+            groups: TransferGroups::default(),
+            announce_limit: AnnounceLimit::default(),
+        }
+    }
+
+    /// Counts the transfers of this sender against `limit`, failing to start a transfer
+    /// once it is reached.
+    ///
+    /// A transfer holds its slot until it is dropped. Transfers consume their sender, so
+    /// pass clones of the same limit to all senders, and receivers, of a node, e.g. to
+    /// the senders attached to one daemon with [`Sender::attach`]. Each sender starts
+    /// with its own limit of [`MAX_ACTIVE_ANNOUNCEMENTS`] by default.
+    pub fn with_announce_limit(mut self, limit: AnnounceLimit) -> Self {
+        self.announce_limit = limit;
+        self
+    }
+
+    /// Tracks the transfers of this sender in `groups`, e.g. to list and cancel the
+    /// transfers of several senders together.
+    ///
+    /// Each sender starts with its own [`TransferGroups`] by default, see
+    /// [`Sender::groups`].
+    pub fn with_groups(mut self, groups: TransferGroups) -> Self {
+        self.groups = groups;
+        self
+    }
+
+    /// The groups the transfers of this sender are tracked in.
+    ///
+    /// Transfers consume their sender, so clone the groups before starting one, to list
+    /// or cancel it later.
+    pub fn groups(&self) -> &TransferGroups {
+        &self.groups
+    }
+
+    /// Sets the prefix of the gossipsub topics transfers are negotiated on.
+    ///
+    /// Defaults to [`DEFAULT_TOPIC_PREFIX`]. Receivers must use the same prefix, which
+    /// allows independent deployments on the same network to keep their transfers apart.
+    pub fn with_topic_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.topic_prefix = prefix.into();
+        self
+    }
+
+    /// Sets how often gossip events dropped for a full buffer are logged, with the kind of
+    /// the last dropped event and the peer it came from.
+    ///
+    /// Every drop is counted in the `dropped_gossip_event` metric, but is only logged if
+    /// `interval` elapsed since the last one was, to diagnose stalled transfers without
+    /// flooding the log. Defaults to [`DEFAULT_DROP_LOG_INTERVAL`], use
+    /// [`Duration::ZERO`] to log every drop.
+    pub fn with_drop_log_interval(self, interval: Duration) -> Self {
+        self.drop_log.lock().unwrap().interval = interval;
+        self
+    }
+
+    /// Sets how many blocks are written to the store at the same time, while the content
+    /// is still being encoded.
+    ///
+    /// Defaults to [`DEFAULT_WRITE_QUEUE`]. Larger queues speed up sharing large content,
+    /// at the cost of keeping more blocks in memory. All writes complete before the
+    /// transfer is offered.
+    pub fn with_write_queue(mut self, len: usize) -> Self {
+        self.write_queue = len.max(1);
+        self
+    }
+
+    /// Also serves the content of transfers as a CAR file over HTTP on `addr`, for
+    /// receivers that can not use libp2p.
+    ///
+    /// The download url is part of the [`Ticket`], so `addr` has to be reachable by the
+    /// receivers. Use port 0 to pick a free port. When binding to an unspecified ip, the
+    /// url uses the most reachable ip of the ticket addresses instead. Disabled by default.
+    ///
+    /// Downloads do not finish a transfer, [`Transfer::done`] still waits for a receiver
+    /// using libp2p.
+    pub fn with_http_addr(mut self, addr: SocketAddr) -> Self {
+        self.http_addr = Some(addr);
+        self
+    }
+
+    /// Bounds how long a transfer is offered, from the moment it is created.
+    ///
+    /// Once `timeout` elapsed without a receiver finishing, the transfer stops answering
+    /// receivers, unsubscribes from its topic and [`Transfer::done`] fails with a
+    /// [`Timeout`] error. No timeout is set by default.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Limits how many of the node's addresses are listed in the [`Ticket`] of a transfer,
+    /// to keep tickets compact enough to share as text.
+    ///
+    /// Defaults to [`DEFAULT_MAX_TICKET_ADDRS`]. When there are more addresses, publicly
+    /// routable ones are kept first, then relayed ones, then ones in private networks and
+    /// finally loopback addresses, see [`select_ticket_addrs`].
+    pub fn with_max_ticket_addrs(mut self, max: usize) -> Self {
+        self.max_ticket_addrs = max.max(1);
+        self
+    }
+
+    /// Waits until `min` peers subscribed to the topic of a transfer before announcing its
+    /// root, so that a known group of receivers starts roughly together.
+    ///
+    /// Once `timeout` elapsed since the transfer was created, the root is announced to
+    /// whoever subscribed by then, or to the first receiver that subscribes afterwards.
+    /// Defaults to announcing on the first subscription.
+    pub fn with_min_subscribers(mut self, min: usize, timeout: Duration) -> Self {
+        self.min_subscribers = min.max(1);
+        self.min_subscribers_timeout = Some(timeout);
+        self
     }
 
     pub async fn transfer_from_dir_builder(
         self,
         dir_builder: DirectoryBuilder,
     ) -> Result<Transfer> {
+        self.transfer(None, None, dir_builder).await
+    }
+
+    /// Like [`Sender::transfer_from_dir_builder`], but adds the transfer to the group `tag`.
+    ///
+    /// The transfers of a group, e.g. the shares of one user session, can be listed with
+    /// [`TransferGroups::transfers_in_group`] and cancelled together with
+    /// [`TransferGroups::cancel_group`], see [`Sender::groups`].
+    pub async fn transfer_from_dir_builder_tagged(
+        self,
+        tag: impl Into<String>,
+        dir_builder: DirectoryBuilder,
+    ) -> Result<Transfer> {
+        self.transfer(Some(tag.into()), None, dir_builder).await
+    }
+
+    /// Creates a ticket for the content the IPNS name `name`, a DNSLink domain, points to.
+    ///
+    /// Nothing is announced: receivers resolve the name themselves, fetch the content from
+    /// this sender, and can pick up newer versions later, see
+    /// [`ReceiverTransfer::refresh`](crate::ReceiverTransfer::refresh). The content must
+    /// therefore be in the store of this sender, e.g. when it is attached to a node that
+    /// has it, see [`Sender::attach`], and stays available as long as the sender runs.
+    pub async fn ipns_ticket(&self, name: impl Into<String>) -> Result<Ticket> {
+        let name = name.into();
+        let path = iroh_resolver::resolver::Path::from_parts("ipns", &name, "")?;
+        ensure!(
+            path.cid().is_none(),
+            "{} is not a DNSLink name, only those can be resolved by receivers",
+            name
+        );
+
+        let (peer_id, addrs) = self
+            .p2p
+            .rpc()
+            .try_p2p()?
+            .get_listening_addrs()
+            .await
+            .context("getting p2p info")?;
+        Ok(Ticket {
+            peer_id,
+            addrs: select_ticket_addrs(addrs, self.max_ticket_addrs),
+            // receivers of the name do not subscribe to a topic
+            topic: transfer_topic(&self.topic_prefix, self.next_id())
+                .hash()
+                .to_string(),
+            topic_prefix: self.topic_prefix.clone(),
+            http_url: None,
+            providers: Vec::new(),
+            ipns_name: Some(name),
+        })
+    }
+
+    /// Serves a transfer created by another [`Sender`], e.g. in another process, from its
+    /// [`TransferState`], see [`Transfer::handoff`].
+    ///
+    /// The content is not encoded again: the complete DAG at the root must already be in the
+    /// store of this sender, so both senders have to use the same store, either attached to
+    /// the same node, see [`Sender::attach`], or one after the other with the same
+    /// `db_path`. The transfer keeps its topic, which requires the same topic prefix and
+    /// [`TransferMode`] as the sender that created it. Its ticket lists the addresses of
+    /// this sender, and its timeout, if any, starts over.
+    pub async fn resume_transfer(self, state: TransferState) -> Result<Transfer> {
+        let active = self.announce_limit.acquire()?;
+        let topic = transfer_topic(&self.topic_prefix, state.id).hash();
+        ensure!(
+            topic.as_str() == state.topic,
+            "transfer {} was created with a different topic prefix",
+            state.id
+        );
+        let store = self.p2p.rpc().try_store()?;
+        ensure!(
+            has_full_dag(&store, state.root).await?,
+            "content {} of transfer {} is not completely in the store",
+            state.root,
+            state.id
+        );
+        self.serve(state, Instant::now(), active).await
+    }
+
+    async fn transfer(
+        self,
+        tag: Option<String>,
+        name: Option<String>,
+        dir_builder: DirectoryBuilder,
+    ) -> Result<Transfer> {
+        let created = Instant::now();
+        let active = self.announce_limit.acquire()?;
         let id = self.next_id();
+        let root_dir = dir_builder.build().await?;
+
+        let store = self.p2p.rpc().try_store()?;
+        let (root, num_parts) = {
+            let puts = root_dir
+                .encode()
+                .map(|part| {
+                    let store = store.clone();
+                    async move {
+                        let (cid, bytes, links) = part?.into_parts();
+                        store.put(cid, bytes, links).await?;
+                        anyhow::Ok(cid)
+                    }
+                })
+                .buffered(self.write_queue);
+            tokio::pin!(puts);
+            let mut num_parts = 0;
+            let mut root_cid = None;
+            // the writes complete in order, so the root, which is encoded last, comes last
+            while let Some(cid) = puts.next().await {
+                num_parts += 1;
+                root_cid = Some(cid?);
+            }
+            (root_cid.context("nothing to transfer")?, num_parts)
+        };
+
+        let state = TransferState {
+            id,
+            root,
+            num_parts,
+            topic: transfer_topic(&self.topic_prefix, id).hash().to_string(),
+            name,
+            tag,
+        };
+        self.serve(state, created, active).await
+    }
+
+    /// Offers the content of `state`, which is in the store, to receivers until one of them
+    /// finished, the timeout counting from `created`.
+    async fn serve(
+        self,
+        state: TransferState,
+        created: Instant,
+        active: ActiveTransfer,
+    ) -> Result<Transfer> {
         let Sender {
             p2p,
+            mut subscription_events,
             mut gossip_events,
             gossip_task,
+            drop_log: _,
+            topic_prefix,
+            write_queue: _,
+            http_addr,
+            timeout,
+            max_ticket_addrs,
+            mut min_subscribers,
+            min_subscribers_timeout,
+            transfer_mode,
+            groups,
+            announce_limit: _,
         } = self;
-
-        let t = Sha256Topic::new(format!("iroh-share-{id}"));
-        let root_dir = dir_builder.build().await?;
+        let deadline = timeout.map(|timeout| created + timeout);
+        let min_subscribers_deadline = min_subscribers_timeout.map(|t| created + t);
+        let (root, num_parts) = (state.root, state.num_parts);
 
         let (done_sender, done_receiver) = oneshot();
 
         let p2p_rpc = p2p.rpc().try_p2p()?;
-        let store = p2p.rpc().try_store()?;
-        let (root, num_parts) = {
-            let parts = root_dir.encode();
-            tokio::pin!(parts);
-            let mut num_parts = 0;
-            let mut root_cid = None;
-            while let Some(part) = parts.next().await {
-                let (cid, bytes, links) = part?.into_parts();
-                num_parts += 1;
-                root_cid = Some(cid);
-                store.put(cid, bytes, links).await?;
-            }
-            (root_cid.unwrap(), num_parts)
-        };
 
-        let topic_hash = t.hash();
+        let topic_hash = TopicHash::from_raw(state.topic.clone());
         let th = topic_hash.clone();
 
-        // subscribe to the topic, to receive responses
-        p2p_rpc.gossipsub_subscribe(topic_hash.clone()).await?;
-        let p2p2 = p2p_rpc.clone();
-        let gossip_task_source = tokio::task::spawn(async move {
-            let mut current_peer = None;
-            while let Some(event) = gossip_events.recv().await {
-                match event {
-                    GossipsubEvent::Subscribed { peer_id, topic } => {
-                        if topic == th && current_peer.is_none() {
-                            info!("connected to {}", peer_id);
-                            current_peer = Some(peer_id);
-
-                            let start =
-                                bincode::serialize(&SenderMessage::Start { root, num_parts })
-                                    .expect("serialize failure");
-                            p2p2.gossipsub_publish(topic.clone(), start.into())
-                                .await
-                                .unwrap();
-                        }
-                    }
-                    GossipsubEvent::Message { from, message, .. } => {
-                        debug!("received message from {}", from);
-                        if let Some(current_peer) = current_peer {
-                            if from == current_peer {
-                                match bincode::deserialize(&message.data) {
-                                    Ok(ReceiverMessage::FinishOk) => {
-                                        info!("finished transfer");
-                                        done_sender.send(Ok(())).ok();
-                                        break;
-                                    }
-                                    Ok(ReceiverMessage::FinishError(err)) => {
-                                        info!("transfer failed: {}", err);
-                                        done_sender.send(Err(anyhow!("{}", err))).ok();
-                                        break;
-                                    }
-                                    Err(err) => {
-                                        warn!("unexpected message: {:?}", err);
-                                    }
-                                }
-                            }
-                        }
-                    }
-                    _ => {}
+        let start: Bytes = encode_control_message(&SenderMessage::Start { root, num_parts })
+            .expect("serialize failure")
+            .into();
+        match transfer_mode {
+            TransferMode::Gossip => {
+                // subscribe to the topic, to receive responses
+                p2p_rpc.gossipsub_subscribe(topic_hash.clone()).await?;
+                if min_subscribers == 1 {
+                    publish_start(&p2p_rpc, &topic_hash, start.clone()).await;
                 }
             }
-        });
+            TransferMode::Direct => {
+                p2p_rpc
+                    .gossipsub_retain(topic_hash.clone(), start.clone())
+                    .await?;
+            }
+        }
 
         let (peer_id, addrs) = p2p_rpc
             .get_listening_addrs()
@@ -134,19 +496,169 @@ impl Sender {
             .context("getting p2p info")?;
         info!("Available addrs: {:?}", addrs);
         let topic_string = topic_hash.to_string();
+        let addrs = select_ticket_addrs(addrs, max_ticket_addrs);
+
+        // all blocks are stored at this point, so the content can be served right away
+        let car_server = match http_addr {
+            Some(addr) => Some(CarServer::new(addr, root, p2p.resolver().clone())?),
+            None => None,
+        };
+        let http_url = match car_server {
+            Some(ref server) => Some(server.url(&addrs)?),
+            None => None,
+        };
 
         let ticket = Ticket {
             peer_id,
             addrs,
             topic: topic_string,
+            topic_prefix,
+            http_url,
+            providers: Vec::new(),
+            ipns_name: None,
+        };
+
+        let (tracked, mut cancel) = groups.track(state.id, state.tag.clone(), ticket.clone());
+        let p2p2 = p2p_rpc.clone();
+        let (subscribers_sender, subscribers) = watch::channel(0);
+        let gossip_task_source = match transfer_mode {
+            TransferMode::Gossip => tokio::task::spawn(async move {
+                let _tracked = tracked;
+                let mut current_peer = None;
+                let mut published = false;
+                let mut refresh = tokio::time::interval(SUBSCRIBERS_INTERVAL);
+                loop {
+                    let event = tokio::select! {
+                        biased;
+                        _ = deadline_elapsed(deadline) => {
+                            let timeout = Timeout(timeout.expect("deadline without timeout"));
+                            warn!("{}", timeout);
+                            p2p2.gossipsub_unsubscribe(th.clone()).await.ok();
+                            done_sender.send(Err(timeout.into())).ok();
+                            break;
+                        }
+                        _ = &mut cancel => {
+                            info!("transfer cancelled");
+                            p2p2.gossipsub_unsubscribe(th.clone()).await.ok();
+                            done_sender.send(Err(Cancelled.into())).ok();
+                            break;
+                        }
+                        _ = deadline_elapsed(min_subscribers_deadline),
+                            if !published && min_subscribers > 1 =>
+                        {
+                            info!(
+                                "{} of {} subscribers joined in time",
+                                *subscribers_sender.borrow(),
+                                min_subscribers
+                            );
+                            min_subscribers = 1;
+                            None
+                        }
+                        event = subscription_events.recv() => match event {
+                            Some(event) => Some(event),
+                            None => break,
+                        },
+                        event = gossip_events.recv() => match event {
+                            Some(event) => Some(event),
+                            None => break,
+                        },
+                        _ = refresh.tick() => {
+                            let peers = refresh_subscribers(&p2p2, &th, &subscribers_sender).await;
+                            // subscriptions are not reported when attached over rpc
+                            if current_peer.is_none() {
+                                current_peer = peers.first().copied();
+                            }
+                            None
+                        }
+                    };
+                    match &event {
+                        Some(
+                            GossipsubEvent::Subscribed { topic, .. }
+                            | GossipsubEvent::Unsubscribed { topic, .. },
+                        ) if *topic == th => {
+                            refresh_subscribers(&p2p2, &th, &subscribers_sender).await;
+                        }
+                        _ => {}
+                    }
+                    if !published && *subscribers_sender.borrow() >= min_subscribers {
+                        published = true;
+                        if let Err(err) = p2p2.gossipsub_publish(th.clone(), start.clone()).await {
+                            warn!("failed to publish start message: {:?}", err);
+                        }
+                    }
+                    match event {
+                        Some(GossipsubEvent::Subscribed { peer_id, topic }) => {
+                            if topic == th && current_peer.is_none() {
+                                info!("connected to {}", peer_id);
+                                current_peer = Some(peer_id);
+                            }
+                        }
+                        Some(GossipsubEvent::Message { from, message, .. }) => {
+                            debug!("received message from {}", from);
+                            if let Some(current_peer) = current_peer {
+                                if from == current_peer {
+                                    match decode_control_message(&message.data) {
+                                        Ok(ReceiverMessage::FinishOk) => {
+                                            info!("finished transfer");
+                                            done_sender.send(Ok(())).ok();
+                                            break;
+                                        }
+                                        Ok(ReceiverMessage::FinishError(err)) => {
+                                            info!("transfer failed: {}", err);
+                                            done_sender.send(Err(anyhow!("{}", err))).ok();
+                                            break;
+                                        }
+                                        Err(err) => {
+                                            warn!("unexpected message: {:?}", err);
+                                        }
+                                    }
+                                }
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+            }),
+            TransferMode::Direct => tokio::task::spawn(async move {
+                let _tracked = tracked;
+                let mut poll = tokio::time::interval(DIRECT_POLL_INTERVAL);
+                loop {
+                    tokio::select! {
+                        biased;
+                        _ = deadline_elapsed(deadline) => {
+                            let timeout = Timeout(timeout.expect("deadline without timeout"));
+                            warn!("{}", timeout);
+                            done_sender.send(Err(timeout.into())).ok();
+                            break;
+                        }
+                        _ = &mut cancel => {
+                            info!("transfer cancelled");
+                            done_sender.send(Err(Cancelled.into())).ok();
+                            break;
+                        }
+                        _ = poll.tick() => {}
+                    }
+                    if let Some(res) = fetch_direct_finish(&p2p2, &th, &subscribers_sender).await {
+                        done_sender.send(res).ok();
+                        break;
+                    }
+                }
+                p2p2.gossipsub_release(th).await.ok();
+            }),
         };
 
         Ok(Transfer {
             ticket,
+            state,
+            topic: topic_hash,
+            subscribers,
             gossip_task_source,
             done_receiver,
             gossip_task,
+            car_server,
             p2p,
+            transfer_mode,
+            _active: active,
         })
     }
 
@@ -156,29 +668,108 @@ impl Sender {
         data: Bytes,
     ) -> Result<Transfer> {
         let name = name.into();
-        // wrap in directory to preserve the name
-        let file = FileBuilder::new()
-            .name(name)
-            .content_bytes(data)
-            .build()
-            .await?;
-        let root_dir = DirectoryBuilder::new().add_file(file);
+        let root_dir = data_dir_builder(name.clone(), data).await?;
+        self.transfer(None, Some(name), root_dir).await
+    }
 
-        self.transfer_from_dir_builder(root_dir).await
+    /// Like [`Sender::transfer_from_data`], but adds the transfer to the group `tag`, see
+    /// [`Sender::transfer_from_dir_builder_tagged`].
+    pub async fn transfer_from_data_tagged(
+        self,
+        tag: impl Into<String>,
+        name: impl Into<String>,
+        data: Bytes,
+    ) -> Result<Transfer> {
+        let name = name.into();
+        let root_dir = data_dir_builder(name.clone(), data).await?;
+        self.transfer(Some(tag.into()), Some(name), root_dir).await
     }
 
+    /// Picks the id of a new transfer, which names its topic `{topic_prefix}-{id}`.
+    ///
+    /// The id is random rather than a counter: a counter restarting at 0 with every sender
+    /// would reuse topics of earlier transfers, and receivers still subscribed to one of
+    /// those would mistake the new transfer for the one they were waiting on. No state has
+    /// to be persisted across restarts to avoid that.
     fn next_id(&self) -> u64 {
         rand::thread_rng().gen()
     }
 }
 
+/// Wraps `data` in a directory, to preserve its name.
+async fn data_dir_builder(name: String, data: Bytes) -> Result<DirectoryBuilder> {
+    let file = FileBuilder::new()
+        .name(name)
+        .content_bytes(data)
+        .build()
+        .await?;
+    Ok(DirectoryBuilder::new().add_file(file))
+}
+
+/// Returns whether the complete DAG at `root` is in the `store`.
+///
+/// The DAG is walked level by level, checking the presence of each level with a single
+/// `has_many` call, until the first missing block. Each block is visited once, and walks
+/// deeper than [`MAX_DAG_DEPTH`] levels fail.
+async fn has_full_dag(store: &StoreClient, root: Cid) -> Result<bool> {
+    let mut visited = HashSet::from([root]);
+    let mut level = vec![root];
+    for _ in 0..MAX_DAG_DEPTH {
+        if level.is_empty() {
+            return Ok(true);
+        }
+        if store.has_many(level.clone()).await?.contains(&false) {
+            return Ok(false);
+        }
+        let links: Vec<Option<Vec<Cid>>> = futures::stream::iter(level)
+            .map(|cid| store.get_links(cid))
+            .buffered(GET_LINKS_CONCURRENCY)
+            .try_collect()
+            .await?;
+        level = Vec::new();
+        for links in links {
+            match links {
+                Some(links) => level.extend(links.into_iter().filter(|link| visited.insert(*link))),
+                // removed since checking its presence
+                None => return Ok(false),
+            }
+        }
+    }
+    bail!("DAG at {} is deeper than {} levels", root, MAX_DAG_DEPTH)
+}
+
+/// What another [`Sender`] needs to know to serve a [`Transfer`], see
+/// [`Transfer::handoff`] and [`Sender::resume_transfer`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TransferState {
+    /// The id of the transfer, which names its topic in [`TransferMode::Gossip`].
+    pub id: u64,
+    /// The root of the content.
+    pub root: Cid,
+    /// How many blocks the content consists of.
+    pub num_parts: usize,
+    /// The topic the transfer is negotiated on, as listed in its [`Ticket`].
+    pub topic: String,
+    /// The name of the data, for transfers created with [`Sender::transfer_from_data`].
+    pub name: Option<String>,
+    /// The group the transfer was added to, if it was created with a tag.
+    pub tag: Option<String>,
+}
+
 #[derive(Debug)]
 pub struct Transfer {
     p2p: P2pNode,
     ticket: Ticket,
+    state: TransferState,
+    topic: TopicHash,
+    subscribers: watch::Receiver<usize>,
     done_receiver: OneShotReceiver<Result<()>>,
     gossip_task: JoinHandle<()>,
     gossip_task_source: JoinHandle<()>,
+    car_server: Option<CarServer>,
+    transfer_mode: TransferMode,
+    /// Counted by the [`AnnounceLimit`] of the sender until dropped.
+    _active: ActiveTransfer,
 }
 
 impl Transfer {
@@ -186,13 +777,779 @@ impl Transfer {
         &self.ticket
     }
 
+    /// The group this transfer was added to, if it was created with a tag.
+    pub fn tag(&self) -> Option<&str> {
+        self.state.tag.as_deref()
+    }
+
+    /// The state another [`Sender`] needs to serve this transfer, see
+    /// [`Sender::resume_transfer`].
+    pub fn state(&self) -> &TransferState {
+        &self.state
+    }
+
+    /// Returns the number of peers currently subscribed to the topic of this transfer,
+    /// as known to gossipsub.
+    ///
+    /// In [`TransferMode::Direct`] there are no subscriptions, the peers that fetched the
+    /// start message of this transfer are counted instead.
+    pub async fn subscriber_count(&self) -> Result<usize> {
+        let p2p = self.p2p.rpc().try_p2p()?;
+        match self.transfer_mode {
+            TransferMode::Gossip => Ok(topic_subscribers(&p2p, &self.topic).await?.len()),
+            TransferMode::Direct => Ok(p2p.gossipsub_fetched_by(self.topic.clone()).await?.len()),
+        }
+    }
+
+    /// Watches the number of peers subscribed to the topic of this transfer, see
+    /// [`Transfer::subscriber_count`].
+    ///
+    /// The value is updated as peers join and leave, until the transfer is done.
+    pub fn subscribers(&self) -> watch::Receiver<usize> {
+        self.subscribers.clone()
+    }
+
     /// Waits until the transfer is done.
+    ///
+    /// All resources of the transfer are released, also if it failed, e.g. with a
+    /// [`Timeout`].
     pub async fn done(self) -> Result<()> {
-        self.done_receiver.await??;
+        let res = match self.done_receiver.await {
+            Ok(res) => res,
+            Err(err) => Err(err.into()),
+        };
         self.gossip_task.abort();
         self.gossip_task_source.await?;
+        if let Some(car_server) = self.car_server {
+            car_server.close().await?;
+        }
+        self.p2p.close().await?;
+
+        res
+    }
+
+    /// Stops serving this transfer, so another [`Sender`] sharing the same store can resume
+    /// it from the returned state, see [`Sender::resume_transfer`].
+    ///
+    /// All resources of the transfer are released, and it is no longer listed in its group.
+    /// A node the sender is attached to keeps running, see [`Sender::attach`].
+    pub async fn handoff(self) -> Result<TransferState> {
+        self.gossip_task.abort();
+        self.gossip_task_source.abort();
+        if let Some(car_server) = self.car_server {
+            car_server.close().await?;
+        }
         self.p2p.close().await?;
 
+        Ok(self.state)
+    }
+}
+
+/// Tracks the transfers of [`Sender`]s that are not done yet, to list and cancel them by
+/// the group they were tagged with, see [`Sender::transfer_from_data_tagged`].
+///
+/// Clones share their transfers, so one instance can cover several senders, see
+/// [`Sender::with_groups`].
+#[derive(Debug, Clone, Default)]
+pub struct TransferGroups {
+    transfers: Arc<Mutex<HashMap<u64, TrackedTransfer>>>,
+}
+
+impl TransferGroups {
+    /// Returns the tickets of the transfers in the group `tag` that are not done yet.
+    pub fn transfers_in_group(&self, tag: &str) -> Vec<Ticket> {
+        self.transfers
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|transfer| transfer.tag.as_deref() == Some(tag))
+            .map(|transfer| transfer.ticket.clone())
+            .collect()
+    }
+
+    /// Cancels all transfers in the group `tag` that are not done yet, returning how many
+    /// were cancelled.
+    ///
+    /// Cancelled transfers stop answering receivers and unsubscribe from their topic, and
+    /// [`Transfer::done`] fails with a [`Cancelled`] error.
+    pub fn cancel_group(&self, tag: &str) -> usize {
+        let mut transfers = self.transfers.lock().unwrap();
+        let ids: Vec<u64> = transfers
+            .iter()
+            .filter(|(_, transfer)| transfer.tag.as_deref() == Some(tag))
+            .map(|(id, _)| *id)
+            .collect();
+        for id in &ids {
+            if let Some(transfer) = transfers.remove(id) {
+                transfer.cancel.send(()).ok();
+            }
+        }
+        ids.len()
+    }
+
+    /// Adds the transfer `id`, returning the receiver of its cancellation.
+    fn track(
+        &self,
+        id: u64,
+        tag: Option<String>,
+        ticket: Ticket,
+    ) -> (Tracked, tokio::sync::oneshot::Receiver<()>) {
+        let (cancel, cancelled) = tokio::sync::oneshot::channel();
+        let transfer = TrackedTransfer {
+            tag,
+            ticket,
+            cancel,
+        };
+        self.transfers.lock().unwrap().insert(id, transfer);
+        let tracked = Tracked {
+            id,
+            transfers: self.transfers.clone(),
+        };
+        (tracked, cancelled)
+    }
+}
+
+/// A transfer of a [`Sender`] that is not done yet.
+#[derive(Debug)]
+struct TrackedTransfer {
+    tag: Option<String>,
+    ticket: Ticket,
+    cancel: tokio::sync::oneshot::Sender<()>,
+}
+
+/// Keeps the transfer `id` in its [`TransferGroups`] until dropped.
+#[derive(Debug)]
+struct Tracked {
+    id: u64,
+    transfers: Arc<Mutex<HashMap<u64, TrackedTransfer>>>,
+}
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        self.transfers.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// Waits until `deadline`, or forever if there is none.
+async fn deadline_elapsed(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => futures::future::pending().await,
+    }
+}
+
+/// Publishes the start message right after subscribing to `topic`.
+///
+/// Usually no receiver is subscribed yet, so this only has the node retain it for receivers
+/// that ask for it directly, which covers those whose subscription is missed.
+async fn publish_start(p2p: &P2pClient, topic: &TopicHash, start: Bytes) {
+    if let Err(err) = p2p.gossipsub_publish(topic.clone(), start).await {
+        debug!("start message retained, but not published: {:?}", err);
+    }
+}
+
+/// Counts the peers subscribed to `topic`.
+async fn topic_subscribers(p2p: &P2pClient, topic: &TopicHash) -> Result<Vec<PeerId>> {
+    let peers = p2p.gossipsub_all_peers().await?;
+    Ok(peers
+        .into_iter()
+        .filter(|(_, topics)| topics.contains(topic))
+        .map(|(peer_id, _)| peer_id)
+        .collect())
+}
+
+/// Updates the number of subscribers to `topic` in `sender`, returning the subscribers.
+async fn refresh_subscribers(
+    p2p: &P2pClient,
+    topic: &TopicHash,
+    sender: &watch::Sender<usize>,
+) -> Vec<PeerId> {
+    match topic_subscribers(p2p, topic).await {
+        Ok(peers) => {
+            let count = peers.len();
+            sender.send_if_modified(|current| std::mem::replace(current, count) != count);
+            peers
+        }
+        Err(err) => {
+            warn!("failed to count subscribers: {:?}", err);
+            Vec::new()
+        }
+    }
+}
+
+/// Asks the receivers of a transfer in [`TransferMode::Direct`], the peers that fetched its
+/// start message, for the message they retained on `topic`, returning the outcome reported
+/// by the first receiver that finished.
+///
+/// Also updates the number of `subscribers` to the number of receivers.
+async fn fetch_direct_finish(
+    p2p: &P2pClient,
+    topic: &TopicHash,
+    subscribers: &watch::Sender<usize>,
+) -> Option<Result<()>> {
+    let peers = match p2p.gossipsub_fetched_by(topic.clone()).await {
+        Ok(peers) => peers,
+        Err(err) => {
+            warn!("failed to list receivers: {:?}", err);
+            return None;
+        }
+    };
+    let count = peers.len();
+    subscribers.send_if_modified(|current| std::mem::replace(current, count) != count);
+    for peer_id in peers {
+        let data = match p2p.gossipsub_fetch_last(peer_id, topic.clone()).await {
+            Ok(Some(data)) => data,
+            Ok(None) => continue,
+            Err(err) => {
+                debug!("failed to ask {} whether it finished: {:?}", peer_id, err);
+                continue;
+            }
+        };
+        match decode_control_message(&data) {
+            Ok(ReceiverMessage::FinishOk) => {
+                info!("{} finished transfer", peer_id);
+                return Some(Ok(()));
+            }
+            Ok(ReceiverMessage::FinishError(err)) => {
+                info!("transfer to {} failed: {}", peer_id, err);
+                return Some(Err(anyhow!("{}", err)));
+            }
+            Err(err) => warn!("unexpected message from {}: {:?}", peer_id, err),
+        }
+    }
+    None
+}
+
+/// Limits how many transfers run at the same time: those offered by [`announce`], and the
+/// transfers of the [`Sender`]s and [`Receiver`](crate::Receiver)s the limit is passed to.
+///
+/// Clones share their count, so one limit covers all transfers it is passed to, e.g.
+/// those of one iroh system or of one p2p node.
+#[derive(Debug, Clone)]
+pub struct AnnounceLimit {
+    max_active: usize,
+    active: Arc<AtomicUsize>,
+}
+
+impl Default for AnnounceLimit {
+    fn default() -> Self {
+        Self::new(MAX_ACTIVE_ANNOUNCEMENTS)
+    }
+}
+
+impl AnnounceLimit {
+    pub fn new(max_active: usize) -> Self {
+        AnnounceLimit {
+            max_active,
+            active: Default::default(),
+        }
+    }
+
+    /// The number of transfers running under this limit.
+    pub fn active(&self) -> usize {
+        self.active.load(Ordering::SeqCst)
+    }
+
+    /// Counts a new transfer, unless the limit is reached already.
+    pub(crate) fn acquire(&self) -> Result<ActiveTransfer> {
+        let max_active = self.max_active;
+        self.active
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |active| {
+                (active < max_active).then(|| active + 1)
+            })
+            .map_err(|active| {
+                anyhow!(
+                    "too many active transfers: {} of at most {}",
+                    active,
+                    max_active
+                )
+            })?;
+        Ok(ActiveTransfer {
+            active: self.active.clone(),
+        })
+    }
+}
+
+/// A running transfer, counted by its [`AnnounceLimit`] until dropped.
+#[derive(Debug)]
+pub(crate) struct ActiveTransfer {
+    active: Arc<AtomicUsize>,
+}
+
+impl Drop for ActiveTransfer {
+    fn drop(&mut self) {
+        self.active.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// The topic the transfer `id` is negotiated on.
+pub(crate) fn transfer_topic(prefix: &str, id: u64) -> Sha256Topic {
+    Sha256Topic::new(format!("{prefix}-{id}"))
+}
+
+/// A transfer offered by [`announce`], until it times out or is stopped.
+///
+/// Dropping it stops the announcement as well.
+#[derive(Debug)]
+pub struct Announcement {
+    ticket: Ticket,
+    stop: tokio::sync::oneshot::Sender<()>,
+    task: JoinHandle<()>,
+}
+
+impl Announcement {
+    /// The ticket to hand to receivers.
+    pub fn ticket(&self) -> &Ticket {
+        &self.ticket
+    }
+
+    /// Stops offering the transfer, receivers that did not join yet can no longer do so.
+    pub async fn stop(self) {
+        drop(self.stop);
+        self.task.await.ok();
+    }
+
+    /// Waits until the announcement timed out.
+    pub async fn done(self) {
+        let Announcement { stop, task, .. } = self;
+        task.await.ok();
+        drop(stop);
+    }
+}
+
+/// Adds the file or directory at `path` to the store of the iroh system behind `client`
+/// and offers it to receivers with [`announce`].
+///
+/// The content is served by the p2p node of that system from its store, so there is no
+/// separate sender to keep alive. Receivers can join for [`ANNOUNCE_TIMEOUT`], or until the
+/// announcement is stopped or dropped. Fails if `limit` is reached.
+///
+/// Up to `concurrency` files are added at the same time, see
+/// [`DirectoryBuilder::concurrency`].
+pub async fn add_and_share(
+    client: &Client,
+    path: &Path,
+    concurrency: usize,
+    limit: &AnnounceLimit,
+) -> Result<Announcement> {
+    let config = UnixfsConfig {
+        // receivers expect a directory, which also preserves the file name
+        wrap: true,
+        keep_name: false,
+        custom_metadata: Default::default(),
+        chunker: Some(ChunkerConfig::Fixed(DEFAULT_CHUNKS_SIZE)),
+        raw_leaf_limit: None,
+        concurrency,
+        directory_layout: Default::default(),
+    };
+    let store = client.try_store()?;
+    let (root, num_parts) = {
+        let puts = Entry::from_path(path, config)
+            .await?
+            .encode()
+            .await?
+            .map(|part| {
+                let store = store.clone();
+                async move {
+                    let (cid, bytes, links) = part?.into_parts();
+                    store.put(cid, bytes, links).await?;
+                    anyhow::Ok(cid)
+                }
+            })
+            .buffered(DEFAULT_WRITE_QUEUE);
+        tokio::pin!(puts);
+        let mut num_parts = 0;
+        let mut root_cid = None;
+        // the writes complete in order, so the root, which is encoded last, comes last
+        while let Some(cid) = puts.next().await {
+            num_parts += 1;
+            root_cid = Some(cid?);
+        }
+        (root_cid.context("nothing to share")?, num_parts)
+    };
+    ensure!(store.has(root).await?, "{} is missing from the store", root);
+
+    announce(
+        client.try_p2p()?,
+        root,
+        num_parts,
+        ANNOUNCE_TIMEOUT,
+        DEFAULT_TOPIC_PREFIX,
+        limit,
+    )
+    .await
+}
+
+/// Offers `root`, which must already be in the store of the node behind `p2p`, to
+/// receivers of the ticket of the returned [`Announcement`].
+///
+/// Unlike [`Sender`] this does not run a separate node, so it can be used with a running
+/// iroh system. As gossip events are not available over rpc, the start message is
+/// published whenever a new peer joins the topic, until `timeout` has elapsed or the
+/// announcement is stopped.
+/// The topic is created with `topic_prefix`, see [`Sender::with_topic_prefix`].
+///
+/// Each announcement keeps a gossipsub subscription and a task alive until `timeout`.
+/// To bound the resources used, announcing fails once `limit` is reached, until one of
+/// the running announcements times out.
+pub async fn announce(
+    p2p: P2pClient,
+    root: Cid,
+    num_parts: usize,
+    timeout: Duration,
+    topic_prefix: &str,
+    limit: &AnnounceLimit,
+) -> Result<Announcement> {
+    let active = limit.acquire()?;
+    let id: u64 = rand::thread_rng().gen();
+    let topic_hash = transfer_topic(topic_prefix, id).hash();
+    p2p.gossipsub_subscribe(topic_hash.clone()).await?;
+    let start: Bytes = encode_control_message(&SenderMessage::Start { root, num_parts })
+        .expect("serialize failure")
+        .into();
+    publish_start(&p2p, &topic_hash, start.clone()).await;
+
+    let (peer_id, addrs) = p2p
+        .get_listening_addrs()
+        .await
+        .context("getting p2p info")?;
+    let ticket = Ticket {
+        peer_id,
+        addrs: select_ticket_addrs(addrs, DEFAULT_MAX_TICKET_ADDRS),
+        topic: topic_hash.to_string(),
+        topic_prefix: topic_prefix.to_string(),
+        http_url: None,
+        providers: Vec::new(),
+        ipns_name: None,
+    };
+
+    let (stop, mut stopped) = tokio::sync::oneshot::channel();
+    let task = tokio::task::spawn(async move {
+        let _active = active;
+        let deadline = tokio::time::Instant::now() + timeout;
+        let mut interval = tokio::time::interval(ANNOUNCE_INTERVAL);
+        let mut announced = HashSet::new();
+        loop {
+            tokio::select! {
+                biased;
+                _ = &mut stopped => {
+                    debug!("announcement of {} stopped", root);
+                    break;
+                }
+                _ = tokio::time::sleep_until(deadline) => break,
+                _ = interval.tick() => {}
+            }
+            let peers = match p2p.gossipsub_all_peers().await {
+                Ok(peers) => peers,
+                Err(err) => {
+                    warn!("failed to list gossipsub peers: {:?}", err);
+                    continue;
+                }
+            };
+            let joined = peers
+                .into_iter()
+                .filter(|(_, topics)| topics.contains(&topic_hash))
+                .filter(|(peer_id, _)| announced.insert(*peer_id))
+                .count();
+            if joined > 0 {
+                info!("announcing {} to {} new peers", root, joined);
+                if let Err(err) = p2p
+                    .gossipsub_publish(topic_hash.clone(), start.clone())
+                    .await
+                {
+                    warn!("failed to announce {}: {:?}", root, err);
+                }
+            }
+        }
+        p2p.gossipsub_unsubscribe(topic_hash).await.ok();
+    });
+
+    Ok(Announcement { ticket, stop, task })
+}
+
+/// Picks the `max` most useful of `addrs` to list in a ticket.
+///
+/// Addresses are ranked by how likely receivers can reach them: publicly routable ones,
+/// including DNS names, first, then relayed ones, then ones in private networks and
+/// finally loopback and other unroutable ones. Addresses of the same rank keep their order.
+pub fn select_ticket_addrs(mut addrs: Vec<Multiaddr>, max: usize) -> Vec<Multiaddr> {
+    addrs.sort_by_key(addr_rank);
+    addrs.truncate(max);
+    addrs
+}
+
+fn addr_rank(addr: &Multiaddr) -> u8 {
+    if addr.iter().any(|p| p == Protocol::P2pCircuit) {
+        return 1;
+    }
+    let ip: IpAddr = match addr.iter().next() {
+        Some(Protocol::Ip4(ip)) => ip.into(),
+        Some(Protocol::Ip6(ip)) => ip.into(),
+        Some(Protocol::Dns(_) | Protocol::Dns4(_) | Protocol::Dns6(_) | Protocol::Dnsaddr(_)) => {
+            return 0;
+        }
+        _ => return 3,
+    };
+    match ip {
+        IpAddr::V4(ip) if is_public_ipv4(&ip) => 0,
+        IpAddr::V6(ip) if is_public_ipv6(&ip) => 0,
+        IpAddr::V4(ip) if ip.is_private() || ip.is_link_local() => 2,
+        // unique local and link local addresses
+        IpAddr::V6(ip) if (ip.segments()[0] & 0xfe00) == 0xfc00 => 2,
+        IpAddr::V6(ip) if (ip.segments()[0] & 0xffc0) == 0xfe80 => 2,
+        _ => 3,
+    }
+}
+
+fn is_public_ipv4(ip: &Ipv4Addr) -> bool {
+    // shared address space of carrier grade NATs, 100.64.0.0/10
+    let shared = ip.octets()[0] == 100 && (ip.octets()[1] & 0xc0) == 64;
+    !(ip.is_private()
+        || ip.is_loopback()
+        || ip.is_link_local()
+        || ip.is_unspecified()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || shared)
+}
+
+fn is_public_ipv6(ip: &Ipv6Addr) -> bool {
+    let segments = ip.segments();
+    // global unicast addresses, 2000::/3, except for documentation ones, 2001:db8::/32
+    (segments[0] & 0xe000) == 0x2000 && segments[..2] != [0x2001, 0xdb8]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cid::multihash::{Code, MultihashDigest};
+
+    #[test]
+    fn test_select_ticket_addrs() {
+        let addrs: Vec<Multiaddr> = [
+            "/ip4/127.0.0.1/tcp/4444",
+            "/ip4/192.168.1.2/tcp/4444",
+            "/ip4/1.2.3.4/tcp/4444/p2p-circuit",
+            "/ip6/fe80::1/tcp/4444",
+            "/ip4/100.64.0.1/tcp/4444",
+            "/ip4/8.8.8.8/udp/4444/quic",
+            "/ip6/2a01:4f8::1/tcp/4444",
+            "/dns4/example.com/tcp/4444",
+        ]
+        .iter()
+        .map(|addr| addr.parse().unwrap())
+        .collect();
+
+        let selected = select_ticket_addrs(addrs.clone(), 4);
+        // public ones, then the relayed one
+        let expected = [&addrs[5], &addrs[6], &addrs[7], &addrs[2]];
+        assert_eq!(selected.iter().collect::<Vec<_>>(), expected);
+
+        // then private networks, then loopback and the shared address space
+        let all = select_ticket_addrs(addrs.clone(), 100);
+        let expected = [&addrs[1], &addrs[3], &addrs[0], &addrs[4]];
+        assert_eq!(all[4..].iter().collect::<Vec<_>>(), expected);
+    }
+
+    #[test]
+    fn test_transfer_groups() {
+        let ticket = |topic: &str| Ticket {
+            peer_id: libp2p::PeerId::random(),
+            addrs: Vec::new(),
+            topic: topic.to_string(),
+            topic_prefix: DEFAULT_TOPIC_PREFIX.to_string(),
+            http_url: None,
+            providers: Vec::new(),
+            ipns_name: None,
+        };
+        let groups = TransferGroups::default();
+        let group = "group";
+        let (first, mut first_cancel) = groups.track(1, Some(group.to_string()), ticket("a"));
+        let (second, mut second_cancel) = groups.track(2, Some(group.to_string()), ticket("b"));
+        let (other, mut other_cancel) = groups.track(3, None, ticket("c"));
+
+        // other instances do not share the transfers, clones do
+        assert!(TransferGroups::default()
+            .transfers_in_group(group)
+            .is_empty());
+        assert_eq!(groups.clone().transfers_in_group(group).len(), 2);
+
+        let mut topics: Vec<_> = groups
+            .transfers_in_group(group)
+            .into_iter()
+            .map(|ticket| ticket.topic)
+            .collect();
+        topics.sort();
+        assert_eq!(topics, ["a", "b"]);
+
+        // transfers leave their group once done
+        drop(second);
+        assert_eq!(groups.transfers_in_group(group).len(), 1);
+        assert!(second_cancel.try_recv().is_err());
+
+        assert_eq!(groups.cancel_group(group), 1);
+        assert!(first_cancel.try_recv().is_ok());
+        assert!(groups.transfers_in_group(group).is_empty());
+        assert_eq!(groups.cancel_group(group), 0);
+        drop(first);
+
+        // untagged transfers are not part of any group
+        assert!(other_cancel.try_recv().is_err());
+        drop(other);
+    }
+
+    #[tokio::test]
+    async fn test_cancel_group_fails_done() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let sender = Sender::new(0, &dir.path().join("db")).await?;
+        let groups = sender.groups().clone();
+        let transfer = sender
+            .transfer_from_data_tagged("group", "foo.txt", Bytes::from_static(b"hello"))
+            .await?;
+        assert_eq!(groups.transfers_in_group("group").len(), 1);
+
+        assert_eq!(groups.cancel_group("group"), 1);
+        let err = tokio::time::timeout(Duration::from_secs(10), transfer.done())
+            .await?
+            .unwrap_err();
+        assert!(err.downcast_ref::<Cancelled>().is_some(), "{err:?}");
+        assert!(groups.transfers_in_group("group").is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_ipns_ticket() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let sender = Sender::new(0, &dir.path().join("db"))
+            .await?
+            .with_topic_prefix("iroh-share-test");
+
+        let ticket = sender.ipns_ticket("example.com").await?;
+        ticket.validate()?;
+        assert_eq!(ticket.ipns_name.as_deref(), Some("example.com"));
+        assert_eq!(ticket.topic_prefix, "iroh-share-test");
+        assert_eq!(
+            ticket.peer_id,
+            sender.p2p.rpc().try_p2p()?.local_peer_id().await?
+        );
+        assert!(!ticket.addrs.is_empty());
+        // the ticket survives sharing it as bytes
+        assert_eq!(Ticket::from_bytes(&ticket.as_bytes())?, ticket);
+
+        // cids are not names receivers can resolve
+        let cid = Cid::new_v1(0x55, Code::Sha2_256.digest(b"hello")).to_string();
+        assert!(sender.ipns_ticket(cid).await.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_announce_limit() {
+        let limit = AnnounceLimit::new(1);
+        let first = limit.acquire().unwrap();
+        let err = limit.clone().acquire().unwrap_err();
+        assert!(err.to_string().contains("too many active transfers"));
+        // other limits are not affected
+        let other = AnnounceLimit::new(1);
+        let _other = other.acquire().unwrap();
+
+        drop(first);
+        let _second = limit.acquire().unwrap();
+        assert_eq!(limit.active(), 1);
+    }
+    #[tokio::test]
+    async fn test_transfer_limit() -> Result<()> {
+        let limit = AnnounceLimit::new(1);
+        let dir = tempfile::tempdir()?;
+        let sender = Sender::new(0, &dir.path().join("db"))
+            .await?
+            .with_announce_limit(limit.clone());
+        let transfer = sender
+            .transfer_from_data("foo.txt", Bytes::from_static(b"hello"))
+            .await?;
+        assert_eq!(limit.active(), 1);
+
+        // a transfer over the cap is rejected, by another sender as well as by a receiver
+        let other_dir = tempfile::tempdir()?;
+        let sender = Sender::new(0, &other_dir.path().join("db"))
+            .await?
+            .with_announce_limit(limit.clone());
+        let err = sender
+            .transfer_from_data("bar.txt", Bytes::from_static(b"world"))
+            .await
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("too many active transfers"),
+            "{err:?}"
+        );
+        let receiver_dir = tempfile::tempdir()?;
+        let receiver = crate::Receiver::new(0, &receiver_dir.path().join("db"))
+            .await?
+            .with_announce_limit(limit.clone());
+        let err = receiver
+            .transfer_from_ticket(transfer.ticket())
+            .await
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("too many active transfers"),
+            "{err:?}"
+        );
+        assert_eq!(limit.active(), 1);
+
+        // dropping the transfer releases its slot
+        drop(transfer);
+        assert_eq!(limit.active(), 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_announcement_stop() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let (node, _events) =
+            P2pNode::new(0, &dir.path().join("db"), NodeOptions::default()).await?;
+        let p2p = node.rpc().try_p2p()?;
+        let limit = AnnounceLimit::new(1);
+
+        let announcement = announce(
+            p2p.clone(),
+            Cid::default(),
+            1,
+            ANNOUNCE_TIMEOUT,
+            DEFAULT_TOPIC_PREFIX,
+            &limit,
+        )
+        .await?;
+        let topic = TopicHash::from_raw(&announcement.ticket().topic);
+        assert!(p2p.gossipsub_topics().await?.contains(&topic));
+        assert_eq!(limit.active(), 1);
+
+        // stopping releases the subscription and the slot right away, not after the timeout
+        announcement.stop().await;
+        assert!(!p2p.gossipsub_topics().await?.contains(&topic));
+        assert_eq!(limit.active(), 0);
+
+        node.close().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_has_full_dag() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let (node, _events) =
+            P2pNode::new(0, &dir.path().join("db"), NodeOptions::default()).await?;
+        let store = node.rpc().try_store()?;
+
+        let leaf = Bytes::from_static(b"leaf");
+        let leaf_cid = Cid::new_v1(0x55, Code::Sha2_256.digest(&leaf));
+        let root = Bytes::from_static(b"root");
+        let root_cid = Cid::new_v1(0x70, Code::Sha2_256.digest(&root));
+        store.put(root_cid, root, vec![leaf_cid]).await?;
+        // the root alone is not enough
+        assert!(!has_full_dag(&store, root_cid).await?);
+
+        store.put(leaf_cid, leaf, Vec::new()).await?;
+        assert!(has_full_dag(&store, root_cid).await?);
+
+        node.close().await?;
         Ok(())
     }
 }
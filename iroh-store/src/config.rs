@@ -14,6 +14,9 @@ pub const CONFIG_FILE_NAME: &str = "store.config.toml";
 /// For example, `IROH_STORE_PATH=/path/to/config` would set the value of the `Config.path` field
 pub const ENV_PREFIX: &str = "IROH_STORE";
 
+/// Default size of the block cache in bytes.
+pub const DEFAULT_CACHE_SIZE: usize = 128 * 1024 * 1024;
+
 /// the path to data directory. If arg_path is `None`, the default iroh_data_path()/store is used
 /// iroh_data_path() returns an operating system-specific directory
 pub fn config_data_path(arg_path: Option<PathBuf>) -> Result<PathBuf> {
@@ -73,6 +76,17 @@ pub struct Config {
     /// Only used to extract the listening address from the `store_addr` field.
     // TODO: split off listening address from RpcClientConfig.
     pub rpc_client: RpcClientConfig,
+    /// Size of the block cache in bytes. Defaults to [`DEFAULT_CACHE_SIZE`].
+    #[serde(default)]
+    pub cache_size: Option<usize>,
+    /// Maximum number of files the database keeps open. Unlimited if not set.
+    #[serde(default)]
+    pub max_open_files: Option<i32>,
+    /// Fraction of the stored blocks, from `0.0` to `1.0`, to verify in the background
+    /// when the store server starts, see [`crate::Store::verify_integrity`]. Corrupt
+    /// blocks are only reported. Not verified if not set.
+    #[serde(default)]
+    pub verify_on_start: Option<f64>,
 }
 
 impl From<ServerConfig> for Config {
@@ -91,6 +105,9 @@ impl Config {
         Self {
             path,
             rpc_client: Default::default(),
+            cache_size: None,
+            max_open_files: None,
+            verify_on_start: None,
         }
     }
 
@@ -102,6 +119,9 @@ impl Config {
                 store_addr: Some(addr),
                 ..Default::default()
             },
+            cache_size: None,
+            max_open_files: None,
+            verify_on_start: None,
         }
     }
 
@@ -123,6 +143,15 @@ impl Source for Config {
             .ok_or_else(|| ConfigError::Foreign("No `path` set. Path is required.".into()))?;
         insert_into_config_map(&mut map, "path", path);
         insert_into_config_map(&mut map, "rpc_client", self.rpc_client.collect()?);
+        if let Some(cache_size) = self.cache_size {
+            insert_into_config_map(&mut map, "cache_size", cache_size as u64);
+        }
+        if let Some(max_open_files) = self.max_open_files {
+            insert_into_config_map(&mut map, "max_open_files", max_open_files as i64);
+        }
+        if let Some(verify_on_start) = self.verify_on_start {
+            insert_into_config_map(&mut map, "verify_on_start", verify_on_start);
+        }
         Ok(map)
     }
 }
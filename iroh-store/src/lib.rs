@@ -6,6 +6,6 @@ pub mod rpc;
 mod store;
 
 pub use crate::config::Config;
-pub use crate::store::Store;
+pub use crate::store::{GcReport, Store, VerifyOptions, VerifyProgress};
 
 pub(crate) const VERSION: &str = env!("CARGO_PKG_VERSION");
@@ -3,11 +3,11 @@ use clap::Parser;
 use iroh_store::{
     cli::Args,
     config::{config_data_path, Config, ServerConfig, CONFIG_FILE_NAME, ENV_PREFIX},
-    metrics, rpc, Store,
+    metrics, rpc, Store, VerifyOptions,
 };
 use iroh_util::lock::ProgramLock;
 use iroh_util::{block_until_sigint, iroh_config_path, make_config};
-use tracing::info;
+use tracing::{error, info, warn};
 
 #[tokio::main(flavor = "multi_thread")]
 async fn main() -> anyhow::Result<()> {
@@ -53,6 +53,7 @@ async fn main() -> anyhow::Result<()> {
     let rpc_addr = config
         .rpc_addr()
         .ok_or_else(|| anyhow!("missing store rpc addr"))?;
+    let verify_on_start = config.verify_on_start;
     let store = if config.path.exists() {
         info!("Opening store at {}", config.path.display());
         Store::open(config).await?
@@ -61,6 +62,10 @@ async fn main() -> anyhow::Result<()> {
         Store::create(config).await?
     };
 
+    if let Some(sample_rate) = verify_on_start {
+        verify_in_background(store.clone(), sample_rate);
+    }
+
     let rpc_task = tokio::spawn(async move { rpc::new(rpc_addr, store).await.unwrap() });
 
     block_until_sigint().await;
@@ -69,3 +74,26 @@ async fn main() -> anyhow::Result<()> {
 
     Ok(())
 }
+
+/// Verifies `sample_rate` of the stored blocks, while the store is already serving requests.
+fn verify_in_background(store: Store, sample_rate: f64) {
+    info!("verifying {}% of the stored blocks", sample_rate * 100.0);
+    tokio::task::spawn_blocking(move || {
+        let options = VerifyOptions {
+            sample_rate,
+            ..Default::default()
+        };
+        match store.verify_integrity(&options, |_| true) {
+            Ok(progress) if progress.corrupt.is_empty() => {
+                info!("verified {} stored blocks", progress.verified);
+            }
+            Ok(progress) => warn!(
+                "found {} corrupt blocks, of {} verified: {:?}",
+                progress.corrupt.len(),
+                progress.verified,
+                progress.corrupt
+            ),
+            Err(err) => error!("failed to verify the store: {:?}", err),
+        }
+    });
+}
@@ -6,15 +6,25 @@ use futures::stream::Stream;
 use iroh_rpc_client::{create_server, ServerError, ServerSocket, StoreServer, HEALTH_POLL_WAIT};
 use iroh_rpc_types::{
     store::{
+        DeleteBlockRequest, DeleteBlockResponse, FlushRequest, GcSubtreeRequest, GcSubtreeResponse,
         GetLinksRequest, GetLinksResponse, GetRequest, GetResponse, GetSizeRequest,
-        GetSizeResponse, HasRequest, HasResponse, PutManyRequest, PutRequest, StoreAddr,
-        StoreRequest, StoreService,
+        GetSizeResponse, HasManyRequest, HasManyResponse, HasRequest, HasResponse, ListCidsRequest,
+        ListCidsResponse, ListPinsRequest, ListPinsResponse, PinRequest, PinUpdateRequest,
+        PutManyRequest, PutRequest, StoreAddr, StoreRequest, StoreService, UnpinRequest,
+        UnpinResponse, VerifyIntegrityRequest, VerifyIntegrityResponse,
     },
-    VersionRequest, VersionResponse, WatchRequest, WatchResponse,
+    RpcError, RpcResult, VersionRequest, VersionResponse, WatchRequest, WatchResponse,
 };
-use tracing::info;
+use tokio::sync::mpsc;
+use tracing::{debug, info};
 
-use crate::{store::Store, VERSION};
+use crate::{
+    store::{Store, VerifyOptions},
+    VERSION,
+};
+
+/// How many cids are sent in a single [`ListCidsResponse`].
+const LIST_CIDS_BATCH_SIZE: usize = 1024;
 
 impl iroh_rpc_types::NamedService for Store {
     const NAME: &'static str = "store";
@@ -67,6 +77,11 @@ impl RpcStore {
         self.0.spawn_blocking(move |x| x.put_many(req)).await
     }
 
+    #[tracing::instrument(skip(self))]
+    async fn flush(self, _: FlushRequest) -> Result<()> {
+        self.0.spawn_blocking(|x| x.flush()).await
+    }
+
     #[tracing::instrument(skip(self))]
     async fn get(self, req: GetRequest) -> Result<GetResponse> {
         let cid = req.cid;
@@ -94,6 +109,17 @@ impl RpcStore {
             .await
     }
 
+    #[tracing::instrument(skip(self))]
+    async fn has_many(self, req: HasManyRequest) -> Result<HasManyResponse> {
+        let cids = req.cids;
+        self.0
+            .spawn_blocking(move |x| {
+                let has = x.has_many(&cids)?;
+                Ok(HasManyResponse { has })
+            })
+            .await
+    }
+
     #[tracing::instrument(skip(self))]
     async fn get_links(self, req: GetLinksRequest) -> Result<GetLinksResponse> {
         let cid = req.cid;
@@ -115,6 +141,149 @@ impl RpcStore {
             })
             .await
     }
+
+    #[tracing::instrument(skip(self))]
+    async fn gc_subtree(self, req: GcSubtreeRequest) -> Result<GcSubtreeResponse> {
+        let root = req.root;
+        self.0
+            .spawn_blocking(move |x| {
+                let report = x.gc_subtree(&root)?;
+                info!("store rpc call: gc subtree {}: {:?}", root, report);
+                Ok(GcSubtreeResponse {
+                    reachable: report.reachable,
+                    removed: report.removed,
+                    removed_bytes: report.removed_bytes,
+                })
+            })
+            .await
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn delete_block(self, req: DeleteBlockRequest) -> Result<DeleteBlockResponse> {
+        let cid = req.cid;
+        self.0
+            .spawn_blocking(move |x| {
+                let removed = x.delete_block(&cid)?;
+                info!("store rpc call: delete block {}: {}", cid, removed);
+                Ok(DeleteBlockResponse { removed })
+            })
+            .await
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn pin(self, req: PinRequest) -> Result<()> {
+        let cid = req.cid;
+        self.0.spawn_blocking(move |x| x.pin(&cid)).await?;
+        info!("store rpc call: pin {}", cid);
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn unpin(self, req: UnpinRequest) -> Result<UnpinResponse> {
+        let cid = req.cid;
+        self.0
+            .spawn_blocking(move |x| {
+                let was_pinned = x.unpin(&cid)?;
+                Ok(UnpinResponse { was_pinned })
+            })
+            .await
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn pin_update(self, req: PinUpdateRequest) -> Result<()> {
+        let (old, new) = (req.old, req.new);
+        self.0
+            .spawn_blocking(move |x| x.pin_update(&old, &new))
+            .await?;
+        info!("store rpc call: pin update {} to {}", old, new);
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn list_pins(self, _: ListPinsRequest) -> Result<ListPinsResponse> {
+        self.0
+            .spawn_blocking(|x| Ok(ListPinsResponse { pins: x.pins()? }))
+            .await
+    }
+
+    #[tracing::instrument(skip(self))]
+    fn list_cids(self, _: ListCidsRequest) -> impl Stream<Item = RpcResult<ListCidsResponse>> {
+        // Iterating the store blocks, so it happens on its own thread, sending batches of cids
+        // as they are read. The small channel keeps a slow client from buffering the store.
+        let (sender, mut receiver) = mpsc::channel(4);
+        let store = self.0;
+        tokio::task::spawn_blocking(move || {
+            if let Err(err) = send_cids(&store, &sender) {
+                sender.blocking_send(Err(RpcError::from(err))).ok();
+            }
+        });
+        async_stream::stream! {
+            while let Some(res) = receiver.recv().await {
+                yield res;
+            }
+        }
+    }
+
+    #[tracing::instrument(skip(self))]
+    fn verify_integrity(
+        self,
+        req: VerifyIntegrityRequest,
+    ) -> impl Stream<Item = RpcResult<VerifyIntegrityResponse>> {
+        // Like `list_cids`, the scan runs on its own thread. It stops once the client drops
+        // the stream, so a full scan can be interrupted at any time.
+        let (sender, mut receiver) = mpsc::channel(4);
+        let store = self.0;
+        tokio::task::spawn_blocking(move || {
+            let options = VerifyOptions {
+                sample_rate: req.sample_rate,
+                resume_after: req.resume_after,
+                quarantine: req.quarantine,
+            };
+            let mut reported = 0;
+            let res = store.verify_integrity(&options, |progress| {
+                let res = VerifyIntegrityResponse {
+                    scanned: progress.scanned,
+                    verified: progress.verified,
+                    unverifiable: progress.unverifiable,
+                    corrupt: progress.corrupt[reported..].to_vec(),
+                    last_id: progress.last_id,
+                    complete: progress.complete,
+                };
+                reported = progress.corrupt.len();
+                sender.blocking_send(Ok(res)).is_ok()
+            });
+            if let Err(err) = res {
+                sender.blocking_send(Err(RpcError::from(err))).ok();
+            }
+        });
+        async_stream::stream! {
+            while let Some(res) = receiver.recv().await {
+                yield res;
+            }
+        }
+    }
+}
+
+/// Sends the cids of all stored blocks in batches, until the receiver is dropped.
+fn send_cids(store: &Store, sender: &mpsc::Sender<RpcResult<ListCidsResponse>>) -> Result<()> {
+    let mut cids = Vec::with_capacity(LIST_CIDS_BATCH_SIZE);
+    for cid in store.list_cids()? {
+        cids.push(cid?);
+        if cids.len() == LIST_CIDS_BATCH_SIZE {
+            let batch = std::mem::replace(&mut cids, Vec::with_capacity(LIST_CIDS_BATCH_SIZE));
+            if sender
+                .blocking_send(Ok(ListCidsResponse { cids: batch }))
+                .is_err()
+            {
+                debug!("list cids receiver dropped");
+                return Ok(());
+            }
+        }
+    }
+    if !cids.is_empty() {
+        sender.blocking_send(Ok(ListCidsResponse { cids })).ok();
+    }
+    Ok(())
 }
 
 /// dispatch a single request from the server 
@@ -128,8 +297,20 @@ async fn dispatch(s: StoreServer, req: StoreRequest, chan: ServerSocket<StoreSer
         PutMany(req) => s.rpc_map_err(req, chan, target, RpcStore::put_many).await,
         Get(req) => s.rpc_map_err(req, chan, target, RpcStore::get).await,
         Has(req) => s.rpc_map_err(req, chan, target, RpcStore::has).await,
+        HasMany(req) => s.rpc_map_err(req, chan, target, RpcStore::has_many).await,
         GetLinks(req) => s.rpc_map_err(req, chan, target, RpcStore::get_links).await,
         GetSize(req) => s.rpc_map_err(req, chan, target, RpcStore::get_size).await,
+        ListCids(req) => s.server_streaming(req, chan, target, RpcStore::list_cids).await,
+        Flush(req) => s.rpc_map_err(req, chan, target, RpcStore::flush).await,
+        VerifyIntegrity(req) => {
+            s.server_streaming(req, chan, target, RpcStore::verify_integrity).await
+        }
+        GcSubtree(req) => s.rpc_map_err(req, chan, target, RpcStore::gc_subtree).await,
+        Pin(req) => s.rpc_map_err(req, chan, target, RpcStore::pin).await,
+        Unpin(req) => s.rpc_map_err(req, chan, target, RpcStore::unpin).await,
+        PinUpdate(req) => s.rpc_map_err(req, chan, target, RpcStore::pin_update).await,
+        ListPins(req) => s.rpc_map_err(req, chan, target, RpcStore::list_pins).await,
+        DeleteBlock(req) => s.rpc_map_err(req, chan, target, RpcStore::delete_block).await,
     }
 }
 
@@ -1,7 +1,9 @@
-use std::{fmt, sync::Arc, thread::available_parallelism};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::{fmt, path::PathBuf, sync::Arc, thread::available_parallelism};
 
-use ahash::AHashSet;
-use anyhow::{anyhow, bail, Context, Result};
+use ahash::{AHashMap, AHashSet};
+use anyhow::{anyhow, bail, ensure, Context, Result};
 use bytes::Bytes;
 use cid::Cid;
 use iroh_metrics::{
@@ -9,17 +11,20 @@ use iroh_metrics::{
     inc, observe, record,
     store::{StoreHistograms, StoreMetrics},
 };
-use multihash::Multihash;
+use multihash::{Code, Multihash, MultihashDigest};
 use rocksdb::{
-    BlockBasedOptions, Cache, ColumnFamily, DBPinnableSlice, Direction, IteratorMode, Options,
+    BlockBasedOptions, Cache, ColumnFamily, DBPinnableSlice, Direction, Env, IteratorMode, Options,
     WriteBatch, DB as RocksDb,
 };
 use smallvec::SmallVec;
 use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
 use tokio::task;
+use tracing::warn;
 
-use crate::cf::{GraphV0, MetadataV0, CF_BLOBS_V0, CF_GRAPH_V0, CF_ID_V0, CF_METADATA_V0};
-use crate::Config;
+use crate::cf::{
+    GraphV0, MetadataV0, CF_BLOBS_V0, CF_GRAPH_V0, CF_ID_V0, CF_METADATA_V0, CF_PINS_V0,
+};
+use crate::config::{Config, DEFAULT_CACHE_SIZE};
 
 #[derive(Clone, Debug)]
 pub struct Store {
@@ -42,8 +47,8 @@ impl fmt::Debug for InnerStore {
     }
 }
 
-/// Creates the default rocksdb options
-fn default_options() -> (Options, Cache) {
+/// Creates the default rocksdb options, tuned by the given config.
+fn default_options(config: &Config) -> (Options, Cache) {
     let mut opts = Options::default();
     opts.set_write_buffer_size(512 * 1024 * 1024);
     opts.optimize_for_point_lookup(64 * 1024 * 1024);
@@ -55,7 +60,12 @@ fn default_options() -> (Options, Cache) {
     opts.set_bytes_per_sync(1_048_576);
     opts.set_blob_file_size(512 * 1024 * 1024);
 
-    let cache = Cache::new_lru_cache(128 * 1024 * 1024).unwrap();
+    if let Some(max_open_files) = config.max_open_files {
+        opts.set_max_open_files(max_open_files);
+    }
+
+    let cache_size = config.cache_size.unwrap_or(DEFAULT_CACHE_SIZE);
+    let cache = Cache::new_lru_cache(cache_size).unwrap();
     let mut bopts = BlockBasedOptions::default();
     // all our data is longer lived, so ribbon filters make sense
     bopts.set_ribbon_filter(10.0);
@@ -88,6 +98,89 @@ fn id_key(cid: &Cid) -> SmallVec<[u8; 64]> {
     key
 }
 
+/// Parses a key of CF_ID_V0 back into the cid it was created from, see [`id_key`].
+///
+/// The cid is always returned as a CIDv1.
+fn cid_from_id_key(key: &[u8]) -> Result<Cid> {
+    let split = key
+        .len()
+        .checked_sub(8)
+        .ok_or_else(|| anyhow!("invalid id key of length {}", key.len()))?;
+    let multihash = Multihash::from_bytes(&key[..split])?;
+    let code = u64::from_be_bytes(key[split..].try_into()?);
+    Ok(Cid::new_v1(code, multihash))
+}
+
+/// How many blocks [`Store::verify_integrity`] scans between progress reports.
+const VERIFY_PROGRESS_INTERVAL: u64 = 1024;
+
+/// Options of [`Store::verify_integrity`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct VerifyOptions {
+    /// Fraction of the blocks to verify, from `0.0` to `1.0`, which verifies all of them.
+    ///
+    /// The sample is random, so repeated runs verify different blocks.
+    pub sample_rate: f64,
+    /// Continues an interrupted verification after the block with this id, see
+    /// [`VerifyProgress::last_id`].
+    pub resume_after: Option<u64>,
+    /// Removes the data of corrupt blocks, so they are no longer served and are fetched
+    /// again when needed. Their cids stay known, as for blocks that are only linked to.
+    pub quarantine: bool,
+}
+
+impl Default for VerifyOptions {
+    fn default() -> Self {
+        VerifyOptions {
+            sample_rate: 1.0,
+            resume_after: None,
+            quarantine: false,
+        }
+    }
+}
+
+/// Progress of [`Store::verify_integrity`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VerifyProgress {
+    /// Number of stored blocks scanned, including those that were not sampled.
+    pub scanned: u64,
+    /// Number of blocks whose data was hashed and compared to their cid.
+    pub verified: u64,
+    /// Number of sampled blocks that could not be verified, because their hash function is
+    /// not supported or their cid is unknown.
+    pub unverifiable: u64,
+    /// Blocks whose data does not match their cid.
+    pub corrupt: Vec<Cid>,
+    /// The id of the last scanned block, to resume from with
+    /// [`VerifyOptions::resume_after`].
+    pub last_id: Option<u64>,
+    /// Whether all blocks were scanned, `false` if the verification was interrupted.
+    pub complete: bool,
+}
+
+/// Result of [`Store::gc_subtree`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GcReport {
+    /// Number of stored blocks in the DAG.
+    pub reachable: u64,
+    /// Number of blocks that were removed, the others are still linked to from outside of
+    /// the DAG.
+    pub removed: u64,
+    /// Size of the data of the removed blocks.
+    pub removed_bytes: u64,
+}
+
+/// Whether the block with `id` is part of a sample of `rate` of all blocks, randomized by
+/// `state`.
+fn is_sampled(state: &RandomState, id: u64, rate: f64) -> bool {
+    if rate >= 1.0 {
+        return true;
+    }
+    let mut hasher = state.build_hasher();
+    hasher.write_u64(id);
+    (hasher.finish() as f64) < rate * u64::MAX as f64
+}
+
 /// Struct used to iterate over all the ids for a multihash
 struct CodeAndId {
     // the ipld code of the id
@@ -101,10 +194,28 @@ impl Store {
     /// Creates a new database.
     #[tracing::instrument]
     pub async fn create(config: Config) -> Result<Self> {
-        let (mut options, cache) = default_options();
+        let (options, cache) = default_options(&config);
+        Self::create_with_options(options, cache, config.path).await
+    }
+
+    /// Creates a new database that is only kept in memory.
+    ///
+    /// Nothing is written to disk, `config.path` only names the database. All content is
+    /// lost once the store is dropped.
+    #[tracing::instrument]
+    pub async fn create_in_memory(config: Config) -> Result<Self> {
+        let (mut options, cache) = default_options(&config);
+        options.set_env(&Env::mem_env()?);
+        Self::create_with_options(options, cache, config.path).await
+    }
+
+    async fn create_with_options(
+        mut options: Options,
+        cache: Cache,
+        path: PathBuf,
+    ) -> Result<Self> {
         options.create_if_missing(true);
 
-        let path = config.path.clone();
         let db = task::spawn_blocking(move || -> Result<_> {
             let mut db = RocksDb::open(&options, path)?;
             {
@@ -123,6 +234,10 @@ impl Store {
                 let opts = Options::default();
                 db.create_cf(CF_ID_V0, &opts)?;
             }
+            {
+                let opts = Options::default();
+                db.create_cf(CF_PINS_V0, &opts)?;
+            }
 
             Ok(db)
         })
@@ -140,8 +255,10 @@ impl Store {
     /// Opens an existing database.
     #[tracing::instrument]
     pub async fn open(config: Config) -> Result<Self> {
-        let (mut options, cache) = default_options();
+        let (mut options, cache) = default_options(&config);
         options.create_if_missing(false);
+        // stores created before pins existed lack their column family
+        options.create_missing_column_families(true);
         // TODO: find a way to read existing options
 
         let path = config.path.clone();
@@ -149,7 +266,13 @@ impl Store {
             let db = RocksDb::open_cf(
                 &options,
                 path,
-                [CF_BLOBS_V0, CF_METADATA_V0, CF_GRAPH_V0, CF_ID_V0],
+                [
+                    CF_BLOBS_V0,
+                    CF_METADATA_V0,
+                    CF_GRAPH_V0,
+                    CF_ID_V0,
+                    CF_PINS_V0,
+                ],
             )?;
 
             // read last inserted id
@@ -182,6 +305,33 @@ impl Store {
         })
     }
 
+    /// Repairs an existing database that can not be opened, e.g. after an unclean shutdown.
+    ///
+    /// Recovers as much of the content as possible, data that can not be recovered is lost.
+    /// The database must not be open while it is repaired.
+    #[tracing::instrument]
+    pub async fn repair(config: Config) -> Result<()> {
+        let (options, _cache) = default_options(&config);
+        let path = config.path.clone();
+        task::spawn_blocking(move || RocksDb::repair(&options, path)).await??;
+        Ok(())
+    }
+
+    /// Whether `err`, returned by [`Store::open`], reports a corrupt database, which
+    /// [`Store::repair`] may recover, rather than e.g. a database in use by another process.
+    pub fn is_corruption(err: &anyhow::Error) -> bool {
+        err.chain().any(|cause| {
+            cause
+                .downcast_ref::<rocksdb::Error>()
+                .map_or(false, |err| err.kind() == rocksdb::ErrorKind::Corruption)
+        })
+    }
+
+    /// Stores a block.
+    ///
+    /// Once this returns, the block is in the write-ahead log, so it survives a crash of
+    /// this process. It is not synced to disk though, and can be lost on power loss or an
+    /// operating system crash, unless the store is flushed afterwards, see [`Store::flush`].
     #[tracing::instrument(skip(self, links, blob))]
     pub fn put<T: AsRef<[u8]>, L>(&self, cid: Cid, blob: T, links: L) -> Result<()>
     where
@@ -190,11 +340,19 @@ impl Store {
         self.write_store()?.put(cid, blob, links)
     }
 
+    /// Stores all `blocks` at once, with the same durability as [`Store::put`].
     #[tracing::instrument(skip(self, blocks))]
     pub fn put_many(&self, blocks: impl IntoIterator<Item = (Cid, Bytes, Vec<Cid>)>) -> Result<()> {
         self.write_store()?.put_many(blocks)
     }
 
+    /// Syncs all blocks stored so far to disk, so they survive power loss.
+    #[tracing::instrument(skip(self))]
+    pub fn flush(&self) -> Result<()> {
+        self.inner.content.flush_wal(true)?;
+        Ok(())
+    }
+
     #[tracing::instrument(skip(self))]
     pub fn get_blob_by_hash(&self, hash: &Multihash) -> Result<Option<DBPinnableSlice<'_>>> {
         self.read_store()?.get_blob_by_hash(hash)
@@ -220,11 +378,216 @@ impl Store {
         self.read_store()?.has(cid)
     }
 
+    /// Checks the presence of all `cids`, returning the results in the same order.
+    #[tracing::instrument(skip(self, cids))]
+    pub fn has_many(&self, cids: &[Cid]) -> Result<Vec<bool>> {
+        let store = self.read_store()?;
+        cids.iter().map(|cid| store.has(cid)).collect()
+    }
+
     #[tracing::instrument(skip(self))]
     pub fn get_links(&self, cid: &Cid) -> Result<Option<Vec<Cid>>> {
         self.read_store()?.get_links(cid)
     }
 
+    /// Iterates over the cids of all blocks in the store, returned as CIDv1.
+    ///
+    /// The order is unspecified. Blocks that are written while iterating may or may not be
+    /// included. Cids only known as links of stored blocks are skipped.
+    pub fn list_cids(&self) -> Result<impl Iterator<Item = Result<Cid>> + '_> {
+        // Iterating does not need a consistent view of the ids, so no lock is held, which
+        // would block all writes for the duration of the iteration.
+        let db = &self.inner.content;
+        let cf = ColumnFamilies::new(db)?;
+        let iter = db
+            .iterator_cf(cf.id, IteratorMode::Start)
+            .filter_map(move |elem| {
+                let stored = || -> Result<Option<Cid>> {
+                    let (key, id) = elem?;
+                    if db.get_pinned_cf(cf.blobs, &*id)?.is_none() {
+                        return Ok(None);
+                    }
+                    cid_from_id_key(&key).map(Some)
+                };
+                stored().transpose()
+            });
+        Ok(iter)
+    }
+
+    /// Checks that the data of stored blocks hashes to their cids, to catch silent
+    /// corruption, e.g. after an unclean shutdown.
+    ///
+    /// Scans the blocks in the order they were stored, verifying a sample of them as
+    /// configured by `options`. `on_progress` is called with the progress so far whenever
+    /// a corrupt block is found, every few blocks and once the scan is complete. Returning
+    /// `false` from it interrupts the scan, which can be continued later from
+    /// [`VerifyProgress::last_id`]. Returns the final progress.
+    #[tracing::instrument(skip(self, on_progress))]
+    pub fn verify_integrity(
+        &self,
+        options: &VerifyOptions,
+        mut on_progress: impl FnMut(&VerifyProgress) -> bool,
+    ) -> Result<VerifyProgress> {
+        ensure!(
+            (0.0..=1.0).contains(&options.sample_rate),
+            "sample rate must be between 0 and 1, got {}",
+            options.sample_rate
+        );
+        let mut progress = VerifyProgress {
+            last_id: options.resume_after,
+            ..Default::default()
+        };
+        let start = match options.resume_after {
+            Some(id) => match id.checked_add(1) {
+                Some(next) => Some(next.to_be_bytes()),
+                None => {
+                    progress.complete = true;
+                    on_progress(&progress);
+                    return Ok(progress);
+                }
+            },
+            None => None,
+        };
+        let mode = match start {
+            Some(ref key) => IteratorMode::From(&key[..], Direction::Forward),
+            None => IteratorMode::Start,
+        };
+
+        // As in `list_cids` no lock is held while iterating, so the store stays writable.
+        let db = &self.inner.content;
+        let cf = ColumnFamilies::new(db)?;
+        let sampler = RandomState::new();
+        for elem in db.iterator_cf(cf.blobs, mode) {
+            let (key, blob) = elem?;
+            let id = u64::from_be_bytes(key[..].try_into()?);
+            progress.scanned += 1;
+            progress.last_id = Some(id);
+
+            let mut found_corrupt = false;
+            if is_sampled(&sampler, id, options.sample_rate) {
+                match self.verify_block(&cf, id, &blob)? {
+                    Some(Ok(())) => progress.verified += 1,
+                    Some(Err(cid)) => {
+                        warn!("stored data of {} does not match its cid", cid);
+                        progress.verified += 1;
+                        progress.corrupt.push(cid);
+                        found_corrupt = true;
+                        if options.quarantine {
+                            let _lock = self.inner.next_id.write().unwrap();
+                            db.delete_cf(cf.blobs, &key)?;
+                        }
+                    }
+                    None => progress.unverifiable += 1,
+                }
+            }
+
+            let report = found_corrupt || progress.scanned % VERIFY_PROGRESS_INTERVAL == 0;
+            if report && !on_progress(&progress) {
+                return Ok(progress);
+            }
+        }
+
+        progress.complete = true;
+        on_progress(&progress);
+        Ok(progress)
+    }
+
+    /// Hashes the data of the block with `id`.
+    ///
+    /// Returns `None` if the block can not be verified, otherwise its cid, as an error if
+    /// the data does not match it.
+    fn verify_block(
+        &self,
+        cf: &ColumnFamilies,
+        id: u64,
+        blob: &[u8],
+    ) -> Result<Option<std::result::Result<(), Cid>>> {
+        // FIXME: can't use pinned because otherwise this can trigger alignment issues :/
+        let meta = match self.inner.content.get_cf(cf.metadata, id.to_be_bytes())? {
+            Some(meta) => meta,
+            None => {
+                warn!("missing metadata for block {}", id);
+                return Ok(None);
+            }
+        };
+        let meta =
+            rkyv::check_archived_root::<MetadataV0>(&meta).map_err(|e| anyhow!("{:?}", e))?;
+        let multihash = Multihash::from_bytes(&meta.multihash)?;
+        let cid = Cid::new_v1(meta.codec, multihash);
+        let code = match Code::try_from(multihash.code()) {
+            Ok(code) => code,
+            Err(_) => return Ok(None),
+        };
+        let digest = code.digest(blob);
+        if digest.size() != multihash.size() {
+            // truncated digests are not supported
+            return Ok(None);
+        }
+        if digest == multihash {
+            Ok(Some(Ok(())))
+        } else {
+            Ok(Some(Err(cid)))
+        }
+    }
+
+    /// Removes the blocks of the DAG at `root` that no block outside of it links to.
+    ///
+    /// Every other stored block is retained. So blocks of the DAG that another stored block
+    /// links to are kept, together with everything they link to, and if `root` itself is
+    /// linked to nothing is removed. Pinned blocks of the DAG are kept the same way, see
+    /// [`Store::pin`]. All stored links are scanned for this, writes are blocked in the
+    /// meantime. As for quarantined blocks, the cids of the removed blocks stay known.
+    #[tracing::instrument(skip(self))]
+    pub fn gc_subtree(&self, root: &Cid) -> Result<GcReport> {
+        self.write_store()?.gc_subtree(root)
+    }
+
+    /// Removes the block `cid` alone, returning whether it was stored.
+    ///
+    /// Unlike [`Store::gc_subtree`] neither the blocks it links to nor the ones linking to
+    /// it are looked at, so only use it for blocks no other block should link to. Its cid
+    /// stays known, so adding the block again reuses its id. Fails if the block is pinned.
+    #[tracing::instrument(skip(self))]
+    pub fn delete_block(&self, cid: &Cid) -> Result<bool> {
+        self.write_store()?.delete_block(cid)
+    }
+
+    /// Pins `cid`, so [`Store::gc_subtree`] keeps it and everything it links to.
+    ///
+    /// Fails if the block is not in the store. Pinning does not check that the rest of the
+    /// DAG is stored, blocks that are added later are kept as well.
+    #[tracing::instrument(skip(self))]
+    pub fn pin(&self, cid: &Cid) -> Result<()> {
+        self.write_store()?.pin(cid)
+    }
+
+    /// Removes the pin of `cid`, returning whether it was pinned.
+    #[tracing::instrument(skip(self))]
+    pub fn unpin(&self, cid: &Cid) -> Result<bool> {
+        self.write_store()?.unpin(cid)
+    }
+
+    /// Moves the pin of `old` to `new` at once, so there is no moment in which both or
+    /// neither of them are pinned.
+    ///
+    /// Fails, without changing any pin, if `old` is not pinned or `new` is not in the store.
+    #[tracing::instrument(skip(self))]
+    pub fn pin_update(&self, old: &Cid, new: &Cid) -> Result<()> {
+        self.write_store()?.pin_update(old, new)
+    }
+
+    /// Returns whether `cid` is pinned.
+    #[tracing::instrument(skip(self))]
+    pub fn is_pinned(&self, cid: &Cid) -> Result<bool> {
+        self.read_store()?.is_pinned(cid)
+    }
+
+    /// Returns all pinned cids, as CIDv1.
+    #[tracing::instrument(skip(self))]
+    pub fn pins(&self) -> Result<Vec<Cid>> {
+        self.read_store()?.pins()
+    }
+
     #[tracing::instrument(skip(self))]
     pub fn consistency_check(&self) -> Result<Vec<String>> {
         self.read_store()?.consistency_check()
@@ -292,6 +655,7 @@ struct ColumnFamilies<'a> {
     metadata: &'a ColumnFamily,
     graph: &'a ColumnFamily,
     blobs: &'a ColumnFamily,
+    pins: &'a ColumnFamily,
 }
 
 impl<'a> ColumnFamilies<'a> {
@@ -309,6 +673,9 @@ impl<'a> ColumnFamilies<'a> {
             blobs: db
                 .cf_handle(CF_BLOBS_V0)
                 .context("missing column family: blobs")?,
+            pins: db
+                .cf_handle(CF_PINS_V0)
+                .context("missing column family: pins")?,
         })
     }
 }
@@ -324,7 +691,12 @@ impl<'a> WriteStore<'a> {
             return Ok(());
         }
 
-        let id = self.next_id();
+        // Cids that were linked to, or whose blocks were removed, keep their id, as the
+        // stored links refer to it.
+        let id = match self.get_id(&cid)? {
+            Some(id) => id,
+            None => self.next_id(),
+        };
 
         let start = std::time::Instant::now();
 
@@ -363,15 +735,20 @@ impl<'a> WriteStore<'a> {
         let mut total_blob_size = 0;
 
         let mut batch = WriteBatch::default();
-        let mut cid_tracker: AHashSet<Cid> = AHashSet::default();
+        // The ids of the blocks in this batch, which are only written at the end, so links
+        // to them need to be looked up here.
+        let mut batch_ids: AHashMap<Cid, u64> = AHashMap::default();
         for (cid, blob, links) in blocks.into_iter() {
-            if cid_tracker.contains(&cid) || self.has(&cid)? {
+            if batch_ids.contains_key(&cid) || self.has(&cid)? {
                 continue;
             }
 
-            cid_tracker.insert(cid);
-
-            let id = self.next_id();
+            // as in `put`, known cids keep their id
+            let id = match self.get_id(&cid)? {
+                Some(id) => id,
+                None => self.next_id(),
+            };
+            batch_ids.insert(cid, id);
 
             let id_bytes = id.to_be_bytes();
 
@@ -384,7 +761,7 @@ impl<'a> WriteStore<'a> {
             let metadata_bytes = rkyv::to_bytes::<_, 1024>(&metadata)?; // TODO: is this the right amount of scratch space?
             let id_key = id_key(&cid);
 
-            let children = self.ensure_id_many(links.into_iter())?;
+            let children = self.ensure_id_many_in_batch(links.into_iter(), &batch_ids)?;
 
             let graph = GraphV0 { children };
             let graph_bytes = rkyv::to_bytes::<_, 1024>(&graph)?; // TODO: is this the right amount of scratch space?
@@ -408,6 +785,20 @@ impl<'a> WriteStore<'a> {
     /// Takes a list of cids and gives them ids, which are both stored and then returned.
     #[tracing::instrument(skip(self, cids))]
     fn ensure_id_many<I>(&mut self, cids: I) -> Result<Vec<u64>>
+    where
+        I: IntoIterator<Item = Cid>,
+    {
+        self.ensure_id_many_in_batch(cids, &AHashMap::default())
+    }
+
+    /// Like [`WriteStore::ensure_id_many`], but takes the ids of the cids that are about to
+    /// be written from `batch_ids`.
+    #[tracing::instrument(skip(self, cids, batch_ids))]
+    fn ensure_id_many_in_batch<I>(
+        &mut self,
+        cids: I,
+        batch_ids: &AHashMap<Cid, u64>,
+    ) -> Result<Vec<u64>>
     where
         I: IntoIterator<Item = Cid>,
     {
@@ -415,7 +806,9 @@ impl<'a> WriteStore<'a> {
         let mut batch = WriteBatch::default();
         for cid in cids {
             let id_key = id_key(&cid);
-            let id = if let Some(id) = self.db.get_pinned_cf(self.cf.id, &id_key)? {
+            let id = if let Some(id) = batch_ids.get(&cid) {
+                *id
+            } else if let Some(id) = self.db.get_pinned_cf(self.cf.id, &id_key)? {
                 u64::from_be_bytes(id.as_ref().try_into()?)
             } else {
                 let id = self.next_id();
@@ -437,6 +830,142 @@ impl<'a> WriteStore<'a> {
         Ok(ids)
     }
 
+    fn gc_subtree(&self, root: &Cid) -> Result<GcReport> {
+        let mut report = GcReport::default();
+        let root = match self.get_id(root)? {
+            Some(id) => id,
+            None => return Ok(report),
+        };
+
+        // the stored blocks of the DAG, with their links
+        let mut dag = AHashMap::<u64, Vec<u64>>::default();
+        let mut queue = vec![root];
+        while let Some(id) = queue.pop() {
+            if dag.contains_key(&id) {
+                continue;
+            }
+            if let Some(children) = self.get_children(id)? {
+                queue.extend_from_slice(&children);
+                dag.insert(id, children);
+            }
+        }
+
+        // pinned blocks, blocks linked to from outside of the DAG, and everything below
+        // them, are kept
+        let mut kept = Vec::new();
+        for elem in self.db.iterator_cf(self.cf.pins, IteratorMode::Start) {
+            let (key, _) = elem?;
+            if let Some(id) = self.db.get_pinned_cf(self.cf.id, &key)? {
+                let id = u64::from_be_bytes(id[..8].try_into()?);
+                if dag.contains_key(&id) {
+                    kept.push(id);
+                }
+            }
+        }
+        for elem in self.db.iterator_cf(self.cf.graph, IteratorMode::Start) {
+            let (key, graph) = elem?;
+            let id = u64::from_be_bytes(key[..].try_into()?);
+            if dag.contains_key(&id) {
+                continue;
+            }
+            let graph =
+                rkyv::check_archived_root::<GraphV0>(&graph).map_err(|e| anyhow!("{:?}", e))?;
+            let children = graph.children.iter().copied();
+            kept.extend(children.filter(|child| dag.contains_key(child)));
+        }
+        let mut retained = AHashSet::new();
+        while let Some(id) = kept.pop() {
+            if retained.insert(id) {
+                kept.extend(dag[&id].iter().filter(|child| dag.contains_key(*child)));
+            }
+        }
+
+        let mut batch = WriteBatch::default();
+        for id in dag.keys().filter(|id| !retained.contains(*id)) {
+            let id_bytes = id.to_be_bytes();
+            if let Some(blob) = self.db.get_pinned_cf(self.cf.blobs, id_bytes)? {
+                report.removed += 1;
+                report.removed_bytes += blob.len() as u64;
+            }
+            batch.delete_cf(self.cf.blobs, id_bytes);
+            batch.delete_cf(self.cf.graph, id_bytes);
+        }
+        self.db.write(batch)?;
+        report.reachable = dag.len() as u64;
+        Ok(report)
+    }
+
+    fn delete_block(&self, cid: &Cid) -> Result<bool> {
+        ensure!(
+            self.db.get_pinned_cf(self.cf.pins, id_key(cid))?.is_none(),
+            "can not delete {}, it is pinned",
+            cid
+        );
+        let id_bytes = match self.get_id(cid)? {
+            Some(id) => id.to_be_bytes(),
+            None => return Ok(false),
+        };
+        if self.db.get_pinned_cf(self.cf.blobs, id_bytes)?.is_none() {
+            return Ok(false);
+        }
+        let mut batch = WriteBatch::default();
+        batch.delete_cf(self.cf.blobs, id_bytes);
+        batch.delete_cf(self.cf.graph, id_bytes);
+        self.db.write(batch)?;
+        Ok(true)
+    }
+
+    fn pin(&self, cid: &Cid) -> Result<()> {
+        ensure!(
+            self.has(cid)?,
+            "can not pin {}, it is not in the store",
+            cid
+        );
+        self.db.put_cf(self.cf.pins, id_key(cid), b"")?;
+        Ok(())
+    }
+
+    fn unpin(&self, cid: &Cid) -> Result<bool> {
+        let key = id_key(cid);
+        if self.db.get_pinned_cf(self.cf.pins, &key)?.is_none() {
+            return Ok(false);
+        }
+        self.db.delete_cf(self.cf.pins, key)?;
+        Ok(true)
+    }
+
+    fn pin_update(&self, old: &Cid, new: &Cid) -> Result<()> {
+        let old_key = id_key(old);
+        ensure!(
+            self.db.get_pinned_cf(self.cf.pins, &old_key)?.is_some(),
+            "{} is not pinned",
+            old
+        );
+        ensure!(
+            self.has(new)?,
+            "can not pin {}, it is not in the store",
+            new
+        );
+        let mut batch = WriteBatch::default();
+        batch.delete_cf(self.cf.pins, old_key);
+        batch.put_cf(self.cf.pins, id_key(new), b"");
+        self.db.write(batch)?;
+        Ok(())
+    }
+
+    /// The ids of the links of the block with `id`, `None` if the block is not stored.
+    fn get_children(&self, id: u64) -> Result<Option<Vec<u64>>> {
+        // FIXME: can't use pinned because otherwise this can trigger alignment issues :/
+        match self.db.get_cf(self.cf.graph, id.to_be_bytes())? {
+            Some(graph) => {
+                let graph =
+                    rkyv::check_archived_root::<GraphV0>(&graph).map_err(|e| anyhow!("{:?}", e))?;
+                Ok(Some(graph.children.to_vec()))
+            }
+            None => Ok(None),
+        }
+    }
+
     #[tracing::instrument(skip(self))]
     fn next_id(&mut self) -> u64 {
         let id = *self.next_id;
@@ -476,6 +1005,17 @@ impl<'a> WriteStore<'a> {
 }
 
 impl<'a> ReadStore<'a> {
+    fn is_pinned(&self, cid: &Cid) -> Result<bool> {
+        Ok(self.db.get_pinned_cf(self.cf.pins, id_key(cid))?.is_some())
+    }
+
+    fn pins(&self) -> Result<Vec<Cid>> {
+        self.db
+            .iterator_cf(self.cf.pins, IteratorMode::Start)
+            .map(|elem| cid_from_id_key(&elem?.0))
+            .collect()
+    }
+
     fn get(&self, cid: &Cid) -> Result<Option<DBPinnableSlice<'a>>> {
         inc!(StoreMetrics::GetRequests);
         let start = std::time::Instant::now();
@@ -677,7 +1217,7 @@ impl<'a> ReadStore<'a> {
 
 #[cfg(test)]
 mod tests {
-    use std::{str::FromStr, sync::Mutex};
+    use std::{collections::BTreeSet, str::FromStr, sync::Mutex};
 
     use cid::multihash::{Code, MultihashDigest};
     use libipld::{
@@ -714,6 +1254,10 @@ mod tests {
             values.push((c, data, links));
         }
 
+        let missing = cid::Cid::new_v1(RAW, Code::Sha2_256.digest(b"missing"));
+        let cids = [values[0].0, missing, values[1].0];
+        assert_eq!(store.has_many(&cids).unwrap(), vec![true, false, true]);
+
         for (i, (c, expected_data, expected_links)) in values.iter().enumerate() {
             dbg!(i);
             assert!(store.has(c).unwrap());
@@ -725,6 +1269,56 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn test_repair() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config::new(dir.path().into());
+
+        let store = Store::create(config.clone()).await.unwrap();
+        let data = b"hello world".to_vec();
+        let c = cid::Cid::new_v1(RAW, Code::Sha2_256.digest(&data));
+        store.put(c, &data, Vec::<cid::Cid>::new()).unwrap();
+        drop(store);
+
+        Store::repair(config.clone()).await.unwrap();
+        let store = Store::open(config).await.unwrap();
+        assert_eq!(&store.get(&c).unwrap().unwrap()[..], &data[..]);
+    }
+
+    #[tokio::test]
+    async fn test_is_corruption() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config::new(dir.path().into());
+
+        // a database in use is not corrupt
+        let store = Store::create(config.clone()).await.unwrap();
+        let err = Store::open(config.clone()).await.unwrap_err();
+        assert!(!Store::is_corruption(&err));
+        drop(store);
+
+        std::fs::write(dir.path().join("CURRENT"), b"garbage").unwrap();
+        let err = Store::open(config).await.unwrap_err();
+        assert!(Store::is_corruption(&err));
+    }
+
+    #[tokio::test]
+    async fn test_flush() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config::new(dir.path().into());
+
+        let store = Store::create(config.clone()).await.unwrap();
+        // flushing an empty store is fine
+        store.flush().unwrap();
+        let data = b"hello world".to_vec();
+        let c = cid::Cid::new_v1(RAW, Code::Sha2_256.digest(&data));
+        store.put(c, &data, Vec::<cid::Cid>::new()).unwrap();
+        store.flush().unwrap();
+        drop(store);
+
+        let store = Store::open(config).await.unwrap();
+        assert_eq!(&store.get(&c).unwrap().unwrap()[..], &data[..]);
+    }
+
     #[tokio::test]
     async fn test_reopen() {
         let dir = tempfile::tempdir().unwrap();
@@ -790,6 +1384,257 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn test_list_cids() -> anyhow::Result<()> {
+        let (store, _dir) = test_store().await?;
+        assert_eq!(store.list_cids()?.count(), 0);
+
+        let mut expected = BTreeSet::new();
+        for i in 0..10u8 {
+            let data = vec![i; 16];
+            let c = Cid::new_v1(RAW, Code::Sha2_256.digest(&data));
+            // the link is only known to the store, not stored
+            let link = Cid::new_v1(RAW, Code::Sha2_256.digest(&[i; 32]));
+            store.put(c, &data, [link])?;
+            expected.insert(c);
+        }
+
+        let cids = store.list_cids()?.collect::<Result<BTreeSet<_>>>()?;
+        assert_eq!(cids, expected);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_verify_integrity() -> anyhow::Result<()> {
+        let (store, _dir) = test_store().await?;
+        let mut corrupt = Vec::new();
+        for i in 0..10u8 {
+            let data = vec![i; 16];
+            let c = Cid::new_v1(RAW, Code::Sha2_256.digest(&data));
+            if i % 4 == 1 {
+                // the store does not check the data it is given
+                store.put(c, b"corrupted", [])?;
+                corrupt.push(c);
+            } else {
+                store.put(c, &data, [])?;
+            }
+        }
+
+        let mut reports = 0;
+        let progress = store.verify_integrity(&VerifyOptions::default(), |_| {
+            reports += 1;
+            true
+        })?;
+        assert!(progress.complete);
+        assert_eq!(progress.scanned, 10);
+        assert_eq!(progress.verified, 10);
+        assert_eq!(progress.corrupt, corrupt);
+        // one report per corrupt block, and the final one
+        assert_eq!(reports, corrupt.len() + 1);
+
+        // interrupted at the first corrupt block, and resumed from there
+        let progress = store.verify_integrity(&VerifyOptions::default(), |_| false)?;
+        assert!(!progress.complete);
+        assert_eq!(progress.corrupt, corrupt[..1]);
+        let options = VerifyOptions {
+            resume_after: progress.last_id,
+            quarantine: true,
+            ..Default::default()
+        };
+        let resumed = store.verify_integrity(&options, |_| true)?;
+        assert!(resumed.complete);
+        assert_eq!(progress.scanned + resumed.scanned, 10);
+        assert_eq!(resumed.corrupt, corrupt[1..]);
+
+        // quarantined blocks are removed, the first one was only reported
+        assert!(store.has(&corrupt[0])?);
+        for c in &corrupt[1..] {
+            assert!(!store.has(c)?);
+        }
+
+        let progress = store.verify_integrity(
+            &VerifyOptions {
+                sample_rate: 0.0,
+                ..Default::default()
+            },
+            |_| true,
+        )?;
+        assert_eq!(progress.scanned, 8);
+        assert_eq!(progress.verified, 0);
+
+        let options = VerifyOptions {
+            sample_rate: 2.0,
+            ..Default::default()
+        };
+        assert!(store.verify_integrity(&options, |_| true).is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_gc_subtree() -> anyhow::Result<()> {
+        let (store, _dir) = test_store().await?;
+        let put = |data: &[u8], links: &[Cid]| -> anyhow::Result<Cid> {
+            let c = Cid::new_v1(RAW, Code::Sha2_256.digest(data));
+            store.put(c, data, links.iter().copied())?;
+            Ok(c)
+        };
+        // root -> (a, b -> c), other -> b
+        let a = put(b"a", &[])?;
+        let c = put(b"c", &[])?;
+        let b = put(b"b", &[c])?;
+        let root = put(b"root", &[a, b])?;
+        let other = put(b"other", &[b])?;
+
+        // linked to from other, so nothing is removed
+        let report = store.gc_subtree(&b)?;
+        assert_eq!(report.reachable, 2);
+        assert_eq!(report.removed, 0);
+
+        // b and c are shared with other
+        let report = store.gc_subtree(&root)?;
+        assert_eq!(
+            report,
+            GcReport {
+                reachable: 4,
+                removed: 2,
+                removed_bytes: 5,
+            }
+        );
+        assert_eq!(
+            store.has_many(&[root, a, b, c])?,
+            [false, false, true, true]
+        );
+        assert!(store.get_links(&root)?.is_none());
+
+        // once other is gone, nothing links to them anymore
+        let report = store.gc_subtree(&other)?;
+        assert_eq!(report.removed, 3);
+        assert_eq!(store.list_cids()?.count(), 0);
+
+        let missing = Cid::new_v1(RAW, Code::Sha2_256.digest(b"missing"));
+        assert_eq!(store.gc_subtree(&missing)?, GcReport::default());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_gc_subtree_readded() -> anyhow::Result<()> {
+        let (store, _dir) = test_store().await?;
+        let block = |data: &[u8], links: &[Cid]| {
+            let c = Cid::new_v1(RAW, Code::Sha2_256.digest(data));
+            (c, Bytes::copy_from_slice(data), links.to_vec())
+        };
+        // root -> b -> c, put at once, leaves first
+        let c = block(b"c", &[]);
+        let b = block(b"b", &[c.0]);
+        let root = block(b"root", &[b.0]);
+        store.put_many([c.clone(), b.clone(), root.clone()])?;
+        assert_eq!(store.gc_subtree(&root.0)?.removed, 3);
+
+        // b is added again, with a parent that outlived the removal of root
+        let other = block(b"other", &[b.0]);
+        store.put(other.0, &other.1, other.2.clone())?;
+        store.put_many([c.clone(), b.clone()])?;
+        store.put(root.0, &root.1, root.2.clone())?;
+        assert_eq!(Vec::<String>::new(), store.consistency_check()?);
+
+        // b and c are still linked to from other
+        let report = store.gc_subtree(&root.0)?;
+        assert_eq!(report.reachable, 3);
+        assert_eq!(report.removed, 1);
+        assert_eq!(
+            store.has_many(&[root.0, b.0, c.0, other.0])?,
+            [false, true, true, true]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_delete_block() -> anyhow::Result<()> {
+        let (store, _dir) = test_store().await?;
+        let put = |data: &[u8], links: &[Cid]| -> anyhow::Result<Cid> {
+            let c = Cid::new_v1(RAW, Code::Sha2_256.digest(data));
+            store.put(c, data, links.iter().copied())?;
+            Ok(c)
+        };
+        // root -> a
+        let a = put(b"a", &[])?;
+        let root = put(b"root", &[a])?;
+
+        // only the block itself is removed
+        assert!(store.delete_block(&root)?);
+        assert!(!store.delete_block(&root)?);
+        assert_eq!(store.has_many(&[root, a])?, [false, true]);
+        assert!(store.get_links(&root)?.is_none());
+
+        // adding it again works as before
+        put(b"root", &[a])?;
+        assert_eq!(store.get_links(&root)?, Some(vec![a]));
+        assert_eq!(Vec::<String>::new(), store.consistency_check()?);
+
+        // pinned blocks are kept
+        store.pin(&root)?;
+        assert!(store.delete_block(&root).is_err());
+        assert!(store.has(&root)?);
+
+        let missing = Cid::new_v1(RAW, Code::Sha2_256.digest(b"missing"));
+        assert!(!store.delete_block(&missing)?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_pins() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let config = Config::new(dir.path().into());
+        let store = Store::create(config.clone()).await?;
+        let put = |data: &[u8], links: &[Cid]| -> anyhow::Result<Cid> {
+            let c = Cid::new_v1(RAW, Code::Sha2_256.digest(data));
+            store.put(c, data, links.iter().copied())?;
+            Ok(c)
+        };
+        // root -> (a, b -> c)
+        let a = put(b"a", &[])?;
+        let c = put(b"c", &[])?;
+        let b = put(b"b", &[c])?;
+        let root = put(b"root", &[a, b])?;
+
+        let missing = Cid::new_v1(RAW, Code::Sha2_256.digest(b"missing"));
+        assert!(store.pin(&missing).is_err());
+        store.pin(&root)?;
+        store.pin(&b)?;
+        assert!(store.is_pinned(&root)?);
+        assert_eq!(
+            store.pins()?.into_iter().collect::<BTreeSet<_>>(),
+            [root, b].into()
+        );
+
+        // pins survive reopening
+        drop(store);
+        let store = Store::open(config).await?;
+        assert!(store.is_pinned(&b)?);
+
+        // a pinned root keeps the whole DAG
+        assert_eq!(store.gc_subtree(&root)?.removed, 0);
+        assert!(store.unpin(&root)?);
+        assert!(!store.unpin(&root)?);
+
+        // moving a pin checks both ends first
+        assert!(store.pin_update(&root, &a).is_err());
+        assert!(store.pin_update(&b, &missing).is_err());
+        assert!(store.is_pinned(&b)?);
+        store.pin_update(&b, &a)?;
+        assert_eq!(store.pins()?, vec![a]);
+        store.pin_update(&a, &b)?;
+
+        // the pinned block inside of the DAG keeps everything below it
+        let report = store.gc_subtree(&root)?;
+        assert_eq!(report.removed, 2);
+        assert_eq!(
+            store.has_many(&[root, a, b, c])?,
+            [false, false, true, true]
+        );
+        Ok(())
+    }
+
     async fn test_store() -> anyhow::Result<(Store, TempDir)> {
         let dir = tempfile::tempdir()?;
         let config = Config::new(dir.path().into());
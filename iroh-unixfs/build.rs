@@ -1,6 +1,8 @@
 fn main() {
     prost_build::Config::new()
         .bytes([".unixfs_pb.Data", ".merkledag_pb.PBNode.Data"])
+        // maps must be encoded in a deterministic order, to get stable CIDs
+        .btree_map([".unixfs_pb.Data.customMetadata"])
         .compile_protos(&["src/unixfs.proto", "src/merkledag.proto"], &["src"])
         .unwrap();
 }
@@ -1,23 +1,28 @@
 use std::{
     collections::BTreeMap,
-    fmt::Debug,
+    fmt::{Debug, Display},
     path::{Path, PathBuf},
     pin::Pin,
+    str::FromStr,
+    sync::Arc,
 };
 
-use anyhow::{ensure, Context, Result};
+use anyhow::{anyhow, ensure, Context, Result};
 use async_recursion::async_recursion;
 use bytes::Bytes;
+use cid::Cid;
 use futures::{
     stream::{self, BoxStream},
-    Stream, StreamExt, TryFutureExt,
+    Stream, StreamExt, TryFutureExt, TryStreamExt,
 };
 use prost::Message;
-use tokio::io::AsyncRead;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::sync::{mpsc, Semaphore};
 
 use crate::{
     balanced_tree::{TreeBuilder, DEFAULT_DEGREE},
     chunker::{self, Chunker, ChunkerConfig, DEFAULT_CHUNK_SIZE_LIMIT},
+    codecs::Codec,
     hamt::{bitfield::Bitfield, bits, hash_key},
     types::Block,
     unixfs::{dag_pb, unixfs_pb, DataType, HamtHashFunction, Node, UnixfsNode},
@@ -31,6 +36,10 @@ use crate::{
 // adding a generous buffer, we are using 6k as our link limit
 const DIRECTORY_LINK_LIMIT: usize = 6000;
 
+/// The key of the custom metadata an unwrapped file stores its name under, see
+/// [`Config::keep_name`].
+pub const NAME_METADATA_KEY: &str = "name";
+
 #[derive(Debug, PartialEq)]
 enum DirectoryType {
     Basic,
@@ -38,6 +47,45 @@ enum DirectoryType {
     Hamt,
 }
 
+/// Which kind of UnixFS directory to build, see [`DirectoryBuilder::layout`].
+///
+/// Other implementations pick the kind by their own rules, so forcing one helps to
+/// reproduce the CIDs they produce.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum DirectoryLayout {
+    /// A basic directory, switched to a hamt sharded one above 6000 entries.
+    #[default]
+    Auto,
+    /// Always a basic directory. Building one with more than 6000 entries fails, as its
+    /// block would exceed the block size limit.
+    Basic,
+    /// Always a hamt sharded directory, even with few entries.
+    Hamt,
+}
+
+impl Display for DirectoryLayout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Auto => write!(f, "auto"),
+            Self::Basic => write!(f, "basic"),
+            Self::Hamt => write!(f, "hamt"),
+        }
+    }
+}
+
+impl FromStr for DirectoryLayout {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(Self::Auto),
+            "basic" => Ok(Self::Basic),
+            "hamt" => Ok(Self::Hamt),
+            _ => Err(anyhow!("unknown directory layout: {}", s)),
+        }
+    }
+}
+
 /// Representation of a constructed Directory.
 #[derive(Debug, PartialEq)]
 pub enum Directory {
@@ -50,6 +98,8 @@ pub enum Directory {
 pub struct BasicDirectory {
     name: String,
     entries: Vec<Entry>,
+    /// How many entries are encoded at the same time, see [`DirectoryBuilder::concurrency`].
+    concurrency: usize,
 }
 
 /// A hamt sharded directory
@@ -65,7 +115,11 @@ impl Directory {
     }
 
     pub fn basic(name: String, entries: Vec<Entry>) -> Self {
-        Directory::Basic(BasicDirectory { name, entries })
+        Directory::Basic(BasicDirectory {
+            name,
+            entries,
+            concurrency: 1,
+        })
     }
 
     pub fn name(&self) -> &str {
@@ -105,20 +159,44 @@ impl Directory {
     }
 
     pub fn encode<'a>(self) -> BoxStream<'a, Result<Block>> {
+        self.encode_inner(None, None)
+    }
+
+    fn encode_inner<'a>(
+        self,
+        reporter: Option<EntryReporter>,
+        limit: Option<EncodeLimit>,
+    ) -> BoxStream<'a, Result<Block>> {
         match self {
-            Directory::Basic(basic) => basic.encode(),
-            Directory::Hamt(hamt) => hamt.encode(),
+            Directory::Basic(basic) => basic.encode_inner(reporter, limit),
+            Directory::Hamt(hamt) => hamt.hamt.encode_inner(reporter, limit),
         }
     }
 }
 
 impl BasicDirectory {
     pub fn encode<'a>(self) -> BoxStream<'a, Result<Block>> {
+        self.encode_inner(None, None)
+    }
+
+    /// Encodes the entries concurrently if this directory, or one containing it, has a
+    /// concurrency above 1, sharing the limit of the outermost one.
+    fn encode_inner<'a>(
+        self,
+        reporter: Option<EntryReporter>,
+        limit: Option<EncodeLimit>,
+    ) -> BoxStream<'a, Result<Block>> {
+        let limit =
+            limit.or_else(|| (self.concurrency > 1).then(|| EncodeLimit::new(self.concurrency)));
+        if let Some(limit) = limit {
+            return self.encode_concurrent(reporter, limit);
+        }
         async_stream::try_stream! {
             let mut links = Vec::new();
             for entry in self.entries {
                 let name = entry.name().to_string();
-                let parts = entry.encode().await?;
+                let entry_reporter = reporter.as_ref().map(|r| r.join(&name));
+                let parts = entry.encode_inner(entry_reporter.clone(), None).await?;
                 tokio::pin!(parts);
                 let mut root = None;
                 while let Some(part) = parts.next().await {
@@ -127,6 +205,9 @@ impl BasicDirectory {
                     yield block;
                 }
                 let root_block = root.expect("file must not be empty");
+                if let Some(entry_reporter) = entry_reporter {
+                    entry_reporter.report(*root_block.cid());
+                }
                 links.push(dag_pb::PbLink {
                     hash: Some(root_block.cid().to_bytes()),
                     name: Some(name),
@@ -135,18 +216,126 @@ impl BasicDirectory {
             }
 
             // directory itself comes last
-            let inner = unixfs_pb::Data {
-                r#type: DataType::Directory as i32,
-                ..Default::default()
-            };
-            let outer = encode_unixfs_pb(&inner, links)?;
-            let node = UnixfsNode::Directory(Node { outer, inner });
-            yield node.encode()?;
+            yield encode_directory_node(links)?;
+        }
+        .boxed()
+    }
+
+    /// Encodes the entries concurrently, within `limit`.
+    ///
+    /// The blocks of different entries are interleaved, but every entry still ends with its
+    /// root, and the links are in entry order, so the directory is the same as when
+    /// encoding the entries one after the other.
+    fn encode_concurrent<'a>(
+        self,
+        reporter: Option<EntryReporter>,
+        limit: EncodeLimit,
+    ) -> BoxStream<'a, Result<Block>> {
+        let concurrency = limit.concurrency;
+        async_stream::try_stream! {
+            let (blocks, mut receiver) = mpsc::channel(concurrency);
+            let links = tokio::task::spawn(
+                stream::iter(self.entries)
+                    .map(move |entry| {
+                        let reporter = reporter.as_ref().map(|r| r.join(entry.name()));
+                        encode_entry_into(entry, blocks.clone(), reporter, limit.clone())
+                    })
+                    .buffered(concurrency)
+                    .try_collect::<Vec<_>>(),
+            );
+            while let Some(block) = receiver.recv().await {
+                yield block;
+            }
+            let links = links.await??;
+
+            // directory itself comes last
+            yield encode_directory_node(links)?;
         }
         .boxed()
     }
 }
 
+/// Bounds how many files of a directory tree are encoded at the same time.
+///
+/// Shared by all directories of the tree, so nesting them does not multiply the limit.
+/// Only files hold a permit while they are encoded, directories wait for their entries
+/// without one, which can therefore not starve them.
+#[derive(Debug, Clone)]
+struct EncodeLimit {
+    concurrency: usize,
+    files: Arc<Semaphore>,
+}
+
+impl EncodeLimit {
+    fn new(concurrency: usize) -> Self {
+        EncodeLimit {
+            concurrency,
+            files: Arc::new(Semaphore::new(concurrency)),
+        }
+    }
+}
+
+/// Encodes `entry`, sending its blocks to `blocks`, and returns the link to its root.
+async fn encode_entry_into(
+    entry: Entry,
+    blocks: mpsc::Sender<Block>,
+    reporter: Option<EntryReporter>,
+    limit: EncodeLimit,
+) -> Result<dag_pb::PbLink> {
+    let name = entry.name().to_string();
+    let parts = entry.encode_inner(reporter.clone(), Some(limit)).await?;
+    tokio::pin!(parts);
+    let mut root = None;
+    while let Some(part) = parts.next().await {
+        let block = part?;
+        root = Some((*block.cid(), block.data().len() as u64));
+        blocks
+            .send(block)
+            .await
+            .map_err(|_| anyhow!("directory encoding was dropped"))?;
+    }
+    let (cid, size) = root.ok_or_else(|| anyhow!("entry {} must not be empty", name))?;
+    if let Some(reporter) = reporter {
+        reporter.report(cid);
+    }
+    Ok(dag_pb::PbLink {
+        hash: Some(cid.to_bytes()),
+        name: Some(name),
+        tsize: Some(size),
+    })
+}
+
+/// Reports the path and root of entries once they are encoded, see [`Entry::encode_reporting`].
+#[derive(Debug, Clone)]
+struct EntryReporter {
+    path: PathBuf,
+    entries: mpsc::UnboundedSender<(PathBuf, Cid)>,
+}
+
+impl EntryReporter {
+    fn join(&self, name: &str) -> Self {
+        EntryReporter {
+            path: self.path.join(name),
+            entries: self.entries.clone(),
+        }
+    }
+
+    fn report(&self, root: Cid) {
+        // the receiver going away must not stop the encoding
+        self.entries.send((self.path.clone(), root)).ok();
+    }
+}
+
+fn encode_directory_node(links: Vec<dag_pb::PbLink>) -> Result<Block> {
+    let inner = unixfs_pb::Data {
+        r#type: DataType::Directory as i32,
+        ..Default::default()
+    };
+    let outer = encode_unixfs_pb(&inner, links)?;
+    let node = UnixfsNode::Directory(Node { outer, inner });
+    node.encode()
+}
+
 impl HamtDirectory {
     pub fn encode<'a>(self) -> BoxStream<'a, Result<Block>> {
         self.hamt.encode()
@@ -184,6 +373,8 @@ pub struct File {
     content: Content,
     tree_builder: TreeBuilder,
     chunker: Chunker,
+    raw_leaf_limit: Option<usize>,
+    custom_metadata: BTreeMap<String, String>,
 }
 
 impl Debug for File {
@@ -193,6 +384,8 @@ impl Debug for File {
             .field("content", &self.content)
             .field("tree_builder", &self.tree_builder)
             .field("chunker", &self.chunker)
+            .field("raw_leaf_limit", &self.raw_leaf_limit)
+            .field("custom_metadata", &self.custom_metadata)
             .finish()
     }
 }
@@ -202,6 +395,11 @@ impl File {
         &self.name
     }
 
+    /// Wrap the file in an unnamed directory, to preserve its name.
+    ///
+    /// UnixFS file nodes have no name field, names are only stored in the links of the
+    /// parent directory. Adding an unwrapped file therefore results in a root without a name,
+    /// unless the name is stored in its custom metadata, see [`Config::keep_name`].
     pub fn wrap(self) -> Directory {
         Directory::single("".into(), Entry::File(self))
     }
@@ -219,7 +417,7 @@ impl File {
     }
 
     pub async fn encode(self) -> Result<impl Stream<Item = Result<Block>>> {
-        let reader = match self.content {
+        let mut reader = match self.content {
             Content::Path(path) => {
                 let f = tokio::fs::File::open(path).await?;
                 let buf = tokio::io::BufReader::new(f);
@@ -227,8 +425,70 @@ impl File {
             }
             Content::Reader(reader) => reader,
         };
+
+        let limit = match self.raw_leaf_limit {
+            Some(limit) => limit,
+            None => {
+                let chunks = self.chunker.chunks(reader);
+                let blocks = self.tree_builder.stream_tree(chunks).boxed();
+                return Ok(attach_custom_metadata(blocks, self.custom_metadata, false));
+            }
+        };
+
+        // Read one byte past the limit, to find out whether the file fits into a raw leaf.
+        let mut head = Vec::new();
+        (&mut reader)
+            .take(limit as u64 + 1)
+            .read_to_end(&mut head)
+            .await?;
+        if head.len() <= limit {
+            let blocks = stream::once(async move { UnixfsNode::Raw(head.into()).encode() });
+            return Ok(attach_custom_metadata(
+                blocks.boxed(),
+                self.custom_metadata,
+                false,
+            ));
+        }
+
+        // Too large for a raw leaf, even if it fits into a single chunk.
+        let reader = std::io::Cursor::new(head).chain(reader);
         let chunks = self.chunker.chunks(reader);
-        Ok(self.tree_builder.stream_tree(chunks))
+        let blocks = self.tree_builder.stream_tree(chunks).boxed();
+        Ok(attach_custom_metadata(blocks, self.custom_metadata, true))
+    }
+}
+
+/// Attaches `custom_metadata` to the root of a file, the last of `blocks`.
+///
+/// A file consisting of a single chunk has a raw root, which can not carry metadata, so it
+/// gets a file node linking to the chunk as its new root. With `file_root` that happens even
+/// without metadata, for files above the raw leaf limit that fit into a single chunk.
+fn attach_custom_metadata(
+    blocks: BoxStream<'static, Result<Block>>,
+    custom_metadata: BTreeMap<String, String>,
+    file_root: bool,
+) -> impl Stream<Item = Result<Block>> {
+    async_stream::try_stream! {
+        tokio::pin!(blocks);
+        let mut root = None;
+        while let Some(block) = blocks.next().await {
+            if let Some(block) = root.replace(block?) {
+                yield block;
+            }
+        }
+
+        if let Some(root) = root {
+            let is_raw = root.cid().codec() == Codec::Raw as u64;
+            if custom_metadata.is_empty() && !(file_root && is_raw) {
+                yield root;
+            } else {
+                let (new_root, keep_root) = file_root_with_metadata(&root, custom_metadata)?;
+                if keep_root {
+                    yield root;
+                }
+                yield new_root;
+            }
+        }
     }
 }
 
@@ -277,6 +537,42 @@ impl Symlink {
     }
 }
 
+/// Encodes a new root for the file with the given `root`, carrying `custom_metadata`.
+///
+/// Returns `true` alongside it, if the old root is a raw chunk that is now the only child
+/// of the new root, and must be kept.
+fn file_root_with_metadata(
+    root: &Block,
+    custom_metadata: BTreeMap<String, String>,
+) -> Result<(Block, bool)> {
+    let (inner, links, keep_root) = match UnixfsNode::decode(root.cid(), root.data().clone())? {
+        UnixfsNode::File(node) => (node.inner, node.outer.links, false),
+        UnixfsNode::Raw(data) => {
+            let size = data.len() as u64;
+            let inner = unixfs_pb::Data {
+                r#type: DataType::File as i32,
+                filesize: Some(size),
+                blocksizes: vec![size],
+                ..Default::default()
+            };
+            let links = vec![dag_pb::PbLink {
+                hash: Some(root.cid().to_bytes()),
+                name: None,
+                tsize: Some(size),
+            }];
+            (inner, links, true)
+        }
+        node => anyhow::bail!("unexpected file root {:?}", node.typ()),
+    };
+    let inner = unixfs_pb::Data {
+        custom_metadata,
+        ..inner
+    };
+    let outer = encode_unixfs_pb(&inner, links)?;
+    let node = UnixfsNode::File(Node { outer, inner });
+    Ok((node.encode()?, keep_root))
+}
+
 /// Constructs a UnixFS file.
 pub struct FileBuilder {
     name: Option<String>,
@@ -284,6 +580,8 @@ pub struct FileBuilder {
     reader: Option<Pin<Box<dyn AsyncRead + Send>>>,
     chunker: Chunker,
     degree: usize,
+    raw_leaf_limit: Option<usize>,
+    custom_metadata: BTreeMap<String, String>,
 }
 
 impl Default for FileBuilder {
@@ -294,6 +592,8 @@ impl Default for FileBuilder {
             reader: None,
             chunker: Chunker::Fixed(chunker::Fixed::default()),
             degree: DEFAULT_DEGREE,
+            raw_leaf_limit: None,
+            custom_metadata: BTreeMap::new(),
         }
     }
 }
@@ -310,7 +610,9 @@ impl Debug for FileBuilder {
             .field("name", &self.name)
             .field("chunker", &self.chunker)
             .field("degree", &self.degree)
+            .field("raw_leaf_limit", &self.raw_leaf_limit)
             .field("reader", &reader)
+            .field("custom_metadata", &self.custom_metadata)
             .finish()
     }
 }
@@ -353,6 +655,25 @@ impl FileBuilder {
         self
     }
 
+    /// Set the size up to which a file is stored as a single raw block.
+    ///
+    /// Larger files are chunked into a dag-pb file node, even if they fit into a single
+    /// chunk. Defaults to `None`, which matches go-ipfs: a file is stored as a raw block if
+    /// it fits into a single chunk, 256KiB with the default chunker.
+    pub fn raw_leaf_limit(mut self, limit: Option<usize>) -> Self {
+        self.raw_leaf_limit = limit;
+        self
+    }
+
+    /// Attach application specific metadata to the file.
+    ///
+    /// The metadata is stored on the root node of the file, in a field that is an iroh
+    /// extension to UnixFS, and can be read back from the resolved node. Defaults to none.
+    pub fn custom_metadata(mut self, custom_metadata: BTreeMap<String, String>) -> Self {
+        self.custom_metadata = custom_metadata;
+        self
+    }
+
     pub fn content_bytes<B: Into<Bytes>>(mut self, content: B) -> Self {
         let bytes = content.into();
         self.reader = Some(Box::pin(std::io::Cursor::new(bytes)));
@@ -365,8 +686,18 @@ impl FileBuilder {
     }
 
     pub async fn build(self) -> Result<File> {
+        if let Some(limit) = self.raw_leaf_limit {
+            ensure!(
+                limit <= DEFAULT_CHUNK_SIZE_LIMIT,
+                "raw leaf limit {} exceeds the block size limit {}",
+                limit,
+                DEFAULT_CHUNK_SIZE_LIMIT
+            );
+        }
         let degree = self.degree;
         let chunker = self.chunker;
+        let raw_leaf_limit = self.raw_leaf_limit;
+        let custom_metadata = self.custom_metadata;
         let tree_builder = TreeBuilder::balanced_tree_with_degree(degree);
         if let Some(path) = self.path {
             let name = match self.name {
@@ -381,7 +712,9 @@ impl FileBuilder {
                 content: Content::Path(path),
                 name,
                 chunker,
+                raw_leaf_limit,
                 tree_builder,
+                custom_metadata,
             });
         }
 
@@ -394,7 +727,9 @@ impl FileBuilder {
                 content: Content::Reader(reader),
                 name,
                 chunker,
+                raw_leaf_limit,
                 tree_builder,
+                custom_metadata,
             });
         }
         anyhow::bail!("must have a path to the content or a reader for the content");
@@ -420,9 +755,47 @@ impl Entry {
     }
 
     pub async fn encode(self) -> Result<BoxStream<'static, Result<Block>>> {
+        self.encode_inner(None, None).await
+    }
+
+    /// Like [`Entry::encode`], but also sends the path and root of every entry below this one
+    /// to `entries`, as soon as that entry is encoded.
+    ///
+    /// Paths are relative to this entry, this entry itself is not reported.
+    pub async fn encode_reporting(
+        self,
+        entries: mpsc::UnboundedSender<(PathBuf, Cid)>,
+    ) -> Result<BoxStream<'static, Result<Block>>> {
+        let reporter = EntryReporter {
+            path: PathBuf::new(),
+            entries,
+        };
+        self.encode_inner(Some(reporter), None).await
+    }
+
+    /// Encodes this entry, a file holds a permit of `limit` until its blocks are consumed.
+    async fn encode_inner(
+        self,
+        reporter: Option<EntryReporter>,
+        limit: Option<EncodeLimit>,
+    ) -> Result<BoxStream<'static, Result<Block>>> {
         Ok(match self {
-            Entry::File(f) => f.encode().await?.boxed(),
-            Entry::Directory(d) => d.encode(),
+            Entry::File(f) => match limit {
+                Some(limit) => {
+                    let permit = limit.files.acquire_owned().await?;
+                    let blocks = f.encode().await?;
+                    async_stream::try_stream! {
+                        let _permit = permit;
+                        tokio::pin!(blocks);
+                        while let Some(block) = blocks.next().await {
+                            yield block?;
+                        }
+                    }
+                    .boxed()
+                }
+                None => f.encode().await?.boxed(),
+            },
+            Entry::Directory(d) => d.encode_inner(reporter, limit),
             Entry::Symlink(s) => stream::iter(Some(s.encode())).boxed(),
         })
     }
@@ -433,6 +806,10 @@ impl Entry {
                 let chunker = chunker_config.into();
                 let dir = DirectoryBuilder::new()
                     .chunker(chunker)
+                    .raw_leaf_limit(config.raw_leaf_limit)
+                    .concurrency(config.concurrency)
+                    .layout(config.directory_layout)
+                    .custom_metadata(config.custom_metadata)
                     .path(path)
                     .build()
                     .await?;
@@ -445,6 +822,8 @@ impl Entry {
                 let chunker = chunker_config.into();
                 let file = FileBuilder::new()
                     .chunker(chunker)
+                    .raw_leaf_limit(config.raw_leaf_limit)
+                    .custom_metadata(config.custom_metadata)
                     .path(path)
                     .build()
                     .await?;
@@ -461,7 +840,14 @@ impl Entry {
         if config.wrap {
             return Ok(Entry::Directory(entry.wrap()));
         }
-        Ok(entry)
+        match entry {
+            Entry::File(mut file) if config.keep_name => {
+                file.custom_metadata
+                    .insert(NAME_METADATA_KEY.to_string(), file.name.clone());
+                Ok(Entry::File(file))
+            }
+            entry => Ok(entry),
+        }
     }
 
     fn wrap(self) -> Directory {
@@ -479,8 +865,12 @@ pub struct DirectoryBuilder {
     name: Option<String>,
     entries: Vec<Entry>,
     typ: DirectoryType,
+    layout: DirectoryLayout,
     chunker: Chunker,
     degree: usize,
+    raw_leaf_limit: Option<usize>,
+    concurrency: usize,
+    custom_metadata: BTreeMap<String, String>,
     path: Option<PathBuf>,
 }
 
@@ -490,8 +880,12 @@ impl Default for DirectoryBuilder {
             name: None,
             entries: Default::default(),
             typ: DirectoryType::Basic,
+            layout: DirectoryLayout::Auto,
             chunker: Chunker::Fixed(chunker::Fixed::default()),
             degree: DEFAULT_DEGREE,
+            raw_leaf_limit: None,
+            concurrency: 1,
+            custom_metadata: BTreeMap::new(),
             path: None,
         }
     }
@@ -502,8 +896,21 @@ impl DirectoryBuilder {
         Default::default()
     }
 
-    pub fn hamt(mut self) -> Self {
-        self.typ = DirectoryType::Hamt;
+    pub fn hamt(self) -> Self {
+        self.layout(DirectoryLayout::Hamt)
+    }
+
+    /// Set which kind of directory to build, and of its subdirectories when built from a
+    /// path. Defaults to [`DirectoryLayout::Auto`].
+    pub fn layout(mut self, layout: DirectoryLayout) -> Self {
+        self.typ = match layout {
+            DirectoryLayout::Auto if self.entries.len() > DIRECTORY_LINK_LIMIT => {
+                DirectoryType::Hamt
+            }
+            DirectoryLayout::Auto | DirectoryLayout::Basic => DirectoryType::Basic,
+            DirectoryLayout::Hamt => DirectoryType::Hamt,
+        };
+        self.layout = layout;
         self
     }
 
@@ -527,6 +934,32 @@ impl DirectoryBuilder {
         self
     }
 
+    /// Set the raw leaf limit of files added from the path, see
+    /// [`FileBuilder::raw_leaf_limit`].
+    pub fn raw_leaf_limit(mut self, limit: Option<usize>) -> Self {
+        self.raw_leaf_limit = limit;
+        self
+    }
+
+    /// Set how many files of the directory and all of its subdirectories are encoded at the
+    /// same time. Defaults to 1, encoding them one after the other.
+    ///
+    /// The limit is shared by the whole tree, the concurrency of subdirectories is ignored
+    /// when encoding them as part of this one. The result does not depend on the
+    /// concurrency, only the order of the blocks of different entries does. The entries of
+    /// hamt sharded directories are encoded sequentially.
+    pub fn concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    /// Attach application specific metadata to the files added from the path, see
+    /// [`FileBuilder::custom_metadata`].
+    pub fn custom_metadata(mut self, custom_metadata: BTreeMap<String, String>) -> Self {
+        self.custom_metadata = custom_metadata;
+        self
+    }
+
     pub fn add_dir(self, dir: Directory) -> Result<Self> {
         Ok(self.entry(Entry::Directory(dir)))
     }
@@ -540,33 +973,66 @@ impl DirectoryBuilder {
     }
 
     fn entry(mut self, entry: Entry) -> Self {
-        if self.typ == DirectoryType::Basic && self.entries.len() >= DIRECTORY_LINK_LIMIT {
+        if self.layout == DirectoryLayout::Auto
+            && self.typ == DirectoryType::Basic
+            && self.entries.len() >= DIRECTORY_LINK_LIMIT
+        {
             self.typ = DirectoryType::Hamt
         }
         self.entries.push(entry);
         self
     }
 
+    /// Builds the directory, with its entries sorted by name.
+    ///
+    /// Sorting makes the root CID independent of the order entries were added in, or read
+    /// from the file system, and matches the link order go-ipfs produces. Fails for a
+    /// [`DirectoryLayout::Basic`] directory with too many entries.
     pub async fn build(self) -> Result<Directory> {
         let DirectoryBuilder {
             name,
-            entries,
+            mut entries,
             typ,
+            layout,
             path,
             chunker,
             degree,
+            raw_leaf_limit,
+            concurrency,
+            custom_metadata,
         } = self;
 
         Ok(if let Some(path) = path {
-            let mut dir = make_dir_from_path(path, chunker.clone(), degree).await?;
+            let mut dir = make_dir_from_path(
+                path,
+                chunker.clone(),
+                degree,
+                raw_leaf_limit,
+                concurrency,
+                layout,
+                custom_metadata,
+            )
+            .await?;
             if let Some(name) = name {
                 dir.set_name(name);
             }
             dir
         } else {
             let name = name.unwrap_or_default();
+            ensure!(
+                typ == DirectoryType::Hamt || entries.len() <= DIRECTORY_LINK_LIMIT,
+                "directory {:?} has {} entries, a basic directory can hold at most {}",
+                name,
+                entries.len(),
+                DIRECTORY_LINK_LIMIT
+            );
+            entries.sort_by(|a, b| a.name().cmp(b.name()));
             match typ {
-                DirectoryType::Basic => Directory::Basic(BasicDirectory { name, entries }),
+                DirectoryType::Basic => Directory::Basic(BasicDirectory {
+                    name,
+                    entries,
+                    concurrency,
+                }),
                 DirectoryType::Hamt => {
                     let hamt = HamtNode::new(entries)
                         .context("unable to build hamt. Probably a hash collision.")?;
@@ -638,6 +1104,14 @@ impl HamtNode {
     }
 
     pub fn encode<'a>(self) -> BoxStream<'a, Result<Block>> {
+        self.encode_inner(None, None)
+    }
+
+    fn encode_inner<'a>(
+        self,
+        reporter: Option<EntryReporter>,
+        limit: Option<EncodeLimit>,
+    ) -> BoxStream<'a, Result<Block>> {
         match self {
             Self::Branch(tree) => {
                 async_stream::try_stream! {
@@ -646,7 +1120,17 @@ impl HamtNode {
                     for (prefix, node) in tree {
                         let name = format!("{:02X}{}", prefix, node.name());
                         bitfield.set_bit(prefix);
-                        let blocks = node.encode();
+                        // only leafs are entries, branches are part of the directory itself
+                        let entry_reporter = match node {
+                            HamtNode::Leaf(HamtLeaf(_, ref entry)) => {
+                                reporter.as_ref().map(|r| r.join(entry.name()))
+                            }
+                            HamtNode::Branch(_) => None,
+                        };
+                        let blocks = node.encode_inner(
+                            entry_reporter.clone().or_else(|| reporter.clone()),
+                            limit.clone(),
+                        );
                         let mut root = None;
                         tokio::pin!(blocks);
                         while let Some(block) = blocks.next().await {
@@ -654,6 +1138,9 @@ impl HamtNode {
                             root = Some(*block.cid());
                             yield block;
                         }
+                        if let (Some(entry_reporter), Some(root)) = (&entry_reporter, root) {
+                            entry_reporter.report(root);
+                        }
                         links.push(crate::unixfs::dag_pb::PbLink {
                             name: Some(name),
                             hash: root.map(|cid| cid.to_bytes()),
@@ -675,9 +1162,11 @@ impl HamtNode {
                 }
                 .boxed()
             }
-            Self::Leaf(HamtLeaf(_hash, entry)) => async move { entry.encode().await }
-                .try_flatten_stream()
-                .boxed(),
+            Self::Leaf(HamtLeaf(_hash, entry)) => {
+                async move { entry.encode_inner(reporter, limit).await }
+                    .try_flatten_stream()
+                    .boxed()
+            }
         }
     }
 }
@@ -739,7 +1228,26 @@ pub(crate) fn encode_unixfs_pb(
 pub struct Config {
     /// Should the outer object be wrapped in a directory?
     pub wrap: bool,
+    /// Store the name of an unwrapped file in the custom metadata of its root, under
+    /// [`NAME_METADATA_KEY`], so it is available without a wrapping directory.
+    ///
+    /// Wrapped entries are named by the link of the wrapping directory instead, and
+    /// unwrapped directories and symlinks are left without a name.
+    pub keep_name: bool,
+    /// Application specific metadata attached to every added file, see
+    /// [`FileBuilder::custom_metadata`].
+    pub custom_metadata: BTreeMap<String, String>,
+    /// The chunker files are split with. The chunker is part of the resulting CIDs, see
+    /// [`ChunkerConfig`].
     pub chunker: Option<ChunkerConfig>,
+    /// The size up to which files are stored as a single raw block, see
+    /// [`FileBuilder::raw_leaf_limit`].
+    pub raw_leaf_limit: Option<usize>,
+    /// How many entries of a directory are encoded at the same time, see
+    /// [`DirectoryBuilder::concurrency`].
+    pub concurrency: usize,
+    /// Which kind of directories to build, see [`DirectoryLayout`].
+    pub directory_layout: DirectoryLayout,
 }
 
 #[async_recursion(?Send)]
@@ -747,13 +1255,20 @@ async fn make_dir_from_path<P: Into<PathBuf>>(
     path: P,
     chunker: Chunker,
     degree: usize,
+    raw_leaf_limit: Option<usize>,
+    concurrency: usize,
+    layout: DirectoryLayout,
+    custom_metadata: BTreeMap<String, String>,
 ) -> Result<Directory> {
     let path = path.into();
-    let mut dir = DirectoryBuilder::new().name(
-        path.file_name()
-            .and_then(|s| s.to_str())
-            .unwrap_or_default(),
-    );
+    let mut dir = DirectoryBuilder::new()
+        .name(
+            path.file_name()
+                .and_then(|s| s.to_str())
+                .unwrap_or_default(),
+        )
+        .concurrency(concurrency)
+        .layout(layout);
 
     let mut directory_reader = tokio::fs::read_dir(path.clone()).await?;
     while let Some(entry) = directory_reader.next_entry().await? {
@@ -765,12 +1280,23 @@ async fn make_dir_from_path<P: Into<PathBuf>>(
             let f = FileBuilder::new()
                 .chunker(chunker.clone())
                 .degree(degree)
+                .raw_leaf_limit(raw_leaf_limit)
+                .custom_metadata(custom_metadata.clone())
                 .path(path)
                 .build()
                 .await?;
             dir = dir.add_file(f);
         } else if path.is_dir() {
-            let d = make_dir_from_path(path, chunker.clone(), degree).await?;
+            let d = make_dir_from_path(
+                path,
+                chunker.clone(),
+                degree,
+                raw_leaf_limit,
+                concurrency,
+                layout,
+                custom_metadata.clone(),
+            )
+            .await?;
             dir = dir.add_dir(d)?;
         } else {
             anyhow::bail!("directory entry is neither file nor directory")
@@ -891,6 +1417,276 @@ mod tests {
         // TODO: check content
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_dir_builder_order() -> Result<()> {
+        async fn root_block(names: &[&str]) -> Result<Block> {
+            let mut dir = DirectoryBuilder::new().name("foo");
+            for name in names {
+                let file = FileBuilder::new()
+                    .name(*name)
+                    .content_bytes(name.as_bytes().to_vec())
+                    .build()
+                    .await?;
+                dir = dir.add_file(file);
+            }
+            dir.build().await?.encode_root().await
+        }
+
+        let expected = root_block(&["a.txt", "b.txt", "c.txt"]).await?;
+        assert_eq!(root_block(&["c.txt", "a.txt", "b.txt"]).await?, expected);
+        assert_eq!(root_block(&["b.txt", "c.txt", "a.txt"]).await?, expected);
+
+        // the links of the encoded directory are sorted as well
+        let dir_block = root_block(&["b.txt", "a.txt"]).await?;
+        let decoded_dir = UnixfsNode::decode(dir_block.cid(), dir_block.data().clone())?;
+        let links = decoded_dir.links().collect::<Result<Vec<_>>>()?;
+        assert_eq!(links[0].name.unwrap(), "a.txt");
+        assert_eq!(links[1].name.unwrap(), "b.txt");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_dir_builder_concurrency() -> Result<()> {
+        async fn encode(concurrency: usize) -> Result<Vec<Block>> {
+            let mut dir = DirectoryBuilder::new().name("foo").concurrency(concurrency);
+            let mut nested = DirectoryBuilder::new().name("bar").concurrency(concurrency);
+            for i in 1..10 {
+                let file = |name: String| {
+                    FileBuilder::new()
+                        .name(name)
+                        .content_bytes(vec![i as u8; i * 10])
+                        .fixed_chunker(8)
+                        .build()
+                };
+                dir = dir.add_file(file(format!("{i}.txt")).await?);
+                nested = nested.add_file(file(format!("nested-{i}.txt")).await?);
+            }
+            let dir = dir.add_dir(nested.build().await?)?.build().await?;
+            dir.encode().try_collect().await
+        }
+
+        let sequential = encode(1).await?;
+        let concurrent = encode(4).await?;
+        assert_eq!(concurrent.last(), sequential.last());
+
+        let cids = |blocks: &[Block]| {
+            blocks
+                .iter()
+                .map(|block| *block.cid())
+                .collect::<std::collections::BTreeSet<_>>()
+        };
+        assert_eq!(concurrent.len(), sequential.len());
+        assert_eq!(cids(&concurrent), cids(&sequential));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_dir_builder_concurrency_limit() -> Result<()> {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::task::{Context, Poll};
+        use tokio::io::ReadBuf;
+
+        /// Tracks how many files are read at the same time, yielding after every read.
+        struct Tracked {
+            data: std::io::Cursor<Vec<u8>>,
+            started: bool,
+            yielded: bool,
+            active: Arc<AtomicUsize>,
+            max_active: Arc<AtomicUsize>,
+        }
+
+        impl AsyncRead for Tracked {
+            fn poll_read(
+                mut self: Pin<&mut Self>,
+                cx: &mut Context<'_>,
+                buf: &mut ReadBuf<'_>,
+            ) -> Poll<std::io::Result<()>> {
+                if !std::mem::replace(&mut self.yielded, true) {
+                    cx.waker().wake_by_ref();
+                    return Poll::Pending;
+                }
+                self.yielded = false;
+                if !std::mem::replace(&mut self.started, true) {
+                    let active = self.active.fetch_add(1, Ordering::SeqCst) + 1;
+                    self.max_active.fetch_max(active, Ordering::SeqCst);
+                }
+                let filled = buf.filled().len();
+                let res = Pin::new(&mut self.data).poll_read(cx, buf);
+                if buf.filled().len() == filled {
+                    self.active.fetch_sub(1, Ordering::SeqCst);
+                }
+                res
+            }
+        }
+
+        let active = Arc::new(AtomicUsize::new(0));
+        let max_active = Arc::new(AtomicUsize::new(0));
+        let mut dir = DirectoryBuilder::new().name("foo").concurrency(2);
+        for i in 0..3 {
+            let mut nested = DirectoryBuilder::new()
+                .name(format!("bar-{i}"))
+                .concurrency(2);
+            for j in 0..3 {
+                let file = FileBuilder::new()
+                    .name(format!("{j}.txt"))
+                    .content_reader(Tracked {
+                        data: std::io::Cursor::new(vec![j as u8; 64]),
+                        started: false,
+                        yielded: false,
+                        active: active.clone(),
+                        max_active: max_active.clone(),
+                    })
+                    .fixed_chunker(8)
+                    .build()
+                    .await?;
+                nested = nested.add_file(file);
+            }
+            dir = dir.add_dir(nested.build().await?)?;
+        }
+        let blocks: Vec<_> = dir.build().await?.encode().try_collect().await?;
+        assert!(!blocks.is_empty());
+
+        // nested directories share the limit of the outermost one
+        assert_eq!(max_active.load(Ordering::SeqCst), 2);
+        assert_eq!(active.load(Ordering::SeqCst), 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_encode_reporting() -> Result<()> {
+        let file = |name: &str| {
+            FileBuilder::new()
+                .name(name)
+                .content_bytes(name.as_bytes().to_vec())
+                .build()
+        };
+        let nested = DirectoryBuilder::new()
+            .name("bar")
+            .add_file(file("b.txt").await?)
+            .build()
+            .await?;
+        let dir = DirectoryBuilder::new()
+            .name("foo")
+            .add_file(file("a.txt").await?)
+            .add_dir(nested)?
+            .build()
+            .await?;
+
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        let blocks: Vec<Block> = Entry::Directory(dir)
+            .encode_reporting(sender)
+            .await?
+            .try_collect()
+            .await?;
+        let mut paths = Vec::new();
+        while let Some((path, cid)) = receiver.recv().await {
+            assert!(blocks.iter().any(|block| *block.cid() == cid));
+            paths.push(path);
+        }
+        let expected: Vec<PathBuf> = vec!["a.txt".into(), "bar/b.txt".into(), "bar".into()];
+        assert_eq!(paths, expected);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_rabin_chunker_dedup() -> Result<()> {
+        use rand::{rngs::StdRng, RngCore, SeedableRng};
+        use std::collections::HashSet;
+
+        let mut content = vec![0u8; 2 * 1024 * 1024];
+        StdRng::seed_from_u64(0).fill_bytes(&mut content);
+        // the same content, shifted by a few inserted bytes
+        let shifted = [&b"shift"[..], &content].concat();
+
+        let leaves = |chunker: ChunkerConfig, content: Vec<u8>| async move {
+            let file = FileBuilder::new()
+                .name("foo")
+                .chunker(chunker.into())
+                .content_bytes(content)
+                .build()
+                .await?;
+            let blocks: Vec<_> = file.encode().await?.try_collect().await?;
+            Ok::<_, anyhow::Error>(
+                blocks
+                    .into_iter()
+                    .filter(|block| block.cid().codec() == Codec::Raw as u64)
+                    .map(|block| *block.cid())
+                    .collect::<HashSet<_>>(),
+            )
+        };
+
+        // fixed size chunks of shifted content all differ
+        let fixed = ChunkerConfig::Fixed(DEFAULT_CHUNKS_SIZE);
+        let original = leaves(fixed, content.clone()).await?;
+        let other = leaves(fixed, shifted.clone()).await?;
+        assert_eq!(original.intersection(&other).count(), 0);
+
+        // content defined chunks only differ around the insertion
+        let original = leaves(ChunkerConfig::Rabin, content).await?;
+        let other = leaves(ChunkerConfig::Rabin, shifted).await?;
+        assert!(original.len() > 2);
+        assert!(original.intersection(&other).count() >= original.len() - 2);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_raw_leaf_limit() -> Result<()> {
+        let encode = |limit: Option<usize>| async move {
+            let file = FileBuilder::new()
+                .name("foo.txt")
+                .content_bytes(b"hello world".to_vec())
+                .fixed_chunker(4)
+                .raw_leaf_limit(limit)
+                .build()
+                .await?;
+            file.encode().await?.try_collect::<Vec<_>>().await
+        };
+        let root_codec = |blocks: &[Block]| blocks.last().unwrap().cid().codec();
+
+        // by default the file is chunked, as it does not fit into a single chunk
+        let blocks = encode(None).await?;
+        assert_eq!(blocks.len(), 4);
+        assert_eq!(root_codec(&blocks), Codec::DagPb as u64);
+
+        // a higher limit keeps it in a single raw block
+        let blocks = encode(Some(11)).await?;
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(root_codec(&blocks), Codec::Raw as u64);
+        assert_eq!(blocks[0].data().as_ref(), b"hello world");
+
+        // just above the limit it is chunked again
+        let blocks = encode(Some(10)).await?;
+        assert_eq!(blocks.len(), 4);
+        assert_eq!(root_codec(&blocks), Codec::DagPb as u64);
+
+        // a file fitting into a single chunk, but above the limit, gets a file node root
+        let file = FileBuilder::new()
+            .name("foo.txt")
+            .content_bytes(b"hey".to_vec())
+            .raw_leaf_limit(Some(2))
+            .build()
+            .await?;
+        let blocks: Vec<_> = file.encode().await?.try_collect().await?;
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(root_codec(&blocks), Codec::DagPb as u64);
+        let root = UnixfsNode::decode(blocks[1].cid(), blocks[1].data().clone())?;
+        assert_eq!(root.typ(), Some(DataType::File));
+        assert_eq!(root.filesize(), Some(3));
+
+        assert!(FileBuilder::new()
+            .name("foo.txt")
+            .content_bytes(b"hey".to_vec())
+            .raw_leaf_limit(Some(DEFAULT_CHUNK_SIZE_LIMIT + 1))
+            .build()
+            .await
+            .is_err());
+
+        Ok(())
+    }
+
     #[cfg(not(windows))]
     #[tokio::test]
     async fn symlink_from_disk_test() -> Result<()> {
@@ -1015,6 +1811,38 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_directory_layout() -> Result<()> {
+        let file = |i: usize| {
+            FileBuilder::new()
+                .name(format!("{i}.txt"))
+                .content_bytes(Bytes::from("hello world"))
+                .build()
+        };
+
+        // hamt sharding can be forced for a small directory
+        let dir = DirectoryBuilder::new()
+            .layout(DirectoryLayout::Hamt)
+            .add_file(file(0).await?)
+            .build()
+            .await?;
+        assert!(matches!(dir, Directory::Hamt(_)));
+
+        let mut auto = DirectoryBuilder::new();
+        let mut basic = DirectoryBuilder::new().layout(DirectoryLayout::Basic);
+        for i in 0..=DIRECTORY_LINK_LIMIT {
+            auto = auto.add_file(file(i).await?);
+            basic = basic.add_file(file(i).await?);
+        }
+        assert!(matches!(auto.build().await?, Directory::Hamt(_)));
+        // a basic directory this large would not fit into a block
+        assert!(basic.build().await.is_err());
+
+        assert_eq!("hamt".parse::<DirectoryLayout>()?, DirectoryLayout::Hamt);
+        assert!("sharded".parse::<DirectoryLayout>().is_err());
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_hamt_hash_collision() -> Result<()> {
         // allow hamt override
@@ -1080,6 +1908,10 @@ mod tests {
             dir,
             Chunker::Fixed(chunker::Fixed::default()),
             DEFAULT_DEGREE,
+            None,
+            1,
+            DirectoryLayout::Auto,
+            BTreeMap::new(),
         )
         .await?;
 
@@ -1099,6 +1931,84 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_from_path_keep_name() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("foo.txt");
+        std::fs::write(&path, b"hello world")?;
+        let config = |wrap, keep_name| Config {
+            wrap,
+            keep_name,
+            custom_metadata: BTreeMap::new(),
+            chunker: Some(ChunkerConfig::Fixed(DEFAULT_CHUNKS_SIZE)),
+            raw_leaf_limit: None,
+            concurrency: 1,
+            directory_layout: DirectoryLayout::Auto,
+        };
+        let root = |entry: Entry| async move {
+            let blocks: Vec<_> = entry.encode().await?.try_collect().await?;
+            let root = blocks.last().unwrap().clone();
+            UnixfsNode::decode(root.cid(), root.data().clone())
+        };
+
+        // without a wrapping directory the name is lost, unless it is kept in the metadata
+        let node = root(Entry::from_path(&path, config(false, false)).await?).await?;
+        assert_eq!(node.custom_metadata(), None);
+        let node = root(Entry::from_path(&path, config(false, true)).await?).await?;
+        let metadata = node.custom_metadata().unwrap();
+        assert_eq!(metadata.get(NAME_METADATA_KEY).unwrap(), "foo.txt");
+
+        // a wrapped file is named by its link
+        let node = root(Entry::from_path(&path, config(true, true)).await?).await?;
+        let links = node.links().collect::<Result<Vec<_>>>()?;
+        assert_eq!(links[0].name, Some("foo.txt"));
+        assert!(node.custom_metadata().unwrap().is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_from_path_custom_metadata() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let nested = dir.path().join("nested");
+        std::fs::create_dir(&nested)?;
+        std::fs::write(nested.join("foo.txt"), b"hello world")?;
+        let custom_metadata: BTreeMap<_, _> = [("author".to_string(), "iroh".to_string())]
+            .into_iter()
+            .collect();
+        let config = Config {
+            wrap: false,
+            keep_name: false,
+            custom_metadata: custom_metadata.clone(),
+            chunker: Some(ChunkerConfig::Fixed(DEFAULT_CHUNKS_SIZE)),
+            raw_leaf_limit: None,
+            concurrency: 1,
+            directory_layout: DirectoryLayout::Auto,
+        };
+
+        // files in subdirectories carry the metadata as well, directories do not
+        let blocks: Vec<_> = Entry::from_path(dir.path(), config)
+            .await?
+            .encode()
+            .await?
+            .try_collect()
+            .await?;
+        let mut files = 0;
+        for block in blocks {
+            match UnixfsNode::decode(block.cid(), block.data().clone())? {
+                node @ UnixfsNode::File(_) => {
+                    assert_eq!(node.custom_metadata(), Some(&custom_metadata));
+                    files += 1;
+                }
+                node @ UnixfsNode::Directory(_) => {
+                    assert!(node.custom_metadata().unwrap().is_empty());
+                }
+                _ => {}
+            }
+        }
+        assert_eq!(files, 1);
+        Ok(())
+    }
+
     #[test]
     fn test_chunk_config_from_str() {
         assert_eq!(
@@ -38,11 +38,21 @@ impl Display for Chunker {
 }
 
 /// Chunker configuration.
+///
+/// The chunks of a file determine its CID, so the same content added with different
+/// chunkers, or chunk sizes, gets different CIDs and shares no blocks. Content is only
+/// deduplicated against other content, and other IPFS implementations, using the same
+/// chunker.
 #[derive(Debug, Clone, PartialEq, Eq, Copy)]
 pub enum ChunkerConfig {
     /// Fixed sized chunker.
     Fixed(usize),
-    /// Rabin chunker.
+    /// Rabin chunker, which cuts chunks at content defined boundaries, between 85KiB and
+    /// 384KiB and 256KiB on average.
+    ///
+    /// Inserting or removing bytes only changes the chunks around the edit, so versions of
+    /// a file share most of their blocks, unlike with fixed size chunks. Uses the parameters
+    /// of `ipfs add --chunker=rabin` in go-ipfs.
     Rabin,
 }
 
@@ -1,13 +1,15 @@
 use std::{
     collections::{HashMap, HashSet},
     fmt::{self, Debug, Display, Formatter},
+    future::Future,
     hash::BuildHasher,
     str::FromStr,
+    sync::atomic::{AtomicUsize, Ordering},
     sync::Arc,
     sync::Mutex,
 };
 
-use anyhow::{anyhow, bail, Result};
+use anyhow::{anyhow, Context, Result};
 use async_trait::async_trait;
 use bytes::Bytes;
 use cid::{multibase::Base, Cid};
@@ -15,12 +17,15 @@ use futures::future::Either;
 use iroh_rpc_client::Client;
 use rand::seq::SliceRandom;
 use reqwest::Url;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::Instant;
 use tracing::{debug, info, trace, warn};
 
 use crate::{
     indexer::{Indexer, IndexerUrl},
     parse_links,
     types::{LoadedCid, Source},
+    unixfs::DecodeLimits,
 };
 
 pub const IROH_STORE: &str = "iroh-store";
@@ -131,10 +136,14 @@ impl FullLoader {
 
     async fn fetch_store(&self, cid: &Cid) -> Result<Option<LoadedCid>> {
         match self.client.try_store() {
-            Ok(store) => Ok(store.get(*cid).await?.map(|data| LoadedCid {
-                data,
-                source: Source::Store(IROH_STORE),
-            })),
+            Ok(store) => Ok(store
+                .get(*cid)
+                .await
+                .context(LoadError::Store)?
+                .map(|data| LoadedCid {
+                    data,
+                    source: Source::Store(IROH_STORE),
+                })),
             Err(err) => {
                 info!("No store available: {:?}", err);
                 Ok(None)
@@ -142,12 +151,17 @@ impl FullLoader {
         }
     }
 
-    async fn fetch_bitswap(&self, ctx: ContextId, cid: &Cid) -> Result<Option<LoadedCid>> {
+    async fn fetch_bitswap(&self, ctx: &LoaderContext, cid: &Cid) -> Result<Option<LoadedCid>> {
         match self.client.try_p2p() {
             Ok(p2p) => {
+                let max_providers = ctx.max_providers();
                 let providers: HashSet<_> = if let Some(ref indexer) = self.indexer {
                     if let Ok(providers) = indexer.find_providers(*cid).await {
-                        providers.into_iter().map(|p| p.id).collect()
+                        providers
+                            .into_iter()
+                            .map(|p| p.id)
+                            .take(max_providers.unwrap_or(usize::MAX))
+                            .collect()
                     } else {
                         Default::default()
                     }
@@ -155,10 +169,18 @@ impl FullLoader {
                     Default::default()
                 };
 
-                let data = p2p.fetch_bitswap(ctx.into(), *cid, providers).await?;
+                let (data, from) = p2p
+                    .fetch_bitswap_with_origin(
+                        ctx.id().into(),
+                        *cid,
+                        providers,
+                        ctx.priority(),
+                        max_providers,
+                    )
+                    .await?;
                 Ok(Some(LoadedCid {
                     data,
-                    source: Source::Bitswap,
+                    source: Source::Bitswap(from),
                 }))
             }
             Err(err) => {
@@ -236,11 +258,13 @@ impl ContentLoader for FullLoader {
     async fn load_cid(&self, cid: &Cid, ctx: &LoaderContext) -> Result<LoadedCid> {
         trace!("{:?} loading {}", ctx.id(), cid);
 
-        if let Some(loaded) = self.fetch_store(cid).await? {
+        if ctx.force_refresh() {
+            debug!("{:?} force refresh, skipping store for {}", ctx.id(), cid);
+        } else if let Some(loaded) = self.fetch_store(cid).await? {
             return Ok(loaded);
         }
 
-        let bitswap_future = self.fetch_bitswap(ctx.id(), cid);
+        let bitswap_future = self.fetch_bitswap(ctx, cid);
         let gateway_future = self.fetch_gateway(cid);
 
         tokio::pin!(bitswap_future);
@@ -261,7 +285,7 @@ impl ContentLoader for FullLoader {
                         if bitswap_offline && gateway_offline {
                             return Err(anyhow!("offline"));
                         }
-                        return Err(anyhow!("failed to find {}", cid));
+                        return Err(LoadError::NotFound(*cid).into());
                     }
                 }
             }
@@ -278,7 +302,7 @@ impl ContentLoader for FullLoader {
                         if bitswap_offline && gateway_offline {
                             return Err(anyhow!("offline"));
                         }
-                        return Err(anyhow!("failed to find {}", cid));
+                        return Err(LoadError::NotFound(*cid).into());
                     }
                 }
             }
@@ -296,6 +320,13 @@ impl ContentLoader for FullLoader {
 #[derive(Debug, Clone)]
 pub struct LoaderContext {
     id: ContextId,
+    force_refresh: bool,
+    priority: Option<i32>,
+    max_providers: Option<usize>,
+    deadline: Option<Instant>,
+    block_budget: Option<Arc<AtomicUsize>>,
+    decode_limits: Option<DecodeLimits>,
+    load_limit: Option<Arc<Semaphore>>,
     inner: Arc<Mutex<InnerLoaderContext>>,
 }
 
@@ -304,6 +335,13 @@ impl LoaderContext {
         trace!("new loader context: {:?}", id);
         LoaderContext {
             id,
+            force_refresh: false,
+            priority: None,
+            max_providers: None,
+            deadline: None,
+            block_budget: None,
+            decode_limits: None,
+            load_limit: None,
             inner: Arc::new(Mutex::new(InnerLoaderContext { closer })),
         }
     }
@@ -311,6 +349,128 @@ impl LoaderContext {
     pub fn id(&self) -> ContextId {
         self.id
     }
+
+    /// If set, loaders skip locally cached content and fetch it from the network again.
+    /// The freshly loaded content is still stored locally.
+    pub fn force_refresh(&self) -> bool {
+        self.force_refresh
+    }
+
+    pub fn set_force_refresh(&mut self, force_refresh: bool) {
+        self.force_refresh = force_refresh;
+    }
+
+    /// The wantlist priority used when fetching content over bitswap, higher is more urgent.
+    /// If `None`, the bitswap default is used.
+    pub fn priority(&self) -> Option<i32> {
+        self.priority
+    }
+
+    pub fn set_priority(&mut self, priority: Option<i32>) {
+        self.priority = priority;
+    }
+
+    /// The maximum number of providers queried when fetching content over bitswap.
+    /// If `None`, the bitswap default is used.
+    pub fn max_providers(&self) -> Option<usize> {
+        self.max_providers
+    }
+
+    pub fn set_max_providers(&mut self, max_providers: Option<usize>) {
+        self.max_providers = max_providers;
+    }
+
+    /// The point in time after which HAMT lookups and waiting for a load slot with this
+    /// context fail.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    pub fn set_deadline(&mut self, deadline: Option<Instant>) {
+        self.deadline = deadline;
+    }
+
+    /// Returns an error if the deadline has passed.
+    pub fn check_deadline(&self) -> Result<()> {
+        match self.deadline {
+            Some(deadline) if deadline <= Instant::now() => {
+                Err(LoadError::DeadlineExceeded(self.id).into())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Runs `fut`, failing with a timeout error if it does not finish before the deadline.
+    pub async fn with_deadline<T, F: Future<Output = Result<T>>>(&self, fut: F) -> Result<T> {
+        match self.deadline {
+            Some(deadline) => tokio::time::timeout_at(deadline, fut)
+                .await
+                .map_err(|_| LoadError::DeadlineExceeded(self.id))?,
+            None => fut.await,
+        }
+    }
+
+    /// The number of blocks that can still be loaded with this context. The budget is shared
+    /// by all clones of the context, so it bounds the total work of a request.
+    /// If `None`, loading is not limited.
+    pub fn block_budget(&self) -> Option<usize> {
+        self.block_budget
+            .as_ref()
+            .map(|budget| budget.load(Ordering::SeqCst))
+    }
+
+    /// Sets the number of blocks that can be loaded with this context and its future clones.
+    pub fn set_block_budget(&mut self, block_budget: Option<usize>) {
+        self.block_budget = block_budget.map(|budget| Arc::new(AtomicUsize::new(budget)));
+    }
+
+    /// Takes one block load from the budget, returns an error if it is exhausted.
+    pub fn consume_block_budget(&self) -> Result<()> {
+        if let Some(budget) = &self.block_budget {
+            budget
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |budget| {
+                    budget.checked_sub(1)
+                })
+                .map_err(|_| anyhow!("{}: block budget exhausted", self.id))?;
+        }
+        Ok(())
+    }
+
+    /// The limits nodes loaded with this context are decoded with.
+    /// If `None`, nodes of any size are decoded.
+    pub fn decode_limits(&self) -> Option<&DecodeLimits> {
+        self.decode_limits.as_ref()
+    }
+
+    pub fn set_decode_limits(&mut self, decode_limits: Option<DecodeLimits>) {
+        self.decode_limits = decode_limits;
+    }
+
+    /// Sets the semaphore bounding the number of blocks loaded at once. It is usually shared
+    /// with other contexts, to bound the loads of all requests together.
+    pub fn set_load_limit(&mut self, load_limit: Option<Arc<Semaphore>>) {
+        self.load_limit = load_limit;
+    }
+
+    /// Waits until a block can be loaded, the load may go ahead while the returned permit is
+    /// held. Fails if the deadline passes while waiting.
+    pub async fn acquire_load(&self) -> Result<Option<OwnedSemaphorePermit>> {
+        match &self.load_limit {
+            Some(load_limit) => {
+                let permit = self
+                    .with_deadline(async {
+                        load_limit
+                            .clone()
+                            .acquire_owned()
+                            .await
+                            .map_err(|_| anyhow!("{}: load limit closed", self.id))
+                    })
+                    .await?;
+                Ok(Some(permit))
+            }
+            None => Ok(None),
+        }
+    }
 }
 
 impl Drop for LoaderContext {
@@ -355,6 +515,29 @@ impl From<ContextId> for u64 {
     }
 }
 
+/// Errors of loading content, which callers can tell apart by downcasting.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LoadError {
+    /// None of the sources has the block.
+    NotFound(Cid),
+    /// The deadline of the context passed, see [`LoaderContext::deadline`].
+    DeadlineExceeded(ContextId),
+    /// Reading from the local store failed, the cause is the error of the store.
+    Store,
+}
+
+impl Display for LoadError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::NotFound(cid) => write!(f, "failed to find {cid}"),
+            LoadError::DeadlineExceeded(id) => write!(f, "{id}: deadline exceeded"),
+            LoadError::Store => write!(f, "failed to read from store"),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
 #[derive(Debug)]
 pub struct InnerLoaderContext {
     closer: async_channel::Sender<ContextId>,
@@ -366,9 +549,9 @@ impl<S: BuildHasher + Clone + Send + Sync + 'static> ContentLoader for HashMap<C
         match self.get(cid) {
             Some(b) => Ok(LoadedCid {
                 data: b.clone(),
-                source: Source::Bitswap,
+                source: Source::Bitswap(None),
             }),
-            None => bail!("not found"),
+            None => Err(LoadError::NotFound(*cid).into()),
         }
     }
 
@@ -381,3 +564,61 @@ impl<S: BuildHasher + Clone + Send + Sync + 'static> ContentLoader for HashMap<C
         Ok(self.contains_key(cid))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_loader_context_block_budget() {
+        let (closer, _closer_r) = async_channel::bounded(1);
+        let mut ctx = LoaderContext::from_path(ContextId(0), closer);
+        assert_eq!(ctx.block_budget(), None);
+        ctx.consume_block_budget().unwrap();
+
+        ctx.set_block_budget(Some(2));
+        let clone = ctx.clone();
+        ctx.consume_block_budget().unwrap();
+        clone.consume_block_budget().unwrap();
+        assert_eq!(ctx.block_budget(), Some(0));
+        let err = clone.consume_block_budget().unwrap_err();
+        assert!(err.to_string().contains("block budget exhausted"));
+        assert_eq!(ctx.block_budget(), Some(0));
+    }
+
+    #[tokio::test]
+    async fn test_loader_context_load_limit() {
+        let (closer, _closer_r) = async_channel::bounded(1);
+        let mut ctx = LoaderContext::from_path(ContextId(0), closer);
+        assert!(ctx.acquire_load().await.unwrap().is_none());
+
+        let load_limit = Arc::new(Semaphore::new(1));
+        ctx.set_load_limit(Some(load_limit.clone()));
+        let clone = ctx.clone();
+        let permit = ctx.acquire_load().await.unwrap();
+        assert_eq!(load_limit.available_permits(), 0);
+
+        // queued loads give up at the deadline
+        ctx.set_deadline(Some(Instant::now() + Duration::from_millis(10)));
+        let err = ctx.acquire_load().await.unwrap_err();
+        assert!(err.to_string().contains("deadline exceeded"));
+
+        drop(permit);
+        assert!(clone.acquire_load().await.unwrap().is_some());
+        assert_eq!(load_limit.available_permits(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_loader_context_deadline() {
+        let (closer, _closer_r) = async_channel::bounded(1);
+        let mut ctx = LoaderContext::from_path(ContextId(0), closer);
+        ctx.check_deadline().unwrap();
+
+        ctx.set_deadline(Some(Instant::now() + Duration::from_millis(10)));
+        let res: Result<()> = ctx.with_deadline(futures::future::pending()).await;
+        assert!(res.unwrap_err().to_string().contains("deadline exceeded"));
+        assert!(ctx.check_deadline().is_err());
+    }
+}
@@ -1,6 +1,12 @@
-use anyhow::{ensure, Result};
-use futures::{stream::BoxStream, Stream, StreamExt};
+use std::sync::Arc;
+
+use anyhow::{ensure, Context, Result};
+use futures::{
+    stream::{self, BoxStream},
+    Stream, StreamExt, TryStreamExt,
+};
 use once_cell::sync::OnceCell;
+use tokio::sync::Semaphore;
 
 use crate::{
     content_loader::{ContentLoader, LoaderContext},
@@ -23,6 +29,9 @@ const MAX_DEPTH: usize = HASH_BIT_LENGTH;
 
 const DEFAULT_FANOUT: u32 = 256;
 
+/// Default number of sub-shards loaded concurrently when listing children.
+pub const DEFAULT_CHILDREN_CONCURRENCY: usize = 8;
+
 #[derive(Debug, PartialEq, Clone)]
 pub struct Hamt {
     root: Node,
@@ -75,12 +84,60 @@ impl Hamt {
         padding.len()
     }
 
+    /// Lists all entries, see [`Hamt::children_with_concurrency`].
     pub fn children<C: ContentLoader>(
         &self,
         ctx: LoaderContext,
         loader: C,
     ) -> impl Stream<Item = Result<Link>> + '_ {
-        self.root.children(ctx, loader)
+        self.children_with_concurrency(ctx, loader, DEFAULT_CHILDREN_CONCURRENCY)
+    }
+
+    /// Lists all entries, loading up to `concurrency` sub-shards at the same time.
+    ///
+    /// The limit is shared by all levels of the shard tree. The entries are yielded in
+    /// shard order, independent of the concurrency.
+    pub fn children_with_concurrency<C: ContentLoader>(
+        &self,
+        ctx: LoaderContext,
+        loader: C,
+        concurrency: usize,
+    ) -> impl Stream<Item = Result<Link>> + '_ {
+        self.root
+            .children(ctx, loader, ShardLoadLimit::new(concurrency.max(1)))
+    }
+
+    /// Lists the entries starting at `position`, each with its own position.
+    ///
+    /// The position of an entry is the index of the pointer leading to it on each level of
+    /// the shard tree, so it stays valid for the same hamt across calls. Only the shards on
+    /// the path to `position` and after it are loaded, one at a time, so taking a prefix of
+    /// the stream only loads what is needed for it. An empty `position` starts at the first
+    /// entry.
+    pub fn children_from<'a, C: ContentLoader>(
+        &'a self,
+        ctx: LoaderContext,
+        loader: C,
+        position: &'a [usize],
+    ) -> BoxStream<'a, Result<(Vec<usize>, Link)>> {
+        self.root.children_from(ctx, loader, Vec::new(), position)
+    }
+}
+
+/// Bounds how many sub-shards are loaded at the same time when listing children, shared
+/// by all levels of the shard tree.
+#[derive(Debug, Clone)]
+struct ShardLoadLimit {
+    concurrency: usize,
+    loads: Arc<Semaphore>,
+}
+
+impl ShardLoadLimit {
+    fn new(concurrency: usize) -> Self {
+        ShardLoadLimit {
+            concurrency,
+            loads: Arc::new(Semaphore::new(concurrency)),
+        }
     }
 }
 
@@ -91,8 +148,18 @@ impl InnerNode {
         loader: C,
     ) -> Result<Self> {
         let cid = link.cid;
+        ctx.consume_block_budget()?;
+        let permit = ctx.acquire_load().await?;
         let loaded_cid = loader.load_cid(&cid, &ctx).await?;
-        let node = UnixfsNode::decode(&cid, loaded_cid.data)?;
+        drop(permit);
+        // shards may come from untrusted peers, a corrupted one must not corrupt lookups
+        // a multihash we can not compute does not make the block corrupt
+        ensure!(
+            iroh_util::verify_hash(&cid, &loaded_cid.data) != Some(false),
+            "hamt block {} does not match its CID",
+            cid
+        );
+        let node = UnixfsNode::decode_with_limits(&cid, loaded_cid.data, ctx.decode_limits())?;
 
         match node {
             UnixfsNode::HamtShard(_, ref hamt) => Ok(InnerNode::Node {
@@ -114,11 +181,12 @@ impl InnerNode {
         &self,
         ctx: LoaderContext,
         loader: C,
+        limit: ShardLoadLimit,
     ) -> impl Stream<Item = Result<Link>> + '_ {
         async_stream::try_stream! {
             match self {
                 InnerNode::Node { node, .. } => {
-                    let mut children = node.children(ctx, loader);
+                    let mut children = node.children(ctx, loader, limit);
                     while let Some(link) = children.next().await {
                         let link = link?;
                         yield link;
@@ -133,7 +201,7 @@ impl InnerNode {
                         }
                     }
                     UnixfsNode::HamtShard(_, hamt) => {
-                        let mut children = hamt.children(ctx, loader);
+                        let mut children = hamt.root.children(ctx, loader, limit);
                         while let Some(link) = children.next().await {
                             let link = link?;
                             yield link;
@@ -144,6 +212,31 @@ impl InnerNode {
             }
         }
     }
+
+    fn children_from<'a, C: ContentLoader>(
+        &'a self,
+        ctx: LoaderContext,
+        loader: C,
+        prefix: Vec<usize>,
+        position: &'a [usize],
+    ) -> BoxStream<'a, Result<(Vec<usize>, Link)>> {
+        match self {
+            InnerNode::Node { node, .. } => node.children_from(ctx, loader, prefix, position),
+            InnerNode::Leaf {
+                value: value @ UnixfsNode::Directory(_),
+                ..
+            } => {
+                let start = position.first().copied().unwrap_or_default();
+                let links = value.links().enumerate().skip(start).map(move |(i, link)| {
+                    let mut position = prefix.clone();
+                    position.push(i);
+                    Ok((position, link?.to_owned()))
+                });
+                stream::iter(links).boxed()
+            }
+            InnerNode::Leaf { .. } => stream::empty().boxed(),
+        }
+    }
 }
 
 impl Node {
@@ -205,6 +298,7 @@ impl Node {
         depth: usize,
     ) -> Result<Option<(&Link, &UnixfsNode)>> {
         ensure!(depth < MAX_DEPTH, "max depth reached");
+        ctx.check_deadline()?;
         let idx = hashed_key.next(self.bit_width)?;
         if !self.bitfield.test_bit(idx) {
             return Ok(None);
@@ -212,7 +306,10 @@ impl Node {
 
         let cindex = self.index_for_bit_pos(idx);
         let child = self.get_child(cindex);
-        let cached_node = self.load_child(ctx.clone(), loader.clone(), child).await?;
+        let cached_node = ctx
+            .with_deadline(self.load_child(ctx.clone(), loader.clone(), child))
+            .await
+            .context("hamt: failed to load shard")?;
         match cached_node {
             InnerNode::Node { node, value } => {
                 let name = child
@@ -268,28 +365,94 @@ impl Node {
         &self.pointers[i]
     }
 
-    fn children<C: ContentLoader>(&self, ctx: LoaderContext, loader: C) -> BoxStream<Result<Link>> {
+    /// Lists all entries of this node, descending into sub-shards.
+    ///
+    /// Sub-shards are loaded concurrently within `limit`, while the order of the entries is
+    /// preserved.
+    fn children<C: ContentLoader>(
+        &self,
+        ctx: LoaderContext,
+        loader: C,
+        limit: ShardLoadLimit,
+    ) -> BoxStream<Result<Link>> {
+        let concurrency = limit.concurrency;
+        stream::iter(&self.pointers)
+            .filter(|pointer| futures::future::ready(pointer.link.name.is_some()))
+            .map(move |pointer| {
+                self.pointer_children(ctx.clone(), loader.clone(), pointer, limit.clone())
+            })
+            .buffered(concurrency)
+            .try_flatten()
+            .boxed()
+    }
+
+    /// Returns the entry `pointer` refers to, or the entries of the sub-shard it points to.
+    async fn pointer_children<'a, C: ContentLoader>(
+        &'a self,
+        ctx: LoaderContext,
+        loader: C,
+        pointer: &'a NodeLink,
+        limit: ShardLoadLimit,
+    ) -> Result<BoxStream<'a, Result<Link>>> {
+        let padding_len = self.padding_len;
+        let name = pointer.link.name.as_deref().unwrap_or_default();
+        if name.len() > padding_len {
+            let name = std::str::from_utf8(&name.as_bytes()[padding_len..])?;
+            let link = Link {
+                cid: pointer.link.cid,
+                name: Some(name.to_string()),
+                tsize: pointer.link.tsize,
+            };
+            Ok(stream::once(async move { Ok(link) }).boxed())
+        } else {
+            // recurse, only the load itself counts against the limit
+            let permit = limit.loads.acquire().await?;
+            let child = self
+                .load_child(ctx.clone(), loader.clone(), pointer)
+                .await?;
+            drop(permit);
+            Ok(child.children(ctx, loader, limit).boxed())
+        }
+    }
+
+    /// Lists the entries of this node starting at `position`, see [`Hamt::children_from`].
+    fn children_from<'a, C: ContentLoader>(
+        &'a self,
+        ctx: LoaderContext,
+        loader: C,
+        prefix: Vec<usize>,
+        position: &'a [usize],
+    ) -> BoxStream<'a, Result<(Vec<usize>, Link)>> {
         async_stream::try_stream! {
-            let padding_len = self.padding_len;
-            for pointer in &self.pointers {
-                if let Some(ref name) = pointer.link.name {
-                    if name.len() > padding_len {
-                        yield Link {
-                            cid: pointer.link.cid,
-                            name: pointer.link.name.as_ref().map(|n| {
-                                std::str::from_utf8(&n.as_bytes()[padding_len..]).unwrap().to_string()
-                            }),
-                            tsize: pointer.link.tsize,
-                        };
+            let start = position.first().copied().unwrap_or_default();
+            for (i, pointer) in self.pointers.iter().enumerate().skip(start) {
+                let name = match pointer.link.name.as_deref() {
+                    Some(name) => name,
+                    None => continue,
+                };
+                let mut child_position = prefix.clone();
+                child_position.push(i);
+                if name.len() > self.padding_len {
+                    let name = std::str::from_utf8(&name.as_bytes()[self.padding_len..])?;
+                    let link = Link {
+                        cid: pointer.link.cid,
+                        name: Some(name.to_string()),
+                        tsize: pointer.link.tsize,
+                    };
+                    yield (child_position, link);
+                } else {
+                    // only the sub-shard `position` points into is entered in the middle
+                    let rest = if i == start && position.len() > 1 {
+                        &position[1..]
                     } else {
-                        // recurse
-                        let child = self.load_child(ctx.clone(), loader.clone(), pointer).await?;
-                        let children = child.children(ctx.clone(), loader.clone());
-                        tokio::pin!(children);
-                        while let Some(link) = children.next().await {
-                            let link = link?;
-                            yield link;
-                        }
+                        &[][..]
+                    };
+                    let child = self.load_child(ctx.clone(), loader.clone(), pointer).await?;
+                    let mut children =
+                        child.children_from(ctx.clone(), loader.clone(), child_position, rest);
+                    while let Some(child) = children.next().await {
+                        let child = child?;
+                        yield child;
                     }
                 }
             }
@@ -331,4 +494,126 @@ mod tests {
             [7, 193, 130, 130, 92, 180, 71, 225]
         );
     }
+
+    #[tokio::test]
+    async fn test_children_concurrency_limit() {
+        use crate::builder::{DirectoryBuilder, FileBuilder};
+        use crate::content_loader::{ContextId, LoadedCid};
+        use bytes::Bytes;
+        use cid::Cid;
+        use std::collections::HashMap;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::time::Duration;
+
+        /// Counts how many blocks are loaded at the same time.
+        #[derive(Debug, Clone, Default)]
+        struct Tracked {
+            blocks: Arc<HashMap<Cid, Bytes>>,
+            active: Arc<AtomicUsize>,
+            max_active: Arc<AtomicUsize>,
+        }
+
+        #[async_trait::async_trait]
+        impl ContentLoader for Tracked {
+            async fn load_cid(&self, cid: &Cid, ctx: &LoaderContext) -> Result<LoadedCid> {
+                let active = self.active.fetch_add(1, Ordering::SeqCst) + 1;
+                self.max_active.fetch_max(active, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(1)).await;
+                let res = self.blocks.load_cid(cid, ctx).await;
+                self.active.fetch_sub(1, Ordering::SeqCst);
+                res
+            }
+
+            async fn stop_session(&self, _ctx: ContextId) -> Result<()> {
+                Ok(())
+            }
+
+            async fn has_cid(&self, cid: &Cid) -> Result<bool> {
+                Ok(self.blocks.contains_key(cid))
+            }
+        }
+
+        // enough entries for sub-shards below sub-shards
+        let mut dir = DirectoryBuilder::new().name("root").hamt();
+        for i in 0..2000u32 {
+            let file = FileBuilder::new()
+                .name(format!("{i}.txt"))
+                .content_bytes(i.to_be_bytes().to_vec())
+                .build()
+                .await
+                .unwrap();
+            dir = dir.add_file(file);
+        }
+        let blocks: Vec<_> = dir
+            .build()
+            .await
+            .unwrap()
+            .encode()
+            .try_collect()
+            .await
+            .unwrap();
+        let root = blocks.last().unwrap().clone();
+        let loader = Tracked {
+            blocks: Arc::new(
+                blocks
+                    .into_iter()
+                    .map(|block| (*block.cid(), block.data().clone()))
+                    .collect(),
+            ),
+            ..Default::default()
+        };
+        let hamt = match UnixfsNode::decode(root.cid(), root.data().clone()).unwrap() {
+            UnixfsNode::HamtShard(_, hamt) => hamt,
+            node => panic!("unexpected root {:?}", node.typ()),
+        };
+
+        let (closer, _closer_r) = async_channel::bounded(1);
+        let ctx = LoaderContext::from_path(ContextId(0), closer);
+        let links: Vec<_> = hamt
+            .children_with_concurrency(ctx, loader.clone(), 4)
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(links.len(), 2000);
+        assert!(loader.max_active.load(Ordering::SeqCst) <= 4);
+    }
+
+    #[tokio::test]
+    async fn test_load_from_link_verifies_hash() {
+        use crate::codecs::Codec;
+        use crate::content_loader::ContextId;
+        use bytes::Bytes;
+        use cid::multihash::{Code, MultihashDigest};
+        use cid::Cid;
+        use std::collections::HashMap;
+
+        let (closer, _closer_r) = async_channel::bounded(1);
+        let ctx = LoaderContext::from_path(ContextId(0), closer);
+        let cid = Cid::new_v1(Codec::Raw as _, Code::Sha2_256.digest(b"hello"));
+        let link = Link {
+            cid,
+            name: Some("hello.txt".into()),
+            tsize: None,
+        };
+
+        let loader: HashMap<Cid, Bytes> = [(cid, Bytes::from_static(b"hello"))].into();
+        InnerNode::load_from_link(ctx.clone(), &link, loader)
+            .await
+            .unwrap();
+
+        let loader: HashMap<Cid, Bytes> = [(cid, Bytes::from_static(b"tampered"))].into();
+        let err = InnerNode::load_from_link(ctx.clone(), &link, loader)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("does not match its CID"));
+
+        // blocks with an unsupported multihash can not be checked, and are loaded
+        let hash = cid::multihash::Multihash::wrap(0x3f_ffff, &[0; 32]).unwrap();
+        let link = Link {
+            cid: Cid::new_v1(Codec::Raw as _, hash),
+            ..link
+        };
+        let loader: HashMap<Cid, Bytes> = [(link.cid, Bytes::from_static(b"hello"))].into();
+        InnerNode::load_from_link(ctx, &link, loader).await.unwrap();
+    }
 }
@@ -4,6 +4,7 @@ use anyhow::{anyhow, Result};
 use bytes::Bytes;
 use cid::Cid;
 use libipld::error::{InvalidMultihash, UnsupportedMultihash};
+use libp2p::PeerId;
 use multihash::{Code, MultihashDigest};
 
 use crate::{codecs::Codec, parse_links, unixfs::dag_pb};
@@ -16,7 +17,8 @@ pub struct LoadedCid {
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Source {
-    Bitswap,
+    /// Fetched via bitswap, from the given peer if it is known.
+    Bitswap(Option<PeerId>),
     Http(String),
     Store(&'static str),
 }
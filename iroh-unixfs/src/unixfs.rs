@@ -1,6 +1,6 @@
 use std::{
-    collections::VecDeque,
-    fmt::Debug,
+    collections::{BTreeMap, VecDeque},
+    fmt::{self, Debug, Display, Formatter},
     pin::Pin,
     task::{Context, Poll},
 };
@@ -146,13 +146,114 @@ impl Node {
     pub fn fanout(&self) -> Option<u32> {
         self.inner.fanout.and_then(|f| u32::try_from(f).ok())
     }
+
+    /// Returns the application specific metadata attached to this node.
+    pub fn custom_metadata(&self) -> &BTreeMap<String, String> {
+        &self.inner.custom_metadata
+    }
+}
+
+/// Limits on the size of nodes, enforced by [`UnixfsNode::decode_with_limits`].
+///
+/// Blocks fetched from the network are untrusted, these bound what a single crafted block
+/// can make us allocate when decoding and traversing it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecodeLimits {
+    /// The maximum number of links of a dag-pb node.
+    pub max_links: usize,
+    /// The maximum size of the data field of a dag-pb node, or of a whole raw block.
+    pub max_data_bytes: usize,
+}
+
+impl DecodeLimits {
+    fn check(&self, cid: &Cid, what: &'static str, len: usize, limit: usize) -> Result<()> {
+        if len > limit {
+            return Err(DecodeLimitExceeded {
+                cid: *cid,
+                what,
+                len,
+                limit,
+            }
+            .into());
+        }
+        Ok(())
+    }
+}
+
+/// The error returned when a node exceeds the [`DecodeLimits`] it is decoded with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecodeLimitExceeded {
+    pub cid: Cid,
+    /// What was limited, either `"links"` or `"data bytes"`.
+    pub what: &'static str,
+    pub len: usize,
+    pub limit: usize,
+}
+
+impl Display for DecodeLimitExceeded {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "node {} has {} {}, exceeding the limit of {}",
+            self.cid, self.len, self.what, self.limit
+        )
+    }
+}
+
+impl std::error::Error for DecodeLimitExceeded {}
+
+/// Counts the links of an encoded dag-pb node and measures its data, without decoding it.
+fn scan_pb_node(mut buf: &[u8]) -> Result<(usize, usize)> {
+    use prost::encoding::{decode_key, decode_varint, WireType};
+
+    let mut links = 0;
+    let mut data_len = 0;
+    while !buf.is_empty() {
+        // both fields of a dag-pb node, `Data` (1) and `Links` (2), are length delimited
+        let (tag, wire_type) = decode_key(&mut buf)?;
+        ensure!(
+            wire_type == WireType::LengthDelimited,
+            "invalid dag-pb field {}",
+            tag
+        );
+        let len = decode_varint(&mut buf)?;
+        ensure!(len <= buf.len() as u64, "truncated dag-pb node");
+        match tag {
+            1 => data_len = len as usize,
+            2 => links += 1,
+            _ => {}
+        }
+        buf.advance(len as usize);
+    }
+    Ok((links, data_len))
 }
 
 impl UnixfsNode {
     pub fn decode(cid: &Cid, buf: Bytes) -> Result<Self> {
+        Self::decode_with_limits(cid, buf, None)
+    }
+
+    /// Decodes a node, failing with [`DecodeLimitExceeded`] if it exceeds `limits`.
+    ///
+    /// The limits are checked on the encoded node, before anything is decoded.
+    pub fn decode_with_limits(
+        cid: &Cid,
+        buf: Bytes,
+        limits: Option<&DecodeLimits>,
+    ) -> Result<Self> {
         match cid.codec() {
-            c if c == Codec::Raw as u64 => Ok(UnixfsNode::Raw(buf)),
+            c if c == Codec::Raw as u64 => {
+                if let Some(limits) = limits {
+                    limits.check(cid, "data bytes", buf.len(), limits.max_data_bytes)?;
+                }
+                Ok(UnixfsNode::Raw(buf))
+            }
             _ => {
+                if let Some(limits) = limits {
+                    let (links, data_len) = scan_pb_node(&buf)?;
+                    limits.check(cid, "links", links, limits.max_links)?;
+                    limits.check(cid, "data bytes", data_len, limits.max_data_bytes)?;
+                }
                 let outer = dag_pb::PbNode::decode(buf)?;
                 let inner_data = outer
                     .data
@@ -264,6 +365,19 @@ impl UnixfsNode {
         }
     }
 
+    /// Returns the application specific metadata attached to this node.
+    /// Raw nodes can not carry any metadata.
+    pub fn custom_metadata(&self) -> Option<&BTreeMap<String, String>> {
+        match self {
+            UnixfsNode::Raw(_) => None,
+            UnixfsNode::Directory(node)
+            | UnixfsNode::RawNode(node)
+            | UnixfsNode::File(node)
+            | UnixfsNode::Symlink(node)
+            | UnixfsNode::HamtShard(node, _) => Some(node.custom_metadata()),
+        }
+    }
+
     pub fn links(&self) -> Links<'_> {
         match self {
             UnixfsNode::Raw(_) => Links::Raw,
@@ -334,6 +448,35 @@ impl UnixfsNode {
         }
     }
 
+    /// If this is a directory or hamt shard, returns a stream that yields its children
+    /// starting at `position`, each with its own position.
+    ///
+    /// Positions of directory entries are their index, see [`Hamt::children_from`] for
+    /// those of hamt shards.
+    pub fn children_from<'a, C: ContentLoader>(
+        &'a self,
+        ctx: LoaderContext,
+        loader: C,
+        position: &'a [usize],
+    ) -> Option<BoxStream<'a, Result<(Vec<usize>, Link)>>> {
+        match self {
+            UnixfsNode::Raw(_)
+            | UnixfsNode::RawNode(_)
+            | UnixfsNode::File(_)
+            | UnixfsNode::Symlink(_) => None,
+            UnixfsNode::Directory(_) => {
+                let start = position.first().copied().unwrap_or_default();
+                let links = self
+                    .links()
+                    .enumerate()
+                    .skip(start)
+                    .map(|(i, link)| Ok((vec![i], link?.to_owned())));
+                Some(futures::stream::iter(links).boxed())
+            }
+            UnixfsNode::HamtShard(_, hamt) => Some(hamt.children_from(ctx, loader, position)),
+        }
+    }
+
     pub fn into_content_reader<C: ContentLoader>(
         self,
         ctx: LoaderContext,
@@ -669,8 +812,10 @@ fn load_next_node<C: ContentLoader + 'static>(
 
     let fut = async move {
         let ctx = ctx.lock().await;
+        let permit = ctx.acquire_load().await?;
         let loaded_cid = loader.load_cid(&link.cid, &ctx).await?;
-        let node = UnixfsNode::decode(&link.cid, loaded_cid.data)?;
+        drop(permit);
+        let node = UnixfsNode::decode_with_limits(&link.cid, loaded_cid.data, ctx.decode_limits())?;
 
         Ok(node)
     }
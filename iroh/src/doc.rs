@@ -36,6 +36,20 @@ little nicer for humans by getting just the cat picture:
 
   > iroh get /ipfs/bafybeihjgu5w6wbbxqevdgccj5xm453dbzpkwmkyoepvs3vh6wft4uvf2q/cat.jpg cat.jpg
 
+With --no-wrap the CID refers to the file itself instead of a directory holding
+it. UnixFS only stores names in the entries of directories, so the name of the
+file is lost, unless --keep-name stores it in the metadata of the file.
+
+With --verbose, the CID and path of every added file and directory is printed to
+stderr as soon as it is imported. The root is always printed to stdout last.
+
+Files are split into chunks of 256KiB by default. With --chunker rabin, chunks
+are cut at boundaries defined by the content instead, so inserting bytes into a
+file only changes the blocks around the insertion, and versions of a file share
+most of their blocks. The chunker is part of the CID: the same file added with
+different chunkers gets different CIDs and shares no blocks. The rabin chunker
+uses the same parameters as 'ipfs add --chunker=rabin' in go-ipfs.
+
 The stored result of add is a 'MerkleDAG'. Merkle proofs (hashes) are a fast
 method of proving and checking data inclusion, and the tree formed by chunking
 the input into blocks is always a directed acyclic graph (DAG). These MerkleDAGs
@@ -132,6 +146,20 @@ reporting, which is intended to be consumed by tools like prometheus and
 grafana. For more info on metrics collection, see
 https://iroh.computer/docs/metrics";
 
+pub const DOCTOR_LONG_DESCRIPTION: &str = "
+doctor runs a series of checks against the running iroh services and reports
+pass or fail for each of them:
+
+  rpc:          Each service responds to health checks.
+  p2p:          The node listens on at least one address, is reachable from
+                the outside and is connected to peers.
+  dht:          The routing table of the DHT is not empty.
+  bitswap:      At least one connected peer speaks bitswap.
+  store:        A small block can be written to, read back from and removed
+                from the store.
+
+The command fails if any of the checks failed.";
+
 pub const GET_LONG_DESCRIPTION: &str = "
 Download file or directory specified by <ipfs-path> from IPFS into [path]. If
 path already exists and is a file then it's overwritten with the new downloaded
@@ -144,7 +172,50 @@ directory name can be derived from the <ipfs-path>, the output will be written
 to the given path's CID.
 
 If <ipfs-path> is already present in the iroh store, no network call will
-be made.";
+be made. Use --max-providers to limit how many providers are queried for each
+block fetched from the network.
+
+By default, output is written while it is being fetched, so a failed download
+can leave partial output behind. With --verify-complete all blocks are fetched,
+checked against their CIDs and stored before any output is written.
+
+Output is written to a staging path first, and moved to the output path once
+complete. By default it is staged next to the output path, use --staging-dir or
+the staging_dir config option to stage it elsewhere. A staging directory on a
+different filesystem than the output path is ignored, as the final move would
+no longer be atomic.
+
+With --best-effort, entries of a directory that can not be fetched are skipped
+instead of failing the download, and listed once it completes. Files are only
+written once all of their blocks were fetched, and the partial tree is still
+moved into place as a whole. It can not be combined with --verify-complete.
+
+Written files get the permissions of the process umask. Use --mode to set them
+explicitly, e.g. --mode 600 for content only the current user may read.";
+
+pub const STORE_VERIFY_LONG_DESCRIPTION: &str = "
+'store verify' checks that the data of stored blocks hashes to their CIDs, to
+catch silent corruption, e.g. after an unclean shutdown, before it causes
+failures to resolve content. Use --sample-rate to only verify a random fraction
+of the blocks, e.g. --sample-rate 0.1 for a tenth of them.
+
+Corrupt blocks are listed as they are found. With --quarantine their data is
+removed as well, so they are fetched from the network again when needed.
+
+A full scan of a large store takes a while. It can be interrupted at any time,
+and continued later with the --resume-after value it last showed.
+
+The store server can also verify a sample of its blocks whenever it starts, see
+the verify_on_start store config option.";
+
+pub const PIN_UPDATE_LONG_DESCRIPTION: &str = "
+'pin update' moves a pin from an old root to a new one, e.g. after publishing a
+new version of a directory, so the blocks only used by the old version can be
+collected. Both pins change at once, so there is no moment in which both or
+neither of the roots are pinned.
+
+The new root must be completely stored, fetch it first if needed. Blocks shared
+by both versions are kept either way.";
 
 pub const P2P_CONNECT_LONG_DESCRIPTION: &str = "
 Attempts to open a new direct connection to a peer address. By default p2p
@@ -197,5 +268,8 @@ for additional details about the peer. For example:
   > iroh p2p lookup QmaCpDMGvV2BGHeYERUEnRQAwe3N8SzbUtfsmvsqQLuvuJ
   > iroh p2p lookup /ip4/104.131.131.82/tcp/4001/p2p/QmaCpDMGvV2BGHeYERUEnRQAwe3N8SzbUtfsmvsqQLuvuJ
 
+With --verbose, the agent version, latency, bitswap state and protocols of each
+peer are listed as well.
+
 For more info on multiaddrs see https://iroh.computer/docs/concepts#multiaddr.
 ";
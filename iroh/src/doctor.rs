@@ -0,0 +1,197 @@
+//! Diagnostic checks of a running iroh system, see `iroh doctor`.
+use std::fmt::Display;
+
+use anyhow::{bail, Result};
+use crossterm::style::Stylize;
+use iroh_api::{Api, BitswapPeerState, Multiaddr, PeerId, RelayState, StatusType};
+
+/// The outcome of a single diagnostic check.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Check {
+    pub name: String,
+    /// Details about the check, on success or failure.
+    pub result: std::result::Result<String, String>,
+}
+
+impl Check {
+    fn new(name: impl Into<String>, result: std::result::Result<String, String>) -> Self {
+        Check {
+            name: name.into(),
+            result,
+        }
+    }
+
+    pub fn passed(&self) -> bool {
+        self.result.is_ok()
+    }
+}
+
+/// Runs all checks and prints the results, failing if any check failed.
+pub async fn run(api: &Api) -> Result<()> {
+    let checks = checks(api).await;
+    for check in &checks {
+        println!("{}", format_check(check));
+    }
+
+    let failed = checks.iter().filter(|c| !c.passed()).count();
+    if failed > 0 {
+        bail!("{} of {} checks failed", failed, checks.len());
+    }
+    println!("All checks passed");
+    Ok(())
+}
+
+/// Runs all checks, in order: rpc connectivity, p2p networking, the DHT, bitswap and the
+/// store.
+pub async fn checks(api: &Api) -> Vec<Check> {
+    let mut checks = Vec::new();
+
+    for service in api.check().await.iter() {
+        let result = match service.status() {
+            StatusType::Serving => Ok(format!("serving, version {}", service.version())),
+            status => Err(format!("{status:?}")),
+        };
+        checks.push(Check::new(format!("rpc {}", service.name()), result));
+    }
+
+    match api.p2p() {
+        Ok(p2p) => {
+            checks.push(Check::new(
+                "p2p listening addresses",
+                non_empty(p2p.listeners().await, "not listening on any address"),
+            ));
+            checks.push(Check::new(
+                "p2p external reachability",
+                non_empty(
+                    p2p.external_addresses().await,
+                    "no address confirmed as reachable from the outside",
+                ),
+            ));
+            checks.push(Check::new(
+                "p2p peers",
+                count(
+                    p2p.peers().await.map(|peers| peers.len()),
+                    "connected",
+                    "not connected to any peers",
+                ),
+            ));
+            if let Ok(relays) = p2p.relays().await {
+                for (relay, state) in relays {
+                    checks.push(relay_check(relay, state));
+                }
+            }
+            checks.push(Check::new(
+                "dht routing table",
+                count(
+                    p2p.routing_table_size().await,
+                    "peers",
+                    "the routing table is empty",
+                ),
+            ));
+            let bitswap_peers = p2p.peers_info().await.map(|peers| {
+                peers
+                    .iter()
+                    .filter(|peer| peer.bitswap_state == Some(BitswapPeerState::Responsive))
+                    .count()
+            });
+            checks.push(Check::new(
+                "bitswap peers",
+                count(
+                    bitswap_peers,
+                    "responsive",
+                    "no connected peer speaks bitswap",
+                ),
+            ));
+        }
+        Err(err) => checks.push(Check::new("p2p", Err(err.to_string()))),
+    }
+
+    let store = api
+        .check_store()
+        .await
+        .map(|_| "writable".to_string())
+        .map_err(|e| e.to_string());
+    checks.push(Check::new("store", store));
+
+    checks
+}
+
+fn non_empty<E: Display>(
+    addrs: std::result::Result<Vec<Multiaddr>, E>,
+    empty: &str,
+) -> std::result::Result<String, String> {
+    match addrs {
+        Ok(addrs) if addrs.is_empty() => Err(empty.to_string()),
+        Ok(addrs) => Ok(addrs
+            .iter()
+            .map(|a| a.to_string())
+            .collect::<Vec<_>>()
+            .join(", ")),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+/// Passes with `n {what}` for a positive count, fails with `none` for zero.
+fn count<E: Display>(
+    n: std::result::Result<usize, E>,
+    what: &str,
+    none: &str,
+) -> std::result::Result<String, String> {
+    match n {
+        Ok(0) => Err(none.to_string()),
+        Ok(n) => Ok(format!("{n} {what}")),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+fn relay_check(relay: PeerId, state: RelayState) -> Check {
+    let result = match state {
+        RelayState::Reserved => Ok("reserved".to_string()),
+        RelayState::Pending => Ok("waiting for reservation".to_string()),
+        RelayState::Backoff { failures, retry_in } => Err(format!(
+            "lost, {} failed attempts, retrying in {}s",
+            failures,
+            retry_in.as_secs()
+        )),
+    };
+    Check::new(format!("p2p relay {relay}"), result)
+}
+
+fn format_check(check: &Check) -> String {
+    match &check.result {
+        Ok(details) => format!("{}\t{}\t{}", "pass".green(), check.name, details),
+        Err(details) => format!("{}\t{}\t{}", "fail".red(), check.name, details),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_non_empty() {
+        let addr: Multiaddr = "/ip4/127.0.0.1/tcp/4444".parse().unwrap();
+        assert_eq!(
+            non_empty::<String>(Ok(vec![addr.clone(), addr]), "empty"),
+            Ok("/ip4/127.0.0.1/tcp/4444, /ip4/127.0.0.1/tcp/4444".to_string())
+        );
+        assert_eq!(
+            non_empty::<String>(Ok(vec![]), "empty"),
+            Err("empty".to_string())
+        );
+        assert_eq!(non_empty(Err("boom"), "empty"), Err("boom".to_string()));
+    }
+
+    #[test]
+    fn test_count() {
+        assert_eq!(
+            count::<String>(Ok(3), "peers", "none"),
+            Ok("3 peers".to_string())
+        );
+        assert_eq!(
+            count::<String>(Ok(0), "peers", "none"),
+            Err("none".to_string())
+        );
+        assert_eq!(count(Err("boom"), "peers", "none"), Err("boom".to_string()));
+    }
+}
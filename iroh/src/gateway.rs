@@ -0,0 +1,272 @@
+//! Maps HTTP gateway style requests onto the [`Resolver`].
+//!
+//! This does not implement a server, it only turns a request path like
+//! `/ipfs/<cid>/sub/file` into the shape of the response, so it can be wired into
+//! any HTTP framework.
+use std::fmt::{self, Debug, Write as _};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use anyhow::Result;
+use futures::TryStreamExt;
+use iroh_resolver::resolver::{LinkNotFound, Out, OutPrettyReader, Path, Resolver};
+use iroh_unixfs::{
+    content_loader::{ContentLoader, LoadError},
+    Link,
+};
+
+/// The file served instead of a directory listing, if present in the directory.
+pub const INDEX_FILE: &str = "index.html";
+
+/// Renders the listing of a directory, that does not contain an [`INDEX_FILE`].
+pub trait DirectoryRenderer: Debug + Send + Sync {
+    /// Returns the body for the directory at `path`, containing `links`.
+    fn render(&self, path: &Path, links: &[Link]) -> String;
+}
+
+/// Renders a directory as a minimal html page.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct HtmlDirectoryRenderer;
+
+impl DirectoryRenderer for HtmlDirectoryRenderer {
+    fn render(&self, path: &Path, links: &[Link]) -> String {
+        let title = escape_html(&path.to_string());
+        let mut out = String::new();
+        let _ = writeln!(out, "<!DOCTYPE html>\n<html>");
+        let _ = writeln!(out, "<head><title>{title}</title></head>");
+        let _ = writeln!(out, "<body>\n<h1>{title}</h1>\n<ul>");
+        for link in links {
+            let name = link.name.clone().unwrap_or_else(|| link.cid.to_string());
+            let name = escape_html(&name);
+            let _ = writeln!(out, "<li><a href=\"{name}\">{name}</a></li>");
+        }
+        out.push_str("</ul>\n</body>\n</html>\n");
+        out
+    }
+}
+
+fn escape_html(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// The shape of the response to a gateway request.
+pub enum GatewayResponse<T: ContentLoader> {
+    /// `200 OK`, with the content of a file.
+    Content {
+        reader: OutPrettyReader<T>,
+        size: Option<u64>,
+    },
+    /// `200 OK`, with a rendered directory listing.
+    DirectoryListing(String),
+    /// `301 Moved Permanently`, to the given location.
+    Redirect(String),
+    /// `400 Bad Request`, the request path could not be parsed.
+    BadRequest(String),
+    /// `404 Not Found`, some component of the path does not exist.
+    NotFound(String),
+}
+
+impl<T: ContentLoader> GatewayResponse<T> {
+    /// The matching HTTP status code.
+    pub fn status_code(&self) -> u16 {
+        match self {
+            GatewayResponse::Content { .. } | GatewayResponse::DirectoryListing(_) => 200,
+            GatewayResponse::Redirect(_) => 301,
+            GatewayResponse::BadRequest(_) => 400,
+            GatewayResponse::NotFound(_) => 404,
+        }
+    }
+}
+
+impl<T: ContentLoader> Debug for GatewayResponse<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Content { size, .. } => f.debug_struct("Content").field("size", size).finish(),
+            Self::DirectoryListing(_) => write!(f, "DirectoryListing"),
+            Self::Redirect(location) => f.debug_tuple("Redirect").field(location).finish(),
+            Self::BadRequest(msg) => f.debug_tuple("BadRequest").field(msg).finish(),
+            Self::NotFound(msg) => f.debug_tuple("NotFound").field(msg).finish(),
+        }
+    }
+}
+
+/// Resolves gateway request paths into [`GatewayResponse`]s.
+#[derive(Debug, Clone)]
+pub struct Gateway<T: ContentLoader> {
+    resolver: Resolver<T>,
+    renderer: Option<Arc<dyn DirectoryRenderer>>,
+}
+
+impl<T: ContentLoader> Gateway<T> {
+    /// Creates a new gateway, rendering directory listings with [`HtmlDirectoryRenderer`].
+    pub fn new(resolver: Resolver<T>) -> Self {
+        Gateway {
+            resolver,
+            renderer: Some(Arc::new(HtmlDirectoryRenderer)),
+        }
+    }
+
+    /// Sets the directory listing renderer.
+    ///
+    /// If `None`, directories without an [`INDEX_FILE`] respond with `404 Not Found`.
+    pub fn with_renderer(mut self, renderer: Option<Arc<dyn DirectoryRenderer>>) -> Self {
+        self.renderer = renderer;
+        self
+    }
+
+    /// Resolves a request path, like `/ipfs/<cid>/sub/file`.
+    ///
+    /// Errors are only returned for failures other than missing content.
+    pub async fn get(&self, request_path: &str) -> Result<GatewayResponse<T>> {
+        let path = match Path::from_str(request_path) {
+            Ok(path) => path,
+            Err(err) => return Ok(GatewayResponse::BadRequest(err.to_string())),
+        };
+        let out = match self.resolve(path.clone()).await? {
+            Ok(out) => out,
+            Err(response) => return Ok(response),
+        };
+
+        if !out.is_dir() {
+            return self.content(out);
+        }
+
+        // Directories are always served with a trailing slash, so relative links work.
+        if !path.has_trailing_slash() {
+            return Ok(GatewayResponse::Redirect(format!("{path}/")));
+        }
+
+        let links: Vec<Link> = out
+            .unixfs_read_dir(&self.resolver, Default::default())?
+            .expect("already know this is a directory")
+            .try_collect()
+            .await?;
+
+        if links.iter().any(|l| l.name.as_deref() == Some(INDEX_FILE)) {
+            let mut index_path = path;
+            index_path.push(INDEX_FILE);
+            return match self.resolve(index_path).await? {
+                Ok(out) => self.content(out),
+                Err(response) => Ok(response),
+            };
+        }
+
+        match self.renderer {
+            Some(ref renderer) => Ok(GatewayResponse::DirectoryListing(
+                renderer.render(&path, &links),
+            )),
+            None => Ok(GatewayResponse::NotFound(format!(
+                "{path} has no {INDEX_FILE}"
+            ))),
+        }
+    }
+
+    async fn resolve(&self, path: Path) -> Result<std::result::Result<Out, GatewayResponse<T>>> {
+        match self.resolver.resolve(path).await {
+            Ok(out) => Ok(Ok(out)),
+            Err(err)
+                if err.downcast_ref::<LinkNotFound>().is_some()
+                    || matches!(
+                        err.downcast_ref::<LoadError>(),
+                        Some(LoadError::NotFound(_))
+                    ) =>
+            {
+                Ok(Err(GatewayResponse::NotFound(err.to_string())))
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    fn content(&self, out: Out) -> Result<GatewayResponse<T>> {
+        let reader = out.pretty(self.resolver.clone(), Default::default(), None)?;
+        let size = reader.size();
+        Ok(GatewayResponse::Content { reader, size })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use iroh_resolver::resolver::stream_to_resolver;
+    use iroh_unixfs::builder::{DirectoryBuilder, FileBuilder};
+
+    async fn gateway(with_index: bool) -> Result<(String, Gateway<impl ContentLoader>)> {
+        let file = FileBuilder::new()
+            .name("hello.txt")
+            .content_bytes(&b"hello world"[..])
+            .build()
+            .await?;
+        let mut dir = DirectoryBuilder::new().name("foo").add_file(file);
+        if with_index {
+            let index = FileBuilder::new()
+                .name(INDEX_FILE)
+                .content_bytes(&b"<h1>index</h1>"[..])
+                .build()
+                .await?;
+            dir = dir.add_file(index);
+        }
+        let dir = dir.build().await?;
+        let (root, resolver) = stream_to_resolver(dir.encode()).await?;
+        Ok((format!("/ipfs/{root}"), Gateway::new(resolver)))
+    }
+
+    #[tokio::test]
+    async fn test_gateway_get() -> Result<()> {
+        let (root, gateway) = gateway(false).await?;
+
+        let res = gateway.get(&format!("{root}/hello.txt")).await?;
+        assert_eq!(res.status_code(), 200);
+        assert!(matches!(
+            res,
+            GatewayResponse::Content { size: Some(11), .. }
+        ));
+
+        let res = gateway.get(&root).await?;
+        assert_eq!(res.status_code(), 301);
+        assert!(matches!(res, GatewayResponse::Redirect(ref l) if l == &format!("{root}/")));
+
+        let res = gateway.get(&format!("{root}/")).await?;
+        match res {
+            GatewayResponse::DirectoryListing(body) => {
+                assert!(body.contains("<a href=\"hello.txt\">hello.txt</a>"))
+            }
+            _ => panic!("unexpected response: {res:?}"),
+        }
+
+        let res = gateway.get(&format!("{root}/missing.txt")).await?;
+        assert_eq!(res.status_code(), 404);
+
+        let res = gateway.get("/ipfs/not-a-cid").await?;
+        assert_eq!(res.status_code(), 400);
+
+        let gateway = gateway.with_renderer(None);
+        let res = gateway.get(&format!("{root}/")).await?;
+        assert_eq!(res.status_code(), 404);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_gateway_get_index() -> Result<()> {
+        let (root, gateway) = gateway(true).await?;
+
+        let res = gateway.get(&format!("{root}/")).await?;
+        assert!(matches!(
+            res,
+            GatewayResponse::Content { size: Some(14), .. }
+        ));
+
+        Ok(())
+    }
+}
@@ -1,7 +1,11 @@
 mod config;
 pub mod doc;
+pub mod doctor;
+pub mod gateway;
 pub mod metrics;
 pub mod p2p;
+pub mod pin;
 pub mod run;
 pub mod services;
 mod size;
+pub mod store;
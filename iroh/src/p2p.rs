@@ -2,7 +2,7 @@ use crate::doc;
 use anyhow::{Error, Result};
 use clap::{Args, Subcommand};
 use crossterm::style::Stylize;
-use iroh_api::{Lookup, Multiaddr, P2pApi, PeerId, PeerIdOrAddr};
+use iroh_api::{Lookup, Multiaddr, P2pApi, PeerId, PeerIdOrAddr, PeerInfo};
 use std::{collections::HashMap, fmt::Display, str::FromStr};
 
 #[derive(Args, Debug, Clone)]
@@ -32,7 +32,11 @@ pub enum P2pCommands {
     },
     #[clap(about = "List connected peers")]
     #[clap(after_help = doc::P2P_PEERS_LONG_DESCRIPTION)]
-    Peers {},
+    Peers {
+        /// Also show the agent version, latency, bitswap state and protocols of each peer
+        #[clap(long, short)]
+        verbose: bool,
+    },
 }
 
 #[derive(Debug, Clone)]
@@ -76,10 +80,14 @@ pub async fn run_command(p2p: &P2pApi, cmd: &P2p) -> Result<()> {
             };
             display_lookup(&lookup);
         }
-        P2pCommands::Peers {} => {
+        P2pCommands::Peers { verbose: false } => {
             let peers = p2p.peers().await?;
             display_peers(peers);
         }
+        P2pCommands::Peers { verbose: true } => {
+            let peers = p2p.peers_info().await?;
+            peers.iter().for_each(display_peer_info);
+        }
     };
     Ok(())
 }
@@ -122,3 +130,28 @@ fn display_peers(peers: HashMap<PeerId, Vec<Multiaddr>>) {
         }
     }
 }
+
+fn display_peer_info(p: &PeerInfo) {
+    println!("{}", p.peer_id.to_string().bold());
+    println!(
+        "  {} {}",
+        "Agent Version:".dim(),
+        p.agent_version.as_deref().unwrap_or("unknown")
+    );
+    if let Some(latency) = p.latency {
+        println!("  {} {:?}", "Latency:".dim(), latency);
+    }
+    match (&p.bitswap_state, &p.bitswap_protocol) {
+        (Some(state), Some(protocol)) => {
+            println!("  {} {:?} ({})", "Bitswap:".dim(), state, protocol)
+        }
+        (Some(state), None) => println!("  {} {:?}", "Bitswap:".dim(), state),
+        (None, _) => {}
+    }
+    println!("  {}", format!("Addresses ({}):", p.addrs.len()).dim());
+    p.addrs.iter().for_each(|addr| println!("    {addr}"));
+    println!("  {}", format!("Protocols ({}):", p.protocols.len()).dim());
+    p.protocols
+        .iter()
+        .for_each(|protocol| println!("    {protocol}"));
+}
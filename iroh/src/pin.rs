@@ -0,0 +1,37 @@
+use crate::doc;
+use anyhow::Result;
+use clap::{Args, Subcommand};
+use iroh_api::{Api, Cid};
+
+#[derive(Args, Debug, Clone)]
+#[clap(about = "Manage the pins that keep content in the local store")]
+#[clap(
+    after_help = "pin commands change which DAGs are kept when unused content is collected.
+See subcommands for additional details."
+)]
+pub struct Pin {
+    #[clap(subcommand)]
+    command: PinCommands,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum PinCommands {
+    #[clap(about = "Move a pin from one root to another")]
+    #[clap(after_help = doc::PIN_UPDATE_LONG_DESCRIPTION)]
+    Update {
+        /// The pinned root to unpin
+        old: Cid,
+        /// The root to pin instead, which must be completely stored
+        new: Cid,
+    },
+}
+
+pub async fn run_command(api: &Api, cmd: &Pin) -> Result<()> {
+    match &cmd.command {
+        PinCommands::Update { old, new } => {
+            api.pin_update(*old, *new).await?;
+            println!("Moved pin from {old} to {new}");
+        }
+    };
+    Ok(())
+}
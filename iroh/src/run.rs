@@ -8,7 +8,8 @@ use crossterm::style::Stylize;
 use futures::StreamExt;
 use indicatif::{ProgressBar, ProgressStyle};
 use iroh_api::{
-    Api, ChunkerConfig, IpfsPath, StatusType, UnixfsConfig, UnixfsEntry, DEFAULT_CHUNKS_SIZE,
+    AddEvent, Api, ChunkerConfig, DirectoryLayout, GetOptions, IpfsPath, StatusType, UnixfsConfig,
+    UnixfsEntry, DEFAULT_CHUNKS_SIZE,
 };
 use iroh_metrics::config::Config as MetricsConfig;
 use iroh_util::{human, iroh_config_path, make_config};
@@ -18,8 +19,10 @@ use crate::doc;
 #[cfg(feature = "testing")]
 use crate::fixture::get_fixture_api;
 use crate::p2p::{run_command as run_p2p_command, P2p};
+use crate::pin::{run_command as run_pin_command, Pin};
 use crate::services::require_services;
 use crate::size::size_stream;
+use crate::store::{run_command as run_store_command, Store};
 
 #[derive(Parser, Debug, Clone)]
 #[clap(version, long_about = None, propagate_version = true)]
@@ -38,6 +41,8 @@ pub struct Cli {
 #[derive(Subcommand, Debug, Clone)]
 enum Commands {
     P2p(P2p),
+    Pin(Pin),
+    Store(Store),
     #[clap(about = "Add a file or directory to iroh & make it available on IPFS")]
     #[clap(after_help = doc::ADD_LONG_DESCRIPTION )]
     Add {
@@ -49,12 +54,27 @@ enum Commands {
         /// Do not wrap added content with a directory
         #[clap(long)]
         no_wrap: bool,
+        /// With --no-wrap, store the name of an added file in the metadata of the file itself
+        #[clap(long, requires = "no_wrap")]
+        keep_name: bool,
         /// Don't provide added content to the network
         #[clap(long)]
         offline: bool,
-        /// Select the chunker to use, when chunking data. Available chunkers are currently "fixed" and "rabin".
+        /// Select the chunker to use, when chunking data. Available chunkers are currently "fixed", "fixed-<size>" and "rabin", which deduplicates edited files better. The chunker changes the resulting CIDs.
         #[clap(long, default_value_t = ChunkerConfig::Fixed(DEFAULT_CHUNKS_SIZE))]
         chunker: ChunkerConfig,
+        /// Store files up to this many bytes as a single raw block. Defaults to files that fit into a single chunk.
+        #[clap(long)]
+        raw_leaf_limit: Option<usize>,
+        /// How many entries of a directory to import at the same time
+        #[clap(long, default_value_t = 1)]
+        concurrency: usize,
+        /// Which kind of UnixFS directories to build: "auto", "basic" or "hamt" sharded. By default directories are sharded above 6000 entries. Forcing "basic" fails for larger directories.
+        #[clap(long, default_value_t = DirectoryLayout::Auto)]
+        directory_layout: DirectoryLayout,
+        /// Print the CID and path of every added file and directory to stderr
+        #[clap(long, short)]
+        verbose: bool,
     },
     #[clap(about = "Fetch IPFS content and write it to disk")]
     #[clap(after_help = doc::GET_LONG_DESCRIPTION )]
@@ -63,6 +83,21 @@ enum Commands {
         ipfs_path: IpfsPath,
         /// filesystem path to write to. Optional and defaults to $CID
         output: Option<PathBuf>,
+        /// maximum number of providers to fetch content from
+        #[clap(long)]
+        max_providers: Option<usize>,
+        /// fetch and verify all content before writing anything to disk
+        #[clap(long)]
+        verify_complete: bool,
+        /// directory to stage the output in, defaults to the parent of the output path
+        #[clap(long)]
+        staging_dir: Option<PathBuf>,
+        /// skip entries that can not be fetched, instead of failing
+        #[clap(long, conflicts_with = "verify_complete")]
+        best_effort: bool,
+        /// octal permissions of the written files, e.g. 600, defaults to the umask
+        #[clap(long, value_parser = parse_mode)]
+        mode: Option<u32>,
     },
     #[clap(about = "Start local iroh services")]
     #[clap(after_help = doc::START_LONG_DESCRIPTION )]
@@ -80,6 +115,9 @@ enum Commands {
         /// when true, updates the status table whenever a change in a process's status occurs
         watch: bool,
     },
+    #[clap(about = "Run diagnostic checks against the running iroh services")]
+    #[clap(after_help = doc::DOCTOR_LONG_DESCRIPTION)]
+    Doctor,
     #[clap(about = "Stop local iroh services")]
     #[clap(after_help = doc::STOP_LONG_DESCRIPTION )]
     Stop {
@@ -133,21 +171,61 @@ impl Cli {
                 path,
                 recursive,
                 no_wrap,
+                keep_name,
                 offline,
                 chunker,
+                raw_leaf_limit,
+                concurrency,
+                directory_layout,
+                verbose,
             } => {
-                add(api, path, *no_wrap, *recursive, *chunker, !*offline).await?;
+                let unixfs = UnixfsConfig {
+                    wrap: !*no_wrap,
+                    keep_name: *keep_name,
+                    custom_metadata: Default::default(),
+                    chunker: Some(*chunker),
+                    raw_leaf_limit: *raw_leaf_limit,
+                    concurrency: *concurrency,
+                    directory_layout: *directory_layout,
+                };
+                add(api, path, *recursive, unixfs, !*offline, *verbose).await?;
             }
             Commands::Get {
                 ipfs_path: path,
                 output,
+                max_providers,
+                verify_complete,
+                staging_dir,
+                best_effort,
+                mode,
             } => {
-                let blocks = api.get(path)?;
-                let root_path =
-                    iroh_api::fs::write_get_stream(path, blocks, output.as_deref()).await?;
+                let options = GetOptions {
+                    max_providers: *max_providers,
+                    verify_complete: *verify_complete,
+                    best_effort: *best_effort,
+                    ..Default::default()
+                };
+                let blocks = api.get_with_options(path, options)?;
+                let staging_dir = staging_dir.as_deref().or_else(|| api.staging_dir());
+                let (root_path, skipped) = iroh_api::fs::write_get_stream_with_staging(
+                    path,
+                    blocks,
+                    output.as_deref(),
+                    staging_dir,
+                    *mode,
+                )
+                .await?;
                 println!("Saving file(s) to {}", root_path.to_str().unwrap());
+                if !skipped.is_empty() {
+                    println!("Skipped {} unavailable entries:", skipped.len());
+                    for path in skipped {
+                        println!("  {path}");
+                    }
+                }
             }
             Commands::P2p(p2p) => run_p2p_command(&api.p2p()?, p2p).await?,
+            Commands::Pin(pin) => run_pin_command(api, pin).await?,
+            Commands::Store(store) => run_store_command(api, store).await?,
             Commands::Start { service, all } => {
                 let svc = match *all {
                     true => vec![
@@ -165,6 +243,9 @@ impl Cli {
             Commands::Status { watch } => {
                 crate::services::status(api, *watch).await?;
             }
+            Commands::Doctor => {
+                crate::doctor::run(api).await?;
+            }
             Commands::Stop { service } => {
                 crate::services::stop(api, service).await?;
             }
@@ -177,10 +258,10 @@ impl Cli {
 async fn add(
     api: &Api,
     path: &Path,
-    no_wrap: bool,
     recursive: bool,
-    chunker: ChunkerConfig,
+    unixfs: UnixfsConfig,
     provide: bool,
+    verbose: bool,
 ) -> Result<()> {
     if !path.exists() {
         anyhow::bail!("Path does not exist");
@@ -255,20 +336,21 @@ async fn add(
     // a while before it starts ending progress reports
     pb.inc(0);
 
-    let entry = UnixfsEntry::from_path(
-        path,
-        UnixfsConfig {
-            wrap: !no_wrap,
-            chunker: Some(chunker),
-        },
-    )
-    .await?;
-    let mut progress = api.add_stream(entry).await?;
+    let entry = UnixfsEntry::from_path(path, unixfs).await?;
+    let mut progress = api.add_events(entry).await?;
     let mut cids = Vec::new();
-    while let Some(prog) = progress.next().await {
-        let (cid, size) = prog?;
-        cids.push(cid);
-        pb.inc(size);
+    while let Some(event) = progress.next().await {
+        match event? {
+            AddEvent::Block { cid, size } => {
+                cids.push(cid);
+                pb.inc(size);
+            }
+            AddEvent::Entry { path, cid } => {
+                if verbose {
+                    pb.suspend(|| eprintln!("added {} {}", cid, path.display()));
+                }
+            }
+        }
     }
     pb.finish_and_clear();
 
@@ -303,3 +385,28 @@ async fn add(
 
     Ok(())
 }
+
+/// Parses octal file permissions, with or without a leading `0o`, e.g. `600` or `0o644`.
+fn parse_mode(s: &str) -> Result<u32, String> {
+    let digits = s.strip_prefix("0o").unwrap_or(s);
+    let mode = u32::from_str_radix(digits, 8).map_err(|_| format!("invalid octal mode {s:?}"))?;
+    if mode > 0o7777 {
+        return Err(format!("mode {s} is out of range, expected at most 7777"));
+    }
+    Ok(mode)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_mode() {
+        assert_eq!(parse_mode("600"), Ok(0o600));
+        assert_eq!(parse_mode("0644"), Ok(0o644));
+        assert_eq!(parse_mode("0o755"), Ok(0o755));
+        assert!(parse_mode("800").is_err());
+        assert!(parse_mode("17777").is_err());
+        assert!(parse_mode("").is_err());
+    }
+}
@@ -0,0 +1,85 @@
+use crate::doc;
+use anyhow::Result;
+use clap::{Args, Subcommand};
+use crossterm::style::Stylize;
+use futures::StreamExt;
+use indicatif::ProgressBar;
+use iroh_api::Api;
+
+#[derive(Args, Debug, Clone)]
+#[clap(about = "Local store commands")]
+#[clap(
+    after_help = "store commands inspect and maintain the blocks stored by this node. See
+subcommands for additional details."
+)]
+pub struct Store {
+    #[clap(subcommand)]
+    command: StoreCommands,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum StoreCommands {
+    #[clap(about = "Verify that stored blocks match their CIDs")]
+    #[clap(after_help = doc::STORE_VERIFY_LONG_DESCRIPTION)]
+    Verify {
+        /// Fraction of the blocks to verify, from 0.0 to 1.0 for all of them
+        #[clap(long, default_value_t = 1.0)]
+        sample_rate: f64,
+        /// Continue an interrupted verification after the given block id
+        #[clap(long)]
+        resume_after: Option<u64>,
+        /// Remove the data of corrupt blocks, so it is fetched again when needed
+        #[clap(long)]
+        quarantine: bool,
+    },
+}
+
+pub async fn run_command(api: &Api, cmd: &Store) -> Result<()> {
+    match &cmd.command {
+        StoreCommands::Verify {
+            sample_rate,
+            resume_after,
+            quarantine,
+        } => verify(api, *sample_rate, *resume_after, *quarantine).await?,
+    };
+    Ok(())
+}
+
+async fn verify(
+    api: &Api,
+    sample_rate: f64,
+    resume_after: Option<u64>,
+    quarantine: bool,
+) -> Result<()> {
+    let mut progress = api
+        .verify_integrity(sample_rate, resume_after, quarantine)
+        .await?;
+
+    let pb = ProgressBar::new_spinner();
+    let mut corrupt = 0;
+    while let Some(update) = progress.next().await {
+        let update = update?;
+        for cid in &update.corrupt {
+            pb.println(format!("{} {}", "corrupt:".red(), cid));
+        }
+        corrupt += update.corrupt.len();
+        if update.complete {
+            pb.finish_and_clear();
+            println!(
+                "Scanned {} blocks, verified {}, {} corrupt, {} could not be verified",
+                update.scanned, update.verified, corrupt, update.unverifiable
+            );
+            return Ok(());
+        }
+        // interrupting the command stops the verification, this allows continuing it
+        if let Some(last_id) = update.last_id {
+            pb.set_message(format!(
+                "Scanned {} blocks, {} corrupt, continue with --resume-after {}",
+                update.scanned, corrupt, last_id
+            ));
+        }
+        pb.tick();
+    }
+    pb.finish_and_clear();
+    anyhow::bail!("the store stopped before the verification was complete")
+}